@@ -0,0 +1,410 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Admin HTTP endpoint for operations that cannot wait, for example, rotating the keys right away
+//! when they are suspected to be compromised. `GET /health` checks that the key store can be
+//! reached. On the NTP server, `GET /mrulist` lists the recent clients, if they are tracked.
+//!
+//! Every request must carry the configured token in an `Authorization: Bearer` header. The
+//! token is sent in cleartext, so the endpoint listens on the loopback address by default.
+
+use lazy_static::lazy_static;
+
+use prometheus::{opts, register_counter, register_int_counter, IntCounter};
+
+use slog::{error, info, warn};
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::WrapError;
+use crate::key_rotator::{rotate_shared, KeyRotator};
+
+/// The address that the endpoint listens to, if `admin_addr` is not specified.
+const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1";
+
+/// The maximum length of a request head. The endpoints don't take a body.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How long a client can take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum number of connections that are served at once. Each has its own thread, and they
+/// are accepted before the token is checked, so the others are closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+lazy_static! {
+    static ref UNAUTHORIZED_COUNTER: IntCounter = register_int_counter!(
+        "admin_unauthorized_requests_total",
+        "Number of admin requests rejected because of a missing or wrong token"
+    ).unwrap();
+    static ref REJECTED_CONNECTION_COUNTER: IntCounter = register_int_counter!(
+        "admin_rejected_connections_total",
+        "Number of admin connections closed because too many were open"
+    ).unwrap();
+}
+
+/// One of the open connections. It's counted until it's dropped, even if its thread panics.
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    /// Count a new connection, unless `MAX_CONNECTIONS` are already open.
+    fn open(count: &Arc<AtomicUsize>) -> Option<OpenConnection> {
+        if count.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(OpenConnection(count.clone()))
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AdminConfig {
+    pub port: u16,
+    pub addr: String,
+
+    /// The token that the clients must present.
+    pub token: String,
+}
+
+/// Return the list of the recent clients as text, for `GET /mrulist`.
+pub type ClientList = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// Parse the admin endpoint settings. The endpoint is enabled only when `admin_port` is
+/// specified, in which case `admin_token_file` is also required. It listens on the loopback
+/// address, unless `admin_addr` is specified.
+pub fn get_admin_config(settings: &config::Config)
+    -> Result<Option<AdminConfig>, config::ConfigError>
+{
+    let addr = match settings.get_str("admin_addr") {
+        Err(config::ConfigError::NotFound(_)) => None,
+        Err(error) => return Err(error),
+        Ok(addr) => Some(addr),
+    };
+    let port = match settings.get_int("admin_port") {
+        // If it's a not-found error, the admin endpoint is disabled.
+        Err(config::ConfigError::NotFound(_)) if addr.is_none() => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(port) => u16::try_from(port).map_err(|_| {
+            config::ConfigError::Message(String::from("the admin port is not a valid u16"))
+        })?,
+    };
+    let addr = addr.unwrap_or_else(|| String::from(DEFAULT_ADMIN_ADDR));
+
+    let token_filename = settings.get_str("admin_token_file")?;
+    let token = String::from(std::fs::read_to_string(&token_filename).wrap_err()?.trim());
+    if token.is_empty() {
+        return Err(config::ConfigError::Message(
+            format!("the admin token in {} is empty", token_filename)
+        ));
+    }
+
+    Ok(Some(AdminConfig { port, addr, token }))
+}
+
+/// The part of an HTTP request that the admin endpoint cares about.
+#[derive(Debug, Eq, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+
+    /// The token in the `Authorization: Bearer` header, if any.
+    token: Option<String>,
+}
+
+/// Parse the request line and the headers. Return `None` if `buf` doesn't hold the whole head
+/// yet.
+fn parse_request(buf: &[u8]) -> Result<Option<Request>, &'static str> {
+    let end = match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| "the request is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) if !method.is_empty() => (method, path),
+        _ => return Err("invalid request line"),
+    };
+
+    let mut token = None;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => return Err("invalid header"),
+        };
+        // Header names are case-insensitive.
+        if name.eq_ignore_ascii_case("authorization") && value.starts_with("Bearer ") {
+            token = Some(String::from(value["Bearer ".len()..].trim()));
+        }
+    }
+
+    Ok(Some(Request {
+        method: String::from(method),
+        path: String::from(path),
+        token,
+    }))
+}
+
+/// Compare the token without leaking how much of it matches through timing.
+fn check_token(token: Option<&str>, expected: &str) -> bool {
+    match token {
+        Some(token) => {
+            ring::constant_time::verify_slices_are_equal(token.as_bytes(), expected.as_bytes())
+                .is_ok()
+        },
+        None => false,
+    }
+}
+
+/// Format an HTTP response with a plaintext body.
+fn response(status: &str, body: &str) -> String {
+    format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}", status, body.len(), body)
+}
+
+/// Read the request head from the client.
+fn read_request(dest: &mut net::TcpStream) -> Result<Request, String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read_count = dest.read(&mut chunk).map_err(|error| error.to_string())?;
+        if read_count == 0 {
+            return Err(String::from("the connection is closed before the request ends"));
+        }
+        buf.extend_from_slice(&chunk[..read_count]);
+
+        if let Some(request) = parse_request(&buf).map_err(String::from)? {
+            return Ok(request);
+        }
+        if buf.len() > MAX_REQUEST_LEN {
+            return Err(String::from("the request is too long"));
+        }
+    }
+}
+
+/// Rotate the keys and return the new key id.
+fn rotate(rotator: &Arc<RwLock<KeyRotator>>) -> Result<u32, String> {
+    rotate_shared(rotator).map_err(|error| error.to_string())?;
+
+    let (key_id, _) = rotator.read().unwrap().latest_key_value();
+    Ok(u32::from_be_bytes(key_id.to_be_bytes()))
+}
+
+fn serve_admin(
+    mut dest: net::TcpStream,
+    conf: &AdminConfig,
+    rotator: &Arc<RwLock<KeyRotator>>,
+    clients: Option<&ClientList>,
+    logger: &slog::Logger,
+) {
+    if let Err(error) = dest.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+        error!(logger, "cannot set the read timeout: {}", error);
+        return;
+    }
+
+    let response = match read_request(&mut dest) {
+        Err(error) => {
+            warn!(logger, "bad admin request: {}", error);
+            response("400 Bad Request", "bad request\n")
+        },
+        Ok(ref request)
+            if !check_token(request.token.as_ref().map(String::as_str), &conf.token) =>
+        {
+            UNAUTHORIZED_COUNTER.inc();
+            warn!(logger, "unauthorized admin request for {}", request.path);
+            response("401 Unauthorized", "unauthorized\n")
+        },
+        Ok(request) => match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/rotate") => match rotate(rotator) {
+                Ok(key_id) => {
+                    info!(logger, "keys rotated on request"; "key_id" => key_id);
+                    response("200 OK", &format!("key_id {}\n", key_id))
+                },
+                Err(error) => {
+                    error!(logger, "requested key rotation failed: {}", error);
+                    response("500 Internal Server Error", "rotation failed\n")
+                },
+            },
+            ("GET", "/health") => {
+                // Don't hold the lock while the store is checked.
+                let store = rotator.read().unwrap().store();
+                match store.health() {
+                    Ok(()) => response("200 OK", "ok\n"),
+                    Err(error) => {
+                        warn!(logger, "the key store is unhealthy: {}", error);
+                        response("503 Service Unavailable", "key store unreachable\n")
+                    },
+                }
+            },
+            ("GET", "/mrulist") => match clients {
+                Some(clients) => response("200 OK", &clients()),
+                None => response("404 Not Found", "not found\n"),
+            },
+            (_, "/rotate") => response("405 Method Not Allowed", "use POST\n"),
+            (_, "/health") => response("405 Method Not Allowed", "use GET\n"),
+            (_, "/mrulist") => response("405 Method Not Allowed", "use GET\n"),
+            _ => response("404 Not Found", "not found\n"),
+        },
+    };
+
+    if let Err(e) = dest.write_all(response.as_bytes()) {
+        error!(logger, "write to TcpStream failed with error: {:?}, unable to serve admin", e);
+    }
+    if let Err(e) = dest.shutdown(net::Shutdown::Write) {
+        error!(logger, "TcpStream shutdown failed with error: {:?}, unable to serve admin", e);
+    }
+}
+
+/// Runs the admin server on the address and port set in config. The list of the `clients` is
+/// served if there is one.
+pub fn run_admin(
+    conf: AdminConfig,
+    rotator: Arc<RwLock<KeyRotator>>,
+    clients: Option<ClientList>,
+    logger: &slog::Logger,
+) -> Result<(), std::io::Error> {
+    let accept = net::TcpListener::bind((conf.addr.as_str(), conf.port))?;
+    let local_addr = accept.local_addr()?;
+    if !local_addr.ip().is_loopback() {
+        warn!(logger, "the admin endpoint listens on {}, where the token is sent in cleartext",
+              local_addr);
+    }
+    let conf = Arc::new(conf);
+    let open_connections = Arc::new(AtomicUsize::new(0));
+    for stream in accept.incoming() {
+        let conn = stream?;
+        let open = match OpenConnection::open(&open_connections) {
+            Some(open) => open,
+            // Dropping the stream closes it.
+            None => {
+                REJECTED_CONNECTION_COUNTER.inc();
+                continue;
+            },
+        };
+        let log_admin = logger.new(slog::o!("component" => "serve_admin"));
+        let conf = conf.clone();
+        let rotator = rotator.clone();
+        let clients = clients.clone();
+        thread::spawn(move || {
+            serve_admin(conn, &conf, &rotator, clients.as_ref(), &log_admin);
+            drop(open);
+        });
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "unreachable"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(b"POST /rotate HTTP/1.1\r\nHost: localhost\r\n\
+                                      authorization: Bearer secret \r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request, Request {
+            method: String::from("POST"),
+            path: String::from("/rotate"),
+            token: Some(String::from("secret")),
+        });
+
+        let request = parse_request(b"GET /rotate HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.token, None);
+
+        // Incomplete requests.
+        assert_eq!(parse_request(b"POST /rotate HTTP/1.1\r\n").unwrap(), None);
+
+        // Invalid requests.
+        assert!(parse_request(b"POST\r\n\r\n").is_err());
+        assert!(parse_request(b"POST /rotate HTTP/1.1\r\nbad header\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_check_token() {
+        assert!(check_token(Some("secret"), "secret"));
+        assert!(!check_token(Some("secre"), "secret"));
+        assert!(!check_token(Some("secret2"), "secret"));
+        assert!(!check_token(None, "secret"));
+    }
+
+    #[test]
+    fn test_open_connection() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut open: Vec<OpenConnection> = (0..MAX_CONNECTIONS)
+            .map(|_| OpenConnection::open(&count).unwrap())
+            .collect();
+        assert!(OpenConnection::open(&count).is_none());
+        assert_eq!(count.load(Ordering::SeqCst), MAX_CONNECTIONS);
+
+        open.pop();
+        assert!(OpenConnection::open(&count).is_some());
+        drop(open);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    fn admin_config(yaml: &str) -> Result<Option<AdminConfig>, config::ConfigError> {
+        let mut settings = config::Config::new();
+        settings.merge(config::File::from_str(yaml, config::FileFormat::Yaml)).unwrap();
+        get_admin_config(&settings)
+    }
+
+    #[test]
+    fn test_get_admin_config() {
+        let token_filename = std::env::temp_dir()
+            .join(format!("cfnts-test-{}.token", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        std::fs::write(&token_filename, "secret\n").unwrap();
+
+        assert!(admin_config("next_port: 123").unwrap().is_none());
+
+        // It listens on the loopback address by default.
+        let conf = admin_config(&format!("
+admin_port: 8002
+admin_token_file: {}
+", token_filename)).unwrap().unwrap();
+        assert_eq!(conf.addr, "127.0.0.1");
+        assert_eq!(conf.port, 8002);
+        assert_eq!(conf.token, "secret");
+
+        let conf = admin_config(&format!("
+admin_addr: \"::1\"
+admin_port: 8002
+admin_token_file: {}
+", token_filename)).unwrap().unwrap();
+        assert_eq!(conf.addr, "::1");
+
+        // The port and the token are required.
+        assert!(admin_config("admin_addr: 127.0.0.1").is_err());
+        assert!(admin_config("admin_port: 8002").is_err());
+        assert!(admin_config(&format!("
+admin_port: 80000
+admin_token_file: {}
+", token_filename)).is_err());
+
+        std::fs::write(&token_filename, "\n").unwrap();
+        assert!(admin_config(&format!("
+admin_port: 8002
+admin_token_file: {}
+", token_filename)).is_err());
+
+        std::fs::remove_file(&token_filename).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! AEAD_AES_128_GCM_SIV from RFC 8452.
+//!
+//! The algorithm is exposed through the `miscreant` `Aead` trait so that it can be used wherever
+//! `Aes128SivAead` is used. Unlike AES-SIV, the tag goes after the ciphertext.
+
+use aesni::Aes128;
+use block_cipher_trait::generic_array::typenum::U16;
+use block_cipher_trait::generic_array::GenericArray;
+use block_cipher_trait::BlockCipher;
+use miscreant::aead::Aead;
+use ring::constant_time::verify_slices_are_equal;
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::{__m128i, _mm_clmulepi64_si128, _mm_set_epi64x};
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::{__m128i, _mm_clmulepi64_si128, _mm_set_epi64x};
+use std::mem;
+
+#[cfg(not(target_feature = "pclmulqdq"))]
+compile_error!("POLYVAL needs the pclmulqdq target feature, which .cargo/config enables");
+
+/// The length of the key in bytes.
+pub const KEY_LEN: usize = 16;
+
+/// The length of the nonce in bytes.
+pub const NONCE_LEN: usize = 12;
+
+/// The length of the tag in bytes.
+pub const TAG_LEN: usize = 16;
+
+/// AEAD_AES_128_GCM_SIV with a fixed key.
+pub struct Aes128GcmSivAead {
+    /// The key generating key. The per-nonce keys are derived from it.
+    key: Aes128,
+}
+
+/// Multiply two polynomials of degree below 64 with the carry-less multiplication instruction,
+/// which takes the same time whatever the operands are.
+fn clmul(a: u64, b: u64) -> u128 {
+    unsafe {
+        let product = _mm_clmulepi64_si128(_mm_set_epi64x(0, a as i64),
+                                           _mm_set_epi64x(0, b as i64), 0x00);
+        mem::transmute::<__m128i, u128>(product)
+    }
+}
+
+/// Multiply two POLYVAL field elements and divide the result by x^128.
+// The elements are little-endian, so bit i of the integer is the coefficient of x^i. The product
+// is reduced like in Montgomery multiplication: the multiple of the polynomial that clears the
+// low 64 bits is added and the result is divided by x^64, twice.
+fn dot(a: u128, b: u128) -> u128 {
+    // x^121 + x^126 + x^127, divided by x^64.
+    const POLY_HIGH: u64 = 0xc200_0000_0000_0000;
+
+    let (a0, a1) = (a as u64, (a >> 64) as u64);
+    let (b0, b1) = (b as u64, (b >> 64) as u64);
+    let middle = clmul(a0, b1) ^ clmul(a1, b0);
+    let mut low = clmul(a0, b0) ^ (middle << 64);
+    let mut high = clmul(a1, b1) ^ (middle >> 64);
+
+    for _ in 0..2 {
+        // Adding m times x^128 + x^127 + x^126 + x^121 + 1 clears the low 64 bits.
+        let m = low as u64;
+        let folded = clmul(m, POLY_HIGH);
+        low ^= u128::from(m) ^ (folded << 64);
+        high ^= u128::from(m) ^ (folded >> 64);
+        low = (low >> 64) | (high << 64);
+        high >>= 64;
+    }
+    low
+}
+
+/// Compute POLYVAL over the associated data and the plaintext, each padded to 16 bytes, and the
+/// length block.
+fn polyval(key: &[u8; 16], associated_data: &[u8], plaintext: &[u8]) -> [u8; 16] {
+    let h = u128::from_le_bytes(*key);
+    let mut s = 0u128;
+
+    for data in &[associated_data, plaintext] {
+        for chunk in data.chunks(16) {
+            let mut block = [0; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            s = dot(s ^ u128::from_le_bytes(block), h);
+        }
+    }
+
+    let lengths = u128::from(associated_data.len() as u64 * 8)
+        | (u128::from(plaintext.len() as u64 * 8) << 64);
+    s = dot(s ^ lengths, h);
+
+    s.to_le_bytes()
+}
+
+impl Aes128GcmSivAead {
+    /// Derive the message authentication key and the message encryption key for a nonce.
+    fn derive_keys(&self, nonce: &[u8]) -> ([u8; 16], Aes128) {
+        let mut derived = [0; 32];
+        for (counter, half) in derived.chunks_mut(8).enumerate() {
+            let mut block = GenericArray::clone_from_slice(&[0; 16]);
+            block[..4].copy_from_slice(&(counter as u32).to_le_bytes());
+            block[4..].copy_from_slice(nonce);
+            self.key.encrypt_block(&mut block);
+            half.copy_from_slice(&block[..8]);
+        }
+
+        let mut auth_key = [0; 16];
+        auth_key.copy_from_slice(&derived[..16]);
+        let enc_key = Aes128::new(GenericArray::from_slice(&derived[16..]));
+        (auth_key, enc_key)
+    }
+
+    /// Compute the tag of the plaintext.
+    fn tag(
+        auth_key: &[u8; 16],
+        enc_key: &Aes128,
+        nonce: &[u8],
+        associated_data: &[u8],
+        plaintext: &[u8],
+    ) -> [u8; 16] {
+        let mut s = polyval(auth_key, associated_data, plaintext);
+        for (byte, nonce_byte) in s.iter_mut().zip(nonce) {
+            *byte ^= nonce_byte;
+        }
+        s[15] &= 0x7f;
+
+        let mut block = GenericArray::clone_from_slice(&s);
+        enc_key.encrypt_block(&mut block);
+        let mut tag = [0; 16];
+        tag.copy_from_slice(&block);
+        tag
+    }
+
+    /// Encrypt or decrypt `buffer` in counter mode starting from the tag.
+    fn ctr(enc_key: &Aes128, tag: &[u8; 16], buffer: &mut [u8]) {
+        let mut counter_block = *tag;
+        counter_block[15] |= 0x80;
+        let mut counter = u32::from_le_bytes([
+            counter_block[0], counter_block[1], counter_block[2], counter_block[3],
+        ]);
+
+        for chunk in buffer.chunks_mut(16) {
+            counter_block[..4].copy_from_slice(&counter.to_le_bytes());
+            let mut keystream = GenericArray::clone_from_slice(&counter_block);
+            enc_key.encrypt_block(&mut keystream);
+            for (byte, key_byte) in chunk.iter_mut().zip(keystream.iter()) {
+                *byte ^= key_byte;
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+}
+
+impl Aead for Aes128GcmSivAead {
+    type KeySize = U16;
+    type TagSize = U16;
+
+    /// Create a new instance.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not 16 bytes long.
+    ///
+    fn new(key: &[u8]) -> Self {
+        assert_eq!(key.len(), KEY_LEN, "AES-128-GCM-SIV key must be 16 bytes");
+        Aes128GcmSivAead {
+            key: Aes128::new(GenericArray::from_slice(key)),
+        }
+    }
+
+    /// Encrypt the plaintext in `buffer` except its last 16 bytes, and put the tag there.
+    ///
+    /// # Panics
+    ///
+    /// If the nonce is not 12 bytes long or the buffer is shorter than the tag.
+    ///
+    fn seal_in_place(&mut self, nonce: &[u8], associated_data: &[u8], buffer: &mut [u8]) {
+        assert_eq!(nonce.len(), NONCE_LEN, "AES-128-GCM-SIV nonce must be 12 bytes");
+        let plaintext_len = buffer.len().checked_sub(TAG_LEN)
+            .expect("the buffer is too short for the tag");
+
+        let (auth_key, enc_key) = self.derive_keys(nonce);
+        let (plaintext, tag_space) = buffer.split_at_mut(plaintext_len);
+        let tag = Self::tag(&auth_key, &enc_key, nonce, associated_data, plaintext);
+        Self::ctr(&enc_key, &tag, plaintext);
+        tag_space.copy_from_slice(&tag);
+    }
+
+    /// Decrypt the ciphertext followed by the tag in `buffer`. Return the plaintext.
+    fn open_in_place<'a>(
+        &mut self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &'a mut [u8],
+    ) -> Result<&'a [u8], miscreant::Error> {
+        if nonce.len() != NONCE_LEN || buffer.len() < TAG_LEN {
+            return Err(miscreant::Error);
+        }
+        let ciphertext_len = buffer.len() - TAG_LEN;
+
+        let (auth_key, enc_key) = self.derive_keys(nonce);
+        let (ciphertext, tag) = buffer.split_at_mut(ciphertext_len);
+        let mut expected_tag = [0; 16];
+        expected_tag.copy_from_slice(tag);
+
+        Self::ctr(&enc_key, &expected_tag, ciphertext);
+        let actual_tag = Self::tag(&auth_key, &enc_key, nonce, associated_data, ciphertext);
+
+        if verify_slices_are_equal(&actual_tag, &expected_tag).is_err() {
+            // Don't leave the unauthenticated plaintext around.
+            for byte in ciphertext.iter_mut() {
+                *byte = 0;
+            }
+            return Err(miscreant::Error);
+        }
+
+        Ok(&buffer[..ciphertext_len])
+    }
+
+    // The default `seal` and `open` assume that the tag goes first like in AES-SIV.
+
+    fn seal(&mut self, nonce: &[u8], associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut buffer = plaintext.to_vec();
+        buffer.resize(plaintext.len() + TAG_LEN, 0);
+        self.seal_in_place(nonce, associated_data, &mut buffer);
+        buffer
+    }
+
+    fn open(
+        &mut self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, miscreant::Error> {
+        let mut buffer = ciphertext.to_vec();
+        let plaintext_len = self.open_in_place(nonce, associated_data, &mut buffer)?.len();
+        buffer.truncate(plaintext_len);
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_polyval() {
+        // RFC 8452, Appendix A.
+        let mut key = [0; 16];
+        key.copy_from_slice(&hex("25629347589242761d31f826ba4b757b"));
+        let data = hex("4f4f95668c83dfb6401762bb2d01a262d1a24ddd2721d006bbe45f20d3c9f362");
+
+        // The test vector doesn't include the length block, so hash the blocks directly.
+        let h = u128::from_le_bytes(key);
+        let mut s = 0u128;
+        for chunk in data.chunks(16) {
+            let mut block = [0; 16];
+            block.copy_from_slice(chunk);
+            s = dot(s ^ u128::from_le_bytes(block), h);
+        }
+        assert_eq!(s.to_le_bytes().to_vec(), hex("f7a3b47b846119fae5b7866cf5e5b77e"));
+    }
+
+    #[test]
+    fn test_aes_128_gcm_siv() {
+        // RFC 8452, Appendix C.1.
+        let key = hex("01000000000000000000000000000000");
+        let nonce = hex("030000000000000000000000");
+        let mut aead = Aes128GcmSivAead::new(&key);
+
+        let sealed = aead.seal(&nonce, &[], &[]);
+        assert_eq!(sealed, hex("dc20e2d83f25705bb49e439eca56de25"));
+        assert_eq!(aead.open(&nonce, &[], &sealed).unwrap(), Vec::<u8>::new());
+
+        let plaintext = hex("0100000000000000");
+        let sealed = aead.seal(&nonce, &[], &plaintext);
+        assert_eq!(sealed, hex("b5d839330ac7b786578782fff6013b815b287c22493a364c"));
+        assert_eq!(aead.open(&nonce, &[], &sealed).unwrap(), plaintext);
+
+        // Round trip with associated data and a tampered ciphertext.
+        let mut sealed = aead.seal(&nonce, b"header", b"a longer message spanning blocks");
+        assert_eq!(aead.open(&nonce, b"header", &sealed).unwrap(),
+                   b"a longer message spanning blocks".to_vec());
+        assert!(aead.open(&nonce, b"headers", &sealed).is_err());
+        sealed[3] ^= 1;
+        assert!(aead.open(&nonce, b"header", &sealed).is_err());
+    }
+}
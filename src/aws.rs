@@ -0,0 +1,422 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A minimal AWS client which reads a secret from Secrets Manager, or decrypts a wrapped key
+//! with KMS, so that the cookie master key never has to be stored unencrypted on the disk.
+//!
+//! The requests are signed with Signature Version 4. The credentials are read from
+//! `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and `AWS_SESSION_TOKEN`, or from the instance
+//! metadata service, if the variables are not set. They are read again for every request, so
+//! the temporary credentials of an instance role are always fresh.
+//! See https://docs.aws.amazon.com/general/latest/gr/signature-version-4.html.
+
+use ring::{digest, hmac};
+
+use serde_json::{json, Value};
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::http::{self, HttpError};
+use crate::key_rotator::take_refresh_interval;
+
+/// How long a request to AWS can take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a request to the instance metadata service can take. It's local, so it's quick.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The url of the instance metadata service.
+const METADATA_URL: &str = "http://169.254.169.254/latest";
+
+/// How often the cookie master key is read again, if not configured.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Error returned from the AWS client.
+#[derive(Debug)]
+pub enum AwsError {
+    /// Error from the HTTP connection to AWS.
+    Http(HttpError),
+    /// AWS replied with a status other than 200.
+    Status(u16, String),
+    /// AWS sent something that we don't understand.
+    Protocol(String),
+    /// There are no credentials, or they cannot be read.
+    Credentials(String),
+    /// The wrapped key cannot be read.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for AwsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AwsError::Http(error) => write!(f, "aws {}", error),
+            AwsError::Status(status, body) => {
+                write!(f, "aws replied with status {}: {}", status, body)
+            },
+            AwsError::Protocol(message) => write!(f, "aws protocol error: {}", message),
+            AwsError::Credentials(message) => write!(f, "aws credentials error: {}", message),
+            AwsError::Io(error) => write!(f, "cannot read the wrapped key: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for AwsError {}
+
+impl From<HttpError> for AwsError {
+    fn from(error: HttpError) -> AwsError {
+        AwsError::Http(error)
+    }
+}
+
+/// AWS credentials.
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    /// The token of temporary credentials.
+    session_token: Option<String>,
+}
+
+/// Return the lowercase hex encoding of `bytes`.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Return the HMAC-SHA-256 tag of `data` under `key`.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
+}
+
+/// Return the key that signs the requests to `service` in `region` on `date`, which looks like
+/// `20150830`.
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> hmac::Tag {
+    let date_key = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    let region_key = hmac_sha256(date_key.as_ref(), region.as_bytes());
+    let service_key = hmac_sha256(region_key.as_ref(), service.as_bytes());
+    hmac_sha256(service_key.as_ref(), b"aws4_request")
+}
+
+/// A request to be signed.
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    /// The query string, which must already be in the canonical form.
+    query: &'a str,
+    /// The headers to sign, including `host` and `x-amz-date`. The names are in lowercase and
+    /// sorted.
+    headers: &'a [(&'a str, &'a str)],
+    payload: &'a [u8],
+}
+
+/// Return the `Authorization` header of a request, which is sent at `timestamp`, which looks
+/// like `20150830T123600Z`.
+fn authorization(
+    request: &Request,
+    access_key_id: &str,
+    secret_access_key: &str,
+    region: &str,
+    service: &str,
+    timestamp: &str,
+) -> String {
+    let mut canonical_headers = String::new();
+    for (name, value) in request.headers {
+        canonical_headers.push_str(&format!("{}:{}\n", name, value.trim()));
+    }
+    let signed_headers = request.headers.iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method,
+        request.path,
+        request.query,
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, request.payload).as_ref()),
+    );
+
+    let date = &timestamp[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref()),
+    );
+    let signature = hmac_sha256(
+        signing_key(secret_access_key, date, region, service).as_ref(),
+        string_to_sign.as_bytes(),
+    );
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key_id,
+        scope,
+        signed_headers,
+        hex(signature.as_ref()),
+    )
+}
+
+/// Read a string field of a JSON object.
+fn string_field<'a>(object: &'a Value, field: &str) -> Result<&'a str, AwsError> {
+    object.get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| AwsError::Protocol(format!("the response has no string field {}", field)))
+}
+
+/// Read the credentials of the instance role from the instance metadata service, with a session
+/// token as IMDSv2 requires.
+fn instance_credentials() -> Result<Credentials, AwsError> {
+    let metadata_error = |error: HttpError| {
+        AwsError::Credentials(format!("instance metadata service: {}", error))
+    };
+    let (status, token) = http::send_and_read(
+        "PUT",
+        &format!("{}/api/token", METADATA_URL),
+        &[("X-aws-ec2-metadata-token-ttl-seconds", "60")],
+        &[],
+        METADATA_TIMEOUT,
+    ).map_err(metadata_error)?;
+    if status != 200 {
+        return Err(AwsError::Credentials(String::from("no instance metadata token")));
+    }
+    let token = String::from_utf8_lossy(&token).into_owned();
+
+    let read = |path: &str| -> Result<Vec<u8>, AwsError> {
+        let (status, body) = http::send_and_read(
+            "GET",
+            &format!("{}/meta-data/iam/security-credentials/{}", METADATA_URL, path),
+            &[("X-aws-ec2-metadata-token", &token)],
+            &[],
+            METADATA_TIMEOUT,
+        ).map_err(metadata_error)?;
+        if status != 200 {
+            return Err(AwsError::Credentials(String::from("the instance has no role")));
+        }
+        Ok(body)
+    };
+    let roles = String::from_utf8_lossy(&read("")?).into_owned();
+    let role = roles.lines().next()
+        .ok_or_else(|| AwsError::Credentials(String::from("the instance has no role")))?;
+    let response: Value = serde_json::from_slice(&read(role)?)
+        .map_err(|error| AwsError::Protocol(error.to_string()))?;
+
+    Ok(Credentials {
+        access_key_id: String::from(string_field(&response, "AccessKeyId")?),
+        secret_access_key: String::from(string_field(&response, "SecretAccessKey")?),
+        session_token: Some(String::from(string_field(&response, "Token")?)),
+    })
+}
+
+/// Return the current credentials.
+fn credentials() -> Result<Credentials, AwsError> {
+    match (std::env::var("AWS_ACCESS_KEY_ID"), std::env::var("AWS_SECRET_ACCESS_KEY")) {
+        (Ok(access_key_id), Ok(secret_access_key)) => Ok(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        }),
+        _ => instance_credentials(),
+    }
+}
+
+/// Call the action `target` of the JSON API of `service` in `region`, for example,
+/// `secretsmanager.GetSecretValue`, and return the response.
+fn call(region: &str, service: &str, target: &str, body: &Value) -> Result<Value, AwsError> {
+    let credentials = credentials()?;
+    let host = format!("{}.{}.amazonaws.com", service, region);
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let payload = body.to_string();
+
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1"),
+        ("host", host.as_str()),
+        ("x-amz-date", timestamp.as_str()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.as_str()));
+    }
+    headers.push(("x-amz-target", target));
+
+    let request = Request {
+        method: "POST",
+        path: "/",
+        query: "",
+        headers: &headers,
+        payload: payload.as_bytes(),
+    };
+    let authorization = authorization(
+        &request,
+        &credentials.access_key_id,
+        &credentials.secret_access_key,
+        region,
+        service,
+        &timestamp,
+    );
+
+    // The host header is added by the HTTP client.
+    let mut sent_headers: Vec<(&str, &str)> = headers.iter()
+        .filter(|(name, _)| *name != "host")
+        .cloned()
+        .collect();
+    sent_headers.push(("authorization", &authorization));
+    let (status, body) = http::send_and_read(
+        "POST",
+        &format!("https://{}/", host),
+        &sent_headers,
+        payload.as_bytes(),
+        REQUEST_TIMEOUT,
+    )?;
+    if status != 200 {
+        return Err(AwsError::Status(status, String::from_utf8_lossy(&body).into_owned()));
+    }
+    serde_json::from_slice(&body).map_err(|error| AwsError::Protocol(error.to_string()))
+}
+
+/// Decode the base64 string `field` of `object`.
+fn decode_field(object: &Value, field: &str) -> Result<Vec<u8>, AwsError> {
+    base64::decode(string_field(object, field)?)
+        .map_err(|_| AwsError::Protocol(format!("{} is not base64", field)))
+}
+
+/// Where the secret is kept.
+#[derive(Clone, Debug)]
+pub enum AwsSource {
+    /// A secret in Secrets Manager. It's either binary, or a base64 string.
+    SecretsManager { secret_id: String },
+    /// A file with a ciphertext blob which is decrypted with KMS.
+    Kms { ciphertext_file: PathBuf },
+}
+
+/// A secret in AWS which is read periodically, like the cookie master key.
+#[derive(Clone, Debug)]
+pub struct AwsSecret {
+    pub region: String,
+    pub source: AwsSource,
+    /// How often the secret is read again.
+    pub refresh_interval: Duration,
+}
+
+impl AwsSecret {
+    /// Read the secret.
+    pub fn fetch(&self) -> Result<Vec<u8>, AwsError> {
+        match &self.source {
+            AwsSource::SecretsManager { secret_id } => {
+                let response = call(&self.region, "secretsmanager",
+                                    "secretsmanager.GetSecretValue",
+                                    &json!({ "SecretId": secret_id }))?;
+                if response.get("SecretBinary").is_some() {
+                    decode_field(&response, "SecretBinary")
+                } else {
+                    base64::decode(string_field(&response, "SecretString")?.trim())
+                        .map_err(|_| AwsError::Protocol(String::from("SecretString is not base64")))
+                }
+            },
+            AwsSource::Kms { ciphertext_file } => {
+                let ciphertext = std::fs::read(ciphertext_file).map_err(AwsError::Io)?;
+                let response = call(&self.region, "kms", "TrentService.Decrypt",
+                                    &json!({ "CiphertextBlob": base64::encode(&ciphertext) }))?;
+                decode_field(&response, "Plaintext")
+            },
+        }
+    }
+}
+
+/// Parse the settings of a secret in the table `name`, which has `region`, and either `secret_id`
+/// or `ciphertext_file`, and optionally `refresh_interval` in seconds. It's `None` if the table
+/// doesn't exist.
+pub fn get_aws_secret(settings: &config::Config, name: &str)
+    -> Result<Option<AwsSecret>, config::ConfigError>
+{
+    let mut table = match settings.get_table(name) {
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(table) => table,
+    };
+
+    let refresh_interval = take_refresh_interval(&mut table, name, DEFAULT_REFRESH_INTERVAL)?;
+    let mut take_str = |key: &str| match table.remove(key) {
+        Some(value) => value.into_str().map(Some),
+        None => Ok(None),
+    };
+    let region = take_str("region")?.ok_or_else(|| config::ConfigError::Message(
+        format!("{} is missing region", name)
+    ))?;
+    let source = match (take_str("secret_id")?, take_str("ciphertext_file")?) {
+        (Some(secret_id), None) => AwsSource::SecretsManager { secret_id },
+        (None, Some(ciphertext_file)) => {
+            AwsSource::Kms { ciphertext_file: PathBuf::from(ciphertext_file) }
+        },
+        _ => return Err(config::ConfigError::Message(
+            format!("{} must have either secret_id or ciphertext_file", name)
+        )),
+    };
+
+    Ok(Some(AwsSecret { region, source, refresh_interval }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example in the AWS documentation.
+    const SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+
+    #[test]
+    fn test_signing_key() {
+        let key = signing_key(SECRET_ACCESS_KEY, "20150830", "us-east-1", "iam");
+        assert_eq!(hex(key.as_ref()),
+                   "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9");
+    }
+
+    #[test]
+    fn test_authorization() {
+        let request = Request {
+            method: "GET",
+            path: "/",
+            query: "Action=ListUsers&Version=2010-05-08",
+            headers: &[
+                ("content-type", "application/x-www-form-urlencoded; charset=utf-8"),
+                ("host", "iam.amazonaws.com"),
+                ("x-amz-date", "20150830T123600Z"),
+            ],
+            payload: b"",
+        };
+        assert_eq!(
+            authorization(&request, "AKIDEXAMPLE", SECRET_ACCESS_KEY, "us-east-1", "iam",
+                          "20150830T123600Z"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7",
+        );
+    }
+
+    #[test]
+    fn test_get_aws_secret() {
+        let mut settings = config::Config::new();
+        assert!(get_aws_secret(&settings, "cookie_key_aws").unwrap().is_none());
+
+        settings.set("cookie_key_aws.region", "us-east-1").unwrap();
+        assert!(get_aws_secret(&settings, "cookie_key_aws").is_err());
+
+        settings.set("cookie_key_aws.secret_id", "cfnts/cookie-key").unwrap();
+        let secret = get_aws_secret(&settings, "cookie_key_aws").unwrap().unwrap();
+        assert_eq!(secret.refresh_interval, DEFAULT_REFRESH_INTERVAL);
+        match secret.source {
+            AwsSource::SecretsManager { secret_id } => assert_eq!(secret_id, "cfnts/cookie-key"),
+            _ => panic!("the source is not Secrets Manager"),
+        }
+
+        settings.set("cookie_key_aws.refresh_interval", 0).unwrap();
+        assert!(get_aws_secret(&settings, "cookie_key_aws").is_err());
+        settings.set("cookie_key_aws.refresh_interval", 60).unwrap();
+        let secret = get_aws_secret(&settings, "cookie_key_aws").unwrap().unwrap();
+        assert_eq!(secret.refresh_interval, Duration::from_secs(60));
+
+        settings.set("cookie_key_aws.ciphertext_file", "/etc/cfnts/cookie.key.enc").unwrap();
+        assert!(get_aws_secret(&settings, "cookie_key_aws").is_err());
+    }
+}
@@ -1,7 +1,12 @@
 use libc::*;
+use net2::unix::{UnixTcpBuilderExt, UnixUdpBuilderExt};
 use net2::{TcpBuilder, UdpBuilder};
+use std::convert::TryFrom;
 use std::net::{SocketAddr, SocketAddr::*};
 use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::systemd;
 
 #[cfg(target_os = "linux")]
 fn set_freebind(fd: c_int) -> Result<(), std::io::Error> {
@@ -29,23 +34,199 @@ fn set_freebind(_fd: c_int) -> Result<(), std::io::Error> {
     Ok(()) // no op for mac build
 }
 
-pub fn tcp_listener(addr: &SocketAddr) -> Result<std::net::TcpListener, std::io::Error> {
+/// Set an integer socket option.
+fn set_int_option(fd: c_int, level: c_int, name: c_int, value: c_int)
+    -> Result<(), std::io::Error>
+{
+    match unsafe {
+        setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>() as u32,
+        )
+    } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Convert a number of seconds or milliseconds to a socket option value.
+fn option_value(value: u64) -> Result<c_int, std::io::Error> {
+    c_int::try_from(value).map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("the socket option value {} is too large", value),
+    ))
+}
+
+/// Turn on TCP keepalive for a connected socket. The first probe is sent after the connection is
+/// idle for `idle`, and then every `interval`. The connection is dropped after `count` probes are
+/// unanswered. If `interval` or `count` is `None`, the system default is used.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_keepalive(
+    socket: &impl AsRawFd,
+    idle: Duration,
+    interval: Option<Duration>,
+    count: Option<u32>,
+) -> Result<(), std::io::Error> {
+    let fd = socket.as_raw_fd();
+    set_int_option(fd, SOL_SOCKET, SO_KEEPALIVE, 1)?;
+    set_int_option(fd, IPPROTO_TCP, TCP_KEEPIDLE, option_value(idle.as_secs())?)?;
+    if let Some(interval) = interval {
+        set_int_option(fd, IPPROTO_TCP, TCP_KEEPINTVL, option_value(interval.as_secs())?)?;
+    }
+    if let Some(count) = count {
+        set_int_option(fd, IPPROTO_TCP, TCP_KEEPCNT, option_value(u64::from(count))?)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_keepalive(
+    socket: &impl AsRawFd,
+    _idle: Duration,
+    _interval: Option<Duration>,
+    _count: Option<u32>,
+) -> Result<(), std::io::Error> {
+    // The idle time and the interval have other names on the other systems, so only turn
+    // keepalive on with the system defaults.
+    set_int_option(socket.as_raw_fd(), SOL_SOCKET, SO_KEEPALIVE, 1)
+}
+
+/// Set how long the data sent on a connected socket can stay unacknowledged before the
+/// connection is dropped.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_user_timeout(socket: &impl AsRawFd, timeout: Duration)
+    -> Result<(), std::io::Error>
+{
+    let timeout = option_value(timeout.as_millis() as u64)?;
+    set_int_option(socket.as_raw_fd(), IPPROTO_TCP, TCP_USER_TIMEOUT, timeout)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_user_timeout(_socket: &impl AsRawFd, _timeout: Duration)
+    -> Result<(), std::io::Error>
+{
+    Ok(()) // no op for mac build
+}
+
+/// Mark the packets sent on the socket bound to `addr` with the DSCP, which is the upper six bits
+/// of the IPv4 TOS and of the IPv6 traffic class. An IPv6 socket marks the IPv4 packets that it
+/// sends to the IPv4-mapped addresses too.
+pub fn set_dscp(socket: &impl AsRawFd, addr: &SocketAddr, dscp: u8)
+    -> Result<(), std::io::Error>
+{
+    let fd = socket.as_raw_fd();
+    let tos = c_int::from(dscp) << 2;
+    match addr {
+        V4(_) => set_int_option(fd, IPPROTO_IP, IP_TOS, tos),
+        V6(_) => {
+            set_int_option(fd, IPPROTO_IPV6, IPV6_TCLASS, tos)?;
+            // The IPv6-only sockets don't send any IPv4 packets, so it doesn't matter if it
+            // fails.
+            let _ = set_int_option(fd, IPPROTO_IP, IP_TOS, tos);
+            Ok(())
+        },
+    }
+}
+
+/// Create a listening TCP socket. If `reuse_port` is true, several sockets can be bound to the
+/// same address and the kernel will distribute the incoming connections among them.
+///
+/// For an IPv6 address, `v6only` sets whether the socket accepts only IPv6 connections or also
+/// IPv4 connections as IPv4-mapped addresses. If it's `None`, the system default is used.
+///
+/// If systemd passed a socket for `addr`, it's used as is and the options are ignored.
+pub fn tcp_listener(addr: &SocketAddr, reuse_port: bool, v6only: Option<bool>)
+    -> Result<std::net::TcpListener, std::io::Error>
+{
+    if let Some(listener) = systemd::tcp_listener(addr)? {
+        return Ok(listener);
+    }
+
     let builder = match addr {
         V4(_) => TcpBuilder::new_v4()?,
         V6(_) => TcpBuilder::new_v6()?,
     };
     builder.reuse_address(true)?;
+    if reuse_port {
+        builder.reuse_port(true)?;
+    }
+    if let (V6(_), Some(v6only)) = (addr, v6only) {
+        builder.only_v6(v6only)?;
+    }
     set_freebind(builder.as_raw_fd())?;
     builder.bind(addr)?;
     builder.listen(128)
 }
 
-pub fn udp_listen(addr: &SocketAddr) -> Result<std::net::UdpSocket, std::io::Error> {
+/// Create a bound UDP socket. If `reuse_port` is true, several sockets can be bound to the same
+/// address and the kernel will distribute the incoming packets among them. See `tcp_listener` for
+/// the meaning of `v6only` and for the sockets passed by systemd.
+pub fn udp_listen(addr: &SocketAddr, reuse_port: bool, v6only: Option<bool>)
+    -> Result<std::net::UdpSocket, std::io::Error>
+{
+    if let Some(socket) = systemd::udp_socket(addr)? {
+        return Ok(socket);
+    }
+
     let builder = match addr {
         V4(_) => UdpBuilder::new_v4()?,
         V6(_) => UdpBuilder::new_v6()?,
     };
     builder.reuse_address(true)?;
+    if reuse_port {
+        builder.reuse_port(true)?;
+    }
+    if let (V6(_), Some(v6only)) = (addr, v6only) {
+        builder.only_v6(v6only)?;
+    }
     set_freebind(builder.as_raw_fd())?;
     builder.bind(addr)
 }
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(fd: c_int, interface: &str) -> Result<(), std::io::Error> {
+    match unsafe {
+        setsockopt(
+            fd,
+            SOL_SOCKET,
+            SO_BINDTODEVICE,
+            interface.as_ptr() as *const c_void,
+            interface.len() as u32,
+        )
+    } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_fd: c_int, _interface: &str) -> Result<(), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "binding a socket to an interface is only supported on Linux",
+    ))
+}
+
+/// Create a UDP socket that sends to the broadcast address or the multicast group `addr` out of
+/// `interface` only. The multicast packets are sent with the TTL or the hop limit `ttl`.
+pub fn udp_broadcast(interface: &str, addr: &SocketAddr, ttl: u32)
+    -> Result<std::net::UdpSocket, std::io::Error>
+{
+    let socket = match addr {
+        V4(_) => std::net::UdpSocket::bind("0.0.0.0:0")?,
+        V6(_) => std::net::UdpSocket::bind("[::]:0")?,
+    };
+    let fd = socket.as_raw_fd();
+    bind_to_device(fd, interface)?;
+    match addr {
+        V4(_) => {
+            socket.set_broadcast(true)?;
+            socket.set_multicast_ttl_v4(ttl)?;
+        },
+        V6(_) => set_int_option(fd, IPPROTO_IPV6, IPV6_MULTICAST_HOPS, option_value(ttl.into())?)?,
+    }
+    Ok(socket)
+}
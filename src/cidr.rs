@@ -0,0 +1,217 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! CIDR address block representation.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use crate::error::WrapError;
+
+/// Return the address itself, or the IPv4 address if it's an IPv4-mapped IPv6 address. A socket
+/// listening on an IPv6 wildcard address sees IPv4 clients as IPv4-mapped addresses.
+pub fn canonical_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(high) << 16 | u32::from(low)))
+            },
+            _ => IpAddr::V6(v6),
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Return the address that a client is tracked by: its IPv4 address, or the /64 network of its
+/// IPv6 address, since a single IPv6 host usually has a whole /64 to pick addresses from.
+pub fn client_network(addr: IpAddr) -> IpAddr {
+    match canonical_ip(addr) {
+        IpAddr::V6(v6) => mask(IpAddr::V6(v6), 64),
+        v4 => v4,
+    }
+}
+
+/// Parse a list of address blocks. It's empty if `key` is not specified.
+pub fn get_cidr_list(settings: &config::Config, key: &str)
+    -> Result<Vec<Cidr>, config::ConfigError>
+{
+    match settings.get_array(key) {
+        Err(config::ConfigError::NotFound(_)) => Ok(Vec::new()),
+        Err(error) => Err(error),
+        Ok(values) => {
+            let mut blocks = Vec::new();
+            for value in values {
+                blocks.push(value.into_str()?.parse::<Cidr>().wrap_err()?);
+            }
+            Ok(blocks)
+        },
+    }
+}
+
+/// An IPv4 or IPv6 address block, for example, `192.0.2.0/24` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cidr {
+    /// The network address. The bits after the prefix are always zero.
+    addr: IpAddr,
+
+    /// The number of leading bits that belong to the network.
+    prefix_len: u8,
+}
+
+/// Keep only the first `prefix_len` bits of `bytes`.
+fn mask_bytes(bytes: &mut [u8], prefix_len: u8) {
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        let bit_offset = index * 8;
+        let prefix_len = usize::from(prefix_len);
+        if prefix_len <= bit_offset {
+            *byte = 0;
+        } else if prefix_len < bit_offset + 8 {
+            *byte &= 0xffu8 << (8 - (prefix_len - bit_offset));
+        }
+    }
+}
+
+/// Return the address with only the first `prefix_len` bits kept.
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mut bytes = v4.octets();
+            mask_bytes(&mut bytes, prefix_len);
+            IpAddr::from(bytes)
+        },
+        IpAddr::V6(v6) => {
+            let mut bytes = v6.octets();
+            mask_bytes(&mut bytes, prefix_len);
+            IpAddr::from(bytes)
+        },
+    }
+}
+
+impl Cidr {
+    /// Create an address block. The bits after the prefix in `addr` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the prefix length is longer than the address.
+    ///
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Cidr, CidrParseError> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(CidrParseError(format!("prefix length {} is too long", prefix_len)));
+        }
+
+        Ok(Cidr {
+            addr: mask(addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    /// Return true if the address is in this block. IPv4-mapped IPv6 addresses are treated as
+    /// IPv4 addresses.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = canonical_ip(*addr);
+
+        match (self.addr, addr) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask(addr, self.prefix_len) == self.addr
+            },
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Error returned when a string is not a valid address block.
+#[derive(Clone, Debug)]
+pub struct CidrParseError(String);
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid address block: {}", self.0)
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    /// Parse an address block. An address without a prefix length is a block containing only
+    /// that address.
+    fn from_str(s: &str) -> Result<Cidr, CidrParseError> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|_| CidrParseError(String::from(s)))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse()
+                .map_err(|_| CidrParseError(String::from(s)))?,
+            None => match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            },
+        };
+
+        Cidr::new(addr, prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let block: Cidr = "192.0.2.0/24".parse().unwrap();
+        assert!(block.contains(&ip("192.0.2.1")));
+        assert!(block.contains(&ip("192.0.2.255")));
+        assert!(!block.contains(&ip("192.0.3.1")));
+        assert!(block.contains(&ip("::ffff:192.0.2.7")));
+        assert!(!block.contains(&ip("2001:db8::1")));
+
+        let block: Cidr = "2001:db8::/33".parse().unwrap();
+        assert!(block.contains(&ip("2001:db8:7fff::1")));
+        assert!(!block.contains(&ip("2001:db8:8000::1")));
+
+        let single: Cidr = "198.51.100.10".parse().unwrap();
+        assert!(single.contains(&ip("198.51.100.10")));
+        assert!(!single.contains(&ip("198.51.100.11")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn test_client_network() {
+        assert_eq!(client_network(ip("::ffff:192.0.2.7")), ip("192.0.2.7"));
+        assert_eq!(client_network(ip("2001:db8:0:1:aaaa::1")), ip("2001:db8:0:1::"));
+        assert_eq!(client_network(ip("2001:db8:0:1::2")), ip("2001:db8:0:1::"));
+    }
+
+    #[test]
+    fn test_cidr_parse_errors() {
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/abc".parse::<Cidr>().is_err());
+        assert!("example.com/24".parse::<Cidr>().is_err());
+
+        let block: Cidr = "192.0.2.77/24".parse().unwrap();
+        assert_eq!(block.to_string(), "192.0.2.0/24");
+    }
+}
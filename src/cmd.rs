@@ -12,18 +12,60 @@ fn create_clap_client_subcommand<'a, 'b>() -> App<'a, 'b> {
     let args = [
         // The hostname is always required and will immediately
         // follow the subcommand string.
-        Arg::with_name("host").index(1).required(true)
+        Arg::with_name("host").index(1).required_unless("server")
             .help("NTS server's hostname (do not include port)"),
+        Arg::with_name("server").long("server").takes_value(true).multiple(true)
+            .number_of_values(1).required(false)
+            .help("Also polls this NTS server, given as a hostname and an optional port, and \
+                   combines the servers that agree with each other. It can be repeated."),
 
         // The rest will be passed as unrequired command-line options.
         Arg::with_name("port").long("port").short("p").takes_value(true).required(false)
             .help("Specifies NTS server's port. The default port number is 1234."),
         Arg::with_name("cert").long("cert").short("c").takes_value(true).required(false)
             .help("Specifies a path to the trusted certificate in PEM format."),
+        Arg::with_name("cacert").long("cacert").takes_value(true).required(false)
+            .help("Trusts the CA certificates of this PEM bundle, or of the PEM files in this \
+                   directory, instead of the built-in roots"),
+        Arg::with_name("pin-sha256").long("pin-sha256").takes_value(true).multiple(true)
+            .number_of_values(1).required(false)
+            .help("Only trusts the NTS-KE server if its verified chain has a public key whose \
+                   base64 SHA-256 hash of the SubjectPublicKeyInfo is this. It can be repeated."),
+        Arg::with_name("insecure").long("insecure").required(false)
+            .conflicts_with_all(&["cert", "cacert", "pin-sha256"])
+            .help("Does not verify the certificate of the NTS-KE server at all, for testing \
+                   against self-signed servers. Anyone on the path can impersonate the server."),
         Arg::with_name("ipv4").long("ipv4").short("4").conflicts_with("ipv6")
             .help("Forces use of IPv4 only"),
         Arg::with_name("ipv6").long("ipv6").short("6").conflicts_with("ipv4")
             .help("Forces use of IPv6 only"),
+        Arg::with_name("keylog").long("keylog").required(false)
+            .help("Logs the TLS secrets to the file named by the SSLKEYLOGFILE environment \
+                   variable"),
+        Arg::with_name("output").long("output").short("o").takes_value(true).required(false)
+            .possible_values(&["text", "json"]).conflicts_with("daemon")
+            .help("Prints the measurement as text, which is the default, or as a JSON record"),
+        Arg::with_name("samples").long("samples").short("n").takes_value(true).required(false)
+            .conflicts_with("daemon")
+            .help("Polls each server this many times, a second apart, and keeps the response \
+                   with the lowest delay. The default is 1."),
+        Arg::with_name("daemon").long("daemon").required(false)
+            .help("Keeps polling the server on an adaptive interval, and steers the system clock \
+                   with the filtered offsets"),
+        Arg::with_name("no-set").long("no-set").required(false).requires("daemon")
+            .help("Only logs the offsets in daemon mode, without steering the clock"),
+        Arg::with_name("step-threshold").long("step-threshold").takes_value(true)
+            .required(false).requires("daemon")
+            .help("Steps the clock when the offset is at least this many seconds, and slews it \
+                   otherwise. The default is 0.128."),
+        Arg::with_name("step-limit").long("step-limit").takes_value(true)
+            .required(false).requires("daemon")
+            .help("Only steps the clock in this many first clock updates, and slews it \
+                   afterwards. By default, it can always be stepped."),
+        Arg::with_name("drift-file").long("drift-file").takes_value(true)
+            .required(false).requires("daemon")
+            .help("Keeps the frequency error of the clock in this file, so that it's corrected \
+                   right away after a restart"),
     ];
 
     // Create a new subcommand.
@@ -57,7 +99,16 @@ fn create_clap_ntp_server_subcommand<'a, 'b>() -> App<'a, 'b> {
             .takes_value(true).required(false)
             .help("Specifies a path to the configuration file. If the path is not specified, \
                    the system-wide configuration file (/etc/cfnts/ntp-server.config) will be \
-                   used instead")
+                   used instead"),
+        Arg::with_name("pcap").long("pcap").takes_value(true).required(false)
+            .help("Writes the NTP packets that are received and sent to a pcap file, for \
+                   debugging"),
+        Arg::with_name("pcap-count").long("pcap-count").takes_value(true).required(false)
+            .requires("pcap")
+            .help("Stops the capture after this many packets. The default is 10000."),
+        Arg::with_name("pcap-duration").long("pcap-duration").takes_value(true).required(false)
+            .requires("pcap")
+            .help("Stops the capture after this many seconds. The default is 60."),
     ];
 
     // Create a new subcommand.
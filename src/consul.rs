@@ -0,0 +1,223 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A minimal Consul client which reads and writes keys in the KV store, and watches a key prefix
+//! with blocking queries. See https://www.consul.io/api-docs/kv.
+//!
+//! The ACL token is read from a file every time that it's needed, so that it can be renewed by
+//! another process. Without the file, `CONSUL_HTTP_TOKEN` is used, if it's set.
+
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::http::{self, HttpError};
+
+/// How long a request to read or write a key can take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the server holds a blocking query when nothing changes.
+const WATCH_WAIT: &str = "5m";
+
+/// How long a blocking query can take. The server adds up to 1/16 of the wait time at random.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(5 * 60 + 30);
+
+/// Error returned from the Consul client.
+#[derive(Debug)]
+pub enum ConsulError {
+    /// Error from the HTTP connection to the server.
+    Http(HttpError),
+    /// The server replied with an unexpected status.
+    Status(u16, String),
+    /// The server sent something that we don't understand.
+    Protocol(String),
+    /// The token file cannot be read.
+    Token(String),
+}
+
+impl fmt::Display for ConsulError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConsulError::Http(error) => write!(f, "consul {}", error),
+            ConsulError::Status(status, body) => {
+                write!(f, "consul server replied with status {}: {}", status, body)
+            },
+            ConsulError::Protocol(message) => write!(f, "consul protocol error: {}", message),
+            ConsulError::Token(message) => write!(f, "consul token error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConsulError {}
+
+impl From<HttpError> for ConsulError {
+    fn from(error: HttpError) -> ConsulError {
+        ConsulError::Http(error)
+    }
+}
+
+impl From<std::io::Error> for ConsulError {
+    fn from(error: std::io::Error) -> ConsulError {
+        ConsulError::Http(HttpError::Io(error))
+    }
+}
+
+/// Return the index to send with the next blocking query, given the index of the last one and
+/// the one that the server returned, and whether the keys changed. The index is reset if it goes
+/// backwards, for example, after the server restores a snapshot.
+fn next_index(last: u64, returned: u64) -> (u64, bool) {
+    if returned < last {
+        (0, true)
+    } else {
+        // The first query only learns the current index.
+        (returned, last != 0 && returned != last)
+    }
+}
+
+/// A client of a Consul agent or server.
+#[derive(Clone, Debug)]
+pub struct Client {
+    /// The url of the server without the trailing slash, for example, `http://localhost:8500`.
+    url: String,
+
+    /// The file that holds the ACL token.
+    token_file: Option<PathBuf>,
+}
+
+impl Client {
+    /// Create a client of the server at `url`. It doesn't connect to the server until a request
+    /// is made.
+    pub fn new(url: &str, token_file: Option<PathBuf>) -> Client {
+        Client {
+            url: String::from(url.trim_end_matches('/')),
+            token_file,
+        }
+    }
+
+    /// Return the current token, if any.
+    fn token(&self) -> Result<Option<String>, ConsulError> {
+        let token = match &self.token_file {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|error| ConsulError::Token(format!("{}: {}", path.display(), error)))?,
+            None => match std::env::var("CONSUL_HTTP_TOKEN") {
+                Ok(token) => token,
+                Err(_) => return Ok(None),
+            },
+        };
+        Ok(Some(String::from(token.trim())))
+    }
+
+    /// Return the url of `key` in the KV store with the `query`. The keys don't start with a
+    /// slash in Consul.
+    fn kv_url(&self, key: &str, query: &str) -> String {
+        format!("{}/v1/kv/{}?{}", self.url, key.trim_start_matches('/'), query)
+    }
+
+    /// Send a request and return the response. The body is not read yet.
+    fn send(&self, method: &str, url: &str, body: &[u8], read_timeout: Duration)
+        -> Result<http::Response, ConsulError>
+    {
+        let token = self.token()?;
+        let headers = match &token {
+            Some(token) => vec![("X-Consul-Token", token.as_str())],
+            None => Vec::new(),
+        };
+        Ok(http::send(method, url, &headers, body, Some(read_timeout))?)
+    }
+
+    /// Check that the cluster has a leader.
+    pub fn health(&self) -> Result<(), ConsulError> {
+        let mut response = self.send("GET", &format!("{}/v1/status/leader", self.url), &[],
+                                     REQUEST_TIMEOUT)?;
+        let mut body = String::new();
+        response.body.read_to_string(&mut body)?;
+        // The reply is the address of the leader in JSON, or an empty string without a leader.
+        if response.status != 200 || body.trim() == "\"\"" {
+            return Err(ConsulError::Status(response.status, body));
+        }
+        Ok(())
+    }
+
+    /// Return the value of `key`, if it exists.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ConsulError> {
+        let mut response = self.send("GET", &self.kv_url(key, "raw"), &[], REQUEST_TIMEOUT)?;
+        let mut body = Vec::new();
+        response.body.read_to_end(&mut body)?;
+        match response.status {
+            200 => Ok(Some(body)),
+            404 => Ok(None),
+            status => Err(ConsulError::Status(status, String::from_utf8_lossy(&body).into_owned())),
+        }
+    }
+
+    /// Set the value of `key`.
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<(), ConsulError> {
+        let mut response = self.send("PUT", &self.kv_url(key, ""), value, REQUEST_TIMEOUT)?;
+        let mut body = String::new();
+        response.body.read_to_string(&mut body)?;
+        // The server replies with `false` if the write is rejected.
+        if response.status != 200 || body.trim() != "true" {
+            return Err(ConsulError::Status(response.status, body));
+        }
+        Ok(())
+    }
+
+    /// Watch all the keys starting with `prefix` with blocking queries, and call `on_change`
+    /// every time that some of them change. It blocks until a query fails, which is always an
+    /// error.
+    pub fn watch_prefix<F: FnMut()>(&self, prefix: &str, mut on_change: F) -> ConsulError {
+        let mut index = 0;
+        loop {
+            let query = format!("recurse=true&keys=true&index={}&wait={}", index, WATCH_WAIT);
+            let mut response = match self.send("GET", &self.kv_url(prefix, &query), &[],
+                                               WATCH_TIMEOUT) {
+                Ok(response) => response,
+                Err(error) => return error,
+            };
+            // The body only lists the keys, and there is none if the prefix is empty.
+            let mut body = Vec::new();
+            if let Err(error) = response.body.read_to_end(&mut body) {
+                return error.into();
+            }
+            if response.status != 200 && response.status != 404 {
+                return ConsulError::Status(response.status,
+                                           String::from_utf8_lossy(&body).into_owned());
+            }
+
+            let returned = match response.header("X-Consul-Index").map(str::parse::<u64>) {
+                Some(Ok(returned)) => returned,
+                _ => return ConsulError::Protocol(String::from("invalid X-Consul-Index")),
+            };
+            let (next, changed) = next_index(index, returned);
+            if changed {
+                on_change();
+            }
+            index = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_index() {
+        // The first query doesn't report a change.
+        assert_eq!(next_index(0, 10), (10, false));
+        // The query times out without changes.
+        assert_eq!(next_index(10, 10), (10, false));
+        assert_eq!(next_index(10, 12), (12, true));
+        // The index goes backwards.
+        assert_eq!(next_index(12, 3), (0, true));
+    }
+
+    #[test]
+    fn test_kv_url() {
+        let client = Client::new("http://consul:8500/", None);
+        assert_eq!(client.kv_url("/nts/nts-keys/3600", "raw"),
+                   "http://consul:8500/v1/kv/nts/nts-keys/3600?raw");
+    }
+}
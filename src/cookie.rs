@@ -12,14 +12,29 @@ use std::io;
 use std::io::Read;
 
 use crate::key_rotator::KeyId;
+use crate::nts_ke::records::KnownAeadAlgorithm;
 
-pub const COOKIE_SIZE: usize = 100;
+/// The length of the key id, the nonce, and the AES-SIV tag of a cookie.
+const COOKIE_OVERHEAD: usize = 36;
+
+/// The keys of an NTS association. Only the first `aead.key_len()` bytes of each key are used.
 #[derive(Debug, Copy, Clone)]
 pub struct NTSKeys {
+    pub aead: KnownAeadAlgorithm,
     pub c2s: [u8; 32],
     pub s2c: [u8; 32],
 }
 
+/// The length of the AEAD algorithm id at the start of the plaintext of a cookie, with the two
+/// bytes of padding after it that keep the cookie a multiple of four bytes long, as the NTP
+/// extension fields have to be.
+const ALGORITHM_FIELD_LEN: usize = 4;
+
+/// Return the length of the cookies that carry the keys for `aead`.
+pub fn cookie_size(aead: KnownAeadAlgorithm) -> usize {
+    COOKIE_OVERHEAD + ALGORITHM_FIELD_LEN + 2 * aead.key_len()
+}
+
 /// Cookie key.
 #[derive(Clone, Debug)]
 pub struct CookieKey(Vec<u8>);
@@ -45,6 +60,12 @@ impl CookieKey {
     }
 }
 
+impl From<Vec<u8>> for CookieKey {
+    fn from(bytes: Vec<u8>) -> CookieKey {
+        CookieKey(bytes)
+    }
+}
+
 // Only used in test.
 #[cfg(test)]
 impl From<&[u8]> for CookieKey {
@@ -56,13 +77,14 @@ impl From<&[u8]> for CookieKey {
 pub fn make_cookie(keys: NTSKeys, master_key: &[u8], key_id: KeyId) -> Vec<u8> {
     let mut nonce = [0; 16];
     rand::thread_rng().fill(&mut nonce);
-    let mut plaintext = [0; 64];
-    for i in 0..32 {
-        plaintext[i] = keys.c2s[i];
-    }
-    for i in 0..32 {
-        plaintext[32 + i] = keys.s2c[i];
-    }
+    // The plaintext starts with the AEAD algorithm id so that the NTP server knows which
+    // algorithm the keys are for.
+    let key_len = keys.aead.key_len();
+    let mut plaintext = Vec::with_capacity(ALGORITHM_FIELD_LEN + 2 * key_len);
+    plaintext.extend(&keys.aead.as_algorithm_id().to_be_bytes());
+    plaintext.extend(&[0; ALGORITHM_FIELD_LEN - 2]);
+    plaintext.extend(&keys.c2s[..key_len]);
+    plaintext.extend(&keys.s2c[..key_len]);
     let mut aead = aead::Aes128SivAead::new(&master_key);
     let mut ciphertext = aead.seal(&nonce, &[], &plaintext);
     let mut out = Vec::new();
@@ -81,19 +103,29 @@ pub fn get_keyid(cookie: &[u8]) -> Option<KeyId> {
 }
 
 fn unpack(pt: Vec<u8>) -> Option<NTSKeys> {
-    if pt.len() != 64 {
-        return None;
+    // The cookies made before the algorithm id was added have only the AES-SIV-CMAC-256 keys.
+    let (aead, pt) = if pt.len() == 64 {
+        (KnownAeadAlgorithm::AeadAesSivCmac256, &pt[..])
+    } else if pt.len() >= ALGORITHM_FIELD_LEN {
+        let id = u16::from_be_bytes([pt[0], pt[1]]);
+        (KnownAeadAlgorithm::from_algorithm_id(id)?, &pt[ALGORITHM_FIELD_LEN..])
     } else {
-        let mut key = NTSKeys {
-            c2s: [0; 32],
-            s2c: [0; 32],
-        };
-        for i in 0..32 {
-            key.c2s[i] = pt[i];
-            key.s2c[i] = pt[32 + i];
-        }
-        return Some(key);
+        return None;
+    };
+
+    let key_len = aead.key_len();
+    if pt.len() != 2 * key_len {
+        return None;
     }
+
+    let mut key = NTSKeys {
+        aead,
+        c2s: [0; 32],
+        s2c: [0; 32],
+    };
+    key.c2s[..key_len].copy_from_slice(&pt[..key_len]);
+    key.s2c[..key_len].copy_from_slice(&pt[key_len..]);
+    Some(key)
 }
 
 pub fn eat_cookie(cookie: &[u8], key: &[u8]) -> Option<NTSKeys> {
@@ -114,6 +146,7 @@ mod tests {
     use super::*;
 
     fn check_eq(a: NTSKeys, b: NTSKeys) {
+        assert_eq!(a.aead, b.aead);
         for i in 0..32 {
             assert_eq!(a.c2s[i], b.c2s[i]);
             assert_eq!(a.s2c[i], b.s2c[i]);
@@ -123,6 +156,7 @@ mod tests {
     #[test]
     fn check_cookie() {
         let test = NTSKeys {
+            aead: KnownAeadAlgorithm::AeadAesSivCmac256,
             s2c: [9; 32],
             c2s: [10; 32],
         };
@@ -132,7 +166,8 @@ mod tests {
         let mut cookie = make_cookie(test, &master_key, key_id);
         let ret = get_keyid(&cookie);
 
-        assert_eq!(cookie.len(), COOKIE_SIZE);
+        assert_eq!(cookie.len(), cookie_size(test.aead));
+        assert_eq!(cookie.len() % 4, 0);
         match ret {
             None => assert!(false),
             Some(id) => assert_eq!(id, key_id),
@@ -152,4 +187,24 @@ mod tests {
             Some(_) => assert!(false),
         }
     }
+
+    #[test]
+    fn check_cookie_gcm_siv() {
+        let mut test = NTSKeys {
+            aead: KnownAeadAlgorithm::AeadAes128GcmSiv,
+            s2c: [0; 32],
+            c2s: [0; 32],
+        };
+        test.s2c[..16].copy_from_slice(&[9; 16]);
+        test.c2s[..16].copy_from_slice(&[10; 16]);
+
+        let master_key = [0x07; 32];
+        let key_id = KeyId::from_be_bytes([0x03; 4]);
+        let cookie = make_cookie(test, &master_key, key_id);
+        assert_eq!(cookie.len(), cookie_size(test.aead));
+        assert_eq!(cookie.len() % 4, 0);
+
+        let keys = eat_cookie(&cookie, &master_key).unwrap();
+        check_eq(keys, test);
+    }
 }
@@ -0,0 +1,340 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A minimal etcd v3 client which talks to the JSON gateway of the server. It can read and write a
+//! key, and watch a key prefix for changes.
+//! See https://etcd.io/docs/v3.4.0/dev-guide/api_grpc_gateway/.
+
+use serde_json::{json, Value};
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::http::{self, HttpError};
+
+/// How long a request to read or write a key can take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the watch stream can be silent before it's opened again. The connection can die
+/// without us noticing, so we don't wait forever. The new stream starts from the revision where
+/// the old one stopped, so no change is missed.
+const WATCH_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Error returned from the etcd client.
+#[derive(Debug)]
+pub enum EtcdError {
+    /// Error from the HTTP connection to the server.
+    Http(HttpError),
+    /// The server replied with a status other than 200.
+    Status(u16, String),
+    /// The server sent something that we don't understand.
+    Protocol(String),
+}
+
+impl fmt::Display for EtcdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EtcdError::Http(error) => write!(f, "etcd {}", error),
+            EtcdError::Status(status, body) => {
+                write!(f, "etcd server replied with status {}: {}", status, body)
+            },
+            EtcdError::Protocol(message) => write!(f, "etcd protocol error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for EtcdError {}
+
+impl From<HttpError> for EtcdError {
+    fn from(error: HttpError) -> EtcdError {
+        EtcdError::Http(error)
+    }
+}
+
+impl From<std::io::Error> for EtcdError {
+    fn from(error: std::io::Error) -> EtcdError {
+        EtcdError::Http(HttpError::Io(error))
+    }
+}
+
+/// Return the end of the key range that holds all the keys starting with `prefix`. It's the
+/// prefix with its last byte incremented, after dropping the trailing `0xff` bytes.
+fn prefix_range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every byte is 0xff, so the range has no end. etcd uses "\0" for that.
+    vec![0]
+}
+
+/// Decode a base64 string field of a JSON object.
+fn decode_field(object: &Value, field: &str) -> Result<Option<Vec<u8>>, EtcdError> {
+    match object.get(field) {
+        None => Ok(None),
+        Some(Value::String(value)) => base64::decode(value)
+            .map(Some)
+            .map_err(|_| EtcdError::Protocol(format!("{} is not base64", field))),
+        Some(_) => Err(EtcdError::Protocol(format!("{} is not a string", field))),
+    }
+}
+
+/// Return the value of the first key in a range response, if any.
+fn parse_range_response(response: &Value) -> Result<Option<Vec<u8>>, EtcdError> {
+    // The gateway omits the empty fields, so `kvs` is missing when the key doesn't exist.
+    match response.get("kvs").and_then(|kvs| kvs.get(0)) {
+        // etcd also omits the empty value.
+        Some(kv) => Ok(Some(decode_field(kv, "value")?.unwrap_or_default())),
+        None => Ok(None),
+    }
+}
+
+/// What a message in the watch stream says.
+#[derive(Debug, Eq, PartialEq)]
+enum WatchMessage {
+    /// The watch is created or some keys changed, without any events to act on.
+    Idle,
+    /// Some keys under the prefix changed.
+    Changed,
+    /// The server cancelled the watch.
+    Canceled(String),
+}
+
+/// Return the revision of the store when a message in the watch stream was sent. The gateway
+/// sends the 64-bit integers as strings.
+fn parse_watch_revision(message: &Value) -> Option<u64> {
+    match message.get("result")?.get("header")?.get("revision")? {
+        Value::String(revision) => revision.parse().ok(),
+        revision => revision.as_u64(),
+    }
+}
+
+/// Return true if reading the watch stream failed because it was silent for too long.
+fn is_timeout(error: serde_json::Error) -> bool {
+    if !error.is_io() {
+        return false;
+    }
+    match std::io::Error::from(error).kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => true,
+        _ => false,
+    }
+}
+
+/// Parse a message in the watch stream.
+fn parse_watch_message(message: &Value) -> Result<WatchMessage, EtcdError> {
+    if let Some(error) = message.get("error") {
+        return Err(EtcdError::Protocol(format!("watch failed: {}", error)));
+    }
+    let result = message.get("result")
+        .ok_or_else(|| EtcdError::Protocol(String::from("the watch message has no result")))?;
+
+    if result.get("canceled").and_then(Value::as_bool).unwrap_or(false) {
+        let reason = result.get("cancel_reason").and_then(Value::as_str).unwrap_or("");
+        return Ok(WatchMessage::Canceled(String::from(reason)));
+    }
+    match result.get("events").and_then(Value::as_array) {
+        Some(events) if !events.is_empty() => Ok(WatchMessage::Changed),
+        _ => Ok(WatchMessage::Idle),
+    }
+}
+
+/// A client of an etcd server.
+#[derive(Clone, Debug)]
+pub struct Client {
+    /// The url of the server without the trailing slash, for example, `http://localhost:2379`.
+    url: String,
+
+    /// The HTTP client, which verifies an `https` server with its CAs.
+    http: http::Client,
+}
+
+impl Client {
+    /// Create a client of the server at `url`. It doesn't connect to the server until a request
+    /// is made.
+    pub fn new(url: &str, http: http::Client) -> Client {
+        Client { url: String::from(url.trim_end_matches('/')), http }
+    }
+
+    /// Check that the server is healthy, which means that the cluster has a leader.
+    pub fn health(&self) -> Result<(), EtcdError> {
+        let (status, body) = self.http.send_and_read(
+            "GET",
+            &format!("{}/health", self.url),
+            &[],
+            &[],
+            REQUEST_TIMEOUT,
+        )?;
+        let response: Value = serde_json::from_slice(&body)
+            .map_err(|error| EtcdError::Protocol(error.to_string()))?;
+        match response.get("health").and_then(Value::as_str) {
+            Some("true") if status == 200 => Ok(()),
+            _ => Err(EtcdError::Status(status, String::from_utf8_lossy(&body).into_owned())),
+        }
+    }
+
+    /// Return the value of `key`, if it exists.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, EtcdError> {
+        let request = json!({ "key": base64::encode(key) });
+        let (status, body) = self.http.send_and_read(
+            "POST",
+            &format!("{}/v3/kv/range", self.url),
+            &[("Content-Type", "application/json")],
+            request.to_string().as_bytes(),
+            REQUEST_TIMEOUT,
+        )?;
+        if status != 200 {
+            return Err(EtcdError::Status(status, String::from_utf8_lossy(&body).into_owned()));
+        }
+
+        let response: Value = serde_json::from_slice(&body)
+            .map_err(|error| EtcdError::Protocol(error.to_string()))?;
+        parse_range_response(&response)
+    }
+
+    /// Set the value of `key`.
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<(), EtcdError> {
+        let request = json!({ "key": base64::encode(key), "value": base64::encode(value) });
+        let (status, body) = self.http.send_and_read(
+            "POST",
+            &format!("{}/v3/kv/put", self.url),
+            &[("Content-Type", "application/json")],
+            request.to_string().as_bytes(),
+            REQUEST_TIMEOUT,
+        )?;
+        if status != 200 {
+            return Err(EtcdError::Status(status, String::from_utf8_lossy(&body).into_owned()));
+        }
+        Ok(())
+    }
+
+    /// Watch all the keys starting with `prefix`, and call `on_change` every time that some of
+    /// them change. It blocks until the watch ends, which is always an error because a watch
+    /// doesn't end by itself. If the stream is silent for too long, it's opened again from the
+    /// last revision that it has seen.
+    pub fn watch_prefix<F: FnMut()>(&self, prefix: &str, mut on_change: F) -> EtcdError {
+        let mut revision = None;
+        loop {
+            if let Err(error) = self.watch_stream(prefix, &mut revision, &mut on_change) {
+                return error;
+            }
+        }
+    }
+
+    /// Open a watch stream, starting after `revision` if it's known, and read it until it's
+    /// silent for `WATCH_READ_TIMEOUT`. `revision` is updated as the stream goes.
+    fn watch_stream<F: FnMut()>(&self, prefix: &str, revision: &mut Option<u64>, on_change: &mut F)
+        -> Result<(), EtcdError>
+    {
+        let mut request = json!({
+            "create_request": {
+                "key": base64::encode(prefix),
+                "range_end": base64::encode(&prefix_range_end(prefix.as_bytes())),
+                // The server sends the current revision now and then, even if nothing changes.
+                "progress_notify": true,
+            }
+        });
+        if let Some(revision) = *revision {
+            request["create_request"]["start_revision"] = json!((revision + 1).to_string());
+        }
+        let response = self.http.send(
+            "POST",
+            &format!("{}/v3/watch", self.url),
+            &[("Content-Type", "application/json")],
+            request.to_string().as_bytes(),
+            Some(WATCH_READ_TIMEOUT),
+        )?;
+        if response.status != 200 {
+            return Err(EtcdError::Status(response.status, String::new()));
+        }
+
+        // The gateway sends a JSON object for every message in the stream.
+        let messages = serde_json::Deserializer::from_reader(response.body).into_iter::<Value>();
+        for message in messages {
+            let message = match message {
+                Ok(message) => message,
+                Err(error) => {
+                    let message = error.to_string();
+                    if is_timeout(error) {
+                        return Ok(());
+                    }
+                    return Err(EtcdError::Protocol(message));
+                },
+            };
+            match parse_watch_message(&message)? {
+                WatchMessage::Idle => (),
+                WatchMessage::Changed => on_change(),
+                WatchMessage::Canceled(reason) => {
+                    return Err(EtcdError::Protocol(format!("the watch is canceled: {}", reason)));
+                },
+            }
+            if let Some(message_revision) = parse_watch_revision(&message) {
+                *revision = Some(message_revision);
+            }
+        }
+        Err(EtcdError::Protocol(String::from("the watch stream ends")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_range_end() {
+        assert_eq!(prefix_range_end(b"/nts/nts-keys/"), b"/nts/nts-keys0".to_vec());
+        assert_eq!(prefix_range_end(b"a\xff\xff"), b"b".to_vec());
+        assert_eq!(prefix_range_end(b"\xff"), vec![0]);
+        assert_eq!(prefix_range_end(b""), vec![0]);
+    }
+
+    #[test]
+    fn test_parse_range_response() {
+        let response = json!({
+            "header": { "revision": "7" },
+            "kvs": [{ "key": "L250cy9udHMta2V5cy8zNjAw", "value": "c2VjcmV0" }],
+            "count": "1",
+        });
+        assert_eq!(parse_range_response(&response).unwrap(), Some(b"secret".to_vec()));
+
+        let response = json!({ "header": { "revision": "7" } });
+        assert_eq!(parse_range_response(&response).unwrap(), None);
+
+        let response = json!({ "kvs": [{ "key": "YQ==" }] });
+        assert_eq!(parse_range_response(&response).unwrap(), Some(Vec::new()));
+
+        let response = json!({ "kvs": [{ "key": "YQ==", "value": "!!" }] });
+        assert!(parse_range_response(&response).is_err());
+    }
+
+    #[test]
+    fn test_parse_watch_message() {
+        let message = json!({ "result": { "header": {}, "created": true } });
+        assert_eq!(parse_watch_message(&message).unwrap(), WatchMessage::Idle);
+
+        let message = json!({ "result": { "events": [{ "kv": { "key": "YQ==" } }] } });
+        assert_eq!(parse_watch_message(&message).unwrap(), WatchMessage::Changed);
+
+        let message = json!({ "result": { "canceled": true, "cancel_reason": "compacted" } });
+        assert_eq!(parse_watch_message(&message).unwrap(),
+                   WatchMessage::Canceled(String::from("compacted")));
+
+        assert!(parse_watch_message(&json!({ "error": { "message": "boom" } })).is_err());
+        assert!(parse_watch_message(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_parse_watch_revision() {
+        let message = json!({ "result": { "header": { "revision": "42" }, "created": true } });
+        assert_eq!(parse_watch_revision(&message), Some(42));
+
+        let message = json!({ "result": { "header": { "revision": 42 } } });
+        assert_eq!(parse_watch_revision(&message), Some(42));
+
+        assert_eq!(parse_watch_revision(&json!({ "result": { "header": {} } })), None);
+        assert_eq!(parse_watch_revision(&json!({ "error": { "message": "boom" } })), None);
+    }
+}
@@ -0,0 +1,483 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A minimal blocking HTTP/1.1 client for talking to the key stores over their HTTP APIs.
+//!
+//! It only supports what the key stores need: one request per connection, `http` and `https`
+//! urls, and response bodies with a `Content-Length`, chunked, or ending with the connection.
+
+use lazy_static::lazy_static;
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long connecting and sending the request can take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum length of a line in the response head or a chunk header.
+const MAX_LINE_LEN: usize = 8192;
+
+lazy_static! {
+    /// The client that trusts the well-known roots, for the module-level functions.
+    static ref DEFAULT_CLIENT: Client = Client::with_roots(default_roots());
+}
+
+/// Error returned from the HTTP client.
+#[derive(Debug)]
+pub enum HttpError {
+    /// Error from the connection to the server.
+    Io(std::io::Error),
+    /// The server sent something that we don't understand.
+    Protocol(String),
+    /// The url is not a valid `http` or `https` url.
+    Url(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Io(error) => write!(f, "http connection error: {}", error),
+            HttpError::Protocol(message) => write!(f, "http protocol error: {}", message),
+            HttpError::Url(message) => write!(f, "invalid http url: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<std::io::Error> for HttpError {
+    fn from(error: std::io::Error) -> HttpError {
+        HttpError::Io(error)
+    }
+}
+
+fn protocol_error(message: &str) -> HttpError {
+    HttpError::Protocol(String::from(message))
+}
+
+/// A connection that is either plain TCP or TLS.
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// How the end of the response body is found.
+enum BodyLength {
+    /// The body has this many bytes left.
+    Fixed(usize),
+    /// The body is chunked. The value is the number of bytes left in the current chunk, or
+    /// `None` if the next chunk header has to be read.
+    Chunked(Option<usize>),
+    /// The body ends when the server closes the connection.
+    UntilClose,
+    /// The whole body is read.
+    Done,
+}
+
+/// The body of a response, which is read from the connection as it arrives.
+pub struct Body {
+    reader: BufReader<Box<dyn Connection>>,
+    length: BodyLength,
+}
+
+/// A response whose body is not read yet.
+pub struct Response {
+    /// The status code.
+    pub status: u16,
+    /// The headers with their names in lowercase.
+    pub headers: Vec<(String, String)>,
+    /// The body of the response.
+    pub body: Body,
+}
+
+impl Response {
+    /// Return the value of the first header named `name`, which is case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read a line without its CRLF.
+fn read_line(reader: &mut impl BufRead) -> Result<String, HttpError> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN as u64).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(protocol_error("the line is not terminated"));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| protocol_error("the line is not UTF-8"))
+}
+
+/// The status code and the headers of a response, and how its body ends.
+type Head = (u16, Vec<(String, String)>, BodyLength);
+
+/// Read the status line and the headers, and find out how the body ends.
+fn read_head(reader: &mut impl BufRead) -> Result<Head, HttpError> {
+    let status_line = read_line(reader)?;
+    let status = status_line.split(' ')
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| protocol_error("invalid status line"))?;
+
+    let mut headers = Vec::new();
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => return Err(protocol_error("invalid header")),
+        };
+
+        // Header names are case-insensitive.
+        if name.eq_ignore_ascii_case("content-length") {
+            let len = value.parse().map_err(|_| protocol_error("invalid content length"))?;
+            content_length = Some(len);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // The body is chunked if it's the last of the codings.
+            let last = value.rsplit(',').next().unwrap_or("").trim();
+            chunked = last.eq_ignore_ascii_case("chunked");
+        }
+        headers.push((name.to_ascii_lowercase(), String::from(value)));
+    }
+
+    // Transfer-Encoding takes precedence over Content-Length, whichever comes first.
+    let length = if chunked {
+        BodyLength::Chunked(None)
+    } else {
+        content_length.map_or(BodyLength::UntilClose, BodyLength::Fixed)
+    };
+    Ok((status, headers, length))
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let to_io_error = |error: HttpError| match error {
+            HttpError::Io(error) => error,
+            error => std::io::Error::new(std::io::ErrorKind::InvalidData, error),
+        };
+
+        loop {
+            let left = match self.length {
+                BodyLength::Done => return Ok(0),
+                BodyLength::UntilClose => return self.reader.read(buf),
+                BodyLength::Fixed(0) => {
+                    self.length = BodyLength::Done;
+                    return Ok(0);
+                },
+                BodyLength::Fixed(left) => left,
+                BodyLength::Chunked(None) => {
+                    let line = read_line(&mut self.reader).map_err(to_io_error)?;
+                    // Ignore the chunk extensions.
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = usize::from_str_radix(size, 16)
+                        .map_err(|_| to_io_error(protocol_error("invalid chunk size")))?;
+                    if size == 0 {
+                        // Skip the trailers.
+                        while !read_line(&mut self.reader).map_err(to_io_error)?.is_empty() {}
+                        self.length = BodyLength::Done;
+                        return Ok(0);
+                    }
+                    self.length = BodyLength::Chunked(Some(size));
+                    continue;
+                },
+                BodyLength::Chunked(Some(left)) => left,
+            };
+
+            let max = buf.len().min(left);
+            let read_count = self.reader.read(&mut buf[..max])?;
+            if read_count == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "the body ends early",
+                ));
+            }
+
+            self.length = match self.length {
+                BodyLength::Chunked(_) if read_count == left => {
+                    // Each chunk is followed by a CRLF.
+                    if !read_line(&mut self.reader).map_err(to_io_error)?.is_empty() {
+                        return Err(to_io_error(protocol_error("the chunk is too long")));
+                    }
+                    BodyLength::Chunked(None)
+                },
+                BodyLength::Chunked(_) => BodyLength::Chunked(Some(left - read_count)),
+                _ => BodyLength::Fixed(left - read_count),
+            };
+            return Ok(read_count);
+        }
+    }
+}
+
+/// Return the well-known roots of the web PKI.
+fn default_roots() -> rustls::RootCertStore {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    roots
+}
+
+/// Open a connection to the host of `url`.
+fn connect(
+    url: &url::Url,
+    read_timeout: Option<Duration>,
+    tls_config: &Arc<rustls::ClientConfig>,
+) -> Result<Box<dyn Connection>, HttpError> {
+    let host = url.host_str().ok_or_else(|| HttpError::Url(String::from("the host is missing")))?;
+    let port = url.port_or_known_default()
+        .ok_or_else(|| HttpError::Url(String::from("the port is missing")))?;
+
+    let addr = (host, port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| HttpError::Url(format!("cannot resolve {}", host)))?;
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(read_timeout)?;
+    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+
+    match url.scheme() {
+        "http" => Ok(Box::new(stream)),
+        "https" => {
+            let hostname = webpki::DNSNameRef::try_from_ascii_str(host)
+                .map_err(|_| HttpError::Url(format!("invalid hostname {}", host)))?;
+            let session = rustls::ClientSession::new(tls_config, hostname);
+            Ok(Box::new(rustls::StreamOwned::new(session, stream)))
+        },
+        scheme => Err(HttpError::Url(format!("unsupported scheme {}", scheme))),
+    }
+}
+
+/// Return the value of the Host header for `url`. The port is left out if it's the default one of
+/// the scheme.
+fn host_header(url: &url::Url) -> String {
+    let host = url.host_str().unwrap_or("");
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => String::from(host),
+    }
+}
+
+/// An HTTP client with the CAs that the `https` servers are verified with.
+#[derive(Clone)]
+pub struct Client {
+    tls_config: Arc<rustls::ClientConfig>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client").finish()
+    }
+}
+
+impl Client {
+    /// Create a client that verifies the `https` servers with the CAs in the PEM file `ca_file`,
+    /// or the well-known roots if it's `None`.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the file cannot be read or it has no valid certificates.
+    ///
+    pub fn new(ca_file: Option<&str>) -> Result<Client, HttpError> {
+        let ca_file = match ca_file {
+            Some(ca_file) => ca_file,
+            None => return Ok(DEFAULT_CLIENT.clone()),
+        };
+
+        let file = std::fs::File::open(ca_file)?;
+        let mut roots = rustls::RootCertStore::empty();
+        match roots.add_pem_file(&mut BufReader::new(file)) {
+            Ok((valid_count, _)) if valid_count > 0 => Ok(Client::with_roots(roots)),
+            _ => Err(HttpError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("cannot parse CA certificates from {}", ca_file),
+            ))),
+        }
+    }
+
+    /// Create a client that verifies the `https` servers with `roots`.
+    fn with_roots(roots: rustls::RootCertStore) -> Client {
+        let mut tls_config = rustls::ClientConfig::new();
+        tls_config.root_store = roots;
+        Client { tls_config: Arc::new(tls_config) }
+    }
+
+    /// Send a request and read the response head. The body is read as the caller reads it, so it
+    /// can be a stream that doesn't end. If `read_timeout` is `None`, reading can block forever.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the url is invalid, the server cannot be reached, or the
+    /// response head is invalid. The status code is not checked.
+    ///
+    pub fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        read_timeout: Option<Duration>,
+    ) -> Result<Response, HttpError> {
+        let url = url::Url::parse(url).map_err(|error| HttpError::Url(error.to_string()))?;
+        let mut connection = connect(&url, read_timeout, &self.tls_config)?;
+
+        let mut path = String::from(url.path());
+        if let Some(query) = url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            method,
+            path,
+            host_header(&url),
+            body.len(),
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+
+        let mut data = request.into_bytes();
+        data.extend_from_slice(body);
+        connection.write_all(&data)?;
+        connection.flush()?;
+
+        let mut reader = BufReader::new(connection);
+        let (status, headers, length) = read_head(&mut reader)?;
+        Ok(Response { status, headers, body: Body { reader, length } })
+    }
+
+    /// Send a request and read the whole response. Return the status code and the body.
+    pub fn send_and_read(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        read_timeout: Duration,
+    ) -> Result<(u16, Vec<u8>), HttpError> {
+        let mut response = self.send(method, url, headers, body, Some(read_timeout))?;
+        let mut body = Vec::new();
+        response.body.read_to_end(&mut body)?;
+        Ok((response.status, body))
+    }
+}
+
+/// Send a request with a client that trusts the well-known roots. See `Client::send`.
+pub fn send(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    read_timeout: Option<Duration>,
+) -> Result<Response, HttpError> {
+    DEFAULT_CLIENT.send(method, url, headers, body, read_timeout)
+}
+
+/// Send a request and read the whole response with a client that trusts the well-known roots.
+/// See `Client::send_and_read`.
+pub fn send_and_read(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    read_timeout: Duration,
+) -> Result<(u16, Vec<u8>), HttpError> {
+    DEFAULT_CLIENT.send_and_read(method, url, headers, body, read_timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a response from bytes and read its whole body.
+    fn parse(data: &'static [u8]) -> Result<(u16, Vec<u8>), std::io::Error> {
+        let mut reader = BufReader::new(Box::new(std::io::Cursor::new(data.to_vec()))
+                                        as Box<dyn Connection>);
+        let (status, _, length) = read_head(&mut reader)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+        let mut body = Vec::new();
+        Body { reader, length }.read_to_end(&mut body)?;
+        Ok((status, body))
+    }
+
+    #[test]
+    fn test_content_length() {
+        let (status, body) = parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloextra")
+            .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"hello");
+
+        assert!(parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel").is_err());
+    }
+
+    #[test]
+    fn test_chunked() {
+        let (status, body) = parse(b"HTTP/1.1 404 Not Found\r\ntransfer-encoding: chunked\r\n\r\n\
+                                     5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n")
+            .unwrap();
+        assert_eq!(status, 404);
+        assert_eq!(body, b"hello, world");
+
+        assert!(parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello!\r\n")
+                .is_err());
+        assert!(parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n").is_err());
+
+        // A Content-Length after the Transfer-Encoding doesn't override it.
+        let (_, body) = parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\
+                                Content-Length: 3\r\n\r\n5\r\nhello\r\n0\r\n\r\n")
+            .unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn test_host_header() {
+        let host = |url| host_header(&url::Url::parse(url).unwrap());
+        assert_eq!(host("http://127.0.0.1:8500/v1/kv/key"), "127.0.0.1:8500");
+        assert_eq!(host("https://vault.example.com:8200/v1"), "vault.example.com:8200");
+        assert_eq!(host("http://[::1]:2379/v3/kv/range"), "[::1]:2379");
+        assert_eq!(host("http://etcd.example.com/"), "etcd.example.com");
+        assert_eq!(host("https://vault.example.com:443/"), "vault.example.com");
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut reader = BufReader::new(Box::new(std::io::Cursor::new(
+            b"HTTP/1.1 200 OK\r\nX-Consul-Index: 42\r\nContent-Length: 0\r\n\r\n".to_vec()
+        )) as Box<dyn Connection>);
+        let (status, headers, length) = read_head(&mut reader).unwrap();
+        let response = Response { status, headers, body: Body { reader, length } };
+        assert_eq!(response.header("x-consul-index"), Some("42"));
+        assert_eq!(response.header("Content-Length"), Some("0"));
+        assert_eq!(response.header("X-Missing"), None);
+    }
+
+    #[test]
+    fn test_until_close() {
+        let (status, body) = parse(b"HTTP/1.0 200 OK\r\n\r\nhello").unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn test_ca_file() {
+        assert!(Client::new(Some("tests/ca.pem")).is_ok());
+        assert!(Client::new(Some("tests/tls-pkcs8.pem")).is_err());
+        assert!(Client::new(Some("tests/missing.pem")).is_err());
+        assert!(Client::new(None).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_head() {
+        assert!(parse(b"HTTP/1.1 OK\r\n\r\n").is_err());
+        assert!(parse(b"HTTP/1.1 200 OK\r\nbad header\r\n\r\n").is_err());
+        assert!(parse(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+}
@@ -0,0 +1,234 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A key store in a local directory, for deployments where all the servers run on one host and
+//! don't need a shared key store.
+//!
+//! Each key is a file named after the epoch time at the beginning of its period. A missing key is
+//! generated and written atomically, so several servers on the host can share the directory and
+//! they always agree on the keys. The keys are secret, so the directory and the files must not be
+//! writable or readable by anyone except the owner, who must be the user running the server.
+
+use rand::Rng;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// The length of a generated key in bytes.
+const KEY_LEN: usize = 32;
+
+/// Generate a new random key.
+pub fn generate_key() -> Vec<u8> {
+    let mut key = vec![0; KEY_LEN];
+    rand::thread_rng().fill(&mut key[..]);
+    key
+}
+
+/// Return an error if the file or directory with `metadata` is not owned by the current user or
+/// its mode has any of the bits in `forbidden_mode`.
+fn check_permissions(
+    path: &Path,
+    metadata: &fs::Metadata,
+    forbidden_mode: u32,
+) -> Result<(), std::io::Error> {
+    // This is safe because `geteuid` cannot fail and has no side effects.
+    let euid = unsafe { libc::geteuid() };
+    if metadata.uid() != euid {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not owned by the user running the server", path.display()),
+        ));
+    }
+    if metadata.mode() & forbidden_mode != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} has mode {:o}, which is too permissive", path.display(),
+                    metadata.mode() & 0o777),
+        ));
+    }
+    Ok(())
+}
+
+/// A directory of keys.
+pub struct KeyDir {
+    path: PathBuf,
+}
+
+impl KeyDir {
+    /// Open the directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the path is not a directory, or it's readable or writable by the
+    /// group or the others, or it's not owned by the current user.
+    ///
+    pub fn open(path: &Path) -> Result<KeyDir, std::io::Error> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        check_permissions(path, &metadata, 0o077)?;
+        Ok(KeyDir { path: path.to_path_buf() })
+    }
+
+    /// Read the key in the file `name`. Return `None` if the file doesn't exist.
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let path = self.path.join(name);
+        let mut file = match File::open(&path) {
+            Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+            Ok(file) => file,
+        };
+
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is not a regular file", path.display()),
+            ));
+        }
+        check_permissions(&path, &metadata, 0o077)?;
+
+        let mut key = Vec::new();
+        file.read_to_end(&mut key)?;
+        Ok(Some(key))
+    }
+
+    /// Return the key in the file `name`. If the file doesn't exist, a new key is generated and
+    /// written to it.
+    pub fn get_or_create(&self, name: &str) -> Result<Vec<u8>, std::io::Error> {
+        if let Some(key) = self.read(name)? {
+            return Ok(key);
+        }
+
+        let key = generate_key();
+
+        // Write the key to a temporary file first, so that nobody reads a partially written key.
+        let temp_path = self.path.join(format!(".{}.{}.tmp", name, std::process::id()));
+        let _ = fs::remove_file(&temp_path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        let written = file.write_all(&key).and_then(|_| file.sync_all());
+
+        // A hard link fails if the file already exists, unlike a rename, so if another server
+        // creates the key at the same time, we use its key instead of replacing it.
+        let linked = written.and_then(|_| fs::hard_link(&temp_path, self.path.join(name)));
+        let _ = fs::remove_file(&temp_path);
+        match linked {
+            Err(ref error) if error.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(error) => return Err(error),
+            Ok(()) => {
+                // Make the new entry durable.
+                File::open(&self.path)?.sync_all()?;
+                return Ok(key);
+            },
+        }
+
+        self.read(name)?.ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("the key {} disappeared after it was created", name),
+        ))
+    }
+
+    /// Remove the keys of the periods that begin before `epoch`.
+    pub fn remove_before(&self, epoch: u64) -> Result<(), std::io::Error> {
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            // Leave alone the files that are not keys.
+            let key_epoch = entry.file_name().to_str().and_then(|name| name.parse::<u64>().ok());
+            match key_epoch {
+                Some(key_epoch) if key_epoch < epoch => fs::remove_file(entry.path())?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Create an empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cfnts-key-dir-{}-{}", name,
+                                                     std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_get_or_create() {
+        let path = test_dir("create");
+        let dir = KeyDir::open(&path).unwrap();
+
+        let key = dir.get_or_create("3600").unwrap();
+        assert_eq!(key.len(), KEY_LEN);
+        assert_eq!(dir.get_or_create("3600").unwrap(), key);
+        assert_ne!(dir.get_or_create("7200").unwrap(), key);
+
+        let mode = fs::metadata(path.join("3600")).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Only the keys are left.
+        let mut names: Vec<_> = fs::read_dir(&path).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["3600", "7200"]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_permissions() {
+        let path = test_dir("permissions");
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(KeyDir::open(&path).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(KeyDir::open(&path).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        assert!(KeyDir::open(&path).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700)).unwrap();
+        let dir = KeyDir::open(&path).unwrap();
+
+        dir.get_or_create("3600").unwrap();
+        fs::set_permissions(path.join("3600"), fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(dir.get_or_create("3600").is_err());
+
+        assert!(KeyDir::open(&path.join("3600")).is_err());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_remove_before() {
+        let path = test_dir("remove");
+        let dir = KeyDir::open(&path).unwrap();
+
+        dir.get_or_create("3600").unwrap();
+        dir.get_or_create("7200").unwrap();
+        fs::write(path.join("README"), b"keys").unwrap();
+
+        dir.remove_before(7200).unwrap();
+        assert!(!path.join("3600").exists());
+        assert!(path.join("7200").exists());
+        assert!(path.join("README").exists());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
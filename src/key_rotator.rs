@@ -2,25 +2,69 @@
 // Copyright (c) 2019, Cloudflare. All rights reserved.
 // See LICENSE for licensing information.
 
-//! Key rotator implementation, which provides key synchronization with Memcached server.
+//! Key rotator implementation, which provides key synchronization with the key store.
 
 use lazy_static::lazy_static;
 
-#[cfg(not(test))]
 use memcache::MemcacheError;
 
-use prometheus::{opts, register_counter, register_int_counter, IntCounter};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    opts, register_counter, register_int_counter, register_int_gauge, Gauge, IntCounter, IntGauge,
+    __register_gauge,
+};
+
+use ring::{hkdf, hmac};
 
-use ring::hmac;
+use rand::Rng;
+
+use slog::{error, info, warn};
 
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 #[cfg(not(test))]
 use std::time::SystemTime;
 
+use crate::aws::AwsSecret;
+use crate::consul::ConsulError;
 use crate::cookie::CookieKey;
+use crate::etcd::EtcdError;
+use crate::key_store::KeyStore;
+use crate::redis::RedisError;
+use crate::signal;
+use crate::vault::{VaultError, VaultSecret};
+
+/// The default length of each period in seconds.
+const DEFAULT_ROTATE_EVERY: u64 = 3600;
+
+/// The default number of previous periods whose keys are kept.
+const DEFAULT_RETAIN_PREVIOUS: u64 = 24;
+
+/// The default number of seconds that the cookies made under the old master key are accepted
+/// after the master key changes.
+const DEFAULT_MASTER_KEY_OVERLAP: u64 = 3600;
+
+/// How often the master key file is read again.
+const MASTER_KEY_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the rotation thread checks whether the process has received SIGHUP.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times the keys are fetched from the key store before a rotation fails.
+const FETCH_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry of a failed fetch. It doubles with every retry.
+const FETCH_BACKOFF_BASE: Duration = Duration::from_millis(200);
+
+/// The maximum delay between the retries of a failed fetch.
+const FETCH_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// How long to wait before watching the key store again after the watch fails.
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 lazy_static! {
     static ref ROTATION_COUNTER: IntCounter =
@@ -30,6 +74,62 @@ lazy_static! {
         "Number of failures in key rotation"
     )
     .unwrap();
+    static ref RETRY_COUNTER: IntCounter = register_int_counter!(
+        "ntp_key_store_fetch_retries_total",
+        "Number of retries after failing to fetch the keys from the key store"
+    )
+    .unwrap();
+    static ref RETRY_EXHAUSTED_COUNTER: IntCounter = register_int_counter!(
+        "ntp_key_store_fetch_retries_exhausted_total",
+        "Number of key fetches that failed after all the retries"
+    )
+    .unwrap();
+    static ref LATEST_KEY_ID_GAUGE: IntGauge = register_int_gauge!(
+        "ntp_key_latest_id",
+        "The id of the latest key, which is the epoch time at the beginning of its period"
+    )
+    .unwrap();
+    static ref KDF_VERSION_GAUGE: IntGauge = register_int_gauge!(
+        "ntp_key_latest_kdf_version",
+        "The version of the KDF that derived the latest key"
+    )
+    .unwrap();
+    static ref OLDER_KEYS_GAUGE: IntGauge = register_int_gauge!(
+        "ntp_key_older_keys",
+        "Number of keys older than the latest key that can still decrypt cookies"
+    )
+    .unwrap();
+    /// When the keys were last rotated successfully.
+    static ref LAST_ROTATION: Mutex<Option<Instant>> = Mutex::new(None);
+    /// It's only registered after the first successful rotation, so that it's not reported
+    /// before.
+    static ref ROTATION_AGE_GAUGE: Gauge = {
+        let gauge = Gauge::new(
+            "ntp_key_rotation_age_seconds",
+            "Seconds since the last successful key rotation",
+        )
+        .unwrap();
+        prometheus::register(Box::new(RotationAgeCollector { gauge: gauge.clone() })).unwrap();
+        gauge
+    };
+}
+
+/// Collector that updates the time since the last successful rotation when it's scraped.
+struct RotationAgeCollector {
+    gauge: Gauge,
+}
+
+impl Collector for RotationAgeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        if let Some(time) = *LAST_ROTATION.lock().unwrap() {
+            self.gauge.set(time.elapsed().as_secs_f64());
+        }
+        self.gauge.collect()
+    }
 }
 
 /// Key id for `KeyRotator`.
@@ -62,13 +162,265 @@ impl KeyId {
     }
 }
 
+/// The key derivation function that turns a key in the key store into a cookie key under the
+/// master key. Each version derives different keys from the same input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kdf {
+    /// Version 1. The cookie key is the HMAC-SHA-256 tag of the store key under the master key.
+    HmacSha256,
+    /// Version 2. The cookie key is expanded with HKDF-SHA-512 from the store key, salted with
+    /// the master key.
+    HkdfSha512,
+}
+
+/// The length of a derived cookie key, which is the key length of AES-SIV-CMAC-256.
+const DERIVED_KEY_LEN: usize = 32;
+
+/// The HKDF info of the cookie keys. It includes the KDF version, so the keys of different
+/// versions never collide.
+const HKDF_INFO: &[u8] = b"cfnts cookie key v2";
+
+/// `hkdf::KeyType` for a derived cookie key.
+struct DerivedKeyLen;
+
+impl hkdf::KeyType for DerivedKeyLen {
+    fn len(&self) -> usize {
+        DERIVED_KEY_LEN
+    }
+}
+
+impl Kdf {
+    /// Return the version number of the KDF.
+    pub fn version(self) -> u8 {
+        match self {
+            Kdf::HmacSha256 => 1,
+            Kdf::HkdfSha512 => 2,
+        }
+    }
+
+    /// Parse the name of a KDF as it appears in the config.
+    fn from_name(name: &str) -> Option<Kdf> {
+        match name {
+            "hmac-sha256" => Some(Kdf::HmacSha256),
+            "hkdf-sha512" => Some(Kdf::HkdfSha512),
+            _ => None,
+        }
+    }
+
+    /// Derive a cookie key from `value` in the key store under `master_key`.
+    fn derive(self, master_key: &[u8], value: &[u8]) -> Vec<u8> {
+        match self {
+            Kdf::HmacSha256 => {
+                let mac_key = hmac::Key::new(hmac::HMAC_SHA256, master_key);
+                hmac::sign(&mac_key, value).as_ref().to_vec()
+            },
+            Kdf::HkdfSha512 => {
+                let mut key = vec![0; DERIVED_KEY_LEN];
+                hkdf::Salt::new(hkdf::HKDF_SHA512, master_key)
+                    .extract(value)
+                    .expand(&[HKDF_INFO], DerivedKeyLen)
+                    .and_then(|okm| okm.fill(&mut key))
+                    .expect("BUG: the derived key length should be valid for HKDF-SHA-512");
+                key
+            },
+        }
+    }
+}
+
+/// A cookie key derived by the rotator, with the version of the KDF that derived it.
+#[derive(Clone, Debug)]
+pub struct DerivedKey {
+    kdf: Kdf,
+    material: Vec<u8>,
+}
+
+impl DerivedKey {
+    /// Return the KDF that derived the key.
+    pub fn kdf(&self) -> Kdf {
+        self.kdf
+    }
+}
+
+impl AsRef<[u8]> for DerivedKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.material
+    }
+}
+
+/// How often the keys are rotated and how long the old keys are kept.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RotationConfig {
+    /// Length of each period in seconds. It must be the same as how often the keys are written to
+    /// the key store.
+    pub rotate_every: u64,
+
+    /// The number of previous periods whose keys are kept. The cookies made with those keys are
+    /// still accepted, so a cookie is valid for at least `rotate_every * retain_previous`
+    /// seconds.
+    pub retain_previous: u64,
+
+    /// How many seconds the cookies made under the old master key are still accepted after the
+    /// master key changes. If it's 0, they are rejected right away.
+    pub master_key_overlap: u64,
+
+    /// The KDF of the keys of the periods that begin at or after `kdf_since`. The keys of the
+    /// earlier periods are derived with `Kdf::HmacSha256`, so the servers can switch to a new KDF
+    /// at a time in the future without breaking the cookies that they have already made.
+    pub kdf: Kdf,
+    pub kdf_since: u64,
+
+    /// The maximum number of seconds that each periodic fetch of the keys is delayed at random,
+    /// so that the servers don't all hit the key store at the same time. It's less than
+    /// `rotate_every`.
+    pub jitter: u64,
+}
+
+impl Default for RotationConfig {
+    fn default() -> RotationConfig {
+        RotationConfig {
+            rotate_every: DEFAULT_ROTATE_EVERY,
+            retain_previous: DEFAULT_RETAIN_PREVIOUS,
+            master_key_overlap: DEFAULT_MASTER_KEY_OVERLAP,
+            kdf: Kdf::HmacSha256,
+            kdf_since: 0,
+            jitter: 0,
+        }
+    }
+}
+
+/// Parse `rotate_every`, `retain_previous`, `cookie_key_overlap`, `kdf`, `kdf_since`, and
+/// `rotate_jitter`. All the NTS-KE and NTP servers sharing a key store must use the same values,
+/// except for `cookie_key_overlap` and `rotate_jitter`.
+pub fn get_rotation_config(settings: &config::Config)
+    -> Result<RotationConfig, config::ConfigError>
+{
+    let get_u64 = |key: &str, default: u64| match settings.get_int(key) {
+        Err(config::ConfigError::NotFound(_)) => Ok(default),
+        Err(error) => Err(error),
+        Ok(value) if value >= 1 => Ok(value as u64),
+        Ok(_) => Err(config::ConfigError::Message(format!("the {} must be at least 1", key))),
+    };
+    let rotation = RotationConfig {
+        rotate_every: get_u64("rotate_every", DEFAULT_ROTATE_EVERY)?,
+        // A cookie made right before a rotation must still be accepted after it, so at least one
+        // previous key has to be kept.
+        retain_previous: get_u64("retain_previous", DEFAULT_RETAIN_PREVIOUS)?,
+        master_key_overlap: match settings.get_int("cookie_key_overlap") {
+            Err(config::ConfigError::NotFound(_)) => DEFAULT_MASTER_KEY_OVERLAP,
+            Err(error) => return Err(error),
+            Ok(value) if value >= 0 => value as u64,
+            Ok(_) => return Err(config::ConfigError::Message(
+                String::from("the cookie_key_overlap must not be negative")
+            )),
+        },
+        kdf: match settings.get_str("kdf") {
+            Err(config::ConfigError::NotFound(_)) => Kdf::HmacSha256,
+            Err(error) => return Err(error),
+            Ok(name) => Kdf::from_name(&name).ok_or_else(|| config::ConfigError::Message(
+                format!("unknown kdf {}", name)
+            ))?,
+        },
+        kdf_since: match settings.get_int("kdf_since") {
+            Err(config::ConfigError::NotFound(_)) => 0,
+            Err(error) => return Err(error),
+            Ok(value) if value >= 0 => value as u64,
+            Ok(_) => return Err(config::ConfigError::Message(
+                String::from("the kdf_since must not be negative")
+            )),
+        },
+        jitter: match settings.get_int("rotate_jitter") {
+            Err(config::ConfigError::NotFound(_)) => 0,
+            Err(error) => return Err(error),
+            Ok(value) if value >= 0 => value as u64,
+            Ok(_) => return Err(config::ConfigError::Message(
+                String::from("the rotate_jitter must not be negative")
+            )),
+        },
+    };
+
+    if rotation.jitter >= rotation.rotate_every {
+        return Err(config::ConfigError::Message(
+            String::from("the rotate_jitter must be less than the rotate_every")
+        ));
+    }
+
+    // The retention window must fit in the epoch times that we compute.
+    if rotation.rotate_every.checked_mul(rotation.retain_previous + 1).is_none() {
+        return Err(config::ConfigError::Message(
+            String::from("the retain_previous is too large for the rotate_every")
+        ));
+    }
+    Ok(rotation)
+}
+
+/// Take the `refresh_interval` in seconds out of the `table` of the secret `name`, or return
+/// `default` if it's not there. The secret would be read again in a loop below 1 second.
+pub fn take_refresh_interval(
+    table: &mut HashMap<String, config::Value>,
+    name: &str,
+    default: Duration,
+) -> Result<Duration, config::ConfigError> {
+    match table.remove("refresh_interval") {
+        Some(value) => match value.into_int()? {
+            secs if secs >= 1 => Ok(Duration::from_secs(secs as u64)),
+            _ => Err(config::ConfigError::Message(
+                format!("the refresh_interval of {} must be at least 1", name)
+            )),
+        },
+        None => Ok(default),
+    }
+}
+
 /// Error struct returned from `KeyRotator::rotate` method.
 #[derive(Debug)]
 pub enum RotateError {
     /// Error from Memcached server.
     MemcacheError(MemcacheError),
-    /// Error when the Memcached server doesn't have a specified `KeyId`.
+    /// Error from Redis server.
+    RedisError(RedisError),
+    /// Error from etcd server.
+    EtcdError(EtcdError),
+    /// Error from Consul server.
+    ConsulError(ConsulError),
+    /// Error from Vault server.
+    VaultError(VaultError),
+    /// Error from the local key directory.
+    IoError(std::io::Error),
+    /// Error when the key store doesn't have a specified `KeyId`.
     KeyIdNotFound(KeyId),
+    /// Error when a key is written to a key store that is read-only.
+    ReadOnly,
+}
+
+impl fmt::Display for RotateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RotateError::MemcacheError(error) => write!(f, "memcached error: {}", error),
+            RotateError::RedisError(error) => write!(f, "{}", error),
+            RotateError::EtcdError(error) => write!(f, "{}", error),
+            RotateError::ConsulError(error) => write!(f, "{}", error),
+            RotateError::VaultError(error) => write!(f, "{}", error),
+            RotateError::IoError(error) => write!(f, "key directory error: {}", error),
+            RotateError::KeyIdNotFound(key_id) => {
+                write!(f, "the key store has no key {:?}", key_id)
+            },
+            RotateError::ReadOnly => write!(f, "the key store is read-only"),
+        }
+    }
+}
+
+impl std::error::Error for RotateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RotateError::MemcacheError(error) => Some(error),
+            RotateError::RedisError(error) => Some(error),
+            RotateError::EtcdError(error) => Some(error),
+            RotateError::ConsulError(error) => Some(error),
+            RotateError::VaultError(error) => Some(error),
+            RotateError::IoError(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl From<MemcacheError> for RotateError {
@@ -78,13 +430,45 @@ impl From<MemcacheError> for RotateError {
     }
 }
 
+impl From<RedisError> for RotateError {
+    /// Wrap RedisError.
+    fn from(error: RedisError) -> RotateError {
+        RotateError::RedisError(error)
+    }
+}
+
+impl From<ConsulError> for RotateError {
+    /// Wrap ConsulError.
+    fn from(error: ConsulError) -> RotateError {
+        RotateError::ConsulError(error)
+    }
+}
+
+impl From<EtcdError> for RotateError {
+    /// Wrap EtcdError.
+    fn from(error: EtcdError) -> RotateError {
+        RotateError::EtcdError(error)
+    }
+}
+
+impl From<VaultError> for RotateError {
+    /// Wrap VaultError.
+    fn from(error: VaultError) -> RotateError {
+        RotateError::VaultError(error)
+    }
+}
+
+impl From<std::io::Error> for RotateError {
+    /// Wrap std::io::Error.
+    fn from(error: std::io::Error) -> RotateError {
+        RotateError::IoError(error)
+    }
+}
+
 /// Key rotator.
 pub struct KeyRotator {
-    /// URL of the Memcached server.
-    memcached_url: String,
-
-    /// Prefix for the Memcached key.
-    prefix: String,
+    /// The key store that the keys are read from.
+    store: Arc<dyn KeyStore>,
 
     // This property type needs to fit an Epoch time in seconds.
     /// Length of each period in seconds.
@@ -93,12 +477,11 @@ pub struct KeyRotator {
     // The number of forward and backward periods are `u64` because the timestamp is `u64` and the
     // duration can be as small as 1.
 
-    /// The number of future periods that the rotator must cache their values from the
-    /// Memcached server.
+    /// The number of future periods that the rotator must cache their values from the key store.
     number_of_forward_periods: u64,
 
-    /// The number of previous periods that the rotator must cache their values from the
-    /// Memcached server.
+    /// The number of previous periods that the rotator must cache their values from the key
+    /// store.
     number_of_backward_periods: u64,
 
     /// Cookie key that will be used as a MAC key of the rotator.
@@ -108,20 +491,31 @@ pub struct KeyRotator {
     latest_key_id: KeyId,
 
     /// Cache store.
-    cache: HashMap<KeyId, hmac::Tag>,
+    cache: HashMap<KeyId, DerivedKey>,
+
+    /// The cache under the previous master key and until when it's used, after the master key
+    /// changes.
+    previous_cache: Option<(HashMap<KeyId, DerivedKey>, Instant)>,
+
+    /// How long the previous cache is used after the master key changes.
+    master_key_overlap: Duration,
+
+    /// The KDF of the keys of the periods that begin at or after `kdf_since`.
+    kdf: Kdf,
+    kdf_since: u64,
+
+    /// The maximum random delay of the periodic rotations in seconds.
+    jitter: u64,
 
     /// Logger.
-    // TODO: since we don't use the logger now, I will put an `allow(dead_code)` here first. I will
-    // remove it when it's used.
-    #[allow(dead_code)]
     logger: slog::Logger,
 }
 
 impl KeyRotator {
-    /// Connect to the Memcached server and sync some inital keys.
+    /// Connect to the key store and sync some inital keys.
     pub fn connect(
-        prefix: String,
-        memcached_url: String,
+        store: Box<dyn KeyStore>,
+        rotation: RotationConfig,
         master_key: CookieKey,
         logger: slog::Logger,
     ) -> Result<KeyRotator, RotateError> {
@@ -130,16 +524,21 @@ impl KeyRotator {
             latest_key_id: KeyId::new(0),
             // The cache should never be empty. This is just a temporary value.
             cache: HashMap::new(),
+            previous_cache: None,
 
-            // It seems that currently we don't have to customize the following three properties,
-            // so I will just put default values.
-            duration: 3600,
+            // It seems that currently we don't have to customize the number of forward periods,
+            // so I will just put a default value.
             number_of_forward_periods: 2,
-            number_of_backward_periods: 24,
+
+            duration: rotation.rotate_every,
+            number_of_backward_periods: rotation.retain_previous,
+            master_key_overlap: Duration::from_secs(rotation.master_key_overlap),
+            kdf: rotation.kdf,
+            kdf_since: rotation.kdf_since,
+            jitter: rotation.jitter,
 
             // From parameters.
-            prefix,
-            memcached_url,
+            store: Arc::from(store),
             master_key,
             logger,
         };
@@ -181,13 +580,26 @@ impl KeyRotator {
     ///
     /// # Errors
     ///
-    /// There is an error, if there is a connection problem with Memcached server or the Memcached
-    /// server doesn't contain a key id it supposed to contain.
+    /// There is an error, if there is a connection problem with the key store or the key store
+    /// doesn't contain a key id it supposed to contain.
     ///
     pub fn rotate(&mut self) -> Result<(), RotateError> {
         // Side-effect. It's not related to the operation.
         ROTATION_COUNTER.inc();
 
+        let (epochs, current_epoch) = self.window();
+        let store_values = fetch_keys_with_retry(&*self.store, &epochs, &self.logger)?;
+        self.apply(epochs, store_values, current_epoch)
+    }
+
+    /// Return the timestamps at the beginning of the periods whose keys we want, and the one of
+    /// the current period.
+    ///
+    /// # Panics
+    ///
+    /// If the system time is before the UNIX Epoch time.
+    ///
+    fn window(&self) -> (Vec<u64>, u64) {
         let duration = SystemTime::now().duration_since(UNIX_EPOCH)
             .expect("The system time must be after the UNIX Epoch time.");
 
@@ -205,24 +617,36 @@ impl KeyRotator {
         // The last period number that we want to iterate through.
         let last_period = current_period.saturating_add(self.number_of_forward_periods);
 
-        let removed_period = first_period.saturating_sub(1);
-        let removed_epoch = removed_period * self.duration;
-        self.cache_remove(KeyId::from_epoch(removed_epoch));
+        // The timestamps at the beginning of the periods.
+        let epochs: Vec<u64> = (first_period..=last_period)
+            .map(|period_number| period_number * self.duration)
+            .collect();
 
-        // Connecting to memcached. I have to add [..] because it seems that Rust is not smart
-        // enough to do auto-dereference.
-        let mut client = memcache::Client::connect(&self.memcached_url[..])?;
-
-        for period_number in first_period..=last_period {
-            // The timestamp at the beginning of the period.
-            let epoch = period_number * self.duration;
+        (epochs, current_epoch)
+    }
 
-            let memcached_key = format!("{}/{}", self.prefix, epoch);
-            let memcached_value: Option<Vec<u8>> = client.get(&memcached_key)?;
+    /// Replace the cached keys with the ones fetched from the store for `epochs`.
+    fn apply(
+        &mut self,
+        epochs: Vec<u64>,
+        store_values: Vec<Option<Vec<u8>>>,
+        current_epoch: u64,
+    ) -> Result<(), RotateError> {
+        // Remove the keys that are out of the window, even if some rotations were missed. The
+        // latest key is kept until a new one is synced.
+        let window: Vec<KeyId> = epochs.iter().map(|epoch| KeyId::from_epoch(*epoch)).collect();
+        let latest_key_id = self.latest_key_id;
+        self.cache.retain(|key_id, _| *key_id == latest_key_id || window.contains(key_id));
+        if let Some((_, until)) = self.previous_cache {
+            if Instant::now() >= until {
+                self.previous_cache = None;
+            }
+        }
 
+        for (epoch, store_value) in epochs.into_iter().zip(store_values) {
             let key_id = KeyId::from_epoch(epoch);
-            match memcached_value {
-                Some(value) => self.cache_insert(key_id, value.as_slice()),
+            match store_value {
+                Some(value) => self.cache_insert(epoch, value.as_slice()),
                 None => {
                     FAILURE_COUNTER.inc();
                     return Err(RotateError::KeyIdNotFound(key_id));
@@ -233,96 +657,309 @@ impl KeyRotator {
         // Not all of our friends may have gotten the same forwards keys as we did.
         self.latest_key_id = KeyId::from_epoch(current_epoch);
 
+        let latest_key_id = self.latest_key_id.0;
+        LATEST_KEY_ID_GAUGE.set(i64::from(latest_key_id));
+        if let Some(key) = self.cache.get(&self.latest_key_id) {
+            KDF_VERSION_GAUGE.set(i64::from(key.kdf().version()));
+        }
+        OLDER_KEYS_GAUGE.set(self.cache.keys().filter(|key_id| key_id.0 < latest_key_id).count()
+                             as i64);
+        *LAST_ROTATION.lock().unwrap() = Some(Instant::now());
+        ROTATION_AGE_GAUGE.set(0.0);
+
         Ok(())
     }
 
-    /// Add an entry to the cache.
-    // It should be private. Don't make it public.
-    fn cache_insert(&mut self, key_id: KeyId, value: &[u8]) {
-        // Create a MAC key.
-        let mac_key = hmac::Key::new(hmac::HMAC_SHA256, self.master_key.as_bytes());
-        // Generating a MAC tag with a MAC key.
-        let tag = hmac::sign(&mac_key, value);
+    /// Replace the master key if it's changed. All the cached keys are derived from the master
+    /// key, so they are synced again. If that fails, the old master key is kept. Otherwise, the
+    /// keys under the old master key are still returned by `get_previous` for the overlap
+    /// period.
+    pub fn set_master_key(&mut self, master_key: CookieKey) -> Result<(), RotateError> {
+        if master_key.as_bytes() == self.master_key.as_bytes() {
+            return Ok(());
+        }
+        let old_master_key = std::mem::replace(&mut self.master_key, master_key);
+        let old_cache = std::mem::replace(&mut self.cache, HashMap::new());
+        if let Err(error) = self.rotate() {
+            self.master_key = old_master_key;
+            self.cache = old_cache;
+            return Err(error);
+        }
+        if self.master_key_overlap > Duration::from_secs(0) {
+            self.previous_cache = Some((old_cache, Instant::now() + self.master_key_overlap));
+        }
+        Ok(())
+    }
 
-        self.cache.insert(key_id, tag);
+    /// Return the key store.
+    pub fn store(&self) -> Arc<dyn KeyStore> {
+        self.store.clone()
     }
 
-    /// Remove an entry from the cache.
+    /// Add an entry to the cache.
     // It should be private. Don't make it public.
-    fn cache_remove(&mut self, key_id: KeyId) {
-        self.cache.remove(&key_id);
+    fn cache_insert(&mut self, epoch: u64, value: &[u8]) {
+        let kdf = if epoch >= self.kdf_since { self.kdf } else { Kdf::HmacSha256 };
+        let material = kdf.derive(self.master_key.as_bytes(), value);
+
+        self.cache.insert(KeyId::from_epoch(epoch), DerivedKey { kdf, material });
     }
 
-    /// Return the latest key id and hmac tag of the rotator.
-    pub fn latest_key_value(&self) -> (KeyId, &hmac::Tag) {
+    /// Return the latest key id and derived key of the rotator.
+    pub fn latest_key_value(&self) -> (KeyId, &DerivedKey) {
         // This unwrap cannot panic because the HashMap will always contain the latest key id.
         (self.latest_key_id, self.get(self.latest_key_id).unwrap())
     }
 
+    /// Return how long ago the period of the latest key began, or `None` if there is no key yet.
+    /// It keeps growing past the `rotate_every` while the rotations fail.
+    pub fn latest_key_age(&self) -> Option<Duration> {
+        self.cache.get(&self.latest_key_id)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Some(now.checked_sub(Duration::from_secs(u64::from(self.latest_key_id.0)))
+            .unwrap_or_default())
+    }
+
     /// Return an entry in the cache using a key id.
-    pub fn get(&self, key_id: KeyId) -> Option<&hmac::Tag> {
+    pub fn get(&self, key_id: KeyId) -> Option<&DerivedKey> {
         self.cache.get(&key_id)
     }
+
+    /// Return the key of `key_id` under the previous master key, if the master key changed
+    /// within the overlap period.
+    pub fn get_previous(&self, key_id: KeyId) -> Option<&DerivedKey> {
+        match &self.previous_cache {
+            Some((cache, until)) if Instant::now() < *until => cache.get(&key_id),
+            _ => None,
+        }
+    }
 }
 
 pub fn periodic_rotate(rotor: Arc<RwLock<KeyRotator>>) {
+    let store = rotor.read().unwrap().store();
+    let watched_rotor = rotor.clone();
+    thread::spawn(move || watch_store(&watched_rotor, &*store));
+
     let mut rotor = rotor.clone();
+    let mut last_count = signal::sighup_count();
+    let mut offset = 0;
     thread::spawn(move || loop {
         inner(&mut rotor);
-        let restlen = read_sleep(&rotor);
-        thread::sleep(Duration::from_secs(restlen as u64));
+
+        // SIGHUP wakes us up early, so that the operators can sync the keys right away, for
+        // example, after the key store is fixed.
+        let deadline = Instant::now() + Duration::from_secs(read_sleep(&rotor, &mut offset));
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep((deadline - now).min(SIGNAL_CHECK_INTERVAL));
+
+            let count = signal::sighup_count();
+            if count != last_count {
+                last_count = count;
+                info!(rotor.read().unwrap().logger, "rotating the keys on SIGHUP");
+                break;
+            }
+        }
     });
 }
 
+/// Rotate the keys every time that they change in the store, so that the new keys are picked up
+/// right away. The periodic rotation still runs in case the watch breaks. It returns right away if
+/// the store cannot be watched.
+fn watch_store(rotor: &Arc<RwLock<KeyRotator>>, store: &dyn KeyStore) {
+    let logger = rotor.read().unwrap().logger.clone();
+    loop {
+        let result = store.watch(&mut || {
+            if let Err(error) = rotate_shared(rotor) {
+                warn!(logger, "key rotation after a key store change failed: {}", error);
+            }
+        });
+        match result {
+            Ok(()) => return,
+            Err(error) => warn!(logger, "key store watch failed, retrying: {}", error),
+        }
+        thread::sleep(WATCH_RETRY_DELAY);
+
+        // Pick up the changes that we missed while the watch was down.
+        inner(&mut rotor.clone());
+    }
+}
+
+/// Read the master key periodically with `read`, and replace it when it changes.
+fn periodic_reload<F>(rotor: Arc<RwLock<KeyRotator>>, interval: Duration, read: F)
+where
+    F: Fn() -> Result<CookieKey, String> + Send + 'static,
+{
+    let logger = rotor.read().unwrap().logger.clone();
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let master_key = match read() {
+            Ok(master_key) => master_key,
+            Err(error) => {
+                warn!(logger, "cannot read the master key: {}", error);
+                continue;
+            },
+        };
+        if let Err(error) = rotor.write().unwrap().set_master_key(master_key) {
+            warn!(logger, "key rotation after a master key change failed: {}", error);
+        }
+    });
+}
+
+/// Read the master key from Vault periodically, so that it can be rotated without restarting the
+/// server.
+pub fn periodic_reload_master_key(rotor: Arc<RwLock<KeyRotator>>, secret: VaultSecret) {
+    periodic_reload(rotor, secret.refresh_interval, move || {
+        secret.fetch()
+            .map(CookieKey::from)
+            .map_err(|error| format!("vault: {}", error))
+    });
+}
+
+/// Read the master key from AWS periodically, so that it can be rotated without restarting the
+/// server.
+pub fn periodic_reload_master_key_aws(rotor: Arc<RwLock<KeyRotator>>, secret: AwsSecret) {
+    periodic_reload(rotor, secret.refresh_interval, move || {
+        secret.fetch()
+            .map(CookieKey::from)
+            .map_err(|error| format!("aws: {}", error))
+    });
+}
+
+/// Read the master key file periodically, so that it can be replaced without restarting the
+/// server.
+pub fn periodic_reload_master_key_file(rotor: Arc<RwLock<KeyRotator>>, path: String) {
+    periodic_reload(rotor, MASTER_KEY_FILE_CHECK_INTERVAL, move || {
+        CookieKey::parse(&path).map_err(|error| format!("{}: {}", path, error))
+    });
+}
+
+/// Rotate the keys of a shared rotator. Unlike `KeyRotator::rotate`, the keys are fetched, and
+/// the fetch is retried, without holding the lock, so the servers can keep using the current keys
+/// in the meantime.
+pub fn rotate_shared(rotor: &RwLock<KeyRotator>) -> Result<(), RotateError> {
+    ROTATION_COUNTER.inc();
+
+    let (store, logger, epochs, current_epoch) = {
+        let rotor = rotor.read().unwrap();
+        let (epochs, current_epoch) = rotor.window();
+        (rotor.store(), rotor.logger.clone(), epochs, current_epoch)
+    };
+    let store_values = fetch_keys_with_retry(&*store, &epochs, &logger)?;
+    rotor.write().unwrap().apply(epochs, store_values, current_epoch)
+}
+
+/// Return the delay before the retry after the failed `attempt`, which starts from 1. It doubles
+/// with every attempt up to `FETCH_BACKOFF_MAX`. Half of it is random, given by `jitter` between
+/// 0 and 1, so that the servers don't hit the store at the same time after an outage.
+fn backoff_delay(attempt: u32, jitter: f64) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(16);
+    let delay = FETCH_BACKOFF_BASE.checked_mul(1 << exponent)
+        .unwrap_or(FETCH_BACKOFF_MAX)
+        .min(FETCH_BACKOFF_MAX);
+    delay / 2 + delay.mul_f64(jitter.max(0.0).min(1.0) / 2.0)
+}
+
+/// Fetch the keys, and retry with backoff if the store fails. The failures are logged more
+/// severely as the attempts run out, and the error is only returned after the last attempt. A
+/// key missing from the store is not a failure.
+fn fetch_keys_with_retry(store: &dyn KeyStore, epochs: &[u64], logger: &slog::Logger)
+    -> Result<Vec<Option<Vec<u8>>>, RotateError>
+{
+    let mut attempt = 1;
+    loop {
+        let error = match store.fetch_keys(epochs) {
+            Ok(store_values) => return Ok(store_values),
+            Err(error) => error,
+        };
+        if attempt == FETCH_ATTEMPTS {
+            RETRY_EXHAUSTED_COUNTER.inc();
+            error!(logger, "fetching keys from the key store failed after {} attempts: {}",
+                   attempt, error);
+            return Err(error);
+        }
+
+        RETRY_COUNTER.inc();
+        let delay = backoff_delay(attempt, rand::thread_rng().gen());
+        if attempt == 1 {
+            info!(logger, "fetching keys from the key store failed, retrying in {:?}: {:?}",
+                  delay, error);
+        } else {
+            warn!(logger, "fetching keys from the key store failed {} times, retrying in {:?}: \
+                           {}", attempt, delay, error);
+        }
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
 fn inner(rotor: &mut Arc<RwLock<KeyRotator>>) {
-    let _ = rotor.write().unwrap().rotate();
+    let _ = rotate_shared(rotor);
 }
 
-fn read_sleep(rotor: &Arc<RwLock<KeyRotator>>) -> u64 {
-    rotor.read().unwrap().duration
+/// Return how long to sleep until the next periodic rotation. Each rotation is delayed by a random
+/// `offset` up to the jitter, which replaces the offset of the previous one, so the rotations
+/// still happen once per period on average.
+fn read_sleep(rotor: &Arc<RwLock<KeyRotator>>, offset: &mut u64) -> u64 {
+    let (duration, jitter) = {
+        let rotor = rotor.read().unwrap();
+        (rotor.duration, rotor.jitter)
+    };
+    let next_offset = rand::thread_rng().gen_range(0, jitter + 1);
+    jittered_sleep(duration, offset, next_offset)
+}
+
+/// Return the sleep between a rotation delayed by `offset` and the next one delayed by
+/// `next_offset`, and remember `next_offset`. The offsets are less than `duration`.
+fn jittered_sleep(duration: u64, offset: &mut u64, next_offset: u64) -> u64 {
+    let sleep = duration + next_offset - (*offset).min(duration);
+    *offset = next_offset;
+    sleep
 }
 
 // ------------------------------------------------------------------------
 // Tests
 // ------------------------------------------------------------------------
 
-#[cfg(test)] use ::memcache::MemcacheError;
-#[cfg(test)] use test::memcache;
 #[cfg(test)] use test::SystemTime;
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    use ::memcache::MemcacheError;
     use lazy_static::lazy_static;
     use sloggers::Build;
     use sloggers::null::NullLoggerBuilder;
     use std::sync::Mutex;
     use std::time::Duration;
 
-    // Mocking memcache.
-    pub mod memcache {
-        use super::*;
-        use std::collections::HashMap;
+    // Mocking the key store.
+    struct MockStore {
+        prefix: String,
+        keys: HashMap<String, Vec<u8>>,
+    }
 
-        lazy_static! {
-            pub static ref HASH_MAP: Mutex<HashMap<String, Vec<u8>>> = Mutex::new(HashMap::new());
+    impl KeyStore for MockStore {
+        fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+            Ok(epochs.iter()
+                .map(|epoch| self.keys.get(&format!("{}/{}", self.prefix, epoch)).cloned())
+                .collect())
         }
-        pub struct Client;
-        impl Client {
-            pub fn connect(_url: &str) -> Result<Client, MemcacheError> {
-                Ok(Client)
-            }
-            pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, MemcacheError> {
-                Ok(HASH_MAP.lock().unwrap().get(&String::from(key)).cloned())
-            }
+
+        fn health(&self) -> Result<(), RotateError> {
+            Ok(())
         }
     }
 
     // Mocking SystemTime.
     lazy_static! {
         pub static ref NOW: Mutex<u64> = Mutex::new(0);
+
+        // The tests that change the time cannot run at the same time.
+        static ref SERIAL: Mutex<()> = Mutex::new(());
     }
     pub struct SystemTime;
     impl SystemTime {
@@ -335,24 +972,30 @@ mod test {
 
     #[test]
     fn test_rotation() {
-        use self::memcache::HASH_MAP;
+        let _serial = SERIAL.lock().unwrap();
 
-        let mut hash_map = HASH_MAP.lock().unwrap();
+        let mut hash_map = HashMap::new();
         hash_map.insert("test/1".to_string(), vec![1; 32]);
         hash_map.insert("test/2".to_string(), vec![2; 32]);
         hash_map.insert("test/3".to_string(), vec![3; 32]);
         hash_map.insert("test/4".to_string(), vec![4; 32]);
-        drop(hash_map);
 
         let mut rotator = KeyRotator {
-            memcached_url: String::from("unused"),
-            prefix: String::from("test"),
+            store: Arc::new(MockStore {
+                prefix: String::from("test"),
+                keys: hash_map,
+            }),
             duration: 1,
             number_of_forward_periods: 1,
             number_of_backward_periods: 1,
             master_key: CookieKey::from(&[0, 32][..]),
             latest_key_id: KeyId::from_be_bytes([1, 2, 3, 4]),
             cache: HashMap::new(),
+            previous_cache: None,
+            master_key_overlap: Duration::from_secs(0),
+            kdf: Kdf::HmacSha256,
+            kdf_since: 0,
+            jitter: 0,
             logger: NullLoggerBuilder.build().unwrap(),
         };
 
@@ -377,4 +1020,221 @@ mod test {
         // Return error because the hash map doesn't have "test/5".
         rotator.rotate().unwrap_err();
     }
+
+    #[test]
+    fn test_retention() {
+        let _serial = SERIAL.lock().unwrap();
+
+        let mut hash_map = HashMap::new();
+        for epoch in (0..=100).step_by(10) {
+            hash_map.insert(format!("test/{}", epoch), vec![epoch as u8; 32]);
+        }
+
+        *NOW.lock().unwrap() = 55;
+        let mut rotator = KeyRotator::connect(
+            Box::new(MockStore { prefix: String::from("test"), keys: hash_map }),
+            RotationConfig {
+                rotate_every: 10,
+                retain_previous: 3,
+                master_key_overlap: 0,
+                ..RotationConfig::default()
+            },
+            CookieKey::from(&[0, 32][..]),
+            NullLoggerBuilder.build().unwrap(),
+        ).unwrap();
+        assert_eq!(rotator.latest_key_value().0, KeyId::from_epoch(50));
+
+        // The cookies made with the keys of the 3 previous periods are still accepted.
+        for epoch in &[20, 30, 40, 50, 60, 70] {
+            assert!(rotator.get(KeyId::from_epoch(*epoch)).is_some(), "epoch {}", epoch);
+        }
+        assert!(rotator.get(KeyId::from_epoch(10)).is_none());
+
+        // Even if some rotations are missed, the old keys are removed.
+        *NOW.lock().unwrap() = 85;
+        rotator.rotate().unwrap();
+        assert!(rotator.get(KeyId::from_epoch(40)).is_none());
+        assert!(rotator.get(KeyId::from_epoch(50)).is_some());
+    }
+
+    #[test]
+    fn test_master_key_overlap() {
+        let _serial = SERIAL.lock().unwrap();
+
+        let mut hash_map = HashMap::new();
+        for epoch in (0..=100).step_by(10) {
+            hash_map.insert(format!("test/{}", epoch), vec![epoch as u8; 32]);
+        }
+
+        *NOW.lock().unwrap() = 55;
+        let mut rotator = KeyRotator::connect(
+            Box::new(MockStore { prefix: String::from("test"), keys: hash_map }),
+            RotationConfig {
+                rotate_every: 10,
+                retain_previous: 3,
+                master_key_overlap: 60,
+                ..RotationConfig::default()
+            },
+            CookieKey::from(&[0, 32][..]),
+            NullLoggerBuilder.build().unwrap(),
+        ).unwrap();
+        let key_id = KeyId::from_epoch(50);
+        let old_key = rotator.get(key_id).unwrap().as_ref().to_vec();
+        assert!(rotator.get_previous(key_id).is_none());
+
+        rotator.set_master_key(CookieKey::from(&[1, 32][..])).unwrap();
+        assert_ne!(rotator.get(key_id).unwrap().as_ref(), &old_key[..]);
+        assert_eq!(rotator.get_previous(key_id).unwrap().as_ref(), &old_key[..]);
+
+        // After the overlap period, the keys under the old master key are gone.
+        rotator.previous_cache.as_mut().unwrap().1 = Instant::now();
+        assert!(rotator.get_previous(key_id).is_none());
+    }
+
+    #[test]
+    fn test_latest_key_age() {
+        let _serial = SERIAL.lock().unwrap();
+
+        let mut hash_map = HashMap::new();
+        for epoch in (0..=100).step_by(10) {
+            hash_map.insert(format!("test/{}", epoch), vec![epoch as u8; 32]);
+        }
+
+        *NOW.lock().unwrap() = 55;
+        let mut rotator = KeyRotator::connect(
+            Box::new(MockStore { prefix: String::from("test"), keys: hash_map }),
+            RotationConfig { rotate_every: 10, retain_previous: 3, ..RotationConfig::default() },
+            CookieKey::from(&[0, 32][..]),
+            NullLoggerBuilder.build().unwrap(),
+        ).unwrap();
+        assert_eq!(rotator.latest_key_age(), Some(Duration::from_secs(5)));
+
+        // The key gets older while the rotations fail.
+        *NOW.lock().unwrap() = 95;
+        rotator.rotate().unwrap_err();
+        assert_eq!(rotator.latest_key_age(), Some(Duration::from_secs(45)));
+
+        rotator.cache.clear();
+        assert_eq!(rotator.latest_key_age(), None);
+    }
+
+    #[test]
+    fn test_kdf_since() {
+        let _serial = SERIAL.lock().unwrap();
+
+        let mut hash_map = HashMap::new();
+        for epoch in (0..=100).step_by(10) {
+            hash_map.insert(format!("test/{}", epoch), vec![7; 32]);
+        }
+
+        *NOW.lock().unwrap() = 55;
+        let rotator = KeyRotator::connect(
+            Box::new(MockStore { prefix: String::from("test"), keys: hash_map }),
+            RotationConfig {
+                rotate_every: 10,
+                retain_previous: 3,
+                kdf: Kdf::HkdfSha512,
+                kdf_since: 50,
+                ..RotationConfig::default()
+            },
+            CookieKey::from(&[0, 32][..]),
+            NullLoggerBuilder.build().unwrap(),
+        ).unwrap();
+
+        // The keys before kdf_since are still derived with the old KDF.
+        let old_key = rotator.get(KeyId::from_epoch(40)).unwrap();
+        let new_key = rotator.get(KeyId::from_epoch(50)).unwrap();
+        assert_eq!(old_key.kdf(), Kdf::HmacSha256);
+        assert_eq!(new_key.kdf(), Kdf::HkdfSha512);
+        assert_eq!(new_key.as_ref().len(), DERIVED_KEY_LEN);
+        assert_ne!(old_key.as_ref(), new_key.as_ref());
+        assert_eq!(old_key.as_ref(), Kdf::HmacSha256.derive(&[0, 32], &[7; 32]).as_slice());
+    }
+
+    #[test]
+    fn test_get_rotation_config() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_rotation_config(&settings).unwrap(), RotationConfig::default());
+
+        settings.set("rotate_every", 600).unwrap();
+        settings.set("retain_previous", 6).unwrap();
+        assert_eq!(get_rotation_config(&settings).unwrap(),
+                   RotationConfig {
+                       rotate_every: 600,
+                       retain_previous: 6,
+                       ..RotationConfig::default()
+                   });
+
+        settings.set("retain_previous", 0).unwrap();
+        assert!(get_rotation_config(&settings).is_err());
+
+        settings.set("retain_previous", i64::max_value()).unwrap();
+        assert!(get_rotation_config(&settings).is_err());
+
+        settings.set("retain_previous", 6).unwrap();
+        settings.set("cookie_key_overlap", 0).unwrap();
+        assert_eq!(get_rotation_config(&settings).unwrap().master_key_overlap, 0);
+        settings.set("cookie_key_overlap", -1).unwrap();
+        assert!(get_rotation_config(&settings).is_err());
+
+        settings.set("cookie_key_overlap", 0).unwrap();
+        settings.set("kdf", "hkdf-sha512").unwrap();
+        settings.set("kdf_since", 7200).unwrap();
+        let rotation = get_rotation_config(&settings).unwrap();
+        assert_eq!((rotation.kdf, rotation.kdf_since), (Kdf::HkdfSha512, 7200));
+        settings.set("kdf", "md5").unwrap();
+        assert!(get_rotation_config(&settings).is_err());
+
+        settings.set("kdf", "hmac-sha256").unwrap();
+        settings.set("rotate_jitter", 60).unwrap();
+        assert_eq!(get_rotation_config(&settings).unwrap().jitter, 60);
+        settings.set("rotate_jitter", 600).unwrap();
+        assert!(get_rotation_config(&settings).is_err());
+    }
+
+    #[test]
+    fn test_jittered_sleep() {
+        let mut offset = 0;
+        assert_eq!(jittered_sleep(3600, &mut offset, 0), 3600);
+        assert_eq!(jittered_sleep(3600, &mut offset, 100), 3700);
+        assert_eq!(offset, 100);
+        assert_eq!(jittered_sleep(3600, &mut offset, 40), 3540);
+        assert_eq!(jittered_sleep(3600, &mut offset, 0), 3560);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(1, 0.0), Duration::from_millis(100));
+        assert_eq!(backoff_delay(1, 1.0), Duration::from_millis(200));
+        assert_eq!(backoff_delay(2, 0.0), Duration::from_millis(200));
+        assert_eq!(backoff_delay(3, 1.0), Duration::from_millis(800));
+
+        // The delay is capped.
+        assert_eq!(backoff_delay(10, 1.0), FETCH_BACKOFF_MAX);
+        assert_eq!(backoff_delay(std::u32::MAX, 0.0), FETCH_BACKOFF_MAX / 2);
+    }
+
+    #[test]
+    fn test_rotate_error() {
+        use std::error::Error;
+
+        let error = RotateError::from(RedisError::Server(String::from("ERR wrong type")));
+        assert_eq!(error.to_string(), "redis server error: ERR wrong type");
+        assert_eq!(error.source().unwrap().to_string(), "redis server error: ERR wrong type");
+
+        let error = RotateError::from(EtcdError::Status(503, String::from("no leader")));
+        assert_eq!(error.to_string(), "etcd server replied with status 503: no leader");
+        assert!(error.source().is_some());
+
+        let error = RotateError::from(VaultError::Token(String::from("expired")));
+        assert_eq!(error.to_string(), "vault token error: expired");
+        assert!(error.source().is_some());
+
+        let error = RotateError::from(ConsulError::Protocol(String::from("bad index")));
+        assert_eq!(error.to_string(), "consul protocol error: bad index");
+        assert!(error.source().is_some());
+
+        assert_eq!(RotateError::ReadOnly.to_string(), "the key store is read-only");
+        assert!(RotateError::ReadOnly.source().is_none());
+    }
 }
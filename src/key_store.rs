@@ -0,0 +1,738 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Key stores, which the servers read the shared keys from.
+//!
+//! `KeyRotator` only talks to a store through the `KeyStore` trait, so a new backend only has to
+//! implement it. The built-in backends are configured with `KeyStoreConfig`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::consul;
+use crate::error::WrapError;
+use crate::etcd;
+use crate::http;
+use crate::key_dir::{self, KeyDir};
+use crate::key_rotator::RotateError;
+use crate::memcached::{self, Credentials, TlsSettings};
+use crate::redis;
+use crate::vault;
+
+/// The default prefix of the keys in the key store.
+const DEFAULT_KEY_PREFIX: &str = "/nts/nts-keys";
+
+/// How long an endpoint that failed is skipped before it's tried again.
+const REPROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A store of the keys shared among the servers. There is a key for each period, which is
+/// identified by the epoch time at the beginning of the period.
+pub trait KeyStore: Send + Sync {
+    /// Return the keys of the periods that begin at `epochs`, in the same order. A key is `None`
+    /// if it's not in the store.
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError>;
+
+    /// Block and call `on_change` every time that some keys change in the store. It only returns
+    /// when the watch fails. If the store cannot be watched, it returns `Ok` right away, and the
+    /// keys are only synced periodically.
+    fn watch(&self, _on_change: &mut dyn FnMut()) -> Result<(), RotateError> {
+        Ok(())
+    }
+
+    /// Check that the store can be reached.
+    fn health(&self) -> Result<(), RotateError>;
+
+    /// Write the key of the period that begins at `epoch`. Only the stores that the key master
+    /// publishes to support it.
+    fn store_key(&self, _epoch: u64, _key: &[u8]) -> Result<(), RotateError> {
+        Err(RotateError::ReadOnly)
+    }
+}
+
+/// Where the keys shared among the servers are read from.
+#[derive(Clone, Debug)]
+pub enum KeyStoreConfig {
+    /// Memcached servers with the urls, for example, `memcache://localhost:11211`. The servers
+    /// are tried in order, so the first one that works is used. If `tls` is set, the connections
+    /// to the servers use TLS. If `credentials` is set, the connections are authenticated with
+    /// SASL.
+    Memcached {
+        urls: Vec<String>,
+        prefix: String,
+        tls: Option<TlsSettings>,
+        credentials: Option<Credentials>,
+    },
+
+    /// A Redis server with the url, for example, `redis://localhost:6379/0`.
+    Redis { url: String, prefix: String },
+
+    /// An etcd server with the url of its JSON gateway, for example, `http://localhost:2379`. The
+    /// prefix is watched, so new keys are picked up as soon as they are written.
+    Etcd { client: etcd::Client, prefix: String },
+
+    /// A Consul server with the url of its HTTP API, for example, `http://localhost:8500`. The
+    /// prefix is watched with blocking queries, so new keys are picked up as soon as they are
+    /// written.
+    Consul { client: consul::Client, prefix: String },
+
+    /// A local directory, where the missing keys are generated. It's only for the deployments
+    /// where all the servers run on the same host.
+    Directory { path: PathBuf },
+
+    /// A Vault server with the url, for example, `https://vault:8200`. The prefix is the API path
+    /// of the keys, and each key is in the `key` field of its secret. If `transit_key` is set, the
+    /// field is a ciphertext which is decrypted with that Transit key. Otherwise, it's base64.
+    Vault { client: vault::Client, prefix: String, transit_key: Option<String> },
+
+    /// The keys are generated by this server, which is the key master, and written to
+    /// `publish_to` for the NTP servers. There must be only one key master.
+    KeyMaster { publish_to: Box<KeyStoreConfig> },
+}
+
+impl KeyStoreConfig {
+    /// Create the store described by the config. It doesn't connect to the store yet.
+    pub fn build(&self) -> Box<dyn KeyStore> {
+        match self.clone() {
+            KeyStoreConfig::Memcached { urls, prefix, tls, credentials } => {
+                let endpoints = Endpoints::new(urls);
+                Box::new(MemcachedStore { endpoints, prefix, tls, credentials })
+            },
+            KeyStoreConfig::Redis { url, prefix } => Box::new(RedisStore { url, prefix }),
+            KeyStoreConfig::Etcd { client, prefix } => Box::new(EtcdStore { client, prefix }),
+            KeyStoreConfig::Consul { client, prefix } => Box::new(ConsulStore { client, prefix }),
+            KeyStoreConfig::Directory { path } => Box::new(DirectoryStore { path }),
+            KeyStoreConfig::Vault { client, prefix, transit_key } => {
+                Box::new(VaultStore { client, prefix, transit_key })
+            },
+            KeyStoreConfig::KeyMaster { publish_to } => Box::new(KeyMasterStore {
+                keys: Mutex::new(BTreeMap::new()),
+                publish_to: publish_to.build(),
+            }),
+        }
+    }
+}
+
+/// Parse a url, or a list of urls for the servers that are tried in order.
+fn get_urls(value: config::Value) -> Result<Vec<String>, config::ConfigError> {
+    let urls = match value.clone().into_array() {
+        Ok(values) => values.into_iter()
+            .map(config::Value::into_str)
+            .collect::<Result<Vec<_>, _>>()?,
+        // If it's not a list, it must be a single url.
+        Err(_) => vec![value.into_str()?],
+    };
+    if urls.is_empty() {
+        return Err(config::ConfigError::Message(String::from("the list of urls is empty")));
+    }
+    Ok(urls)
+}
+
+/// Parse the key store settings. They are in the `keystore` table with `backend` and `url`, and
+/// optionally `prefix`. The `directory` backend takes a `path` instead. The `memcached` backend
+/// takes a list of urls as well, and also `tls`, `tls_ca_file`, `tls_cert_file`, `tls_key_file`,
+/// `tls_server_name`, `username`, and `password_file`. The `consul` backend also takes
+/// `token_file`. The `vault` backend also takes `token_file` and `transit_key`. Without the
+/// table, the Memcached servers at `memc_url` are used with the prefix `memc_prefix`, if it's
+/// set.
+///
+/// If `key_master` is set, this server generates the keys and publishes them to that key store,
+/// which must be configured, because the NTP servers need the keys to read the cookies. Only the
+/// NTS-KE server can be the key master, so `key_master` is refused unless `key_master_allowed` is
+/// set.
+pub fn get_key_store_config(settings: &config::Config, key_master_allowed: bool)
+    -> Result<KeyStoreConfig, config::ConfigError>
+{
+    let key_master = match settings.get_bool("key_master") {
+        Err(config::ConfigError::NotFound(_)) => false,
+        result => result?,
+    };
+    if !key_master {
+        return get_shared_key_store_config(settings);
+    }
+    if !key_master_allowed {
+        return Err(config::ConfigError::Message(
+            String::from("only the NTS-KE server can be the key master")
+        ));
+    }
+
+    let publish_to = match get_shared_key_store_config(settings) {
+        Err(config::ConfigError::NotFound(_)) => {
+            return Err(config::ConfigError::Message(
+                String::from("the key master needs a key store to publish the keys to")
+            ));
+        },
+        Err(error) => return Err(error),
+        Ok(KeyStoreConfig::Directory { .. }) => {
+            return Err(config::ConfigError::Message(String::from(
+                "the key master can only publish to memcached, redis, etcd, consul, or vault"
+            )));
+        },
+        Ok(config) => Box::new(config),
+    };
+    Ok(KeyStoreConfig::KeyMaster { publish_to })
+}
+
+/// Parse the settings of the key store that is shared with the other servers.
+fn get_shared_key_store_config(settings: &config::Config)
+    -> Result<KeyStoreConfig, config::ConfigError>
+{
+    let mut table = match settings.get_table("keystore") {
+        Err(config::ConfigError::NotFound(_)) => {
+            let prefix = match settings.get_str("memc_prefix") {
+                Err(config::ConfigError::NotFound(_)) => String::from(DEFAULT_KEY_PREFIX),
+                result => result?,
+            };
+            return Ok(KeyStoreConfig::Memcached {
+                urls: get_urls(settings.get("memc_url")?)?,
+                prefix,
+                tls: None,
+                credentials: None,
+            });
+        },
+        Err(error) => return Err(error),
+        Ok(table) => table,
+    };
+
+    // Only the memcached backend uses it.
+    let tls_enabled = match table.remove("tls") {
+        Some(value) => value.into_bool()?,
+        None => false,
+    };
+    // Only the memcached backend takes a list.
+    let url = table.remove("url");
+
+    let mut take_str = |key: &str| match table.remove(key) {
+        Some(value) => value.into_str().map(Some),
+        None => Ok(None),
+    };
+    let backend = take_str("backend")?.ok_or_else(|| config::ConfigError::Message(
+        String::from("the keystore is missing backend")
+    ))?;
+    if backend == "directory" {
+        let path = take_str("path")?.ok_or_else(|| config::ConfigError::Message(
+            String::from("the keystore is missing path")
+        ))?;
+        return Ok(KeyStoreConfig::Directory { path: PathBuf::from(path) });
+    }
+
+    let url = url.ok_or_else(|| config::ConfigError::Message(
+        String::from("the keystore is missing url")
+    ))?;
+    let prefix = take_str("prefix")?.unwrap_or_else(|| String::from(DEFAULT_KEY_PREFIX));
+
+    match backend.as_str() {
+        "memcached" => {
+            let tls = if tls_enabled {
+                let ca_file = take_str("tls_ca_file")?;
+                let cert_file = take_str("tls_cert_file")?;
+                let key_file = take_str("tls_key_file")?;
+                let server_name = take_str("tls_server_name")?;
+                Some(TlsSettings::load(
+                    ca_file.as_ref().map(String::as_str),
+                    cert_file.as_ref().map(String::as_str),
+                    key_file.as_ref().map(String::as_str),
+                    server_name,
+                ).wrap_err()?)
+            } else {
+                None
+            };
+            let credentials = match (take_str("username")?, take_str("password_file")?) {
+                (Some(username), Some(password_file)) => {
+                    Some(Credentials::load(username, &password_file).wrap_err()?)
+                },
+                (None, None) => None,
+                _ => return Err(config::ConfigError::Message(
+                    String::from("the keystore username and password_file must be set together")
+                )),
+            };
+            Ok(KeyStoreConfig::Memcached { urls: get_urls(url)?, prefix, tls, credentials })
+        },
+        "redis" => Ok(KeyStoreConfig::Redis { url: url.into_str()?, prefix }),
+        "etcd" => {
+            let ca_file = take_str("tls_ca_file")?;
+            let http = http::Client::new(ca_file.as_ref().map(String::as_str)).wrap_err()?;
+            Ok(KeyStoreConfig::Etcd { client: etcd::Client::new(&url.into_str()?, http), prefix })
+        },
+        "consul" => {
+            let token_file = take_str("token_file")?.map(PathBuf::from);
+            Ok(KeyStoreConfig::Consul {
+                client: consul::Client::new(&url.into_str()?, token_file),
+                prefix,
+            })
+        },
+        "vault" => {
+            let token_file = take_str("token_file")?.map(PathBuf::from);
+            Ok(KeyStoreConfig::Vault {
+                client: vault::Client::new(&url.into_str()?, token_file),
+                prefix,
+                transit_key: take_str("transit_key")?,
+            })
+        },
+        _ => Err(config::ConfigError::Message(
+            format!("unknown keystore backend {}", backend)
+        )),
+    }
+}
+
+/// Servers that are tried in order until one of them works. A server that fails is skipped for
+/// `REPROBE_INTERVAL`, unless all the others fail too.
+struct Endpoints {
+    urls: Vec<String>,
+    /// When each server last failed, or `None` if it's healthy.
+    failed_at: Mutex<Vec<Option<Instant>>>,
+}
+
+impl Endpoints {
+    fn new(urls: Vec<String>) -> Endpoints {
+        let failed_at = Mutex::new(vec![None; urls.len()]);
+        Endpoints { urls, failed_at }
+    }
+
+    /// Call `f` with the url of each server in turn until it succeeds, and return its result. The
+    /// healthy servers and the ones due to be probed again come first, and then the others. If
+    /// all of them fail, return the last error.
+    fn try_each<T, F>(&self, mut f: F) -> Result<T, RotateError>
+    where
+        F: FnMut(&str) -> Result<T, RotateError>,
+    {
+        let now = Instant::now();
+        let (mut order, skipped): (Vec<usize>, Vec<usize>) = {
+            let failed_at = self.failed_at.lock().unwrap();
+            (0..self.urls.len()).partition(|index| match failed_at[*index] {
+                Some(time) => now.duration_since(time) >= REPROBE_INTERVAL,
+                None => true,
+            })
+        };
+        order.extend(skipped);
+
+        let mut last_error = None;
+        for index in order {
+            let result = f(&self.urls[index]);
+            let mut failed_at = self.failed_at.lock().unwrap();
+            match result {
+                Ok(value) => {
+                    failed_at[index] = None;
+                    return Ok(value);
+                },
+                Err(error) => {
+                    failed_at[index] = Some(Instant::now());
+                    last_error = Some(error);
+                },
+            }
+        }
+        // There is at least one url, so there is an error.
+        Err(last_error.unwrap())
+    }
+}
+
+/// Keys in Memcached servers.
+struct MemcachedStore {
+    endpoints: Endpoints,
+    prefix: String,
+    tls: Option<TlsSettings>,
+    credentials: Option<Credentials>,
+}
+
+impl MemcachedStore {
+    /// Connect to `url` with our own client, which supports TLS and SASL. It's `None` if neither
+    /// is configured, and then the `memcache` crate is used.
+    fn connect_secure(&self, url: &str) -> Result<Option<memcached::Client>, RotateError> {
+        if self.tls.is_none() && self.credentials.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(memcached::Client::connect(
+            url,
+            self.tls.as_ref(),
+            self.credentials.as_ref(),
+        )?))
+    }
+
+    /// Fetch the keys from the server at `url`.
+    fn fetch_keys_from(&self, url: &str, epochs: &[u64])
+        -> Result<Vec<Option<Vec<u8>>>, RotateError>
+    {
+        let names = epochs.iter().map(|epoch| format!("{}/{}", self.prefix, epoch));
+        let mut keys = Vec::new();
+        match self.connect_secure(url)? {
+            Some(mut client) => {
+                for name in names {
+                    keys.push(client.get(&name)?);
+                }
+            },
+            None => {
+                let mut client = memcache::Client::connect(url)?;
+                for name in names {
+                    keys.push(client.get(&name)?);
+                }
+            },
+        }
+        Ok(keys)
+    }
+}
+
+impl KeyStore for MemcachedStore {
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+        self.endpoints.try_each(|url| self.fetch_keys_from(url, epochs))
+    }
+
+    fn health(&self) -> Result<(), RotateError> {
+        self.endpoints.try_each(|url| {
+            match self.connect_secure(url)? {
+                Some(mut client) => client.version().map(|_| ())?,
+                None => memcache::Client::connect(url)?.version().map(|_| ())?,
+            }
+            Ok(())
+        })
+    }
+
+    fn store_key(&self, epoch: u64, key: &[u8]) -> Result<(), RotateError> {
+        let name = format!("{}/{}", self.prefix, epoch);
+        self.endpoints.try_each(|url| {
+            match self.connect_secure(url)? {
+                Some(mut client) => client.set(&name, key)?,
+                None => memcache::Client::connect(url)?.set(&name, key, 0)?,
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Keys in a Redis server.
+struct RedisStore {
+    url: String,
+    prefix: String,
+}
+
+impl KeyStore for RedisStore {
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+        let mut client = redis::Client::connect(&self.url)?;
+        let mut keys = Vec::new();
+        for epoch in epochs {
+            keys.push(client.get(&format!("{}/{}", self.prefix, epoch))?);
+        }
+        Ok(keys)
+    }
+
+    fn health(&self) -> Result<(), RotateError> {
+        Ok(redis::Client::connect(&self.url)?.ping()?)
+    }
+
+    fn store_key(&self, epoch: u64, key: &[u8]) -> Result<(), RotateError> {
+        let name = format!("{}/{}", self.prefix, epoch);
+        Ok(redis::Client::connect(&self.url)?.set(&name, key)?)
+    }
+}
+
+/// Keys in an etcd server.
+struct EtcdStore {
+    client: etcd::Client,
+    prefix: String,
+}
+
+impl KeyStore for EtcdStore {
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+        let mut keys = Vec::new();
+        for epoch in epochs {
+            keys.push(self.client.get(&format!("{}/{}", self.prefix, epoch))?);
+        }
+        Ok(keys)
+    }
+
+    fn watch(&self, on_change: &mut dyn FnMut()) -> Result<(), RotateError> {
+        Err(self.client.watch_prefix(&format!("{}/", self.prefix), on_change).into())
+    }
+
+    fn health(&self) -> Result<(), RotateError> {
+        Ok(self.client.health()?)
+    }
+
+    fn store_key(&self, epoch: u64, key: &[u8]) -> Result<(), RotateError> {
+        Ok(self.client.put(&format!("{}/{}", self.prefix, epoch), key)?)
+    }
+}
+
+/// Keys in a Consul server.
+struct ConsulStore {
+    client: consul::Client,
+    prefix: String,
+}
+
+impl KeyStore for ConsulStore {
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+        let mut keys = Vec::new();
+        for epoch in epochs {
+            keys.push(self.client.get(&format!("{}/{}", self.prefix, epoch))?);
+        }
+        Ok(keys)
+    }
+
+    fn watch(&self, on_change: &mut dyn FnMut()) -> Result<(), RotateError> {
+        Err(self.client.watch_prefix(&format!("{}/", self.prefix), on_change).into())
+    }
+
+    fn health(&self) -> Result<(), RotateError> {
+        Ok(self.client.health()?)
+    }
+
+    fn store_key(&self, epoch: u64, key: &[u8]) -> Result<(), RotateError> {
+        Ok(self.client.put(&format!("{}/{}", self.prefix, epoch), key)?)
+    }
+}
+
+/// Keys in a local directory.
+struct DirectoryStore {
+    path: PathBuf,
+}
+
+impl KeyStore for DirectoryStore {
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+        let dir = KeyDir::open(&self.path)?;
+        let mut keys = Vec::new();
+        for epoch in epochs {
+            // The local keys are generated on demand, so they always exist.
+            keys.push(Some(dir.get_or_create(&epoch.to_string())?));
+        }
+
+        // Nobody else removes the old local keys. If it fails, the keys are left until the next
+        // time, which is harmless.
+        if let Some(first_epoch) = epochs.iter().min() {
+            let _ = dir.remove_before(*first_epoch);
+        }
+        Ok(keys)
+    }
+
+    fn health(&self) -> Result<(), RotateError> {
+        KeyDir::open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Keys in a Vault server.
+struct VaultStore {
+    client: vault::Client,
+    prefix: String,
+    transit_key: Option<String>,
+}
+
+impl KeyStore for VaultStore {
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+        let mut keys = Vec::new();
+        for epoch in epochs {
+            let path = format!("{}/{}", self.prefix, epoch);
+            let key = match &self.transit_key {
+                None => self.client.read_field(&path, "key")?,
+                Some(transit_key) => match self.client.read_string(&path, "key")? {
+                    Some(ciphertext) => {
+                        Some(self.client.transit_decrypt(transit_key, &ciphertext)?)
+                    },
+                    None => None,
+                },
+            };
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    fn health(&self) -> Result<(), RotateError> {
+        Ok(self.client.health()?)
+    }
+
+    fn store_key(&self, epoch: u64, key: &[u8]) -> Result<(), RotateError> {
+        let path = format!("{}/{}", self.prefix, epoch);
+        let value = match &self.transit_key {
+            None => base64::encode(key),
+            Some(transit_key) => self.client.transit_encrypt(transit_key, key)?,
+        };
+        Ok(self.client.write_string(&path, "key", &value)?)
+    }
+}
+
+/// Keys generated by the key master.
+struct KeyMasterStore {
+    /// The keys that are generated or read from `publish_to`, by epoch. A key that failed to be
+    /// published is kept, so the same key is published again instead of a new one.
+    keys: Mutex<BTreeMap<u64, Vec<u8>>>,
+    publish_to: Box<dyn KeyStore>,
+}
+
+impl KeyStore for KeyMasterStore {
+    fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+        // The keys that are already published win, for example, after a restart.
+        let published = self.publish_to.fetch_keys(epochs)?;
+
+        let mut keys = self.keys.lock().unwrap();
+        let mut result = Vec::new();
+        for (epoch, published_key) in epochs.iter().zip(published) {
+            let key = match published_key {
+                Some(key) => key,
+                None => {
+                    let key = keys.entry(*epoch).or_insert_with(key_dir::generate_key).clone();
+                    self.publish_to.store_key(*epoch, &key)?;
+                    key
+                },
+            };
+            keys.insert(*epoch, key.clone());
+            result.push(Some(key));
+        }
+
+        // Forget the keys that are out of the window.
+        if let Some(first_epoch) = epochs.iter().min() {
+            *keys = keys.split_off(first_epoch);
+        }
+        Ok(result)
+    }
+
+    fn health(&self) -> Result<(), RotateError> {
+        self.publish_to.health()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error() -> RotateError {
+        RotateError::IoError(std::io::Error::new(std::io::ErrorKind::Other, "down"))
+    }
+
+    #[test]
+    fn test_failover() {
+        let endpoints = Endpoints::new(vec![String::from("a"), String::from("b"),
+                                            String::from("c")]);
+
+        let mut tried = Vec::new();
+        let result = endpoints.try_each(|url| {
+            tried.push(String::from(url));
+            if url == "c" { Ok(url.len()) } else { Err(error()) }
+        });
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(tried, vec!["a", "b", "c"]);
+
+        // The failed servers are tried last until they are due to be probed again.
+        let mut tried = Vec::new();
+        let _ = endpoints.try_each(|url| -> Result<(), RotateError> {
+            tried.push(String::from(url));
+            Err(error())
+        });
+        assert_eq!(tried, vec!["c", "a", "b"]);
+
+        endpoints.failed_at.lock().unwrap()[0] = Some(Instant::now() - REPROBE_INTERVAL);
+        let mut tried = Vec::new();
+        let result = endpoints.try_each(|url| {
+            tried.push(String::from(url));
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(tried, vec!["a"]);
+        assert!(endpoints.failed_at.lock().unwrap()[0].is_none());
+    }
+
+    /// A store in memory.
+    #[derive(Default)]
+    struct MemoryStore {
+        keys: Mutex<BTreeMap<u64, Vec<u8>>>,
+    }
+
+    impl KeyStore for MemoryStore {
+        fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+            let keys = self.keys.lock().unwrap();
+            Ok(epochs.iter().map(|epoch| keys.get(epoch).cloned()).collect())
+        }
+
+        fn health(&self) -> Result<(), RotateError> {
+            Ok(())
+        }
+
+        fn store_key(&self, epoch: u64, key: &[u8]) -> Result<(), RotateError> {
+            self.keys.lock().unwrap().insert(epoch, key.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_key_master() {
+        let store = KeyMasterStore {
+            keys: Mutex::new(BTreeMap::new()),
+            publish_to: Box::new(MemoryStore::default()),
+        };
+        let keys = store.fetch_keys(&[0, 10, 20]).unwrap();
+        assert!(keys.iter().all(Option::is_some));
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(store.fetch_keys(&[10, 20, 30]).unwrap()[..2], keys[1..]);
+        assert!(!store.keys.lock().unwrap().contains_key(&0));
+
+        let published = MemoryStore::default();
+        published.keys.lock().unwrap().insert(0, b"published".to_vec());
+        let store = KeyMasterStore {
+            keys: Mutex::new(BTreeMap::new()),
+            publish_to: Box::new(published),
+        };
+        let keys = store.fetch_keys(&[0, 10]).unwrap();
+        assert_eq!(keys[0], Some(b"published".to_vec()));
+        assert_eq!(store.publish_to.fetch_keys(&[10]).unwrap()[0], keys[1]);
+    }
+
+    #[test]
+    fn test_get_key_master_config() {
+        let mut settings = config::Config::new();
+        settings.set("key_master", true).unwrap();
+        // There is no key store to publish the keys to.
+        assert!(get_key_store_config(&settings, true).is_err());
+
+        settings.set("memc_url", "memcache://a:11211").unwrap();
+        match get_key_store_config(&settings, true).unwrap() {
+            KeyStoreConfig::KeyMaster { publish_to } => match *publish_to {
+                KeyStoreConfig::Memcached { .. } => (),
+                _ => panic!("the key master doesn't publish to memcached"),
+            },
+            _ => panic!("the backend is not the key master"),
+        }
+        // The NTP server cannot be the key master.
+        assert!(get_key_store_config(&settings, false).is_err());
+
+        settings.set("keystore.backend", "vault").unwrap();
+        settings.set("keystore.url", "https://vault:8200").unwrap();
+        settings.set("keystore.transit_key", "cfnts").unwrap();
+        match get_key_store_config(&settings, true).unwrap() {
+            KeyStoreConfig::KeyMaster { publish_to } => match *publish_to {
+                KeyStoreConfig::Vault { transit_key, .. } => {
+                    assert_eq!(transit_key, Some(String::from("cfnts")))
+                },
+                _ => panic!("the key master doesn't publish to vault"),
+            },
+            _ => panic!("the backend is not the key master"),
+        }
+
+        settings.set("keystore.backend", "directory").unwrap();
+        settings.set("keystore.path", "/var/lib/cfnts/keys").unwrap();
+        assert!(get_key_store_config(&settings, true).is_err());
+    }
+
+    #[test]
+    fn test_get_urls() {
+        let mut settings = config::Config::new();
+        settings.set("memc_url", "memcache://a:11211").unwrap();
+        match get_key_store_config(&settings, false).unwrap() {
+            KeyStoreConfig::Memcached { urls, .. } => assert_eq!(urls, vec!["memcache://a:11211"]),
+            _ => panic!("the backend is not memcached"),
+        }
+
+        settings.set("memc_prefix", "/staging/nts-keys").unwrap();
+        match get_key_store_config(&settings, false).unwrap() {
+            KeyStoreConfig::Memcached { prefix, .. } => assert_eq!(prefix, "/staging/nts-keys"),
+            _ => panic!("the backend is not memcached"),
+        }
+
+        settings.set("memc_url", vec!["memcache://a:11211", "memcache://b:11211"]).unwrap();
+        match get_key_store_config(&settings, false).unwrap() {
+            KeyStoreConfig::Memcached { urls, .. } => assert_eq!(urls.len(), 2),
+            _ => panic!("the backend is not memcached"),
+        }
+
+        settings.set("memc_url", Vec::<String>::new()).unwrap();
+        assert!(get_key_store_config(&settings, false).is_err());
+    }
+}
@@ -10,15 +10,30 @@ extern crate slog_scope;
 extern crate slog_stdlog;
 extern crate sloggers;
 
+mod admin;
+mod aes_gcm_siv;
+mod aws;
 mod cfsock;
+mod cidr;
 mod cmd;
+mod consul;
 mod cookie;
 mod error;
+mod etcd;
+mod http;
+mod key_dir;
 mod key_rotator;
+mod key_store;
+mod memcached;
 mod metrics;
 mod ntp;
 mod nts_ke;
+mod rate_limiter;
+mod redis;
+mod signal;
 mod sub_command;
+mod systemd;
+mod vault;
 
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
@@ -48,6 +63,9 @@ fn create_logger<'a>(matches: &clap::ArgMatches<'a>) -> slog::Logger {
 
 /// The entry point of cfnts.
 fn main() {
+    // The sockets passed by systemd have to be taken before any thread is spawned.
+    systemd::init();
+
     // According to the documentation of `get_matches`, if the parsing fails, an error will be
     // displayed to the user and the process will exit with an error code.
     let matches = cmd::create_clap_command().get_matches();
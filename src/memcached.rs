@@ -0,0 +1,388 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A minimal Memcached client which speaks just enough of the binary protocol to read and write
+//! the keys over TLS, or with SASL authentication. The `memcache` crate only supports cleartext
+//! connections and takes the credentials from the url, so this client is used when either is
+//! configured. See https://github.com/memcached/memcached/wiki/BinaryProtocolRevamped.
+
+use memcache::MemcacheError;
+
+use rustls::internal::pemfile;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a connection, a request, or a response can take.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default port of Memcached.
+const DEFAULT_PORT: u16 = 11211;
+
+/// The maximum length of a response body that we accept. The keys are much shorter.
+const MAX_BODY_LEN: usize = 1 << 20;
+
+/// The length of the request and response headers.
+const HEADER_LEN: usize = 24;
+
+const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+
+const OPCODE_GET: u8 = 0x00;
+const OPCODE_SET: u8 = 0x01;
+const OPCODE_VERSION: u8 = 0x0b;
+const OPCODE_SASL_AUTH: u8 = 0x21;
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_AUTH_ERROR: u16 = 0x0020;
+
+/// The TLS settings of the connections to Memcached.
+#[derive(Clone)]
+pub struct TlsSettings {
+    config: Arc<rustls::ClientConfig>,
+
+    /// The name that the server certificate must have. If it's `None`, the host in the url is
+    /// used.
+    server_name: Option<String>,
+}
+
+// `rustls::ClientConfig` doesn't implement `Debug`.
+impl fmt::Debug for TlsSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsSettings")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+/// The SASL credentials of the connections to Memcached.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+// Don't leak the password into the logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+impl Credentials {
+    /// Create the credentials with the password in `password_file`. The trailing newline of the
+    /// file is ignored.
+    pub fn load(username: String, password_file: &str) -> Result<Credentials, std::io::Error> {
+        let password = std::fs::read_to_string(password_file)?;
+        let password = String::from(password.trim_end_matches(&['\n', '\r'][..]));
+        Ok(Credentials { username, password })
+    }
+
+    /// Return the SASL PLAIN message, which is an empty authorization identity, the username, and
+    /// the password, separated by NUL.
+    fn plain_message(&self) -> Vec<u8> {
+        format!("\0{}\0{}", self.username, self.password).into_bytes()
+    }
+}
+
+/// Return an error about the file `filename` which has invalid content.
+fn invalid_file(what: &str, filename: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("cannot parse {} from {}", what, filename),
+    )
+}
+
+impl TlsSettings {
+    /// Load the TLS settings. The server certificate is verified with the CA certificates in
+    /// `ca_file`, or the well-known roots if it's `None`. If `cert_file` and `key_file` are set,
+    /// the client authenticates itself with that certificate.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if any file cannot be read or parsed, or only one of `cert_file`
+    /// and `key_file` is set.
+    ///
+    pub fn load(
+        ca_file: Option<&str>,
+        cert_file: Option<&str>,
+        key_file: Option<&str>,
+        server_name: Option<String>,
+    ) -> Result<TlsSettings, std::io::Error> {
+        let mut config = rustls::ClientConfig::new();
+
+        match ca_file {
+            Some(ca_file) => {
+                let mut reader = BufReader::new(File::open(ca_file)?);
+                let certs = pemfile::certs(&mut reader)
+                    .map_err(|()| invalid_file("CA certificates", ca_file))?;
+                for cert in &certs {
+                    config.root_store.add(cert)
+                        .map_err(|_| invalid_file("CA certificates", ca_file))?;
+                }
+            },
+            None => config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+
+        match (cert_file, key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let mut reader = BufReader::new(File::open(cert_file)?);
+                let certs = pemfile::certs(&mut reader)
+                    .map_err(|()| invalid_file("certificates", cert_file))?;
+                let mut reader = BufReader::new(File::open(key_file)?);
+                let key = pemfile::pkcs8_private_keys(&mut reader)
+                    .ok()
+                    .and_then(|mut keys| keys.pop())
+                    .ok_or_else(|| invalid_file("a private key", key_file))?;
+                config.set_single_client_cert(certs, key);
+            },
+            (None, None) => (),
+            _ => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the client certificate and key must be set together",
+            )),
+        }
+
+        Ok(TlsSettings { config: Arc::new(config), server_name })
+    }
+}
+
+/// Encode a request.
+fn encode_request(opcode: u8, extras: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+    let body_len = extras.len() + key.len() + value.len();
+    let mut request = Vec::with_capacity(HEADER_LEN + body_len);
+    request.push(REQUEST_MAGIC);
+    request.push(opcode);
+    request.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    request.push(extras.len() as u8);
+    // Data type and vbucket id.
+    request.extend_from_slice(&[0, 0, 0]);
+    request.extend_from_slice(&(body_len as u32).to_be_bytes());
+    // Opaque and CAS.
+    request.extend_from_slice(&[0; 12]);
+    request.extend_from_slice(extras);
+    request.extend_from_slice(key);
+    request.extend_from_slice(value);
+    request
+}
+
+/// A response from the server.
+#[derive(Debug, Eq, PartialEq)]
+struct Response {
+    status: u16,
+    value: Vec<u8>,
+}
+
+/// Read a response from the server.
+fn read_response(reader: &mut impl Read) -> Result<Response, MemcacheError> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if header[0] != RESPONSE_MAGIC {
+        return Err(MemcacheError::ClientError(String::from("invalid response magic")));
+    }
+
+    let key_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let extras_len = header[4] as usize;
+    let status = u16::from_be_bytes([header[6], header[7]]);
+    let body_len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    if body_len > MAX_BODY_LEN || extras_len + key_len > body_len {
+        return Err(MemcacheError::ClientError(format!("invalid body length {}", body_len)));
+    }
+
+    let mut body = vec![0; body_len];
+    reader.read_exact(&mut body)?;
+    Ok(Response { status, value: body.split_off(extras_len + key_len) })
+}
+
+/// A connection that is either plain TCP or TLS.
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// A connection to a Memcached server.
+pub struct Client {
+    stream: Box<dyn Connection>,
+}
+
+impl Client {
+    /// Connect to the server at `url`, which looks like `memcache://host[:port]`. The connection
+    /// uses TLS, if `tls` is set, and is authenticated with SASL PLAIN, if `credentials` is set.
+    pub fn connect(
+        url: &str,
+        tls: Option<&TlsSettings>,
+        credentials: Option<&Credentials>,
+    ) -> Result<Client, MemcacheError> {
+        let parsed = url::Url::parse(url)
+            .map_err(|error| MemcacheError::ClientError(error.to_string()))?;
+        let host = parsed.host_str()
+            .ok_or_else(|| MemcacheError::ClientError(String::from("the host is missing")))?;
+        let port = parsed.port().unwrap_or(DEFAULT_PORT);
+
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let stream: Box<dyn Connection> = match tls {
+            Some(tls) => {
+                let server_name = tls.server_name.as_ref().map(String::as_str).unwrap_or(host);
+                let dns_name = webpki::DNSNameRef::try_from_ascii_str(server_name)
+                    .map_err(|_| {
+                        MemcacheError::ClientError(format!("invalid server name {}", server_name))
+                    })?;
+                let session = rustls::ClientSession::new(&tls.config, dns_name);
+                Box::new(rustls::StreamOwned::new(session, stream))
+            },
+            None => Box::new(stream),
+        };
+        let mut client = Client { stream };
+
+        if let Some(credentials) = credentials {
+            client.authenticate(credentials)?;
+        }
+        Ok(client)
+    }
+
+    /// Authenticate with SASL PLAIN.
+    fn authenticate(&mut self, credentials: &Credentials) -> Result<(), MemcacheError> {
+        let response = self.request(OPCODE_SASL_AUTH, &[], b"PLAIN",
+                                    &credentials.plain_message())?;
+        match response.status {
+            STATUS_OK => Ok(()),
+            STATUS_AUTH_ERROR => Err(MemcacheError::ClientError(
+                format!("authentication failed for {}", credentials.username)
+            )),
+            status => Err(MemcacheError::ServerError(status)),
+        }
+    }
+
+    /// Send a request and read its response.
+    fn request(&mut self, opcode: u8, extras: &[u8], key: &[u8], value: &[u8])
+        -> Result<Response, MemcacheError>
+    {
+        self.stream.write_all(&encode_request(opcode, extras, key, value))?;
+        self.stream.flush()?;
+        read_response(&mut self.stream)
+    }
+
+    /// Return the value of `key`, if it exists.
+    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, MemcacheError> {
+        let response = self.request(OPCODE_GET, &[], key.as_bytes(), &[])?;
+        match response.status {
+            STATUS_OK => Ok(Some(response.value)),
+            STATUS_KEY_NOT_FOUND => Ok(None),
+            status => Err(MemcacheError::ServerError(status)),
+        }
+    }
+
+    /// Set the value of `key`, which never expires.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), MemcacheError> {
+        // The flags and the expiration time.
+        let response = self.request(OPCODE_SET, &[0; 8], key.as_bytes(), value)?;
+        match response.status {
+            STATUS_OK => Ok(()),
+            status => Err(MemcacheError::ServerError(status)),
+        }
+    }
+
+    /// Return the version of the server.
+    pub fn version(&mut self) -> Result<String, MemcacheError> {
+        let response = self.request(OPCODE_VERSION, &[], &[], &[])?;
+        match response.status {
+            STATUS_OK => Ok(String::from_utf8(response.value)?),
+            status => Err(MemcacheError::ServerError(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request() {
+        let request = encode_request(OPCODE_GET, &[], b"key", &[]);
+        assert_eq!(request, vec![
+            0x80, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            b'k', b'e', b'y',
+        ]);
+
+        // A SET request with the flags and the expiration time.
+        let request = encode_request(OPCODE_SET, &[0; 8], b"key", b"value");
+        assert_eq!(&request[..12], &[
+            0x80, 0x01, 0x00, 0x03, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10,
+        ]);
+        assert_eq!(&request[HEADER_LEN + 8..], &b"keyvalue"[..]);
+    }
+
+    #[test]
+    fn test_read_response() {
+        let read = |mut data: &[u8]| read_response(&mut data);
+
+        // A GET response with 4 bytes of flags.
+        let response = read(&[
+            0x81, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0xde, 0xad, 0xbe, 0xef, b'W', b'o', b'r', b'l', b'd',
+        ]).unwrap();
+        assert_eq!(response, Response { status: STATUS_OK, value: b"World".to_vec() });
+
+        // Key not found.
+        let response = read(&[
+            0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            b'N', b'o', b't', b' ', b'f', b'o', b'u', b'n', b'd',
+        ]).unwrap();
+        assert_eq!(response.status, STATUS_KEY_NOT_FOUND);
+
+        // Invalid responses.
+        assert!(read(&[0x81, 0x00]).is_err());
+        assert!(read(&[0x80; HEADER_LEN]).is_err());
+        let mut truncated = vec![0x81, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5];
+        truncated.extend_from_slice(&[0; 12]);
+        truncated.extend_from_slice(b"abc");
+        assert!(read(&truncated).is_err());
+        // The extras and the key are longer than the body.
+        let mut header = vec![0x81, 0, 0, 2, 4, 0, 0, 0, 0, 0, 0, 5];
+        header.extend_from_slice(&[0; 12]);
+        header.extend_from_slice(b"abcde");
+        assert!(read(&header).is_err());
+    }
+
+    #[test]
+    fn test_load_tls_settings() {
+        assert!(TlsSettings::load(None, None, None, None).is_ok());
+        assert!(TlsSettings::load(Some("tests/ca.pem"), Some("tests/tls.pem"),
+                                  Some("tests/tls-pkcs8.pem"), None).is_ok());
+        assert!(TlsSettings::load(Some("/nonexistent/ca.pem"), None, None, None).is_err());
+        assert!(TlsSettings::load(None, Some("tests/intermediate.pem"), None, None).is_err());
+    }
+
+    #[test]
+    fn test_sasl_plain() {
+        let credentials = Credentials {
+            username: String::from("cfnts"),
+            password: String::from("secret"),
+        };
+        let request = encode_request(OPCODE_SASL_AUTH, &[], b"PLAIN",
+                                     &credentials.plain_message());
+        assert_eq!(&request[..12], &[
+            0x80, 0x21, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12,
+        ]);
+        assert_eq!(&request[HEADER_LEN..], &b"PLAIN\0cfnts\0secret"[..]);
+
+        // The password is not in the debug output.
+        assert!(!format!("{:?}", credentials).contains("secret"));
+    }
+}
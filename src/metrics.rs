@@ -1,14 +1,21 @@
 // Our goal is to shove data at prometheus in response to requests.
 use lazy_static::lazy_static;
 use prometheus::{
-    self, register_int_gauge, Encoder, __register_gauge, labels, opts,
+    self, exponential_buckets, histogram_opts, register_histogram_vec, register_int_gauge,
+    Encoder, HistogramVec, __register_gauge, labels, opts,
 };
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::net;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
-use slog::{error};
+use crate::systemd;
+
+use slog::{error, info};
 
 #[derive(Clone, Debug)]
 pub struct MetricsConfig {
@@ -16,6 +23,23 @@ pub struct MetricsConfig {
     pub addr: String,
 }
 
+/// Check whether the server is ready to serve the clients, for `GET /readyz`. It returns the
+/// reason, if it's not.
+pub type ReadinessCheck = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// The total of a counter with some labels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CounterSample {
+    pub name: String,
+    pub help: String,
+    /// The labels, sorted by their names.
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// How long a client can take to send its request line.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 lazy_static! {
@@ -27,19 +51,170 @@ lazy_static! {
         }
     ))
     .unwrap();
+    static ref KE_EXCHANGE_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "nts_ke_exchange_duration_seconds",
+        "Time from accepting an NTS-KE connection to flushing the response, or to closing the \
+         connection if there is no response",
+        &["result"],
+        // From 1ms to about 16s.
+        exponential_buckets(0.001, 2.0, 15).unwrap()
+    ).unwrap();
+    /// The totals of the counters from before the process restarted.
+    static ref RESTORED_COUNTERS: Mutex<Vec<CounterSample>> = Mutex::new(Vec::new());
+}
+
+/// Add the totals of the counters from before the process restarted to the ones that it counts,
+/// so that a restart doesn't reset them.
+pub fn restore_counters(samples: Vec<CounterSample>) {
+    *RESTORED_COUNTERS.lock().unwrap() = samples;
+}
+
+/// Return the totals of all the counters, including the ones from before the process restarted.
+pub fn counter_samples() -> Vec<CounterSample> {
+    let mut samples = Vec::new();
+    for family in gather() {
+        if family.get_field_type() != MetricType::COUNTER {
+            continue;
+        }
+        for metric in family.get_metric() {
+            samples.push(CounterSample {
+                name: family.get_name().to_owned(),
+                help: family.get_help().to_owned(),
+                labels: metric_labels(metric),
+                value: metric.get_counter().get_value(),
+            });
+        }
+    }
+    samples
+}
+
+fn metric_labels(metric: &Metric) -> Vec<(String, String)> {
+    metric.get_label().iter()
+        .map(|pair| (pair.get_name().to_owned(), pair.get_value().to_owned()))
+        .collect()
+}
+
+/// Add the `restored` counters to the ones in `families`. The ones that haven't been counted
+/// since the restart are added as they are.
+fn add_restored(families: &mut Vec<MetricFamily>, restored: &[CounterSample]) {
+    for sample in restored {
+        let family = match families.iter().position(|family| family.get_name() == sample.name) {
+            Some(index) => &mut families[index],
+            None => {
+                let mut family = MetricFamily::new();
+                family.set_name(sample.name.clone());
+                family.set_help(sample.help.clone());
+                family.set_field_type(MetricType::COUNTER);
+                families.push(family);
+                families.last_mut().unwrap()
+            },
+        };
+        // A metric that is no longer a counter starts over.
+        if family.get_field_type() != MetricType::COUNTER {
+            continue;
+        }
+        let metrics = family.mut_metric();
+        match metrics.iter_mut().find(|metric| metric_labels(metric) == sample.labels) {
+            Some(metric) => {
+                let value = metric.get_counter().get_value() + sample.value;
+                metric.mut_counter().set_value(value);
+            },
+            None => {
+                let mut metric = Metric::new();
+                for (name, value) in &sample.labels {
+                    let mut pair = LabelPair::new();
+                    pair.set_name(name.clone());
+                    pair.set_value(value.clone());
+                    metric.mut_label().push(pair);
+                }
+                metric.mut_counter().set_value(sample.value);
+                metrics.push(metric);
+            },
+        }
+    }
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+}
+
+/// Return the current values of all the metrics, with the counters from before the process
+/// restarted added.
+fn gather() -> Vec<MetricFamily> {
+    let mut families = prometheus::gather();
+    add_restored(&mut families, &RESTORED_COUNTERS.lock().unwrap());
+    families
+}
+
+/// Record how long an NTS-KE exchange took and how it ended, for example, `ok` or the name of the
+/// Error record sent.
+pub fn observe_ke_exchange(result: &str, duration: Duration) {
+    let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9;
+    KE_EXCHANGE_HISTOGRAM.with_label_values(&[result]).observe(seconds);
 }
 
 fn scrape_result() -> String {
     let mut buffer = Vec::new();
     let encoder = prometheus::TextEncoder::new();
-    let families = prometheus::gather();
+    let families = gather();
     encoder.encode(&families, &mut buffer).unwrap();
     "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\r\n".to_owned()
         + &String::from_utf8(buffer).unwrap()
 }
 
-fn serve_metrics(mut dest: net::TcpStream, logger: slog::Logger) -> () {
-    if let Err(e) = dest.write(&scrape_result().as_bytes()) {
+/// Write the current values of all the metrics to the log, one metric per line. This is used
+/// before exiting so that the last values are not lost.
+pub fn log_metrics(logger: &slog::Logger) {
+    let mut buffer = Vec::new();
+    let encoder = prometheus::TextEncoder::new();
+    let families = gather();
+    if let Err(error) = encoder.encode(&families, &mut buffer) {
+        error!(logger, "cannot encode metrics: {:?}", error);
+        return;
+    }
+
+    for line in String::from_utf8_lossy(&buffer).lines() {
+        // Skip the HELP and TYPE comments.
+        if !line.starts_with('#') {
+            info!(logger, "metric {}", line);
+        }
+    }
+}
+
+/// Return the path in the request line of an HTTP request.
+fn request_path(request_line: &str) -> Option<&str> {
+    request_line.split(' ').nth(1)
+}
+
+/// Return the response to `GET /readyz`.
+fn readiness_result(readiness: &ReadinessCheck, logger: &slog::Logger) -> String {
+    let (status, body) = match readiness() {
+        Ok(()) => ("200 OK", String::from("ready\n")),
+        Err(reason) => {
+            info!(logger, "not ready: {}", reason);
+            ("503 Service Unavailable", format!("not ready: {}\n", reason))
+        },
+    };
+    format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status, body.len(), body)
+}
+
+fn serve_metrics(
+    mut dest: net::TcpStream,
+    readiness: Option<ReadinessCheck>,
+    logger: slog::Logger,
+) -> () {
+    // Only the path of the request matters. Anything other than /readyz gets the metrics.
+    let mut request_line = String::new();
+    if let Err(e) = dest.set_read_timeout(Some(REQUEST_TIMEOUT))
+        .and_then(|()| BufReader::new(&dest).read_line(&mut request_line))
+    {
+        error!(logger, "read from TcpStream failed with error: {:?}, unable to serve metrics", e);
+        return;
+    }
+    let response = match (request_path(&request_line), &readiness) {
+        (Some("/readyz"), Some(readiness)) => readiness_result(readiness, &logger),
+        _ => scrape_result(),
+    };
+
+    if let Err(e) = dest.write(response.as_bytes()) {
         error!(logger, "write to TcpStream failed with error: {:?}, unable to serve metrics", e);
     }
     if let Err(e) = dest.shutdown(net::Shutdown::Write) {
@@ -47,17 +222,31 @@ fn serve_metrics(mut dest: net::TcpStream, logger: slog::Logger) -> () {
     }
 }
 
-/// Runs the metric server on the address and port set in config
+/// Use the socket passed by systemd for the address and port in config, if any. Otherwise, bind
+/// a new one.
+fn bind_metrics(conf: &MetricsConfig) -> Result<net::TcpListener, std::io::Error> {
+    for addr in (conf.addr.as_str(), conf.port).to_socket_addrs()? {
+        if let Some(listener) = systemd::tcp_listener(&addr)? {
+            return Ok(listener);
+        }
+    }
+    net::TcpListener::bind((conf.addr.as_str(), conf.port))
+}
+
+/// Runs the metric server on the address and port set in config. If there is a `readiness`
+/// check, it's also served on `/readyz`.
 pub fn run_metrics(conf: MetricsConfig,
+                   readiness: Option<ReadinessCheck>,
                    logger: &slog::Logger) -> Result<(), std::io::Error> {
     VERSION_INFO.set(1);
-    let accept = net::TcpListener::bind((conf.addr.as_str(), conf.port))?;
+    let accept = bind_metrics(&conf)?;
     for stream in accept.incoming() {
         match stream {
             Ok(conn) => {
                 let log_metrics = logger.new(slog::o!("component"=>"serve_metrics"));
+                let readiness = readiness.clone();
                 thread::spawn(move || {
-                    serve_metrics(conn, log_metrics);
+                    serve_metrics(conn, readiness, log_metrics);
                 });
             }
             Err(err) => return Err(err),
@@ -65,3 +254,44 @@ pub fn run_metrics(conf: MetricsConfig,
     }
     return Err(io::Error::new(io::ErrorKind::Other, "unreachable"));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_restored() {
+        let sample = |name: &str, labels: &[(&str, &str)], value| CounterSample {
+            name: name.to_owned(),
+            help: String::from("Number of queries"),
+            labels: labels.iter().map(|&(n, v)| (n.to_owned(), v.to_owned())).collect(),
+            value,
+        };
+        let mut families = Vec::new();
+        add_restored(&mut families, &[sample("queries_total", &[("family", "ipv4")], 3.0)]);
+        let restored = [
+            sample("queries_total", &[("family", "ipv4")], 2.0),
+            sample("queries_total", &[("family", "ipv6")], 1.0),
+            sample("kod_total", &[], 5.0),
+        ];
+        add_restored(&mut families, &restored);
+
+        // The totals are added to the ones that were counted, and the others are added as they
+        // are.
+        assert_eq!(families.iter().map(|family| family.get_name()).collect::<Vec<_>>(),
+                   vec!["kod_total", "queries_total"]);
+        let queries = families[1].get_metric();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].get_counter().get_value(), 5.0);
+        let labels = vec![(String::from("family"), String::from("ipv6"))];
+        assert_eq!(metric_labels(&queries[1]), labels);
+        assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 5.0);
+    }
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("GET /readyz HTTP/1.1\r\n"), Some("/readyz"));
+        assert_eq!(request_path("GET /metrics HTTP/1.1\r\n"), Some("/metrics"));
+        assert_eq!(request_path(""), None);
+    }
+}
@@ -3,21 +3,24 @@ use crate::nts_ke::client::NtsKeResult;
 use miscreant::aead::Aead;
 use miscreant::aead::Aes128SivAead;
 use rand::Rng;
-use slog::{debug};
+use slog::{debug, info};
 use std::error::Error;
 use std::fmt;
 
-use std::net::{UdpSocket, ToSocketAddrs};
+use std::net::{SocketAddr, UdpSocket, ToSocketAddrs};
 use std::time::{Duration, SystemTime};
 
+use super::protocol::parse_ntp_packet;
 use super::protocol::parse_nts_packet;
+use super::protocol::serialize_ntp_packet;
 use super::protocol::serialize_nts_packet;
 use super::protocol::LeapState;
 use super::protocol::NtpExtension;
 use super::protocol::NtpExtensionType::*;
+use super::protocol::NtpPacket;
 use super::protocol::NtpPacketHeader;
 use super::protocol::NtsPacket;
-use super::protocol::PacketMode::Client;
+use super::protocol::PacketMode::{self, Client};
 use super::protocol::TWO_POW_32;
 use super::protocol::UNIX_OFFSET;
 
@@ -26,16 +29,37 @@ use self::NtpClientError::*;
 const BUFF_SIZE: usize = 2048;
 const TIMEOUT: Duration = Duration::from_secs(10);
 
+/// The kiss code of an NTS NAK.
+const KOD_NTSN: u32 = 0x4e54_534e;
+
 pub struct NtpResult {
     pub stratum: u8,
     pub time_diff: f64,
+    /// The round-trip delay in seconds, without the time that the server held the request.
+    pub delay: f64,
+    pub leap: LeapState,
+    /// The root delay and dispersion of the server in seconds.
+    pub root_delay: f64,
+    pub root_dispersion: f64,
+    pub server: SocketAddr,
+    /// The new cookies that the server sent with an NTS response.
+    pub cookies: Vec<Vec<u8>>,
+    /// The origin, receive, transmit and destination timestamps of the exchange, in seconds
+    /// since the NTP epoch.
+    pub timestamps: [f64; 4],
 }
 
 #[derive(Debug, Clone)]
 pub enum NtpClientError {
     NoIpv4AddrFound,
     NoIpv6AddrFound,
-    InvalidUid
+    InvalidUid,
+    /// The response doesn't answer the request.
+    InvalidResponse,
+    /// The server sent an NTS NAK, since it cannot use the cookie.
+    NtsNak,
+    /// All the cookies are used up, so the key exchange must be done again.
+    OutOfCookies,
 }
 
 impl std::error::Error for NtpClientError {
@@ -51,7 +75,11 @@ impl std::error::Error for NtpClientError {
 
 impl std::fmt::Display for NtpClientError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Ntp Client Error ")
+        match self {
+            NtsNak => write!(f, "the server sent an NTS NAK"),
+            OutOfCookies => write!(f, "out of NTS cookies"),
+            _ => write!(f, "Ntp Client Error "),
+        }
     }
 }
 
@@ -70,43 +98,151 @@ fn timestamp_to_float(time: u64) -> f64 {
     (ts_secs as f64) + (ts_frac as f64) / TWO_POW_32
 }
 
-/// Run the NTS client with the given data from key exchange
+/// Returns a float representing the NTP short format (16.16) in seconds
+fn short_to_float(value: u32) -> f64 {
+    f64::from(value) / 65536.0
+}
+
+/// Returns the result of an exchange where the request was sent at `t1` and the response with
+/// `header` received at `t4`
+fn exchange_result(
+    header: &NtpPacketHeader,
+    t1: f64,
+    t4: f64,
+    server: SocketAddr,
+    cookies: Vec<Vec<u8>>,
+) -> NtpResult {
+    let t2 = timestamp_to_float(header.receive_timestamp);
+    let t3 = timestamp_to_float(header.transmit_timestamp);
+    NtpResult {
+        stratum: header.stratum,
+        time_diff: ((t2 - t1) + (t3 - t4)) / 2.0,
+        delay: (t4 - t1) - (t3 - t2),
+        leap: header.leap_indicator,
+        root_delay: short_to_float(header.root_delay),
+        root_dispersion: short_to_float(header.root_dispersion),
+        server,
+        cookies,
+        timestamps: [t1, t2, t3, t4],
+    }
+}
+
+/// Run an unauthenticated NTP exchange with the server at `addr`
+pub fn run_ntp_client(addr: SocketAddr) -> Result<NtpResult, Box<dyn Error>> {
+    let socket = if addr.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0")?
+    } else {
+        UdpSocket::bind("[::]:0")?
+    };
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.set_write_timeout(Some(TIMEOUT))?;
+    socket.connect(addr)?;
+
+    // The transmit timestamp is random, so that the response can be matched to the request
+    // without revealing the time of the client.
+    let transmit_timestamp = rand::thread_rng().gen::<u64>();
+    let packet = NtpPacket {
+        header: NtpPacketHeader {
+            leap_indicator: LeapState::NoLeap,
+            version: 4,
+            mode: Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0x20,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: 0,
+            reference_timestamp: 0,
+            origin_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp,
+        },
+        exts: vec![],
+    };
+    let t1 = system_to_ntpfloat(SystemTime::now());
+    socket.send(&serialize_ntp_packet(packet))?;
+    let mut buff = [0; BUFF_SIZE];
+    let size = socket.recv(&mut buff)?;
+    let t4 = system_to_ntpfloat(SystemTime::now());
+
+    let header = parse_ntp_packet(&buff[0..size])?.header;
+    if header.mode != PacketMode::Server || header.origin_timestamp != transmit_timestamp {
+        return Err(Box::new(InvalidResponse));
+    }
+    Ok(exchange_result(&header, t1, t4, addr, vec![]))
+}
+
+/// Return the addresses of the NTP servers that the key exchange named, in the order that they
+/// are tried, and only the ones of the address family that the client is restricted to.
+fn ntp_addrs(
+    logger: &slog::Logger,
+    state: &NtsKeResult,
+) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+    let mut addrs = Vec::new();
+    for server in &state.next_servers {
+        match (server.as_str(), state.next_port).to_socket_addrs() {
+            Ok(resolved) => addrs.extend(resolved.filter(|addr| match state.use_ipv4 {
+                Some(use_ipv4) => addr.is_ipv4() == use_ipv4,
+                None => true,
+            })),
+            Err(err) => debug!(logger, "cannot resolve the NTP server {}: {}", server, err),
+        }
+    }
+    match (addrs.is_empty(), state.use_ipv4) {
+        (true, Some(true)) => Err(Box::new(NoIpv4AddrFound)),
+        (true, Some(false)) => Err(Box::new(NoIpv6AddrFound)),
+        (true, None) => Err(Box::new(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "cannot resolve the NTP servers",
+        ))),
+        (false, _) => Ok(addrs),
+    }
+}
+
+/// Run the NTS client with the given data from key exchange. The NTP servers that it named are
+/// tried in order until one of them answers. Each attempt uses up one of the cookies, so that the
+/// servers cannot link the attempts, and the new cookies of the response are added to `state`.
+/// The fallback stops when the cookies run out, and the key exchange must be done again.
 pub fn run_nts_ntp_client(
     logger: &slog::Logger,
-    state: NtsKeResult,
+    state: &mut NtsKeResult,
 ) -> Result<NtpResult, Box<dyn Error>> {
-
-    let mut ip_addrs = (state.next_server.as_str(), state.next_port).to_socket_addrs()?;
-    let addr;
-    let socket;
-    if let Some(use_ipv4) = state.use_ipv4 {
-        if use_ipv4 {
-            // mandated to use ipv4
-            addr = ip_addrs.find(|&x| x.is_ipv4());
-            if addr == None {
-                return Err(Box::new(NoIpv4AddrFound));
-            }
-            socket = UdpSocket::bind("0.0.0.0:0");
-        } else {
-            // mandated to use ipv6
-            addr = ip_addrs.find(|&x| x.is_ipv6());
-            if addr == None {
-                return Err(Box::new(NoIpv6AddrFound));
-            }
-            socket = UdpSocket::bind("[::]:0");
+    let mut last_error: Option<Box<dyn Error>> = None;
+    for addr in ntp_addrs(logger, state)? {
+        if state.cookies.is_empty() {
+            debug!(logger, "out of cookies before trying the NTP server {}", addr);
+            break;
         }
-    } else {
-        // sniff whichever one is supported
-        addr = ip_addrs.next();
-        // check if this address is ipv4 or ipv6
-        if addr.unwrap().is_ipv6() {
-            socket = UdpSocket::bind("[::]:0");
-        } else {
-            socket = UdpSocket::bind("0.0.0.0:0");
+        let cookie = state.cookies.remove(0);
+        if last_error.is_some() {
+            info!(logger, "falling back to the NTP server {}", addr);
+        }
+        match nts_exchange(logger, state, cookie, addr) {
+            Ok(result) => {
+                state.cookies.extend(result.cookies.iter().cloned());
+                return Ok(result);
+            },
+            Err(err) => {
+                debug!(logger, "the exchange with {} failed: {}", addr, err);
+                last_error = Some(err);
+            },
         }
     }
+    Err(last_error.unwrap_or_else(|| Box::new(OutOfCookies)))
+}
 
-    let socket = socket.unwrap();
+/// Run an NTS exchange with the NTP server at `addr`, which uses `cookie`.
+fn nts_exchange(
+    logger: &slog::Logger,
+    state: &NtsKeResult,
+    cookie: Vec<u8>,
+    addr: SocketAddr,
+) -> Result<NtpResult, Box<dyn Error>> {
+    let socket = if addr.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0")?
+    } else {
+        UdpSocket::bind("[::]:0")?
+    };
     socket.set_read_timeout(Some(TIMEOUT))?;
     socket.set_write_timeout(Some(TIMEOUT))?;
     let mut send_aead = Aes128SivAead::new(&state.keys.c2s);
@@ -135,7 +271,7 @@ pub fn run_nts_ntp_client(
         },
         NtpExtension {
             ext_type: NTSCookie,
-            contents: state.cookies[0].clone(),
+            contents: cookie,
         },
     ];
     let packet = NtsPacket {
@@ -143,7 +279,7 @@ pub fn run_nts_ntp_client(
         auth_exts: exts,
         auth_enc_exts: vec![],
     };
-    socket.connect(addr.unwrap())?;
+    socket.connect(addr)?;
     let wire_packet = &serialize_nts_packet::<Aes128SivAead>(packet, &mut send_aead);
     let t1 = system_to_ntpfloat(SystemTime::now());
     socket.send(wire_packet)?;
@@ -152,6 +288,9 @@ pub fn run_nts_ntp_client(
     let (size, _origin) = socket.recv_from(&mut buff)?;
     let t4 = system_to_ntpfloat(SystemTime::now());
     debug!(logger, "received packet");
+    if is_nts_nak(&buff[0..size], &unique_id) {
+        return Err(Box::new(NtsNak));
+    }
     let received = parse_nts_packet::<Aes128SivAead>(&buff[0..size], &mut recv_aead);
     match received {
         Err(x) => Err(Box::new(x)),
@@ -163,12 +302,123 @@ pub fn run_nts_ntp_client(
                 return Err(Box::new(InvalidUid));
             }
 
-            Ok(NtpResult {
-                stratum: packet.header.stratum,
-                time_diff: ((timestamp_to_float(packet.header.receive_timestamp) - t1)
-                    + (timestamp_to_float(packet.header.transmit_timestamp) - t4))
-                    / 2.0,
-            })
+            let cookies = packet.auth_enc_exts.into_iter()
+                .filter(|ext| ext.ext_type == NTSCookie)
+                .map(|ext| ext.contents)
+                .collect();
+            Ok(exchange_result(&packet.header, t1, t4, addr, cookies))
         },
     }
 }
+
+/// Return whether `buff` is an NTS NAK of the request with the unique identifier `unique_id`.
+/// It's not authenticated, so it cannot be told apart from a forged one. It only means that the
+/// cookies must be replaced, which a forged response could also cause by being dropped.
+fn is_nts_nak(buff: &[u8], unique_id: &[u8]) -> bool {
+    let packet = match parse_ntp_packet(buff) {
+        Ok(packet) => packet,
+        Err(_) => return false,
+    };
+    packet.header.stratum == 0 && packet.header.reference_id == KOD_NTSN
+        && packet.exts.iter()
+            .any(|ext| ext.ext_type == UniqueIdentifier && ext.contents == unique_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cookie::NTSKeys;
+    use crate::nts_ke::records::KnownAeadAlgorithm;
+
+    fn state(next_servers: &[&str], use_ipv4: Option<bool>) -> NtsKeResult {
+        NtsKeResult {
+            cookies: vec![vec![0; 16]],
+            next_protocols: vec![0],
+            aead_scheme: 15,
+            next_servers: next_servers.iter().map(|server| String::from(*server)).collect(),
+            next_port: 123,
+            redirected: true,
+            keys: NTSKeys {
+                aead: KnownAeadAlgorithm::AeadAesSivCmac256,
+                c2s: [0; 32],
+                s2c: [0; 32],
+            },
+            use_ipv4,
+            certificates: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_ntp_addrs() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let servers = ["192.0.2.1", "2001:db8::1", "192.0.2.2"];
+
+        // The servers are tried in the order of the records.
+        let addrs = ntp_addrs(&logger, &state(&servers, None)).unwrap();
+        let expected: Vec<SocketAddr> = ["192.0.2.1:123", "[2001:db8::1]:123", "192.0.2.2:123"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(addrs, expected);
+
+        let addrs = ntp_addrs(&logger, &state(&servers, Some(true))).unwrap();
+        assert_eq!(addrs, vec![expected[0], expected[2]]);
+        let addrs = ntp_addrs(&logger, &state(&servers, Some(false))).unwrap();
+        assert_eq!(addrs, vec![expected[1]]);
+        assert!(ntp_addrs(&logger, &state(&servers[..1], Some(false))).is_err());
+    }
+
+    #[test]
+    fn test_cookie_per_attempt() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        // Nothing listens on the port, so each exchange fails right away.
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut state = state(&["127.0.0.1", "127.0.0.2"], None);
+        state.next_port = port;
+
+        // Each server that is tried uses up its own cookie.
+        state.cookies = vec![vec![1; 16], vec![2; 16], vec![3; 16]];
+        assert!(run_nts_ntp_client(&logger, &mut state).is_err());
+        assert_eq!(state.cookies, vec![vec![3; 16]]);
+
+        // The fallback stops when the cookies run out.
+        assert!(run_nts_ntp_client(&logger, &mut state).is_err());
+        assert!(state.cookies.is_empty());
+        match run_nts_ntp_client(&logger, &mut state) {
+            Err(err) => match err.downcast_ref() {
+                Some(OutOfCookies) => (),
+                _ => panic!("the client is not out of cookies"),
+            },
+            Ok(_) => panic!("the exchange cannot succeed"),
+        }
+    }
+
+    #[test]
+    fn test_is_nts_nak() {
+        let unique_id = vec![7; 32];
+        let nak = |stratum, reference_id| serialize_ntp_packet(NtpPacket {
+            header: NtpPacketHeader {
+                leap_indicator: LeapState::Unknown,
+                version: 4,
+                mode: PacketMode::Server,
+                stratum,
+                poll: 0,
+                precision: 0,
+                root_delay: 0,
+                root_dispersion: 0,
+                reference_id,
+                reference_timestamp: 0,
+                origin_timestamp: 0,
+                receive_timestamp: 0,
+                transmit_timestamp: 0,
+            },
+            exts: vec![NtpExtension { ext_type: UniqueIdentifier, contents: unique_id.clone() }],
+        });
+        assert!(is_nts_nak(&nak(0, KOD_NTSN), &unique_id));
+        assert!(!is_nts_nak(&nak(0, KOD_NTSN), &[8; 32]));
+        assert!(!is_nts_nak(&nak(0, 0x5241_5445), &unique_id));
+        assert!(!is_nts_nak(&nak(2, KOD_NTSN), &unique_id));
+        assert!(!is_nts_nak(&[0; 12], &unique_id));
+    }
+}
@@ -0,0 +1,427 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Discipline of the system clock by the client daemon. The daemon polls the server on an
+//! interval that grows while the clock is stable and shrinks when it's not, and hands the
+//! filtered offsets to the kernel, whose phase-locked loop steers the clock gradually. The
+//! frequency error that the loop learns is kept in a drift file, so that it doesn't have to be
+//! learned again after a restart.
+
+use slog::info;
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// The shortest poll interval in log2 seconds, which is 64 seconds.
+pub const MIN_POLL: i8 = 6;
+
+/// The longest poll interval in log2 seconds, which is 1024 seconds.
+pub const MAX_POLL: i8 = 10;
+
+/// How far the poll counter goes before the interval changes.
+const POLL_LIMIT: i32 = 30;
+
+/// The offsets under this many times the jitter mean that the clock is stable.
+const POLL_ADJUST: f64 = 4.0;
+
+/// The poll interval, which adapts to how well the clock follows the server.
+#[derive(Debug)]
+pub struct PollInterval {
+    /// The interval in log2 seconds.
+    poll: i8,
+
+    /// Goes up with each stable offset and down with each other one. The interval changes when
+    /// it reaches the limit.
+    counter: i32,
+}
+
+impl Default for PollInterval {
+    fn default() -> PollInterval {
+        PollInterval { poll: MIN_POLL, counter: 0 }
+    }
+}
+
+impl PollInterval {
+    /// Return the interval in log2 seconds.
+    pub fn exponent(&self) -> i8 {
+        self.poll
+    }
+
+    /// Return the time until the next poll.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1 << self.poll)
+    }
+
+    /// Update the interval with the `offset` of a new sample and the `jitter` of the recent
+    /// ones, both in seconds.
+    pub fn update(&mut self, offset: f64, jitter: f64) {
+        if offset.abs() < POLL_ADJUST * jitter {
+            self.counter += i32::from(self.poll);
+            if self.counter > POLL_LIMIT {
+                self.counter = POLL_LIMIT;
+                if self.poll < MAX_POLL {
+                    self.counter = 0;
+                    self.poll += 1;
+                }
+            }
+        } else {
+            self.counter -= 2 * i32::from(self.poll);
+            if self.counter < -POLL_LIMIT {
+                self.counter = -POLL_LIMIT;
+                if self.poll > MIN_POLL {
+                    self.counter = 0;
+                    self.poll -= 1;
+                }
+            }
+        }
+    }
+}
+
+/// The largest frequency error that the kernel corrects, in parts per million.
+const MAX_FREQUENCY: f64 = 500.0;
+
+/// Read the frequency error in parts per million from the drift file at `path`, or return
+/// `None` if there is none yet.
+pub fn read_drift(path: &str) -> Result<Option<f64>, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    match text.trim().parse::<f64>() {
+        Ok(ppm) if ppm.abs() <= MAX_FREQUENCY => Ok(Some(ppm)),
+        _ => Err(Error::new(ErrorKind::InvalidData,
+                            format!("the drift file {} is not a frequency in ppm", path))),
+    }
+}
+
+/// Write the frequency error `ppm` to the drift file at `path`. It's written to a temporary file
+/// first and then renamed, so that the file is never left half written.
+pub fn write_drift(path: &str, ppm: f64) -> Result<(), Error> {
+    let temporary = format!("{}.tmp", path);
+    fs::write(&temporary, format!("{:.3}\n", ppm))?;
+    fs::rename(&temporary, path)
+}
+
+/// How an offset is removed from the clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Correction {
+    /// The clock is set to the right time at once.
+    Step,
+
+    /// The clock is sped up or slowed down until it has the right time.
+    Slew,
+}
+
+/// When the clock is stepped instead of slewed, like the makestep of chrony. The offsets over
+/// the threshold are stepped, in the first clock updates only if there is a limit.
+#[derive(Debug)]
+pub struct StepPolicy {
+    /// The smallest offset that is stepped, in seconds.
+    threshold: f64,
+
+    /// How many clock updates can step the clock, if they are limited.
+    limit: Option<u32>,
+
+    /// How many clock updates there have been.
+    updates: u32,
+}
+
+impl StepPolicy {
+    pub fn new(threshold: f64, limit: Option<u32>) -> StepPolicy {
+        StepPolicy { threshold, limit, updates: 0 }
+    }
+
+    /// Return how the clock is corrected for `offset` seconds in the next clock update.
+    pub fn correction(&mut self, offset: f64) -> Correction {
+        let allowed = self.limit.map_or(true, |limit| self.updates < limit);
+        self.updates = self.updates.saturating_add(1);
+        if allowed && offset.abs() >= self.threshold {
+            Correction::Step
+        } else {
+            Correction::Slew
+        }
+    }
+}
+
+/// Something that steers the clock.
+pub trait ClockControl {
+    /// Move the clock forward by `offset` seconds at once, or back if it's negative.
+    fn step(&mut self, offset: f64) -> Result<(), Error>;
+
+    /// Return the frequency error of the clock that is corrected, in parts per million.
+    fn frequency(&mut self) -> Result<f64, Error>;
+
+    /// Correct the frequency error of the clock by `ppm` parts per million from now on.
+    fn set_frequency(&mut self, ppm: f64) -> Result<(), Error>;
+
+    /// Remove `offset` seconds from the clock gradually, with a loop time constant that suits
+    /// the poll interval of `poll` log2 seconds. The estimated and the maximum error of the clock
+    /// are in seconds.
+    fn slew(&mut self, offset: f64, poll: i8, esterror: f64, maxerror: f64)
+        -> Result<(), Error>;
+}
+
+/// Steers the system clock with the phase-locked loop of the kernel.
+#[derive(Debug, Default)]
+pub struct KernelClock;
+
+/// Only logs what would be done to the clock.
+pub struct DryRun {
+    logger: slog::Logger,
+
+    /// The frequency error that would be corrected.
+    frequency: f64,
+}
+
+impl DryRun {
+    pub fn new(logger: slog::Logger) -> DryRun {
+        DryRun { logger, frequency: 0.0 }
+    }
+}
+
+impl ClockControl for DryRun {
+    fn step(&mut self, offset: f64) -> Result<(), Error> {
+        info!(self.logger, "not stepping the clock by {:.6} s", offset);
+        Ok(())
+    }
+
+    fn frequency(&mut self) -> Result<f64, Error> {
+        Ok(self.frequency)
+    }
+
+    fn set_frequency(&mut self, ppm: f64) -> Result<(), Error> {
+        info!(self.logger, "not setting the frequency error of the clock to {:.3} ppm", ppm);
+        self.frequency = ppm;
+        Ok(())
+    }
+
+    fn slew(&mut self, offset: f64, poll: i8, _esterror: f64, _maxerror: f64)
+        -> Result<(), Error>
+    {
+        info!(self.logger, "not slewing the clock by {:.6} s", offset; "poll" => poll);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod kernel {
+    use libc::{c_int, c_long, c_uint};
+
+    pub const ADJ_OFFSET: c_uint = 0x0001;
+    pub const ADJ_FREQUENCY: c_uint = 0x0002;
+    pub const ADJ_MAXERROR: c_uint = 0x0004;
+    pub const ADJ_ESTERROR: c_uint = 0x0008;
+    pub const ADJ_STATUS: c_uint = 0x0010;
+    pub const ADJ_TIMECONST: c_uint = 0x0020;
+    pub const ADJ_NANO: c_uint = 0x2000;
+
+    pub const STA_PLL: c_int = 0x0001;
+    pub const STA_NANO: c_int = 0x2000;
+
+    /// The largest offset that the loop of the kernel takes, in seconds.
+    pub const MAX_PHASE: f64 = 0.5;
+
+    /// The largest error that the kernel takes, in seconds.
+    pub const MAX_ERROR: f64 = 16.0;
+
+    /// The frequency is in parts per million with 16 fractional bits.
+    pub const FREQUENCY_SCALE: f64 = 65536.0;
+
+    /// The `struct timex` of glibc, which libc doesn't have yet.
+    #[repr(C)]
+    pub struct Timex {
+        pub modes: c_uint,
+        pub offset: c_long,
+        pub freq: c_long,
+        pub maxerror: c_long,
+        pub esterror: c_long,
+        pub status: c_int,
+        pub constant: c_long,
+        pub precision: c_long,
+        pub tolerance: c_long,
+        pub time: libc::timeval,
+        pub tick: c_long,
+        pub ppsfreq: c_long,
+        pub jitter: c_long,
+        pub shift: c_int,
+        pub stabil: c_long,
+        pub jitcnt: c_long,
+        pub calcnt: c_long,
+        pub errcnt: c_long,
+        pub stbcnt: c_long,
+        pub tai: c_int,
+        pub padding: [c_int; 11],
+    }
+
+    extern "C" {
+        pub fn adjtimex(buf: *mut Timex) -> c_int;
+    }
+
+    /// Return the `seconds` in microseconds, as the kernel takes the errors.
+    pub fn micros(seconds: f64) -> c_long {
+        (seconds.max(0.0).min(MAX_ERROR) * 1e6) as c_long
+    }
+
+    /// Call adjtimex with the `modes` and the fields that `set` fills in, and return the struct
+    /// that the kernel wrote back.
+    pub fn call(modes: c_uint, set: impl FnOnce(&mut Timex)) -> Result<Timex, std::io::Error> {
+        // This is safe because the struct is plain integers, and the kernel only reads the
+        // fields of the modes and writes the struct back.
+        unsafe {
+            let mut timex: Timex = std::mem::zeroed();
+            timex.modes = modes;
+            set(&mut timex);
+            if adjtimex(&mut timex) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(timex)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ClockControl for KernelClock {
+    fn step(&mut self, offset: f64) -> Result<(), Error> {
+        use self::kernel::*;
+
+        // This is safe because the structs are plain integers that live on the stack.
+        unsafe {
+            let mut now: libc::timespec = std::mem::zeroed();
+            if libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) == -1 {
+                return Err(Error::last_os_error());
+            }
+            let nanos = now.tv_nsec as i64 + (offset.fract() * 1e9) as i64;
+            let time = libc::timespec {
+                tv_sec: now.tv_sec + offset.trunc() as libc::time_t
+                    + nanos.div_euclid(1_000_000_000) as libc::time_t,
+                tv_nsec: nanos.rem_euclid(1_000_000_000) as libc::c_long,
+            };
+            if libc::clock_settime(libc::CLOCK_REALTIME, &time) == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        // The offset that the kernel was still slewing away is gone with the step.
+        call(ADJ_OFFSET | ADJ_STATUS | ADJ_NANO, |timex| timex.status = STA_PLL | STA_NANO)?;
+        Ok(())
+    }
+
+    fn frequency(&mut self) -> Result<f64, Error> {
+        Ok(self::kernel::call(0, |_| ())?.freq as f64 / self::kernel::FREQUENCY_SCALE)
+    }
+
+    fn set_frequency(&mut self, ppm: f64) -> Result<(), Error> {
+        use self::kernel::*;
+
+        let freq = (ppm.max(-MAX_FREQUENCY).min(MAX_FREQUENCY) * FREQUENCY_SCALE) as libc::c_long;
+        call(ADJ_FREQUENCY, |timex| timex.freq = freq)?;
+        Ok(())
+    }
+
+    fn slew(&mut self, offset: f64, poll: i8, esterror: f64, maxerror: f64)
+        -> Result<(), Error>
+    {
+        use self::kernel::*;
+
+        let modes = ADJ_OFFSET | ADJ_STATUS | ADJ_TIMECONST | ADJ_MAXERROR | ADJ_ESTERROR
+            | ADJ_NANO;
+        call(modes, |timex| {
+            // The clock is behind the server by the offset, so the kernel has to move it forward.
+            timex.offset = (offset.max(-MAX_PHASE).min(MAX_PHASE) * 1e9) as libc::c_long;
+            // Setting the status clears STA_UNSYNC, which tells the kernel that the clock is
+            // synchronized.
+            timex.status = STA_PLL | STA_NANO;
+            timex.constant = libc::c_long::from((poll - 4).max(0));
+            timex.esterror = micros(esterror);
+            timex.maxerror = micros(maxerror);
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ClockControl for KernelClock {
+    fn step(&mut self, _offset: f64) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Other, "the clock can only be stepped on Linux"))
+    }
+
+    fn frequency(&mut self) -> Result<f64, Error> {
+        Err(Error::new(ErrorKind::Other, "the clock can only be steered on Linux"))
+    }
+
+    fn set_frequency(&mut self, _ppm: f64) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Other, "the clock can only be steered on Linux"))
+    }
+
+    fn slew(&mut self, _offset: f64, _poll: i8, _esterror: f64, _maxerror: f64)
+        -> Result<(), Error>
+    {
+        Err(Error::new(ErrorKind::Other, "the clock can only be steered on Linux"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_interval() {
+        let mut poll = PollInterval::default();
+        assert_eq!(poll.interval(), Duration::from_secs(64));
+
+        // The interval grows while the offsets are within the jitter.
+        for _ in 0..6 {
+            poll.update(0.001, 0.002);
+        }
+        assert_eq!(poll.exponent(), MIN_POLL + 1);
+        for _ in 0..100 {
+            poll.update(0.001, 0.002);
+        }
+        assert_eq!(poll.exponent(), MAX_POLL);
+
+        // And shrinks when they are not.
+        for _ in 0..4 {
+            poll.update(0.1, 0.002);
+        }
+        assert_eq!(poll.exponent(), MAX_POLL - 1);
+        for _ in 0..100 {
+            poll.update(0.1, 0.002);
+        }
+        assert_eq!(poll.exponent(), MIN_POLL);
+    }
+
+    #[test]
+    fn test_step_policy() {
+        let mut policy = StepPolicy::new(0.1, None);
+        assert_eq!(policy.correction(0.05), Correction::Slew);
+        assert_eq!(policy.correction(-0.2), Correction::Step);
+        for _ in 0..100 {
+            policy.correction(0.0);
+        }
+        assert_eq!(policy.correction(1.0), Correction::Step);
+
+        // The large offsets are slewed after the first updates.
+        let mut policy = StepPolicy::new(0.1, Some(2));
+        assert_eq!(policy.correction(0.05), Correction::Slew);
+        assert_eq!(policy.correction(1.0), Correction::Step);
+        assert_eq!(policy.correction(1.0), Correction::Slew);
+    }
+
+    #[test]
+    fn test_drift() {
+        let path = std::env::temp_dir().join(format!("cfnts-drift-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(read_drift(path).unwrap(), None);
+
+        write_drift(path, -12.345).unwrap();
+        assert_eq!(read_drift(path).unwrap(), Some(-12.345));
+
+        fs::write(path, "600\n").unwrap();
+        assert!(read_drift(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The registry of the extension fields that the parser doesn't know itself, such as the
+//! experimental ones of the drafts. A handler is registered for the type of the field, and then
+//! the fields of that type are checked when the packets are parsed, and can be decoded into a
+//! value and encoded from one, without changing the parser.
+
+// The server itself registers nothing, this is for the code that builds on it.
+#![allow(dead_code)]
+
+use lazy_static::lazy_static;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+
+use super::protocol::{self, NtpExtension, NtpExtensionType};
+
+/// The smallest contents of an extension field, which is 16 bytes long with its header.
+const MIN_CONTENTS_LEN: usize = 12;
+
+/// Parses and serializes the contents of an extension field of one type.
+pub trait ExtensionHandler: Send + Sync {
+    /// Return the name of the field, for the errors.
+    fn name(&self) -> &str;
+
+    /// Return the value in `contents`, or an error if they are malformed. The contents are
+    /// padded with zeros to a multiple of 4 bytes, and to at least 12.
+    fn parse(&self, contents: &[u8]) -> Result<Box<dyn Any + Send>, Error>;
+
+    /// Return the contents of `value`, or an error if it's not a value of this field.
+    fn serialize(&self, value: &dyn Any) -> Result<Vec<u8>, Error>;
+}
+
+lazy_static! {
+    static ref HANDLERS: RwLock<HashMap<u16, Arc<dyn ExtensionHandler>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Register `handler` for the fields of `ext_type`. The types that the parser knows itself, and
+/// the ones that already have a handler, cannot be registered.
+pub fn register(ext_type: u16, handler: Arc<dyn ExtensionHandler>) -> Result<(), Error> {
+    if protocol::is_known_extension(ext_type) {
+        return Err(Error::new(ErrorKind::AlreadyExists,
+                              format!("extension type {:#06x} is built in", ext_type)));
+    }
+    let mut handlers = HANDLERS.write().unwrap();
+    if handlers.contains_key(&ext_type) {
+        return Err(Error::new(ErrorKind::AlreadyExists,
+                              format!("extension type {:#06x} is already registered", ext_type)));
+    }
+    handlers.insert(ext_type, handler);
+    Ok(())
+}
+
+fn handler(ext_type: u16) -> Option<Arc<dyn ExtensionHandler>> {
+    HANDLERS.read().unwrap().get(&ext_type).cloned()
+}
+
+/// Check the contents of a received field of `ext_type`, if it has a handler. The fields without
+/// one are left alone.
+pub fn check(ext_type: u16, contents: &[u8]) -> Result<(), Error> {
+    match handler(ext_type) {
+        Some(handler) => handler.parse(contents).map(|_| ()).map_err(|error| {
+            Error::new(ErrorKind::InvalidInput,
+                       format!("malformed {} extension: {}", handler.name(), error))
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Return the value of `ext`, which has to be of a registered type whose values are `T`.
+pub fn decode<T: Any>(ext: &NtpExtension) -> Result<T, Error> {
+    let ext_type = match ext.ext_type {
+        NtpExtensionType::Unknown(ext_type) => ext_type,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "the extension type is built in")),
+    };
+    let handler = handler(ext_type)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "the extension type is not registered"))?;
+    let value = handler.parse(&ext.contents)?;
+    let value: Box<dyn Any> = value;
+    value.downcast::<T>().map(|value| *value).map_err(|_| {
+        Error::new(ErrorKind::InvalidInput,
+                   format!("the {} extension has another type of value", handler.name()))
+    })
+}
+
+/// Return the extension field of `ext_type` with `value`, which has to be registered. Its
+/// contents are padded with zeros as needed.
+pub fn encode(ext_type: u16, value: &dyn Any) -> Result<NtpExtension, Error> {
+    let handler = handler(ext_type)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "the extension type is not registered"))?;
+    let mut contents = handler.serialize(value)?;
+    let padded = (contents.len().max(MIN_CONTENTS_LEN) + 3) / 4 * 4;
+    if padded + 4 > usize::from(std::u16::MAX) {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("the {} extension is too long", handler.name())));
+    }
+    contents.resize(padded, 0);
+    Ok(NtpExtension { ext_type: NtpExtensionType::Unknown(ext_type), contents })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ntp::protocol::{parse_ntp_packet, serialize_ntp_packet, NtpPacket};
+
+    /// A field with a big-endian u32 in its first 4 bytes, whose other bytes are zeros.
+    struct Counter;
+
+    impl ExtensionHandler for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn parse(&self, contents: &[u8]) -> Result<Box<dyn Any + Send>, Error> {
+            if contents.len() < 4 || contents[4..].iter().any(|&byte| byte != 0) {
+                return Err(Error::new(ErrorKind::InvalidData, "not a counter"));
+            }
+            let mut value = [0; 4];
+            value.copy_from_slice(&contents[..4]);
+            Ok(Box::new(u32::from_be_bytes(value)))
+        }
+
+        fn serialize(&self, value: &dyn Any) -> Result<Vec<u8>, Error> {
+            let value = value.downcast_ref::<u32>()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a u32"))?;
+            Ok(value.to_be_bytes().to_vec())
+        }
+    }
+
+    fn packet(exts: Vec<NtpExtension>) -> Vec<u8> {
+        let mut query = vec![0x23];
+        query.resize(48, 0);
+        let mut packet = parse_ntp_packet(&query).unwrap();
+        packet.exts = exts;
+        serialize_ntp_packet(NtpPacket { ..packet })
+    }
+
+    #[test]
+    fn test_registry() {
+        register(0xf001, Arc::new(Counter)).unwrap();
+        assert!(register(0xf001, Arc::new(Counter)).is_err());
+        assert!(register(0x0204, Arc::new(Counter)).is_err());
+
+        let ext = encode(0xf001, &7u32).unwrap();
+        assert_eq!(ext.contents.len(), 12);
+        assert!(encode(0xf001, &7u64).is_err());
+        assert!(encode(0xf002, &7u32).is_err());
+
+        let parsed = parse_ntp_packet(&packet(vec![ext])).unwrap();
+        assert_eq!(decode::<u32>(&parsed.exts[0]).unwrap(), 7);
+        assert!(decode::<u64>(&parsed.exts[0]).is_err());
+
+        // The malformed fields of the registered types make the packet malformed, and the others
+        // are left alone.
+        let malformed = NtpExtension {
+            ext_type: NtpExtensionType::Unknown(0xf001),
+            contents: vec![1; 12],
+        };
+        assert!(parse_ntp_packet(&packet(vec![malformed])).is_err());
+        let unknown = NtpExtension {
+            ext_type: NtpExtensionType::Unknown(0xf003),
+            contents: vec![1; 12],
+        };
+        assert!(parse_ntp_packet(&packet(vec![unknown])).is_ok());
+    }
+}
@@ -1,3 +1,5 @@
 pub mod client;
+pub mod discipline;
+pub mod extensions;
 pub mod protocol;
 pub mod server;
@@ -1,5 +1,5 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use miscreant::aead::Aead;
+use miscreant::aead::{Aead, Aes128SivAead};
 use rand::Rng;
 
 use std::io::{Cursor, Error, ErrorKind, Read, Write};
@@ -9,6 +9,9 @@ use self::LeapState::*;
 use self::NtpExtensionType::*;
 use self::PacketMode::*;
 
+use super::extensions;
+use crate::aes_gcm_siv::{self, Aes128GcmSivAead};
+
 /// These numbers are from RFC 5905
 pub const VERSION: u8 = 4;
 pub const UNIX_OFFSET: u64 = 2_208_988_800;
@@ -16,8 +19,25 @@ pub const PHI: f64 = 15e-6;
 /// TWO_POW_32 is a floating point power of two (2**32)
 pub const TWO_POW_32: f64 = 4294967296.0;
 
+/// The version of draft-ietf-ntp-ntpv5-02.
+pub const VERSION_5: u8 = 5;
+
+/// A client that wants to upgrade to NTPv5 sends this in the reference timestamp of an NTPv4
+/// request, and the servers that support NTPv5 send it back ("NTP5DRFT").
+pub const NTPV5_NEGOTIATION_MAGIC: u64 = 0x4e54_5035_4452_4654;
+
+/// The NTPv5 flag which says that the server is not synchronized or its leap status is unknown.
+pub const V5_FLAG_UNKNOWN_LEAP: u16 = 0x0001;
+
+/// The NTPv5 timescale of UTC, which is the only one that we serve.
+pub const V5_TIMESCALE_UTC: u8 = 0;
+
+/// The modes of the control messages of ntpq and of the private messages of ntpdc, like the
+/// monlist requests that were used for amplification. They are never answered.
+pub const MODE_CONTROL: u8 = 6;
+pub const MODE_PRIVATE: u8 = 7;
+
 const HEADER_SIZE: u64 = 48;
-const NONCE_LEN: usize = 16;
 const EXT_TYPE_UNIQUE_IDENTIFIER: u16 = 0x0104;
 const EXT_TYPE_NTS_COOKIE: u16 = 0x0204;
 const EXT_TYPE_NTS_COOKIE_PLACEHOLDER: u16 = 0x0304;
@@ -70,6 +90,15 @@ fn type_from_wire(ext: u16) -> NtpExtensionType {
     }
 }
 
+/// Return whether the fields of `ext_type` are parsed by this module itself.
+#[allow(dead_code)]
+pub fn is_known_extension(ext_type: u16) -> bool {
+    match type_from_wire(ext_type) {
+        NtpExtensionType::Unknown(_) => false,
+        _ => true,
+    }
+}
+
 /// Header of an NTP and NTS packet
 /// See RFC 5905 for meaning of these fields
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -89,6 +118,29 @@ pub struct NtpPacketHeader {
     pub transmit_timestamp: u64,
 }
 
+/// Header of an NTPv5 packet. See draft-ietf-ntp-ntpv5-02 for the meaning of these fields. It's
+/// as long as the NTPv4 header, but the reference id and timestamps are gone, and the origin
+/// timestamp is replaced with the cookies, which the server echoes back. The root delay and
+/// dispersion are in the 4.28 fixed-point format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtpV5PacketHeader {
+    pub leap_indicator: LeapState,
+    pub version: u8,
+    pub mode: PacketMode,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub timescale: u8,
+    pub era: u8,
+    pub flags: u16,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub server_cookie: u64,
+    pub client_cookie: u64,
+    pub receive_timestamp: u64,
+    pub transmit_timestamp: u64,
+}
+
 /// The authenticating extension needs to be treated
 /// differently from all other extensions. We can't write it out
 /// until we know the data it authenticates, so the nts parsing
@@ -188,6 +240,77 @@ pub fn parse_packet_header(packet: &[u8]) -> Result<NtpPacketHeader, std::io::Er
     }
 }
 
+/// Return the version of a packet, which is in the first byte of every version.
+pub fn packet_version(packet: &[u8]) -> Option<u8> {
+    packet.first().map(|first| parse_version(*first))
+}
+
+/// Return the number of the mode of a packet, which is in the first byte of every version.
+pub fn packet_mode_number(packet: &[u8]) -> Option<u8> {
+    packet.first().map(|first| first & 0x07)
+}
+
+/// Return why a packet cannot be answered as a request: it's `too_short` for a header, its mode
+/// is not the client one, its `extensions` are malformed, or it's `other`, such as an NTPv5
+/// packet with a bad header.
+pub fn malformed_reason(packet: &[u8]) -> &'static str {
+    if packet.len() < HEADER_SIZE as usize {
+        "too_short"
+    } else if parse_mode(packet[0]) != Client {
+        "mode"
+    } else if parse_extensions(&packet[HEADER_SIZE as usize..]).is_err() {
+        "extensions"
+    } else {
+        "other"
+    }
+}
+
+/// Extract an NTPv5 packet header from packet and return an error if it cannot be done. The
+/// extensions after the header are ignored.
+pub fn parse_v5_packet_header(packet: &[u8]) -> Result<NtpV5PacketHeader, std::io::Error> {
+    if packet.len() < HEADER_SIZE as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "Too short"));
+    }
+    let mut buff = Cursor::new(packet);
+    let first = buff.read_u8()?;
+    Ok(NtpV5PacketHeader {
+        leap_indicator: parse_leap_indicator(first),
+        version: parse_version(first),
+        mode: parse_mode(first),
+        stratum: buff.read_u8()?,
+        poll: buff.read_i8()?,
+        precision: buff.read_i8()?,
+        timescale: buff.read_u8()?,
+        era: buff.read_u8()?,
+        flags: buff.read_u16::<BigEndian>()?,
+        root_delay: buff.read_u32::<BigEndian>()?,
+        root_dispersion: buff.read_u32::<BigEndian>()?,
+        server_cookie: buff.read_u64::<BigEndian>()?,
+        client_cookie: buff.read_u64::<BigEndian>()?,
+        receive_timestamp: buff.read_u64::<BigEndian>()?,
+        transmit_timestamp: buff.read_u64::<BigEndian>()?,
+    })
+}
+
+/// Return the wire format of an NTPv5 header.
+pub fn serialize_v5_header(head: NtpV5PacketHeader) -> Vec<u8> {
+    let mut buff = Vec::with_capacity(HEADER_SIZE as usize);
+    buff.push(create_first(head.leap_indicator, head.version, head.mode));
+    buff.push(head.stratum);
+    buff.push(head.poll as u8);
+    buff.push(head.precision as u8);
+    buff.push(head.timescale);
+    buff.push(head.era);
+    buff.extend_from_slice(&head.flags.to_be_bytes());
+    buff.extend_from_slice(&head.root_delay.to_be_bytes());
+    buff.extend_from_slice(&head.root_dispersion.to_be_bytes());
+    buff.extend_from_slice(&head.server_cookie.to_be_bytes());
+    buff.extend_from_slice(&head.client_cookie.to_be_bytes());
+    buff.extend_from_slice(&head.receive_timestamp.to_be_bytes());
+    buff.extend_from_slice(&head.transmit_timestamp.to_be_bytes());
+    buff
+}
+
 /// serialize_header returns a Vec<u8> containing the wire
 /// format of the header.
 pub fn serialize_header(head: NtpPacketHeader) -> Vec<u8> {
@@ -247,6 +370,7 @@ fn parse_extensions(buff: &[u8]) -> Result<Vec<NtpExtension>, std::io::Error> {
         }
         let mut contents: Vec<u8> = vec![0; (ext_len - 4) as usize];
         reader.read(&mut contents)?;
+        extensions::check(ext_type, &contents)?;
         retval.push(NtpExtension {
             ext_type: type_from_wire(ext_type),
             contents: contents,
@@ -379,25 +503,39 @@ fn parse_decrypt_auth_ext<T: Aead>(
     Ok(res.unwrap())
 }
 
+/// AEAD algorithms that can protect NTS packets.
+pub trait NtsAead: Aead {
+    /// The length of the nonces that we generate.
+    const NONCE_LEN: usize;
+}
+
+impl NtsAead for Aes128SivAead {
+    const NONCE_LEN: usize = 16;
+}
+
+impl NtsAead for Aes128GcmSivAead {
+    const NONCE_LEN: usize = aes_gcm_siv::NONCE_LEN;
+}
+
 /// serialize_nts_packet serializes the packet and does all the encryption
-pub fn serialize_nts_packet<T: Aead>(packet: NtsPacket, encryptor: &mut T) -> Vec<u8> {
+pub fn serialize_nts_packet<T: NtsAead>(packet: NtsPacket, encryptor: &mut T) -> Vec<u8> {
     let mut buff = Cursor::new(Vec::new());
     buff.write_all(&serialize_header(packet.header))
         .expect("Nts header could not be written, failed to serialize NtsPacket");
     buff.write_all(&serialize_extensions(packet.auth_exts))
         .expect("Nts extensions could not be written, failed to serialize NtsPacket");
     let plaintext = serialize_extensions(packet.auth_enc_exts);
-    let mut nonce = [0; NONCE_LEN];
-    rand::thread_rng().fill(&mut nonce);
+    let mut nonce = vec![0; T::NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce[..]);
     let ciphertext = encryptor.seal(&nonce, &buff.get_ref(), &plaintext);
 
     let mut authent_buffer = Cursor::new(Vec::new());
-    authent_buffer.write_u16::<BigEndian>(NONCE_LEN as u16)
+    authent_buffer.write_u16::<BigEndian>(T::NONCE_LEN as u16)
         .expect("Nonce length could not be written, failed to serialize NtsPacket"); // length of the nonce
     authent_buffer.write_u16::<BigEndian>(ciphertext.len() as u16)
         .expect("Ciphertext length could not be written, failed to serialize NtsPacket");
     authent_buffer.write_all(&nonce)
-        .expect("Nonce could not be written, failed to serialize NtsPacket"); // 16 bytes so no padding
+        .expect("Nonce could not be written, failed to serialize NtsPacket"); // 12 or 16 bytes so no padding
     authent_buffer.write_all(&ciphertext)
         .expect("Ciphertext could not be written, failed to serialize NtsPacket");
     let padlen = (4 - (ciphertext.len() % 4)) % 4;
@@ -450,6 +588,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_packet_mode_number() {
+        // A monlist request of ntpdc.
+        assert_eq!(packet_mode_number(&[0x17, 0x00, 0x03, 0x2a]), Some(MODE_PRIVATE));
+        assert_eq!(packet_mode_number(&[0x16, 0x02]), Some(MODE_CONTROL));
+        assert_eq!(packet_mode_number(&[0x23]), Some(Client as u8));
+        assert_eq!(packet_mode_number(&[]), None);
+    }
+
+    #[test]
+    fn test_malformed_reason() {
+        let mut packet = vec![0x23; 48];
+        assert_eq!(malformed_reason(&packet[..47]), "too_short");
+        assert_eq!(malformed_reason(&packet), "other");
+        // An extension field shorter than its own header.
+        packet.extend(&[0x01, 0x04, 0x00, 0x02]);
+        assert_eq!(malformed_reason(&packet), "extensions");
+        packet[0] = 0x24;
+        assert_eq!(malformed_reason(&packet), "mode");
+    }
+
+    #[test]
+    fn test_ntpv5_header_parse() {
+        let header = NtpV5PacketHeader {
+            leap_indicator: NoLeap,
+            version: VERSION_5,
+            mode: Server,
+            stratum: 2,
+            poll: -3,
+            precision: -20,
+            timescale: V5_TIMESCALE_UTC,
+            era: 1,
+            flags: V5_FLAG_UNKNOWN_LEAP,
+            root_delay: 0x0123_4567,
+            root_dispersion: 0x89ab_cdef,
+            server_cookie: 0x1122_3344_5566_7788,
+            client_cookie: 0x99aa_bbcc_ddee_ff00,
+            receive_timestamp: 1,
+            transmit_timestamp: 2,
+        };
+        let data = serialize_v5_header(header);
+        assert_eq!(data.len(), HEADER_SIZE as usize);
+        assert_eq!(packet_version(&data), Some(VERSION_5));
+        assert_eq!(&data[4..8], &[0, 1, 0, 1]);
+        assert_eq!(parse_v5_packet_header(&data).unwrap(), header);
+
+        assert!(parse_v5_packet_header(&data[..47]).is_err());
+        assert_eq!(packet_version(&[]), None);
+    }
+
     fn check_eq_ext(a: &NtpExtension, b: &NtpExtension) {
         assert_eq!(a.ext_type, b.ext_type);
         assert_eq!(a.contents.len(), b.contents.len());
@@ -468,7 +656,7 @@ mod tests {
         check_ext_array_eq(pkt1.auth_enc_exts, pkt2.auth_enc_exts);
         check_ext_array_eq(pkt1.auth_exts, pkt2.auth_exts);
     }
-    fn roundtrip_test<T: Aead>(input: NtsPacket, enc: &mut T) {
+    fn roundtrip_test<T: NtsAead>(input: NtsPacket, enc: &mut T) {
         let mut packet = serialize_nts_packet::<T>(input.clone(), enc);
         let decrypt = parse_nts_packet(&packet, enc).unwrap();
         check_nts_match(input, decrypt);
@@ -518,6 +706,9 @@ mod tests {
                 contents: vec![0xfe; 32],
             }],
         };
-        roundtrip_test::<Aes128SivAead>(packet, &mut test_aead);
+        roundtrip_test::<Aes128SivAead>(packet.clone(), &mut test_aead);
+
+        let mut gcm_siv_aead = Aes128GcmSivAead::new(&key[..16]);
+        roundtrip_test::<Aes128GcmSivAead>(packet, &mut gcm_siv_aead);
     }
 }
@@ -0,0 +1,459 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Batched packet I/O. On Linux, recvmmsg and sendmmsg receive and send many packets in one
+//! system call. The other systems receive and send the packets one at a time.
+//!
+//! With UDP GSO and GRO, the kernel also sends the responses to the same client from one buffer,
+//! which it splits into packets, and coalesces the requests from the same client into one buffer.
+//! A lot of clients behind a NAT send from the same address and port.
+
+use libc::{c_void, in6_pktinfo, in_pktinfo, msghdr};
+use nix::sys::socket::SockAddr;
+
+use std::io::Error;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use super::timestamping::{self, Control, PacketInfo, Received};
+
+/// The socket option and the control message of UDP GSO. It's not in libc yet.
+#[cfg(target_os = "linux")]
+const UDP_SEGMENT: libc::c_int = 103;
+
+/// The most packets that the kernel sends from one buffer with UDP GSO.
+const MAX_SEGMENTS: usize = 64;
+
+/// The most bytes that are sent from one buffer with UDP GSO, which leaves room for the headers
+/// in the 64 KiB of an IP packet.
+const MAX_SEGMENTED_BYTES: usize = 65000;
+
+/// The size of the buffers that the requests are received into with UDP GRO, which fits the
+/// largest UDP packet.
+pub const GRO_BUF_SIZE: usize = 65535;
+
+/// A response to send.
+pub struct Outgoing {
+    pub data: Vec<u8>,
+    pub address: SockAddr,
+    /// The local address that the request was received on, if it's known.
+    pub info: Option<PacketInfo>,
+}
+
+/// Return the header to send `packet`, with its data in `iov` and its packet info in `control`.
+/// They have to outlive the header.
+pub fn send_header(packet: &Outgoing, iov: &mut libc::iovec, control: &mut Control) -> msghdr {
+    let mut header: msghdr = unsafe { mem::zeroed() };
+    let (address, address_len) = unsafe { packet.address.as_ffi_pair() };
+    header.msg_name = address as *const libc::sockaddr as *mut c_void;
+    header.msg_namelen = address_len;
+    *iov = libc::iovec {
+        iov_base: packet.data.as_ptr() as *mut c_void,
+        iov_len: packet.data.len(),
+    };
+    header.msg_iov = iov;
+    header.msg_iovlen = 1;
+
+    // Only the source address of an IPv4 response is set. The interface that the request came
+    // in on is left to the routing table, since the routes of a multihomed host can be
+    // asymmetric. The IPv6 one is kept for the link-local addresses.
+    let info = match packet.info {
+        Some(PacketInfo::V4(info)) => Some(PacketInfo::V4(in_pktinfo { ipi_ifindex: 0, ..info })),
+        info => info,
+    };
+    let (level, kind, data, len) = match &info {
+        Some(PacketInfo::V4(info)) => (libc::IPPROTO_IP, libc::IP_PKTINFO,
+                                       info as *const in_pktinfo as *const u8,
+                                       mem::size_of::<in_pktinfo>()),
+        Some(PacketInfo::V6(info)) => (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO,
+                                       info as *const in6_pktinfo as *const u8,
+                                       mem::size_of::<in6_pktinfo>()),
+        None => return header,
+    };
+    unsafe {
+        header.msg_control = control.as_mut_ptr() as *mut c_void;
+        header.msg_controllen = libc::CMSG_SPACE(len as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&header);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = kind;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+        ptr::copy_nonoverlapping(data, libc::CMSG_DATA(cmsg), len);
+    }
+    header
+}
+
+/// Return whether the responses to `a` and `b` are sent from the same address.
+fn same_source(a: &Option<PacketInfo>, b: &Option<PacketInfo>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(PacketInfo::V4(a)), Some(PacketInfo::V4(b))) => {
+            a.ipi_spec_dst.s_addr == b.ipi_spec_dst.s_addr && a.ipi_addr.s_addr == b.ipi_addr.s_addr
+        },
+        (Some(PacketInfo::V6(a)), Some(PacketInfo::V6(b))) => {
+            a.ipi6_addr.s6_addr == b.ipi6_addr.s6_addr && a.ipi6_ifindex == b.ipi6_ifindex
+        },
+        _ => false,
+    }
+}
+
+/// Return the groups of the packets that can be sent from one buffer with UDP GSO, by their
+/// indexes. The packets of a group are sent to the same address from the same one, and all of
+/// them but the last one have the same size. The last one can be smaller. A packet that can't be
+/// grouped with the others is in a group by itself.
+pub fn segments(packets: &[Outgoing]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    // The groups that can still take more packets.
+    let mut open: Vec<usize> = Vec::new();
+    for (index, packet) in packets.iter().enumerate() {
+        let size = packet.data.len();
+        let joined = open.iter().position(|&group| {
+            let first = &packets[groups[group][0]];
+            first.address == packet.address && same_source(&first.info, &packet.info)
+                && size <= first.data.len()
+        });
+        match joined {
+            Some(position) => {
+                let group = &mut groups[open[position]];
+                group.push(index);
+                let segment_size = packets[group[0]].data.len();
+                let full = group.len() == MAX_SEGMENTS
+                    || (group.len() + 1) * segment_size > MAX_SEGMENTED_BYTES;
+                if size < segment_size || full {
+                    open.remove(position);
+                }
+            },
+            None => {
+                if size > 0 && 2 * size <= MAX_SEGMENTED_BYTES {
+                    open.push(groups.len());
+                }
+                groups.push(vec![index]);
+            },
+        }
+    }
+    groups
+}
+
+/// Return the header to send the packets of `group` from one buffer, with their data in `iovs`
+/// and the control messages in `control`. The kernel splits it into packets of the size of the
+/// first one, if there is more than one.
+#[cfg(target_os = "linux")]
+fn segmented_header(
+    packets: &[Outgoing],
+    group: &[usize],
+    iovs: &mut [libc::iovec],
+    control: &mut Control,
+) -> msghdr {
+    let first = &packets[group[0]];
+    let mut header = send_header(first, &mut iovs[0], control);
+    if group.len() == 1 {
+        return header;
+    }
+    for (iov, &index) in iovs.iter_mut().zip(group) {
+        let data = &packets[index].data;
+        *iov = libc::iovec { iov_base: data.as_ptr() as *mut c_void, iov_len: data.len() };
+    }
+    header.msg_iov = iovs.as_mut_ptr();
+    header.msg_iovlen = group.len() as _;
+
+    // The size of the segments comes after the packet info, if there is one.
+    let len = mem::size_of::<u16>();
+    unsafe {
+        let offset: usize = header.msg_controllen as _;
+        header.msg_control = control.as_mut_ptr() as *mut c_void;
+        header.msg_controllen = (offset + libc::CMSG_SPACE(len as u32) as usize) as _;
+        let cmsg = (control.as_mut_ptr() as *mut u8).add(offset) as *mut libc::cmsghdr;
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, first.data.len() as u16);
+    }
+    header
+}
+
+/// Let the kernel coalesce the packets that are received on the socket with UDP GRO.
+#[cfg(target_os = "linux")]
+pub fn enable_gro(fd: RawFd) -> Result<(), Error> {
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(fd, libc::SOL_UDP, timestamping::UDP_GRO,
+                         &enable as *const libc::c_int as *const c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_gro(_fd: RawFd) -> Result<(), Error> {
+    Err(Error::new(std::io::ErrorKind::Other, "UDP GRO is only supported on Linux"))
+}
+
+/// Receive the packets that are waiting on the socket into `bufs`, at most one in each. It
+/// waits for the first packet only.
+#[cfg(target_os = "linux")]
+pub fn receive(fd: RawFd, bufs: &mut [Vec<u8>]) -> Result<Vec<Received>, Error> {
+    let count = bufs.len();
+    if count == 1 {
+        return Ok(vec![timestamping::receive(fd, &mut bufs[0])?]);
+    }
+    let mut addresses: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+    let mut controls: Vec<Control> = vec![[0; 64]; count];
+    let mut iovs: Vec<libc::iovec> = bufs.iter_mut()
+        .map(|buf| libc::iovec { iov_base: buf.as_mut_ptr() as *mut c_void, iov_len: buf.len() })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovs.iter_mut()
+        .zip(addresses.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((iov, address), control)| libc::mmsghdr {
+            msg_hdr: timestamping::receive_header(iov, address, control),
+            msg_len: 0,
+        })
+        .collect();
+
+    let received = unsafe {
+        libc::recvmmsg(fd, headers.as_mut_ptr(), count as _, libc::MSG_WAITFORONE,
+                       ptr::null_mut())
+    };
+    if received < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(headers[..received as usize].iter()
+        .zip(addresses.iter())
+        .map(|(header, address)| {
+            timestamping::received_from(&header.msg_hdr, header.msg_len as usize, address)
+        })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn receive(fd: RawFd, bufs: &mut [Vec<u8>]) -> Result<Vec<Received>, Error> {
+    // The other systems receive one packet at a time.
+    Ok(vec![timestamping::receive(fd, &mut bufs[0])?])
+}
+
+/// Send the packets, and return the result of each one. A packet that fails doesn't stop the
+/// ones after it.
+#[cfg(target_os = "linux")]
+pub fn send(fd: RawFd, packets: &[Outgoing]) -> Vec<Result<(), Error>> {
+    let count = packets.len();
+    let mut iovs: Vec<libc::iovec> = vec![unsafe { mem::zeroed() }; count];
+    let mut controls: Vec<Control> = vec![[0; 64]; count];
+    let mut headers: Vec<libc::mmsghdr> = packets.iter()
+        .zip(iovs.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((packet, iov), control)| libc::mmsghdr {
+            msg_hdr: send_header(packet, iov, control),
+            msg_len: 0,
+        })
+        .collect();
+    send_headers(fd, &mut headers)
+}
+
+/// Send the messages of `headers` with sendmmsg, and return the result of each one.
+#[cfg(target_os = "linux")]
+fn send_headers(fd: RawFd, headers: &mut [libc::mmsghdr]) -> Vec<Result<(), Error>> {
+    let count = headers.len();
+    let mut results = Vec::with_capacity(count);
+    while results.len() < count {
+        let start = results.len();
+        let sent = unsafe {
+            libc::sendmmsg(fd, headers[start..].as_mut_ptr(), (count - start) as _, 0)
+        };
+        if sent <= 0 {
+            // The first packet failed, so it's skipped.
+            results.push(Err(Error::last_os_error()));
+        } else {
+            results.extend((0..sent).map(|_| Ok(())));
+        }
+    }
+    results
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send(fd: RawFd, packets: &[Outgoing]) -> Vec<Result<(), Error>> {
+    packets.iter().map(|packet| send_one(fd, packet)).collect()
+}
+
+/// Send one packet with sendmsg.
+fn send_one(fd: RawFd, packet: &Outgoing) -> Result<(), Error> {
+    let mut iov: libc::iovec = unsafe { mem::zeroed() };
+    let mut control = [0; 64];
+    let header = send_header(packet, &mut iov, &mut control);
+    if unsafe { libc::sendmsg(fd, &header, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The results of sending a batch of packets with UDP GSO.
+pub struct SegmentedSend {
+    /// The result of each packet.
+    pub results: Vec<Result<(), Error>>,
+
+    /// The number of packets that the kernel split from a larger buffer.
+    pub offloaded: usize,
+
+    /// The error that shows that the socket can't send the packets with UDP GSO, if one of the
+    /// sends failed with it. The packets of that send were sent one at a time instead.
+    pub unsupported: Option<Error>,
+}
+
+/// Return whether `error` means that UDP GSO isn't supported by the kernel or the interface.
+fn is_unsupported(error: &Error) -> bool {
+    let codes = [libc::EIO, libc::EINVAL, libc::ENOPROTOOPT, libc::EOPNOTSUPP];
+    error.raw_os_error().map_or(false, |code| codes.contains(&code))
+}
+
+/// Send the packets with UDP GSO in the `groups` that `segments` returned for them, and return
+/// the result of each one. The packets of a send that fails are sent one at a time.
+#[cfg(target_os = "linux")]
+pub fn send_segmented(fd: RawFd, packets: &[Outgoing], groups: &[Vec<usize>]) -> SegmentedSend {
+    let mut iovs: Vec<Vec<libc::iovec>> = groups.iter()
+        .map(|group| vec![unsafe { mem::zeroed() }; group.len()])
+        .collect();
+    let mut controls: Vec<Control> = vec![[0; 64]; groups.len()];
+    let mut headers: Vec<libc::mmsghdr> = groups.iter()
+        .zip(iovs.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((group, iovs), control)| libc::mmsghdr {
+            msg_hdr: segmented_header(packets, group, iovs, control),
+            msg_len: 0,
+        })
+        .collect();
+
+    let mut sent = SegmentedSend {
+        results: packets.iter().map(|_| Ok(())).collect(),
+        offloaded: 0,
+        unsupported: None,
+    };
+    for (group, result) in groups.iter().zip(send_headers(fd, &mut headers)) {
+        match result {
+            Ok(()) if group.len() > 1 => sent.offloaded += group.len(),
+            Ok(()) => (),
+            Err(error) if group.len() > 1 => {
+                if sent.unsupported.is_none() && is_unsupported(&error) {
+                    sent.unsupported = Some(error);
+                }
+                for &index in group {
+                    sent.results[index] = send_one(fd, &packets[index]);
+                }
+            },
+            Err(error) => sent.results[group[0]] = Err(error),
+        }
+    }
+    sent
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_segmented(fd: RawFd, packets: &[Outgoing], _groups: &[Vec<usize>])
+    -> SegmentedSend
+{
+    // The other systems send the packets one at a time.
+    SegmentedSend { results: send(fd, packets), offloaded: 0, unsupported: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_batch() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = SockAddr::new_inet(nix::sys::socket::InetAddr::from_std(
+            &receiver.local_addr().unwrap()));
+        let packets: Vec<Outgoing> = (0..3u8)
+            .map(|i| Outgoing { data: vec![i; 4], address, info: None })
+            .collect();
+        assert!(send(sender.as_raw_fd(), &packets).iter().all(|result| result.is_ok()));
+
+        let mut bufs = vec![vec![0; 16]; 4];
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            let batch = receive(receiver.as_raw_fd(), &mut bufs).unwrap();
+            for (buf, packet) in bufs.iter().zip(batch.iter()) {
+                assert_eq!(packet.bytes, 4);
+                received.push(buf[0]);
+            }
+        }
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let address = |port| SockAddr::new_inet(nix::sys::socket::InetAddr::from_std(
+            &SocketAddr::from(([192, 0, 2, 1], port))));
+        let packet = |port, len| {
+            Outgoing { data: vec![0; len], address: address(port), info: None }
+        };
+        let packets = vec![
+            packet(1, 48), packet(2, 48), packet(1, 48), packet(1, 40), packet(1, 48),
+            packet(2, 56),
+        ];
+        // The smaller packet ends its group, and the larger one can't join one.
+        assert_eq!(segments(&packets), vec![vec![0, 2, 3], vec![1], vec![4], vec![5]]);
+
+        let packets: Vec<Outgoing> = (0..MAX_SEGMENTS + 1).map(|_| packet(1, 48)).collect();
+        let groups = segments(&packets);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), MAX_SEGMENTS);
+    }
+
+    #[test]
+    fn test_segmented() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = SockAddr::new_inet(nix::sys::socket::InetAddr::from_std(
+            &receiver.local_addr().unwrap()));
+        let packets: Vec<Outgoing> = [48, 48, 20]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| Outgoing { data: vec![i as u8; len], address, info: None })
+            .collect();
+        let groups = segments(&packets);
+        assert_eq!(groups, vec![vec![0, 1, 2]]);
+        let sent = send_segmented(sender.as_raw_fd(), &packets, &groups);
+        assert!(sent.results.iter().all(|result| result.is_ok()));
+
+        // Whether or not the kernel split them, they arrive as separate packets.
+        let mut buf = [0; 128];
+        for (i, &len) in [48, 48, 20].iter().enumerate() {
+            let (bytes, _) = receiver.recv_from(&mut buf).unwrap();
+            assert_eq!(bytes, len);
+            assert_eq!(buf[0], i as u8);
+        }
+    }
+
+    #[test]
+    fn test_reply_source() {
+        // The server is bound to all the addresses, and the client sends to one of them.
+        let server = UdpSocket::bind("0.0.0.0:0").unwrap();
+        setsockopt(server.as_raw_fd(), sockopt::Ipv4PacketInfo, &true).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = SocketAddr::from(([127, 0, 0, 2], server.local_addr().unwrap().port()));
+        client.send_to(b"ntp", destination).unwrap();
+
+        let mut bufs = vec![vec![0; 16]];
+        let request = receive(server.as_raw_fd(), &mut bufs).unwrap().remove(0);
+        assert!(request.info.is_some());
+        let response = Outgoing {
+            data: b"ntp".to_vec(),
+            address: request.address.unwrap(),
+            info: request.info,
+        };
+        assert!(send(server.as_raw_fd(), &[response])[0].is_ok());
+
+        let mut buf = [0; 16];
+        let (_, source) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(source, destination);
+    }
+}
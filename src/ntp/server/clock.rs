@@ -0,0 +1,87 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The clock that the server reads the time from. The packet path only goes through the
+//! `ClockSource` trait, so another clock, such as a simulated one in tests or one disciplined by
+//! PTP, can be used instead of the system clock. The offset to the upstream servers and the leap
+//! smear are still applied on top of it.
+//!
+//! The kernel timestamps of the received packets are taken with the system clock, so a clock
+//! that is not the system clock has to be used without them.
+
+use std::time::SystemTime;
+
+use crate::ntp::protocol::{LeapState, UNIX_OFFSET};
+
+/// The precision of the system clock in log2 seconds, which is about 4 us. The clock is usually
+/// read much more precisely than that, but the time it takes to answer a request is not.
+const SYSTEM_CLOCK_PRECISION: i8 = -18;
+
+/// A source of the time that is served.
+pub trait ClockSource: Send + Sync {
+    /// Return the current time.
+    fn now(&self) -> SystemTime;
+
+    /// Return the precision of the clock in log2 seconds, which is advertised unless it's
+    /// configured or an upstream server gives it.
+    fn precision(&self) -> i8;
+
+    /// Return the leap second that the clock announces itself, if the server doesn't announce
+    /// one from its upstream servers or the leap file.
+    fn leap(&self) -> LeapState {
+        LeapState::NoLeap
+    }
+
+    /// Return the era of the NTP timestamp of `time`, which is how many times its 32-bit seconds
+    /// have wrapped around, modulo 256.
+    fn era(&self, time: SystemTime) -> u8 {
+        // Safe absent time machines
+        let unix_time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        ((unix_time.as_secs() + UNIX_OFFSET) >> 32) as u8
+    }
+}
+
+/// The system clock, which doesn't announce leap seconds.
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn precision(&self) -> i8 {
+        SYSTEM_CLOCK_PRECISION
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// A clock that is stopped at a given time.
+    struct StoppedClock(SystemTime);
+
+    impl ClockSource for StoppedClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+
+        fn precision(&self) -> i8 {
+            -20
+        }
+    }
+
+    #[test]
+    fn test_era() {
+        // The first era ends in February 2036.
+        let end = SystemTime::UNIX_EPOCH + Duration::from_secs((1 << 32) - UNIX_OFFSET);
+        let clock = StoppedClock(end);
+        assert_eq!(clock.era(clock.now() - Duration::from_secs(1)), 0);
+        assert_eq!(clock.era(clock.now()), 1);
+        assert_eq!(clock.leap(), LeapState::NoLeap);
+        assert_eq!(SystemClock.era(SystemTime::UNIX_EPOCH), 0);
+    }
+}
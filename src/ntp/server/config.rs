@@ -4,16 +4,82 @@
 
 //! NTP server configuration.
 
+use rustls::Certificate;
 use sloggers::terminal::TerminalLoggerBuilder;
 use sloggers::Build;
 
 use std::convert::TryFrom;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 
+use crate::admin::{get_admin_config, AdminConfig};
+use crate::aws::{get_aws_secret, AwsSecret};
 use crate::cookie::CookieKey;
 use crate::error::WrapError;
+use crate::key_rotator::{get_rotation_config, RotationConfig};
+use crate::key_store::{get_key_store_config, KeyStoreConfig};
 use crate::metrics::MetricsConfig;
+use crate::rate_limiter::{get_rate_limit_config, RateLimitConfig};
+use crate::sub_command::client::load_tls_certs;
+use crate::vault::{get_vault_secret, VaultSecret};
+
+use super::leap::{LeapTable, SmearConfig, SmearShape};
+use super::min_interval::{get_min_interval_config, MinIntervalConfig};
+use super::pcap::PcapConfig;
+use super::persist::PersistConfig;
+use super::refclock::{default_shm_perm, RefclockConfig, RefclockSource};
+use super::symmetric::SymmetricKeys;
+use super::timestamping::Timestamping;
+use super::xdp::XdpConfig;
+
+/// The default port of the upstream NTP servers.
+const DEFAULT_NTP_PORT: u16 = 123;
+
+/// The default port of the upstream NTS-KE servers.
+const DEFAULT_NTS_KE_PORT: u16 = 4460;
+
+/// How often the upstream servers are polled by default.
+const DEFAULT_UPSTREAM_POLL: Duration = Duration::from_secs(64);
+
+/// How often the broadcast packets are sent by default.
+const DEFAULT_BROADCAST_INTERVAL: Duration = Duration::from_secs(64);
+
+/// The number of CPUs in the affinity mask of a thread.
+const MAX_CPUS: usize = 1024;
+
+/// How often the state is saved by default, if it's kept across restarts.
+const DEFAULT_STATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most clients that a worker can keep in its most recently used list.
+const MAX_MRU_SIZE: usize = 1 << 20;
+
+/// The most threads that the NTS requests can be answered by.
+const MAX_NTS_THREADS: usize = 256;
+
+/// The most packets that recvmmsg and sendmmsg take at a time.
+const MAX_BATCH_SIZE: usize = 1024;
+
+/// The root delay and dispersion that are advertised by default, which are 10 in the NTP short
+/// format.
+const DEFAULT_ROOT_DELAY: f64 = 10.0 / 65536.0;
+const DEFAULT_ROOT_DISPERSION: f64 = 10.0 / 65536.0;
+
+/// The largest root delay or dispersion in seconds that fits in the NTP short format.
+const MAX_ROOT_DISTANCE: f64 = 65536.0;
+
+/// The range of the precision in log2 seconds, from about 0.2 ns to 1 s.
+const PRECISION_RANGE: std::ops::RangeInclusive<i64> = -32..=0;
+
+/// The largest poll interval in log2 seconds of RFC 5905, which is about 36 hours.
+const MAX_POLL: i64 = 17;
+
+/// The cookie placeholders that get a new cookie by default. A client keeps eight cookies, and
+/// the one it uses is replaced anyway.
+const DEFAULT_MAX_COOKIE_PLACEHOLDERS: usize = 7;
+
+/// The shortest poll interval in seconds, so that the upstream servers are not overloaded.
+const MIN_UPSTREAM_POLL: i64 = 16;
 
 fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     let mut metrics = None;
@@ -28,32 +94,534 @@ fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     return metrics;
 }
 
+/// An address that the server listens to, and the options of its socket.
+#[derive(Clone, Copy, Debug)]
+pub struct NtpListenAddr {
+    /// Address and port to listen to.
+    pub addr: SocketAddr,
+
+    /// Whether an IPv6 socket receives only IPv6 packets. If it's `None`, the system default is
+    /// used.
+    pub v6only: Option<bool>,
+
+    /// How the packets are timestamped.
+    pub timestamping: Timestamping,
+}
+
+/// Parse an `addr` entry. It's either an address string, or a table with the address in its
+/// `addr` key and the socket options in the other keys.
+fn get_listen_addr(value: config::Value) -> Result<NtpListenAddr, config::ConfigError> {
+    let mut table = match value.clone().into_table() {
+        Ok(table) => table,
+        // If it's not a table, it must be an address string.
+        Err(_) => return Ok(NtpListenAddr {
+            addr: value.into_str()?.parse().wrap_err()?,
+            v6only: None,
+            timestamping: Timestamping::Kernel,
+        }),
+    };
+
+    let addr: SocketAddr = match table.remove("addr") {
+        Some(addr) => addr.into_str()?.parse().wrap_err()?,
+        None => return Err(config::ConfigError::Message(
+            String::from("an addr entry is missing addr")
+        )),
+    };
+
+    let v6only = match table.remove("v6only") {
+        Some(_) if addr.is_ipv4() => return Err(config::ConfigError::Message(
+            format!("v6only is set for the IPv4 address {}", addr)
+        )),
+        Some(value) => Some(value.into_bool()?),
+        None => None,
+    };
+
+    let timestamping = match table.remove("timestamping") {
+        Some(value) => match value.into_str()?.as_str() {
+            "user" => Timestamping::User,
+            "kernel" => Timestamping::Kernel,
+            "kernel-tx" => Timestamping::KernelTransmit,
+            other => return Err(config::ConfigError::Message(
+                format!("unknown timestamping {} for the address {}", other, addr)
+            )),
+        },
+        None => Timestamping::Kernel,
+    };
+
+    Ok(NtpListenAddr { addr, v6only, timestamping })
+}
+
+/// An upstream server that the server synchronizes to.
+#[derive(Clone, Debug)]
+pub struct UpstreamServer {
+    pub host: String,
+
+    /// The NTP port, or the NTS-KE port if NTS is used.
+    pub port: u16,
+
+    /// Whether the upstream server is queried over NTS.
+    pub nts: bool,
+
+    /// The certificate that the NTS-KE server is verified against. Without it, the Web PKI roots
+    /// are used.
+    pub trusted_cert: Option<Certificate>,
+}
+
+/// Parse an `upstreams` entry. It's either a host name, or a table with the host name in its
+/// `server` key and the options in the other keys.
+fn get_upstream(value: config::Value) -> Result<UpstreamServer, config::ConfigError> {
+    let mut table = match value.clone().into_table() {
+        Ok(table) => table,
+        // If it's not a table, it must be a host name.
+        Err(_) => return Ok(UpstreamServer {
+            host: value.into_str()?,
+            port: DEFAULT_NTP_PORT,
+            nts: false,
+            trusted_cert: None,
+        }),
+    };
+
+    let host = match table.remove("server") {
+        Some(host) => host.into_str()?,
+        None => return Err(config::ConfigError::Message(
+            String::from("an upstreams entry is missing server")
+        )),
+    };
+    let nts = match table.remove("nts") {
+        Some(nts) => nts.into_bool()?,
+        None => false,
+    };
+    let port = match table.remove("port") {
+        Some(port) => match u16::try_from(port.into_int()?) {
+            Ok(port) => port,
+            Err(_) => return Err(config::ConfigError::Message(
+                format!("the port of the upstream {} is not a valid u16", host)
+            )),
+        },
+        None if nts => DEFAULT_NTS_KE_PORT,
+        None => DEFAULT_NTP_PORT,
+    };
+    let trusted_cert = match table.remove("cert") {
+        Some(_) if !nts => return Err(config::ConfigError::Message(
+            format!("cert is set for the upstream {} without nts", host)
+        )),
+        Some(path) => match load_tls_certs(path.into_str()?)?.into_iter().next() {
+            Some(cert) => Some(cert),
+            None => return Err(config::ConfigError::Message(
+                format!("no certificate for the upstream {}", host)
+            )),
+        },
+        None => None,
+    };
+
+    Ok(UpstreamServer { host, port, nts, trusted_cert })
+}
+
+/// An interface that the broadcast packets are sent on, for the clients that listen for them
+/// instead of querying the server.
+#[derive(Clone, Debug)]
+pub struct BroadcastConfig {
+    pub interface: String,
+
+    /// The broadcast address, or the multicast group, that the packets are sent to.
+    pub addr: SocketAddr,
+
+    /// How often the packets are sent.
+    pub interval: Duration,
+
+    /// The legacy MAC key that the packets are signed with, if they are.
+    pub key_id: Option<u32>,
+
+    /// The TTL or the hop limit of the multicast packets.
+    pub ttl: u32,
+}
+
+/// Parse a `broadcast` entry, which is a table with the interface in its `interface` key, the
+/// address in its `addr` key, and the options in the other keys. The keys are the ones that the
+/// packets can be signed with.
+fn get_broadcast(
+    value: config::Value,
+    keys: Option<&SymmetricKeys>,
+) -> Result<BroadcastConfig, config::ConfigError> {
+    let mut table = value.into_table()?;
+    let interface = match table.remove("interface") {
+        Some(interface) => interface.into_str()?,
+        None => return Err(config::ConfigError::Message(
+            String::from("a broadcast entry is missing interface")
+        )),
+    };
+    let addr = match table.remove("addr") {
+        Some(addr) => addr.into_str()?,
+        None => return Err(config::ConfigError::Message(
+            format!("the broadcast on {} is missing addr", interface)
+        )),
+    };
+    // The port is optional.
+    let addr = match (SocketAddr::from_str(&addr), IpAddr::from_str(&addr)) {
+        (Ok(addr), _) => addr,
+        (_, Ok(ip)) => SocketAddr::new(ip, DEFAULT_NTP_PORT),
+        _ => return Err(config::ConfigError::Message(
+            format!("the broadcast address {} is not a valid address", addr)
+        )),
+    };
+    // IPv6 has no broadcast addresses.
+    if addr.is_ipv6() && !addr.ip().is_multicast() {
+        return Err(config::ConfigError::Message(
+            format!("the IPv6 broadcast address {} is not a multicast group", addr)
+        ));
+    }
+    let interval = match table.remove("interval") {
+        Some(interval) => match interval.into_int()? {
+            interval if interval > 0 => Duration::from_secs(interval as u64),
+            _ => return Err(config::ConfigError::Message(
+                format!("the interval of the broadcast on {} must be positive", interface)
+            )),
+        },
+        None => DEFAULT_BROADCAST_INTERVAL,
+    };
+    let key_id = match table.remove("key_id") {
+        Some(key_id) => match u32::try_from(key_id.into_int()?) {
+            Ok(key_id) if keys.map_or(false, |keys| keys.contains(key_id)) => Some(key_id),
+            _ => return Err(config::ConfigError::Message(
+                format!("the key_id of the broadcast on {} is not in the keys file", interface)
+            )),
+        },
+        None => None,
+    };
+    let ttl = match table.remove("ttl") {
+        Some(ttl) => match u32::try_from(ttl.into_int()?) {
+            Ok(ttl) if (1..=255).contains(&ttl) => ttl,
+            _ => return Err(config::ConfigError::Message(
+                format!("the ttl of the broadcast on {} must be between 1 and 255", interface)
+            )),
+        },
+        None => 1,
+    };
+
+    Ok(BroadcastConfig { interface, addr, interval, key_id, ttl })
+}
+
+/// Parse a reference ID, which is either an IPv4 address or up to four ASCII characters padded
+/// with zeros.
+fn parse_refid(refid: &str) -> Option<u32> {
+    if let Ok(ip) = Ipv4Addr::from_str(refid) {
+        return Some(u32::from(ip));
+    }
+    if refid.len() > 4 || !refid.is_ascii() {
+        return None;
+    }
+    let mut refid_bytes = [0; 4];
+    refid_bytes[..refid.len()].copy_from_slice(refid.as_bytes());
+    Some(u32::from_be_bytes(refid_bytes))
+}
+
+/// Parse a DSCP, which is a number up to 63 or the name of a class, such as EF, AF41 or CS6.
+fn parse_dscp(name: &str) -> Option<u8> {
+    let name = name.to_uppercase();
+    let dscp = if name == "EF" {
+        46
+    } else if name.starts_with("CS") {
+        match name[2..].parse::<u8>() {
+            Ok(class) if class <= 7 => class * 8,
+            _ => return None,
+        }
+    } else if name.starts_with("AF") {
+        match &name.as_bytes()[2..] {
+            [x @ b'1'..=b'4', y @ b'1'..=b'3'] => (x - b'0') * 8 + (y - b'0') * 2,
+            _ => return None,
+        }
+    } else {
+        name.parse().ok()?
+    };
+    if dscp > 63 {
+        return None;
+    }
+    Some(dscp)
+}
+
+/// Parse the permissions of a SHM segment, which are an octal mode such as 0666.
+fn parse_perm(perm: &str) -> Option<u32> {
+    let perm = u32::from_str_radix(perm.trim_start_matches("0o"), 8).ok()?;
+    if perm > 0o777 {
+        return None;
+    }
+    Some(perm)
+}
+
+/// Return the float at `key` in seconds, which is `default` if it's not set.
+fn get_root_distance(
+    settings: &config::Config,
+    key: &str,
+    default: f64,
+) -> Result<f64, config::ConfigError> {
+    match settings.get_float(key) {
+        Err(config::ConfigError::NotFound(_)) => Ok(default),
+        Err(error) => Err(error),
+        Ok(seconds) if (0.0..MAX_ROOT_DISTANCE).contains(&seconds) => Ok(seconds),
+        Ok(_) => Err(config::ConfigError::Message(
+            format!("{} must be between 0 and {} seconds", key, MAX_ROOT_DISTANCE)
+        )),
+    }
+}
+
+/// Parse a `refclocks` entry, which is a table with the device in its `pps` key or the SHM unit
+/// in its `shm` key, and the options in the other keys.
+fn get_refclock(value: config::Value) -> Result<RefclockConfig, config::ConfigError> {
+    let mut table = value.into_table()?;
+    let (source, default_refid) = match (table.remove("pps"), table.remove("shm")) {
+        (Some(path), None) => (RefclockSource::Pps(path.into_str()?), "PPS"),
+        (None, Some(unit)) => match u32::try_from(unit.into_int()?) {
+            Ok(unit) => {
+                let perm = match table.remove("perm") {
+                    Some(perm) => parse_perm(&perm.into_str()?).ok_or_else(|| {
+                        config::ConfigError::Message(format!(
+                            "the perm of the shm unit {} is not an octal mode up to 0777", unit
+                        ))
+                    })?,
+                    None => default_shm_perm(unit),
+                };
+                (RefclockSource::Shm { unit, perm }, "GPS")
+            },
+            Err(_) => return Err(config::ConfigError::Message(
+                String::from("the shm unit of a refclock is not a valid u32")
+            )),
+        },
+        _ => return Err(config::ConfigError::Message(
+            String::from("a refclocks entry needs either pps or shm")
+        )),
+    };
+
+    let name = match table.remove("name") {
+        Some(name) => name.into_str()?,
+        None => match &source {
+            RefclockSource::Pps(path) => path.clone(),
+            RefclockSource::Shm { unit, .. } => format!("shm{}", unit),
+        },
+    };
+    let offset = match table.remove("offset") {
+        Some(offset) => offset.into_float()?,
+        None => 0.0,
+    };
+    let refid = match table.remove("refid") {
+        Some(refid) => refid.into_str()?,
+        None => String::from(default_refid),
+    };
+    let refid = match parse_refid(&refid) {
+        Some(refid) => refid,
+        None => return Err(config::ConfigError::Message(
+            format!("the refid of the refclock {} is not up to four ASCII characters", name)
+        )),
+    };
+
+    Ok(RefclockConfig { name, source, offset, refid })
+}
+
+/// Parse the `xdp` table, which has the interface, the pinned XSKMAP, the queues and the port.
+fn get_xdp(value: config::Value) -> Result<XdpConfig, config::ConfigError> {
+    if !cfg!(feature = "af-xdp") {
+        return Err(config::ConfigError::Message(
+            String::from("xdp needs cfnts to be built with the af-xdp feature")
+        ));
+    }
+    let mut table = value.into_table()?;
+    let interface = match table.remove("interface") {
+        Some(interface) => interface.into_str()?,
+        None => return Err(config::ConfigError::Message(
+            String::from("xdp is missing interface")
+        )),
+    };
+    let xsks_map = match table.remove("xsks_map") {
+        Some(path) => path.into_str()?,
+        None => return Err(config::ConfigError::Message(
+            String::from("xdp is missing xsks_map")
+        )),
+    };
+    let queues = match table.remove("queues") {
+        Some(queues) => {
+            let mut xdp_queues = Vec::new();
+            for queue in queues.into_array()? {
+                match u32::try_from(queue.into_int()?) {
+                    Ok(queue) => xdp_queues.push(queue),
+                    Err(_) => return Err(config::ConfigError::Message(
+                        format!("a queue of the interface {} is not a valid u32", interface)
+                    )),
+                }
+            }
+            xdp_queues
+        },
+        None => vec![0],
+    };
+    let port = match table.remove("port") {
+        Some(port) => match u16::try_from(port.into_int()?) {
+            Ok(port) => port,
+            Err(_) => return Err(config::ConfigError::Message(
+                String::from("the xdp port is not a valid u16")
+            )),
+        },
+        None => DEFAULT_NTP_PORT,
+    };
+
+    Ok(XdpConfig { interface, queues, xsks_map, port })
+}
+
 /// Configuration for running an NTP server.
 #[derive(Debug)]
 pub struct NtpServerConfig {
     /// List of addresses and ports to the server will be listening to.
     // Each of the elements can be either IPv4 or IPv6 address. It cannot be a UNIX socket address.
-    addrs: Vec<SocketAddr>,
+    addrs: Vec<NtpListenAddr>,
 
     pub cookie_key: CookieKey,
 
+    /// Where the cookie key is read again periodically, if it's kept in Vault.
+    pub cookie_key_vault: Option<VaultSecret>,
+
+    /// Where the cookie key is read again periodically, if it's kept in AWS.
+    pub cookie_key_aws: Option<AwsSecret>,
+
+    /// The file that the cookie key is read again from periodically, if it's not kept in Vault
+    /// or AWS.
+    pub cookie_key_file: Option<String>,
+
+    /// The file that the config was parsed from, if any. It's parsed again on SIGHUP.
+    pub config_file: Option<String>,
+
     /// The logger that will be used throughout the application, while the server is running.
     /// This property is mandatory because logging is very important for debugging.
     logger: slog::Logger,
 
-    pub memcached_url: String,
+    /// The key store that is used to sync the keys with the NTS-KE server.
+    pub key_store: KeyStoreConfig,
+
+    /// How often the keys are rotated and how long the old keys are kept.
+    pub rotation: RotationConfig,
     pub metrics_config: Option<MetricsConfig>,
     pub upstream_addr: Option<SocketAddr>,
+
+    /// The admin endpoint settings. If it's `None`, there is no admin endpoint.
+    pub admin_config: Option<AdminConfig>,
+
+    /// The number of clients in the most recently used list of each worker, if they are
+    /// tracked.
+    pub mru_size: Option<usize>,
+
+    /// Where and how often the totals of the counters, the most recently used list of the
+    /// clients and their rate limits are saved, if they are kept across restarts.
+    pub persist: Option<PersistConfig>,
+
+    /// The upstream servers that the server synchronizes to. It's a stratum 1 server if there
+    /// is none.
+    pub upstreams: Vec<UpstreamServer>,
+
+    /// How often the upstream servers are polled.
+    pub upstream_poll: Duration,
+
+    /// The reference clocks that the server synchronizes to as a stratum 1 server.
+    pub refclocks: Vec<RefclockConfig>,
+
+    /// The stratum, the reference ID, and the root delay and dispersion in seconds that are
+    /// advertised when the server doesn't synchronize to anything itself. They describe the
+    /// source of the system clock.
+    pub stratum: u8,
+    pub refid: u32,
+    pub root_delay: f64,
+    pub root_dispersion: f64,
+
+    /// The precision of the system clock in log2 seconds that is advertised, if it's set, instead
+    /// of the default one or the one of the local upstream.
+    pub precision: Option<i8>,
+
+    /// The shortest poll interval in log2 seconds that is advertised, if it's set. The clients
+    /// that follow the poll field of the responses don't query more often than that.
+    pub min_poll: Option<i8>,
+
+    /// Whether the NTPv5 requests of draft-ietf-ntp-ntpv5 are answered in NTPv5, and the NTPv4
+    /// clients are told that they can switch to it.
+    pub ntpv5: bool,
+
+    /// Whether the clients can use the interleaved mode to get the transmit timestamps taken
+    /// after the responses are sent.
+    pub interleaved: bool,
+
+    /// Whether the NTS requests whose unique identifier was seen before from the same client are
+    /// dropped.
+    pub replay_protection: bool,
+
+    /// Whether the dropped control packets of modes 6 and 7 are logged, at most once in a while
+    /// by each worker.
+    pub log_control_packets: bool,
+
+    /// The fraction of the queries whose transmit timestamp is logged with how far it is from
+    /// the time that they were received at. None of them are if it's zero.
+    pub client_offset_sample_rate: f64,
+
+    /// Per-client rate limit of the queries on each address, if it's enabled.
+    pub query_rate_limit: Option<RateLimitConfig>,
+
+    /// The minimum interval between the queries of each client, if the clients that poll faster
+    /// are penalized.
+    pub min_interval: Option<MinIntervalConfig>,
+
+    /// The leap-seconds.list file that the leap seconds are announced from, if any.
+    pub leap_file: Option<String>,
+
+    /// How the leap seconds are smeared, if they are. The smeared leap seconds are not announced.
+    pub leap_smear: Option<SmearConfig>,
+
+    /// The number of worker threads for each address. If there is more than one, each of them
+    /// has its own socket bound with SO_REUSEPORT.
+    pub workers: usize,
+
+    /// The CPUs that the workers are pinned to in turn. They are not pinned if it's empty.
+    pub worker_cpus: Vec<usize>,
+
+    /// How many packets each worker receives and sends in one system call. It's always one on
+    /// the systems without recvmmsg and sendmmsg.
+    pub batch_size: usize,
+
+    /// The number of threads that the NTS requests are answered by, apart from the workers. The
+    /// workers answer them themselves if it's zero.
+    pub nts_threads: usize,
+
+    /// Whether the packets are received and sent with io_uring. It needs the `io-uring` feature,
+    /// and the workers fall back to recvmmsg and sendmmsg if the kernel doesn't support it.
+    pub io_uring: bool,
+
+    /// Whether the responses to the same client are sent from one buffer with UDP GSO, and the
+    /// requests from the same client are received into one with UDP GRO. The workers fall back to
+    /// sending and receiving the packets one at a time if the kernel doesn't support it.
+    pub udp_offload: bool,
+
+    /// The AF_XDP datapath, if it's enabled. It needs the `af-xdp` feature, and its workers
+    /// answer the requests on their queues in addition to the ones on the sockets.
+    pub xdp: Option<XdpConfig>,
+
+    /// The most cookie placeholders in an NTS request that get a new cookie each. The cookie
+    /// that the request used is always replaced.
+    pub max_cookie_placeholders: usize,
+
+    /// The keys of the legacy MACs of the clients without NTS, if they are enabled.
+    pub symmetric_keys: Option<SymmetricKeys>,
+
+    /// The DSCP that the responses are marked with, if they are.
+    pub dscp: Option<u8>,
+
+    /// The interfaces that the broadcast packets are sent on.
+    pub broadcasts: Vec<BroadcastConfig>,
+
+    /// The capture that the packets are written to for debugging, if it's asked for on the
+    /// command line.
+    pub pcap: Option<PcapConfig>,
 }
 
 /// We decided to make NtpServerConfig mutable so that you can add more address after you parse
 /// the config file.
 impl NtpServerConfig {
-    /// Create a NTP server config object with the given cookie key, memcached url, the metrics
+    /// Create a NTP server config object with the given cookie key, key store, the metrics
     /// config, and the upstream address port.
     pub fn new(
         cookie_key: CookieKey,
-        memcached_url: String,
+        key_store: KeyStoreConfig,
         metrics_config: Option<MetricsConfig>,
         upstream_addr: Option<SocketAddr>,
     ) -> NtpServerConfig {
@@ -68,21 +636,61 @@ impl NtpServerConfig {
             logger: TerminalLoggerBuilder::new().build()
                 .expect("BUG: TerminalLoggerBuilder::build shouldn't return an error."),
 
+            config_file: None,
+            cookie_key_vault: None,
+
+            cookie_key_aws: None,
+            cookie_key_file: None,
+            rotation: RotationConfig::default(),
+            ntpv5: false,
+            interleaved: false,
+            replay_protection: false,
+            log_control_packets: false,
+            client_offset_sample_rate: 0.0,
+            admin_config: None,
+            mru_size: None,
+            persist: None,
+            query_rate_limit: None,
+            min_interval: None,
+            leap_file: None,
+            leap_smear: None,
+            workers: 1,
+            worker_cpus: Vec::new(),
+            batch_size: 1,
+            nts_threads: 0,
+            io_uring: false,
+            udp_offload: false,
+            xdp: None,
+            max_cookie_placeholders: DEFAULT_MAX_COOKIE_PLACEHOLDERS,
+            symmetric_keys: None,
+            dscp: None,
+            broadcasts: Vec::new(),
+            pcap: None,
+            upstreams: Vec::new(),
+            upstream_poll: DEFAULT_UPSTREAM_POLL,
+            refclocks: Vec::new(),
+            stratum: 1,
+            refid: 0,
+            root_delay: DEFAULT_ROOT_DELAY,
+            root_dispersion: DEFAULT_ROOT_DISPERSION,
+            precision: None,
+            min_poll: None,
+
             // From parameters.
             cookie_key,
-            memcached_url,
+            key_store,
             metrics_config,
             upstream_addr,
         }
     }
 
-    /// Add an address into the config.
-    pub fn add_address(&mut self, addr: SocketAddr) {
+    /// Add an address with its socket options into the config.
+    pub fn add_address(&mut self, addr: NtpListenAddr) {
         self.addrs.push(addr);
     }
 
     /// Return a list of addresses.
-    pub fn addrs(&self) -> &[SocketAddr] {
+    pub fn addrs(&self) -> &[NtpListenAddr] {
         self.addrs.as_slice()
     }
 
@@ -120,7 +728,8 @@ impl NtpServerConfig {
         let mut settings = config::Config::new();
         settings.merge(config::File::with_name(filename))?;
 
-        let memcached_url = settings.get_str("memc_url")?;
+        let key_store = get_key_store_config(&settings, false)?;
+        let rotation = get_rotation_config(&settings)?;
 
         // Resolves metrics configuration.
         let metrics_config = get_metrics_config(&settings);
@@ -178,23 +787,332 @@ impl NtpServerConfig {
         // Note that all of the file reading stuffs should be at the end of the function so that
         // all the not-file-related stuffs can fail fast.
 
-        let cookie_key_filename = settings.get_str("cookie_key_file")?;
-        let cookie_key = CookieKey::parse(&cookie_key_filename).wrap_err()?;
+        // The cookie key is read from Vault or AWS instead of the file, if it's configured.
+        let cookie_key_vault = get_vault_secret(&settings, "cookie_key_vault")?;
+        let cookie_key_aws = get_aws_secret(&settings, "cookie_key_aws")?;
+        let (cookie_key, cookie_key_file) = match (&cookie_key_vault, &cookie_key_aws) {
+            (Some(_), Some(_)) => return Err(config::ConfigError::Message(String::from(
+                "cookie_key_vault and cookie_key_aws cannot be used together"
+            ))),
+            (Some(secret), None) => (CookieKey::from(secret.fetch().wrap_err()?), None),
+            (None, Some(secret)) => (CookieKey::from(secret.fetch().wrap_err()?), None),
+            (None, None) => {
+                let cookie_key_filename = settings.get_str("cookie_key_file")?;
+                (CookieKey::parse(&cookie_key_filename).wrap_err()?, Some(cookie_key_filename))
+            },
+        };
 
         let mut config = NtpServerConfig::new(
             cookie_key,
-            memcached_url,
+            key_store,
             metrics_config,
             upstream_sock_addr,
         );
+        config.config_file = Some(String::from(filename));
+        config.cookie_key_vault = cookie_key_vault;
+        config.cookie_key_aws = cookie_key_aws;
+        config.cookie_key_file = cookie_key_file;
+        config.rotation = rotation;
+        config.ntpv5 = match settings.get_bool("ntpv5") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(ntpv5) => ntpv5,
+        };
+        config.interleaved = match settings.get_bool("interleaved") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(interleaved) => interleaved,
+        };
+        config.replay_protection = match settings.get_bool("replay_protection") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(replay_protection) => replay_protection,
+        };
+        config.log_control_packets = match settings.get_bool("log_control_packets") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(log_control_packets) => log_control_packets,
+        };
+        config.client_offset_sample_rate = match settings.get_float("client_offset_sample_rate") {
+            Err(config::ConfigError::NotFound(_)) => 0.0,
+            Err(error) => return Err(error),
+            Ok(rate) if (0.0..=1.0).contains(&rate) => rate,
+            Ok(_) => return Err(config::ConfigError::Message(
+                String::from("client_offset_sample_rate must be between 0 and 1")
+            )),
+        };
+        config.admin_config = get_admin_config(&settings)?;
+        config.mru_size = match settings.get_int("mru_size") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(val) => match usize::try_from(val) {
+                Ok(val) if val > 0 && val <= MAX_MRU_SIZE => Some(val),
+                _ => return Err(config::ConfigError::Message(format!(
+                    "the MRU size is not an integer between 1 and {}", MAX_MRU_SIZE
+                ))),
+            },
+        };
+        let state_interval = match settings.get_int("state_interval") {
+            Err(config::ConfigError::NotFound(_)) => DEFAULT_STATE_INTERVAL,
+            Err(error) => return Err(error),
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds as u64),
+            Ok(_) => return Err(config::ConfigError::Message(
+                String::from("state_interval must be positive")
+            )),
+        };
+        config.persist = match settings.get_str("state_file") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(path) => Some(PersistConfig { path, interval: state_interval }),
+        };
+        config.max_cookie_placeholders = match settings.get_int("max_cookie_placeholders") {
+            Err(config::ConfigError::NotFound(_)) => DEFAULT_MAX_COOKIE_PLACEHOLDERS,
+            Err(error) => return Err(error),
+            Ok(val) => match usize::try_from(val) {
+                Ok(val) => val,
+                Err(_) => return Err(config::ConfigError::Message(
+                    String::from("max_cookie_placeholders is not a non-negative integer")
+                )),
+            },
+        };
+        config.symmetric_keys = match settings.get_str("symmetric_keys_file") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(path) => Some(SymmetricKeys::read(&path).wrap_err()?),
+        };
+        config.broadcasts = match settings.get_array("broadcast") {
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(error) => return Err(error),
+            Ok(broadcasts) => {
+                let keys = config.symmetric_keys.as_ref();
+                broadcasts.into_iter().map(|value| get_broadcast(value, keys))
+                    .collect::<Result<_, _>>()?
+            },
+        };
+        config.query_rate_limit = get_rate_limit_config(&settings, "query_rate")?;
+        config.min_interval = get_min_interval_config(&settings)?;
+        config.leap_file = match settings.get_str("leap_file") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(path) => {
+                // Fail fast if the file cannot be used. It's read again by the server.
+                LeapTable::read(&path).wrap_err()?;
+                Some(path)
+            },
+        };
+        let smear_shape = match settings.get_str("leap_smear_shape") {
+            Err(config::ConfigError::NotFound(_)) => SmearShape::Linear,
+            Err(error) => return Err(error),
+            Ok(ref shape) if shape == "linear" => SmearShape::Linear,
+            Ok(ref shape) if shape == "cosine" => SmearShape::Cosine,
+            Ok(shape) => return Err(config::ConfigError::Message(
+                format!("unknown leap smear shape: {}", shape)
+            )),
+        };
+        config.leap_smear = match settings.get_int("leap_smear") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(window) if window <= 0 => return Err(config::ConfigError::Message(
+                String::from("leap_smear must be positive")
+            )),
+            Ok(_) if config.leap_file.is_none() => return Err(config::ConfigError::Message(
+                String::from("leap_smear needs a leap_file")
+            )),
+            Ok(window) => Some(SmearConfig { window: window as u64, shape: smear_shape }),
+        };
+
+        config.io_uring = match settings.get_bool("io_uring") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(true) if !cfg!(feature = "io-uring") => return Err(config::ConfigError::Message(
+                String::from("io_uring needs cfnts to be built with the io-uring feature")
+            )),
+            Ok(io_uring) => io_uring,
+        };
+        config.udp_offload = match settings.get_bool("udp_offload") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(udp_offload) => udp_offload,
+        };
+        config.xdp = match settings.get::<config::Value>("xdp") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(xdp) => Some(get_xdp(xdp)?),
+        };
+        config.dscp = match settings.get_str("dscp") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(name) => match parse_dscp(&name) {
+                Some(dscp) => Some(dscp),
+                None => return Err(config::ConfigError::Message(
+                    format!("invalid DSCP: {}", name)
+                )),
+            },
+        };
+
+        config.upstreams = match settings.get_array("upstreams") {
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(error) => return Err(error),
+            Ok(upstreams) => {
+                upstreams.into_iter().map(get_upstream).collect::<Result<_, _>>()?
+            },
+        };
+        if !config.upstreams.is_empty() && config.upstream_addr.is_some() {
+            return Err(config::ConfigError::Message(String::from(
+                "upstreams and upstream_addr cannot be used together"
+            )));
+        }
+        config.refclocks = match settings.get_array("refclocks") {
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(error) => return Err(error),
+            Ok(refclocks) => {
+                refclocks.into_iter().map(get_refclock).collect::<Result<_, _>>()?
+            },
+        };
+        if !config.refclocks.is_empty()
+            && (!config.upstreams.is_empty() || config.upstream_addr.is_some())
+        {
+            return Err(config::ConfigError::Message(String::from(
+                "refclocks cannot be used together with upstreams or upstream_addr"
+            )));
+        }
+        let synchronized = !config.upstreams.is_empty() || !config.refclocks.is_empty()
+            || config.upstream_addr.is_some();
+        let local_clock = ["stratum", "refid", "root_delay", "root_dispersion"].iter()
+            .find(|key| settings.get::<config::Value>(key).is_ok());
+        if let (true, Some(key)) = (synchronized, local_clock) {
+            return Err(config::ConfigError::Message(format!(
+                "{} cannot be used together with upstreams, refclocks or upstream_addr", key
+            )));
+        }
+        config.stratum = match settings.get_int("stratum") {
+            Err(config::ConfigError::NotFound(_)) => 1,
+            Err(error) => return Err(error),
+            Ok(stratum) if (1..=15).contains(&stratum) => stratum as u8,
+            Ok(_) => return Err(config::ConfigError::Message(
+                String::from("the stratum must be between 1 and 15")
+            )),
+        };
+        config.refid = match settings.get_str("refid") {
+            Err(config::ConfigError::NotFound(_)) => 0,
+            Err(error) => return Err(error),
+            Ok(refid) => match parse_refid(&refid) {
+                Some(refid) => refid,
+                None => return Err(config::ConfigError::Message(String::from(
+                    "the refid is neither an IPv4 address nor up to four ASCII characters"
+                ))),
+            },
+        };
+        config.root_delay = get_root_distance(&settings, "root_delay", DEFAULT_ROOT_DELAY)?;
+        config.root_dispersion =
+            get_root_distance(&settings, "root_dispersion", DEFAULT_ROOT_DISPERSION)?;
+        config.precision = match settings.get_int("precision") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(precision) if PRECISION_RANGE.contains(&precision) => Some(precision as i8),
+            Ok(_) => return Err(config::ConfigError::Message(format!(
+                "the precision must be between {} and {}",
+                PRECISION_RANGE.start(), PRECISION_RANGE.end()
+            ))),
+        };
+        config.min_poll = match settings.get_int("min_poll") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(poll) if (0..=MAX_POLL).contains(&poll) => Some(poll as i8),
+            Ok(_) => return Err(config::ConfigError::Message(
+                format!("min_poll must be between 0 and {}", MAX_POLL)
+            )),
+        };
+
+        config.upstream_poll = match settings.get_int("upstream_poll") {
+            Err(config::ConfigError::NotFound(_)) => DEFAULT_UPSTREAM_POLL,
+            Err(error) => return Err(error),
+            Ok(poll) if poll >= MIN_UPSTREAM_POLL => Duration::from_secs(poll as u64),
+            Ok(_) => return Err(config::ConfigError::Message(
+                format!("upstream_poll must be at least {} seconds", MIN_UPSTREAM_POLL)
+            )),
+        };
+
+        config.workers = match settings.get_int("workers") {
+            // If it's a not-found error, there is one worker for each address.
+            Err(config::ConfigError::NotFound(_)) => 1,
+            Err(error) => return Err(error),
+            Ok(val) => match usize::try_from(val) {
+                Ok(val) if val > 0 => val,
+                _ => return Err(config::ConfigError::Message(
+                    String::from("the number of workers is not a positive integer")
+                )),
+            },
+        };
+        config.worker_cpus = match settings.get_array("worker_cpus") {
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(error) => return Err(error),
+            Ok(cpus) => {
+                let mut worker_cpus = Vec::new();
+                for cpu in cpus {
+                    match usize::try_from(cpu.into_int()?) {
+                        Ok(cpu) if cpu < MAX_CPUS => worker_cpus.push(cpu),
+                        _ => return Err(config::ConfigError::Message(
+                            String::from("a worker CPU is not a valid CPU number")
+                        )),
+                    }
+                }
+                worker_cpus
+            },
+        };
+        config.batch_size = match settings.get_int("batch_size") {
+            // If it's a not-found error, the packets are received and sent one at a time.
+            Err(config::ConfigError::NotFound(_)) => 1,
+            Err(error) => return Err(error),
+            Ok(val) => match usize::try_from(val) {
+                Ok(val) if val > 0 && val <= MAX_BATCH_SIZE => val,
+                _ => return Err(config::ConfigError::Message(format!(
+                    "the batch size is not an integer between 1 and {}", MAX_BATCH_SIZE
+                ))),
+            },
+        };
+        config.nts_threads = match settings.get_int("nts_threads") {
+            Err(config::ConfigError::NotFound(_)) => 0,
+            Err(error) => return Err(error),
+            Ok(val) => match usize::try_from(val) {
+                Ok(val) if val <= MAX_NTS_THREADS => val,
+                _ => return Err(config::ConfigError::Message(format!(
+                    "nts_threads is not an integer between 0 and {}", MAX_NTS_THREADS
+                ))),
+            },
+        };
 
         let addrs = settings.get_array("addr")?;
         for addr in addrs {
-            // Parse SocketAddr from a string.
-            let sock_addr = addr.to_string().parse().wrap_err()?;
-            config.add_address(sock_addr);
+            config.add_address(get_listen_addr(addr)?);
         }
 
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen_addr(entry: &str) -> Result<NtpListenAddr, config::ConfigError> {
+        let mut settings = config::Config::new();
+        let yaml = format!("addr:\n  - {}", entry);
+        settings.merge(config::File::from_str(&yaml, config::FileFormat::Yaml)).unwrap();
+        get_listen_addr(settings.get_array("addr")?.remove(0))
+    }
+
+    #[test]
+    fn test_v6only() {
+        assert_eq!(listen_addr("\"[::]:123\"").unwrap().v6only, None);
+        assert_eq!(listen_addr("{addr: \"[::]:123\", v6only: false}").unwrap().v6only,
+                   Some(false));
+        assert_eq!(listen_addr("{addr: \"[::1]:123\", v6only: true}").unwrap().v6only,
+                   Some(true));
+
+        // It's only for the IPv6 addresses.
+        assert!(listen_addr("{addr: 127.0.0.1:123, v6only: true}").is_err());
+        assert!(listen_addr("{addr: \"[::]:123\", v6only: maybe}").is_err());
+        assert!(listen_addr("{v6only: true}").is_err());
+    }
+}
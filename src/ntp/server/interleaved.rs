@@ -0,0 +1,95 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Per-client state of the interleaved mode of draft-ietf-ntp-interleaved-modes. In that mode,
+//! a client gets the transmit timestamp of the previous response, which is taken after the
+//! response is sent, instead of the less accurate one taken before sending the current response.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::cidr::canonical_ip;
+
+/// The timestamps of the last response sent to a client.
+struct Exchange {
+    /// The receive timestamp of the request.
+    receive_timestamp: u64,
+
+    /// The time that the response was sent.
+    transmit_timestamp: u64,
+}
+
+/// The last exchange with each client.
+pub struct InterleavedLog {
+    /// The maximum number of clients that are remembered.
+    max_clients: usize,
+
+    /// The exchanges indexed by the client addresses.
+    clients: HashMap<IpAddr, Exchange>,
+}
+
+impl InterleavedLog {
+    /// Create an empty log which remembers up to `max_clients` clients.
+    pub fn new(max_clients: usize) -> InterleavedLog {
+        InterleavedLog {
+            max_clients,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Return the transmit timestamp of the previous response to `addr`, if the client asks for
+    /// the interleaved mode. It asks by sending the receive timestamp of its previous request as
+    /// the origin timestamp.
+    pub fn previous_transmit(&self, addr: IpAddr, origin_timestamp: u64) -> Option<u64> {
+        match self.clients.get(&canonical_ip(addr)) {
+            Some(exchange) if origin_timestamp != 0
+                && exchange.receive_timestamp == origin_timestamp => {
+                Some(exchange.transmit_timestamp)
+            },
+            _ => None,
+        }
+    }
+
+    /// Record the timestamps of a response sent to `addr`.
+    pub fn record(&mut self, addr: IpAddr, receive_timestamp: u64, transmit_timestamp: u64) {
+        let addr = canonical_ip(addr);
+        // When the log is full, it starts over. The clients just get a response in the basic
+        // mode once.
+        if self.clients.len() >= self.max_clients && !self.clients.contains_key(&addr) {
+            self.clients.clear();
+        }
+        self.clients.insert(addr, Exchange { receive_timestamp, transmit_timestamp });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleaved_log() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let mut log = InterleavedLog::new(2);
+        assert_eq!(log.previous_transmit(client, 10), None);
+
+        log.record(client, 10, 11);
+        assert_eq!(log.previous_transmit(client, 10), Some(11));
+        // A client in the basic mode sends its own transmit timestamp.
+        assert_eq!(log.previous_transmit(client, 12), None);
+        assert_eq!(log.previous_transmit(other, 10), None);
+        // IPv4-mapped IPv6 addresses are the same clients.
+        assert_eq!(log.previous_transmit("::ffff:192.0.2.1".parse().unwrap(), 10), Some(11));
+
+        log.record(client, 20, 21);
+        assert_eq!(log.previous_transmit(client, 10), None);
+        assert_eq!(log.previous_transmit(client, 20), Some(21));
+
+        // The log starts over when it's full.
+        log.record(other, 30, 31);
+        log.record("192.0.2.3".parse().unwrap(), 40, 41);
+        assert_eq!(log.previous_transmit(client, 20), None);
+        assert_eq!(log.clients.len(), 1);
+    }
+}
@@ -0,0 +1,325 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Leap seconds from a leap-seconds.list file, as published by the IETF and NIST. Each line has
+//! an NTP time in seconds and the TAI-UTC offset from that time on. The `#$` and `#@` lines hold
+//! the time of the last update and the expiration, and the `#h` line holds the SHA-1 hash of the
+//! data.
+
+use ring::digest;
+
+use std::f64::consts::PI;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::ntp::protocol::LeapState;
+
+/// How long before a leap second it's announced in the leap indicator, which is one day.
+const ANNOUNCE_PERIOD: u64 = 86400;
+
+/// A leap second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LeapEvent {
+    /// The NTP time in seconds right after the leap second, when the new offset applies.
+    pub at: u64,
+
+    /// Whether a second is inserted or deleted.
+    pub kind: LeapState,
+}
+
+impl LeapEvent {
+    /// Return true if the leap second is announced at the NTP time `now` in seconds.
+    pub fn is_announced(&self, now: u64) -> bool {
+        self.at.saturating_sub(ANNOUNCE_PERIOD) <= now && now < self.at
+    }
+}
+
+/// How the leap second is spread over the smear window.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SmearShape {
+    /// The clock runs at a constant slower or faster rate during the window.
+    Linear,
+    /// The rate changes smoothly, so that the frequency doesn't jump at the edges of the window.
+    Cosine,
+}
+
+/// How the leap seconds are smeared.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SmearConfig {
+    /// The length of the window in seconds, which is centered on the leap second.
+    pub window: u64,
+    pub shape: SmearShape,
+}
+
+impl SmearConfig {
+    /// Return the number of seconds added to the time `elapsed` seconds after the beginning of
+    /// the window on a timeline without the leap second. It goes from 0 to -1 for an inserted
+    /// second, and from 0 to 1 for a deleted one.
+    fn offset(&self, kind: LeapState, elapsed: f64) -> f64 {
+        let progress = (elapsed / self.window as f64).max(0.0).min(1.0);
+        let fraction = match self.shape {
+            SmearShape::Linear => progress,
+            SmearShape::Cosine => (1.0 - (PI * progress).cos()) / 2.0,
+        };
+        match kind {
+            LeapState::Positive => -fraction,
+            LeapState::Negative => fraction,
+            _ => 0.0,
+        }
+    }
+}
+
+/// The smear of a leap second. The system clock is expected to step at the leap second, as the
+/// kernel does when the clock daemon announces it, so the time is first mapped to a timeline
+/// without the step. The step is detected with the monotonic clock, which doesn't step.
+#[derive(Clone, Copy, Debug)]
+pub struct LeapSmear {
+    config: SmearConfig,
+    event: LeapEvent,
+
+    /// The monotonic and the system time at some point before the leap second.
+    anchor: (Instant, SystemTime),
+}
+
+/// Return the number of seconds since the Unix epoch of `time`, which can be negative.
+fn unix_seconds(time: SystemTime) -> f64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(error) => -error.duration().as_secs_f64(),
+    }
+}
+
+impl LeapSmear {
+    /// Create the smear of `event`, where `unix_offset` is the number of seconds between the NTP
+    /// and the Unix epochs. It has to be created before the leap second.
+    pub fn new(config: SmearConfig, event: LeapEvent, unix_offset: u64) -> LeapSmear {
+        LeapSmear {
+            config,
+            event: LeapEvent { at: event.at - unix_offset, kind: event.kind },
+            anchor: (Instant::now(), SystemTime::now()),
+        }
+    }
+
+    /// Return the leap second being smeared. Its time is in Unix seconds.
+    pub fn event(&self) -> LeapEvent {
+        self.event
+    }
+
+    /// Return whether the smear window has ended at `now`.
+    pub fn is_over(&self, now: SystemTime) -> bool {
+        let end = self.event.at + self.config.window - self.config.window / 2;
+        unix_seconds(now) > end as f64 + 1.0
+    }
+
+    /// Return the number of seconds that the system clock has stepped for the leap second at
+    /// `now` on the system clock and `monotonic_now` on the monotonic clock. It's 0 before the
+    /// leap second, -1 after an inserted second, and 1 after a deleted one.
+    fn step(&self, now: SystemTime, monotonic_now: Instant) -> f64 {
+        let (anchor_monotonic, anchor_system) = self.anchor;
+        let expected = unix_seconds(anchor_system)
+            + monotonic_now.duration_since(anchor_monotonic.min(monotonic_now)).as_secs_f64();
+        let step = match self.event.kind {
+            LeapState::Positive => -1.0,
+            LeapState::Negative => 1.0,
+            _ => return 0.0,
+        };
+        // Less than half a second is just the drift between the clocks.
+        if (unix_seconds(now) - expected) * step >= 0.5 { step } else { 0.0 }
+    }
+
+    /// Return the smeared time of `time` on the system clock, which was read just now.
+    pub fn apply(&self, time: SystemTime) -> SystemTime {
+        let step = self.step(SystemTime::now(), Instant::now());
+        // The time on a timeline without the step.
+        let continuous = unix_seconds(time) - step;
+        let start = self.event.at as f64 - (self.config.window / 2) as f64;
+        if continuous < start || continuous > start + self.config.window as f64 + 1.0 {
+            return time;
+        }
+
+        let shift = self.config.offset(self.event.kind, continuous - start) - step;
+        if shift >= 0.0 {
+            time + Duration::from_secs_f64(shift)
+        } else {
+            time - Duration::from_secs_f64(-shift)
+        }
+    }
+}
+
+/// The contents of a leap-seconds.list file.
+#[derive(Debug)]
+pub struct LeapTable {
+    /// The NTP time in seconds after which the file is no longer valid.
+    pub expires: u64,
+
+    /// The NTP times in seconds and the TAI-UTC offsets from those times on, in order.
+    entries: Vec<(u64, i32)>,
+}
+
+/// Return an `InvalidData` error.
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Parse a number in a field of a line.
+fn parse_field<T: std::str::FromStr>(field: Option<&str>, line: &str) -> Result<T, Error> {
+    field.and_then(|field| field.parse().ok())
+        .ok_or_else(|| invalid(format!("invalid leap file line: {}", line)))
+}
+
+impl LeapTable {
+    /// Parse a leap-seconds.list file. If it has a hash, it has to match the data.
+    pub fn parse(text: &str) -> Result<LeapTable, Error> {
+        let mut updated: Option<u64> = None;
+        let mut expires: Option<u64> = None;
+        let mut hash: Option<Vec<u32>> = None;
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            if line.starts_with("#$") {
+                updated = Some(parse_field(line[2..].split_whitespace().next(), line)?);
+            } else if line.starts_with("#@") {
+                expires = Some(parse_field(line[2..].split_whitespace().next(), line)?);
+            } else if line.starts_with("#h") {
+                let words = line[2..].split_whitespace()
+                    .map(|word| u32::from_str_radix(word, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid(format!("invalid leap file hash: {}", line)))?;
+                hash = Some(words);
+            } else if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            } else {
+                let time: u64 = parse_field(fields.next(), line)?;
+                let offset: i32 = parse_field(fields.next(), line)?;
+                if let Some((last, _)) = entries.last() {
+                    if time <= *last {
+                        return Err(invalid(format!("leap file is out of order at {}", time)));
+                    }
+                }
+                entries.push((time, offset));
+            }
+        }
+
+        let updated = updated.ok_or_else(|| invalid(String::from("leap file has no #$ line")))?;
+        let expires = expires.ok_or_else(|| invalid(String::from("leap file has no #@ line")))?;
+        if entries.is_empty() {
+            return Err(invalid(String::from("leap file has no leap seconds")));
+        }
+
+        // The hash covers the digits of the update time, the expiration, and the data lines.
+        if let Some(hash) = hash {
+            let mut data = format!("{}{}", updated, expires);
+            for (time, offset) in &entries {
+                data.push_str(&format!("{}{}", time, offset));
+            }
+            let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data.as_bytes());
+            let words: Vec<u32> = digest.as_ref()
+                .chunks(4)
+                .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect();
+            if words != hash {
+                return Err(invalid(String::from("leap file hash mismatch")));
+            }
+        }
+
+        Ok(LeapTable { expires, entries })
+    }
+
+    /// Read and parse a leap-seconds.list file.
+    pub fn read(path: &str) -> Result<LeapTable, Error> {
+        LeapTable::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Return the TAI-UTC offset at the NTP time `now` in seconds, if it's after the first entry.
+    pub fn tai_offset(&self, now: u64) -> Option<i32> {
+        self.entries.iter()
+            .take_while(|(time, _)| *time <= now)
+            .last()
+            .map(|(_, offset)| *offset)
+    }
+
+    /// Return the first leap second after the NTP time `now` in seconds, if any.
+    pub fn next_leap(&self, now: u64) -> Option<LeapEvent> {
+        self.entries.windows(2)
+            .find(|pair| pair[1].0 > now)
+            .map(|pair| LeapEvent {
+                at: pair[1].0,
+                kind: if pair[1].1 > pair[0].1 { LeapState::Positive } else { LeapState::Negative },
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEAP_FILE: &str = "\
+# A part of leap-seconds.list.
+#$\t 3676924800
+#@\t 3928521600
+#
+2272060800\t10\t# 1 Jan 1972
+2287785600\t11\t# 1 Jul 1972
+3692217600\t37\t# 1 Jan 2017
+#h\tb11624ae 51254890 e740a58 e6679018 6c36b341
+";
+
+    #[test]
+    fn test_parse() {
+        let table = LeapTable::parse(LEAP_FILE).unwrap();
+        assert_eq!(table.expires, 3928521600);
+        assert_eq!(table.tai_offset(2272060799), None);
+        assert_eq!(table.tai_offset(2272060800), Some(10));
+        assert_eq!(table.tai_offset(3800000000), Some(37));
+
+        // The hash must match the data.
+        assert!(LeapTable::parse(&LEAP_FILE.replace("\t37", "\t38")).is_err());
+        assert!(LeapTable::parse(&LEAP_FILE.replace("#@", "#")).is_err());
+        assert!(LeapTable::parse("#$ 1\n#@ 2\n").is_err());
+    }
+
+    #[test]
+    fn test_next_leap() {
+        let table = LeapTable::parse(LEAP_FILE).unwrap();
+        let event = table.next_leap(3692217600 - 100).unwrap();
+        assert_eq!(event, LeapEvent { at: 3692217600, kind: LeapState::Positive });
+        assert!(table.next_leap(3692217600).is_none());
+
+        // The leap second is only announced in the day before it.
+        assert!(!event.is_announced(3692217600 - 86401));
+        assert!(event.is_announced(3692217600 - 86400));
+        assert!(event.is_announced(3692217599));
+        assert!(!event.is_announced(3692217600));
+
+        let table = LeapTable::parse("#$ 1\n#@ 2\n100 37\n200 36\n").unwrap();
+        assert_eq!(table.next_leap(150).unwrap().kind, LeapState::Negative);
+    }
+
+    #[test]
+    fn test_smear_offset() {
+        let linear = SmearConfig { window: 100, shape: SmearShape::Linear };
+        assert_eq!(linear.offset(LeapState::Positive, -10.0), 0.0);
+        assert_eq!(linear.offset(LeapState::Positive, 25.0), -0.25);
+        assert_eq!(linear.offset(LeapState::Negative, 25.0), 0.25);
+        assert_eq!(linear.offset(LeapState::Positive, 110.0), -1.0);
+
+        let cosine = SmearConfig { window: 100, shape: SmearShape::Cosine };
+        assert!((cosine.offset(LeapState::Positive, 50.0) + 0.5).abs() < 1e-9);
+        assert!(cosine.offset(LeapState::Positive, 10.0) > -0.1);
+    }
+
+    #[test]
+    fn test_smear_step() {
+        let config = SmearConfig { window: 100, shape: SmearShape::Linear };
+        let event = LeapEvent { at: 1000, kind: LeapState::Positive };
+        let smear = LeapSmear::new(config, event, 0);
+        let (monotonic, system) = smear.anchor;
+        let later = monotonic + Duration::from_secs(10);
+
+        assert_eq!(smear.step(system + Duration::from_secs(10), later), 0.0);
+        assert_eq!(smear.step(system + Duration::from_millis(9800), later), 0.0);
+        // The system clock went back by a second for the inserted second.
+        assert_eq!(smear.step(system + Duration::from_secs(9), later), -1.0);
+    }
+}
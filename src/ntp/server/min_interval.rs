@@ -0,0 +1,330 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The minimum interval between the queries of each client, like the discard minimum of ntpd.
+//! Unlike the rate limit, which allows bursts, it looks at the time between two queries. A client
+//! gets a strike for each query that comes too soon after the last one, and loses one for each
+//! minimum interval that it waits. The more strikes it has, the more likely its queries are
+//! dropped, and it's banned for a while when it gets too many.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::cidr::{client_network, get_cidr_list, Cidr};
+
+/// How many strikes a client can get before it's banned by default.
+const DEFAULT_BAN_AFTER: u32 = 16;
+
+/// How long a client is banned by default.
+const DEFAULT_BAN: Duration = Duration::from_secs(600);
+
+/// The most clients that each worker keeps track of.
+const MAX_CLIENTS: usize = 65536;
+
+/// Configuration of the minimum interval between the queries of each client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MinIntervalConfig {
+    /// The shortest time that is sane between two queries of a client.
+    pub interval: Duration,
+
+    /// The number of strikes that get a client banned. Its queries are dropped with the
+    /// probability of its strikes over this.
+    pub ban_after: u32,
+
+    /// How long the banned clients are.
+    pub ban: Duration,
+
+    /// The clients in these address blocks are never penalized.
+    pub exempt: Vec<Cidr>,
+}
+
+/// Parse the minimum interval from `min_interval`, `min_interval_ban_after`, `min_interval_ban`
+/// and `min_interval_exempt`. It's enabled only when `min_interval` is specified.
+pub fn get_min_interval_config(settings: &config::Config)
+    -> Result<Option<MinIntervalConfig>, config::ConfigError>
+{
+    let interval = match settings.get_float("min_interval") {
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Duration::from_secs_f64(seconds),
+        Ok(_) => return Err(config::ConfigError::Message(
+            String::from("min_interval must be positive")
+        )),
+    };
+
+    let ban_after = match settings.get_int("min_interval_ban_after") {
+        Err(config::ConfigError::NotFound(_)) => DEFAULT_BAN_AFTER,
+        Err(error) => return Err(error),
+        Ok(val) => match u32::try_from(val) {
+            Ok(val) if val > 0 => val,
+            _ => return Err(config::ConfigError::Message(
+                String::from("min_interval_ban_after is not a positive u32")
+            )),
+        },
+    };
+
+    let ban = match settings.get_int("min_interval_ban") {
+        Err(config::ConfigError::NotFound(_)) => DEFAULT_BAN,
+        Err(error) => return Err(error),
+        Ok(seconds) if seconds > 0 => Duration::from_secs(seconds as u64),
+        Ok(_) => return Err(config::ConfigError::Message(
+            String::from("min_interval_ban must be positive")
+        )),
+    };
+
+    let exempt = get_cidr_list(settings, "min_interval_exempt")?;
+
+    Ok(Some(MinIntervalConfig { interval, ban_after, ban, exempt }))
+}
+
+/// What happens to a query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    /// It's answered.
+    Allow,
+
+    /// It's dropped because the client has strikes.
+    Penalty,
+
+    /// It's dropped because the client was just banned.
+    Ban,
+
+    /// It's dropped because the client is banned.
+    Banned,
+}
+
+/// What is known about a client.
+struct Client {
+    /// When its last query came.
+    last: Instant,
+
+    /// The queries that came too soon, less the intervals that it waited.
+    strikes: u32,
+
+    /// Until when it's banned, if it is.
+    banned_until: Option<Instant>,
+}
+
+impl Client {
+    /// Until when the client matters: the end of its ban, or else its last query.
+    fn relevant_until(&self) -> Instant {
+        self.banned_until.map_or(self.last, |until| until.max(self.last))
+    }
+}
+
+/// The clients of a worker, and how they have been polling.
+pub struct MinInterval {
+    config: MinIntervalConfig,
+
+    /// The most clients that are kept track of.
+    max_clients: usize,
+
+    /// The clients by their IPv4 addresses or IPv6 /64 networks, see `client_network`.
+    clients: HashMap<IpAddr, Client>,
+
+    /// The last time that the forgiven clients were purged.
+    last_purge: Instant,
+}
+
+impl MinInterval {
+    pub fn new(config: MinIntervalConfig) -> MinInterval {
+        MinInterval {
+            config,
+            max_clients: MAX_CLIENTS,
+            clients: HashMap::new(),
+            last_purge: Instant::now(),
+        }
+    }
+
+    /// Record a query from `addr` at `now`, and return what happens to it. `roll` is a random
+    /// number between 0 and 1, which decides whether a client with strikes is penalized.
+    pub fn check(&mut self, addr: IpAddr, now: Instant, roll: f64) -> Verdict {
+        if self.config.exempt.iter().any(|block| block.contains(&addr)) {
+            return Verdict::Allow;
+        }
+        let addr = client_network(addr);
+
+        self.purge(now, false);
+
+        let client = match self.clients.get_mut(&addr) {
+            Some(client) => client,
+            None => {
+                self.make_room(now);
+                self.clients.insert(addr, Client { last: now, strikes: 0, banned_until: None });
+                return Verdict::Allow;
+            },
+        };
+        // The queries during a ban don't count against the client, so that it ends.
+        match client.banned_until {
+            Some(until) if now < until => return Verdict::Banned,
+            Some(_) => client.banned_until = None,
+            None => (),
+        }
+
+        let elapsed = now.duration_since(client.last.min(now));
+        client.last = now;
+        if elapsed < self.config.interval {
+            client.strikes += 1;
+        } else {
+            let waited = (elapsed.as_secs_f64() / self.config.interval.as_secs_f64())
+                .min(f64::from(std::u32::MAX));
+            client.strikes = client.strikes.saturating_sub(waited as u32);
+        }
+
+        if client.strikes >= self.config.ban_after {
+            client.strikes = 0;
+            client.banned_until = Some(now + self.config.ban);
+            Verdict::Ban
+        } else if roll < f64::from(client.strikes) / f64::from(self.config.ban_after) {
+            Verdict::Penalty
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    /// Forget the clients that are not banned and would have no strikes left by now. A client
+    /// that isn't known has no strikes, so it doesn't change the result of `check`.
+    // Like the rate limiter, unless it's forced, it only runs once in a while to keep `check`
+    // cheap.
+    fn purge(&mut self, now: Instant, force: bool) {
+        let forgiven = self.config.interval * self.config.ban_after;
+        if !force && now.duration_since(self.last_purge.min(now)) < forgiven {
+            return;
+        }
+
+        self.clients.retain(|_, client| {
+            client.banned_until.map_or(false, |until| now < until)
+                || now.duration_since(client.last.min(now)) < forgiven
+        });
+        self.last_purge = now;
+    }
+
+    /// Make room for a new client if there are `max_clients` already. The forgiven clients are
+    /// purged first. If that's not enough, the half that stopped mattering the longest ago is
+    /// forgotten, so the banned clients are kept until their bans end if possible.
+    fn make_room(&mut self, now: Instant) {
+        if self.clients.len() < self.max_clients {
+            return;
+        }
+        self.purge(now, true);
+        if self.clients.len() < self.max_clients {
+            return;
+        }
+
+        let mut relevant_until: Vec<Instant> = self.clients.values()
+            .map(Client::relevant_until)
+            .collect();
+        let middle = relevant_until.len().saturating_sub(1) / 2;
+        relevant_until.sort_unstable();
+        let median = relevant_until[middle];
+        self.clients.retain(|_, client| client.relevant_until() > median);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MinIntervalConfig {
+        MinIntervalConfig {
+            interval: Duration::from_secs(2),
+            ban_after: 4,
+            ban: Duration::from_secs(60),
+            exempt: vec!["198.51.100.0/24".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_penalties() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut guard = MinInterval::new(config());
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // The sane clients are never dropped.
+        assert_eq!(guard.check(client, at(0), 0.0), Verdict::Allow);
+        assert_eq!(guard.check(client, at(2000), 0.0), Verdict::Allow);
+
+        // Each query that is too soon makes the next ones more likely to be dropped.
+        assert_eq!(guard.check(client, at(2100), 0.2), Verdict::Penalty);
+        assert_eq!(guard.check(client, at(2200), 0.3), Verdict::Penalty);
+        assert_eq!(guard.check(client, at(2300), 0.8), Verdict::Allow);
+
+        // Waiting takes the strikes away.
+        assert_eq!(guard.check(client, at(6300), 0.3), Verdict::Allow);
+        assert_eq!(guard.check(client, at(6400), 0.3), Verdict::Penalty);
+
+        // Too many get the client banned, until the ban ends.
+        assert_eq!(guard.check(client, at(6500), 0.99), Verdict::Allow);
+        assert_eq!(guard.check(client, at(6600), 0.99), Verdict::Ban);
+        assert_eq!(guard.check(client, at(60000), 0.0), Verdict::Banned);
+        assert_eq!(guard.check(client, at(66600), 0.0), Verdict::Allow);
+
+        // The addresses in an IPv6 /64 are the same client.
+        assert_eq!(guard.check("2001:db8::1".parse().unwrap(), at(0), 0.0), Verdict::Allow);
+        assert_eq!(guard.check("2001:db8::2".parse().unwrap(), at(100), 0.0), Verdict::Penalty);
+        assert_eq!(guard.check("2001:db8:0:1::1".parse().unwrap(), at(100), 0.0), Verdict::Allow);
+
+        // The exempted clients are never penalized.
+        let exempted: IpAddr = "::ffff:198.51.100.9".parse().unwrap();
+        for millis in 0..10 {
+            assert_eq!(guard.check(exempted, at(millis), 0.0), Verdict::Allow);
+        }
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut guard = MinInterval::new(config());
+        let start = Instant::now();
+        guard.check("192.0.2.1".parse().unwrap(), start, 0.0);
+        for _ in 0..5 {
+            guard.check("192.0.2.2".parse().unwrap(), start, 0.99);
+        }
+        // The banned client is kept until its ban ends.
+        guard.check("192.0.2.3".parse().unwrap(), start + Duration::from_secs(10), 0.0);
+        assert_eq!(guard.clients.len(), 2);
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let mut guard = MinInterval::new(config());
+        guard.max_clients = 4;
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        for _ in 0..5 {
+            guard.check("192.0.2.0".parse().unwrap(), at(0), 0.99);
+        }
+        for i in 1..4 {
+            guard.check(format!("192.0.2.{}", i).parse().unwrap(), at(i), 0.0);
+        }
+
+        // The least recently seen half is forgotten, but not the banned client.
+        guard.check("192.0.2.4".parse().unwrap(), at(4), 0.0);
+        assert_eq!(guard.clients.len(), 3);
+        let banned: IpAddr = "192.0.2.0".parse().unwrap();
+        assert_eq!(guard.check(banned, at(5), 0.0), Verdict::Banned);
+        assert!(!guard.clients.contains_key(&"192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_get_min_interval_config() {
+        let mut settings = config::Config::new();
+        assert!(get_min_interval_config(&settings).unwrap().is_none());
+
+        settings.set("min_interval", 2).unwrap();
+        let config = get_min_interval_config(&settings).unwrap().unwrap();
+        assert_eq!((config.interval, config.ban_after, config.ban),
+                   (Duration::from_secs(2), DEFAULT_BAN_AFTER, DEFAULT_BAN));
+
+        settings.set("min_interval_ban_after", 0).unwrap();
+        assert!(get_min_interval_config(&settings).is_err());
+        settings.set("min_interval_ban_after", 8).unwrap();
+        settings.set("min_interval_ban", -1).unwrap();
+        assert!(get_min_interval_config(&settings).is_err());
+        settings.set("min_interval", 0).unwrap();
+        assert!(get_min_interval_config(&settings).is_err());
+    }
+}
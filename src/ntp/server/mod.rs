@@ -4,8 +4,25 @@
 
 //! NTP server implementation.
 
+mod batch;
+mod clock;
 mod config;
+mod interleaved;
+mod leap;
+mod min_interval;
+mod mru;
+mod pcap;
+mod persist;
+mod pool;
+mod refclock;
+mod replay;
 mod server;
+mod symmetric;
+mod timestamping;
+pub mod upstream;
+mod uring;
+mod xdp;
 
 pub use self::server::start_ntp_server;
 pub use self::config::NtpServerConfig;
+pub use self::pcap::PcapConfig;
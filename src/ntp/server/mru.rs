@@ -0,0 +1,248 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The most recently used list of the clients, like the mrulist of ntpd. Each worker remembers
+//! the clients that it answered most recently, with the number of their queries and when they
+//! were first and last seen, and the metrics server lists them on `GET /mrulist`. Unlike ntpd,
+//! the list is never sent over NTP.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    opts, register_counter, register_int_counter, register_int_gauge, IntCounter, IntGauge,
+    __register_gauge,
+};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cidr::canonical_ip;
+
+lazy_static! {
+    static ref MRU_CLIENTS_GAUGE: IntGauge = register_int_gauge!(
+        "ntp_mru_clients",
+        "Number of clients in the most recently used lists of the workers"
+    )
+    .unwrap();
+    static ref MRU_EVICTED_COUNTER: IntCounter = register_int_counter!(
+        "ntp_mru_evicted_total",
+        "Number of clients dropped from the most recently used lists to make room for new ones"
+    )
+    .unwrap();
+}
+
+/// What is known about a client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MruEntry {
+    /// The address and port of the last query.
+    pub addr: SocketAddr,
+
+    /// The number of queries.
+    pub count: u64,
+
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+/// The most recently seen clients of a worker, up to a fixed number.
+pub struct MruTable {
+    /// The most clients that are remembered.
+    capacity: usize,
+
+    /// The number of queries recorded so far, which orders the clients.
+    queries: u64,
+
+    /// The clients by their addresses, with the query that they were last seen in.
+    clients: HashMap<IpAddr, (u64, MruEntry)>,
+
+    /// The addresses of the clients by the query that they were last seen in.
+    order: BTreeMap<u64, IpAddr>,
+}
+
+impl MruTable {
+    /// Create an empty table of up to `capacity` clients.
+    pub fn new(capacity: usize) -> MruTable {
+        MruTable {
+            capacity,
+            queries: 0,
+            clients: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Record a query from `addr`, which is one client whatever its port. The least recently
+    /// seen client is forgotten if the table is full.
+    pub fn record(&mut self, addr: SocketAddr, now: SystemTime) {
+        let key = canonical_ip(addr.ip());
+        self.queries += 1;
+        let query = self.queries;
+        if let Some((last_query, entry)) = self.clients.get_mut(&key) {
+            self.order.remove(last_query);
+            *last_query = query;
+            entry.addr = addr;
+            entry.count += 1;
+            entry.last_seen = now;
+            self.order.insert(query, key);
+            return;
+        }
+        self.insert(key, MruEntry { addr, count: 1, first_seen: now, last_seen: now });
+    }
+
+    /// Add a new client as the most recently seen one, and forget the least recently seen one if
+    /// the table is full.
+    fn insert(&mut self, key: IpAddr, entry: MruEntry) {
+        if self.clients.len() >= self.capacity {
+            let first = self.order.keys().next().cloned();
+            if let Some(oldest) = first.and_then(|query| self.order.remove(&query)) {
+                self.clients.remove(&oldest);
+                MRU_CLIENTS_GAUGE.dec();
+                MRU_EVICTED_COUNTER.inc();
+            }
+        }
+        self.clients.insert(key, (self.queries, entry));
+        self.order.insert(self.queries, key);
+        MRU_CLIENTS_GAUGE.inc();
+    }
+
+    /// Remember the clients in `entries`, most recently seen first, as they are, for example,
+    /// from before the server restarted.
+    pub fn restore(&mut self, entries: &[MruEntry]) {
+        for entry in entries.iter().rev() {
+            let key = canonical_ip(entry.addr.ip());
+            self.queries += 1;
+            if let Some((last_query, _)) = self.clients.remove(&key) {
+                self.order.remove(&last_query);
+                MRU_CLIENTS_GAUGE.dec();
+            }
+            self.insert(key, *entry);
+        }
+    }
+
+    /// Return the clients, most recently seen first.
+    pub fn entries(&self) -> Vec<MruEntry> {
+        self.order.values().rev().map(|key| self.clients[key].1).collect()
+    }
+}
+
+impl Drop for MruTable {
+    fn drop(&mut self) {
+        MRU_CLIENTS_GAUGE.sub(self.clients.len() as i64);
+    }
+}
+
+/// Merge the lists of the workers, since a client can be answered by more than one, and return
+/// the clients most recently seen first.
+pub fn merge(tables: &[Arc<Mutex<MruTable>>]) -> Vec<MruEntry> {
+    let mut merged: HashMap<IpAddr, MruEntry> = HashMap::new();
+    for table in tables {
+        for entry in table.lock().unwrap().entries() {
+            merged.entry(canonical_ip(entry.addr.ip()))
+                .and_modify(|merged| {
+                    if entry.last_seen > merged.last_seen {
+                        merged.addr = entry.addr;
+                        merged.last_seen = entry.last_seen;
+                    }
+                    merged.first_seen = merged.first_seen.min(entry.first_seen);
+                    merged.count += entry.count;
+                })
+                .or_insert(entry);
+        }
+    }
+    let mut entries: Vec<MruEntry> = merged.into_iter().map(|(_, entry)| entry).collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_seen));
+    entries
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() as f64 + f64::from(since_epoch.subsec_nanos()) * 1e-9
+}
+
+/// Return the list as text, with a line for each client and its times in Unix seconds.
+pub fn format(entries: &[MruEntry]) -> String {
+    let mut text = String::from("# address port count first_seen last_seen\n");
+    for entry in entries {
+        writeln!(text, "{} {} {} {:.3} {:.3}", entry.addr.ip(), entry.addr.port(), entry.count,
+                 unix_seconds(entry.first_seen), unix_seconds(entry.last_seen)).unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_mru_table() {
+        let mut table = MruTable::new(2);
+        let first: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let second: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+        table.record(first, at(1));
+        table.record(second, at(2));
+        // The IPv4-mapped address and the new port are the same client.
+        table.record("[::ffff:192.0.2.1]:124".parse().unwrap(), at(3));
+        let entries = table.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].addr, "[::ffff:192.0.2.1]:124".parse().unwrap());
+        assert_eq!(entries[0].count, 2);
+        assert_eq!((entries[0].first_seen, entries[0].last_seen), (at(1), at(3)));
+        assert_eq!(entries[1].addr, second);
+
+        // The least recently seen client is forgotten.
+        table.record("192.0.2.3:123".parse().unwrap(), at(4));
+        let entries = table.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].count, 2);
+        assert!(entries.iter().all(|entry| entry.addr != second));
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut table = MruTable::new(2);
+        let entry = |addr: &str, seen| MruEntry {
+            addr: addr.parse().unwrap(),
+            count: 5,
+            first_seen: at(1),
+            last_seen: at(seen),
+        };
+        table.restore(&[entry("192.0.2.3:123", 3), entry("192.0.2.2:123", 2),
+                        entry("192.0.2.1:123", 1)]);
+        assert_eq!(table.entries(), vec![entry("192.0.2.3:123", 3), entry("192.0.2.2:123", 2)]);
+
+        // The restored clients are counted on.
+        table.record("192.0.2.2:123".parse().unwrap(), at(4));
+        assert_eq!(table.entries()[0].count, 6);
+    }
+
+    #[test]
+    fn test_merge() {
+        let client: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let other: SocketAddr = "192.0.2.2:123".parse().unwrap();
+        let tables: Vec<Arc<Mutex<MruTable>>> = (0..2)
+            .map(|_| Arc::new(Mutex::new(MruTable::new(4))))
+            .collect();
+        tables[0].lock().unwrap().record(client, at(1));
+        tables[0].lock().unwrap().record(other, at(2));
+        tables[1].lock().unwrap().record("192.0.2.1:124".parse().unwrap(), at(3));
+
+        let entries = merge(&tables);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], MruEntry {
+            addr: "192.0.2.1:124".parse().unwrap(),
+            count: 2,
+            first_seen: at(1),
+            last_seen: at(3),
+        });
+        assert_eq!(format(&entries[1..]),
+                   "# address port count first_seen last_seen\n192.0.2.2 123 1 2.000 2.000\n");
+    }
+}
@@ -0,0 +1,213 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A capture of the NTP packets that the server receives and sends, for debugging. It's written
+//! in the pcap format with raw IP packets, so it can be opened in Wireshark or tcpdump next to a
+//! capture taken on the client. The IP and UDP headers are made up from the addresses, since the
+//! server only sees the payloads.
+
+use std::fs::File;
+use std::io::{Error, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::xdp::{checksum_add, checksum_finish};
+
+/// The magic number of the pcap files with timestamps in microseconds.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// The link type of the raw IPv4 and IPv6 packets.
+const LINKTYPE_RAW: u32 = 101;
+
+/// The most bytes of each packet that are captured, which is more than an NTP packet can have.
+const SNAPLEN: u32 = 65535;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+const HOP_LIMIT: u8 = 64;
+
+/// The packet capture that is asked for on the command line.
+#[derive(Clone, Debug)]
+pub struct PcapConfig {
+    /// The file that the packets are written to. It's replaced if it exists.
+    pub path: String,
+
+    /// The most packets that are written, both received and sent.
+    pub max_packets: u64,
+
+    /// How long the packets are captured for after the server starts.
+    pub duration: Duration,
+}
+
+/// An open capture, which stops when it has enough packets or it's been long enough.
+pub struct Capture {
+    /// The file, until the capture is over.
+    file: Option<File>,
+
+    /// The number of packets that can still be written.
+    packets_left: u64,
+
+    /// When the capture is over.
+    deadline: Instant,
+}
+
+impl Capture {
+    /// Create the file of the capture and write the header.
+    pub fn create(config: &PcapConfig) -> Result<Capture, Error> {
+        let mut file = File::create(&config.path)?;
+        let mut header = Vec::with_capacity(24);
+        header.extend(&PCAP_MAGIC.to_le_bytes());
+        header.extend(&2u16.to_le_bytes());
+        header.extend(&4u16.to_le_bytes());
+        // The timestamps are in UTC, and their accuracy is not known.
+        header.extend(&0u32.to_le_bytes());
+        header.extend(&0u32.to_le_bytes());
+        header.extend(&SNAPLEN.to_le_bytes());
+        header.extend(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Capture {
+            file: Some(file),
+            packets_left: config.max_packets,
+            deadline: Instant::now() + config.duration,
+        })
+    }
+
+    /// Write a UDP packet with `payload` from `src` to `dst` at `time`, unless the capture is
+    /// over. Return true if this call ended it. Each packet is written right away, so that the
+    /// file can be read while the server is running.
+    pub fn write(
+        &mut self,
+        time: SystemTime,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> Result<bool, Error> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        if self.packets_left == 0 || Instant::now() >= self.deadline {
+            self.file = None;
+            return Ok(true);
+        }
+        let result = file.write_all(&record(time, src, dst, payload));
+        self.packets_left -= 1;
+        if result.is_err() {
+            self.file = None;
+        }
+        result.map(|()| false)
+    }
+}
+
+/// Return the pcap record of a UDP packet with `payload` from `src` to `dst` at `time`.
+fn record(time: SystemTime, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let packet = ip_packet(src, dst, payload);
+    // The clock can be before the Unix epoch only if it's very wrong.
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let mut record = Vec::with_capacity(16 + packet.len());
+    record.extend(&(since_epoch.as_secs() as u32).to_le_bytes());
+    record.extend(&since_epoch.subsec_micros().to_le_bytes());
+    record.extend(&(packet.len() as u32).to_le_bytes());
+    record.extend(&(packet.len() as u32).to_le_bytes());
+    record.extend(packet);
+    record
+}
+
+/// Return the IP packet of a UDP packet with `payload` from `src` to `dst`. If only one of the
+/// addresses is IPv6, which happens on the dual-stack sockets, the other one is IPv4-mapped.
+fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + udp_len);
+    let mut sum = 0;
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend(&[0x45, 0]);
+            packet.extend(&((IPV4_HEADER_LEN + udp_len) as u16).to_be_bytes());
+            packet.extend(&[0, 0, 0x40, 0, HOP_LIMIT, IPPROTO_UDP, 0, 0]);
+            packet.extend(&src.octets());
+            packet.extend(&dst.octets());
+            let header_checksum = checksum_finish(checksum_add(0, &packet));
+            packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+            sum = checksum_add(sum, &packet[12..20]);
+        },
+        (src, dst) => {
+            let to_v6 = |ip| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend(&[0x60, 0, 0, 0]);
+            packet.extend(&(udp_len as u16).to_be_bytes());
+            packet.extend(&[IPPROTO_UDP, HOP_LIMIT]);
+            packet.extend(&to_v6(src).octets());
+            packet.extend(&to_v6(dst).octets());
+            sum = checksum_add(sum, &packet[8..40]);
+        },
+    }
+
+    let udp_offset = packet.len();
+    packet.extend(&src.port().to_be_bytes());
+    packet.extend(&dst.port().to_be_bytes());
+    packet.extend(&(udp_len as u16).to_be_bytes());
+    packet.extend(&[0, 0]);
+    packet.extend(payload);
+    sum = checksum_add(sum, &[0, IPPROTO_UDP]);
+    sum = checksum_add(sum, &(udp_len as u16).to_be_bytes());
+    let checksum = match checksum_finish(checksum_add(sum, &packet[udp_offset..])) {
+        // A zero checksum means that there is none.
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    packet[udp_offset + 6..udp_offset + 8].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv4_packet() {
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.2:123".parse().unwrap();
+        let packet = ip_packet(client, server, &[0x23; 48]);
+        assert_eq!(packet.len(), 20 + 8 + 48);
+        assert_eq!(&packet[2..4], &[0, 76]);
+        assert_eq!(&packet[12..16], &[192, 0, 2, 1]);
+        assert_eq!(&packet[20..24], &[0x9c, 0x40, 0, 123]);
+        // The checksums of the packets with the right checksums are zero.
+        assert_eq!(checksum_finish(checksum_add(0, &packet[..20])), 0);
+        let pseudo_header = [&packet[12..20], &[0, IPPROTO_UDP, 0, 56]].concat();
+        let sum = checksum_add(checksum_add(0, &pseudo_header), &packet[20..]);
+        assert_eq!(checksum_finish(sum), 0);
+    }
+
+    #[test]
+    fn test_ipv6_packet() {
+        let client: SocketAddr = "[::ffff:192.0.2.1]:40000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.2:123".parse().unwrap();
+        let packet = ip_packet(server, client, &[0x24; 48]);
+        assert_eq!(packet.len(), 40 + 8 + 48);
+        assert_eq!(packet[0] >> 4, 6);
+        // The IPv4 address of the server is mapped.
+        let mapped: std::net::Ipv6Addr = "::ffff:192.0.2.2".parse().unwrap();
+        assert_eq!(&packet[8..24], &mapped.octets());
+        let pseudo_header = [&packet[8..40], &[0, IPPROTO_UDP, 0, 56]].concat();
+        let sum = checksum_add(checksum_add(0, &pseudo_header), &packet[40..]);
+        assert_eq!(checksum_finish(sum), 0);
+    }
+
+    #[test]
+    fn test_record() {
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.2:123".parse().unwrap();
+        let time = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 250_000_000);
+        let record = record(time, client, server, &[0x23; 48]);
+        assert_eq!(&record[..4], &1_000_000_000u32.to_le_bytes());
+        assert_eq!(&record[4..8], &250_000u32.to_le_bytes());
+        assert_eq!(&record[8..12], &76u32.to_le_bytes());
+        assert_eq!(record.len(), 16 + 76);
+    }
+}
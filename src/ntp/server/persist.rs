@@ -0,0 +1,217 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The state that the server keeps across restarts: the totals of the counters, the most
+//! recently used list of the clients, and the clients that have used up some of their rate
+//! limit. It's written to a file once in a while and when the server gets SIGTERM, and read back
+//! when it starts, so that a deploy doesn't reset the long-term graphs or let the abusive clients
+//! off their limit.
+
+use serde_json::{json, Map, Value};
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::mru::MruEntry;
+use crate::metrics::CounterSample;
+
+/// Where and how often the state is saved.
+#[derive(Clone, Debug)]
+pub struct PersistConfig {
+    /// The file that the state is written to. It's replaced all at once.
+    pub path: String,
+
+    /// How long the server waits between the snapshots.
+    pub interval: Duration,
+}
+
+/// A client whose rate limit bucket is not full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimitedClient {
+    pub addr: IpAddr,
+
+    /// The tokens left in the bucket when the snapshot was taken.
+    pub tokens: f64,
+}
+
+/// The state of the server at some time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// When it was taken.
+    pub time: SystemTime,
+
+    pub counters: Vec<CounterSample>,
+
+    /// The clients, most recently seen first.
+    pub clients: Vec<MruEntry>,
+
+    pub limited: Vec<LimitedClient>,
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+fn from_unix_seconds(value: &Value) -> Result<SystemTime, Error> {
+    match value.as_f64() {
+        Some(seconds) if seconds >= 0.0 && seconds.is_finite() => {
+            Ok(UNIX_EPOCH + Duration::from_secs_f64(seconds))
+        },
+        _ => Err(invalid("a time is not a number of seconds")),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid state file: {}", message))
+}
+
+fn field<'a>(object: &'a Value, name: &str) -> Result<&'a Value, Error> {
+    object.get(name).ok_or_else(|| invalid(&format!("{} is missing", name)))
+}
+
+fn string_field<'a>(object: &'a Value, name: &str) -> Result<&'a str, Error> {
+    field(object, name)?.as_str().ok_or_else(|| invalid(&format!("{} is not a string", name)))
+}
+
+fn number_field(object: &Value, name: &str) -> Result<f64, Error> {
+    field(object, name)?.as_f64().ok_or_else(|| invalid(&format!("{} is not a number", name)))
+}
+
+fn array_field<'a>(object: &'a Value, name: &str) -> Result<&'a Vec<Value>, Error> {
+    field(object, name)?.as_array().ok_or_else(|| invalid(&format!("{} is not an array", name)))
+}
+
+impl Snapshot {
+    /// Return the snapshot as JSON.
+    pub fn to_json(&self) -> String {
+        let counters: Vec<Value> = self.counters.iter()
+            .map(|counter| {
+                let labels: Map<String, Value> = counter.labels.iter()
+                    .map(|(name, value)| (name.clone(), Value::from(value.clone())))
+                    .collect();
+                json!({
+                    "name": counter.name,
+                    "help": counter.help,
+                    "labels": labels,
+                    "value": counter.value,
+                })
+            })
+            .collect();
+        let clients: Vec<Value> = self.clients.iter()
+            .map(|client| json!({
+                "address": client.addr.to_string(),
+                "count": client.count,
+                "first_seen": unix_seconds(client.first_seen),
+                "last_seen": unix_seconds(client.last_seen),
+            }))
+            .collect();
+        let limited: Vec<Value> = self.limited.iter()
+            .map(|client| json!({ "address": client.addr.to_string(), "tokens": client.tokens }))
+            .collect();
+        json!({
+            "time": unix_seconds(self.time),
+            "counters": counters,
+            "clients": clients,
+            "limited": limited,
+        }).to_string()
+    }
+
+    /// Parse a snapshot from the JSON that `to_json` returned.
+    pub fn from_json(text: &str) -> Result<Snapshot, Error> {
+        let value: Value = serde_json::from_str(text)
+            .map_err(|error| invalid(&error.to_string()))?;
+
+        let mut counters = Vec::new();
+        for counter in array_field(&value, "counters")? {
+            let labels = field(counter, "labels")?.as_object()
+                .ok_or_else(|| invalid("labels is not an object"))?;
+            let mut sorted = Vec::new();
+            for (name, value) in labels {
+                let value = value.as_str().ok_or_else(|| invalid("a label is not a string"))?;
+                sorted.push((name.clone(), value.to_owned()));
+            }
+            sorted.sort();
+            counters.push(CounterSample {
+                name: string_field(counter, "name")?.to_owned(),
+                help: string_field(counter, "help")?.to_owned(),
+                labels: sorted,
+                value: number_field(counter, "value")?,
+            });
+        }
+
+        let mut clients = Vec::new();
+        for client in array_field(&value, "clients")? {
+            let addr: SocketAddr = string_field(client, "address")?.parse()
+                .map_err(|_| invalid("a client address is not valid"))?;
+            clients.push(MruEntry {
+                addr,
+                count: field(client, "count")?.as_u64()
+                    .ok_or_else(|| invalid("count is not a number"))?,
+                first_seen: from_unix_seconds(field(client, "first_seen")?)?,
+                last_seen: from_unix_seconds(field(client, "last_seen")?)?,
+            });
+        }
+
+        let mut limited = Vec::new();
+        for client in array_field(&value, "limited")? {
+            let addr: IpAddr = string_field(client, "address")?.parse()
+                .map_err(|_| invalid("a limited address is not valid"))?;
+            limited.push(LimitedClient { addr, tokens: number_field(client, "tokens")? });
+        }
+
+        let time = from_unix_seconds(field(&value, "time")?)?;
+        Ok(Snapshot { time, counters, clients, limited })
+    }
+}
+
+/// Write `snapshot` to `path`. It's written to a temporary file first and then renamed, so that
+/// the file is never left half written.
+pub fn write(path: &str, snapshot: &Snapshot) -> Result<(), Error> {
+    let temporary = format!("{}.tmp", path);
+    fs::write(&temporary, snapshot.to_json())?;
+    fs::rename(&temporary, path)
+}
+
+/// Read the snapshot at `path`, or return `None` if there is none yet.
+pub fn read(path: &str) -> Result<Option<Snapshot>, Error> {
+    match fs::read_to_string(path) {
+        Ok(text) => Snapshot::from_json(&text).map(Some),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_json() {
+        let snapshot = Snapshot {
+            time: at(1_600_000_000),
+            counters: vec![CounterSample {
+                name: String::from("ntp_family_queries_total"),
+                help: String::from("Number of queries"),
+                labels: vec![(String::from("family"), String::from("ipv6"))],
+                value: 42.0,
+            }],
+            clients: vec![MruEntry {
+                addr: "[2001:db8::1]:123".parse().unwrap(),
+                count: 7,
+                first_seen: at(1_599_999_000),
+                last_seen: at(1_599_999_990),
+            }],
+            limited: vec![LimitedClient { addr: "192.0.2.1".parse().unwrap(), tokens: 0.5 }],
+        };
+        assert_eq!(Snapshot::from_json(&snapshot.to_json()).unwrap(), snapshot);
+        assert!(Snapshot::from_json("{}").is_err());
+        assert!(Snapshot::from_json("not json").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A fixed pool of threads that jobs are handed to through a bounded queue. The NTP workers give
+//! it the NTS requests, so that a burst of them doesn't hold up the plain NTP requests behind
+//! their crypto.
+
+use crossbeam::channel::{bounded, Sender, TrySendError};
+
+use std::sync::Arc;
+use std::thread;
+
+/// The sending end of the queue of the pool. The threads stop when all of the clones are dropped.
+pub struct Pool<T> {
+    sender: Sender<T>,
+}
+
+impl<T> Clone for Pool<T> {
+    fn clone(&self) -> Pool<T> {
+        Pool { sender: self.sender.clone() }
+    }
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Start `threads` threads named `name` that run `handler` on the jobs, with up to `queue`
+    /// jobs waiting for them.
+    pub fn new<F>(threads: usize, queue: usize, name: &str, handler: F)
+        -> Result<Pool<T>, std::io::Error>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = bounded::<T>(queue);
+        let handler = Arc::new(handler);
+        for index in 0..threads {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, index))
+                .spawn(move || {
+                    for job in receiver {
+                        handler(job);
+                    }
+                })?;
+        }
+        Ok(Pool { sender })
+    }
+
+    /// Queue `job`, or give it back if the queue is full.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        self.sender.try_send(job).map_err(|error| match error {
+            TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam::channel::unbounded;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_pool() {
+        let (done, results) = unbounded();
+        let pool = Pool::new(2, 4, "test", move |job: u32| done.send(job * 2).unwrap()).unwrap();
+        for job in 0..4 {
+            pool.submit(job).unwrap();
+        }
+        let mut doubled: Vec<u32> = results.iter().take(4).collect();
+        doubled.sort_unstable();
+        assert_eq!(doubled, vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn test_full_queue() {
+        // The only thread is held up by the first job until the queue has been filled.
+        let (started, wait_start) = unbounded();
+        let barrier = Arc::new(Barrier::new(2));
+        let held = barrier.clone();
+        let pool = Pool::new(1, 1, "test", move |hold: bool| {
+            if hold {
+                started.send(()).unwrap();
+                held.wait();
+            }
+        }).unwrap();
+        pool.submit(true).unwrap();
+        wait_start.recv().unwrap();
+        pool.submit(false).unwrap();
+        assert_eq!(pool.submit(false), Err(false));
+        barrier.wait();
+    }
+}
@@ -0,0 +1,315 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Reference clocks, which make the server a stratum 1 server. A PPS device gives the system
+//! time of the pulse at the beginning of each second, and gpsd writes the time of its receiver to
+//! a shared memory segment in the format of the SHM driver of ntpd, which chrony and the other
+//! programs that feed ntpd use too.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::time::SystemTime;
+
+use crate::ntp::protocol::LeapState;
+
+/// The key of the shared memory segment of the unit 0. The other units follow it.
+const SHM_KEY: libc::key_t = 0x4e54_5030;
+
+/// The oldest SHM sample that is used, in seconds. A writer that has stopped can leave its last
+/// sample valid in the segment.
+const SHM_MAX_AGE: f64 = 4.0;
+
+/// Tells the PPS device to return the last pulse without waiting for the next one.
+const PPS_TIME_INVALID: u32 = 0x01;
+
+/// The time in the PPS API of the kernel.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsKtime {
+    sec: i64,
+    nsec: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsKinfo {
+    assert_sequence: u32,
+    clear_sequence: u32,
+    assert_tu: PpsKtime,
+    clear_tu: PpsKtime,
+    current_mode: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsFdata {
+    info: PpsKinfo,
+    timeout: PpsKtime,
+}
+
+/// The PPS_FETCH ioctl, which is `_IOWR('p', 0xa4, struct pps_fdata *)`. The size is the one of
+/// a pointer, not of the structure.
+const PPS_FETCH: libc::c_ulong = (3 << 30)
+    | ((mem::size_of::<*mut PpsFdata>() as libc::c_ulong) << 16)
+    | ((b'p' as libc::c_ulong) << 8)
+    | 0xa4;
+
+/// The shared memory segment of the SHM driver.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ShmTime {
+    /// In mode 1, the sample is only used if the count is the same before and after reading it.
+    mode: libc::c_int,
+    count: libc::c_int,
+    clock_sec: libc::time_t,
+    clock_usec: libc::c_int,
+    receive_sec: libc::time_t,
+    receive_usec: libc::c_int,
+    leap: libc::c_int,
+    precision: libc::c_int,
+    nsamples: libc::c_int,
+    valid: libc::c_int,
+    clock_nsec: libc::c_uint,
+    receive_nsec: libc::c_uint,
+    dummy: [libc::c_int; 8],
+}
+
+/// Where the time of a reference clock comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RefclockSource {
+    /// A PPS device, such as /dev/pps0.
+    Pps(String),
+    /// A unit of the SHM driver, which gpsd writes to. The segment is created with the
+    /// permissions `perm` if the writer hasn't created it yet.
+    Shm { unit: u32, perm: u32 },
+}
+
+/// Return the permissions that the SHM segment of `unit` is created with by default. Like in
+/// ntpd, only root can write to the units 0 and 1, and anyone to the others.
+pub fn default_shm_perm(unit: u32) -> u32 {
+    if unit < 2 { 0o600 } else { 0o666 }
+}
+
+/// A reference clock in the configuration.
+#[derive(Clone, Debug)]
+pub struct RefclockConfig {
+    /// The name in the metrics.
+    pub name: String,
+    pub source: RefclockSource,
+    /// The seconds added to the offset of the clock, to make up for the delay of its cable or
+    /// receiver.
+    pub offset: f64,
+    /// The reference ID that is advertised when the clock is selected.
+    pub refid: u32,
+}
+
+/// A measurement of a reference clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RefclockSample {
+    /// How much the reference clock is ahead of the system clock in seconds.
+    pub offset: f64,
+    pub leap: LeapState,
+}
+
+/// Return the offset of a pulse at `nsec` nanoseconds into a second of the system clock. The
+/// pulse marks the closest whole second, so the system clock has to be within half a second
+/// already, for example, from the upstream servers.
+fn pps_offset(nsec: i32) -> f64 {
+    if nsec < 500_000_000 {
+        -f64::from(nsec) / 1e9
+    } else {
+        f64::from(1_000_000_000 - nsec) / 1e9
+    }
+}
+
+/// Return a time in the SHM segment in seconds. The nanoseconds are only set by the newer
+/// writers. They match the microseconds if they are set.
+fn shm_seconds(sec: libc::time_t, usec: libc::c_int, nsec: libc::c_uint) -> f64 {
+    let nanos = if nsec / 1000 == usec as libc::c_uint {
+        f64::from(nsec)
+    } else {
+        f64::from(usec) * 1e3
+    };
+    sec as f64 + nanos / 1e9
+}
+
+/// Return whether the sample in a copy of the SHM segment was received more than `SHM_MAX_AGE`
+/// before `now`, which is in seconds since the Unix epoch.
+fn shm_is_stale(shm: &ShmTime, now: f64) -> bool {
+    now - shm_seconds(shm.receive_sec, shm.receive_usec, shm.receive_nsec) > SHM_MAX_AGE
+}
+
+/// Return the sample in a copy of the SHM segment.
+fn shm_sample(shm: &ShmTime) -> RefclockSample {
+    let clock = shm_seconds(shm.clock_sec, shm.clock_usec, shm.clock_nsec);
+    let receive = shm_seconds(shm.receive_sec, shm.receive_usec, shm.receive_nsec);
+    let leap = match shm.leap {
+        0 => LeapState::NoLeap,
+        1 => LeapState::Positive,
+        2 => LeapState::Negative,
+        _ => LeapState::Unknown,
+    };
+    RefclockSample { offset: clock - receive, leap }
+}
+
+/// An opened reference clock.
+pub enum Refclock {
+    Pps {
+        device: File,
+        /// The sequence number of the last pulse that was read.
+        last_sequence: Option<u32>,
+    },
+    Shm(*mut ShmTime),
+}
+
+impl Refclock {
+    /// Open the reference clock. The SHM segment is created if the writer hasn't yet.
+    pub fn open(source: &RefclockSource) -> Result<Refclock, Error> {
+        match source {
+            RefclockSource::Pps(path) => Ok(Refclock::Pps {
+                device: File::open(path)?,
+                last_sequence: None,
+            }),
+            RefclockSource::Shm { unit, perm } => {
+                let key = SHM_KEY + *unit as libc::key_t;
+                let flags = libc::IPC_CREAT | *perm as libc::c_int;
+                let id = unsafe { libc::shmget(key, mem::size_of::<ShmTime>(), flags) };
+                if id < 0 {
+                    return Err(Error::last_os_error());
+                }
+                let segment = unsafe { libc::shmat(id, ptr::null(), 0) };
+                if segment as isize == -1 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(Refclock::Shm(segment as *mut ShmTime))
+            },
+        }
+    }
+
+    /// Return the new sample of the clock since the last call, if there is one.
+    pub fn read(&mut self) -> Result<Option<RefclockSample>, Error> {
+        match self {
+            Refclock::Pps { device, last_sequence } => {
+                let mut data = PpsFdata::default();
+                data.timeout.flags = PPS_TIME_INVALID;
+                let result = unsafe {
+                    libc::ioctl(device.as_raw_fd(), PPS_FETCH as _, &mut data as *mut PpsFdata)
+                };
+                if result < 0 {
+                    return Err(Error::last_os_error());
+                }
+                let info = data.info;
+                if *last_sequence == Some(info.assert_sequence) || info.assert_sequence == 0 {
+                    return Ok(None);
+                }
+                *last_sequence = Some(info.assert_sequence);
+                Ok(Some(RefclockSample {
+                    offset: pps_offset(info.assert_tu.nsec),
+                    leap: LeapState::NoLeap,
+                }))
+            },
+            Refclock::Shm(segment) => {
+                let segment = *segment;
+                // The writer can change the segment at any time, so it's copied first.
+                let (count, shm) = unsafe {
+                    let count = ptr::read_volatile(&(*segment).count);
+                    (count, ptr::read_volatile(segment))
+                };
+                if shm.valid == 0 {
+                    return Ok(None);
+                }
+                let changed = unsafe { ptr::read_volatile(&(*segment).count) } != count;
+                unsafe { ptr::write_volatile(&mut (*segment).valid, 0) };
+                // Safe absent time machines
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                if shm_is_stale(&shm, now.as_secs_f64()) {
+                    return Ok(None);
+                }
+                match shm.mode {
+                    0 => Ok(Some(shm_sample(&shm))),
+                    1 if !changed => Ok(Some(shm_sample(&shm))),
+                    1 => Ok(None),
+                    mode => Err(Error::new(ErrorKind::InvalidData,
+                                           format!("unknown SHM mode {}", mode))),
+                }
+            },
+        }
+    }
+}
+
+impl Drop for Refclock {
+    fn drop(&mut self) {
+        if let Refclock::Shm(segment) = self {
+            unsafe { libc::shmdt(*segment as *const libc::c_void) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shm_time() -> ShmTime {
+        ShmTime {
+            mode: 1,
+            count: 0,
+            clock_sec: 1000,
+            clock_usec: 0,
+            receive_sec: 999,
+            receive_usec: 750_000,
+            leap: 0,
+            precision: -20,
+            nsamples: 3,
+            valid: 1,
+            clock_nsec: 0,
+            receive_nsec: 750_000_000,
+            dummy: [0; 8],
+        }
+    }
+
+    #[test]
+    fn test_pps_offset() {
+        assert_eq!(pps_offset(0), 0.0);
+        assert_eq!(pps_offset(250_000_000), -0.25);
+        assert_eq!(pps_offset(999_000_000), 0.001);
+    }
+
+    #[test]
+    fn test_shm_sample() {
+        let mut shm = shm_time();
+        assert_eq!(shm_sample(&shm), RefclockSample { offset: 0.25, leap: LeapState::NoLeap });
+
+        // The old writers only set the microseconds.
+        shm.receive_nsec = 0;
+        shm.receive_usec = 500_000;
+        shm.leap = 1;
+        assert_eq!(shm_sample(&shm), RefclockSample { offset: 0.5, leap: LeapState::Positive });
+    }
+
+    #[test]
+    fn test_shm_is_stale() {
+        let shm = shm_time();
+        assert!(!shm_is_stale(&shm, 1000.0));
+        assert!(!shm_is_stale(&shm, 999.75 + SHM_MAX_AGE));
+        assert!(shm_is_stale(&shm, 1004.0));
+    }
+
+    #[test]
+    fn test_default_shm_perm() {
+        assert_eq!(default_shm_perm(0), 0o600);
+        assert_eq!(default_shm_perm(1), 0o600);
+        assert_eq!(default_shm_perm(2), 0o666);
+    }
+
+    #[test]
+    fn test_pps_fetch() {
+        if mem::size_of::<*mut PpsFdata>() == 8 {
+            assert_eq!(PPS_FETCH, 0xc008_70a4);
+        }
+    }
+}
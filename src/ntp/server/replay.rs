@@ -0,0 +1,112 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Detection of replayed NTS requests. The unique identifiers of the last authenticated requests
+//! of each client are remembered, and a request with an identifier that was already seen is a
+//! replay of an earlier one.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+use crate::cidr::canonical_ip;
+
+/// The identifiers seen from a client.
+struct Client {
+    /// When the client was last seen, in the order of the requests.
+    last_seen: u64,
+
+    /// The identifiers of the last requests, oldest first.
+    identifiers: VecDeque<Vec<u8>>,
+}
+
+/// The identifiers seen from the recent clients.
+pub struct ReplayCache {
+    /// The maximum number of clients that are remembered.
+    max_clients: usize,
+
+    /// The number of identifiers that are remembered for each client.
+    identifiers_per_client: usize,
+
+    /// The number of requests checked so far.
+    requests: u64,
+
+    /// The clients indexed by their addresses.
+    clients: HashMap<IpAddr, Client>,
+}
+
+impl ReplayCache {
+    /// Create an empty cache which remembers the last `identifiers_per_client` identifiers of up
+    /// to `max_clients` clients.
+    pub fn new(max_clients: usize, identifiers_per_client: usize) -> ReplayCache {
+        ReplayCache {
+            max_clients,
+            identifiers_per_client,
+            requests: 0,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Return whether an authenticated request from `addr` with the unique identifier is a
+    /// replay, and remember the identifier if it's not.
+    pub fn is_replay(&mut self, addr: IpAddr, identifier: &[u8]) -> bool {
+        let addr = canonical_ip(addr);
+        self.requests += 1;
+        if self.clients.len() >= self.max_clients && !self.clients.contains_key(&addr) {
+            self.evict();
+        }
+
+        let client = self.clients.entry(addr).or_insert_with(|| Client {
+            last_seen: 0,
+            identifiers: VecDeque::new(),
+        });
+        client.last_seen = self.requests;
+        if client.identifiers.iter().any(|seen| seen.as_slice() == identifier) {
+            return true;
+        }
+        if client.identifiers.len() >= self.identifiers_per_client {
+            client.identifiers.pop_front();
+        }
+        client.identifiers.push_back(identifier.to_vec());
+        false
+    }
+
+    /// Forget the least recently seen half of the clients, so that the cost of finding them is
+    /// spread over the next clients.
+    fn evict(&mut self) {
+        let mut last_seen: Vec<u64> = self.clients.values()
+            .map(|client| client.last_seen)
+            .collect();
+        let middle = last_seen.len().saturating_sub(1) / 2;
+        last_seen.sort_unstable();
+        let median = last_seen[middle];
+        self.clients.retain(|_, client| client.last_seen > median);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_cache() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let mut cache = ReplayCache::new(2, 2);
+        assert!(!cache.is_replay(client, b"one"));
+        assert!(cache.is_replay(client, b"one"));
+        // IPv4-mapped IPv6 addresses are the same clients.
+        assert!(cache.is_replay("::ffff:192.0.2.1".parse().unwrap(), b"one"));
+        assert!(!cache.is_replay(other, b"one"));
+
+        // Only the last identifiers of each client are remembered.
+        assert!(!cache.is_replay(client, b"two"));
+        assert!(!cache.is_replay(client, b"three"));
+        assert!(!cache.is_replay(client, b"one"));
+
+        // The least recently seen client is forgotten when the cache is full.
+        assert!(!cache.is_replay("192.0.2.3".parse().unwrap(), b"one"));
+        assert!(cache.is_replay(client, b"one"));
+        assert!(!cache.is_replay(other, b"one"));
+    }
+}
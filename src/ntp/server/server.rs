@@ -1,48 +1,136 @@
 use crate::cfsock;
-use super::config::NtpServerConfig;
-use crate::cookie::{eat_cookie, get_keyid, make_cookie, NTSKeys, COOKIE_SIZE};
+use super::batch::{self, Outgoing};
+use super::clock::{ClockSource, SystemClock};
+use super::config::{BroadcastConfig, NtpServerConfig, UpstreamServer};
+use super::interleaved::InterleavedLog;
+use super::leap::{LeapEvent, LeapSmear, LeapTable, SmearConfig};
+use super::min_interval::{MinInterval, MinIntervalConfig, Verdict};
+use super::mru::{self, MruTable};
+use super::pcap::Capture;
+use super::persist::{self, LimitedClient, PersistConfig, Snapshot};
+use super::pool::Pool;
+use super::refclock::{Refclock, RefclockConfig};
+use super::replay::ReplayCache;
+use super::symmetric::{MacCheck, SymmetricKeys};
+use super::timestamping::{self, PacketInfo, Timestamping};
+use super::upstream::{self, Discipline, Filter, Sample};
+use super::uring::Uring;
+use super::xdp::{self, Xsk};
+use crate::admin::{self, ClientList};
+use crate::aes_gcm_siv::Aes128GcmSivAead;
+use crate::cidr::canonical_ip;
+use crate::cookie::{cookie_size, eat_cookie, get_keyid, make_cookie, NTSKeys};
 use crate::metrics;
-use crate::key_rotator::{periodic_rotate, KeyRotator};
+use crate::key_rotator::{
+    periodic_reload_master_key, periodic_reload_master_key_aws, periodic_reload_master_key_file,
+    periodic_rotate, KeyRotator,
+};
+use crate::nts_ke::client::{run_nts_ke_client, NtsKeResult};
+use crate::nts_ke::records::KnownAeadAlgorithm;
+use crate::rate_limiter::{RateLimitConfig, RateLimiter, TokenBucket};
+use crate::signal;
+use crate::sub_command::client::ClientConfig;
 
 use lazy_static::lazy_static;
-use prometheus::{opts, register_counter, register_int_counter, IntCounter};
-use slog::{error, info};
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, register_counter, register_gauge,
+    register_gauge_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Gauge, GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    __register_counter_vec, __register_gauge, __register_gauge_vec,
+};
+use rand::Rng;
+use slog::{debug, error, info, warn};
 
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
     SocketAddr,
-    ToSocketAddrs, UdpSocket,
+    ToSocketAddrs,
+    UdpSocket,
 };
 use std::os::unix::io::AsRawFd;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec;
 
 use crossbeam::sync::WaitGroup;
-use libc::{in6_pktinfo, in_pktinfo};
 /// Miscreant calls Aes128SivAead what IANA calls AEAD_AES_SIV_CMAC_256
-use miscreant::aead::Aead;
 use miscreant::aead::Aes128SivAead;
-use nix::sys::socket::{
-    recvmsg, sendmsg, setsockopt, sockopt, CmsgSpace, ControlMessage, MsgFlags,
-};
-use nix::sys::time::{TimeVal, TimeValLike};
-use nix::sys::uio::IoVec;
+use nix::sys::socket::{setsockopt, sockopt, SockAddr};
+use ring::digest;
 
+use crate::ntp::client::{run_nts_ntp_client, run_ntp_client, NtpResult};
 use crate::ntp::protocol;
 use crate::ntp::protocol::{
     extract_extension, has_extension, is_nts_packet, parse_ntp_packet, parse_nts_packet,
     serialize_header, serialize_ntp_packet, serialize_nts_packet, LeapState, LeapState::*,
     NtpExtension, NtpExtensionType::NTSCookie, NtpExtensionType::UniqueIdentifier, NtpPacket,
-    NtpPacketHeader, NtsPacket, PacketMode, PHI, UNIX_OFFSET,
+    NtpPacketHeader, NtpV5PacketHeader, NtsAead, NtsPacket, PacketMode, PHI, UNIX_OFFSET,
 };
 
 const BUF_SIZE: usize = 1280; // Anything larger might fragment.
 const TWO_POW_32: f64 = 4294967296.0;
 const TWO_POW_16: f64 = 65536.0;
 
+/// How often the reference clocks are read.
+const REFCLOCK_POLL: Duration = Duration::from_secs(1);
+
+/// The dispersion of a sample of a reference clock in seconds, which covers the precision of the
+/// clock.
+const REFCLOCK_DISPERSION: f64 = 1e-6;
+
+/// How often the server checks whether it has received a signal.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the leap file is read again.
+const LEAP_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often a client over the rate limit can get a RATE kiss of death, in kisses per second.
+const RATE_KOD_RATE: f64 = 0.1;
+
+/// The reference id of the kiss of death for the clients that have to slow down.
+const KOD_RATE: u32 = 0x5241_5445; // RATE
+
+/// The reference id of the kiss of death for the NTS clients whose cookies cannot be used.
+const KOD_NTSN: u32 = 0x4e54_534e; // NTSN
+
+/// Why an NTS request got an NTS NAK, which labels the NAK counter. The cookie cannot be parsed,
+/// the key that it was made under is gone, it cannot be decrypted with that key, or the request
+/// doesn't authenticate under the keys in the cookie.
+const NAK_MALFORMED_COOKIE: &str = "malformed_cookie";
+const NAK_MISSING_KEY: &str = "missing_key";
+const NAK_UNDECRYPTABLE_COOKIE: &str = "undecryptable_cookie";
+const NAK_UNAUTHENTICATED: &str = "unauthenticated";
+
+/// The most NTS requests that wait for the NTS pool. The ones over that are dropped.
+const NTS_QUEUE_SIZE: usize = 4096;
+
+/// How many mangled packets are logged per second on average, and at most at once.
+const MALFORMED_LOG_RATE: f64 = 1.0;
+const MALFORMED_LOG_BURST: u32 = 10;
+
+/// How many bytes at the beginning of a mangled packet are logged.
+const MALFORMED_LOG_BYTES: usize = 16;
+
+/// How often each worker logs the control packets that it dropped, at most.
+const CONTROL_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The maximum number of clients whose last exchange is remembered for the interleaved mode on
+/// each socket.
+const INTERLEAVED_MAX_CLIENTS: usize = 65536;
+
+/// The number of clients whose NTS unique identifiers are remembered on each socket.
+const REPLAY_MAX_CLIENTS: usize = 65536;
+
+/// The number of unique identifiers that are remembered for each client.
+const REPLAY_IDENTIFIERS_PER_CLIENT: usize = 16;
+
 lazy_static! {
     static ref QUERY_COUNTER: IntCounter =
         register_int_counter!("ntp_queries_total", "Number of NTP queries").unwrap();
@@ -63,6 +151,12 @@ lazy_static! {
         "Number of packets without valid ntp headers"
     )
     .unwrap();
+    static ref MALFORMED_PACKET_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_malformed_packets_total",
+        "Number of packets that could not be answered, by why they are malformed",
+        &["reason"]
+    )
+    .unwrap();
     static ref MISSING_KEY_COUNTER: IntCounter =
         register_int_counter!("ntp_missing_key_total", "Number of keys we could not find").unwrap();
     static ref UNDECRYPTABLE_COOKIE_COUNTER: IntCounter = register_int_counter!(
@@ -70,6 +164,110 @@ lazy_static! {
         "Number of cookies we could not decrypt"
     )
     .unwrap();
+    static ref NTS_NAK_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_nts_naks_total",
+        "Number of NTS NAKs sent, by why the request could not be authenticated",
+        &["reason"]
+    )
+    .unwrap();
+    static ref CLAMPED_RESPONSE_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_clamped_responses_total",
+        "Number of responses that would have been larger than the requests, by whether cookies \
+         were left out of them or they were dropped",
+        &["action"]
+    )
+    .unwrap();
+    static ref NTPV5_COUNTER: IntCounter = register_int_counter!(
+        "ntp_v5_queries_total",
+        "Number of NTPv5 queries"
+    )
+    .unwrap();
+    static ref INTERLEAVED_COUNTER: IntCounter = register_int_counter!(
+        "ntp_interleaved_responses_total",
+        "Number of responses sent in the interleaved mode"
+    )
+    .unwrap();
+    static ref RATE_LIMITED_COUNTER: IntCounter = register_int_counter!(
+        "ntp_rate_limited_total",
+        "Number of queries dropped by the per-client rate limit"
+    )
+    .unwrap();
+    static ref RATE_KOD_COUNTER: IntCounter = register_int_counter!(
+        "ntp_rate_kod_total",
+        "Number of RATE Kiss of Death packets sent to clients over the rate limit"
+    )
+    .unwrap();
+    static ref MIN_INTERVAL_DROP_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_min_interval_drops_total",
+        "Number of queries dropped from the clients that poll too often, by whether the client \
+         has strikes or is banned",
+        &["reason"]
+    )
+    .unwrap();
+    static ref MIN_INTERVAL_BAN_COUNTER: IntCounter = register_int_counter!(
+        "ntp_min_interval_bans_total",
+        "Number of times that a client was banned for polling too often"
+    )
+    .unwrap();
+    static ref NTS_POOL_FULL_COUNTER: IntCounter = register_int_counter!(
+        "ntp_nts_pool_full_total",
+        "Number of NTS requests dropped because the queue of the NTS pool was full"
+    )
+    .unwrap();
+    static ref CONTROL_PACKET_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_control_packets_total",
+        "Number of the control packets of ntpq and ntpdc that were dropped",
+        &["mode"]
+    )
+    .unwrap();
+    static ref BROADCAST_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_broadcasts_total",
+        "Number of broadcast packets sent on each interface",
+        &["interface"]
+    )
+    .unwrap();
+    static ref UDP_SEND_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_udp_sends_total",
+        "Number of responses sent by the workers, by whether the kernel split them from a larger \
+         buffer with UDP GSO",
+        &["kind"]
+    )
+    .unwrap();
+    static ref FAMILY_QUERY_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_family_queries_total",
+        "Number of queries received from the clients of each address family",
+        &["family"]
+    )
+    .unwrap();
+    static ref REPLAY_COUNTER: IntCounter = register_int_counter!(
+        "ntp_replayed_requests_total",
+        "Number of NTS requests dropped because their unique identifier was seen before"
+    )
+    .unwrap();
+    static ref MAC_FAILURE_COUNTER: IntCounter = register_int_counter!(
+        "ntp_mac_failures_total",
+        "Number of queries with a legacy MAC that could not be verified"
+    )
+    .unwrap();
+    static ref RESPONSE_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "ntp_response_duration_seconds",
+        "Time from receiving a query to sending the response, by whether the query was NTS and \
+         whether it was answered normally",
+        &["path", "result"],
+        // From 1us to about 0.5s.
+        exponential_buckets(1e-6, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    static ref TAI_OFFSET_GAUGE: IntGauge = register_int_gauge!(
+        "ntp_tai_offset_seconds",
+        "The current TAI-UTC offset in the leap file"
+    )
+    .unwrap();
+    static ref LEAP_FILE_EXPIRY_GAUGE: IntGauge = register_int_gauge!(
+        "ntp_leap_file_expiry_timestamp_seconds",
+        "The Unix time when the leap file expires"
+    )
+    .unwrap();
     static ref UPSTREAM_QUERY_COUNTER: IntCounter = register_int_counter!(
         "ntp_upstream_queries_total",
         "Number of upstream queries sent"
@@ -80,6 +278,20 @@ lazy_static! {
         "Number of failed upstream queries"
     )
     .unwrap();
+    static ref UPSTREAM_OFFSET_GAUGE: Gauge = register_gauge!(
+        "ntp_upstream_offset_seconds",
+        "The offset added to the local clock to follow the upstream servers"
+    )
+    .unwrap();
+    static ref REFCLOCK_OFFSET_GAUGE: GaugeVec = register_gauge_vec!(
+        "ntp_refclock_offset_seconds",
+        "The last offset of each reference clock to the local clock",
+        &["refclock"]
+    )
+    .unwrap();
+    /// The mangled packets that are logged, which all of the workers share.
+    static ref MALFORMED_LOG_BUCKET: Mutex<TokenBucket> =
+        Mutex::new(TokenBucket::new(MALFORMED_LOG_RATE, MALFORMED_LOG_BURST));
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -94,6 +306,677 @@ struct ServerState {
     refid: u32,
     refstamp: u64,
     taken: SystemTime,
+    /// The next leap second in the leap file, if any.
+    leap_event: Option<LeapEvent>,
+    /// The leap second being smeared, if the leap seconds are smeared instead of announced.
+    leap_smear: Option<LeapSmear>,
+    /// The seconds added to the local clock to follow the upstream servers.
+    offset: f64,
+    /// The configured precision, which is advertised instead of `precision` if it's set.
+    precision_override: Option<i8>,
+    /// The shortest poll interval that is advertised, if it's set.
+    min_poll: Option<i8>,
+}
+
+impl ServerState {
+    /// Return the poll interval that is advertised to the clients.
+    fn advertised_poll(&self) -> i8 {
+        match self.min_poll {
+            Some(min_poll) => self.poll.max(min_poll),
+            None => self.poll,
+        }
+    }
+
+    /// Return the precision that is advertised to the clients.
+    fn advertised_precision(&self) -> i8 {
+        self.precision_override.unwrap_or(self.precision)
+    }
+}
+
+/// The way that a query was answered, which labels the latency of the response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct QueryPath {
+    /// Whether the query was NTS.
+    nts: bool,
+
+    /// `ok`, or why the client got a kiss of death or a crypto-NAK instead: `auth_failure` or
+    /// `rate_limited`.
+    result: &'static str,
+}
+
+impl QueryPath {
+    fn ok(nts: bool) -> QueryPath {
+        QueryPath { nts, result: "ok" }
+    }
+
+    fn auth_failure(nts: bool) -> QueryPath {
+        QueryPath { nts, result: "auth_failure" }
+    }
+}
+
+/// Observe the time from receiving a query to sending its response.
+fn observe_response(path: QueryPath, received: SystemTime, sent: SystemTime) {
+    // The clock can be stepped in between.
+    if let Ok(duration) = sent.duration_since(received) {
+        let label = if path.nts { "nts" } else { "ntp" };
+        RESPONSE_HISTOGRAM.with_label_values(&[label, path.result])
+            .observe(duration.as_secs_f64());
+    }
+}
+
+/// The settings that are applied again when the config file is reloaded on SIGHUP.
+#[derive(Clone, Debug, PartialEq)]
+struct Tunables {
+    /// Whether NTPv5 is enabled.
+    ntpv5: bool,
+
+    /// Whether the interleaved mode is enabled.
+    interleaved: bool,
+
+    /// Per-client rate limit of the queries, if it's enabled.
+    query_rate_limit: Option<RateLimitConfig>,
+
+    /// The minimum interval between the queries of each client, if it's enforced.
+    min_interval: Option<MinIntervalConfig>,
+
+    /// The fraction of the queries whose transmit timestamp is logged.
+    client_offset_sample_rate: f64,
+}
+
+impl Tunables {
+    fn from_config(config: &NtpServerConfig) -> Tunables {
+        Tunables {
+            ntpv5: config.ntpv5,
+            interleaved: config.interleaved,
+            query_rate_limit: config.query_rate_limit.clone(),
+            min_interval: config.min_interval.clone(),
+            client_offset_sample_rate: config.client_offset_sample_rate,
+        }
+    }
+}
+
+/// The tunables that the workers share, with a generation that is bumped each time that they
+/// are replaced, so that the workers can tell without taking the lock.
+struct SharedTunables {
+    generation: AtomicUsize,
+    tunables: RwLock<Tunables>,
+}
+
+impl SharedTunables {
+    fn new(tunables: Tunables) -> SharedTunables {
+        SharedTunables { generation: AtomicUsize::new(0), tunables: RwLock::new(tunables) }
+    }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Return the current tunables and their generation.
+    fn load(&self) -> (usize, Tunables) {
+        let tunables = self.tunables.read().unwrap();
+        (self.generation(), tunables.clone())
+    }
+
+    fn store(&self, tunables: Tunables) {
+        let mut guard = self.tunables.write().unwrap();
+        *guard = tunables;
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// The features of the server that each socket has its own state of.
+struct SocketOptions {
+    /// The settings that can change while the server is running.
+    tunables: Arc<SharedTunables>,
+
+    /// How the packets are timestamped.
+    timestamping: Timestamping,
+
+    /// How many packets are received and sent at a time.
+    batch_size: usize,
+
+    /// The most cookie placeholders in an NTS request that get a new cookie.
+    max_cookie_placeholders: usize,
+
+    /// The keys of the legacy MACs, if they are enabled.
+    symmetric_keys: Option<Arc<SymmetricKeys>>,
+
+    /// Whether the replayed NTS requests are dropped.
+    replay_protection: bool,
+
+    /// Whether the dropped control packets are logged.
+    log_control_packets: bool,
+
+    /// Whether the packets are received and sent with io_uring.
+    io_uring: bool,
+
+    /// Whether the packets are received and sent with UDP GRO and GSO.
+    udp_offload: bool,
+
+    /// The limiter of the queries of the worker, which is shared with the thread that saves the
+    /// state.
+    query_limiter: Arc<Mutex<Option<RateLimiter>>>,
+
+    /// The state from before the server restarted, if it was saved.
+    restored: Option<Arc<Snapshot>>,
+
+    /// The most recently used list of the clients, if they are tracked.
+    mru: Option<Arc<Mutex<MruTable>>>,
+
+    /// The pool that the NTS requests are handed to, if there is one.
+    nts_pool: Option<Pool<NtsJob>>,
+
+    /// The capture that the packets are written to, if they are captured.
+    capture: Option<Arc<Mutex<Capture>>>,
+
+    /// The clock that the time is read from.
+    clock: Arc<dyn ClockSource>,
+}
+
+/// What a worker answers the queries with, which the NTS pool gets a copy of along with each
+/// request that it answers.
+#[derive(Clone)]
+struct Responder {
+    /// Whether NTPv5 is enabled.
+    ntpv5: bool,
+
+    /// The last exchange with each client, if the interleaved mode is enabled.
+    interleaved_log: Option<Arc<Mutex<InterleavedLog>>>,
+
+    /// The recent NTS unique identifiers, if the replays are dropped.
+    replay_cache: Option<Arc<Mutex<ReplayCache>>>,
+
+    /// The most cookie placeholders in an NTS request that get a new cookie.
+    max_cookie_placeholders: usize,
+
+    /// The keys of the legacy MACs, if they are enabled.
+    symmetric_keys: Option<Arc<SymmetricKeys>>,
+
+    /// The clock that the time is read from.
+    clock: Arc<dyn ClockSource>,
+}
+
+impl Responder {
+    /// Return the response to a query from `client` that was received at `r_system` and the
+    /// way it was answered, or `None` if the query is dropped.
+    fn answer(
+        &self,
+        query: &[u8],
+        client: Option<IpAddr>,
+        r_system: SystemTime,
+        keys: &Arc<RwLock<KeyRotator>>,
+        servstate: &Arc<RwLock<ServerState>>,
+        logger: ClientLogger,
+    ) -> Option<(Vec<u8>, QueryPath)> {
+        let t_system = self.clock.now();
+        let resp = response(
+            query,
+            r_system,
+            t_system,
+            keys.clone(),
+            servstate.clone(),
+            logger,
+            RequestOptions {
+                ntpv5: self.ntpv5,
+                interleaved: match (&self.interleaved_log, client) {
+                    (Some(log), Some(client)) => Some((&**log, client)),
+                    _ => None,
+                },
+                max_cookie_placeholders: self.max_cookie_placeholders,
+                symmetric_keys: self.symmetric_keys.as_ref().map(|keys| &**keys),
+                replay: match (&self.replay_cache, client) {
+                    (Some(cache), Some(client)) => Some((&**cache, client)),
+                    _ => None,
+                },
+                clock: self.clock.as_ref(),
+            },
+        );
+        unless_mangled(resp, query, logger)
+    }
+
+    /// Remember the receive and transmit times of the response to `client` for the interleaved
+    /// mode, if it's enabled.
+    fn record(
+        &self,
+        client: IpAddr,
+        r_system: SystemTime,
+        sent: SystemTime,
+        servstate: &Arc<RwLock<ServerState>>,
+    ) {
+        if let Some(log) = &self.interleaved_log {
+            let state = servstate.read().unwrap();
+            log.lock().unwrap().record(client, ntp_timestamp(served_time(&state, r_system)),
+                                       ntp_timestamp(served_time(&state, sent)))
+        }
+    }
+}
+
+/// Return the address that a packet with `info` was received on by a socket bound to `bound`,
+/// which the response is sent from. The socket can be bound to the wildcard address.
+fn local_address(info: Option<PacketInfo>, bound: SocketAddr) -> SocketAddr {
+    let ip = match info {
+        Some(PacketInfo::V4(info)) => {
+            IpAddr::V4(Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)))
+        },
+        Some(PacketInfo::V6(info)) => IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)),
+        None => bound.ip(),
+    };
+    SocketAddr::new(ip, bound.port())
+}
+
+/// Write a packet from `src` to `dst` to the capture, if the packets are captured.
+fn capture_packet(
+    capture: &Option<Arc<Mutex<Capture>>>,
+    time: SystemTime,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: &[u8],
+    logger: &slog::Logger,
+) {
+    if let Some(capture) = capture {
+        match capture.lock().unwrap().write(time, src, dst, payload) {
+            Ok(false) => (),
+            Ok(true) => info!(logger, "the packet capture is over"),
+            Err(err) => error!(logger, "cannot write the packet capture, stopping it: {}", err),
+        }
+    }
+}
+
+/// Return the response, or `None` if it's an error, which is counted and logged unless the
+/// query is a replay.
+fn unless_mangled(
+    resp: Result<(Vec<u8>, QueryPath), std::io::Error>,
+    query: &[u8],
+    logger: ClientLogger,
+) -> Option<(Vec<u8>, QueryPath)> {
+    match resp {
+        Ok(answer) => Some(answer),
+        // The replays and the responses that would amplify the requests are counted and dropped.
+        Err(ref err) if err.kind() == ErrorKind::AlreadyExists => None,
+        Err(ref err) if err.kind() == ErrorKind::PermissionDenied => None,
+        Err(_) => {
+            MANGLED_PACKET_COUNTER.inc(); // The packet is too mangled to do much with.
+            let reason = protocol::malformed_reason(query);
+            MALFORMED_PACKET_COUNTER.with_label_values(&[reason]).inc();
+            // The garbage can come at any rate, so only some of it is logged.
+            if MALFORMED_LOG_BUCKET.lock().unwrap().take(Instant::now()) {
+                let first_bytes = &query[..query.len().min(MALFORMED_LOG_BYTES)];
+                debug!(logger.get(), "mangled packet"; "reason" => reason, "length" => query.len(),
+                       "first_bytes" => format!("{:02x?}", first_bytes));
+            }
+            None
+        }
+    }
+}
+
+/// The logger of the messages about a client. The child logger with the address of the client is
+/// only made when something is logged, since most of the queries log nothing.
+#[derive(Clone, Copy)]
+struct ClientLogger<'a> {
+    logger: &'a slog::Logger,
+    client_addr: Option<SocketAddr>,
+}
+
+impl<'a> ClientLogger<'a> {
+    fn get(self) -> slog::Logger {
+        self.logger.new(slog::o!("client"=>self.client_addr))
+    }
+}
+
+/// An NTS request that one of the threads of the NTS pool answers. It sends the response itself.
+struct NtsJob {
+    query: Vec<u8>,
+    client_addr: SocketAddr,
+    r_system: SystemTime,
+    responder: Responder,
+
+    /// The socket that the request was received on, and where the response goes.
+    socket: Arc<UdpSocket>,
+    address: SockAddr,
+    info: Option<PacketInfo>,
+
+    capture: Option<Arc<Mutex<Capture>>>,
+    logger: slog::Logger,
+}
+
+/// Answer an NTS request in the NTS pool.
+fn answer_nts_job(
+    job: NtsJob,
+    keys: &Arc<RwLock<KeyRotator>>,
+    servstate: &Arc<RwLock<ServerState>>,
+) {
+    let logger = ClientLogger { logger: &job.logger, client_addr: Some(job.client_addr) };
+    let client = job.client_addr.ip();
+    let answer = job.responder.answer(&job.query, Some(client), job.r_system, keys,
+                                      servstate, logger);
+    let (data, path) = match answer {
+        Some(answer) => answer,
+        None => return,
+    };
+    let outgoing = [Outgoing { data, address: job.address, info: job.info }];
+    for result in batch::send(job.socket.as_raw_fd(), &outgoing) {
+        if let Err(err) = result {
+            error!(logger.get(), "error sending response to {}: {:}", job.address, err);
+            continue;
+        }
+        let sent = job.responder.clock.now();
+        if let (Some(_), SockAddr::Inet(address), Ok(bound)) =
+            (&job.capture, job.address, job.socket.local_addr())
+        {
+            let local = local_address(job.info, bound);
+            capture_packet(&job.capture, sent, local, address.to_std(), &outgoing[0].data,
+                           &job.logger);
+        }
+        observe_response(path, job.r_system, sent);
+        job.responder.record(client, job.r_system, sent, servstate);
+    }
+}
+
+/// The state that a worker keeps across the queries, apart from the server state.
+struct Worker {
+    /// The settings that can change while the server is running, and the generation of the
+    /// ones that the worker follows.
+    tunables: Arc<SharedTunables>,
+    generation: usize,
+
+    /// The keys of the cookies and the state that the responses are made from.
+    keys: Arc<RwLock<KeyRotator>>,
+    servstate: Arc<RwLock<ServerState>>,
+
+    responder: Responder,
+
+    /// The rate limit of the queries, and the limiters of the queries and of the kisses of
+    /// death, if the queries are limited. The limiter of the queries is shared with the thread
+    /// that saves the state.
+    query_rate_limit: Option<RateLimitConfig>,
+    query_limiter: Arc<Mutex<Option<RateLimiter>>>,
+    kiss_limiter: Option<RateLimiter>,
+
+    /// The state from before the server restarted, until the limiter of the queries is created.
+    restored: Option<Arc<Snapshot>>,
+
+    /// The minimum interval between the queries of each client, and the strikes and the bans of
+    /// the clients, if it's enforced.
+    min_interval_config: Option<MinIntervalConfig>,
+    min_interval: Option<MinInterval>,
+
+    /// The fraction of the queries whose transmit timestamp is logged.
+    client_offset_sample_rate: f64,
+
+    /// The most recently used list of the clients, if they are tracked.
+    mru: Option<Arc<Mutex<MruTable>>>,
+
+    /// The log of the dropped control packets, if they are logged.
+    control_log: Option<ControlLog>,
+
+    /// The NTS pool and the socket of the worker, if the NTS requests are handed to the pool.
+    nts_pool: Option<(Pool<NtsJob>, Arc<UdpSocket>)>,
+
+    /// The capture that the packets are written to, if they are captured.
+    capture: Option<Arc<Mutex<Capture>>>,
+}
+
+/// The dropped control packets that a worker logs once in a while.
+struct ControlLog {
+    /// When they were last logged, if they ever were.
+    logged: Option<Instant>,
+
+    /// The number of them that were dropped since then.
+    dropped: u64,
+}
+
+impl Worker {
+    fn new(
+        options: &SocketOptions,
+        keys: Arc<RwLock<KeyRotator>>,
+        servstate: Arc<RwLock<ServerState>>,
+    ) -> Worker {
+        let mut worker = Worker {
+            tunables: options.tunables.clone(),
+            generation: 0,
+            keys,
+            servstate,
+            responder: Responder {
+                ntpv5: false,
+                interleaved_log: None,
+                replay_cache: if options.replay_protection {
+                    let cache = ReplayCache::new(REPLAY_MAX_CLIENTS, REPLAY_IDENTIFIERS_PER_CLIENT);
+                    Some(Arc::new(Mutex::new(cache)))
+                } else {
+                    None
+                },
+                max_cookie_placeholders: options.max_cookie_placeholders,
+                symmetric_keys: options.symmetric_keys.clone(),
+                clock: options.clock.clone(),
+            },
+            query_rate_limit: None,
+            query_limiter: options.query_limiter.clone(),
+            kiss_limiter: None,
+            restored: options.restored.clone(),
+            min_interval_config: None,
+            min_interval: None,
+            client_offset_sample_rate: 0.0,
+            mru: options.mru.clone(),
+            capture: options.capture.clone(),
+            control_log: if options.log_control_packets {
+                Some(ControlLog { logged: None, dropped: 0 })
+            } else {
+                None
+            },
+            nts_pool: None,
+        };
+        let (generation, tunables) = worker.tunables.load();
+        worker.apply(generation, tunables);
+        worker
+    }
+
+    /// Follow the tunables if they were replaced since the worker last looked.
+    fn reload(&mut self) {
+        if self.tunables.generation() != self.generation {
+            let (generation, tunables) = self.tunables.load();
+            self.apply(generation, tunables);
+        }
+    }
+
+    fn apply(&mut self, generation: usize, tunables: Tunables) {
+        self.generation = generation;
+        self.responder.ntpv5 = tunables.ntpv5;
+        self.client_offset_sample_rate = tunables.client_offset_sample_rate;
+        // Each client sticks to one socket, so each socket has its own log. It's kept as long as
+        // the mode stays enabled.
+        if !tunables.interleaved {
+            self.responder.interleaved_log = None;
+        } else if self.responder.interleaved_log.is_none() {
+            let log = InterleavedLog::new(INTERLEAVED_MAX_CLIENTS);
+            self.responder.interleaved_log = Some(Arc::new(Mutex::new(log)));
+        }
+        // The clients over the limit get a RATE kiss of death once in a while, and their other
+        // queries are dropped. The kisses are limited as well, so that the server cannot be
+        // used to flood someone whose address is spoofed. The buckets are kept unless the limit
+        // changes.
+        if self.query_rate_limit != tunables.query_rate_limit {
+            let mut query_limiter = tunables.query_rate_limit.clone().map(|limit| {
+                RateLimiter::new(limit.rate, limit.burst, limit.exempt)
+            });
+            // The clients that were limited before the server restarted stay limited. The
+            // buckets are refilled for as long as the server was down.
+            if let (Some(limiter), Some(snapshot)) = (&mut query_limiter, self.restored.take()) {
+                let age = SystemTime::now().duration_since(snapshot.time).unwrap_or_default();
+                let now = Instant::now();
+                for client in &snapshot.limited {
+                    limiter.restore(client.addr, client.tokens, age, now);
+                }
+            }
+            *self.query_limiter.lock().unwrap() = query_limiter;
+            self.kiss_limiter = tunables.query_rate_limit.as_ref()
+                .map(|_| RateLimiter::new(RATE_KOD_RATE, 1, Vec::new()));
+            self.query_rate_limit = tunables.query_rate_limit;
+        }
+        // Like the buckets, the strikes and the bans are kept unless the interval changes.
+        if self.min_interval_config != tunables.min_interval {
+            self.min_interval = tunables.min_interval.clone().map(MinInterval::new);
+            self.min_interval_config = tunables.min_interval;
+        }
+    }
+    /// Return the response to a query from `client_addr` that was received at `r_system` and
+    /// the way it was answered, or `None` if the query is dropped or handed to the NTS pool. The
+    /// pool sends the response to `reply_to` itself.
+    fn respond(
+        &mut self,
+        query: &[u8],
+        client_addr: Option<SocketAddr>,
+        r_system: SystemTime,
+        logger: &slog::Logger,
+        reply_to: Option<(SockAddr, Option<PacketInfo>)>,
+    ) -> Option<(Vec<u8>, QueryPath)> {
+        self.reload();
+        // Nothing is sent back to the control packets, so they can't be used for amplification.
+        match protocol::packet_mode_number(query) {
+            Some(mode @ protocol::MODE_CONTROL) | Some(mode @ protocol::MODE_PRIVATE) => {
+                self.drop_control_packet(mode, client_addr, logger);
+                return None;
+            },
+            _ => (),
+        }
+        let client = client_addr.map(|addr| addr.ip());
+        if let Some(client) = client {
+            // The IPv4 clients of a dual-stack socket come from IPv4-mapped addresses.
+            let family = match canonical_ip(client) {
+                IpAddr::V4(_) => "ipv4",
+                IpAddr::V6(_) => "ipv6",
+            };
+            FAMILY_QUERY_COUNTER.with_label_values(&[family]).inc();
+        }
+        if let (Some(mru), Some(client_addr)) = (&self.mru, client_addr) {
+            mru.lock().unwrap().record(client_addr, r_system);
+        }
+        let client_logger = ClientLogger { logger, client_addr };
+
+        // The clients that poll far too often are penalized apart from the rate limit.
+        if let (Some(min_interval), Some(client)) = (&mut self.min_interval, client) {
+            let roll = rand::thread_rng().gen::<f64>();
+            match min_interval.check(client, Instant::now(), roll) {
+                Verdict::Allow => (),
+                Verdict::Penalty => {
+                    MIN_INTERVAL_DROP_COUNTER.with_label_values(&["penalty"]).inc();
+                    return None;
+                },
+                Verdict::Ban => {
+                    MIN_INTERVAL_BAN_COUNTER.inc();
+                    MIN_INTERVAL_DROP_COUNTER.with_label_values(&["banned"]).inc();
+                    info!(client_logger.get(), "banned the client for polling too often");
+                    return None;
+                },
+                Verdict::Banned => {
+                    MIN_INTERVAL_DROP_COUNTER.with_label_values(&["banned"]).inc();
+                    return None;
+                },
+            }
+        }
+
+        // Whether the client is over the limit, and if so, whether it gets a kiss of death.
+        let limited = match (self.query_limiter.lock().unwrap().as_mut(), &mut self.kiss_limiter,
+                             client) {
+            (Some(queries), Some(kisses), Some(client)) => {
+                let now = Instant::now();
+                if queries.check(client, now) {
+                    None
+                } else {
+                    Some(kisses.check(client, now))
+                }
+            },
+            _ => None,
+        };
+        match limited {
+            Some(true) => {
+                RATE_KOD_COUNTER.inc();
+                let kod = parse_ntp_packet(query).and_then(|query| {
+                    if query.header.mode != PacketMode::Client {
+                        return Err(Error::new(ErrorKind::InvalidData, "not client mode"));
+                    }
+                    let path = QueryPath { nts: is_nts_packet(&query), result: "rate_limited" };
+                    Ok((serialize_ntp_packet(kiss_of_death(query, KOD_RATE)), path))
+                });
+                unless_mangled(kod, query, client_logger)
+            },
+            Some(false) => {
+                RATE_LIMITED_COUNTER.inc();
+                None
+            },
+            None => {
+                self.sample_client_offset(query, r_system, client_logger);
+                if let (Some((pool, socket)), Some(client_addr), Some((address, info))) =
+                    (&self.nts_pool, client_addr, reply_to)
+                {
+                    if parse_ntp_packet(query).ok().map_or(false, |packet| is_nts_packet(&packet)) {
+                        let job = NtsJob {
+                            query: query.to_vec(),
+                            client_addr,
+                            r_system,
+                            responder: self.responder.clone(),
+                            socket: socket.clone(),
+                            address,
+                            info,
+                            capture: self.capture.clone(),
+                            logger: logger.clone(),
+                        };
+                        if pool.submit(job).is_err() {
+                            NTS_POOL_FULL_COUNTER.inc();
+                        }
+                        return None;
+                    }
+                }
+                self.responder.answer(query, client, r_system, &self.keys, &self.servstate,
+                                      client_logger)
+            },
+        }
+    }
+
+    /// Log how long after the transmit timestamp of the client a query was received, for a
+    /// sample of the queries.
+    fn sample_client_offset(&self, query: &[u8], r_system: SystemTime, logger: ClientLogger) {
+        let rate = self.client_offset_sample_rate;
+        if rate == 0.0 || !rand::thread_rng().gen_bool(rate) {
+            return;
+        }
+        // The NTPv5 requests have no transmit timestamp.
+        if protocol::packet_version(query) == Some(protocol::VERSION_5) {
+            return;
+        }
+        let transmit = match protocol::parse_packet_header(query) {
+            Ok(header) if header.transmit_timestamp != 0 => header.transmit_timestamp,
+            _ => return,
+        };
+        let receive = ntp_timestamp(served_time(&self.servstate.read().unwrap(), r_system));
+        // The difference is taken modulo 2^64, so that it's right across the end of the era.
+        let seconds = receive.wrapping_sub(transmit) as i64 as f64 / TWO_POW_32;
+        info!(logger.get(), "received {:+.6} seconds after the transmit timestamp of the client",
+              seconds);
+    }
+
+    /// Count a dropped control packet of `mode` from `client_addr`, and log the ones dropped so
+    /// far if they are logged and it's been long enough since the last time.
+    fn drop_control_packet(
+        &mut self,
+        mode: u8,
+        client_addr: Option<SocketAddr>,
+        logger: &slog::Logger,
+    ) {
+        let label = if mode == protocol::MODE_CONTROL { "control" } else { "private" };
+        CONTROL_PACKET_COUNTER.with_label_values(&[label]).inc();
+        if let Some(log) = &mut self.control_log {
+            log.dropped += 1;
+            let now = Instant::now();
+            let due = log.logged
+                .map_or(true, |logged| now.duration_since(logged) >= CONTROL_LOG_INTERVAL);
+            if due {
+                warn!(logger, "dropped {} control packets, the last one of mode {} from {:?}",
+                      log.dropped, mode, client_addr);
+                log.logged = Some(now);
+                log.dropped = 0;
+            }
+        }
+    }
 }
 
 /// run_server runs the ntp server on the given socket.
@@ -104,10 +987,30 @@ fn run_server(
     servstate: Arc<RwLock<ServerState>>,
     logger: slog::Logger,
     ipv4: bool,
+    options: SocketOptions,
 ) -> Result<(), std::io::Error> {
+    let socket = Arc::new(socket);
+    let bound = socket.local_addr()?;
+    let mut worker = Worker::new(&options, keys, servstate.clone());
+    worker.nts_pool = options.nts_pool.clone().map(|pool| (pool, socket.clone()));
+    let SocketOptions { mut timestamping, batch_size, io_uring, mut udp_offload, .. } = options;
+    // The kernel transmit timestamps of the responses that the NTS pool sends would be mixed up
+    // with the ones of the worker.
+    if timestamping == Timestamping::KernelTransmit && worker.nts_pool.is_some() {
+        warn!(logger,
+              "cannot timestamp the sent packets with the NTS pool, falling back to kernel");
+        timestamping = Timestamping::Kernel;
+    }
     let sockfd = socket.as_raw_fd();
-    setsockopt(sockfd, sockopt::ReceiveTimestamp, &true)
-        .expect("setsockopt failed; can't run ntp server");
+    if let Err(err) = timestamping::enable(sockfd, timestamping) {
+        if timestamping != Timestamping::KernelTransmit {
+            return Err(err);
+        }
+        // The transmit timestamps are not supported everywhere.
+        warn!(logger, "cannot timestamp the sent packets, falling back to kernel: {}", err);
+        timestamping = Timestamping::Kernel;
+        timestamping::enable(sockfd, timestamping)?;
+    }
     if ipv4 {
         setsockopt(sockfd, sockopt::Ipv4PacketInfo, &true)
             .expect("setsockopt failed; can't run ntp server");
@@ -115,88 +1018,174 @@ fn run_server(
         setsockopt(sockfd, sockopt::Ipv6RecvPacketInfo, &true)
             .expect("setsockopt failed; can't run ntp server");
     }
-    // The following is adapted from the example in the nix crate docs:
-    // https://docs.rs/nix/0.13.0/nix/sys/socket/enum.ControlMessage.html#variant.ScmTimestamp
-    // Most of these functions are documented in manpages, and nix is a thin wrapper around them.
+    let mut ring = if io_uring {
+        match Uring::new(sockfd, batch_size, BUF_SIZE) {
+            Ok(ring) => Some(ring),
+            Err(err) => {
+                warn!(logger, "cannot set up io_uring, falling back to recvmmsg: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if udp_offload && ring.is_some() {
+        warn!(logger, "cannot offload the UDP segmentation with io_uring");
+        udp_offload = false;
+    }
+    // The requests that the kernel coalesces need larger buffers.
+    let gro = if udp_offload { Some(batch::enable_gro(sockfd)) } else { None };
+    let buf_size = match gro {
+        Some(Ok(())) => batch::GRO_BUF_SIZE,
+        Some(Err(err)) => {
+            warn!(logger, "cannot coalesce the received packets, receiving them one at a time: {}",
+                  err);
+            BUF_SIZE
+        },
+        None => BUF_SIZE,
+    };
+    // The buffers are allocated once, and each batch of packets is received into them.
+    let mut bufs = vec![vec![0; buf_size]; batch_size];
     loop {
         // Receive and respond to packets
-        let mut buf = [0; BUF_SIZE];
-        let flags = MsgFlags::empty();
-        let mut cmsgspace: CmsgSpace<(TimeVal, CmsgSpace<(in_pktinfo, CmsgSpace<in6_pktinfo>)>)> =
-            CmsgSpace::new();
-        let iov = [IoVec::from_mut_slice(&mut buf)];
-        let r = recvmsg(sockfd, &iov, Some(&mut cmsgspace), flags);
-        if let Err(_err) = r {
-            error!(logger, "error receiving message: {:?}", _err);
-            continue;
-        }
-        let r = r.unwrap(); // this is safe because of previous if
-        if let None = r.address {
-            // No return address => we can't do anything
-            continue;
-        }
-        let src = r.address.unwrap();
-        // We should only have a single cmsg of known type.
-        // The nix crate implements a typesafe interface to cmsg,
-        // hence some of the matching here.
-        let mut r_time = TimeVal::nanoseconds(0);
-        let mut msgs: Vec<ControlMessage> = Vec::new();
-        for msg in r.cmsgs() {
-            match msg {
-                ControlMessage::ScmTimestamp(&r_timestamp) => r_time = r_timestamp,
-                ControlMessage::Ipv4PacketInfo(_inf) => {
-                    if ipv4 {
-                        msgs.push(msg);
-                    } else {
-                        error!(logger, "v6 connection got v4 info");
-                        continue;
-                    }
+        let received = match &mut ring {
+            Some(ring) => ring.receive(&mut bufs),
+            None => batch::receive(sockfd, &mut bufs),
+        };
+        let received = match received {
+            Ok(received) => received,
+            Err(err) => {
+                error!(logger, "error receiving message: {:?}", err);
+                continue;
+            }
+        };
+        let mut outgoing = Vec::with_capacity(received.len());
+        // The client, the receive time and the path of each response, for the interleaved mode
+        // and the latency.
+        let mut exchanges = Vec::with_capacity(received.len());
+        for (buf, r) in bufs.iter().zip(received) {
+            let src = match r.address {
+                Some(src) => src,
+                // No return address => we can't do anything
+                None => continue,
+            };
+            let client_addr = match src {
+                SockAddr::Inet(addr) => Some(addr.to_std()),
+                _ => None,
+            };
+            // The response is sent from the address that the query was sent to.
+            let info = match r.info {
+                Some(PacketInfo::V4(_)) if !ipv4 => {
+                    error!(logger, "got the packet info of the other address family");
+                    None
                 }
-                ControlMessage::Ipv6PacketInfo(_inf) => {
-                    if !ipv4 {
-                        msgs.push(msg);
-                    } else {
-                        error!(logger, "v4 connection got v6 info");
-                        continue;
-                    }
+                Some(PacketInfo::V6(_)) if ipv4 => {
+                    error!(logger, "got the packet info of the other address family");
+                    None
+                }
+                info => info,
+            };
+
+            // Without the kernel timestamps, the clock is read now.
+            let r_system = r.timestamp.unwrap_or_else(|| worker.responder.clock.now());
+            // The requests that the kernel coalesced are answered one at a time.
+            let segment_size = r.segment_size.unwrap_or(r.bytes).max(1);
+            for start in (0..r.bytes.max(1)).step_by(segment_size) {
+                let query = &buf[start..r.bytes.min(start + segment_size)];
+                if let Some(client_addr) = client_addr {
+                    capture_packet(&worker.capture, r_system, client_addr,
+                                   local_address(info, bound), query, &logger);
                 }
-                _ => {
-                    error!(logger, "unexpected control message");
-                    continue;
+                let reply_to = Some((src, info));
+                let answer = worker.respond(query, client_addr, r_system, &logger, reply_to);
+                if let Some((data, path)) = answer {
+                    outgoing.push(Outgoing { data, address: src, info });
+                    exchanges.push((client_addr.map(|addr| addr.ip()), r_system, path));
                 }
             }
         }
+        if outgoing.is_empty() {
+            continue;
+        }
 
-        let r_system = SystemTime::UNIX_EPOCH
-            + Duration::new(r_time.tv_sec() as u64, r_time.tv_usec() as u32 * 1000);
-        let t_system = SystemTime::now();
-        // We now have the receive times and the current time as SystemTimes
-        let resp = response(
-            &buf[..r.bytes],
-            r_system,
-            t_system,
-            keys.clone(),
-            servstate.clone(),
-            logger.clone(),
-        );
-        match resp {
-            Ok(data) => {
-                let resp = sendmsg(
-                    sockfd,
-                    &[IoVec::from_slice(&data)],
-                    &msgs,
-                    flags,
-                    Some(&src),
-                );
-                if let Err(err) = resp {
-                    error!(logger, "error sending response: {:}", err);
+        let mut offloaded = 0;
+        let results = match &mut ring {
+            Some(ring) => ring.send(&outgoing),
+            None if udp_offload => {
+                let groups = batch::segments(&outgoing);
+                let sent = batch::send_segmented(sockfd, &outgoing, &groups);
+                if let Some(err) = sent.unsupported {
+                    warn!(logger,
+                          "cannot offload the UDP segmentation, falling back to sendmmsg: {}", err);
+                    udp_offload = false;
                 }
+                offloaded = sent.offloaded;
+                sent.results
+            },
+            None => batch::send(sockfd, &outgoing),
+        };
+        UDP_SEND_COUNTER.with_label_values(&["offloaded"]).inc_by(offloaded as i64);
+        UDP_SEND_COUNTER.with_label_values(&["normal"])
+            .inc_by((outgoing.len() - offloaded) as i64);
+        // Take the transmit timestamp after the responses are sent, so that the next response
+        // in the interleaved mode has a more accurate one. The kernel one is taken when the
+        // packet leaves, but it can only be told apart when a single response was sent.
+        let kernel_sent = match timestamping {
+            Timestamping::KernelTransmit => timestamping::transmit_timestamp(sockfd)
+                .filter(|_| outgoing.len() == 1),
+            _ => None,
+        };
+        let sent = kernel_sent.unwrap_or_else(|| worker.responder.clock.now());
+        let responses = results.into_iter().zip(&outgoing).zip(exchanges);
+        for ((result, packet), (client, r_system, path)) in responses {
+            if let Err(err) = result {
+                error!(logger, "error sending response to {}: {:}", packet.address, err);
+                continue;
             }
-            Err(_) => {
-                MANGLED_PACKET_COUNTER.inc(); // The packet is too mangled to do much with.
-                error!(logger, "mangled packet");
+            if let SockAddr::Inet(address) = packet.address {
+                capture_packet(&worker.capture, sent, local_address(packet.info, bound),
+                               address.to_std(), &packet.data, &logger);
             }
-        };
+            observe_response(path, r_system, sent);
+            if let Some(client) = client {
+                worker.responder.record(client, r_system, sent, &servstate);
+            }
+        }
+    }
+}
+
+/// run_xdp_server answers the NTP requests that the XDP program redirects to the AF_XDP socket
+/// of a queue. The kernel doesn't timestamp them, so the clock is read when they are processed.
+fn run_xdp_server(
+    mut xsk: Xsk,
+    keys: Arc<RwLock<KeyRotator>>,
+    servstate: Arc<RwLock<ServerState>>,
+    logger: slog::Logger,
+    port: u16,
+    dscp: Option<u8>,
+    options: SocketOptions,
+) -> Result<(), std::io::Error> {
+    let mut worker = Worker::new(&options, keys, servstate.clone());
+    loop {
+        xsk.process(|frame, len| {
+            let request = xdp::parse_request(&frame[..len], port)?;
+            let r_system = worker.responder.clock.now();
+            let query = &frame[request.payload.clone()];
+            capture_packet(&worker.capture, r_system, request.client, request.local, query,
+                           &logger);
+            let (data, path) = worker.respond(query, Some(request.client), r_system, &logger,
+                                              None)?;
+            let len = xdp::write_response(frame, &request, &data, dscp);
+            if len.is_some() {
+                // The response is sent as soon as it's queued.
+                let sent = worker.responder.clock.now();
+                capture_packet(&worker.capture, sent, request.local, request.client, &data,
+                               &logger);
+                observe_response(path, r_system, sent);
+                worker.responder.record(request.client.ip(), r_system, sent, &servstate);
+            }
+            len
+        })?;
     }
 }
 
@@ -206,33 +1195,70 @@ pub fn start_ntp_server(
 ) -> Result<(), Box<dyn std::error::Error>> {
     let logger = config.logger().clone();
 
-    info!(logger, "Initializing keys with memcached");
+    info!(logger, "Initializing keys from the key store");
 
     let key_rotator = KeyRotator::connect(
-        String::from("/nts/nts-keys"), // prefix
-        config.memcached_url.clone(), // memcached_url
+        config.key_store.build(), // key store
+        config.rotation, // rotation
         config.cookie_key.clone(), // master_key
         logger.clone(), // logger
-    ).expect("error connecting to the memcached server");
+    ).expect("error connecting to the key store");
 
+    // The keys are synced right away on SIGHUP.
+    signal::install_sighup_handler()?;
     let keys = Arc::new(RwLock::new(key_rotator));
     periodic_rotate(keys.clone());
+    if let Some(secret) = config.cookie_key_vault.clone() {
+        periodic_reload_master_key(keys.clone(), secret);
+    }
+    if let Some(secret) = config.cookie_key_aws.clone() {
+        periodic_reload_master_key_aws(keys.clone(), secret);
+    }
+    if let Some(path) = config.cookie_key_file.clone() {
+        periodic_reload_master_key_file(keys.clone(), path);
+    }
 
+    // The time is read from the system clock.
+    let clock: Arc<dyn ClockSource> = Arc::new(SystemClock);
     let servstate_struct = ServerState {
         leap: Unknown,
         stratum: 16,
         version: protocol::VERSION,
         poll: 7,
-        precision: -18,
+        precision: clock.precision(),
         root_delay: 10,
         root_dispersion: 10,
         refid: 0,
         refstamp: 0,
         taken: SystemTime::now(),
+        leap_event: None,
+        leap_smear: None,
+        offset: 0.0,
+        precision_override: config.precision,
+        min_poll: config.min_poll,
     };
 
     let servstate = Arc::new(RwLock::new(servstate_struct));
     match config.upstream_addr.clone() {
+        // The server is unsynchronized until the reference clocks are read.
+        None if !config.refclocks.is_empty() => {
+            info!(logger, "synchronizing to {} reference clocks", config.refclocks.len());
+            let servstate = servstate.clone();
+            let refclocks = config.refclocks.clone();
+            let refclock_logger = logger.new(slog::o!("task"=>"reading refclocks"));
+            thread::spawn(move || periodic_poll_refclocks(servstate, refclocks, refclock_logger));
+        }
+        // The server is unsynchronized until the upstream servers answer.
+        None if !config.upstreams.is_empty() => {
+            info!(logger, "synchronizing to {} upstream servers", config.upstreams.len());
+            let servstate = servstate.clone();
+            let upstreams = config.upstreams.clone();
+            let poll = config.upstream_poll;
+            let poll_logger = logger.new(slog::o!("task"=>"polling upstreams"));
+            thread::spawn(move || {
+                periodic_poll_upstreams(servstate, upstreams, poll, poll_logger)
+            });
+        }
         Some(upstream_addr) => {
             info!(logger, "connecting to upstream");
             let servstate = servstate.clone();
@@ -245,44 +1271,335 @@ pub fn start_ntp_server(
         }
         None => {
             let mut state_guard = servstate.write().unwrap();
-            info!(logger, "setting stratum to 1");
+            info!(logger, "setting stratum to {}", config.stratum);
             (*state_guard).leap = NoLeap;
-            (*state_guard).stratum = 1;
+            set_static_state(&mut state_guard, &config);
         }
     }
 
+    // The settings that don't need the sockets to be opened again are applied on SIGHUP.
+    let tunables = Arc::new(SharedTunables::new(Tunables::from_config(&config)));
+    if let Some(path) = config.config_file.clone() {
+        let tunables = tunables.clone();
+        // The stratum fields only come from the config if the system clock is trusted.
+        let static_state = config.upstream_addr.is_none() && config.upstreams.is_empty() &&
+            config.refclocks.is_empty();
+        let servstate = if static_state { Some(servstate.clone()) } else { None };
+        let reload_logger = logger.new(slog::o!("task"=>"reloading config"));
+        thread::spawn(move || reload_on_sighup(&path, tunables, servstate, reload_logger));
+    }
+
+    if let Some(path) = config.leap_file.clone() {
+        let servstate = servstate.clone();
+        let smear = config.leap_smear;
+        let leap_logger = logger.new(slog::o!("task"=>"reading leap file"));
+        thread::spawn(move || periodic_read_leap_file(servstate, &path, smear, leap_logger));
+    }
+
     if let Some(metrics_config) = config.metrics_config.clone() {
         info!(logger, "spawning metrics");
         let log_metrics = logger.new(slog::o!("component"=>"metrics"));
         thread::spawn(move || {
-            metrics::run_metrics(metrics_config, &log_metrics)
+            metrics::run_metrics(metrics_config, None, &log_metrics)
                 .expect("metrics could not be run; starting ntp server failed");
         });
     }
 
     let wg = WaitGroup::new();
-    for addr in config.addrs() {
-        let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-        let socket = cfsock::udp_listen(&addr)?;
-        let wg = wg.clone();
-        let logger = logger.new(slog::o!("listen_addr"=>addr));
+    let symmetric_keys = config.symmetric_keys.clone().map(Arc::new);
+    for broadcast in config.broadcasts.clone() {
+        info!(logger, "broadcasting to {} on {}", broadcast.addr, broadcast.interface);
+        let socket = cfsock::udp_broadcast(&broadcast.interface, &broadcast.addr, broadcast.ttl)?;
+        let servstate = servstate.clone();
+        let keys = symmetric_keys.clone();
+        let clock = clock.clone();
+        let broadcast_logger = logger.new(slog::o!("task"=>"broadcasting"));
+        thread::spawn(move || {
+            periodic_broadcast(servstate, broadcast, socket, keys, clock, broadcast_logger)
+        });
+    }
+    // The counters, the clients and their rate limits carry on from before the restart.
+    let restored = match &config.persist {
+        Some(persist) => match persist::read(&persist.path) {
+            Ok(snapshot) => snapshot.map(Arc::new),
+            Err(err) => {
+                warn!(logger, "cannot read the state from {}, starting afresh: {}", persist.path,
+                      err);
+                None
+            },
+        },
+        None => None,
+    };
+    if let Some(snapshot) = &restored {
+        info!(logger, "restoring {} counters, {} clients and {} rate limited clients",
+              snapshot.counters.len(), snapshot.clients.len(), snapshot.limited.len());
+        metrics::restore_counters(snapshot.counters.clone());
+    }
+    // The workers are pinned to the CPUs in turn across all the addresses.
+    let mut cpus = config.worker_cpus.iter().cycle();
+    // Each worker has its own list, and the admin endpoint merges them.
+    let mut mru_tables = Vec::new();
+    // Each worker has its own limiter, and the state that is saved merges them.
+    let mut query_limiters = Vec::new();
+    // The NTS requests are answered by their own threads, if there are any, so that their crypto
+    // doesn't hold up the plain NTP requests. The AF_XDP workers answer all of theirs themselves.
+    let nts_pool = if config.nts_threads > 0 {
+        info!(logger, "answering the NTS requests with {} threads", config.nts_threads);
         let keys = keys.clone();
         let servstate = servstate.clone();
-        info!(logger, "Listening on: {}", socket.local_addr()?);
-        let mut use_ipv4 = true;
-        if let SocketAddr::V6(_) = addr {
-            use_ipv4 = false;
+        Some(Pool::new(config.nts_threads, NTS_QUEUE_SIZE, "nts", move |job| {
+            answer_nts_job(job, &keys, &servstate)
+        })?)
+    } else {
+        None
+    };
+    // The packets of all of the workers go into one capture.
+    let capture = match &config.pcap {
+        Some(pcap) => {
+            info!(logger, "capturing up to {} packets to {} for {:?}", pcap.max_packets,
+                  pcap.path, pcap.duration);
+            Some(Arc::new(Mutex::new(Capture::create(pcap)?)))
+        },
+        None => None,
+    };
+    let mut socket_options = |timestamping| SocketOptions {
+        tunables: tunables.clone(),
+        timestamping,
+        batch_size: config.batch_size,
+        max_cookie_placeholders: config.max_cookie_placeholders,
+        symmetric_keys: symmetric_keys.clone(),
+        replay_protection: config.replay_protection,
+        log_control_packets: config.log_control_packets,
+        io_uring: config.io_uring,
+        udp_offload: config.udp_offload,
+        nts_pool: nts_pool.clone(),
+        capture: capture.clone(),
+        clock: clock.clone(),
+        query_limiter: {
+            let limiter = Arc::new(Mutex::new(None));
+            query_limiters.push(limiter.clone());
+            limiter
+        },
+        restored: restored.clone(),
+        mru: config.mru_size.map(|size| {
+            let mut table = MruTable::new(size);
+            // The merged list of the clients is restored into the first table, so that they
+            // are not counted twice.
+            if let (true, Some(snapshot)) = (mru_tables.is_empty(), &restored) {
+                table.restore(&snapshot.clients);
+            }
+            let table = Arc::new(Mutex::new(table));
+            mru_tables.push(table.clone());
+            table
+        }),
+    };
+    for listen_addr in config.addrs() {
+        let addr = listen_addr.addr;
+        // Each worker has its own socket on the same address, and the kernel spreads the
+        // clients among them. A client always goes to the same one.
+        for worker in 0..config.workers {
+            let socket = cfsock::udp_listen(&addr, config.workers > 1, listen_addr.v6only)?;
+            if let Some(dscp) = config.dscp {
+                cfsock::set_dscp(&socket, &addr, dscp)?;
+            }
+            let wg = wg.clone();
+            let logger = logger.new(slog::o!("listen_addr"=>addr, "worker"=>worker));
+            let keys = keys.clone();
+            let servstate = servstate.clone();
+            let options = socket_options(listen_addr.timestamping);
+            let cpu = cpus.next().cloned();
+            info!(logger, "Listening on: {}", socket.local_addr()?);
+            let mut use_ipv4 = true;
+            if let SocketAddr::V6(_) = addr {
+                use_ipv4 = false;
+            }
+            thread::spawn(move || {
+                if let Some(cpu) = cpu {
+                    if let Err(err) = pin_to_cpu(cpu) {
+                        warn!(logger, "cannot pin the worker to the CPU {}: {}", cpu, err);
+                    }
+                }
+                run_server(socket, keys, servstate, logger, use_ipv4, options)
+                    .expect("server could not be run");
+                drop(wg);
+            });
+        }
+    }
+    if let Some(xdp_config) = &config.xdp {
+        // Each queue of the interface has its own worker.
+        for &queue in &xdp_config.queues {
+            let xsk = Xsk::new(xdp_config, queue)?;
+            let wg = wg.clone();
+            let logger = logger.new(
+                slog::o!("interface"=>xdp_config.interface.clone(), "queue"=>queue)
+            );
+            let keys = keys.clone();
+            let servstate = servstate.clone();
+            let options = socket_options(Timestamping::User);
+            let port = xdp_config.port;
+            let dscp = config.dscp;
+            let cpu = cpus.next().cloned();
+            info!(logger, "Answering with AF_XDP on port {} through {}", port,
+                  xdp_config.xsks_map);
+            thread::spawn(move || {
+                if let Some(cpu) = cpu {
+                    if let Err(err) = pin_to_cpu(cpu) {
+                        warn!(logger, "cannot pin the worker to the CPU {}: {}", cpu, err);
+                    }
+                }
+                run_xdp_server(xsk, keys, servstate, logger, port, dscp, options)
+                    .expect("AF_XDP server could not be run");
+                drop(wg);
+            });
         }
+    }
+    if let Some(persist) = config.persist.clone() {
+        info!(logger, "saving the state to {} every {:?}", persist.path, persist.interval);
+        let mru_tables = mru_tables.clone();
+        let persist_logger = logger.new(slog::o!("component"=>"persist"));
+        // The last state is saved when the server is stopped.
+        signal::install_sigterm_handler()?;
+        thread::spawn(move || {
+            periodic_save_state(persist, mru_tables, query_limiters, persist_logger)
+        });
+    }
+    if let Some(admin_config) = config.admin_config.clone() {
+        info!(logger, "spawning admin endpoint");
+        let keys = keys.clone();
+        let log_admin = logger.new(slog::o!("component"=>"admin"));
+        let clients: Option<ClientList> = if config.mru_size.is_some() {
+            Some(Arc::new(move || mru::format(&mru::merge(&mru_tables))))
+        } else {
+            None
+        };
         thread::spawn(move || {
-            run_server(socket, keys, servstate, logger, use_ipv4)
-                .expect("server could not be run");
-            drop(wg);
+            admin::run_admin(admin_config, keys, clients, &log_admin)
+                .expect("admin endpoint could not be run; starting ntp server failed");
         });
     }
     wg.wait();
     Ok(())
 }
 
+/// Return the current totals of the counters, the most recently used list of the clients and the
+/// clients that have used up some of their rate limit.
+fn take_snapshot(
+    mru_tables: &[Arc<Mutex<MruTable>>],
+    query_limiters: &[Arc<Mutex<Option<RateLimiter>>>],
+) -> Snapshot {
+    // A client could be limited by more than one worker, and the emptiest bucket is kept.
+    let now = Instant::now();
+    let mut limited: HashMap<IpAddr, f64> = HashMap::new();
+    for limiter in query_limiters {
+        if let Some(limiter) = limiter.lock().unwrap().as_ref() {
+            for (addr, tokens) in limiter.buckets(now) {
+                let least = limited.entry(addr).or_insert(tokens);
+                *least = least.min(tokens);
+            }
+        }
+    }
+    Snapshot {
+        time: SystemTime::now(),
+        counters: metrics::counter_samples(),
+        clients: mru::merge(mru_tables),
+        limited: limited.into_iter()
+            .map(|(addr, tokens)| LimitedClient { addr, tokens })
+            .collect(),
+    }
+}
+
+/// Save the state every `persist.interval`, and one last time before exiting when the process
+/// receives SIGTERM. This function never returns.
+fn periodic_save_state(
+    persist: PersistConfig,
+    mru_tables: Vec<Arc<Mutex<MruTable>>>,
+    query_limiters: Vec<Arc<Mutex<Option<RateLimiter>>>>,
+    logger: slog::Logger,
+) {
+    let mut saved = Instant::now();
+    loop {
+        thread::sleep(SIGNAL_CHECK_INTERVAL);
+        let stopping = signal::sigterm_received();
+        if !stopping && saved.elapsed() < persist.interval {
+            continue;
+        }
+        let snapshot = take_snapshot(&mru_tables, &query_limiters);
+        if let Err(err) = persist::write(&persist.path, &snapshot) {
+            error!(logger, "cannot save the state to {}: {}", persist.path, err);
+        }
+        saved = Instant::now();
+        if stopping {
+            info!(logger, "saved the state to {}, exiting", persist.path);
+            std::process::exit(0);
+        }
+    }
+}
+
+/// Set the stratum fields that describe the source of the system clock when it's trusted.
+fn set_static_state(state: &mut ServerState, config: &NtpServerConfig) {
+    state.stratum = config.stratum;
+    state.refid = config.refid;
+    state.root_delay = seconds_to_short(config.root_delay);
+    state.root_dispersion = seconds_to_short(config.root_dispersion);
+}
+
+/// Parse the config file at `path` again every time that the process receives SIGHUP, and apply
+/// the tunables, and the stratum fields to `servstate` if it's given. A config that cannot be
+/// parsed is ignored. This function never returns.
+fn reload_on_sighup(
+    path: &str,
+    tunables: Arc<SharedTunables>,
+    servstate: Option<Arc<RwLock<ServerState>>>,
+    logger: slog::Logger,
+) {
+    let mut last_count = signal::sighup_count();
+
+    loop {
+        thread::sleep(SIGNAL_CHECK_INTERVAL);
+
+        let count = signal::sighup_count();
+        if count == last_count {
+            continue;
+        }
+        last_count = count;
+
+        info!(logger, "reloading the config from {}", path);
+        let config = match NtpServerConfig::parse(path) {
+            Ok(config) => config,
+            Err(error) => {
+                error!(logger, "failed to reload the config, keeping the old one: {}", error);
+                continue;
+            },
+        };
+        tunables.store(Tunables::from_config(&config));
+        if let Some(servstate) = &servstate {
+            let mut state = servstate.write().unwrap();
+            set_static_state(&mut state, &config);
+        }
+        info!(logger, "reloaded the config with stratum {}", config.stratum);
+    }
+}
+
+/// Pin the current thread to `cpu`.
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> Result<(), std::io::Error> {
+    // This is safe because the set is a plain bit mask that lives on the stack.
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> Result<(), std::io::Error> {
+    Ok(()) // no op for mac build
+}
+
 /// Compute the current dispersion to within 1 ULP.
 fn fix_dispersion(disp: u32, now: SystemTime, taken: SystemTime) -> u32 {
     let disp_frac = (disp & 0x0000ffff) as f64;
@@ -301,6 +1618,98 @@ fn fix_dispersion(disp: u32, now: SystemTime, taken: SystemTime) -> u32 {
     }
 }
 
+/// Convert an NTP short (16.16) to the 4.28 format of NTPv5, which saturates at 16 seconds.
+fn short_to_v5(value: u32) -> u32 {
+    if value >= 16 << 16 {
+        std::u32::MAX
+    } else {
+        value << 12
+    }
+}
+
+/// Return the number of seconds since the NTP epoch, without wrapping around at the end of the era.
+fn ntp_seconds(time: SystemTime) -> u64 {
+    // Safe absent time machines
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + UNIX_OFFSET
+}
+
+/// Return the leap indicator at `time`. In the day before a leap second in the leap file, the
+/// leap second is announced, unless the server is not synchronized. Otherwise, the one that the
+/// clock announces is, if the upstream servers don't announce any.
+fn leap_indicator(servstate: &ServerState, clock: &dyn ClockSource, time: SystemTime)
+    -> LeapState
+{
+    match servstate.leap_event {
+        Some(event) if servstate.leap != Unknown && event.is_announced(ntp_seconds(time)) => {
+            event.kind
+        },
+        _ if servstate.leap == NoLeap => clock.leap(),
+        _ => servstate.leap,
+    }
+}
+
+/// Return `time` as it's served, which follows the upstream servers, and is smeared in the smear
+/// window of a leap second.
+fn served_time(servstate: &ServerState, time: SystemTime) -> SystemTime {
+    let time = match servstate.leap_smear {
+        Some(smear) => smear.apply(time),
+        None => time,
+    };
+    if servstate.offset >= 0.0 {
+        time + Duration::from_secs_f64(servstate.offset)
+    } else {
+        time - Duration::from_secs_f64(-servstate.offset)
+    }
+}
+
+/// Read the leap file periodically, so that it can be updated without restarting the server,
+/// and keep track of the next leap second and the TAI-UTC offset. If `smear` is set, the next
+/// leap second is smeared instead of announced.
+fn periodic_read_leap_file(
+    servstate: Arc<RwLock<ServerState>>,
+    path: &str,
+    smear: Option<SmearConfig>,
+    logger: slog::Logger,
+) {
+    loop {
+        match LeapTable::read(path) {
+            Ok(table) => {
+                let now = ntp_seconds(SystemTime::now());
+                if table.expires <= now {
+                    warn!(logger, "the leap file {} has expired", path);
+                }
+                LEAP_FILE_EXPIRY_GAUGE.set(table.expires as i64 - UNIX_OFFSET as i64);
+                if let Some(offset) = table.tai_offset(now) {
+                    TAI_OFFSET_GAUGE.set(i64::from(offset));
+                }
+
+                let leap_event = table.next_leap(now);
+                let mut state = servstate.write().unwrap();
+                if let Some(config) = smear {
+                    let current = state.leap_smear.filter(|s| !s.is_over(SystemTime::now()));
+                    state.leap_smear = match (current, leap_event) {
+                        // The smear is created again until right before the leap second, so
+                        // that the clocks don't drift apart, and kept until the end of the window.
+                        (Some(current), _) if current.event().at + UNIX_OFFSET <= now + 1 => {
+                            Some(current)
+                        },
+                        (_, Some(event)) => Some(LeapSmear::new(config, event, UNIX_OFFSET)),
+                        (_, None) => None,
+                    };
+                    if current.map(|s| s.event()) != state.leap_smear.map(|s| s.event()) {
+                        info!(logger, "the next leap second to smear is {:?}", leap_event);
+                    }
+                } else if state.leap_event != leap_event {
+                    info!(logger, "the next leap second is {:?}", leap_event);
+                    state.leap_event = leap_event;
+                }
+            },
+            Err(error) => warn!(logger, "cannot read the leap file {}: {}", path, error),
+        }
+        thread::sleep(LEAP_FILE_CHECK_INTERVAL);
+    }
+}
+
 fn ntp_timestamp(time: SystemTime) -> u64 {
     let unix_time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap(); // Safe absent time machines
     let unix_offset = Duration::new(UNIX_OFFSET, 0);
@@ -317,16 +1726,17 @@ fn create_header(
     received: SystemTime,
     transmit: SystemTime,
     servstate: Arc<RwLock<ServerState>>,
+    clock: &dyn ClockSource,
 ) -> NtpPacketHeader {
     let servstate = servstate.read().unwrap();
-    let receive_timestamp = ntp_timestamp(received);
-    let transmit_timestamp = ntp_timestamp(transmit);
+    let receive_timestamp = ntp_timestamp(served_time(&servstate, received));
+    let transmit_timestamp = ntp_timestamp(served_time(&servstate, transmit));
     NtpPacketHeader {
-        leap_indicator: servstate.leap,
+        leap_indicator: leap_indicator(&servstate, clock, transmit),
         version: servstate.version,
         mode: PacketMode::Server,
-        poll: servstate.poll,
-        precision: servstate.precision,
+        poll: servstate.advertised_poll(),
+        precision: servstate.advertised_precision(),
         stratum: servstate.stratum,
         root_delay: servstate.root_delay,
         root_dispersion: fix_dispersion(servstate.root_dispersion, transmit, servstate.taken),
@@ -338,16 +1748,131 @@ fn create_header(
     }
 }
 
+/// Create the header of a broadcast packet, or return `None` if the server is not synchronized.
+/// There is no request, so the origin and receive timestamps are zero.
+fn broadcast_header(
+    servstate: &ServerState,
+    clock: &dyn ClockSource,
+    poll: i8,
+    transmit: SystemTime,
+) -> Option<NtpPacketHeader> {
+    if servstate.leap == Unknown {
+        return None;
+    }
+    Some(NtpPacketHeader {
+        leap_indicator: leap_indicator(servstate, clock, transmit),
+        version: servstate.version,
+        mode: PacketMode::Broadcast,
+        poll,
+        precision: servstate.advertised_precision(),
+        stratum: servstate.stratum,
+        root_delay: servstate.root_delay,
+        root_dispersion: fix_dispersion(servstate.root_dispersion, transmit, servstate.taken),
+        reference_id: servstate.refid,
+        reference_timestamp: servstate.refstamp,
+        origin_timestamp: 0,
+        receive_timestamp: 0,
+        transmit_timestamp: ntp_timestamp(served_time(servstate, transmit)),
+    })
+}
+
+/// Send a broadcast packet on the socket every interval of `config`, signed with its key if it
+/// has one. Nothing is sent while the server is not synchronized.
+fn periodic_broadcast(
+    servstate: Arc<RwLock<ServerState>>,
+    config: BroadcastConfig,
+    socket: UdpSocket,
+    keys: Option<Arc<SymmetricKeys>>,
+    clock: Arc<dyn ClockSource>,
+    logger: slog::Logger,
+) {
+    // The poll field is the interval in log2 seconds, rounded down.
+    let poll = (63 - config.interval.as_secs().leading_zeros()) as i8;
+    loop {
+        let servstate = *servstate.read().unwrap();
+        let header = broadcast_header(&servstate, clock.as_ref(), poll, clock.now());
+        if let Some(header) = header {
+            let mut packet = serialize_header(header);
+            if let (Some(key_id), Some(keys)) = (config.key_id, &keys) {
+                keys.sign(key_id, &mut packet);
+            }
+            match socket.send_to(&packet, config.addr) {
+                Ok(_) => BROADCAST_COUNTER.with_label_values(&[&config.interface]).inc(),
+                Err(err) => warn!(logger, "cannot broadcast on {}: {}", config.interface, err),
+            }
+        }
+        thread::sleep(config.interval);
+    }
+}
+
+/// The optional features that apply to a request.
+struct RequestOptions<'a> {
+    /// Whether NTPv5 is enabled.
+    ntpv5: bool,
+
+    /// The log of the interleaved mode and the address of the client, if the mode is enabled.
+    /// It's only locked to look up the previous transmit time, not during the crypto.
+    interleaved: Option<(&'a Mutex<InterleavedLog>, IpAddr)>,
+
+    /// The most cookie placeholders in an NTS request that get a new cookie.
+    max_cookie_placeholders: usize,
+
+    /// The keys of the legacy MACs, if they are enabled.
+    symmetric_keys: Option<&'a SymmetricKeys>,
+
+    /// The cache of the NTS unique identifiers and the address of the client, if the replays
+    /// are dropped. It's only locked to check the identifier.
+    replay: Option<(&'a Mutex<ReplayCache>, IpAddr)>,
+
+    /// The clock that the time is read from.
+    clock: &'a dyn ClockSource,
+}
+
 fn response(
     query: &[u8],
     r_time: SystemTime,
     t_time: SystemTime,
     cookie_keys: Arc<RwLock<KeyRotator>>,
     servstate: Arc<RwLock<ServerState>>,
-    logger: slog::Logger,
-) -> Result<Vec<u8>, std::io::Error> {
+    logger: ClientLogger,
+    options: RequestOptions,
+) -> Result<(Vec<u8>, QueryPath), std::io::Error> {
+    let query_len = query.len();
+    // Without NTPv5, the NTPv5 requests are answered as if they were NTPv4 ones, like before.
+    if options.ntpv5 && protocol::packet_version(query) == Some(protocol::VERSION_5) {
+        QUERY_COUNTER.inc();
+        NTPV5_COUNTER.inc();
+        let resp = ntpv5_response(query, r_time, t_time, servstate, options.clock)?;
+        return Ok((no_larger(resp, query_len)?, QueryPath::ok(false)));
+    }
+
+    // The legacy MAC is not an extension field, so it's removed before the packet is parsed.
+    let mac_check = match options.symmetric_keys {
+        Some(keys) => keys.check(query),
+        None => MacCheck::None(query),
+    };
+    let query = match mac_check {
+        MacCheck::None(query) | MacCheck::Valid(query, _) | MacCheck::Invalid(query) => query,
+    };
+
     let query_packet = parse_ntp_packet(query)?; // Should try to send a KOD if this happens
-    let resp_header = create_header(&query_packet, r_time, t_time, servstate);
+    let mut resp_header = create_header(&query_packet, r_time, t_time, servstate, options.clock);
+    // Tell the clients that ask that they can switch to NTPv5.
+    let magic = protocol::NTPV5_NEGOTIATION_MAGIC;
+    if options.ntpv5 && query_packet.header.reference_timestamp == magic {
+        resp_header.reference_timestamp = magic;
+    }
+    // In the interleaved mode, the client gets the transmit timestamp of the previous response
+    // instead of this one, and the origin timestamp is the receive timestamp that the client sent,
+    // so that it can tell the modes apart.
+    let previous_transmit = options.interleaved.and_then(|(log, client)| {
+        log.lock().unwrap().previous_transmit(client, query_packet.header.origin_timestamp)
+    });
+    if let Some(transmit_timestamp) = previous_transmit {
+        INTERLEAVED_COUNTER.inc();
+        resp_header.origin_timestamp = query_packet.header.receive_timestamp;
+        resp_header.transmit_timestamp = transmit_timestamp;
+    }
 
     QUERY_COUNTER.inc();
 
@@ -358,62 +1883,179 @@ fn response(
         NTS_COUNTER.inc();
         let cookie = extract_extension(&query_packet, NTSCookie).unwrap();
         let keyid_maybe = get_keyid(&cookie.contents);
-        match keyid_maybe {
+        // It's why the request cannot be authenticated if it cannot.
+        let resp = match keyid_maybe {
             Some(keyid) => {
                 let point = cookie_keys.read().unwrap();
                 let key_maybe = (*point).get(keyid);
                 match key_maybe {
                     Some(key) => {
-                        let nts_keys = eat_cookie(&cookie.contents, key.as_ref());
+                        // The cookie may be made under the previous master key, if it has just
+                        // changed.
+                        let nts_keys = eat_cookie(&cookie.contents, key.as_ref())
+                            .or_else(|| {
+                                let key = (*point).get_previous(keyid)?;
+                                eat_cookie(&cookie.contents, key.as_ref())
+                            });
                         match nts_keys {
                             Some(nts_dir_keys) => {
-                                Ok(process_nts(
+                                process_nts(
                                     resp_header,
                                     nts_dir_keys,
                                     cookie_keys.clone(),
                                     query,
-                                ))
+                                    options.max_cookie_placeholders,
+                                    options.replay,
+                                )?.ok_or(NAK_UNAUTHENTICATED)
                             },
                             None => {
                                 UNDECRYPTABLE_COOKIE_COUNTER.inc();
-                                error!(logger, "undecryptable cookie with keyid {:x?}", keyid);
-                                send_kiss_of_death(query_packet)
+                                error!(logger.get(), "undecryptable cookie with keyid {:x?}",
+                                       keyid);
+                                Err(NAK_UNDECRYPTABLE_COOKIE)
                             }
                         }
                     }
                     None => {
                         MISSING_KEY_COUNTER.inc();
-                        error!(logger, "cannot access key {:x?}", keyid);
-                        send_kiss_of_death(query_packet)
+                        error!(logger.get(), "cannot access key {:x?}", keyid);
+                        Err(NAK_MISSING_KEY)
                     }
                 }
             }
             None => {
                 MALFORMED_COOKIE_COUNTER.inc();
-                error!(logger, "malformed cookie");
-                send_kiss_of_death(query_packet)
+                error!(logger.get(), "malformed cookie");
+                Err(NAK_MALFORMED_COOKIE)
             }
+        };
+        match resp {
+            Ok(resp) => Ok((resp, QueryPath::ok(true))),
+            Err(reason) => {
+                NTS_NAK_COUNTER.with_label_values(&[reason]).inc();
+                Ok((no_larger(nts_nak(query_packet), query_len)?, QueryPath::auth_failure(true)))
+            },
         }
     } else {
-        Ok(serialize_header(resp_header))
+        let mut resp = serialize_header(resp_header);
+        let mut path = QueryPath::ok(false);
+        match (mac_check, options.symmetric_keys) {
+            (MacCheck::Valid(_, key_id), Some(keys)) => {
+                keys.sign(key_id, &mut resp);
+                return Ok((resp, path));
+            },
+            // The crypto-NAK is a MAC with the key ID 0 and no digest.
+            (MacCheck::Invalid(_), _) => {
+                MAC_FAILURE_COUNTER.inc();
+                resp.extend(&[0; 4]);
+                path = QueryPath::auth_failure(false);
+            },
+            _ => (),
+        }
+        Ok((no_larger(resp, query_len)?, path))
     }
 }
 
+/// Respond to an NTPv5 request. The extensions, including NTS, are not supported yet, so they are
+/// ignored and the response has none. Only UTC is served, whatever timescale is asked for.
+fn ntpv5_response(
+    query: &[u8],
+    r_time: SystemTime,
+    t_time: SystemTime,
+    servstate: Arc<RwLock<ServerState>>,
+    clock: &dyn ClockSource,
+) -> Result<Vec<u8>, std::io::Error> {
+    let query_header = protocol::parse_v5_packet_header(query)?;
+    if query_header.mode != PacketMode::Client {
+        return Err(Error::new(ErrorKind::InvalidData, "not client mode"));
+    }
+
+    let servstate = servstate.read().unwrap();
+    let flags = if servstate.leap == Unknown { protocol::V5_FLAG_UNKNOWN_LEAP } else { 0 };
+    Ok(protocol::serialize_v5_header(NtpV5PacketHeader {
+        leap_indicator: leap_indicator(&servstate, clock, t_time),
+        version: protocol::VERSION_5,
+        mode: PacketMode::Server,
+        stratum: servstate.stratum,
+        poll: servstate.advertised_poll(),
+        precision: servstate.advertised_precision(),
+        timescale: protocol::V5_TIMESCALE_UTC,
+        era: clock.era(r_time),
+        flags,
+        root_delay: short_to_v5(servstate.root_delay),
+        root_dispersion: short_to_v5(
+            fix_dispersion(servstate.root_dispersion, t_time, servstate.taken)
+        ),
+        // The server cookie is only for the interleaved mode.
+        server_cookie: 0,
+        client_cookie: query_header.client_cookie,
+        receive_timestamp: ntp_timestamp(served_time(&servstate, r_time)),
+        transmit_timestamp: ntp_timestamp(served_time(&servstate, t_time)),
+    }))
+}
+
 fn process_nts(
     resp_header: NtpPacketHeader,
     keys: NTSKeys,
     cookie_keys: Arc<RwLock<KeyRotator>>,
     query_raw: &[u8],
-) -> Vec<u8> {
-    let mut recv_aead = Aes128SivAead::new(&keys.c2s);
-    let mut send_aead = Aes128SivAead::new(&keys.s2c);
-    let query = parse_nts_packet::<Aes128SivAead>(query_raw, &mut recv_aead);
-    match query {
-        Ok(packet) => serialize_nts_packet(
-            nts_response(packet, resp_header, keys, cookie_keys),
-            &mut send_aead,
+    max_placeholders: usize,
+    replay: Option<(&Mutex<ReplayCache>, IpAddr)>,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    // The cookie tells us which AEAD algorithm was negotiated in NTS-KE.
+    match keys.aead {
+        KnownAeadAlgorithm::AeadAesSivCmac256 => process_nts_with::<Aes128SivAead>(
+            resp_header, keys, cookie_keys, query_raw, max_placeholders, replay,
+        ),
+        KnownAeadAlgorithm::AeadAes128GcmSiv => process_nts_with::<Aes128GcmSivAead>(
+            resp_header, keys, cookie_keys, query_raw, max_placeholders, replay,
         ),
-        Err(_) => serialize_ntp_packet(kiss_of_death(parse_ntp_packet(query_raw).unwrap())),
+    }
+}
+
+fn process_nts_with<T: NtsAead>(
+    resp_header: NtpPacketHeader,
+    keys: NTSKeys,
+    cookie_keys: Arc<RwLock<KeyRotator>>,
+    query_raw: &[u8],
+    max_placeholders: usize,
+    replay: Option<(&Mutex<ReplayCache>, IpAddr)>,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let key_len = keys.aead.key_len();
+    let mut recv_aead = T::new(&keys.c2s[..key_len]);
+    let mut send_aead = T::new(&keys.s2c[..key_len]);
+    let query = parse_nts_packet::<T>(query_raw, &mut recv_aead);
+    match query {
+        Ok(packet) => {
+            // The unique identifier is authenticated, so only the client could have sent it.
+            let identifier = packet.auth_exts.iter()
+                .find(|ext| ext.ext_type == protocol::NtpExtensionType::UniqueIdentifier);
+            if let (Some((cache, client)), Some(identifier)) = (replay, identifier) {
+                if cache.lock().unwrap().is_replay(client, &identifier.contents) {
+                    REPLAY_COUNTER.inc();
+                    return Err(Error::new(ErrorKind::AlreadyExists, "replayed request"));
+                }
+            }
+            let mut resp_packet =
+                nts_response(packet, resp_header, keys, cookie_keys, max_placeholders);
+            let mut resp = serialize_nts_packet(resp_packet.clone(), &mut send_aead);
+            // Each cookie makes the ciphertext longer by its extension field, and there is no
+            // padding since the cookies are padded already. The cookie that replaces the one in
+            // the request is always sent.
+            if resp.len() > query_raw.len() {
+                let cookie_len = 4 + cookie_size(keys.aead);
+                let excess = (resp.len() - query_raw.len() + cookie_len - 1) / cookie_len;
+                let cookies = resp_packet.auth_enc_exts.len();
+                resp_packet.auth_enc_exts.truncate(cookies.saturating_sub(excess).max(1));
+                if resp_packet.auth_enc_exts.len() < cookies {
+                    CLAMPED_RESPONSE_COUNTER.with_label_values(&["truncated"]).inc();
+                    resp = serialize_nts_packet(resp_packet, &mut send_aead);
+                }
+            }
+            Ok(Some(resp))
+        },
+        // The request doesn't authenticate under the keys in the cookie.
+        Err(_) => Ok(None),
     }
 }
 
@@ -422,49 +2064,61 @@ fn nts_response(
     header: NtpPacketHeader,
     keys: NTSKeys,
     cookie_keys: Arc<RwLock<KeyRotator>>,
+    max_placeholders: usize,
 ) -> NtsPacket {
     let mut resp_packet = NtsPacket {
         header: header,
         auth_exts: vec![],
         auth_enc_exts: vec![],
     };
+    // Each placeholder asks for one more cookie. Only the ones at least as big as a cookie
+    // count, so that the response is not bigger than the query.
+    let mut placeholders = 0;
     for ext in query.auth_exts {
         match ext.ext_type {
             protocol::NtpExtensionType::UniqueIdentifier => resp_packet.auth_exts.push(ext),
             protocol::NtpExtensionType::NTSCookiePlaceholder => {
-                if ext.contents.len() >= COOKIE_SIZE {
-                    // Avoid amplification
-                    let keymaker = cookie_keys.read().unwrap();
-                    let (key_id, curr_key) = keymaker.latest_key_value();
-                    let cookie = make_cookie(keys, curr_key.as_ref(), key_id);
-                    resp_packet.auth_enc_exts.push(NtpExtension {
-                        ext_type: NTSCookie,
-                        contents: cookie,
-                    })
+                if ext.contents.len() >= cookie_size(keys.aead) {
+                    placeholders += 1;
                 }
             }
             _ => {}
         }
     }
-    // This is a free cookie to replace the one consumed in the packet
+
+    // The first cookie is a free one to replace the one consumed in the packet.
     let keymaker = cookie_keys.read().unwrap();
     let (key_id, curr_key) = keymaker.latest_key_value();
-    let cookie = make_cookie(keys, curr_key.as_ref(), key_id);
-    resp_packet.auth_enc_exts.push(NtpExtension {
-        ext_type: NTSCookie,
-        contents: cookie,
-    });
+    for _ in 0..=placeholders.min(max_placeholders) {
+        let cookie = make_cookie(keys, curr_key.as_ref(), key_id);
+        resp_packet.auth_enc_exts.push(NtpExtension {
+            ext_type: NTSCookie,
+            contents: cookie,
+        });
+    }
     resp_packet
 }
 
-fn send_kiss_of_death(query_packet: NtpPacket) -> Result<Vec<u8>, std::io::Error> {
-    let resp = kiss_of_death(query_packet);
-    Ok(serialize_ntp_packet(resp))
+/// Return the response to a request that is not authenticated, or drop it if it's larger than the
+/// request, so that the server cannot be used to amplify the traffic sent to a spoofed address.
+fn no_larger(resp: Vec<u8>, query_len: usize) -> Result<Vec<u8>, std::io::Error> {
+    if resp.len() > query_len {
+        CLAMPED_RESPONSE_COUNTER.with_label_values(&["dropped"]).inc();
+        return Err(Error::new(ErrorKind::PermissionDenied, "response larger than the request"));
+    }
+    Ok(resp)
+}
+
+/// The NTS NAK of RFC 8915, which tells the client that its request could not be authenticated,
+/// so that it can get new cookies from the NTS-KE server. It's a kiss of death with the code
+/// NTSN, and the unique identifier of the request as the only extension field.
+fn nts_nak(query_packet: NtpPacket) -> Vec<u8> {
+    serialize_ntp_packet(kiss_of_death(query_packet, KOD_NTSN))
 }
 
 /// The kiss of death tells the client it has done something wrong.
 /// draft-ietf-ntp-using-nts-for-ntp-18 and RFC 5905 specify the format.
-fn kiss_of_death(query_packet: NtpPacket) -> NtpPacket {
+fn kiss_of_death(query_packet: NtpPacket, code: u32) -> NtpPacket {
     KOD_COUNTER.inc();
     let kod_header = NtpPacketHeader {
         leap_indicator: LeapState::Unknown,
@@ -475,7 +2129,7 @@ fn kiss_of_death(query_packet: NtpPacket) -> NtpPacket {
         stratum: 0,
         root_delay: 0,
         root_dispersion: 0,
-        reference_id: 0x4e54534e, // NTSN
+        reference_id: code,
         reference_timestamp: 0,
         origin_timestamp: query_packet.header.transmit_timestamp,
         receive_timestamp: 0,
@@ -558,3 +2212,192 @@ fn refresh_servstate(
         thread::sleep(time::Duration::from_secs(1));
     }
 }
+
+/// Convert seconds to the NTP short format (16.16), which saturates.
+fn seconds_to_short(seconds: f64) -> u32 {
+    (seconds * TWO_POW_16).max(0.0).min(f64::from(std::u32::MAX)) as u32
+}
+
+/// Return the reference ID of an upstream server at `ip`. It's the IPv4 address, or the first
+/// four bytes of a hash of the IPv6 address.
+fn upstream_refid(ip: IpAddr) -> u32 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip),
+        IpAddr::V6(ip) => {
+            let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &ip.octets());
+            let mut refid = [0; 4];
+            refid.copy_from_slice(&hash.as_ref()[..4]);
+            u32::from_be_bytes(refid)
+        },
+    }
+}
+
+/// Run one exchange with `upstream`. Over NTS, the key exchange is done again when `nts_state`
+/// runs out of cookies or the exchange fails.
+fn poll_upstream(
+    upstream: &UpstreamServer,
+    nts_state: &mut Option<NtsKeResult>,
+    logger: &slog::Logger,
+) -> Result<NtpResult, Box<dyn std::error::Error>> {
+    if !upstream.nts {
+        let addr = match (upstream.host.as_str(), upstream.port).to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(Box::new(Error::new(ErrorKind::NotFound, "no address found"))),
+        };
+        return run_ntp_client(addr);
+    }
+
+    let mut state = match nts_state.take() {
+        Some(state) if !state.cookies.is_empty() => state,
+        _ => run_nts_ke_client(logger, ClientConfig {
+            host: upstream.host.clone(),
+            port: Some(upstream.port.to_string()),
+            trusted_certs: upstream.trusted_cert.iter().cloned().collect(),
+            pins: Vec::new(),
+            insecure: false,
+            use_ipv4: None,
+            key_log: false,
+        })?,
+    };
+    // Each cookie is used once, and the server sends new ones in the response. The state is
+    // dropped if the exchange fails, or the cookies run out.
+    let result = run_nts_ntp_client(logger, &mut state)?;
+    *nts_state = Some(state);
+    Ok(result)
+}
+
+/// Select the sources out of the best samples of the reachable ones, and update the offset of the
+/// served time and the synchronization state that is advertised to the clients.
+fn update_synchronization(
+    servstate: &RwLock<ServerState>,
+    discipline: &mut Discipline,
+    candidates: &[Sample],
+    poll: Duration,
+    logger: &slog::Logger,
+) {
+    let now = SystemTime::now();
+    let mut state = servstate.write().unwrap();
+    match upstream::select(candidates) {
+        Some((peer, offset)) => {
+            state.offset = discipline.update(offset);
+            UPSTREAM_OFFSET_GAUGE.set(state.offset);
+            state.leap = peer.leap;
+            state.stratum = peer.stratum + 1;
+            state.poll = (poll.as_secs() as f64).log2().round() as i8;
+            state.root_delay = seconds_to_short(peer.root_delay + peer.delay);
+            state.root_dispersion = seconds_to_short(peer.root_dispersion);
+            state.refid = peer.refid;
+            state.refstamp = ntp_timestamp(served_time(&state, now));
+            state.taken = now;
+        },
+        None => {
+            if state.stratum != 16 {
+                warn!(logger, "lost the synchronization to the time sources");
+            }
+            state.leap = Unknown;
+            state.stratum = 16;
+        },
+    }
+}
+
+/// Poll the upstream servers every `poll`, and update the offset of the served time and the
+/// synchronization state that is advertised to the clients.
+fn periodic_poll_upstreams(
+    servstate: Arc<RwLock<ServerState>>,
+    upstreams: Vec<UpstreamServer>,
+    poll: Duration,
+    logger: slog::Logger,
+) {
+    let mut filters: Vec<Filter> = upstreams.iter().map(|_| Filter::default()).collect();
+    let mut nts_states: Vec<Option<NtsKeResult>> = vec![None; upstreams.len()];
+    let mut discipline = Discipline::default();
+    loop {
+        let mut candidates = Vec::new();
+        for (i, upstream) in upstreams.iter().enumerate() {
+            UPSTREAM_QUERY_COUNTER.inc();
+            let sample = match poll_upstream(upstream, &mut nts_states[i], &logger) {
+                // The stratum 0 is a kiss of death.
+                Ok(result) if result.stratum == 0 => {
+                    UPSTREAM_FAILURE_COUNTER.inc();
+                    warn!(logger, "kiss of death from the upstream {}", upstream.host);
+                    None
+                },
+                Ok(result) => Some(Sample {
+                    offset: result.time_diff,
+                    delay: result.delay,
+                    stratum: result.stratum,
+                    leap: result.leap,
+                    root_delay: result.root_delay,
+                    root_dispersion: result.root_dispersion,
+                    refid: upstream_refid(result.server.ip()),
+                }),
+                Err(err) => {
+                    UPSTREAM_FAILURE_COUNTER.inc();
+                    warn!(logger, "cannot poll the upstream {}: {}", upstream.host, err);
+                    None
+                },
+            };
+            filters[i].add(sample);
+            if let Some((mut best, jitter)) = filters[i].best() {
+                best.root_dispersion += jitter;
+                candidates.push(best);
+            }
+        }
+
+        update_synchronization(&servstate, &mut discipline, &candidates, poll, &logger);
+        thread::sleep(poll);
+    }
+}
+
+/// Read the reference clocks every second, and update the offset of the served time and the
+/// synchronization state that is advertised to the clients.
+fn periodic_poll_refclocks(
+    servstate: Arc<RwLock<ServerState>>,
+    configs: Vec<RefclockConfig>,
+    logger: slog::Logger,
+) {
+    let mut refclocks: Vec<Option<Refclock>> = configs.iter().map(|_| None).collect();
+    let mut filters: Vec<Filter> = configs.iter().map(|_| Filter::default()).collect();
+    let mut discipline = Discipline::default();
+    loop {
+        let mut candidates = Vec::new();
+        for (i, config) in configs.iter().enumerate() {
+            // The clock is opened again after an error, since gpsd or the device may come back.
+            if refclocks[i].is_none() {
+                match Refclock::open(&config.source) {
+                    Ok(refclock) => refclocks[i] = Some(refclock),
+                    Err(err) => warn!(logger, "cannot open the refclock {}: {}", config.name, err),
+                }
+            }
+            let sample = match refclocks[i].as_mut().map(Refclock::read) {
+                Some(Ok(sample)) => sample,
+                Some(Err(err)) => {
+                    warn!(logger, "cannot read the refclock {}: {}", config.name, err);
+                    refclocks[i] = None;
+                    None
+                },
+                None => None,
+            };
+            filters[i].add(sample.map(|sample| {
+                let offset = sample.offset + config.offset;
+                REFCLOCK_OFFSET_GAUGE.with_label_values(&[&config.name]).set(offset);
+                Sample {
+                    offset,
+                    delay: 0.0,
+                    stratum: 0,
+                    leap: sample.leap,
+                    root_delay: 0.0,
+                    root_dispersion: REFCLOCK_DISPERSION,
+                    refid: config.refid,
+                }
+            }));
+            if let Some((mut best, jitter)) = filters[i].best() {
+                best.root_dispersion += jitter;
+                candidates.push(best);
+            }
+        }
+
+        update_synchronization(&servstate, &mut discipline, &candidates, REFCLOCK_POLL, &logger);
+        thread::sleep(REFCLOCK_POLL);
+    }
+}
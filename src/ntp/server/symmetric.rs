@@ -0,0 +1,233 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The legacy symmetric-key authentication of RFC 5905, for the clients that cannot use NTS. The
+//! request ends with a key ID and a digest, which is the SHA-1 hash of the key and the packet or
+//! the AES-CMAC of the packet as in RFC 8573, and the response is signed with the same key.
+//!
+//! The keys are read from a file in the format of ntp.keys. Each line has a key ID from 1 to
+//! 65535, the type SHA1 or AES128CMAC, and the key, which is ASCII if it's up to 20 characters
+//! and hex otherwise.
+
+use aesni::Aes128;
+use cmac::{Cmac, Mac};
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+/// The length of the NTP header that the MAC follows.
+const HEADER_LEN: usize = 48;
+
+/// The length of the key ID at the beginning of the MAC.
+const KEY_ID_LEN: usize = 4;
+
+/// The longest key that is taken as ASCII rather than hex.
+const MAX_ASCII_KEY_LEN: usize = 20;
+
+/// The highest key ID in a keys file.
+const MAX_KEY_ID: u32 = 65535;
+
+/// The digest algorithm of a key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacAlgorithm {
+    Sha1,
+    AesCmac,
+}
+
+impl MacAlgorithm {
+    /// Return the length of the digest in bytes.
+    fn digest_len(self) -> usize {
+        match self {
+            MacAlgorithm::Sha1 => 20,
+            MacAlgorithm::AesCmac => 16,
+        }
+    }
+}
+
+/// The result of checking the MAC at the end of a request.
+#[derive(Debug, PartialEq)]
+pub enum MacCheck<'a> {
+    /// The request has no MAC. It's the whole packet.
+    None(&'a [u8]),
+    /// The MAC is valid under the key ID, and the request is the packet without it.
+    Valid(&'a [u8], u32),
+    /// The key is unknown or the digest doesn't match. The request is the packet without the
+    /// MAC, and the response is a crypto-NAK.
+    Invalid(&'a [u8]),
+}
+
+/// Return an `InvalidData` error.
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Parse the key of a line, which is ASCII if it's short and hex otherwise.
+fn parse_key(key: &str, line: &str) -> Result<Vec<u8>, Error> {
+    if key.len() <= MAX_ASCII_KEY_LEN {
+        return Ok(key.as_bytes().to_vec());
+    }
+    if key.len() % 2 != 0 {
+        return Err(invalid(format!("invalid key in keys file line: {}", line)));
+    }
+    (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid(format!("invalid key in keys file line: {}", line)))
+}
+
+/// Return the digest of `message` under the key.
+fn digest(algorithm: MacAlgorithm, key: &[u8], message: &[u8]) -> Vec<u8> {
+    match algorithm {
+        MacAlgorithm::Sha1 => {
+            let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+            context.update(key);
+            context.update(message);
+            context.finish().as_ref().to_vec()
+        },
+        MacAlgorithm::AesCmac => {
+            // The length of the AES-CMAC keys is checked when they are parsed.
+            let mut mac = Cmac::<Aes128>::new_varkey(key).expect("AES-CMAC key is not 16 bytes");
+            mac.input(message);
+            mac.result().code().to_vec()
+        },
+    }
+}
+
+/// The symmetric keys by their key IDs.
+#[derive(Clone, Debug, Default)]
+pub struct SymmetricKeys {
+    keys: HashMap<u32, (MacAlgorithm, Vec<u8>)>,
+}
+
+impl SymmetricKeys {
+    /// Parse a keys file. The text after a `#` is a comment.
+    pub fn parse(text: &str) -> Result<SymmetricKeys, Error> {
+        let mut keys = HashMap::new();
+        for line in text.lines() {
+            let data = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = data.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() != 3 {
+                return Err(invalid(format!("invalid keys file line: {}", line)));
+            }
+            let key_id = match fields[0].parse() {
+                Ok(key_id) if key_id > 0 && key_id <= MAX_KEY_ID => key_id,
+                _ => return Err(invalid(format!("invalid key ID in keys file line: {}", line))),
+            };
+            let algorithm = match fields[1].to_uppercase().as_str() {
+                "SHA1" => MacAlgorithm::Sha1,
+                "AES128CMAC" => MacAlgorithm::AesCmac,
+                _ => return Err(invalid(format!("unknown key type in keys file line: {}", line))),
+            };
+            let key = parse_key(fields[2], line)?;
+            if algorithm == MacAlgorithm::AesCmac && key.len() != 16 {
+                return Err(invalid(format!("AES128CMAC key is not 16 bytes: {}", line)));
+            }
+            if keys.insert(key_id, (algorithm, key)).is_some() {
+                return Err(invalid(format!("duplicate key ID {} in keys file", key_id)));
+            }
+        }
+        Ok(SymmetricKeys { keys })
+    }
+
+    /// Read and parse a keys file.
+    pub fn read(path: &str) -> Result<SymmetricKeys, Error> {
+        SymmetricKeys::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Return whether there is a key with the key ID.
+    pub fn contains(&self, key_id: u32) -> bool {
+        self.keys.contains_key(&key_id)
+    }
+
+    /// Check the MAC of a request. A MAC is 20 or 24 bytes after the header, which is shorter
+    /// than any extension field.
+    pub fn check<'a>(&self, packet: &'a [u8]) -> MacCheck<'a> {
+        let mac_len = packet.len().saturating_sub(HEADER_LEN);
+        if mac_len != KEY_ID_LEN + 16 && mac_len != KEY_ID_LEN + 20 {
+            return MacCheck::None(packet);
+        }
+        let (message, mac) = packet.split_at(HEADER_LEN);
+        let key_id = u32::from_be_bytes([mac[0], mac[1], mac[2], mac[3]]);
+        match self.keys.get(&key_id) {
+            Some((algorithm, key)) if algorithm.digest_len() == mac_len - KEY_ID_LEN => {
+                let expected = digest(*algorithm, key, message);
+                match verify_slices_are_equal(&expected, &mac[KEY_ID_LEN..]) {
+                    Ok(()) => MacCheck::Valid(message, key_id),
+                    Err(_) => MacCheck::Invalid(message),
+                }
+            },
+            _ => MacCheck::Invalid(message),
+        }
+    }
+
+    /// Append the MAC of `packet` under the key ID, which is known to be in the keys.
+    pub fn sign(&self, key_id: u32, packet: &mut Vec<u8>) {
+        let (algorithm, key) = &self.keys[&key_id];
+        let mac = digest(*algorithm, key, packet);
+        packet.extend(&key_id.to_be_bytes());
+        packet.extend(mac);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &str = "\
+# ntp.keys
+1 SHA1 secret
+2 AES128CMAC 000102030405060708090a0b0c0d0e0f  # RFC 4493
+";
+
+    #[test]
+    fn test_parse() {
+        let keys = SymmetricKeys::parse(KEYS).unwrap();
+        assert_eq!(keys.keys[&1], (MacAlgorithm::Sha1, b"secret".to_vec()));
+        assert_eq!(keys.keys[&2].1, (0..16).collect::<Vec<u8>>());
+
+        assert!(SymmetricKeys::parse("0 SHA1 secret").is_err());
+        assert!(SymmetricKeys::parse("1 MD5 secret").is_err());
+        assert!(SymmetricKeys::parse("1 AES128CMAC secret").is_err());
+        assert!(SymmetricKeys::parse("1 SHA1 a\n1 SHA1 b").is_err());
+    }
+
+    #[test]
+    fn test_cmac() {
+        // The empty message of RFC 4493.
+        let key: Vec<u8> = vec![0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6,
+                                0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c];
+        let expected = vec![0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28,
+                            0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75, 0x67, 0x46];
+        assert_eq!(digest(MacAlgorithm::AesCmac, &key, &[]), expected);
+    }
+
+    #[test]
+    fn test_check() {
+        let keys = SymmetricKeys::parse(KEYS).unwrap();
+        let header = [0x23; HEADER_LEN];
+        assert_eq!(keys.check(&header), MacCheck::None(&header));
+
+        for key_id in 1..=2 {
+            let mut packet = header.to_vec();
+            keys.sign(key_id, &mut packet);
+            assert_eq!(keys.check(&packet), MacCheck::Valid(&header, key_id));
+
+            let last = packet.len() - 1;
+            packet[last] ^= 1;
+            assert_eq!(keys.check(&packet), MacCheck::Invalid(&header));
+        }
+
+        // The key is unknown.
+        let mut packet = header.to_vec();
+        packet.extend(&[0, 0, 0, 3]);
+        packet.extend(&[0; 20]);
+        assert_eq!(keys.check(&packet), MacCheck::Invalid(&header));
+    }
+}
@@ -0,0 +1,276 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Kernel timestamps of the NTP packets. The kernel timestamps a received packet when it comes
+//! in, so the time that the server takes to get to it doesn't count. With SO_TIMESTAMPING, the
+//! kernel timestamps the sent packets as well, and the interleaved mode tells the clients about
+//! them later.
+//!
+//! nix doesn't give the data of the timestamp messages other than SCM_TIMESTAMP, so the messages
+//! are received with libc directly.
+
+use libc::{c_int, c_void, cmsghdr, in6_pktinfo, in_pktinfo, msghdr, timespec, timeval};
+use nix::sys::socket::{sockaddr_storage_to_addr, SockAddr};
+
+use std::io::Error;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::{Duration, SystemTime};
+
+/// Only the timestamp is looped back to the error queue, not the sent packet. It's not in libc
+/// yet.
+const SOF_TIMESTAMPING_OPT_TSONLY: libc::c_uint = 1 << 11;
+
+/// The socket option and the control message of UDP GRO, which aren't in libc yet either.
+pub const UDP_GRO: c_int = 104;
+
+/// How long to wait for the timestamp of a sent packet in milliseconds.
+const TRANSMIT_TIMESTAMP_WAIT: c_int = 1;
+
+/// The space for the control messages of a packet, aligned like their headers.
+pub type Control = [u64; 64];
+
+/// How the packets are timestamped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timestamping {
+    /// The clock is read after the packet is received.
+    User,
+    /// The kernel timestamps the received packets in nanoseconds with SO_TIMESTAMPNS.
+    Kernel,
+    /// The kernel timestamps the received and the sent packets with SO_TIMESTAMPING.
+    KernelTransmit,
+}
+
+/// The local address that a packet was received on, which the response is sent from.
+#[derive(Clone, Copy)]
+pub enum PacketInfo {
+    V4(in_pktinfo),
+    V6(in6_pktinfo),
+}
+
+/// A received packet.
+pub struct Received {
+    pub bytes: usize,
+    pub address: Option<SockAddr>,
+    /// The kernel timestamp, if the kernel timestamps the packets.
+    pub timestamp: Option<SystemTime>,
+    pub info: Option<PacketInfo>,
+    /// The size of the packets that the kernel coalesced into this one with UDP GRO, if it did.
+    pub segment_size: Option<usize>,
+}
+
+fn set_int_option(fd: RawFd, name: c_int, value: c_int) -> Result<(), Error> {
+    let result = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, name, &value as *const c_int as *const c_void,
+                         mem::size_of::<c_int>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enable the timestamping of the packets on the socket.
+pub fn enable(fd: RawFd, timestamping: Timestamping) -> Result<(), Error> {
+    match timestamping {
+        Timestamping::User => Ok(()),
+        Timestamping::Kernel => set_int_option(fd, libc::SO_TIMESTAMPNS, 1),
+        Timestamping::KernelTransmit => {
+            let flags = libc::SOF_TIMESTAMPING_RX_SOFTWARE | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+                | libc::SOF_TIMESTAMPING_SOFTWARE | SOF_TIMESTAMPING_OPT_TSONLY;
+            set_int_option(fd, libc::SO_TIMESTAMPING, flags as c_int)
+        },
+    }
+}
+
+fn timespec_to_system(time: timespec) -> Option<SystemTime> {
+    // The timestamps that the kernel didn't take are zero.
+    if time.tv_sec == 0 && time.tv_nsec == 0 {
+        return None;
+    }
+    Some(SystemTime::UNIX_EPOCH + Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+/// Return the timestamp in the control message, if it's one.
+unsafe fn message_timestamp(cmsg: *const cmsghdr) -> Option<SystemTime> {
+    let data = libc::CMSG_DATA(cmsg);
+    match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+        (libc::SOL_SOCKET, libc::SCM_TIMESTAMP) => {
+            let time = ptr::read_unaligned(data as *const timeval);
+            timespec_to_system(timespec { tv_sec: time.tv_sec, tv_nsec: time.tv_usec * 1000 })
+        },
+        (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+            timespec_to_system(ptr::read_unaligned(data as *const timespec))
+        },
+        // The first one is the software timestamp, and the others are the hardware ones.
+        (libc::SOL_SOCKET, libc::SCM_TIMESTAMPING) => {
+            timespec_to_system(ptr::read_unaligned(data as *const [timespec; 3])[0])
+        },
+        _ => None,
+    }
+}
+
+/// Return the packet info in the control message, if it's one.
+unsafe fn message_info(cmsg: *const cmsghdr) -> Option<PacketInfo> {
+    let data = libc::CMSG_DATA(cmsg);
+    match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+        (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+            Some(PacketInfo::V4(ptr::read_unaligned(data as *const in_pktinfo)))
+        },
+        (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+            Some(PacketInfo::V6(ptr::read_unaligned(data as *const in6_pktinfo)))
+        },
+        _ => None,
+    }
+}
+
+/// Return the size of the coalesced packets in the control message, if it's the one of UDP GRO.
+unsafe fn message_segment_size(cmsg: *const cmsghdr) -> Option<usize> {
+    match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+        (libc::SOL_UDP, UDP_GRO) => {
+            let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int);
+            Some(size as usize).filter(|&size| size > 0)
+        },
+        _ => None,
+    }
+}
+
+/// Return the header to receive a packet into the buffer of `iov`, with its source address in
+/// `address` and the control messages in `control`. They have to outlive the header.
+pub fn receive_header(
+    iov: &mut libc::iovec,
+    address: &mut libc::sockaddr_storage,
+    control: &mut Control,
+) -> msghdr {
+    let mut header: msghdr = unsafe { mem::zeroed() };
+    header.msg_name = address as *mut libc::sockaddr_storage as *mut c_void;
+    header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    header.msg_iov = iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut c_void;
+    header.msg_controllen = mem::size_of::<Control>() as _;
+    header
+}
+
+/// Return the packet of `bytes` that was received with `header`.
+pub fn received_from(
+    header: &msghdr,
+    bytes: usize,
+    address: &libc::sockaddr_storage,
+) -> Received {
+    let mut received = Received {
+        bytes,
+        address: if header.msg_namelen == 0 {
+            None
+        } else {
+            unsafe { sockaddr_storage_to_addr(address, header.msg_namelen as usize) }.ok()
+        },
+        timestamp: None,
+        info: None,
+        segment_size: None,
+    };
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(header) };
+    while !cmsg.is_null() {
+        unsafe {
+            received.timestamp = received.timestamp.or_else(|| message_timestamp(cmsg));
+            received.info = received.info.or_else(|| message_info(cmsg));
+            received.segment_size = received.segment_size.or_else(|| message_segment_size(cmsg));
+            cmsg = libc::CMSG_NXTHDR(header, cmsg);
+        }
+    }
+    received
+}
+
+/// Call `recvmsg` with `flags`, pass each control message to `on_message`, and return the size
+/// of the data and the length of the address.
+fn receive_message<F: FnMut(*const cmsghdr)>(
+    fd: RawFd,
+    buf: &mut [u8],
+    address: &mut libc::sockaddr_storage,
+    flags: c_int,
+    mut on_message: F,
+) -> Result<(usize, usize), Error> {
+    let mut control = [0; 64];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut c_void, iov_len: buf.len() };
+    let mut header = receive_header(&mut iov, address, &mut control);
+
+    let bytes = unsafe { libc::recvmsg(fd, &mut header, flags) };
+    if bytes < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&header) };
+    while !cmsg.is_null() {
+        on_message(cmsg);
+        cmsg = unsafe { libc::CMSG_NXTHDR(&header, cmsg) };
+    }
+    Ok((bytes as usize, header.msg_namelen as usize))
+}
+
+/// Receive a packet on the socket.
+pub fn receive(fd: RawFd, buf: &mut [u8]) -> Result<Received, Error> {
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut control = [0; 64];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut c_void, iov_len: buf.len() };
+    let mut header = receive_header(&mut iov, &mut address, &mut control);
+
+    let bytes = unsafe { libc::recvmsg(fd, &mut header, 0) };
+    if bytes < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(received_from(&header, bytes as usize, &address))
+}
+
+/// Return the kernel timestamp of the last packet sent on the socket, if it comes soon. The
+/// older timestamps left in the error queue are dropped.
+pub fn transmit_timestamp(fd: RawFd) -> Option<SystemTime> {
+    // The error queue is always polled for, even without asking.
+    let mut pollfd = libc::pollfd { fd, events: 0, revents: 0 };
+    let ready = unsafe { libc::poll(&mut pollfd, 1, TRANSMIT_TIMESTAMP_WAIT) };
+    if ready <= 0 || pollfd.revents & libc::POLLERR == 0 {
+        return None;
+    }
+
+    let mut latest = None;
+    let mut buf = [0; 64];
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+    let mut on_message = |cmsg| {
+        if let Some(timestamp) = unsafe { message_timestamp(cmsg) } {
+            latest = Some(timestamp);
+        }
+    };
+    while receive_message(fd, &mut buf, &mut address, flags, &mut on_message).is_ok() {}
+    latest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_kernel_timestamps() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        enable(receiver.as_raw_fd(), Timestamping::Kernel).unwrap();
+        enable(sender.as_raw_fd(), Timestamping::KernelTransmit).unwrap();
+
+        let before = SystemTime::now();
+        sender.send_to(b"ntp", receiver.local_addr().unwrap()).unwrap();
+        let sent = transmit_timestamp(sender.as_raw_fd());
+
+        let mut buf = [0; 16];
+        let received = receive(receiver.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..received.bytes], b"ntp");
+        assert!(received.address.is_some());
+        let timestamp = received.timestamp.unwrap();
+        assert!(before <= timestamp && timestamp <= SystemTime::now());
+        if let Some(sent) = sent {
+            assert!(before <= sent);
+        }
+    }
+}
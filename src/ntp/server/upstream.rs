@@ -0,0 +1,329 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Synchronization with the upstream servers. The samples of each server go through a clock
+//! filter, the servers that disagree with the majority are dropped, and the offset that the
+//! server applies to the local clock follows the combined offset of the others.
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use crate::ntp::protocol::LeapState;
+
+/// How many polls of a server are remembered. The server is unreachable if none of them was
+/// answered.
+const FILTER_SIZE: usize = 8;
+
+/// Offsets that differ from the current one by more than this are applied at once. Smaller
+/// ones are applied gradually.
+const STEP_THRESHOLD: f64 = 0.128;
+
+/// The fraction of the measured offset that is applied after each poll.
+const GAIN: f64 = 0.25;
+
+/// The highest stratum that can be synchronized to, since the server advertises one more.
+const MAX_UPSTREAM_STRATUM: u8 = 14;
+
+/// One exchange with an upstream server. The times are in seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// How much the upstream clock is ahead of the local one.
+    pub offset: f64,
+    /// The round-trip delay.
+    pub delay: f64,
+    pub stratum: u8,
+    pub leap: LeapState,
+    pub root_delay: f64,
+    pub root_dispersion: f64,
+    /// The reference ID that identifies the server to the clients.
+    pub refid: u32,
+}
+
+impl Sample {
+    /// Return the root distance, which bounds the error of the offset.
+    pub fn root_distance(&self) -> f64 {
+        (self.root_delay + self.delay) / 2.0 + self.root_dispersion
+    }
+
+    /// Return whether the server is synchronized. The stratum of a reference clock is 0.
+    fn is_synchronized(&self) -> bool {
+        self.leap != LeapState::Unknown && self.stratum <= MAX_UPSTREAM_STRATUM
+    }
+
+    /// Return whether the times are all finite, which they are unless the packet is bogus.
+    fn is_finite(&self) -> bool {
+        self.offset.is_finite() && self.delay.is_finite() && self.root_distance().is_finite()
+    }
+}
+
+/// Compare two times in a total order, where NaN comes after the infinity, so that the sorts
+/// never panic.
+pub fn total_cmp(a: f64, b: f64) -> Ordering {
+    // The same as f64::total_cmp, which is newer than Rust 1.38.
+    let key = |x: f64| {
+        let bits = x.to_bits() as i64;
+        bits ^ (((bits >> 63) as u64) >> 1) as i64
+    };
+    key(a).cmp(&key(b))
+}
+
+/// The last polls of an upstream server.
+#[derive(Debug, Default)]
+pub struct Filter {
+    polls: VecDeque<Option<Sample>>,
+}
+
+impl Filter {
+    /// Add the result of a poll, which is `None` if it failed. A sample whose times are not
+    /// finite counts as a failed poll.
+    pub fn add(&mut self, sample: Option<Sample>) {
+        if self.polls.len() == FILTER_SIZE {
+            self.polls.pop_front();
+        }
+        self.polls.push_back(sample.filter(Sample::is_finite));
+    }
+
+    /// Return the sample with the lowest delay, which has the least error, and the jitter of
+    /// the samples, if the server is reachable.
+    pub fn best(&self) -> Option<(Sample, f64)> {
+        let samples: Vec<&Sample> = self.polls.iter().flatten().collect();
+        let best = **samples.iter().min_by(|a, b| total_cmp(a.delay, b.delay))?;
+        let squares: f64 = samples.iter().map(|s| (s.offset - best.offset).powi(2)).sum();
+        Some((best, (squares / samples.len() as f64).sqrt()))
+    }
+}
+
+/// Return the median of `values`, which must not be empty.
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| total_cmp(*a, *b));
+    let middle = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+/// Return the lowest and the highest time that the correctness intervals of a majority of the
+/// servers have in common, with the intersection algorithm of RFC 5905. The interval of a server
+/// is its offset plus or minus its root distance. It's `None` if there is no such majority.
+fn intersection(candidates: &[&Sample]) -> Option<(f64, f64)> {
+    let count = candidates.len();
+    // The low ends of the intervals are -1, the offsets 0, and the high ends 1, so that the
+    // intervals that only touch still overlap.
+    let mut edges: Vec<(f64, i32)> = Vec::with_capacity(3 * count);
+    for sample in candidates {
+        let distance = sample.root_distance();
+        edges.push((sample.offset - distance, -1));
+        edges.push((sample.offset, 0));
+        edges.push((sample.offset + distance, 1));
+    }
+    edges.sort_by(|a, b| total_cmp(a.0, b.0).then(a.1.cmp(&b.1)));
+
+    // More and more falsetickers are allowed until the others agree, as long as they are fewer
+    // than half.
+    let mut allow = 0;
+    while 2 * allow < count {
+        let needed = (count - allow) as i32;
+        // The offsets outside of the intersection are of falsetickers too.
+        let mut outside = 0;
+
+        let mut chime = 0;
+        let mut low = None;
+        for &(time, edge) in &edges {
+            chime -= edge;
+            if chime >= needed {
+                low = Some(time);
+                break;
+            }
+            if edge == 0 {
+                outside += 1;
+            }
+        }
+        chime = 0;
+        let mut high = None;
+        for &(time, edge) in edges.iter().rev() {
+            chime += edge;
+            if chime >= needed {
+                high = Some(time);
+                break;
+            }
+            if edge == 0 {
+                outside += 1;
+            }
+        }
+
+        if outside <= allow {
+            if let (Some(low), Some(high)) = (low, high) {
+                if low <= high {
+                    return Some((low, high));
+                }
+            }
+        }
+        allow += 1;
+    }
+    None
+}
+
+/// Return the indices of the samples of the servers that are selected out of `candidates`. The
+/// others are not synchronized, have times that are not finite, or are falsetickers, whose
+/// correctness interval is outside of the intersection of the majority. Nothing is selected if
+/// there is no majority.
+pub fn selected(candidates: &[Sample]) -> Vec<usize> {
+    let synchronized: Vec<usize> = (0..candidates.len())
+        .filter(|&i| candidates[i].is_synchronized() && candidates[i].is_finite())
+        .collect();
+    let samples: Vec<&Sample> = synchronized.iter().map(|&i| &candidates[i]).collect();
+    let (low, high) = match intersection(&samples) {
+        Some(interval) => interval,
+        None => return Vec::new(),
+    };
+    synchronized.into_iter()
+        .filter(|&i| {
+            let sample = &candidates[i];
+            sample.offset - sample.root_distance() <= high
+                && sample.offset + sample.root_distance() >= low
+        })
+        .collect()
+}
+
+/// Select the servers out of the best samples of the reachable ones. It returns the sample of
+/// the server that the clients are told about, which has the lowest root distance, and the
+/// combined offset of the selected servers.
+pub fn select(candidates: &[Sample]) -> Option<(Sample, f64)> {
+    let selected: Vec<&Sample> = selected(candidates).into_iter()
+        .map(|i| &candidates[i])
+        .collect();
+    let peer = **selected.iter()
+        .min_by(|a, b| total_cmp(a.root_distance(), b.root_distance()))?;
+    let mut offsets: Vec<f64> = selected.iter().map(|sample| sample.offset).collect();
+    let offset = median(&mut offsets);
+    Some((peer, offset))
+}
+
+/// The offset that is added to the local clock when the time is served.
+#[derive(Debug, Default)]
+pub struct Discipline {
+    offset: Option<f64>,
+}
+
+impl Discipline {
+    /// Update the offset with the measured one, and return it.
+    pub fn update(&mut self, measured: f64) -> f64 {
+        let offset = match self.offset {
+            Some(offset) if (measured - offset).abs() <= STEP_THRESHOLD => {
+                offset + (measured - offset) * GAIN
+            },
+            // The first offset and the large ones are stepped to.
+            _ => measured,
+        };
+        self.offset = Some(offset);
+        offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(offset: f64, delay: f64) -> Sample {
+        Sample {
+            offset,
+            delay,
+            stratum: 1,
+            leap: LeapState::NoLeap,
+            root_delay: 0.0,
+            root_dispersion: 0.001,
+            refid: 0,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = Filter::default();
+        assert!(filter.best().is_none());
+        filter.add(Some(sample(0.010, 0.050)));
+        filter.add(Some(sample(0.002, 0.020)));
+        filter.add(None);
+        let (best, jitter) = filter.best().unwrap();
+        assert_eq!(best, sample(0.002, 0.020));
+        assert!((jitter - (0.008f64.powi(2) / 2.0).sqrt()).abs() < 1e-12);
+
+        // The server becomes unreachable after enough failed polls.
+        for _ in 0..FILTER_SIZE {
+            filter.add(None);
+        }
+        assert!(filter.best().is_none());
+    }
+
+    #[test]
+    fn test_total_cmp() {
+        let mut values = [1.0, std::f64::NAN, -0.5, std::f64::INFINITY, 0.0, -1.0];
+        values.sort_by(|a, b| total_cmp(*a, *b));
+        assert_eq!(values[..5], [-1.0, -0.5, 0.0, 1.0, std::f64::INFINITY]);
+        assert!(values[5].is_nan());
+    }
+
+    #[test]
+    fn test_not_finite() {
+        let mut filter = Filter::default();
+        filter.add(Some(sample(std::f64::NAN, 0.010)));
+        filter.add(Some(sample(0.001, std::f64::INFINITY)));
+        assert!(filter.best().is_none());
+
+        let candidates = [sample(0.001, 0.020), sample(std::f64::NAN, 0.010)];
+        assert_eq!(selected(&candidates), vec![0]);
+    }
+
+    #[test]
+    fn test_select() {
+        assert!(select(&[]).is_none());
+
+        let mut unsynchronized = sample(0.0, 0.01);
+        unsynchronized.leap = LeapState::Unknown;
+        assert!(select(&[unsynchronized]).is_none());
+
+        // The server that is far off is dropped.
+        let mut near = sample(0.0021, 0.010);
+        near.root_dispersion = 0.0005;
+        let candidates = [sample(0.001, 0.020), near, sample(0.003, 0.030), sample(5.0, 0.010)];
+        assert_eq!(selected(&candidates), vec![0, 1, 2]);
+        let (peer, offset) = select(&candidates).unwrap();
+        assert_eq!(peer, near);
+        assert_eq!(offset, 0.0021);
+    }
+
+    #[test]
+    fn test_intersection() {
+        // A single server agrees with itself.
+        assert_eq!(selected(&[sample(1.0, 0.010)]), vec![0]);
+
+        // Two servers that disagree, or three that all disagree, have no majority.
+        assert!(selected(&[sample(0.0, 0.010), sample(1.0, 0.010)]).is_empty());
+        let candidates = [sample(0.0, 0.010), sample(1.0, 0.010), sample(2.0, 0.010)];
+        assert!(select(&candidates).is_none());
+
+        // The offsets of the majority are in the intersection of their intervals.
+        let mut wide = sample(0.004, 0.050);
+        wide.root_dispersion = 0.010;
+        let candidates = [sample(0.0, 0.010), wide, sample(0.003, 0.010), sample(-3.0, 0.010)];
+        assert_eq!(selected(&candidates), vec![0, 1, 2]);
+        // They don't agree if the offsets are outside of it.
+        let candidates = [sample(0.0, 0.010), sample(0.011, 0.010), sample(-3.0, 0.010)];
+        assert!(selected(&candidates).is_empty());
+
+        // Two of the five servers are falsetickers.
+        let candidates = [sample(0.001, 0.010), sample(1.0, 0.010), sample(0.002, 0.010),
+                          sample(-1.0, 0.010), sample(0.0, 0.010)];
+        assert_eq!(selected(&candidates), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn test_discipline() {
+        let mut discipline = Discipline::default();
+        assert_eq!(discipline.update(0.100), 0.100);
+        assert!((discipline.update(0.020) - 0.080).abs() < 1e-12);
+        assert_eq!(discipline.update(1.0), 1.0);
+    }
+}
@@ -0,0 +1,643 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Packet I/O with io_uring, which is built with the `io-uring` feature. A receive is kept
+//! pending in the kernel for each buffer, so the packets that arrive while the server is busy
+//! are already copied when it asks for them, and the responses are sent with the same system
+//! call that puts the receives back.
+//!
+//! The ring is set up with the system calls directly, since libc doesn't have them yet. It
+//! needs Linux 5.3 for IORING_OP_RECVMSG and IORING_OP_SENDMSG, and the server falls back to
+//! recvmmsg and sendmmsg only if it cannot be set up. Whether io_uring is faster depends on the
+//! kernel and the load, so it's not picked automatically. `bench_receive` compares the two on
+//! the loopback interface:
+//!
+//! ```text
+//! cargo test --release --features io-uring -- --ignored --nocapture bench_receive
+//! ```
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use self::ring::Uring;
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+pub use self::stub::Uring;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod ring {
+    use libc::{c_void, msghdr};
+
+    use std::collections::{HashMap, VecDeque};
+    use std::io::Error;
+    use std::mem;
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::super::batch::{send_header, Outgoing};
+    use super::super::timestamping::{self, Control, Received};
+
+    /// The system calls of io_uring, which have the same numbers on all the architectures.
+    const SYS_IO_URING_SETUP: libc::c_long = 425;
+    const SYS_IO_URING_ENTER: libc::c_long = 426;
+
+    const IORING_OFF_SQ_RING: libc::off_t = 0;
+    const IORING_OFF_CQ_RING: libc::off_t = 0x0800_0000;
+    const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+    const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+    const IORING_OP_SENDMSG: u8 = 9;
+    const IORING_OP_RECVMSG: u8 = 10;
+    const IORING_OP_ASYNC_CANCEL: u8 = 14;
+
+    /// The sends are told apart from the receives, whose user data is the index of the buffer,
+    /// by this bit.
+    const SEND_TAG: u64 = 1 << 63;
+    /// The bit of the user data of the cancellations.
+    const CANCEL_TAG: u64 = 1 << 62;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct SqringOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        flags: u32,
+        dropped: u32,
+        array: u32,
+        resv1: u32,
+        resv2: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct CqringOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        overflow: u32,
+        cqes: u32,
+        flags: u32,
+        resv1: u32,
+        resv2: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Params {
+        sq_entries: u32,
+        cq_entries: u32,
+        flags: u32,
+        sq_thread_cpu: u32,
+        sq_thread_idle: u32,
+        features: u32,
+        wq_fd: u32,
+        resv: [u32; 3],
+        sq_off: SqringOffsets,
+        cq_off: CqringOffsets,
+    }
+
+    /// A submission queue entry, with only the fields of the message operations.
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Sqe {
+        opcode: u8,
+        flags: u8,
+        ioprio: u16,
+        fd: i32,
+        off: u64,
+        addr: u64,
+        len: u32,
+        msg_flags: u32,
+        user_data: u64,
+        pad: [u64; 3],
+    }
+
+    /// A completion queue entry.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Cqe {
+        user_data: u64,
+        res: i32,
+        flags: u32,
+    }
+
+    /// A memory mapping of the ring, which is unmapped when it's dropped.
+    struct Mapping {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    impl Mapping {
+        fn new(fd: RawFd, len: usize, offset: libc::off_t) -> Result<Mapping, Error> {
+            let ptr = unsafe {
+                libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                           libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+            Ok(Mapping { ptr: ptr as *mut u8, len })
+        }
+
+        /// Return the value at `offset` bytes into the mapping.
+        fn at<T>(&self, offset: u32) -> *mut T {
+            unsafe { self.ptr.add(offset as usize) as *mut T }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr as *mut c_void, self.len) };
+        }
+    }
+
+    /// A buffer that a packet is received into, with the header and the space for the address
+    /// and the control messages. The slots are never moved, since the kernel writes to them while
+    /// the receives are pending.
+    struct Slot {
+        buf: Vec<u8>,
+        iov: libc::iovec,
+        address: libc::sockaddr_storage,
+        control: Control,
+        header: msghdr,
+        /// Whether a receive is pending.
+        armed: bool,
+    }
+
+    /// A packet that is being sent, with a copy of the response. It's boxed so that the header
+    /// stays where the kernel reads it until the send completes.
+    struct Sending {
+        packet: Outgoing,
+        iov: libc::iovec,
+        control: Control,
+        header: msghdr,
+    }
+
+    /// An io_uring that receives and sends the packets of a socket. The buffers of the pending
+    /// receives and sends belong to the ring, so they are only freed once the kernel is done
+    /// with them.
+    pub struct Uring {
+        fd: RawFd,
+        socket: RawFd,
+        sq: Mapping,
+        cq: Mapping,
+        sqes: Mapping,
+        params: Params,
+        slots: Vec<Slot>,
+        /// The slots that a packet of the given size was received into, oldest first.
+        ready: VecDeque<(usize, usize)>,
+        /// The sends that haven't completed, by their number.
+        sending: HashMap<u64, Box<Sending>>,
+        /// The number of the next send.
+        next_send: u64,
+        /// The number of entries submitted since the last system call.
+        unsubmitted: u32,
+        /// Whether the kernel is too old to cancel the requests, which needs Linux 5.5.
+        cancel_unsupported: bool,
+    }
+
+    impl Uring {
+        /// Set up a ring for the socket with a receive buffer of `buf_size` bytes for each of
+        /// the `slots` packets.
+        pub fn new(socket: RawFd, slots: usize, buf_size: usize) -> Result<Uring, Error> {
+            // Each slot can have a receive and a send pending at the same time.
+            let entries = (2 * slots).next_power_of_two() as u32;
+            let mut params = Params::default();
+            let fd = unsafe {
+                libc::syscall(SYS_IO_URING_SETUP, entries, &mut params as *mut Params)
+            };
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            let fd = fd as RawFd;
+            let map = || -> Result<(Mapping, Mapping, Mapping), Error> {
+                let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+                let cq_len = params.cq_off.cqes as usize
+                    + params.cq_entries as usize * mem::size_of::<Cqe>();
+                let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+                Ok((Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+                    Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+                    Mapping::new(fd, sqes_len, IORING_OFF_SQES)?))
+            };
+            let (sq, cq, sqes) = match map() {
+                Ok(mappings) => mappings,
+                Err(err) => {
+                    unsafe { libc::close(fd) };
+                    return Err(err);
+                },
+            };
+
+            let slots = (0..slots)
+                .map(|_| Slot {
+                    buf: vec![0; buf_size],
+                    iov: unsafe { mem::zeroed() },
+                    address: unsafe { mem::zeroed() },
+                    control: [0; 64],
+                    header: unsafe { mem::zeroed() },
+                    armed: false,
+                })
+                .collect();
+            Ok(Uring {
+                fd,
+                socket,
+                sq,
+                cq,
+                sqes,
+                params,
+                slots,
+                ready: VecDeque::new(),
+                sending: HashMap::new(),
+                next_send: 0,
+                unsubmitted: 0,
+                cancel_unsupported: false,
+            })
+        }
+
+        /// Return whether there is room to queue an entry.
+        fn has_room(&self) -> bool {
+            let off = &self.params.sq_off;
+            let head = unsafe { &*self.sq.at::<AtomicU32>(off.head) };
+            let tail = unsafe { &*self.sq.at::<AtomicU32>(off.tail) };
+            let queued = tail.load(Ordering::Relaxed).wrapping_sub(head.load(Ordering::Acquire));
+            queued < self.params.sq_entries
+        }
+
+        /// Queue an entry, which is submitted with the next system call.
+        fn push(&mut self, mut sqe: Sqe) {
+            // There is room for a receive and a send for each slot.
+            assert!(self.has_room());
+            let off = &self.params.sq_off;
+            let tail = unsafe { &*self.sq.at::<AtomicU32>(off.tail) };
+            let mask = unsafe { *self.sq.at::<u32>(off.ring_mask) };
+            let current = tail.load(Ordering::Relaxed);
+
+            let index = current & mask;
+            sqe.fd = self.socket;
+            unsafe {
+                ptr::write(self.sqes.at::<Sqe>(0).add(index as usize), sqe);
+                ptr::write(self.sq.at::<u32>(off.array).add(index as usize), index);
+            }
+            tail.store(current.wrapping_add(1), Ordering::Release);
+            self.unsubmitted += 1;
+        }
+
+        /// Submit the queued entries and wait for a completion if `wait` is true.
+        fn enter(&mut self, wait: bool) -> Result<(), Error> {
+            let (min_complete, flags) = if wait { (1, IORING_ENTER_GETEVENTS) } else { (0, 0) };
+            if self.unsubmitted == 0 && !wait {
+                return Ok(());
+            }
+            let result = unsafe {
+                libc::syscall(SYS_IO_URING_ENTER, self.fd, self.unsubmitted, min_complete, flags,
+                              ptr::null::<libc::sigset_t>(), 0)
+            };
+            if result < 0 {
+                let err = Error::last_os_error();
+                // The entries stay queued if the call is interrupted.
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    return Ok(());
+                }
+                return Err(err);
+            }
+            self.unsubmitted = self.unsubmitted.saturating_sub(result as u32);
+            Ok(())
+        }
+
+        /// Take the completions, keep the received packets, and pass the results of the sends
+        /// to `on_send` with their numbers.
+        fn reap<F: FnMut(u64, i32)>(&mut self, mut on_send: F) {
+            let off = &self.params.cq_off;
+            let head = unsafe { &*self.cq.at::<AtomicU32>(off.head) };
+            let tail = unsafe { &*self.cq.at::<AtomicU32>(off.tail) };
+            let mask = unsafe { *self.cq.at::<u32>(off.ring_mask) };
+            let cqes = self.cq.at::<Cqe>(off.cqes);
+            loop {
+                let current = head.load(Ordering::Relaxed);
+                if current == tail.load(Ordering::Acquire) {
+                    break;
+                }
+                let cqe = unsafe { ptr::read(cqes.add((current & mask) as usize)) };
+                head.store(current.wrapping_add(1), Ordering::Release);
+
+                if cqe.user_data & CANCEL_TAG != 0 {
+                    if cqe.res == -libc::EINVAL {
+                        self.cancel_unsupported = true;
+                    }
+                    continue;
+                }
+                if cqe.user_data & SEND_TAG != 0 {
+                    let number = cqe.user_data & !SEND_TAG;
+                    self.sending.remove(&number);
+                    on_send(number, cqe.res);
+                    continue;
+                }
+                let index = cqe.user_data as usize;
+                self.slots[index].armed = false;
+                // A failed receive is just armed again.
+                if cqe.res >= 0 {
+                    self.ready.push_back((index, cqe.res as usize));
+                }
+            }
+        }
+
+        /// Queue a receive for each slot that doesn't have one pending.
+        fn arm(&mut self) {
+            for index in 0..self.slots.len() {
+                if self.slots[index].armed || self.ready.iter().any(|(ready, _)| *ready == index) {
+                    continue;
+                }
+                let slot = &mut self.slots[index];
+                slot.iov = libc::iovec {
+                    iov_base: slot.buf.as_mut_ptr() as *mut c_void,
+                    iov_len: slot.buf.len(),
+                };
+                slot.header = timestamping::receive_header(&mut slot.iov, &mut slot.address,
+                                                           &mut slot.control);
+                slot.armed = true;
+                let addr = &slot.header as *const msghdr as u64;
+                self.push(Sqe {
+                    opcode: IORING_OP_RECVMSG,
+                    addr,
+                    len: 1,
+                    user_data: index as u64,
+                    ..Sqe::default()
+                });
+            }
+        }
+
+        /// Receive the packets into `bufs`, at most one in each. It waits for the first packet
+        /// only.
+        pub fn receive(&mut self, bufs: &mut [Vec<u8>]) -> Result<Vec<Received>, Error> {
+            self.arm();
+            while self.ready.is_empty() {
+                self.enter(true)?;
+                self.reap(|_, _| ());
+            }
+            self.enter(false)?;
+
+            let mut received = Vec::new();
+            for buf in bufs.iter_mut() {
+                let (index, bytes) = match self.ready.pop_front() {
+                    Some(ready) => ready,
+                    None => break,
+                };
+                let slot = &self.slots[index];
+                let bytes = bytes.min(buf.len());
+                buf[..bytes].copy_from_slice(&slot.buf[..bytes]);
+                received.push(timestamping::received_from(&slot.header, bytes, &slot.address));
+            }
+            Ok(received)
+        }
+
+        /// Send the packets along with the receives that are put back, and return the result
+        /// of each one.
+        pub fn send(&mut self, packets: &[Outgoing]) -> Vec<Result<(), Error>> {
+            self.arm();
+            let first = self.next_send;
+            for packet in packets {
+                let mut sending = Box::new(Sending {
+                    packet: Outgoing {
+                        data: packet.data.clone(),
+                        address: packet.address,
+                        info: packet.info,
+                    },
+                    iov: unsafe { mem::zeroed() },
+                    control: [0; 64],
+                    header: unsafe { mem::zeroed() },
+                });
+                let Sending { packet, iov, control, header } = &mut *sending;
+                *header = send_header(packet, iov, control);
+                let addr = header as *const msghdr as u64;
+                let number = self.next_send;
+                self.next_send += 1;
+                self.sending.insert(number, sending);
+                self.push(Sqe {
+                    opcode: IORING_OP_SENDMSG,
+                    addr,
+                    len: 1,
+                    user_data: SEND_TAG | number,
+                    ..Sqe::default()
+                });
+            }
+
+            // The sends that are still pending if this fails keep their buffers until they
+            // complete.
+            let count = packets.len();
+            let mut results: Vec<Option<Result<(), Error>>> = (0..count).map(|_| None).collect();
+            let mut pending = count;
+            while pending > 0 {
+                if let Err(err) = self.enter(true) {
+                    return results.into_iter()
+                        .map(|result| result.unwrap_or_else(|| {
+                            Err(Error::new(err.kind(), err.to_string()))
+                        }))
+                        .collect();
+                }
+                self.reap(|number, res| {
+                    // The sends left over from a call that failed are skipped.
+                    if number < first {
+                        return;
+                    }
+                    results[(number - first) as usize] = Some(if res < 0 {
+                        Err(Error::from_raw_os_error(-res))
+                    } else {
+                        Ok(())
+                    });
+                    pending -= 1;
+                });
+            }
+            results.into_iter().map(|result| result.unwrap()).collect()
+        }
+
+        /// Cancel the pending receives and sends, wait for them, and return whether they all
+        /// completed.
+        fn cancel(&mut self) -> bool {
+            let targets: Vec<u64> = self.slots.iter()
+                .enumerate()
+                .filter(|(_, slot)| slot.armed)
+                .map(|(index, _)| index as u64)
+                .chain(self.sending.keys().map(|number| SEND_TAG | number))
+                .collect();
+            for target in targets {
+                if !self.has_room() && self.enter(false).is_err() || !self.has_room() {
+                    return false;
+                }
+                self.push(Sqe {
+                    opcode: IORING_OP_ASYNC_CANCEL,
+                    addr: target,
+                    user_data: CANCEL_TAG,
+                    ..Sqe::default()
+                });
+            }
+            while self.slots.iter().any(|slot| slot.armed) || !self.sending.is_empty() {
+                if self.cancel_unsupported || self.enter(true).is_err() {
+                    return false;
+                }
+                self.reap(|_, _| ());
+            }
+            true
+        }
+    }
+
+    impl Drop for Uring {
+        fn drop(&mut self) {
+            // The kernel may still write to the slots and read the packets being sent, so they
+            // are leaked if the requests cannot be cancelled.
+            if !self.cancel() {
+                mem::forget(mem::replace(&mut self.slots, Vec::new()));
+                mem::forget(mem::replace(&mut self.sending, HashMap::new()));
+            }
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+mod stub {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::io::RawFd;
+
+    use super::super::batch::Outgoing;
+    use super::super::timestamping::Received;
+
+    /// The stand-in for the ring without the `io-uring` feature, which is never set up.
+    pub struct Uring(());
+
+    impl Uring {
+        pub fn new(_socket: RawFd, _slots: usize, _buf_size: usize) -> Result<Uring, Error> {
+            Err(Error::new(ErrorKind::Other, "built without the io-uring feature"))
+        }
+
+        pub fn receive(&mut self, _bufs: &mut [Vec<u8>]) -> Result<Vec<Received>, Error> {
+            unreachable!()
+        }
+
+        pub fn send(&mut self, _packets: &[Outgoing]) -> Vec<Result<(), Error>> {
+            unreachable!()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "io-uring", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use super::super::batch::{self, Outgoing};
+
+    use nix::sys::socket::{InetAddr, SockAddr};
+
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_uring() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // io_uring can be disabled in the kernel or by a seccomp filter.
+        let mut ring = match Uring::new(server.as_raw_fd(), 4, 16) {
+            Ok(ring) => ring,
+            Err(_) => return,
+        };
+
+        for i in 0..3u8 {
+            client.send_to(&[i; 4], server.local_addr().unwrap()).unwrap();
+        }
+        let mut bufs = vec![vec![0; 16]; 4];
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let batch = ring.receive(&mut bufs).unwrap();
+            for (buf, packet) in bufs.iter().zip(batch.iter()) {
+                assert_eq!(packet.bytes, 4);
+                received.push((buf[0], packet.address.unwrap()));
+            }
+        }
+        assert_eq!(received.iter().map(|(i, _)| *i).collect::<Vec<u8>>(), vec![0, 1, 2]);
+
+        let address = SockAddr::new_inet(InetAddr::from_std(&client.local_addr().unwrap()));
+        assert_eq!(received[0].1, address);
+        let response = Outgoing { data: b"ntp".to_vec(), address, info: None };
+        assert!(ring.send(&[response])[0].is_ok());
+        let mut buf = [0; 16];
+        let (bytes, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..bytes], b"ntp");
+    }
+
+    #[test]
+    fn test_cancel() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut ring = match Uring::new(server.as_raw_fd(), 4, 16) {
+            Ok(ring) => ring,
+            Err(_) => return,
+        };
+        client.send_to(&[0; 4], server.local_addr().unwrap()).unwrap();
+        let mut bufs = vec![vec![0; 16]; 4];
+        while ring.receive(&mut bufs).unwrap().is_empty() {}
+
+        // The receives that were put back are cancelled, so the next packet is left to the
+        // socket.
+        drop(ring);
+        client.send_to(&[1; 4], server.local_addr().unwrap()).unwrap();
+        let mut buf = [0; 16];
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (bytes, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..bytes], &[1; 4]);
+    }
+    /// The packets that each receive path gets in `bench_receive`.
+    const BENCH_PACKETS: usize = 1_000_000;
+
+    /// Return how many packets per second `receive` gets from two threads that flood the socket.
+    fn packet_rate(server: &UdpSocket, receive: &mut dyn FnMut(&mut [Vec<u8>]) -> usize) -> f64 {
+        let stop = Arc::new(AtomicBool::new(false));
+        let senders: Vec<_> = (0..2).map(|_| {
+            let stop = stop.clone();
+            let address = server.local_addr().unwrap();
+            thread::spawn(move || {
+                let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    let _ = client.send_to(&[0x23; 48], address);
+                }
+            })
+        }).collect();
+
+        let mut bufs = vec![vec![0; 2048]; 32];
+        let start = Instant::now();
+        let mut received = 0;
+        while received < BENCH_PACKETS {
+            received += receive(&mut bufs);
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        stop.store(true, Ordering::Relaxed);
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        received as f64 / elapsed
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_receive() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fd = server.as_raw_fd();
+        let recvmmsg = packet_rate(&server, &mut |bufs| batch::receive(fd, bufs).unwrap().len());
+        println!("recvmmsg: {:.0} packets/s", recvmmsg);
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut ring = match Uring::new(server.as_raw_fd(), 32, 2048) {
+            Ok(ring) => ring,
+            Err(err) => {
+                println!("io_uring: cannot be set up: {}", err);
+                return;
+            },
+        };
+        let uring = packet_rate(&server, &mut |bufs| ring.receive(bufs).unwrap().len());
+        println!("io_uring: {:.0} packets/s ({:+.1}%)", uring, (uring / recvmmsg - 1.0) * 100.0);
+    }
+}
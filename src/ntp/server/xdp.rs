@@ -0,0 +1,779 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The experimental AF_XDP datapath, which is built with the `af-xdp` feature. An XDP program
+//! redirects the NTP requests on some queues of an interface to the AF_XDP sockets of the
+//! workers, which parse them from the frames in the shared memory and write the responses over
+//! them in place, so the packets never go through the UDP stack of the kernel.
+//!
+//! cfnts doesn't load the XDP program. It has to be attached to the interface already, and to
+//! redirect the UDP packets to the NTP port into an XSKMAP pinned in the BPF file system,
+//! indexed by the queue that they came in on. Each worker puts its socket into the map at the
+//! index of its queue. The other packets are left to the kernel. It needs Linux 5.4.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+
+#[cfg(all(feature = "af-xdp", target_os = "linux"))]
+pub use self::socket::Xsk;
+#[cfg(not(all(feature = "af-xdp", target_os = "linux")))]
+pub use self::stub::Xsk;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const VLAN_TAG_LEN: usize = 4;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_UDP: u8 = 17;
+
+/// The TTL and the hop limit of the responses.
+const HOP_LIMIT: u8 = 64;
+
+/// The XDP datapath of an interface.
+#[derive(Clone, Debug)]
+pub struct XdpConfig {
+    /// The interface that the XDP program is attached to.
+    pub interface: String,
+
+    /// The receive queues of the interface, each of which gets a worker.
+    pub queues: Vec<u32>,
+
+    /// The path of the pinned XSKMAP that the program redirects the requests into.
+    pub xsks_map: String,
+
+    /// The UDP port of the requests.
+    pub port: u16,
+}
+
+/// An NTP request in a frame.
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    /// The address and port of the client.
+    pub client: SocketAddr,
+
+    /// The address and port that the request was sent to, which the response is sent from.
+    pub local: SocketAddr,
+
+    /// The offset of the IP header.
+    ip_offset: usize,
+
+    /// The NTP packet in the frame.
+    pub payload: Range<usize>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Add the 16-bit words of `data` to the one's complement sum `sum`. Only the last part can
+/// have an odd length.
+pub fn checksum_add(sum: u32, data: &[u8]) -> u32 {
+    data.chunks(2).fold(sum, |sum, word| {
+        let word = match word {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => unreachable!(),
+        };
+        let sum = sum + u32::from(word);
+        (sum & 0xffff) + (sum >> 16)
+    })
+}
+
+/// Return the Internet checksum of the sum.
+pub fn checksum_finish(sum: u32) -> u16 {
+    !(((sum & 0xffff) + (sum >> 16)) as u16)
+}
+
+/// Parse the NTP request to `port` in an Ethernet frame, which can have a VLAN tag. Anything
+/// else, including the fragments and the IPv6 extension headers, is `None`.
+pub fn parse_request(frame: &[u8], port: u16) -> Option<Request> {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return None;
+    }
+    let (ethertype, ip_offset) = match read_u16(frame, 12) {
+        ETHERTYPE_VLAN if frame.len() >= ETHERNET_HEADER_LEN + VLAN_TAG_LEN => {
+            (read_u16(frame, 16), ETHERNET_HEADER_LEN + VLAN_TAG_LEN)
+        },
+        ethertype => (ethertype, ETHERNET_HEADER_LEN),
+    };
+    let ip = &frame[ip_offset..];
+
+    let (client, local, udp_offset, ip_end) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            if ip.len() < IPV4_HEADER_LEN || ip[0] >> 4 != 4 || ip[9] != IPPROTO_UDP {
+                return None;
+            }
+            // The more fragments flag and the fragment offset.
+            if read_u16(ip, 6) & 0x3fff != 0 {
+                return None;
+            }
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let total_len = usize::from(read_u16(ip, 2));
+            if header_len < IPV4_HEADER_LEN || total_len < header_len || total_len > ip.len() {
+                return None;
+            }
+            let client = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let local = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            (IpAddr::V4(client), IpAddr::V4(local), header_len, total_len)
+        },
+        ETHERTYPE_IPV6 => {
+            if ip.len() < IPV6_HEADER_LEN || ip[0] >> 4 != 6 || ip[6] != IPPROTO_UDP {
+                return None;
+            }
+            let total_len = IPV6_HEADER_LEN + usize::from(read_u16(ip, 4));
+            if total_len > ip.len() {
+                return None;
+            }
+            let mut client = [0; 16];
+            client.copy_from_slice(&ip[8..24]);
+            let mut local = [0; 16];
+            local.copy_from_slice(&ip[24..40]);
+            (IpAddr::V6(Ipv6Addr::from(client)), IpAddr::V6(Ipv6Addr::from(local)),
+             IPV6_HEADER_LEN, total_len)
+        },
+        _ => return None,
+    };
+
+    let udp = &ip[udp_offset..ip_end];
+    if udp.len() < UDP_HEADER_LEN || read_u16(udp, 2) != port {
+        return None;
+    }
+    let udp_len = usize::from(read_u16(udp, 4));
+    if udp_len < UDP_HEADER_LEN || udp_len > udp.len() {
+        return None;
+    }
+    let payload_offset = ip_offset + udp_offset + UDP_HEADER_LEN;
+    Some(Request {
+        client: SocketAddr::new(client, read_u16(udp, 0)),
+        local: SocketAddr::new(local, port),
+        ip_offset,
+        payload: payload_offset..ip_offset + udp_offset + udp_len,
+    })
+}
+
+/// Write the response to `request` over its frame, which has room for `frame.len()` bytes,
+/// and return the length of the response frame. It's `None` if the response doesn't fit. The
+/// IPv4 options of the request are not sent back.
+pub fn write_response(
+    frame: &mut [u8],
+    request: &Request,
+    payload: &[u8],
+    dscp: Option<u8>,
+) -> Option<usize> {
+    let ip_offset = request.ip_offset;
+    let ip_header_len = match request.client {
+        SocketAddr::V4(_) => IPV4_HEADER_LEN,
+        SocketAddr::V6(_) => IPV6_HEADER_LEN,
+    };
+    let udp_offset = ip_offset + ip_header_len;
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let end = udp_offset + udp_len;
+    if end > frame.len() || end - ip_offset > usize::from(std::u16::MAX) {
+        return None;
+    }
+    let traffic_class = dscp.unwrap_or(0) << 2;
+
+    // The frame goes back to where it came from, with the VLAN tag as it was.
+    let mut source = [0; 6];
+    source.copy_from_slice(&frame[6..12]);
+    frame.copy_within(0..6, 6);
+    frame[..6].copy_from_slice(&source);
+
+    let mut sum = 0;
+    let ip = &mut frame[ip_offset..udp_offset];
+    match (request.local.ip(), request.client.ip()) {
+        (IpAddr::V4(local), IpAddr::V4(client)) => {
+            ip[0] = 0x45;
+            ip[1] = traffic_class;
+            write_u16(ip, 2, (ip_header_len + udp_len) as u16);
+            // The identification doesn't matter, since the responses are not fragmented.
+            write_u16(ip, 4, 0);
+            write_u16(ip, 6, 0x4000);
+            ip[8] = HOP_LIMIT;
+            ip[9] = IPPROTO_UDP;
+            write_u16(ip, 10, 0);
+            ip[12..16].copy_from_slice(&local.octets());
+            ip[16..20].copy_from_slice(&client.octets());
+            let header_checksum = checksum_finish(checksum_add(0, ip));
+            write_u16(ip, 10, header_checksum);
+            sum = checksum_add(sum, &ip[12..20]);
+        },
+        (IpAddr::V6(local), IpAddr::V6(client)) => {
+            ip[0] = 0x60 | traffic_class >> 4;
+            ip[1] = traffic_class << 4;
+            write_u16(ip, 2, 0);
+            write_u16(ip, 4, udp_len as u16);
+            ip[6] = IPPROTO_UDP;
+            ip[7] = HOP_LIMIT;
+            ip[8..24].copy_from_slice(&local.octets());
+            ip[24..40].copy_from_slice(&client.octets());
+            sum = checksum_add(sum, &ip[8..40]);
+        },
+        _ => unreachable!("the addresses of a request are of the same family"),
+    }
+
+    let udp = &mut frame[udp_offset..end];
+    write_u16(udp, 0, request.local.port());
+    write_u16(udp, 2, request.client.port());
+    write_u16(udp, 4, udp_len as u16);
+    write_u16(udp, 6, 0);
+    udp[UDP_HEADER_LEN..].copy_from_slice(payload);
+    sum = checksum_add(sum, &[0, IPPROTO_UDP]);
+    sum = checksum_add(sum, &(udp_len as u16).to_be_bytes());
+    let checksum = match checksum_finish(checksum_add(sum, udp)) {
+        // A zero checksum means that there is none.
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    write_u16(udp, 6, checksum);
+    Some(end)
+}
+
+#[cfg(all(feature = "af-xdp", target_os = "linux"))]
+mod socket {
+    use libc::{c_int, c_void};
+
+    use std::ffi::CString;
+    use std::io::{Error, ErrorKind};
+    use std::marker::PhantomData;
+    use std::mem;
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+    use std::slice;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::XdpConfig;
+
+    /// The AF_XDP definitions, which are not in libc yet.
+    const AF_XDP: c_int = 44;
+    const SOL_XDP: c_int = 283;
+
+    const XDP_MMAP_OFFSETS: c_int = 1;
+    const XDP_RX_RING: c_int = 2;
+    const XDP_TX_RING: c_int = 3;
+    const XDP_UMEM_REG: c_int = 4;
+    const XDP_UMEM_FILL_RING: c_int = 5;
+    const XDP_UMEM_COMPLETION_RING: c_int = 6;
+
+    const XDP_PGOFF_RX_RING: libc::off64_t = 0;
+    const XDP_PGOFF_TX_RING: libc::off64_t = 0x8000_0000;
+    const XDP_UMEM_PGOFF_FILL_RING: libc::off64_t = 0x1_0000_0000;
+    const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off64_t = 0x1_8000_0000;
+
+    const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+    const XDP_RING_NEED_WAKEUP: u32 = 1;
+
+    const BPF_MAP_UPDATE_ELEM: c_int = 2;
+    const BPF_OBJ_GET: c_int = 7;
+
+    /// The size of a frame in the shared memory, which holds any packet up to the usual MTU.
+    const FRAME_SIZE: usize = 2048;
+
+    /// The number of frames, which all fit in the fill ring at once.
+    const FRAME_COUNT: u32 = 4096;
+
+    /// The size of the receive and transmit rings.
+    const RING_SIZE: u32 = 2048;
+
+    /// How long to wait for the requests in milliseconds.
+    const POLL_TIMEOUT: c_int = 1000;
+
+    #[repr(C)]
+    struct UmemReg {
+        addr: u64,
+        len: u64,
+        chunk_size: u32,
+        headroom: u32,
+        flags: u32,
+        tx_metadata_len: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct RingOffset {
+        producer: u64,
+        consumer: u64,
+        desc: u64,
+        flags: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct MmapOffsets {
+        rx: RingOffset,
+        tx: RingOffset,
+        fr: RingOffset,
+        cr: RingOffset,
+    }
+
+    #[repr(C)]
+    struct SockaddrXdp {
+        family: u16,
+        flags: u16,
+        ifindex: u32,
+        queue_id: u32,
+        shared_umem_fd: u32,
+    }
+
+    /// A frame in a receive or transmit ring.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Desc {
+        addr: u64,
+        len: u32,
+        options: u32,
+    }
+
+    #[repr(C)]
+    struct BpfObjGet {
+        pathname: u64,
+        bpf_fd: u32,
+        file_flags: u32,
+    }
+
+    #[repr(C)]
+    struct BpfMapUpdate {
+        map_fd: u32,
+        pad: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+
+    /// A memory mapping, which is unmapped when it's dropped.
+    struct Mapping {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    impl Mapping {
+        fn new(fd: RawFd, len: usize, offset: libc::off64_t) -> Result<Mapping, Error> {
+            let flags = if fd < 0 {
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS
+            } else {
+                libc::MAP_SHARED | libc::MAP_POPULATE
+            };
+            let ptr = unsafe {
+                libc::mmap64(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, flags, fd,
+                             offset)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+            Ok(Mapping { ptr: ptr as *mut u8, len })
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr as *mut c_void, self.len) };
+        }
+    }
+
+    /// A ring of descriptors that is shared with the kernel. Either the kernel produces them and
+    /// the socket consumes them, or the other way around.
+    struct Ring<T> {
+        mapping: Mapping,
+        offsets: RingOffset,
+        mask: u32,
+        size: u32,
+        desc: PhantomData<T>,
+    }
+
+    impl<T: Copy> Ring<T> {
+        fn new(fd: RawFd, offsets: RingOffset, size: u32, pgoff: libc::off64_t)
+            -> Result<Ring<T>, Error>
+        {
+            let len = offsets.desc as usize + size as usize * mem::size_of::<T>();
+            let mapping = Mapping::new(fd, len, pgoff)?;
+            Ok(Ring { mapping, offsets, mask: size - 1, size, desc: PhantomData })
+        }
+
+        fn at<U>(&self, offset: u64) -> *mut U {
+            unsafe { self.mapping.ptr.add(offset as usize) as *mut U }
+        }
+
+        fn producer(&self) -> &AtomicU32 {
+            unsafe { &*self.at::<AtomicU32>(self.offsets.producer) }
+        }
+
+        fn consumer(&self) -> &AtomicU32 {
+            unsafe { &*self.at::<AtomicU32>(self.offsets.consumer) }
+        }
+
+        /// Return whether the kernel has to be woken up to go through the ring.
+        fn needs_wakeup(&self) -> bool {
+            let flags = unsafe { &*self.at::<AtomicU32>(self.offsets.flags) };
+            flags.load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0
+        }
+
+        /// Return whether there is room for another descriptor.
+        fn has_room(&self) -> bool {
+            let used = self.producer().load(Ordering::Relaxed)
+                .wrapping_sub(self.consumer().load(Ordering::Acquire));
+            used < self.size
+        }
+
+        /// Produce a descriptor. There has to be room for it.
+        fn push(&mut self, desc: T) {
+            let producer = self.producer().load(Ordering::Relaxed);
+            let index = (producer & self.mask) as usize;
+            unsafe { ptr::write(self.at::<T>(self.offsets.desc).add(index), desc) };
+            self.producer().store(producer.wrapping_add(1), Ordering::Release);
+        }
+
+        /// Consume the next descriptor, if there is one.
+        fn pop(&mut self) -> Option<T> {
+            let consumer = self.consumer().load(Ordering::Relaxed);
+            if consumer == self.producer().load(Ordering::Acquire) {
+                return None;
+            }
+            let index = (consumer & self.mask) as usize;
+            let desc = unsafe { ptr::read(self.at::<T>(self.offsets.desc).add(index)) };
+            self.consumer().store(consumer.wrapping_add(1), Ordering::Release);
+            Some(desc)
+        }
+    }
+
+    fn set_option<T>(fd: RawFd, name: c_int, value: &T) -> Result<(), Error> {
+        let result = unsafe {
+            libc::setsockopt(fd, SOL_XDP, name, value as *const T as *const c_void,
+                             mem::size_of::<T>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn bpf<T>(cmd: c_int, attr: &T) -> Result<libc::c_long, Error> {
+        let result = unsafe {
+            libc::syscall(libc::SYS_bpf, cmd, attr as *const T, mem::size_of::<T>())
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(result)
+    }
+
+    /// Put the socket into the pinned XSKMAP at the index of its queue.
+    fn insert_into_map(path: &str, queue: u32, fd: RawFd) -> Result<(), Error> {
+        let path = CString::new(path).map_err(|err| Error::new(ErrorKind::Other, err))?;
+        let map_fd = bpf(BPF_OBJ_GET, &BpfObjGet {
+            pathname: path.as_ptr() as u64,
+            bpf_fd: 0,
+            file_flags: 0,
+        })? as RawFd;
+        let result = bpf(BPF_MAP_UPDATE_ELEM, &BpfMapUpdate {
+            map_fd: map_fd as u32,
+            pad: 0,
+            key: &queue as *const u32 as u64,
+            value: &fd as *const RawFd as u64,
+            flags: 0,
+        });
+        unsafe { libc::close(map_fd) };
+        result.map(|_| ())
+    }
+
+    /// An AF_XDP socket that receives the requests of a queue into its frames and sends the
+    /// responses from them.
+    pub struct Xsk {
+        fd: RawFd,
+        umem: Mapping,
+        fill: Ring<u64>,
+        completion: Ring<u64>,
+        rx: Ring<Desc>,
+        tx: Ring<Desc>,
+    }
+
+    impl Xsk {
+        /// Bind a socket to the queue of the interface, and put it into the XSKMAP.
+        pub fn new(config: &XdpConfig, queue: u32) -> Result<Xsk, Error> {
+            let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            Xsk::set_up(fd, config, queue).map_err(|err| {
+                unsafe { libc::close(fd) };
+                err
+            })
+        }
+
+        fn set_up(fd: RawFd, config: &XdpConfig, queue: u32) -> Result<Xsk, Error> {
+            let umem = Mapping::new(-1, FRAME_SIZE * FRAME_COUNT as usize, 0)?;
+            set_option(fd, XDP_UMEM_REG, &UmemReg {
+                addr: umem.ptr as u64,
+                len: umem.len as u64,
+                chunk_size: FRAME_SIZE as u32,
+                headroom: 0,
+                flags: 0,
+                tx_metadata_len: 0,
+            })?;
+            set_option(fd, XDP_UMEM_FILL_RING, &FRAME_COUNT)?;
+            set_option(fd, XDP_UMEM_COMPLETION_RING, &FRAME_COUNT)?;
+            set_option(fd, XDP_RX_RING, &RING_SIZE)?;
+            set_option(fd, XDP_TX_RING, &RING_SIZE)?;
+
+            let mut offsets = MmapOffsets::default();
+            let mut len = mem::size_of::<MmapOffsets>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(fd, SOL_XDP, XDP_MMAP_OFFSETS,
+                                 &mut offsets as *mut MmapOffsets as *mut c_void, &mut len)
+            };
+            if result < 0 {
+                return Err(Error::last_os_error());
+            }
+            // The rings have no flags before Linux 5.4.
+            if len as usize != mem::size_of::<MmapOffsets>() {
+                return Err(Error::new(ErrorKind::Other, "AF_XDP needs Linux 5.4"));
+            }
+            let mut fill = Ring::new(fd, offsets.fr, FRAME_COUNT, XDP_UMEM_PGOFF_FILL_RING)?;
+            let completion = Ring::new(fd, offsets.cr, FRAME_COUNT,
+                                       XDP_UMEM_PGOFF_COMPLETION_RING)?;
+            let rx = Ring::new(fd, offsets.rx, RING_SIZE, XDP_PGOFF_RX_RING)?;
+            let tx = Ring::new(fd, offsets.tx, RING_SIZE, XDP_PGOFF_TX_RING)?;
+            for frame in 0..u64::from(FRAME_COUNT) {
+                fill.push(frame * FRAME_SIZE as u64);
+            }
+
+            let interface = CString::new(config.interface.as_str())
+                .map_err(|err| Error::new(ErrorKind::Other, err))?;
+            let ifindex = unsafe { libc::if_nametoindex(interface.as_ptr()) };
+            if ifindex == 0 {
+                return Err(Error::last_os_error());
+            }
+            let address = SockaddrXdp {
+                family: AF_XDP as u16,
+                flags: XDP_USE_NEED_WAKEUP,
+                ifindex,
+                queue_id: queue,
+                shared_umem_fd: 0,
+            };
+            let result = unsafe {
+                libc::bind(fd, &address as *const SockaddrXdp as *const libc::sockaddr,
+                           mem::size_of::<SockaddrXdp>() as libc::socklen_t)
+            };
+            if result < 0 {
+                return Err(Error::last_os_error());
+            }
+            insert_into_map(&config.xsks_map, queue, fd)?;
+            Ok(Xsk { fd, umem, fill, completion, rx, tx })
+        }
+
+        /// Wait for the requests, and pass each frame to `respond` with the length of the
+        /// request, which is followed by the room for the rest of the frame. The frames that it
+        /// returns the length of a response for are sent back, and the others are dropped.
+        pub fn process<F>(&mut self, mut respond: F) -> Result<(), Error>
+        where
+            F: FnMut(&mut [u8], usize) -> Option<usize>,
+        {
+            // The frames that were sent take the receives again.
+            while let Some(addr) = self.completion.pop() {
+                self.fill.push(addr);
+            }
+
+            let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut pollfd, 1, POLL_TIMEOUT) } < 0 {
+                let err = Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    return Ok(());
+                }
+                return Err(err);
+            }
+
+            let mut sent = false;
+            while let Some(desc) = self.rx.pop() {
+                let start = desc.addr as usize;
+                let frame_start = start - start % FRAME_SIZE;
+                let frame = unsafe {
+                    slice::from_raw_parts_mut(self.umem.ptr.add(start),
+                                              frame_start + FRAME_SIZE - start)
+                };
+                match respond(frame, desc.len as usize) {
+                    Some(len) if self.tx.has_room() => {
+                        self.tx.push(Desc { addr: desc.addr, len: len as u32, options: 0 });
+                        sent = true;
+                    },
+                    _ => self.fill.push(frame_start as u64),
+                }
+            }
+            if sent && self.tx.needs_wakeup() {
+                // The sends are kicked off without waiting for them.
+                unsafe {
+                    libc::sendto(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0)
+                };
+            }
+            Ok(())
+        }
+    }
+
+    // The shared memory is only touched through the socket, so it can move to a worker.
+    unsafe impl Send for Xsk {}
+
+    impl Drop for Xsk {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(not(all(feature = "af-xdp", target_os = "linux")))]
+mod stub {
+    use std::io::{Error, ErrorKind};
+
+    use super::XdpConfig;
+
+    /// The stand-in for the socket without the `af-xdp` feature, which is never set up.
+    pub struct Xsk(());
+
+    impl Xsk {
+        pub fn new(_config: &XdpConfig, _queue: u32) -> Result<Xsk, Error> {
+            Err(Error::new(ErrorKind::Other, "built without the af-xdp feature"))
+        }
+
+        pub fn process<F>(&mut self, _respond: F) -> Result<(), Error>
+        where
+            F: FnMut(&mut [u8], usize) -> Option<usize>,
+        {
+            unreachable!()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const SERVER_MAC: [u8; 6] = [2, 0, 0, 0, 0, 2];
+
+    /// Return a frame from the client to the server with the UDP payload.
+    fn frame(client: SocketAddr, server: SocketAddr, vlan: bool, payload: &[u8]) -> Vec<u8> {
+        let mut frame = SERVER_MAC.to_vec();
+        frame.extend(&CLIENT_MAC);
+        if vlan {
+            frame.extend(&[0x81, 0x00, 0x00, 0x2a]);
+        }
+        let udp_len = (UDP_HEADER_LEN + payload.len()) as u16;
+        match (client.ip(), server.ip()) {
+            (IpAddr::V4(client), IpAddr::V4(server)) => {
+                frame.extend(&ETHERTYPE_IPV4.to_be_bytes());
+                frame.extend(&[0x45, 0]);
+                frame.extend(&(IPV4_HEADER_LEN as u16 + udp_len).to_be_bytes());
+                frame.extend(&[0, 0, 0, 0, 64, IPPROTO_UDP, 0, 0]);
+                frame.extend(&client.octets());
+                frame.extend(&server.octets());
+            },
+            (IpAddr::V6(client), IpAddr::V6(server)) => {
+                frame.extend(&ETHERTYPE_IPV6.to_be_bytes());
+                frame.extend(&[0x60, 0, 0, 0]);
+                frame.extend(&udp_len.to_be_bytes());
+                frame.extend(&[IPPROTO_UDP, 64]);
+                frame.extend(&client.octets());
+                frame.extend(&server.octets());
+            },
+            _ => unreachable!(),
+        }
+        frame.extend(&client.port().to_be_bytes());
+        frame.extend(&server.port().to_be_bytes());
+        frame.extend(&udp_len.to_be_bytes());
+        frame.extend(&[0, 0]);
+        frame.extend(payload);
+        frame
+    }
+
+    /// Check the checksums of a frame that was written by `write_response`.
+    fn check_checksums(frame: &[u8], ip_offset: usize) {
+        let ip = &frame[ip_offset..];
+        let (pseudo, udp) = if ip[0] >> 4 == 4 {
+            assert_eq!(checksum_finish(checksum_add(0, &ip[..IPV4_HEADER_LEN])), 0);
+            (&ip[12..20], &ip[IPV4_HEADER_LEN..])
+        } else {
+            (&ip[8..40], &ip[IPV6_HEADER_LEN..])
+        };
+        let mut sum = checksum_add(0, pseudo);
+        sum = checksum_add(sum, &[0, IPPROTO_UDP]);
+        sum = checksum_add(sum, &(udp.len() as u16).to_be_bytes());
+        assert_eq!(checksum_finish(checksum_add(sum, udp)), 0);
+    }
+
+    #[test]
+    fn test_checksum() {
+        // The example of RFC 1071.
+        let sum = checksum_add(0, &[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]);
+        assert_eq!(checksum_finish(sum), !0xddf2);
+        assert_eq!(checksum_add(0, &[0x12]), 0x1200);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cases: Vec<(SocketAddr, SocketAddr)> = vec![
+            ("192.0.2.1:40000".parse().unwrap(), "198.51.100.1:123".parse().unwrap()),
+            ("[2001:db8::1]:40000".parse().unwrap(), "[2001:db8::123]:123".parse().unwrap()),
+        ];
+        for (client, server) in cases {
+            for &vlan in &[false, true] {
+                let request_frame = frame(client, server, vlan, &[0x23; 48]);
+                let request = parse_request(&request_frame, 123).unwrap();
+                assert_eq!(request.client, client);
+                assert_eq!(request.local, server);
+                assert_eq!(&request_frame[request.payload.clone()], &[0x23; 48][..]);
+                assert!(parse_request(&request_frame, 124).is_none());
+                assert!(parse_request(&request_frame[..request.payload.end - 1], 123).is_none());
+
+                // The response is longer than the request, and the frame has room for it.
+                let mut buf = request_frame.clone();
+                buf.resize(2048, 0);
+                let len = write_response(&mut buf, &request, &[0x24; 72], Some(46)).unwrap();
+                let response_frame = &buf[..len];
+                assert_eq!(len, request_frame.len() + 24);
+                assert_eq!(&response_frame[..6], &CLIENT_MAC);
+                assert_eq!(&response_frame[6..12], &SERVER_MAC);
+                let response = parse_request(response_frame, 40000).unwrap();
+                assert_eq!(response.client, server);
+                assert_eq!(response.local, client);
+                assert_eq!(&response_frame[response.payload.clone()], &[0x24; 72][..]);
+                check_checksums(response_frame, request.ip_offset);
+                // The DSCP is in the top six bits of the traffic class.
+                let ip = &response_frame[request.ip_offset..];
+                let traffic_class = match client {
+                    SocketAddr::V4(_) => ip[1],
+                    SocketAddr::V6(_) => ip[0] << 4 | ip[1] >> 4,
+                };
+                assert_eq!(traffic_class >> 2, 46);
+
+                assert!(write_response(&mut buf[..len - 1], &request, &[0x24; 72], None)
+                        .is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_not_ntp() {
+        let client = "192.0.2.1:40000".parse().unwrap();
+        let server = "198.51.100.1:123".parse().unwrap();
+        let request_frame = frame(client, server, false, &[0x23; 48]);
+        // A fragment.
+        let mut fragment = request_frame.clone();
+        fragment[ETHERNET_HEADER_LEN + 6] = 0x20;
+        assert!(parse_request(&fragment, 123).is_none());
+        // TCP.
+        let mut tcp = request_frame.clone();
+        tcp[ETHERNET_HEADER_LEN + 9] = 6;
+        assert!(parse_request(&tcp, 123).is_none());
+        // ARP.
+        let mut arp = request_frame;
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert!(parse_request(&arp, 123).is_none());
+    }
+}
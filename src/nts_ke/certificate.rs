@@ -0,0 +1,364 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The details of an X.509 certificate that the client reports about the NTS-KE server: its
+//! names, its validity and its public key. Only the few fields that are needed are read out of
+//! the DER encoding, since the certificate has already been verified by rustls. The public keys
+//! can also be pinned, so that only a server whose verified chain has one of them is trusted.
+
+use ring::digest;
+use rustls::{Certificate, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use webpki::{EndEntityCert, SignatureAlgorithm, TLSServerTrustAnchors, TrustAnchor};
+
+use std::io::{Error, ErrorKind};
+use std::time::SystemTime;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+
+/// The signature algorithms that the chains are verified with, which are the ones of rustls.
+static SIGNATURE_ALGORITHMS: &[&SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// The explicit tag of the version of the certificate.
+const TAG_VERSION: u8 = 0xa0;
+
+/// The attributes of the names that are shown, and their short names. The OIDs are 2.5.4.x.
+const ATTRIBUTES: [(u8, &str); 6] = [(6, "C"), (8, "ST"), (7, "L"), (10, "O"), (11, "OU"),
+                                     (3, "CN")];
+
+/// The details of a certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificateInfo {
+    /// The subject, like `C=US, O=Example, CN=example.com`.
+    pub subject: String,
+
+    pub issuer: String,
+
+    /// The validity in RFC 3339.
+    pub not_before: String,
+    pub not_after: String,
+
+    /// The DER encoding of the SubjectPublicKeyInfo.
+    pub spki: Vec<u8>,
+
+    /// The SHA-256 hash of the whole certificate.
+    pub fingerprint: Vec<u8>,
+}
+
+fn malformed() -> Error {
+    Error::new(ErrorKind::InvalidData, "malformed certificate")
+}
+
+/// Read the DER element at the start of `input` with the tag `tag`, and return its contents and
+/// its whole encoding. `input` is moved past it.
+fn read<'a>(input: &mut &'a [u8], tag: u8) -> Result<(&'a [u8], &'a [u8]), Error> {
+    let data = *input;
+    if data.len() < 2 || data[0] != tag {
+        return Err(malformed());
+    }
+    let (len, header) = match data[1] {
+        len if len < 0x80 => (usize::from(len), 2),
+        0x81..=0x84 => {
+            let count = usize::from(data[1] & 0x7f);
+            let bytes = data.get(2..2 + count).ok_or_else(malformed)?;
+            (bytes.iter().fold(0, |len, &byte| len << 8 | usize::from(byte)), 2 + count)
+        },
+        _ => return Err(malformed()),
+    };
+    let end = header.checked_add(len).filter(|&end| end <= data.len()).ok_or_else(malformed)?;
+    *input = &data[end..];
+    Ok((&data[header..end], &data[..end]))
+}
+
+/// Return the tag of the element at the start of `input`.
+fn peek(input: &[u8]) -> Option<u8> {
+    input.first().cloned()
+}
+
+/// Return the attributes of a Name that are shown.
+fn name(mut input: &[u8]) -> Result<String, Error> {
+    let mut attributes = Vec::new();
+    while !input.is_empty() {
+        let (mut set, _) = read(&mut input, TAG_SET)?;
+        while !set.is_empty() {
+            let (mut attribute, _) = read(&mut set, TAG_SEQUENCE)?;
+            let (oid, _) = read(&mut attribute, TAG_OID)?;
+            // The value is one of the string types, which are all read as UTF-8.
+            let tag = peek(attribute).ok_or_else(malformed)?;
+            let (value, _) = read(&mut attribute, tag)?;
+            if let [0x55, 0x04, kind] = *oid {
+                if let Some((_, short)) = ATTRIBUTES.iter().find(|(id, _)| *id == kind) {
+                    attributes.push(format!("{}={}", short, String::from_utf8_lossy(value)));
+                }
+            }
+        }
+    }
+    Ok(attributes.join(", "))
+}
+
+/// Return a UTCTime or GeneralizedTime in RFC 3339.
+fn time(input: &mut &[u8]) -> Result<String, Error> {
+    let tag = peek(input).ok_or_else(malformed)?;
+    let (value, _) = read(input, tag)?;
+    let value = std::str::from_utf8(value).map_err(|_| malformed())?;
+    let full = match (tag, value.len()) {
+        // The two-digit years are from 1950 to 2049.
+        (TAG_UTC_TIME, 13) if &value[..2] >= "50" => format!("19{}", value),
+        (TAG_UTC_TIME, 13) => format!("20{}", value),
+        (TAG_GENERALIZED_TIME, 15) => String::from(value),
+        _ => return Err(malformed()),
+    };
+    if !full.ends_with('Z') || !full[..14].bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(malformed());
+    }
+    Ok(format!("{}-{}-{}T{}:{}:{}Z", &full[..4], &full[4..6], &full[6..8], &full[8..10],
+               &full[10..12], &full[12..14]))
+}
+
+/// Return the details of the certificate with the DER encoding `der`.
+pub fn certificate_info(der: &[u8]) -> Result<CertificateInfo, Error> {
+    let mut input = der;
+    let (mut certificate, _) = read(&mut input, TAG_SEQUENCE)?;
+    let (mut tbs, _) = read(&mut certificate, TAG_SEQUENCE)?;
+    if peek(tbs) == Some(TAG_VERSION) {
+        read(&mut tbs, TAG_VERSION)?;
+    }
+    read(&mut tbs, TAG_INTEGER)?;
+    read(&mut tbs, TAG_SEQUENCE)?;
+    let (issuer, _) = read(&mut tbs, TAG_SEQUENCE)?;
+    let (mut validity, _) = read(&mut tbs, TAG_SEQUENCE)?;
+    let not_before = time(&mut validity)?;
+    let not_after = time(&mut validity)?;
+    let (subject, _) = read(&mut tbs, TAG_SEQUENCE)?;
+    let (_, spki) = read(&mut tbs, TAG_SEQUENCE)?;
+
+    Ok(CertificateInfo {
+        subject: name(subject)?,
+        issuer: name(issuer)?,
+        not_before,
+        not_after,
+        spki: spki.to_vec(),
+        fingerprint: digest::digest(&digest::SHA256, der).as_ref().to_vec(),
+    })
+}
+
+/// Return the SHA-256 hash of the SubjectPublicKeyInfo of the certificate with the DER
+/// encoding `der`, which is what is pinned.
+pub fn spki_sha256(der: &[u8]) -> Result<Vec<u8>, Error> {
+    let info = certificate_info(der)?;
+    Ok(digest::digest(&digest::SHA256, &info.spki).as_ref().to_vec())
+}
+
+/// Return the SHA-256 hash of the SubjectPublicKeyInfo of a trust anchor, which only has the
+/// contents of the SEQUENCE.
+fn anchor_spki_sha256(anchor: &TrustAnchor) -> Vec<u8> {
+    let len = anchor.spki.len();
+    let mut spki = vec![TAG_SEQUENCE];
+    match len {
+        0..=0x7f => spki.push(len as u8),
+        0x80..=0xff => spki.extend_from_slice(&[0x81, len as u8]),
+        _ => spki.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    spki.extend_from_slice(anchor.spki);
+    digest::digest(&digest::SHA256, &spki).as_ref().to_vec()
+}
+
+/// Return whether a public key whose hash is one of `pins` is on a verified path from the
+/// end-entity certificate, which is the first one of the chain, to a root of `roots`. The other
+/// certificates that the server sends only count if the end-entity certificate chains up to
+/// them, since the server can send any extra ones.
+pub fn is_pinned(
+    roots: &RootCertStore,
+    certificates: &[Certificate],
+    pins: &[Vec<u8>],
+    time: webpki::Time,
+) -> bool {
+    let (end_entity, intermediates) = match certificates.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+    if spki_sha256(&end_entity.0).ok().map_or(false, |hash| pins.contains(&hash)) {
+        return true;
+    }
+
+    // The path is built again, up to the pinned certificates and roots only.
+    let pinned_certificates: Vec<TrustAnchor> = intermediates.iter()
+        .filter(|certificate| {
+            spki_sha256(&certificate.0).ok().map_or(false, |hash| pins.contains(&hash))
+        })
+        .filter_map(|certificate| {
+            webpki::trust_anchor_util::cert_der_as_trust_anchor(&certificate.0).ok()
+        })
+        .collect();
+    let anchors: Vec<TrustAnchor> = roots.roots.iter()
+        .map(|root| root.to_trust_anchor())
+        .filter(|anchor| pins.contains(&anchor_spki_sha256(anchor)))
+        .chain(pinned_certificates)
+        .collect();
+    if anchors.is_empty() {
+        return false;
+    }
+    let intermediates: Vec<&[u8]> = intermediates.iter()
+        .map(|certificate| certificate.0.as_slice())
+        .collect();
+    EndEntityCert::from(&end_entity.0)
+        .and_then(|cert| cert.verify_is_valid_tls_server_cert(SIGNATURE_ALGORITHMS,
+                                                              &TLSServerTrustAnchors(&anchors),
+                                                              &intermediates, time))
+        .is_ok()
+}
+
+/// Verifies the chain of the server like rustls does, and then checks that a public key on the
+/// verified path is pinned.
+pub struct PinningVerifier {
+    /// A default configuration, whose verifier is the one of rustls, which is not public.
+    default: rustls::ClientConfig,
+
+    /// The SHA-256 hashes of the SubjectPublicKeyInfo that are pinned.
+    pins: Vec<Vec<u8>>,
+}
+
+impl PinningVerifier {
+    pub fn new(pins: Vec<Vec<u8>>) -> PinningVerifier {
+        PinningVerifier { default: rustls::ClientConfig::new(), pins }
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified = self.default.get_verifier()
+            .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        let time = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        if !is_pinned(roots, presented_certs, &self.pins, time) {
+            let message = "the public key of the server is not pinned";
+            return Err(TLSError::General(String::from(message)));
+        }
+        Ok(verified)
+    }
+}
+
+/// Accepts any chain, for the test servers with self-signed certificates.
+pub struct InsecureVerifier;
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::internal::pemfile::certs;
+
+    #[test]
+    fn test_certificate_info() {
+        let certificates = certs(&mut &include_bytes!("../../tests/tls.pem")[..]).unwrap();
+        let info = certificate_info(&certificates[0].0).unwrap();
+        assert_eq!(info.subject,
+                   "C=US, ST=CA, L=San Francisco, O=Cloudflare test, OU=Crypto team, CN=localhost");
+        assert_eq!(info.issuer, "C=US, ST=California, L=San Francisco, O=HappyCert, Inc., \
+                                 OU=HappyCert Intermediate, CN=(dev use only)");
+        assert_eq!(info.not_before, "2019-05-31T17:04:00Z");
+        assert_eq!(info.not_after, "2020-05-30T17:04:00Z");
+        assert_eq!(info.spki[0], TAG_SEQUENCE);
+        assert_eq!(&info.fingerprint[..4], &[0x13, 0xc8, 0xc1, 0x43]);
+
+        assert!(certificate_info(&certificates[0].0[..100]).is_err());
+        assert!(certificate_info(&[]).is_err());
+    }
+
+    /// A time when the test certificates are valid.
+    fn time() -> webpki::Time {
+        webpki::Time::from_seconds_since_unix_epoch(1_580_000_000)
+    }
+
+    fn pem(data: &[u8]) -> Vec<Certificate> {
+        certs(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn test_is_pinned() {
+        let roots = RootCertStore::empty();
+        let certificates = pem(include_bytes!("../../tests/tls.pem"));
+        let pin = base64::decode("NsqFhJKXQ3I93n0J5iVHuJ6hnZNT+UpUnfll8tc1Ja0=").unwrap();
+        assert_eq!(spki_sha256(&certificates[0].0).unwrap(), pin);
+        assert!(is_pinned(&roots, &certificates, &[vec![0; 32], pin], time()));
+        assert!(!is_pinned(&roots, &certificates, &[vec![0; 32]], time()));
+        assert!(!is_pinned(&roots, &[], &[vec![0; 32]], time()));
+    }
+
+    #[test]
+    fn test_is_pinned_intermediate() {
+        let leaf = pem(include_bytes!("../../tests/tls.pem"));
+        let intermediate = pem(include_bytes!("../../tests/intermediate.pem"));
+        let pins = vec![spki_sha256(&intermediate[0].0).unwrap()];
+        let chain = vec![leaf[0].clone(), intermediate[0].clone()];
+        let roots = RootCertStore::empty();
+        assert!(is_pinned(&roots, &chain, &pins, time()));
+
+        // The intermediate certificate doesn't count if the path doesn't go through it.
+        let other = pem(include_bytes!("../../tests/ca.pem"));
+        let chain = vec![other[0].clone(), intermediate[0].clone()];
+        assert!(!is_pinned(&roots, &chain, &pins, time()));
+    }
+
+    #[test]
+    fn test_is_pinned_root() {
+        let chain = pem(include_bytes!("../../tests/chain.pem"));
+        let ca = pem(include_bytes!("../../tests/ca.pem"));
+        let pins = vec![spki_sha256(&ca[0].0).unwrap()];
+        let mut roots = RootCertStore::empty();
+        roots.add(&ca[0]).unwrap();
+        assert!(is_pinned(&roots, &chain[..2], &pins, time()));
+
+        // The root must be one of the trusted ones, or sent after the intermediate one.
+        assert!(!is_pinned(&RootCertStore::empty(), &chain[..2], &pins, time()));
+        assert!(is_pinned(&RootCertStore::empty(), &chain, &pins, time()));
+    }
+
+    #[test]
+    fn test_is_pinned_extra_certificate() {
+        let leaf = pem(include_bytes!("../../tests/tls.pem"));
+        let ca = pem(include_bytes!("../../tests/ca.pem"));
+        let ca_pin = spki_sha256(&ca[0].0).unwrap();
+
+        // A pinned certificate that the server sends after its own doesn't make it trusted if
+        // the path to it is missing.
+        let chain = vec![leaf[0].clone(), ca[0].clone()];
+        let pins = vec![ca_pin];
+        assert!(!is_pinned(&RootCertStore::empty(), &chain, &pins, time()));
+    }
+}
@@ -1,4 +1,4 @@
-use slog::{debug, info};
+use slog::{debug, info, warn};
 use std::error::Error;
 use std::fmt;
 use std::io::{Read, Write};
@@ -6,10 +6,11 @@ use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::sync::Arc;
 use std::time::Duration;
 
-use rustls;
+use rustls::{self, Session};
 use webpki;
 use webpki_roots;
 
+use super::certificate::{InsecureVerifier, PinningVerifier};
 use super::records;
 
 use self::ClientError::*;
@@ -53,8 +54,8 @@ struct ClientState {
     cookies: Vec<Cookie>,
     next_protocols: Vec<u16>,
     aead_scheme: u16,
-    next_port: u16,
-    next_server: String,
+    next_port: Option<u16>,
+    next_servers: Vec<String>,
     keys: NTSKeys,
 }
 
@@ -63,10 +64,16 @@ pub struct NtsKeResult {
     pub cookies: Vec<Cookie>,
     pub next_protocols: Vec<u16>,
     pub aead_scheme: u16,
-    pub next_server: String,
+    /// The NTP servers in the order that they are tried. It's the NTS-KE server if it didn't
+    /// name any.
+    pub next_servers: Vec<String>,
     pub next_port: u16,
+    /// Whether the NTS-KE server named the NTP servers or their port.
+    pub redirected: bool,
     pub keys: NTSKeys,
     pub use_ipv4: Option<bool>,
+    /// The certificate chain of the server, starting with its own.
+    pub certificates: Vec<rustls::Certificate>,
 }
 
 #[derive(Debug, Clone)]
@@ -116,17 +123,18 @@ fn process_record(
         KeRecord::Error(_) => return Err(Box::new(ErrorRecord)),
         KeRecord::Warning(_) => return Ok(()),
         KeRecord::AeadAlgorithm(record) => {
-            // TODO: Accessing at index zero can panic.
-            let algorithm = record.algorithms()[0];
-            state.aead_scheme = algorithm.as_algorithm_id();
-
+            // The server must choose exactly one of the algorithms that we offered.
             if record.algorithms().len() != 1 {
                 return Err(Box::new(InvalidRecord));
             }
+
+            let algorithm = record.algorithms()[0];
+            state.aead_scheme = algorithm.as_algorithm_id();
         }
         KeRecord::NewCookie(record) => state.cookies.push(record.into_bytes()),
-        KeRecord::Server(record) => state.next_server = record.into_string(),
-        KeRecord::Port(record) => state.next_port = record.port(),
+        // Each of the servers is a fallback for the ones before it.
+        KeRecord::Server(record) => state.next_servers.push(record.into_string()),
+        KeRecord::Port(record) => state.next_port = Some(record.port()),
     }
 
     Ok(())
@@ -142,18 +150,39 @@ pub fn run_nts_ke_client(
     let alpn_bytes = alpn_proto.into_bytes();
     tls_config.set_protocols(&[alpn_bytes]);
 
-    match client_config.trusted_cert {
-        Some(cert) => {
-            info!(logger, "loading custom trust root");
-            tls_config.root_store.add(&cert)?;
+    if client_config.trusted_certs.is_empty() {
+        tls_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    } else {
+        info!(logger, "loading {} custom trust roots", client_config.trusted_certs.len());
+        // A bundle can have certificates that are not trust anchors, which are skipped.
+        for cert in &client_config.trusted_certs {
+            if let Err(err) = tls_config.root_store.add(cert) {
+                debug!(logger, "skipping a trust root: {:?}", err);
+            }
         }
-        None => {
-            tls_config
-                .root_store
-                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        if tls_config.root_store.is_empty() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "none of the custom trust roots can be used",
+            )));
         }
     }
 
+    if client_config.insecure {
+        warn!(logger, "NOT VERIFYING THE CERTIFICATE OF {}: anyone on the path can impersonate \
+                       the server, so this is only for testing", client_config.host);
+        tls_config.dangerous().set_certificate_verifier(Arc::new(InsecureVerifier));
+    } else if !client_config.pins.is_empty() {
+        let verifier = PinningVerifier::new(client_config.pins.clone());
+        tls_config.dangerous().set_certificate_verifier(Arc::new(verifier));
+    }
+
+    if client_config.key_log {
+        tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
+
     let rc_config = Arc::new(tls_config);
     let hostname = webpki::DNSNameRef::try_from_ascii_str(client_config.host.as_str())
         .expect("server hostname is invalid");
@@ -200,14 +229,15 @@ pub fn run_nts_ke_client(
     tls_stream.write(clientrec)?;
     tls_stream.flush()?;
     debug!(logger, "Request transmitted");
-    let keys = records::gen_key(tls_stream.sess).unwrap();
+    let keys = records::gen_key(tls_stream.sess, KnownAeadAlgorithm::AeadAesSivCmac256)
+        .unwrap();
 
     let mut state = ClientState {
         finished: false,
         cookies: Vec::new(),
         next_protocols: Vec::new(),
-        next_server: client_config.host.clone(),
-        next_port: DEFAULT_NTP_PORT,
+        next_servers: Vec::new(),
+        next_port: None,
         keys: keys,
         aead_scheme: DEFAULT_SCHEME,
     };
@@ -270,15 +300,27 @@ pub fn run_nts_ke_client(
         }
     }
     debug!(logger, "saw the end of the response");
+    let redirected = !state.next_servers.is_empty() || state.next_port.is_some();
+    if state.next_servers.is_empty() {
+        state.next_servers.push(client_config.host.clone());
+    }
+    let next_port = state.next_port.unwrap_or(DEFAULT_NTP_PORT);
+    if redirected {
+        info!(logger, "the NTS-KE server {} redirects NTP to {} on port {}", client_config.host,
+              state.next_servers.join(", "), next_port);
+    }
+    let certificates = client.get_peer_certificates().unwrap_or_default();
     stream.shutdown(Shutdown::Write)?;
 
     Ok(NtsKeResult {
         aead_scheme: state.aead_scheme,
         cookies: state.cookies,
         next_protocols: state.next_protocols,
-        next_server: state.next_server,
-        next_port: state.next_port,
+        next_servers: state.next_servers,
+        next_port,
+        redirected,
         keys: state.keys,
         use_ipv4: client_config.use_ipv4,
+        certificates,
     })
 }
@@ -1,3 +1,4 @@
+pub mod certificate;
 pub mod client;
 pub mod records;
 pub mod server;
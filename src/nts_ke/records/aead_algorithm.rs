@@ -9,15 +9,42 @@ use std::convert::TryFrom;
 use super::KeRecordTrait;
 use super::Party;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum KnownAeadAlgorithm {
     AeadAesSivCmac256,
+    AeadAes128GcmSiv,
 }
 
 impl KnownAeadAlgorithm {
     pub fn as_algorithm_id(&self) -> u16 {
         match self {
             KnownAeadAlgorithm::AeadAesSivCmac256 => 15,
+            KnownAeadAlgorithm::AeadAes128GcmSiv => 30,
+        }
+    }
+
+    /// Return the algorithm with the IANA id, if we support it.
+    pub fn from_algorithm_id(id: u16) -> Option<KnownAeadAlgorithm> {
+        match id {
+            15 => Some(KnownAeadAlgorithm::AeadAesSivCmac256),
+            30 => Some(KnownAeadAlgorithm::AeadAes128GcmSiv),
+            _ => None,
+        }
+    }
+
+    /// Return the IANA name of the algorithm.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KnownAeadAlgorithm::AeadAesSivCmac256 => "AEAD_AES_SIV_CMAC_256",
+            KnownAeadAlgorithm::AeadAes128GcmSiv => "AEAD_AES_128_GCM_SIV",
+        }
+    }
+
+    /// Return the length of the keys in bytes.
+    pub fn key_len(&self) -> usize {
+        match self {
+            KnownAeadAlgorithm::AeadAesSivCmac256 => 32,
+            KnownAeadAlgorithm::AeadAes128GcmSiv => 16,
         }
     }
 }
@@ -81,11 +108,10 @@ impl KeRecordTrait for AeadAlgorithmRecord {
         for word in bytes.chunks_exact(2) {
             let algorithm_code = u16::from_be_bytes([word[0], word[1]]);
 
-            let algorithm = KnownAeadAlgorithm::AeadAesSivCmac256;
-            if algorithm.as_algorithm_id() == algorithm_code {
+            // The client may offer algorithms that we don't support. They are just not
+            // negotiable, so we skip them.
+            if let Some(algorithm) = KnownAeadAlgorithm::from_algorithm_id(algorithm_code) {
                 algorithms.push(algorithm);
-            } else {
-                return Err(String::from("unknown AEAD algorithm id"));
             }
         }
 
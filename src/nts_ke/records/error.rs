@@ -7,21 +7,49 @@
 use super::KeRecordTrait;
 use super::Party;
 
-enum ErrorKind {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KnownErrorCode {
     UnrecognizedCriticalRecord,
     BadRequest,
+    InternalServerError,
 }
 
-impl ErrorKind {
-    fn as_code(&self) -> u16 {
+impl KnownErrorCode {
+    pub fn as_code(&self) -> u16 {
         match self {
-            ErrorKind::UnrecognizedCriticalRecord => 0,
-            ErrorKind::BadRequest => 1,
+            KnownErrorCode::UnrecognizedCriticalRecord => 0,
+            KnownErrorCode::BadRequest => 1,
+            KnownErrorCode::InternalServerError => 2,
+        }
+    }
+
+    /// Return the error with the IANA code, if we know it.
+    pub fn from_code(code: u16) -> Option<KnownErrorCode> {
+        match code {
+            0 => Some(KnownErrorCode::UnrecognizedCriticalRecord),
+            1 => Some(KnownErrorCode::BadRequest),
+            2 => Some(KnownErrorCode::InternalServerError),
+            _ => None,
+        }
+    }
+
+    /// Return the name of the error used in the logs and the metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KnownErrorCode::UnrecognizedCriticalRecord => "unrecognized_critical_record",
+            KnownErrorCode::BadRequest => "bad_request",
+            KnownErrorCode::InternalServerError => "internal_server_error",
         }
     }
 }
 
-pub struct ErrorRecord(ErrorKind);
+pub struct ErrorRecord(KnownErrorCode);
+
+impl ErrorRecord {
+    pub fn new(code: KnownErrorCode) -> ErrorRecord {
+        ErrorRecord(code)
+    }
+}
 
 impl KeRecordTrait for ErrorRecord {
     fn critical(&self) -> bool {
@@ -48,16 +76,9 @@ impl KeRecordTrait for ErrorRecord {
 
         let error_code = u16::from_be_bytes([bytes[0], bytes[1]]);
 
-        let kind = ErrorKind::UnrecognizedCriticalRecord;
-        if kind.as_code() == error_code {
-            return Ok(ErrorRecord(kind));
+        match KnownErrorCode::from_code(error_code) {
+            Some(code) => Ok(ErrorRecord(code)),
+            None => Err(String::from("unknown error code")),
         }
-
-        let kind = ErrorKind::BadRequest;
-        if kind.as_code() == error_code {
-            return Ok(ErrorRecord(kind));
-        }
-
-        return Err(String::from("unknown error code"));
     }
 }
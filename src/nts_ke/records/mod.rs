@@ -30,6 +30,9 @@ use crate::cookie::NTSKeys;
 
 pub const HEADER_SIZE: usize = 4;
 
+/// The label of the TLS exporter that the keys are exported with.
+pub const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security/1";
+
 pub enum KeRecord {
     EndOfMessage(EndOfMessageRecord),
     NextProtocol(NextProtocolRecord),
@@ -103,7 +106,7 @@ pub fn deserialize(sender: Party, bytes: &[u8]) -> Result<KeRecord, DeserializeE
     let critical = bytes[0] >> 7 == 1;
 
     // The following 15 bits are the record type number.
-    let record_type = u16::from_be_bytes([bytes[0] & 0x7, bytes[1]]);
+    let record_type = u16::from_be_bytes([bytes[0] & 0x7f, bytes[1]]);
 
     // The third and fourth bytes are the body length.
     let length = u16::from_be_bytes([bytes[2], bytes[3]]);
@@ -145,20 +148,24 @@ pub fn deserialize(sender: Party, bytes: &[u8]) -> Result<KeRecord, DeserializeE
     Ok(record)
 }
 
-/// gen_key computes the client and server keys using exporters.
+/// gen_key computes the client and server keys for the negotiated AEAD algorithm using exporters.
 /// https://tools.ietf.org/html/draft-ietf-ntp-using-nts-for-ntp-18#section-6
-pub fn gen_key<T: rustls::Session>(session: &T) -> Result<NTSKeys, TLSError> {
+pub fn gen_key<T: rustls::Session>(session: &T, aead: KnownAeadAlgorithm)
+    -> Result<NTSKeys, TLSError>
+{
     let mut keys: NTSKeys = NTSKeys {
+        aead,
         c2s: [0; 32],
         s2c: [0; 32],
     };
-    let c2s_con = [0, 0, 0, 15, 00];
-    let s2c_con = [0, 0, 0, 15, 01];
+    let [aead_hi, aead_lo] = aead.as_algorithm_id().to_be_bytes();
+    let c2s_con = [0, 0, aead_hi, aead_lo, 0];
+    let s2c_con = [0, 0, aead_hi, aead_lo, 1];
     let context_c2s = Some(&c2s_con[..]);
     let context_s2c = Some(&s2c_con[..]);
-    let label = "EXPORTER-network-time-security/1".as_bytes();
-    session.export_keying_material(&mut keys.c2s, label, context_c2s)?;
-    session.export_keying_material(&mut keys.s2c, label, context_s2c)?;
+    let key_len = aead.key_len();
+    session.export_keying_material(&mut keys.c2s[..key_len], EXPORTER_LABEL, context_c2s)?;
+    session.export_keying_material(&mut keys.s2c[..key_len], EXPORTER_LABEL, context_s2c)?;
 
     Ok(keys)
 }
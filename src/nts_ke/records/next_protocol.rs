@@ -9,7 +9,7 @@ use std::convert::TryFrom;
 use super::KeRecordTrait;
 use super::Party;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum KnownNextProtocol {
     Ntpv4,
 }
@@ -79,11 +79,11 @@ impl KeRecordTrait for NextProtocolRecord {
         for word in bytes.chunks_exact(2) {
             let protocol_code = u16::from_be_bytes([word[0], word[1]]);
 
+            // The client may offer protocols that we don't support. They are just not
+            // negotiable, so we skip them.
             let protocol = KnownNextProtocol::Ntpv4;
             if protocol.as_protocol_id() == protocol_code {
                 protocols.push(protocol);
-            } else {
-                return Err(String::from("unknown Next Protocol id"));
             }
         }
 
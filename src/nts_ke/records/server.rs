@@ -3,8 +3,7 @@
 // See LICENSE for licensing information.
 
 //! Server negotiation record representation.
-/// This Server negotiation will not be sent from the server because currently, we are not
-/// interested in running an NTP server on different IP address.
+
 use std::convert::TryFrom;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
@@ -25,6 +24,19 @@ pub struct ServerRecord {
 }
 
 impl ServerRecord {
+    /// Create a record from a hostname, an IPv4 address, or an IPv6 address.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the address is empty, not ASCII, or too long for a record.
+    ///
+    pub fn new(sender: Party, address: &str) -> Result<ServerRecord, String> {
+        if address.is_empty() || u16::try_from(address.len()).is_err() {
+            return Err(format!("invalid server address length {}", address.len()));
+        }
+        ServerRecord::from_bytes(sender, address.as_bytes())
+    }
+
     pub fn into_string(self) -> String {
         match self.address {
             Address::Hostname(name) => name,
@@ -0,0 +1,239 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! TLS certificate resolution for the NTS-KE server.
+
+use rustls::{Certificate, PrivateKey, ResolvesServerCert, SignatureScheme, TLSError};
+use rustls::sign::{self, CertifiedKey};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Certificate resolver which selects a certificate chain using the hostname that the client
+/// sent in the SNI extension.
+///
+/// If the client doesn't send SNI or the hostname is unknown, the default certificate chain is
+/// used instead. The default certificate chain can have alternatives with keys of other types, in
+/// which case the first one that can sign with any of the client's signature schemes is used.
+pub struct KeCertResolver {
+    /// The certificate chains that will be used when there is no match, in order of preference.
+    default: Vec<CertifiedKey>,
+
+    /// The certificate chains indexed by lowercase hostnames.
+    by_name: HashMap<String, CertifiedKey>,
+}
+
+/// Create a `CertifiedKey` from a certificate chain, its corresponding private key, and the OCSP
+/// response to staple, if any.
+fn certified_key(certs: Vec<Certificate>, secret_key: &PrivateKey, ocsp: Option<Vec<u8>>)
+    -> Result<CertifiedKey, TLSError>
+{
+    let signing_key = sign::any_supported_type(secret_key)
+        .map_err(|()| TLSError::General(String::from("invalid private key")))?;
+    let mut key = CertifiedKey::new(certs, Arc::new(signing_key));
+    key.ocsp = ocsp;
+    Ok(key)
+}
+
+impl KeCertResolver {
+    /// Create a new resolver with the default certificate chain, its corresponding private key,
+    /// and its OCSP response, if any.
+    pub fn new(certs: Vec<Certificate>, secret_key: &PrivateKey, ocsp: Option<Vec<u8>>)
+        -> Result<KeCertResolver, TLSError>
+    {
+        Ok(KeCertResolver {
+            default: vec![certified_key(certs, secret_key, ocsp)?],
+            by_name: HashMap::new(),
+        })
+    }
+
+    /// Add an alternative to the default certificate chain. It's used when the client doesn't
+    /// support the signature schemes of the chains added before it.
+    pub fn add_default(
+        &mut self,
+        certs: Vec<Certificate>,
+        secret_key: &PrivateKey,
+        ocsp: Option<Vec<u8>>,
+    ) -> Result<(), TLSError> {
+        self.default.push(certified_key(certs, secret_key, ocsp)?);
+        Ok(())
+    }
+
+    /// Add a certificate chain which will be used when the client asks for `hostname`.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the hostname is not a valid DNS name, the private key is
+    /// invalid, or the end-entity certificate is not valid for the hostname.
+    ///
+    pub fn add(
+        &mut self,
+        hostname: &str,
+        certs: Vec<Certificate>,
+        secret_key: &PrivateKey,
+        ocsp: Option<Vec<u8>>,
+    ) -> Result<(), TLSError> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(hostname)
+            .map_err(|_| TLSError::General(format!("invalid hostname {}", hostname)))?;
+
+        let key = certified_key(certs, secret_key, ocsp)?;
+        key.cross_check_end_entity_cert(Some(name))?;
+
+        // DNS names are case-insensitive, so we index by the lowercase name.
+        self.by_name.insert(hostname.to_ascii_lowercase(), key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for KeCertResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let key = server_name.and_then(|name| {
+            let name: &str = name.into();
+            self.by_name.get(&name.to_ascii_lowercase())
+        });
+        if let Some(key) = key {
+            return Some(key.clone());
+        }
+
+        // If none of the default chains matches the client's signature schemes, we still try
+        // the first one and let the handshake fail the usual way.
+        self.default.iter()
+            .find(|key| key.key.choose_scheme(sigschemes).is_some())
+            .or_else(|| self.default.first())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::Session;
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+
+    fn chain() -> Vec<Certificate> {
+        certs(&mut &include_bytes!("../../../tests/chain.pem")[..]).unwrap()
+    }
+
+    fn leaf() -> Vec<Certificate> {
+        certs(&mut &include_bytes!("../../../tests/tls.pem")[..]).unwrap()
+    }
+
+    fn secret_key() -> PrivateKey {
+        pkcs8_private_keys(&mut &include_bytes!("../../../tests/tls-pkcs8.pem")[..])
+            .unwrap()
+            .remove(0)
+    }
+
+    fn name(hostname: &str) -> Option<webpki::DNSNameRef<'_>> {
+        Some(webpki::DNSNameRef::try_from_ascii_str(hostname).unwrap())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let ecdsa = [SignatureScheme::ECDSA_NISTP256_SHA256];
+        let mut resolver = KeCertResolver::new(leaf(), &secret_key(), None).unwrap();
+        resolver.add("LocalHost", chain(), &secret_key(), Some(vec![1, 2, 3])).unwrap();
+
+        // The hostname matches regardless of case.
+        for hostname in &["localhost", "LOCALHOST"] {
+            let key = resolver.resolve(name(hostname), &ecdsa).unwrap();
+            assert_eq!(key.cert, chain());
+            assert_eq!(key.ocsp, Some(vec![1, 2, 3]));
+        }
+
+        // The default chain is used without SNI or with an unknown hostname.
+        for server_name in vec![None, name("server")] {
+            let key = resolver.resolve(server_name, &ecdsa).unwrap();
+            assert_eq!(key.cert, leaf());
+            assert_eq!(key.ocsp, None);
+        }
+
+        // Without a matching signature scheme, the first default chain is still tried.
+        let key = resolver.resolve(None, &[SignatureScheme::RSA_PSS_SHA256]).unwrap();
+        assert_eq!(key.cert, leaf());
+    }
+
+    #[test]
+    fn test_add() {
+        let mut resolver = KeCertResolver::new(leaf(), &secret_key(), None).unwrap();
+        resolver.add_default(chain(), &secret_key(), None).unwrap();
+        assert_eq!(resolver.default.len(), 2);
+
+        // The certificate is not valid for this name.
+        assert!(resolver.add("example.com", leaf(), &secret_key(), None).is_err());
+        // This is not a DNS name.
+        assert!(resolver.add("local host", leaf(), &secret_key(), None).is_err());
+        // This is not a private key.
+        assert!(resolver.add("localhost", leaf(), &PrivateKey(vec![0; 32]), None).is_err());
+        assert!(resolver.by_name.is_empty());
+    }
+
+    // Records the OCSP response stapled by the server.
+    struct OcspRecorder(std::sync::Mutex<Option<Vec<u8>>>);
+
+    impl rustls::ServerCertVerifier for OcspRecorder {
+        fn verify_server_cert(
+            &self,
+            _roots: &rustls::RootCertStore,
+            _presented_certs: &[Certificate],
+            _dns_name: webpki::DNSNameRef,
+            ocsp_response: &[u8],
+        ) -> Result<rustls::ServerCertVerified, TLSError> {
+            *self.0.lock().unwrap() = Some(ocsp_response.to_vec());
+            Ok(rustls::ServerCertVerified::assertion())
+        }
+    }
+
+    /// Run a TLS 1.3 handshake in memory with the client asking for `hostname`. Return the OCSP
+    /// response that the client received.
+    fn stapled_ocsp(resolver: KeCertResolver, hostname: &str) -> Vec<u8> {
+        let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+        server_config.cert_resolver = Arc::new(resolver);
+
+        let recorder = Arc::new(OcspRecorder(std::sync::Mutex::new(None)));
+        let mut client_config = rustls::ClientConfig::new();
+        client_config.dangerous().set_certificate_verifier(recorder.clone());
+        let name = webpki::DNSNameRef::try_from_ascii_str(hostname).unwrap();
+
+        let mut server = rustls::ServerSession::new(&Arc::new(server_config));
+        let mut client = rustls::ClientSession::new(&Arc::new(client_config), name);
+        while server.is_handshaking() || client.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets().unwrap();
+
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets().unwrap();
+        }
+
+        let ocsp = recorder.0.lock().unwrap().take();
+        ocsp.unwrap()
+    }
+
+    #[test]
+    fn test_ocsp_stapling() {
+        let mut resolver = KeCertResolver::new(leaf(), &secret_key(), Some(vec![1, 2, 3])).unwrap();
+        resolver.add("localhost", chain(), &secret_key(), Some(vec![4, 5, 6])).unwrap();
+        resolver.add("server", chain(), &secret_key(), None).unwrap();
+        assert_eq!(stapled_ocsp(resolver, "localhost"), vec![4, 5, 6]);
+
+        let mut resolver = KeCertResolver::new(leaf(), &secret_key(), Some(vec![1, 2, 3])).unwrap();
+        resolver.add("server", chain(), &secret_key(), None).unwrap();
+        assert_eq!(stapled_ocsp(resolver, "bogus.com"), vec![1, 2, 3]);
+
+        // Nothing is stapled without an OCSP response.
+        let mut resolver = KeCertResolver::new(leaf(), &secret_key(), Some(vec![1, 2, 3])).unwrap();
+        resolver.add("server", chain(), &secret_key(), None).unwrap();
+        assert!(stapled_ocsp(resolver, "server").is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! TLS client certificate verification for the NTS-KE server.
+
+use rustls::{
+    AllowAnyAuthenticatedClient,
+    Certificate,
+    ClientCertVerified,
+    ClientCertVerifier,
+    DistinguishedNames,
+    RootCertStore,
+    TLSError,
+};
+
+use std::sync::Arc;
+
+/// Client certificate verifier which requires the client certificate to be signed by one of the
+/// trusted CAs and, optionally, to be valid for one of the allowed names.
+pub struct KeClientCertVerifier {
+    /// The verifier that checks the certificate chain against the trusted CAs.
+    inner: Arc<dyn ClientCertVerifier>,
+
+    /// The DNS names in the subject alternative names that the client certificate may have. If
+    /// it's empty, every certificate signed by the trusted CAs is accepted.
+    allowed_sans: Vec<String>,
+}
+
+impl KeClientCertVerifier {
+    /// Create a new verifier with the trusted CAs and the allowed names.
+    pub fn new(roots: RootCertStore, allowed_sans: Vec<String>) -> KeClientCertVerifier {
+        KeClientCertVerifier {
+            inner: AllowAnyAuthenticatedClient::new(roots),
+            allowed_sans,
+        }
+    }
+
+    /// Check that the end-entity certificate is valid for at least one of the allowed names.
+    fn check_allowed_sans(&self, cert: &Certificate) -> Result<(), TLSError> {
+        let end_entity = webpki::EndEntityCert::from(&cert.0)
+            .map_err(TLSError::WebPKIError)?;
+
+        let allowed = self.allowed_sans.iter().any(|san| {
+            match webpki::DNSNameRef::try_from_ascii_str(san) {
+                Ok(name) => end_entity.verify_is_valid_for_dns_name(name).is_ok(),
+                Err(_) => false,
+            }
+        });
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(TLSError::General(String::from("client certificate name is not allowed")))
+        }
+    }
+}
+
+impl ClientCertVerifier for KeClientCertVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        true
+    }
+
+    fn client_auth_root_subjects(&self) -> DistinguishedNames {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(&self, presented_certs: &[Certificate])
+        -> Result<ClientCertVerified, TLSError>
+    {
+        let verified = self.inner.verify_client_cert(presented_certs)?;
+
+        if !self.allowed_sans.is_empty() {
+            // The inner verifier already made sure that there is at least one certificate.
+            self.check_allowed_sans(&presented_certs[0])?;
+        }
+
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::internal::pemfile::certs;
+
+    fn verifier(allowed_sans: &[&str]) -> KeClientCertVerifier {
+        let mut roots = RootCertStore::empty();
+        roots.add_pem_file(&mut &include_bytes!("../../../tests/ca.pem")[..]).unwrap();
+        let allowed_sans = allowed_sans.iter().map(|san| san.to_string()).collect();
+        KeClientCertVerifier::new(roots, allowed_sans)
+    }
+
+    #[test]
+    fn test_check_allowed_sans() {
+        let cert = certs(&mut &include_bytes!("../../../tests/tls.pem")[..]).unwrap().remove(0);
+
+        // The certificate is valid for server, localhost, and bogus.com.
+        assert!(verifier(&["example.com", "localhost"]).check_allowed_sans(&cert).is_ok());
+        assert!(verifier(&["example.com"]).check_allowed_sans(&cert).is_err());
+        assert!(verifier(&["not a name"]).check_allowed_sans(&cert).is_err());
+        assert!(verifier(&["localhost"]).check_allowed_sans(&Certificate(vec![0; 32])).is_err());
+    }
+
+    #[test]
+    fn test_client_auth() {
+        let verifier = verifier(&[]);
+        assert!(verifier.offer_client_auth());
+        assert!(verifier.client_auth_mandatory());
+        assert_eq!(verifier.client_auth_root_subjects().len(), 1);
+
+        // A client must present a certificate.
+        assert!(verifier.verify_client_cert(&[]).is_err());
+    }
+}
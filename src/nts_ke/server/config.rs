@@ -4,19 +4,47 @@
 
 //! NTS-KE server configuration.
 
-use rustls::{Certificate, PrivateKey};
+use rustls::{Certificate, PrivateKey, ProtocolVersion, RootCertStore, SupportedCipherSuite};
 use rustls::internal::pemfile;
 
+use sloggers::file::FileLoggerBuilder;
 use sloggers::terminal::TerminalLoggerBuilder;
 use sloggers::Build;
 
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs::File;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
+use crate::admin::{get_admin_config, AdminConfig};
+use crate::aws::{get_aws_secret, AwsSecret};
+use crate::cidr::{get_cidr_list, Cidr};
 use crate::cookie::CookieKey;
 use crate::error::WrapError;
+use crate::key_rotator::{get_rotation_config, RotationConfig};
+use crate::key_store::{get_key_store_config, KeyStoreConfig};
 use crate::metrics::MetricsConfig;
+use crate::rate_limiter::{get_rate_limit_config, RateLimitConfig};
+use crate::vault::{get_vault_secret, VaultSecret};
+use crate::nts_ke::records::{Party, ServerRecord};
+
+use super::next_server::{NextServerSelection, NtpEndpoint};
+
+/// The default lifetime hint of the TLS session tickets in seconds. The tickets stay decryptable
+/// for as long as the key rotator keeps the key, so this is only a hint to the clients.
+const DEFAULT_TLS_TICKET_LIFETIME: u32 = 3600;
+
+/// The default interval in seconds between re-reading the OCSP responses.
+const DEFAULT_TLS_OCSP_REFRESH_INTERVAL: u64 = 3600;
+
+/// The default number of bytes that a client can send on a connection. A TLS handshake and a
+/// request fit in a few kilobytes.
+const DEFAULT_CONN_BUFFER_LIMIT: usize = 65536;
+
+/// The default time in seconds that the open connections can take to finish on shutdown.
+const DEFAULT_DRAIN_TIMEOUT: u64 = 10;
 
 fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     let mut metrics = None;
@@ -31,42 +59,735 @@ fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     return metrics;
 }
 
+/// What to do with the records in a request that are marked critical but we don't know.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnknownCriticalRecords {
+    /// Reply with an Error record, as the spec requires.
+    Reject,
+
+    /// Skip them and log a warning. It's only for interoperability testing.
+    Ignore,
+}
+
 /// Configuration for running an NTS-KE server.
 #[derive(Debug)]
 pub struct KeServerConfig {
     /// List of addresses and ports to the server will be listening to.
     // Each of the elements can be either IPv4 or IPv6 address. It cannot be a UNIX socket address.
-    addrs: Vec<SocketAddr>,
+    addrs: Vec<KeListenAddr>,
 
     /// The initial cookie key for the NTS-KE server.
     cookie_key: CookieKey,
 
+    /// Where the cookie key is read again periodically, if it's kept in Vault.
+    pub cookie_key_vault: Option<VaultSecret>,
+
+    /// Where the cookie key is read again periodically, if it's kept in AWS.
+    pub cookie_key_aws: Option<AwsSecret>,
+
+    /// The file that the cookie key is read again from periodically, if it's not kept in Vault
+    /// or AWS.
+    pub cookie_key_file: Option<String>,
+
     // If you don't to have a timeout, just set it to a very high value.
     timeout: u64,
 
+    /// How long in seconds a new connection can take to finish the TLS handshake. If it's `None`,
+    /// the connection timeout is used.
+    handshake_timeout: Option<u64>,
+
+    /// How long in seconds a connection can stay idle after the TLS handshake. If it's `None`,
+    /// the connection timeout is used.
+    idle_timeout: Option<u64>,
+
     /// The logger that will be used throughout the application, while the server is running.
     /// This property is mandatory because logging is very important for debugging.
     logger: slog::Logger,
 
-    /// The url of the memcached server. The memcached server is used to sync data between the
-    /// NTS-KE server and the NTP server.
-    memcached_url: String,
+    /// Whether to write an access log entry for every NTS-KE connection.
+    pub access_log: bool,
+
+    /// The logger for the access log, if it's written to its own file. If it's `None`, the
+    /// access log goes to `logger`.
+    access_logger: Option<slog::Logger>,
+
+    /// The key store that is used to sync the keys between the NTS-KE server and the NTP server.
+    key_store: KeyStoreConfig,
+
+    /// How often the keys are rotated and how long the old keys are kept.
+    pub rotation: RotationConfig,
 
     pub metrics_config: Option<MetricsConfig>,
+
+    /// The admin endpoint settings. If it's `None`, there is no admin endpoint.
+    pub admin_config: Option<AdminConfig>,
     pub next_port: u16,
+
+    /// The NTP servers that the clients should use. One of them is advertised in each NTS-KE
+    /// response. If it's empty, the clients use the NTP server on the same host as the NTS-KE
+    /// server on `next_port`.
+    pub next_servers: Vec<NtpEndpoint>,
+
+    /// How one of `next_servers` is chosen for each NTS-KE response.
+    pub next_server_selection: NextServerSelection,
     pub tls_certs: Vec<Certificate>,
     pub tls_secret_keys: Vec<PrivateKey>,
+
+    /// The file that the TLS certificates were imported from, if any. It's used to re-import the
+    /// certificates when the server is asked to reload them.
+    tls_certs_filename: Option<String>,
+
+    /// The file that the TLS private keys were imported from, if any. It's used to re-import the
+    /// private keys when the server is asked to reload them.
+    tls_secret_keys_filename: Option<String>,
+
+    /// A second certificate chain for the clients that don't support the signature algorithm of
+    /// `tls_certs`, for example, an RSA chain next to an ECDSA one. If it's `None`, only
+    /// `tls_certs` is served.
+    pub tls_alt_cert: Option<TlsCertChain>,
+
+    /// Certificate chains that will be used instead of `tls_certs` when the client asks for a
+    /// specific hostname using SNI.
+    pub sni_certs: Vec<SniCert>,
+
+    /// TLS client authentication settings. If it's `None`, the clients don't have to present
+    /// certificates.
+    pub client_auth: Option<ClientAuthConfig>,
+
+    /// The file that the config was parsed from, if any. The client authentication settings are
+    /// read again from it when the server is asked to reload the certificates.
+    filename: Option<String>,
+
+    /// The TLS cipher suites that can be negotiated, in order of preference. If it's empty, all
+    /// the TLS 1.3 cipher suites of rustls are allowed and the client's preference is used.
+    pub tls_cipher_suites: Vec<&'static SupportedCipherSuite>,
+
+    /// What to do with the unknown critical records in the requests.
+    pub unknown_critical_records: UnknownCriticalRecords,
+
+    /// Whether to issue TLS session tickets so that the clients can resume their sessions.
+    pub tls_session_tickets: bool,
+
+    /// Whether to log the TLS secrets to the file named by the `SSLKEYLOGFILE` environment
+    /// variable, so that the sessions can be decrypted for debugging.
+    pub tls_key_log: bool,
+
+    /// The lifetime hint of the TLS session tickets in seconds.
+    pub tls_ticket_lifetime: u32,
+
+    /// The DER-encoded OCSP response that will be stapled with `tls_certs`, if any.
+    pub tls_ocsp: Option<Vec<u8>>,
+
+    /// The file that the OCSP response was imported from, if any.
+    tls_ocsp_filename: Option<String>,
+
+    /// How often the OCSP responses are re-read from their files in seconds.
+    pub tls_ocsp_refresh_interval: u64,
+
+    /// Per-client connection rate limit. If it's `None`, the connections are not limited.
+    pub conn_rate_limit: Option<RateLimitConfig>,
+
+    /// The clients that can connect. If it's empty, every client that is not in
+    /// `client_denylist` can connect.
+    pub client_allowlist: Vec<Cidr>,
+
+    /// The clients that can never connect, even if they are in `client_allowlist`.
+    pub client_denylist: Vec<Cidr>,
+
+    /// The maximum number of connections that can be open at the same time. If it's `None`,
+    /// there is no limit.
+    pub max_connections: Option<usize>,
+
+    /// The maximum number of bytes that a client can send on a connection, including the TLS
+    /// handshake and the PROXY header. The connection is closed when it sends more.
+    pub conn_buffer_limit: usize,
+
+    /// TCP keepalive settings of the client connections. If it's `None`, the system default is
+    /// used.
+    pub tcp_keepalive: Option<TcpKeepaliveConfig>,
+
+    /// How long the data sent to a client can stay unacknowledged before the connection is
+    /// dropped by the kernel (`TCP_USER_TIMEOUT`). If it's `None`, the system default is used.
+    pub tcp_user_timeout: Option<Duration>,
+
+    /// How long in seconds the open connections can take to finish after the server is asked to
+    /// shut down.
+    pub drain_timeout: u64,
+
+    /// The number of listener threads for each address. If there is more than one, each of them
+    /// binds its own socket with `SO_REUSEPORT`.
+    pub accept_workers: usize,
+
+    /// How old in seconds the latest key can get before the server stops accepting connections,
+    /// because the rotations keep failing. If it's `None`, it's twice the `rotate_every`.
+    pub max_key_age: Option<u64>,
+}
+
+/// The socket address that a listener listens to.
+#[derive(Clone, Debug)]
+pub enum KeSocketAddr {
+    /// TCP address and port.
+    Tcp(SocketAddr),
+
+    /// Path of a Unix domain socket, for a load balancer on the same host.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for KeSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeSocketAddr::Tcp(addr) => write!(f, "{}", addr),
+            KeSocketAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An address that the server listens to, and the options of its listener.
+#[derive(Clone, Debug)]
+pub struct KeListenAddr {
+    /// Address to listen to.
+    pub addr: KeSocketAddr,
+
+    /// Whether the connections start with a PROXY protocol header from a load balancer.
+    pub proxy_protocol: bool,
+
+    /// Whether TLS is terminated by the load balancer. The connections carry the NTS-KE records
+    /// in plaintext, and the keys exported from the TLS session come in the PROXY header.
+    pub plaintext: bool,
+
+    /// Whether an IPv6 listener accepts only IPv6 connections. If it's `None`, the system
+    /// default is used.
+    pub v6only: Option<bool>,
+}
+
+impl KeListenAddr {
+    /// Create a listen address without any option.
+    pub fn new(addr: KeSocketAddr) -> KeListenAddr {
+        KeListenAddr {
+            addr,
+            proxy_protocol: false,
+            plaintext: false,
+            v6only: None,
+        }
+    }
+}
+
+/// Parse an `addr` entry. It's either an address string, or a table with the address in its
+/// `addr` key, or the path of a Unix domain socket in its `path` key, and the listener options in
+/// the other keys.
+fn get_listen_addr(value: config::Value) -> Result<KeListenAddr, config::ConfigError> {
+    let mut table = match value.clone().into_table() {
+        Ok(table) => table,
+        // If it's not a table, it must be an address string.
+        Err(_) => {
+            let addr = value.into_str()?.parse().wrap_err()?;
+            return Ok(KeListenAddr::new(KeSocketAddr::Tcp(addr)));
+        },
+    };
+
+    let addr = match (table.remove("addr"), table.remove("path")) {
+        (Some(addr), None) => KeSocketAddr::Tcp(addr.into_str()?.parse().wrap_err()?),
+        (None, Some(path)) => KeSocketAddr::Unix(PathBuf::from(path.into_str()?)),
+        (Some(_), Some(_)) => return Err(config::ConfigError::Message(
+            String::from("an addr entry has both addr and path")
+        )),
+        (None, None) => return Err(config::ConfigError::Message(
+            String::from("an addr entry is missing addr")
+        )),
+    };
+
+    let mut listen_addr = KeListenAddr::new(addr.clone());
+    if let Some(value) = table.remove("proxy_protocol") {
+        listen_addr.proxy_protocol = value.into_bool()?;
+    }
+    if let Some(value) = table.remove("plaintext") {
+        listen_addr.plaintext = value.into_bool()?;
+        // The keys can only come from the load balancer in the PROXY header.
+        if listen_addr.plaintext && !listen_addr.proxy_protocol {
+            return Err(config::ConfigError::Message(
+                format!("plaintext is set without proxy_protocol for {}", addr)
+            ));
+        }
+    }
+    if let Some(value) = table.remove("v6only") {
+        match addr {
+            KeSocketAddr::Tcp(addr) if addr.is_ipv6() => (),
+            _ => return Err(config::ConfigError::Message(
+                format!("v6only is set for the non-IPv6 address {}", addr)
+            )),
+        }
+        listen_addr.v6only = Some(value.into_bool()?);
+    }
+    // Only a load balancer on the same host can connect to a Unix domain socket, and the
+    // addresses of the clients can only come from its PROXY header.
+    if let KeSocketAddr::Unix(_) = addr {
+        if !listen_addr.proxy_protocol {
+            return Err(config::ConfigError::Message(
+                format!("proxy_protocol is not set for {}", addr)
+            ));
+        }
+    }
+
+    Ok(listen_addr)
+}
+
+/// Parse an optional non-negative integer setting.
+fn get_optional_u64(settings: &config::Config, key: &str)
+    -> Result<Option<u64>, config::ConfigError>
+{
+    match settings.get_int(key) {
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(error) => Err(error),
+        Ok(val) => match u64::try_from(val) {
+            Ok(val) => Ok(Some(val)),
+            Err(_) => Err(config::ConfigError::Message(
+                format!("the {} is not a valid u64", key)
+            )),
+        },
+    }
+}
+
+/// Check that a hostname or an IP address can be put in a Server Negotiation record.
+fn check_next_server(server: &str) -> Result<(), config::ConfigError> {
+    ServerRecord::new(Party::Server, server)
+        .map(|_| ())
+        .map_err(|error| config::ConfigError::Message(
+            format!("the next server {} is invalid: {}", server, error)
+        ))
+}
+
+/// Parse the NTP servers advertised to the clients. It's either a single `next_server`, or a
+/// `next_servers` list whose entries have `server`, and optionally `port` and `weight`. The port
+/// is `next_port` if it's not specified.
+fn get_next_servers(settings: &config::Config, next_port: u16)
+    -> Result<Vec<NtpEndpoint>, config::ConfigError>
+{
+    let entries = match settings.get_array("next_servers") {
+        Ok(entries) => entries,
+        Err(config::ConfigError::NotFound(_)) => {
+            return match settings.get_str("next_server") {
+                // If it's a not-found error, the clients use the NTP server on this host.
+                Err(config::ConfigError::NotFound(_)) => Ok(Vec::new()),
+                Err(error) => Err(error),
+                Ok(server) => {
+                    check_next_server(&server)?;
+                    Ok(vec![NtpEndpoint { server: Some(server), port: next_port, weight: 1 }])
+                },
+            };
+        },
+        Err(error) => return Err(error),
+    };
+
+    let mut endpoints = Vec::new();
+    for entry in entries {
+        let mut table = entry.into_table()?;
+        let server = match table.remove("server") {
+            Some(value) => value.into_str()?,
+            None => return Err(config::ConfigError::Message(
+                String::from("a next server entry is missing server")
+            )),
+        };
+        check_next_server(&server)?;
+
+        let port = match table.remove("port") {
+            Some(value) => u16::try_from(value.into_int()?).map_err(|_| {
+                config::ConfigError::Message(format!("the port of {} is not a valid u16", server))
+            })?,
+            None => next_port,
+        };
+        let weight = match table.remove("weight") {
+            Some(value) => match u32::try_from(value.into_int()?) {
+                Ok(weight) if weight > 0 => weight,
+                _ => return Err(config::ConfigError::Message(
+                    format!("the weight of {} is not a positive u32", server)
+                )),
+            },
+            None => 1,
+        };
+
+        endpoints.push(NtpEndpoint { server: Some(server), port, weight });
+    }
+
+    Ok(endpoints)
+}
+
+/// Parse the TLS cipher suites by their IANA names, for example, `TLS13_AES_128_GCM_SHA256`. The
+/// list is empty if `tls_cipher_suites` is not specified.
+fn get_tls_cipher_suites(settings: &config::Config)
+    -> Result<Vec<&'static SupportedCipherSuite>, config::ConfigError>
+{
+    let names = match settings.get_array("tls_cipher_suites") {
+        Err(config::ConfigError::NotFound(_)) => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+        Ok(values) => values,
+    };
+
+    let mut suites = Vec::new();
+    for name in names {
+        let name = name.into_str()?;
+        // We support only TLS 1.3, so the older suites would never be negotiated.
+        let suite = rustls::ALL_CIPHERSUITES.iter()
+            .find(|suite| {
+                suite.usable_for_version(ProtocolVersion::TLSv1_3)
+                    && format!("{:?}", suite.suite) == name
+            })
+            .ok_or_else(|| config::ConfigError::Message(
+                format!("unknown TLS 1.3 cipher suite {}", name)
+            ))?;
+        suites.push(*suite);
+    }
+
+    if suites.is_empty() {
+        return Err(config::ConfigError::Message(
+            String::from("the TLS cipher suite list is empty")
+        ));
+    }
+    Ok(suites)
+}
+
+/// TCP keepalive settings of the client connections.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpKeepaliveConfig {
+    /// How long a connection is idle before the first probe.
+    pub idle: Duration,
+
+    /// How long between the probes. If it's `None`, the system default is used.
+    pub interval: Option<Duration>,
+
+    /// How many unanswered probes drop the connection. If it's `None`, the system default is
+    /// used.
+    pub count: Option<u32>,
+}
+
+/// Parse the TCP keepalive settings. Keepalive is turned on only when `tcp_keepalive_idle` is
+/// specified.
+fn get_tcp_keepalive_config(settings: &config::Config)
+    -> Result<Option<TcpKeepaliveConfig>, config::ConfigError>
+{
+    let idle = match get_optional_u64(settings, "tcp_keepalive_idle")? {
+        Some(idle) if idle > 0 => Duration::from_secs(idle),
+        Some(_) => return Err(config::ConfigError::Message(
+            String::from("the TCP keepalive idle time must be positive")
+        )),
+        // If it's not specified, the system default is used, which is usually no keepalive.
+        None => return Ok(None),
+    };
+
+    let interval = match get_optional_u64(settings, "tcp_keepalive_interval")? {
+        Some(0) => return Err(config::ConfigError::Message(
+            String::from("the TCP keepalive interval must be positive")
+        )),
+        interval => interval.map(Duration::from_secs),
+    };
+
+    let count = match get_optional_u64(settings, "tcp_keepalive_count")? {
+        Some(count) => match u32::try_from(count) {
+            Ok(count) if count > 0 => Some(count),
+            _ => return Err(config::ConfigError::Message(
+                String::from("the TCP keepalive count is not a positive u32")
+            )),
+        },
+        None => None,
+    };
+
+    Ok(Some(TcpKeepaliveConfig { idle, interval, count }))
+}
+
+/// Trusted CAs and allowed names for TLS client authentication.
+#[derive(Clone, Debug)]
+pub struct ClientAuthConfig {
+    /// The CAs that must sign the client certificates.
+    pub roots: RootCertStore,
+
+    /// The DNS names that the client certificates may be valid for. If it's empty, any client
+    /// certificate signed by the CAs is accepted.
+    pub allowed_sans: Vec<String>,
+}
+
+/// Parse the TLS client authentication settings. The client authentication is enabled only when
+/// `client_ca_file` is specified.
+fn get_client_auth_config(settings: &config::Config)
+    -> Result<Option<ClientAuthConfig>, config::ConfigError>
+{
+    let ca_filename = match settings.get_str("client_ca_file") {
+        // If it's a not-found error, the client authentication is disabled.
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(filename) => filename,
+    };
+
+    let allowed_sans = match settings.get_array("client_allowed_sans") {
+        Err(config::ConfigError::NotFound(_)) => Vec::new(),
+        Err(error) => return Err(error),
+        Ok(values) => values.into_iter()
+            .map(|value| value.into_str())
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+
+    let file = File::open(&ca_filename).wrap_err()?;
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut std::io::BufReader::new(file)) {
+        Ok((valid_count, _)) if valid_count > 0 => (),
+        _ => return Err(config::ConfigError::Message(
+            format!("cannot parse client CA certificates from {}", ca_filename)
+        )),
+    }
+
+    Ok(Some(ClientAuthConfig { roots, allowed_sans }))
+}
+
+/// TLS certificate chain, its private key, and the files that they were loaded from.
+#[derive(Clone, Debug)]
+pub struct TlsCertChain {
+    /// The certificate chain.
+    pub tls_certs: Vec<Certificate>,
+
+    /// The private key corresponding to the certificate chain.
+    pub tls_secret_key: PrivateKey,
+
+    /// The file that the certificate chain was loaded from.
+    tls_certs_filename: String,
+
+    /// The file that the private key was loaded from.
+    tls_secret_keys_filename: String,
+
+    /// The DER-encoded OCSP response that will be stapled with the certificate chain, if any.
+    pub tls_ocsp: Option<Vec<u8>>,
+
+    /// The file that the OCSP response was loaded from, if any.
+    tls_ocsp_filename: Option<String>,
+}
+
+impl TlsCertChain {
+    /// Load a certificate chain, its private key, and optionally its OCSP response from files.
+    /// Only the first private key in the file is used.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if we cannot read the files or there is no private key in the file.
+    ///
+    pub fn load(
+        tls_certs_filename: String,
+        tls_secret_keys_filename: String,
+        tls_ocsp_filename: Option<String>,
+    ) -> Result<TlsCertChain, std::io::Error> {
+        let tls_certs = load_tls_certs(&tls_certs_filename)?;
+        let tls_secret_key = match load_tls_secret_keys(&tls_secret_keys_filename)?
+            .into_iter()
+            .next()
+        {
+            Some(secret_key) => secret_key,
+            None => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("no TLS private key found in {}", tls_secret_keys_filename),
+            )),
+        };
+
+        let tls_ocsp = match &tls_ocsp_filename {
+            Some(filename) => load_ocsp_response(filename)?,
+            None => None,
+        };
+
+        Ok(TlsCertChain {
+            tls_certs,
+            tls_secret_key,
+            tls_certs_filename,
+            tls_secret_keys_filename,
+            tls_ocsp,
+            tls_ocsp_filename,
+        })
+    }
+
+    /// Load the certificate chain, private key, and OCSP response again from the same files.
+    pub fn reload(&self) -> Result<TlsCertChain, std::io::Error> {
+        TlsCertChain::load(
+            self.tls_certs_filename.clone(),
+            self.tls_secret_keys_filename.clone(),
+            self.tls_ocsp_filename.clone(),
+        )
+    }
+}
+
+/// TLS certificate chain and private key for a specific SNI hostname.
+#[derive(Clone, Debug)]
+pub struct SniCert {
+    /// The hostname that the client sends in the SNI extension.
+    pub hostname: String,
+
+    /// The certificate chain for the hostname and its private key.
+    pub chain: TlsCertChain,
+}
+
+impl SniCert {
+    /// Load a certificate chain, its private key, and optionally its OCSP response for the
+    /// hostname from files. Only the first private key in the file is used.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if we cannot read the files or there is no private key in the file.
+    ///
+    pub fn load(
+        hostname: String,
+        tls_certs_filename: String,
+        tls_secret_keys_filename: String,
+        tls_ocsp_filename: Option<String>,
+    ) -> Result<SniCert, std::io::Error> {
+        let chain = TlsCertChain::load(
+            tls_certs_filename,
+            tls_secret_keys_filename,
+            tls_ocsp_filename,
+        )?;
+        Ok(SniCert { hostname, chain })
+    }
+
+    /// Load the certificate chain, private key, and OCSP response again from the same files.
+    pub fn reload(&self) -> Result<SniCert, std::io::Error> {
+        Ok(SniCert {
+            hostname: self.hostname.clone(),
+            chain: self.chain.reload()?,
+        })
+    }
+}
+
+/// Parse a list of SNI certificates. Each entry must be a table with `hostname`, `tls_cert_file`,
+/// and `tls_key_file` keys. The `tls_ocsp_file` key is optional.
+fn get_sni_certs(settings: &config::Config) -> Result<Vec<SniCert>, config::ConfigError> {
+    let entries = match settings.get_array("sni_certs") {
+        // If it's a not-found error, there is no SNI certificate.
+        Err(config::ConfigError::NotFound(_)) => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+        Ok(entries) => entries,
+    };
+
+    let mut sni_certs = Vec::new();
+    for entry in entries {
+        let mut table = entry.into_table()?;
+        let mut take_str = |key: &str| match table.remove(key) {
+            Some(value) => value.into_str(),
+            None => Err(config::ConfigError::Message(
+                format!("an SNI certificate entry is missing {}", key)
+            )),
+        };
+        let hostname = take_str("hostname")?;
+        let certs_filename = take_str("tls_cert_file")?;
+        let secret_keys_filename = take_str("tls_key_file")?;
+        let ocsp_filename = match table.remove("tls_ocsp_file") {
+            Some(value) => Some(value.into_str()?),
+            None => None,
+        };
+
+        let sni_cert = SniCert::load(
+            hostname,
+            certs_filename,
+            secret_keys_filename,
+            ocsp_filename,
+        ).wrap_err()?;
+        sni_certs.push(sni_cert);
+    }
+
+    Ok(sni_certs)
+}
+
+/// Parse the second certificate chain served next to `tls_cert_file`. It's enabled only when
+/// `tls_alt_cert_file` is specified, in which case `tls_alt_key_file` is also required. The
+/// `tls_alt_ocsp_file` key is optional.
+fn get_tls_alt_cert(settings: &config::Config)
+    -> Result<Option<TlsCertChain>, config::ConfigError>
+{
+    let certs_filename = match settings.get_str("tls_alt_cert_file") {
+        // If it's a not-found error, there is only one certificate chain.
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(filename) => filename,
+    };
+    let secret_keys_filename = settings.get_str("tls_alt_key_file")?;
+    let ocsp_filename = match settings.get_str("tls_alt_ocsp_file") {
+        Err(config::ConfigError::NotFound(_)) => None,
+        Err(error) => return Err(error),
+        Ok(filename) => Some(filename),
+    };
+
+    let chain = TlsCertChain::load(certs_filename, secret_keys_filename, ocsp_filename)
+        .wrap_err()?;
+    Ok(Some(chain))
+}
+
+/// Load a DER-encoded OCSP response from a file. An empty file means that there is no OCSP
+/// response to staple.
+///
+/// # Errors
+///
+/// There will be an error if we cannot read the file.
+///
+pub fn load_ocsp_response(filename: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let ocsp = std::fs::read(filename)?;
+
+    if ocsp.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(ocsp))
+    }
+}
+
+/// Load TLS certificates from a file.
+///
+/// # Errors
+///
+/// There will be an error if we cannot open the file or the content is not parsable to get
+/// certificates.
+///
+pub fn load_tls_certs(filename: &str) -> Result<Vec<Certificate>, std::io::Error> {
+    // Open a file. If there is any error, return it immediately.
+    let file = File::open(filename)?;
+
+    match pemfile::certs(&mut std::io::BufReader::new(file)) {
+        Ok(certs) => Ok(certs),
+        // We don't use Err(_) here because if the error type of `rustls` changes in the
+        // future, we will get noticed.
+        //
+        // The `std::io` module has an error kind of `InvalidData` which is perfectly
+        // suitable for our kind of error.
+        Err(()) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("cannot parse TLS certificates from {}", filename),
+        )),
+    }
+}
+
+/// Load TLS private keys from a file.
+///
+/// # Errors
+///
+/// There will be an error if we cannot open the file or the content is not parsable to get
+/// private keys.
+///
+pub fn load_tls_secret_keys(filename: &str) -> Result<Vec<PrivateKey>, std::io::Error> {
+    // Open a file. If there is any error, return it immediately.
+    let file = File::open(filename)?;
+
+    match pemfile::pkcs8_private_keys(&mut std::io::BufReader::new(file)) {
+        Ok(secret_keys) => Ok(secret_keys),
+        // We don't use Err(_) here because if the error type of `rustls` changes in the
+        // future, we will get noticed.
+        //
+        // The `std::io` module has an error kind of `InvalidData` which is perfectly
+        // suitable for our kind of error.
+        Err(()) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("cannot parse TLS private keys from {}", filename),
+        )),
+    }
 }
 
 /// We decided to make KeServerConfig mutable so that you can add more cert, private key, or
 /// address after you parse the config file.
 impl KeServerConfig {
-    /// Create a NTS-KE server config object with the given next port, memcached url, connection
+    /// Create a NTS-KE server config object with the given next port, key store, connection
     /// timeout, and the metrics config.
     pub fn new(
         timeout: u64,
         cookie_key: CookieKey,
-        memcached_url: String,
+        key_store: KeyStoreConfig,
         metrics_config: Option<MetricsConfig>,
         next_port: u16,
     ) -> KeServerConfig {
@@ -81,15 +802,50 @@ impl KeServerConfig {
             logger: TerminalLoggerBuilder::new().build()
                 .expect("BUG: TerminalLoggerBuilder::build shouldn't return an error."),
 
+            admin_config: None,
+            cookie_key_vault: None,
+            cookie_key_aws: None,
+            cookie_key_file: None,
+            rotation: RotationConfig::default(),
+            access_log: false,
+            access_logger: None,
             tls_certs: Vec::new(),
             tls_secret_keys: Vec::new(),
+            tls_certs_filename: None,
+            tls_secret_keys_filename: None,
+            tls_alt_cert: None,
+            sni_certs: Vec::new(),
+            client_auth: None,
+            filename: None,
+            tls_cipher_suites: Vec::new(),
+            unknown_critical_records: UnknownCriticalRecords::Reject,
+            tls_session_tickets: false,
+            tls_key_log: false,
+            tls_ticket_lifetime: DEFAULT_TLS_TICKET_LIFETIME,
+            tls_ocsp: None,
+            tls_ocsp_filename: None,
+            tls_ocsp_refresh_interval: DEFAULT_TLS_OCSP_REFRESH_INTERVAL,
+            conn_rate_limit: None,
+            client_allowlist: Vec::new(),
+            client_denylist: Vec::new(),
+            max_connections: None,
+            conn_buffer_limit: DEFAULT_CONN_BUFFER_LIMIT,
+            tcp_keepalive: None,
+            tcp_user_timeout: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            accept_workers: 1,
+            max_key_age: None,
 
             // From parameters.
             cookie_key,
             timeout,
-            memcached_url,
+            handshake_timeout: None,
+            idle_timeout: None,
+            key_store,
             metrics_config,
             next_port,
+            next_servers: Vec::new(),
+            next_server_selection: NextServerSelection::RoundRobin,
         }
     }
 
@@ -107,13 +863,13 @@ impl KeServerConfig {
         self.tls_secret_keys.push(secret_key);
     }
 
-    /// Add an address into the config.
-    pub fn add_address(&mut self, addr: SocketAddr) {
+    /// Add an address with its listener options into the config.
+    pub fn add_address(&mut self, addr: KeListenAddr) {
         self.addrs.push(addr);
     }
 
     /// Return a list of addresses.
-    pub fn addrs(&self) -> &[SocketAddr] {
+    pub fn addrs(&self) -> &[KeListenAddr] {
         self.addrs.as_slice()
     }
 
@@ -132,9 +888,23 @@ impl KeServerConfig {
         &self.logger
     }
 
-    /// Return the memcached url of the config.
-    pub fn memcached_url(&self) -> &str {
-        &self.memcached_url
+    /// Set a separate logger for the access log.
+    pub fn set_access_logger(&mut self, logger: slog::Logger) {
+        self.access_logger = Some(logger);
+    }
+
+    /// Return the logger for the access log, if the access log is enabled. It's the main logger
+    /// unless the access log has its own.
+    pub fn access_logger(&self) -> Option<&slog::Logger> {
+        if !self.access_log {
+            return None;
+        }
+        Some(self.access_logger.as_ref().unwrap_or(&self.logger))
+    }
+
+    /// Return the key store of the config.
+    pub fn key_store(&self) -> &KeyStoreConfig {
+        &self.key_store
     }
 
     /// Return the connection timeout of the config.
@@ -142,6 +912,16 @@ impl KeServerConfig {
         self.timeout
     }
 
+    /// Return the TLS handshake timeout of the config.
+    pub fn handshake_timeout(&self) -> u64 {
+        self.handshake_timeout.unwrap_or_else(|| self.timeout())
+    }
+
+    /// Return the post-handshake idle timeout of the config.
+    pub fn idle_timeout(&self) -> u64 {
+        self.idle_timeout.unwrap_or_else(|| self.timeout())
+    }
+
     /// Import TLS certificates from a file.
     ///
     /// # Errors
@@ -152,28 +932,12 @@ impl KeServerConfig {
     // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
     // method has to be private for now.
     fn import_tls_certs(&mut self, filename: &str) -> Result<(), std::io::Error> {
-        // Open a file. If there is any error, return it immediately.
-        let file = File::open(filename)?;
-
-        match pemfile::certs(&mut std::io::BufReader::new(file)) {
-            Ok(certs) => {
-                // Add all parsed certificates.
-                for cert in certs {
-                    self.add_tls_cert(cert);
-                }
-                // Return success.
-                Ok(())
-            },
-            // We don't use Err(_) here because if the error type of `rustls` changes in the
-            // future, we will get noticed.
-            //
-            // The `std::io` module has an error kind of `InvalidData` which is perfectly
-            // suitable for our kind of error.
-            Err(()) => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("cannot parse TLS certificates from {}", filename),
-            )),
+        // Add all parsed certificates.
+        for cert in load_tls_certs(filename)? {
+            self.add_tls_cert(cert);
         }
+        self.tls_certs_filename = Some(String::from(filename));
+        Ok(())
     }
 
     /// Import TLS private keys from a file.
@@ -186,28 +950,65 @@ impl KeServerConfig {
     // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
     // method has to be private for now.
     fn import_tls_secret_keys(&mut self, filename: &str) -> Result<(), std::io::Error> {
-        // Open a file. If there is any error, return it immediately.
-        let file = File::open(filename)?;
-
-        match pemfile::pkcs8_private_keys(&mut std::io::BufReader::new(file)) {
-            Ok(secret_keys) => {
-                // Add all parsed secret keys.
-                for secret_key in secret_keys {
-                    self.add_tls_secret_key(secret_key);
-                }
-                // Return success.
-                Ok(())
-            },
-            // We don't use Err(_) here because if the error type of `rustls` changes in the
-            // future, we will get noticed.
-            //
-            // The `std::io` module has an error kind of `InvalidData` which is perfectly
-            // suitable for our kind of error.
-            Err(()) => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("cannot parse TLS private keys from {}", filename),
-            )),
+        // Add all parsed secret keys.
+        for secret_key in load_tls_secret_keys(filename)? {
+            self.add_tls_secret_key(secret_key);
         }
+        self.tls_secret_keys_filename = Some(String::from(filename));
+        Ok(())
+    }
+
+    /// Import a DER-encoded OCSP response for `tls_certs` from a file.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if we cannot read the file.
+    ///
+    fn import_tls_ocsp(&mut self, filename: &str) -> Result<(), std::io::Error> {
+        self.tls_ocsp = load_ocsp_response(filename)?;
+        self.tls_ocsp_filename = Some(String::from(filename));
+        Ok(())
+    }
+
+    /// Return the file that the OCSP response was imported from, if any.
+    pub fn tls_ocsp_filename(&self) -> Option<&str> {
+        self.tls_ocsp_filename.as_ref().map(String::as_str)
+    }
+
+    /// Return true if there is any OCSP response file that needs to be refreshed.
+    pub fn has_tls_ocsp(&self) -> bool {
+        self.tls_ocsp_filename.is_some()
+            || self.tls_alt_cert.iter().any(|chain| chain.tls_ocsp_filename.is_some())
+            || self.sni_certs.iter().any(|sni_cert| sni_cert.chain.tls_ocsp_filename.is_some())
+    }
+
+    /// Return the file that the TLS certificates were imported from, if any.
+    pub fn tls_certs_filename(&self) -> Option<&str> {
+        self.tls_certs_filename.as_ref().map(String::as_str)
+    }
+
+    /// Return the file that the TLS private keys were imported from, if any.
+    pub fn tls_secret_keys_filename(&self) -> Option<&str> {
+        self.tls_secret_keys_filename.as_ref().map(String::as_str)
+    }
+
+    /// Read the TLS client authentication settings again from the file that the config was
+    /// parsed from. If the config was not parsed from a file, the current settings are returned.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if we cannot read or parse the file, or the CA file.
+    ///
+    pub fn reload_client_auth(&self) -> Result<Option<ClientAuthConfig>, std::io::Error> {
+        let filename = match &self.filename {
+            Some(filename) => filename,
+            None => return Ok(self.client_auth.clone()),
+        };
+
+        let to_io_error = |error| std::io::Error::new(std::io::ErrorKind::InvalidData, error);
+        let mut settings = config::Config::new();
+        settings.merge(config::File::with_name(filename)).map_err(to_io_error)?;
+        get_client_auth_config(&settings).map_err(to_io_error)
     }
 
     /// Parse a config from a file.
@@ -248,7 +1049,34 @@ impl KeServerConfig {
                 ));
             },
         };
-        let memcached_url = settings.get_str("memc_url")?;
+
+        // Resolves the NTP servers advertised in the Server Negotiation and Port Negotiation
+        // records.
+        let next_servers = get_next_servers(&settings, next_port)?;
+        let next_server_selection = match settings.get_str("next_server_selection") {
+            Err(config::ConfigError::NotFound(_)) => NextServerSelection::RoundRobin,
+            Err(error) => return Err(error),
+            Ok(selection) => match selection.as_str() {
+                "round_robin" => NextServerSelection::RoundRobin,
+                "random" => NextServerSelection::Random,
+                _ => return Err(config::ConfigError::Message(
+                    format!("unknown next server selection {}", selection)
+                )),
+            },
+        };
+        let unknown_critical_records = match settings.get_str("unknown_critical_records") {
+            Err(config::ConfigError::NotFound(_)) => UnknownCriticalRecords::Reject,
+            Err(error) => return Err(error),
+            Ok(policy) => match policy.as_str() {
+                "reject" => UnknownCriticalRecords::Reject,
+                "ignore" => UnknownCriticalRecords::Ignore,
+                _ => return Err(config::ConfigError::Message(
+                    format!("unknown unknown_critical_records policy {}", policy)
+                )),
+            },
+        };
+        let key_store = get_key_store_config(&settings, true)?;
+        let rotation = get_rotation_config(&settings)?;
 
         // XXX: The code of parsing a connection timeout here is quite ugly due to the `get_int`
         // interface. Please don't be surprised :)
@@ -280,6 +1108,49 @@ impl KeServerConfig {
         // Resolves metrics configuration.
         let metrics_config = get_metrics_config(&settings);
 
+        // Resolves the timeouts of the TLS handshake and the idle connections. They fall back
+        // to the connection timeout.
+        let handshake_timeout = get_optional_u64(&settings, "handshake_timeout")?;
+        let idle_timeout = get_optional_u64(&settings, "idle_timeout")?;
+
+        // Resolves the drain timeout.
+        let drain_timeout = match settings.get_int("drain_timeout") {
+            // If it's a not-found error, we just set it to the default value.
+            Err(config::ConfigError::NotFound(_)) => DEFAULT_DRAIN_TIMEOUT,
+            Err(error) => return Err(error),
+            Ok(val) => match u64::try_from(val) {
+                Ok(val) => val,
+                Err(_) => {
+                    return Err(config::ConfigError::Message(
+                        String::from("the drain timeout is not a valid u64")
+                    ));
+                },
+            },
+        };
+
+        // Resolves the age of the latest key at which the server is no longer ready. A healthy
+        // server's latest key gets as old as the rotate_every plus the rotate_jitter.
+        let max_key_age = get_optional_u64(&settings, "max_key_age")?;
+        if let Some(max_key_age) = max_key_age {
+            if max_key_age <= rotation.rotate_every + rotation.jitter {
+                return Err(config::ConfigError::Message(String::from(
+                    "the max_key_age must be greater than the rotate_every plus the rotate_jitter"
+                )));
+            }
+        }
+
+        // Resolves the per-client connection rate limit.
+        let conn_rate_limit = get_rate_limit_config(&settings, "conn_rate")?;
+
+        // Resolves the TCP settings of the client connections.
+        let tcp_keepalive = get_tcp_keepalive_config(&settings)?;
+        let tcp_user_timeout = get_optional_u64(&settings, "tcp_user_timeout")?
+            .map(Duration::from_secs);
+
+        // Resolves the client access lists.
+        let client_allowlist = get_cidr_list(&settings, "client_allowlist")?;
+        let client_denylist = get_cidr_list(&settings, "client_denylist")?;
+
         // Note that all of the file reading stuffs should be at the end of the function so that
         // all the not-file-related stuffs can fail fast.
 
@@ -288,27 +1159,386 @@ impl KeServerConfig {
         let certs_filename = settings.get_str("tls_cert_file")?;
         let secret_keys_filename = settings.get_str("tls_key_file")?;
 
-        let cookie_key_filename = settings.get_str("cookie_key_file")?;
-        let cookie_key = CookieKey::parse(&cookie_key_filename).wrap_err()?;
+        // The cookie key is read from Vault or AWS instead of the file, if it's configured.
+        let cookie_key_vault = get_vault_secret(&settings, "cookie_key_vault")?;
+        let cookie_key_aws = get_aws_secret(&settings, "cookie_key_aws")?;
+        let (cookie_key, cookie_key_file) = match (&cookie_key_vault, &cookie_key_aws) {
+            (Some(_), Some(_)) => return Err(config::ConfigError::Message(String::from(
+                "cookie_key_vault and cookie_key_aws cannot be used together"
+            ))),
+            (Some(secret), None) => (CookieKey::from(secret.fetch().wrap_err()?), None),
+            (None, Some(secret)) => (CookieKey::from(secret.fetch().wrap_err()?), None),
+            (None, None) => {
+                let cookie_key_filename = settings.get_str("cookie_key_file")?;
+                (CookieKey::parse(&cookie_key_filename).wrap_err()?, Some(cookie_key_filename))
+            },
+        };
 
         let mut config = KeServerConfig::new(
             timeout,
             cookie_key,
-            memcached_url,
+            key_store,
             metrics_config,
             next_port,
         );
 
+        config.cookie_key_vault = cookie_key_vault;
+
+        config.cookie_key_aws = cookie_key_aws;
+        config.cookie_key_file = cookie_key_file;
+        config.rotation = rotation;
+        config.next_servers = next_servers;
+        config.next_server_selection = next_server_selection;
+        config.unknown_critical_records = unknown_critical_records;
+        config.conn_rate_limit = conn_rate_limit;
+        config.client_allowlist = client_allowlist;
+        config.client_denylist = client_denylist;
+        config.drain_timeout = drain_timeout;
+        config.max_key_age = max_key_age;
+        config.handshake_timeout = handshake_timeout;
+        config.idle_timeout = idle_timeout;
+        config.tcp_keepalive = tcp_keepalive;
+        config.tcp_user_timeout = tcp_user_timeout;
+
+        match settings.get_int("max_connections") {
+            // If it's a not-found error, there is no limit.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => {
+                config.max_connections = match usize::try_from(val) {
+                    Ok(val) if val > 0 => Some(val),
+                    _ => {
+                        return Err(config::ConfigError::Message(
+                            String::from("the maximum number of connections is not a positive \
+                                          integer")
+                        ));
+                    },
+                };
+            },
+        }
+
+        match settings.get_int("conn_buffer_limit") {
+            // If it's a not-found error, we just leave it at the default value.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => {
+                config.conn_buffer_limit = match usize::try_from(val) {
+                    Ok(val) if val > 0 => val,
+                    _ => {
+                        return Err(config::ConfigError::Message(
+                            String::from("the connection buffer limit is not a positive integer")
+                        ));
+                    },
+                };
+            },
+        }
+
+        match settings.get_int("accept_workers") {
+            // If it's a not-found error, there is one worker for each address.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => {
+                config.accept_workers = match usize::try_from(val) {
+                    Ok(val) if val > 0 => val,
+                    _ => {
+                        return Err(config::ConfigError::Message(
+                            String::from("the number of accept workers is not a positive integer")
+                        ));
+                    },
+                };
+            },
+        }
+
         config.import_tls_certs(&certs_filename).wrap_err()?;
         config.import_tls_secret_keys(&secret_keys_filename).wrap_err()?;
+        config.tls_alt_cert = get_tls_alt_cert(&settings)?;
+        config.sni_certs = get_sni_certs(&settings)?;
+
+        match settings.get_str("tls_ocsp_file") {
+            // If it's a not-found error, there is no OCSP response to staple.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(filename) => config.import_tls_ocsp(&filename).wrap_err()?,
+        }
+
+        match settings.get_int("tls_ocsp_refresh_interval") {
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => {
+                config.tls_ocsp_refresh_interval = match u64::try_from(val) {
+                    Ok(val) if val > 0 => val,
+                    _ => {
+                        return Err(config::ConfigError::Message(
+                            String::from("the OCSP refresh interval is not a positive u64")
+                        ));
+                    },
+                };
+            },
+        }
+        config.client_auth = get_client_auth_config(&settings)?;
+        config.filename = Some(String::from(filename));
+        config.tls_cipher_suites = get_tls_cipher_suites(&settings)?;
+        config.admin_config = get_admin_config(&settings)?;
+
+        match settings.get_bool("tls_session_tickets") {
+            // If it's a not-found error, we just leave the session tickets disabled.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => config.tls_session_tickets = val,
+        }
+
+        match settings.get_bool("tls_key_log") {
+            // If it's a not-found error, we just leave the key logging disabled.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => config.tls_key_log = val,
+        }
+
+        match settings.get_int("tls_ticket_lifetime") {
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => {
+                config.tls_ticket_lifetime = match u32::try_from(val) {
+                    Ok(val) => val,
+                    Err(_) => {
+                        return Err(config::ConfigError::Message(
+                            String::from("the TLS ticket lifetime is not a valid u32")
+                        ));
+                    },
+                };
+            },
+        }
+
+        match settings.get_bool("access_log") {
+            // If it's a not-found error, we just leave the access log disabled.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(val) => config.access_log = val,
+        }
+
+        match settings.get_str("access_log_file") {
+            // If it's a not-found error, the access log goes to the main logger.
+            Err(config::ConfigError::NotFound(_)) => (),
+            Err(error) => return Err(error),
+            Ok(filename) => {
+                let logger = FileLoggerBuilder::new(&filename).build().wrap_err()?;
+                config.set_access_logger(logger);
+            },
+        }
 
         let addrs = settings.get_array("addr")?;
         for addr in addrs {
-            // Parse SocketAddr from a string.
-            let sock_addr = addr.to_string().parse().wrap_err()?;
-            config.add_address(sock_addr);
+            config.add_address(get_listen_addr(addr)?);
         }
 
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(yaml: &str) -> config::Config {
+        let mut settings = config::Config::new();
+        settings.merge(config::File::from_str(yaml, config::FileFormat::Yaml)).unwrap();
+        settings
+    }
+
+    #[test]
+    fn test_get_sni_certs() {
+        assert!(get_sni_certs(&settings("next_port: 123")).unwrap().is_empty());
+
+        let sni_certs = get_sni_certs(&settings("
+sni_certs:
+  - hostname: localhost
+    tls_cert_file: tests/chain.pem
+    tls_key_file: tests/tls-pkcs8.pem
+  - hostname: server
+    tls_cert_file: tests/tls.pem
+    tls_key_file: tests/tls-pkcs8.pem
+")).unwrap();
+        assert_eq!(sni_certs.len(), 2);
+        assert_eq!(sni_certs[0].hostname, "localhost");
+        assert_eq!(sni_certs[0].chain.tls_certs.len(), 3);
+        assert_eq!(sni_certs[1].hostname, "server");
+        assert_eq!(sni_certs[1].chain.tls_certs.len(), 1);
+        assert_eq!(sni_certs[1].chain.tls_ocsp, None);
+        assert_eq!(sni_certs[1].reload().unwrap().chain.tls_certs, sni_certs[1].chain.tls_certs);
+
+        // Each entry needs a hostname, a certificate chain, and a private key.
+        assert!(get_sni_certs(&settings("
+sni_certs:
+  - hostname: localhost
+    tls_cert_file: tests/chain.pem
+")).is_err());
+        // There is no private key in the file.
+        assert!(get_sni_certs(&settings("
+sni_certs:
+  - hostname: localhost
+    tls_cert_file: tests/chain.pem
+    tls_key_file: tests/chain.pem
+")).is_err());
+    }
+
+    #[test]
+    fn test_get_client_auth_config() {
+        assert!(get_client_auth_config(&settings("next_port: 123")).unwrap().is_none());
+
+        let client_auth = get_client_auth_config(&settings("
+client_ca_file: tests/ca.pem
+client_allowed_sans:
+  - client.example.com
+  - localhost
+")).unwrap().unwrap();
+        assert_eq!(client_auth.roots.len(), 1);
+        assert_eq!(client_auth.allowed_sans, vec!["client.example.com", "localhost"]);
+
+        let client_auth = get_client_auth_config(&settings("client_ca_file: tests/chain.pem"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_auth.roots.len(), 3);
+        assert!(client_auth.allowed_sans.is_empty());
+
+        // There is no certificate in the file.
+        assert!(get_client_auth_config(&settings("client_ca_file: tests/tls-pkcs8.pem")).is_err());
+        assert!(get_client_auth_config(&settings("client_ca_file: tests/missing.pem")).is_err());
+    }
+
+    #[test]
+    fn test_reload_client_auth() {
+        let key_store = KeyStoreConfig::Memcached {
+            urls: vec![String::from("memcache://localhost:11211")],
+            prefix: String::from("/nts/nts-keys"),
+            tls: None,
+            credentials: None,
+        };
+        let cookie_key = CookieKey::from(vec![0; 32]);
+        let mut config = KeServerConfig::new(30, cookie_key, key_store, None, 123);
+        assert!(config.reload_client_auth().unwrap().is_none());
+
+        let filename = std::env::temp_dir()
+            .join(format!("cfnts-test-{}.yaml", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        std::fs::write(&filename, "client_ca_file: tests/ca.pem").unwrap();
+        config.filename = Some(filename.clone());
+        let client_auth = config.reload_client_auth().unwrap().unwrap();
+        assert_eq!(client_auth.roots.len(), 1);
+        assert!(client_auth.allowed_sans.is_empty());
+
+        // The allowed names are picked up from the file.
+        std::fs::write(&filename, "
+client_ca_file: tests/chain.pem
+client_allowed_sans:
+  - localhost
+").unwrap();
+        let client_auth = config.reload_client_auth().unwrap().unwrap();
+        assert_eq!(client_auth.roots.len(), 3);
+        assert_eq!(client_auth.allowed_sans, vec!["localhost"]);
+
+        std::fs::write(&filename, "client_ca_file: tests/missing.pem").unwrap();
+        assert!(config.reload_client_auth().is_err());
+
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn test_load_ocsp_response() {
+        let filename = std::env::temp_dir()
+            .join(format!("cfnts-test-{}.ocsp", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // An empty file means that there is nothing to staple.
+        std::fs::write(&filename, b"").unwrap();
+        assert_eq!(load_ocsp_response(&filename).unwrap(), None);
+
+        std::fs::write(&filename, [1, 2, 3]).unwrap();
+        assert_eq!(load_ocsp_response(&filename).unwrap(), Some(vec![1, 2, 3]));
+
+        let chain = get_tls_alt_cert(&settings(&format!("
+tls_alt_cert_file: tests/tls.pem
+tls_alt_key_file: tests/tls-pkcs8.pem
+tls_alt_ocsp_file: {}
+", filename))).unwrap().unwrap();
+        assert_eq!(chain.tls_ocsp, Some(vec![1, 2, 3]));
+
+        // The new response is picked up on reload.
+        std::fs::write(&filename, [4, 5, 6]).unwrap();
+        assert_eq!(chain.reload().unwrap().tls_ocsp, Some(vec![4, 5, 6]));
+
+        std::fs::remove_file(&filename).unwrap();
+        assert!(load_ocsp_response(&filename).is_err());
+        assert!(chain.reload().is_err());
+    }
+
+    /// Parse a config with the required settings and `extra`.
+    fn parse(extra: &str) -> Result<KeServerConfig, config::ConfigError> {
+        let filename = std::env::temp_dir()
+            .join(format!("cfnts-test-{}-{:?}.yaml", std::process::id(),
+                          std::thread::current().id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        std::fs::write(&filename, format!("
+addr:
+  - 127.0.0.1:4460
+tls_key_file: tests/tls-pkcs8.pem
+tls_cert_file: tests/chain.pem
+cookie_key_file: tests/cookie.key
+memc_url: memcache://memcache:11211
+next_port: 123
+{}", extra)).unwrap();
+        let config = KeServerConfig::parse(&filename);
+        std::fs::remove_file(&filename).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_max_connections() {
+        assert_eq!(parse("").unwrap().max_connections, None);
+        assert_eq!(parse("max_connections: 1000").unwrap().max_connections, Some(1000));
+        assert!(parse("max_connections: 0").is_err());
+        assert!(parse("max_connections: -1").is_err());
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        assert_eq!(parse("").unwrap().drain_timeout, DEFAULT_DRAIN_TIMEOUT);
+        assert_eq!(parse("drain_timeout: 0").unwrap().drain_timeout, 0);
+        assert_eq!(parse("drain_timeout: 60").unwrap().drain_timeout, 60);
+        assert!(parse("drain_timeout: -1").is_err());
+    }
+
+    #[test]
+    fn test_accept_workers() {
+        assert_eq!(parse("").unwrap().accept_workers, 1);
+        assert_eq!(parse("accept_workers: 4").unwrap().accept_workers, 4);
+        assert!(parse("accept_workers: 0").is_err());
+        assert!(parse("accept_workers: -1").is_err());
+    }
+
+    fn listen_addr(entry: &str) -> Result<KeListenAddr, config::ConfigError> {
+        let mut addrs = settings(&format!("addr:\n  - {}", entry)).get_array("addr")?;
+        get_listen_addr(addrs.remove(0))
+    }
+
+    #[test]
+    fn test_v6only() {
+        assert_eq!(listen_addr("\"[::]:4460\"").unwrap().v6only, None);
+        assert_eq!(listen_addr("{addr: \"[::]:4460\"}").unwrap().v6only, None);
+        assert_eq!(listen_addr("{addr: \"[::]:4460\", v6only: false}").unwrap().v6only,
+                   Some(false));
+        assert_eq!(listen_addr("{addr: \"[::1]:4460\", v6only: true}").unwrap().v6only,
+                   Some(true));
+
+        // It's only for the IPv6 addresses.
+        assert!(listen_addr("{addr: 127.0.0.1:4460, v6only: true}").is_err());
+        assert!(listen_addr("{path: /run/cfnts.sock, proxy_protocol: true, v6only: true}")
+            .is_err());
+        assert!(listen_addr("{addr: \"[::]:4460\", v6only: maybe}").is_err());
+    }
+}
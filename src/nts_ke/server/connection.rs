@@ -4,46 +4,105 @@
 
 //! NTS-KE server connection.
 
-use mio::tcp::{Shutdown, TcpStream};
+use lazy_static::lazy_static;
+
+use prometheus::{
+    opts,
+    register_counter,
+    register_int_counter,
+    register_int_counter_vec,
+    IntCounter,
+    IntCounterVec,
+    __register_counter_vec,
+};
+
+use ring::{digest, hkdf};
 
 use rustls::Session;
 
-use slog::{debug, error, info};
+use slog::{debug, error, info, warn};
 
+use std::net::{Shutdown, SocketAddr};
 use std::sync::{Arc, RwLock};
 use std::io::{Read, Write};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::cookie::{make_cookie, NTSKeys};
 use crate::key_rotator::KeyRotator;
+use crate::metrics;
+use crate::nts_ke::records::deserialize;
 use crate::nts_ke::records::gen_key;
 use crate::nts_ke::records::serialize;
+use crate::nts_ke::records::EXPORTER_LABEL;
 use crate::nts_ke::records::{
     AeadAlgorithmRecord,
+    DeserializeError,
+    ErrorRecord,
+    KeRecord,
+    KeRecordTrait,
+    HEADER_SIZE,
     EndOfMessageRecord,
     NextProtocolRecord,
     NewCookieRecord,
     PortRecord,
+    ServerRecord,
 
     KnownAeadAlgorithm,
+    KnownErrorCode,
     KnownNextProtocol,
     Party,
 };
 
+use super::config::UnknownCriticalRecords;
 use super::listener::KeServerListener;
-use super::server::KeServerState;
+use super::next_server::NtpEndpoint;
+use super::proxy_protocol;
+use super::server::{AccessRecord, KeServerState};
+use super::socket::KeStream;
+
+/// The number of cookies sent in each response. According to the spec, if the next protocol is
+/// NTPv4, we should send eight cookies to the client.
+const COOKIE_COUNT: usize = 8;
+
+/// The type of the PROXY protocol TLV that carries the exporter secret of the TLS session in hex,
+/// as HAProxy's `ssl_fc_exporter_secret` returns it. It's in the range reserved for custom
+/// applications.
+const PP2_TYPE_EXPORTER_SECRET: u8 = 0xe0;
+
+lazy_static! {
+    static ref HANDSHAKE_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_handshakes_total",
+        "Number of completed TLS handshakes, including resumed ones"
+    ).unwrap();
+    static ref OVERSIZE_REQUEST_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_oversize_requests_total",
+        "Number of connections closed for sending more than the connection buffer limit"
+    ).unwrap();
+    static ref UNKNOWN_CRITICAL_RECORD_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_unknown_critical_records_total",
+        "Number of unknown critical records in the requests, by whether they were rejected or \
+         ignored",
+        &["action"]
+    ).unwrap();
+    static ref ERROR_RECORD_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_error_records_total",
+        "Number of Error records sent to the clients",
+        &["code"]
+    ).unwrap();
+}
 
 // response uses the configuration and the keys and computes the response
 // sent to the client.
-fn response(keys: NTSKeys, rotator: &Arc<RwLock<KeyRotator>>, port: u16) -> Vec<u8> {
+fn response(keys: NTSKeys, rotator: &Arc<RwLock<KeyRotator>>, next_server: &NtpEndpoint)
+    -> Vec<u8>
+{
     let mut response: Vec<u8> = Vec::new();
 
     let next_protocol_record = NextProtocolRecord::from(vec![
         KnownNextProtocol::Ntpv4,
     ]);
-    let aead_record = AeadAlgorithmRecord::from(vec![
-        KnownAeadAlgorithm::AeadAesSivCmac256,
-    ]);
-    let port_record = PortRecord::new(Party::Server, port);
+    let aead_record = AeadAlgorithmRecord::from(vec![keys.aead]);
+    let port_record = PortRecord::new(Party::Server, next_server.port);
     let end_record = EndOfMessageRecord;
 
     response.append(&mut serialize(next_protocol_record));
@@ -52,20 +111,224 @@ fn response(keys: NTSKeys, rotator: &Arc<RwLock<KeyRotator>>, port: u16) -> Vec<
     let rotor = rotator.read().unwrap();
     let (key_id, actual_key) = rotor.latest_key_value();
 
-    // According to the spec, if the next protocol is NTPv4, we should send eight cookies to the
-    // client.
-    for _ in 0..8 {
+    for _ in 0..COOKIE_COUNT {
         let cookie = make_cookie(keys, actual_key.as_ref(), key_id);
         let cookie_record = NewCookieRecord::from(cookie);
         response.append(&mut serialize(cookie_record));
     }
+    // The address was already checked when the config was parsed.
+    if let Some(server) = &next_server.server {
+        let server_record = ServerRecord::new(Party::Server, server)
+            .expect("the next server is invalid");
+        response.append(&mut serialize(server_record));
+    }
     response.append(&mut serialize(port_record));
     response.append(&mut serialize(end_record));
     response
 }
 
+// error_response computes the response sent to the client when its request cannot be served.
+fn error_response(code: KnownErrorCode) -> Vec<u8> {
+    let mut response: Vec<u8> = Vec::new();
+    response.append(&mut serialize(ErrorRecord::new(code)));
+    response.append(&mut serialize(EndOfMessageRecord));
+    response
+}
+
+/// Check the client's request and return the AEAD algorithms that it offers and we support, in
+/// the client's order of preference, and the number of unknown critical records that were
+/// ignored.
+///
+/// # Errors
+///
+/// Return the code of the Error record that we should send back, if the request has a critical
+/// record that we don't know and `unknown_critical_records` says to reject it, or it doesn't
+/// offer NTPv4 or any AEAD algorithm that we support. The AEAD Algorithm Negotiation record is
+/// mandatory with NTPv4, so a request without it is a bad request too.
+///
+fn parse_request(request: &[u8], unknown_critical_records: UnknownCriticalRecords)
+    -> Result<(Vec<KnownAeadAlgorithm>, usize), KnownErrorCode>
+{
+    let mut next_protocol = None;
+    let mut aeads = None;
+    let mut ignored = 0;
+
+    let mut offset = 0;
+    while request.len() - offset >= HEADER_SIZE {
+        let body_len = usize::from(u16::from_be_bytes([request[offset + 2], request[offset + 3]]));
+        let end = offset + HEADER_SIZE + body_len;
+        if end > request.len() {
+            break;
+        }
+
+        match deserialize(Party::Client, &request[offset..end]) {
+            // The unsupported protocols and algorithms are already skipped when parsing the
+            // records, so we only have to check that something is left.
+            Ok(KeRecord::NextProtocol(record)) => {
+                next_protocol = Some(record.protocols().contains(&KnownNextProtocol::Ntpv4));
+            },
+            Ok(KeRecord::AeadAlgorithm(record)) => aeads = Some(record.algorithms().to_vec()),
+            Ok(KeRecord::EndOfMessage(_)) => break,
+            Ok(_) | Err(DeserializeError::UnknownNotCriticalRecord) => (),
+            Err(DeserializeError::UnknownCriticalRecord) => match unknown_critical_records {
+                UnknownCriticalRecords::Reject => {
+                    UNKNOWN_CRITICAL_RECORD_COUNTER.with_label_values(&["rejected"]).inc();
+                    return Err(KnownErrorCode::UnrecognizedCriticalRecord);
+                },
+                UnknownCriticalRecords::Ignore => {
+                    UNKNOWN_CRITICAL_RECORD_COUNTER.with_label_values(&["ignored"]).inc();
+                    ignored += 1;
+                },
+            },
+            Err(DeserializeError::Parsing(_)) => return Err(KnownErrorCode::BadRequest),
+        }
+        offset = end;
+    }
+
+    // The Next Protocol Negotiation record is mandatory and we only speak NTPv4.
+    if next_protocol != Some(true) {
+        return Err(KnownErrorCode::BadRequest);
+    }
+
+    match aeads {
+        Some(aeads) if !aeads.is_empty() => Ok((aeads, ignored)),
+        _ => Err(KnownErrorCode::BadRequest),
+    }
+}
+
+/// Append `data` to the part of the request in `buffer`, and return the request once its End of
+/// Message record has arrived. A request can be split across several reads.
+fn buffer_request(buffer: &mut Vec<u8>, data: &[u8]) -> Option<Vec<u8>> {
+    buffer.extend_from_slice(data);
+
+    let mut offset = 0;
+    while buffer.len() - offset >= HEADER_SIZE {
+        let record_type = u16::from_be_bytes([buffer[offset] & 0x7f, buffer[offset + 1]]);
+        let body_len = usize::from(u16::from_be_bytes([buffer[offset + 2], buffer[offset + 3]]));
+        offset += HEADER_SIZE + body_len;
+        if offset > buffer.len() {
+            return None;
+        }
+        if record_type == EndOfMessageRecord::record_type() {
+            buffer.truncate(offset);
+            return Some(std::mem::replace(buffer, Vec::new()));
+        }
+    }
+    None
+}
+
+/// The TLS 1.3 exporter secret of a session that is terminated by the load balancer. The keys for
+/// any AEAD algorithm can be exported from it, as if the session were our own.
+struct ExporterSecret {
+    /// The HKDF of the cipher suite of the session.
+    algorithm: hkdf::Algorithm,
+
+    /// The exporter secret.
+    secret: Vec<u8>,
+}
+
+/// The output length of an HKDF expansion.
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+impl ExporterSecret {
+    /// Parse the exporter secret that the load balancer sent in the PROXY header. Its hash comes
+    /// from the name of the cipher suite in the standard SSL TLV.
+    fn from_tlvs(tlvs: &[(u8, &[u8])]) -> Result<ExporterSecret, String> {
+        let ssl = find_tlv(tlvs, proxy_protocol::PP2_TYPE_SSL)
+            .ok_or_else(|| String::from("the PROXY header has no SSL TLV"))?;
+        let ssl_tlvs = proxy_protocol::parse_ssl_tlvs(ssl).map_err(|error| error.to_string())?;
+        let cipher = find_tlv(&ssl_tlvs, proxy_protocol::PP2_SUBTYPE_SSL_CIPHER)
+            .ok_or_else(|| String::from("the PROXY header has no cipher suite"))?;
+        // Only the TLS 1.3 cipher suites, whose names end with the hash, have exporter secrets.
+        let algorithm = if cipher.starts_with(b"TLS_") && cipher.ends_with(b"_SHA256") {
+            hkdf::HKDF_SHA256
+        } else if cipher.starts_with(b"TLS_") && cipher.ends_with(b"_SHA384") {
+            hkdf::HKDF_SHA384
+        } else {
+            return Err(format!("the cipher suite {} is not a TLS 1.3 one",
+                               String::from_utf8_lossy(cipher)));
+        };
+
+        let secret = find_tlv(tlvs, PP2_TYPE_EXPORTER_SECRET)
+            .ok_or_else(|| String::from("the PROXY header has no exporter secret"))?;
+        let secret = decode_hex(secret)
+            .ok_or_else(|| String::from("the exporter secret is not in hex"))?;
+        if secret.len() != algorithm.hmac_algorithm().digest_algorithm().output_len {
+            return Err(format!("the exporter secret has the wrong length {}", secret.len()));
+        }
+
+        Ok(ExporterSecret { algorithm, secret })
+    }
+
+    /// Export the keys for `aead` in the same way that `gen_key` does.
+    fn export_keys(&self, aead: KnownAeadAlgorithm) -> NTSKeys {
+        let mut keys = NTSKeys { aead, c2s: [0; 32], s2c: [0; 32] };
+        let [aead_hi, aead_lo] = aead.as_algorithm_id().to_be_bytes();
+        let key_len = aead.key_len();
+        self.export(&mut keys.c2s[..key_len], &[0, 0, aead_hi, aead_lo, 0]);
+        self.export(&mut keys.s2c[..key_len], &[0, 0, aead_hi, aead_lo, 1]);
+        keys
+    }
+
+    /// The TLS-Exporter function of RFC 8446, section 7.5.
+    fn export(&self, out: &mut [u8], context: &[u8]) {
+        let hash = self.algorithm.hmac_algorithm().digest_algorithm();
+        let mut secret = vec![0; hash.output_len];
+        expand_label(
+            &hkdf::Prk::new_less_safe(self.algorithm, &self.secret),
+            EXPORTER_LABEL,
+            digest::digest(hash, &[]).as_ref(),
+            &mut secret,
+        );
+        expand_label(
+            &hkdf::Prk::new_less_safe(self.algorithm, &secret),
+            b"exporter",
+            digest::digest(hash, context).as_ref(),
+            out,
+        );
+    }
+}
+
+/// Return the value of the first TLV of type `kind`.
+fn find_tlv<'a>(tlvs: &[(u8, &'a [u8])], kind: u8) -> Option<&'a [u8]> {
+    tlvs.iter().find(|(found, _)| *found == kind).map(|(_, value)| *value)
+}
+
+/// The HKDF-Expand-Label function of RFC 8446, section 7.1.
+fn expand_label(prk: &hkdf::Prk, label: &[u8], context: &[u8], out: &mut [u8]) {
+    const PREFIX: &[u8] = b"tls13 ";
+    let out_len = (out.len() as u16).to_be_bytes();
+    let label_len = [(PREFIX.len() + label.len()) as u8];
+    let context_len = [context.len() as u8];
+    let info = [&out_len[..], &label_len, PREFIX, label, &context_len, context];
+    prk.expand(&info, OutputLen(out.len()))
+        .and_then(|okm| okm.fill(out))
+        .expect("the exported key is too long");
+}
+
+/// Decode a hex string. Return `None` if it's not a valid hex string.
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            let digits = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(digits, 16).ok()
+        })
+        .collect()
+}
+
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum KeServerConnState {
+    /// Waiting for the PROXY protocol header from the load balancer.
+    ProxyHeader,
     /// The connection is just connected. The TLS handshake is not done yet.
     Connected,
     /// Doing the TLS handshake,
@@ -78,48 +341,118 @@ pub enum KeServerConnState {
     Closed,
 }
 
-/// NTS-KE server TCP connection.
+/// NTS-KE server connection over TCP or a Unix domain socket.
 pub struct KeServerConn {
     /// Reference back to the corresponding `KeServer` state.
     server_state: Arc<KeServerState>,
 
-    /// Kernel TCP stream.
-    tcp_stream: TcpStream,
+    /// Kernel TCP or Unix domain socket stream.
+    stream: KeStream,
 
     /// The mio token for this connection.
     token: mio::Token,
 
-    /// TLS session for this connection.
+    /// TLS session for this connection. It's not used if TLS is terminated by the load balancer.
     tls_session: rustls::ServerSession,
 
+    /// Whether TLS is terminated by the load balancer.
+    plaintext: bool,
+
+    /// The exporter secret of the TLS session of the load balancer, if TLS is terminated there.
+    exporter_secret: Option<ExporterSecret>,
+
+    /// The response that is not written to the load balancer yet, if TLS is terminated there.
+    plaintext_out: Vec<u8>,
+
+    /// The part of the request that is read before its End of Message record.
+    request: Vec<u8>,
+
     /// The status of the connection.
     state: KeServerConnState,
 
+    /// The address of the client. Behind a load balancer, it's the load balancer's address
+    /// until the PROXY header is read.
+    client_addr: SocketAddr,
+
+    /// The part of the PROXY header that is already read.
+    proxy_header: Vec<u8>,
+
+    /// The time that the connection will be closed, if there is no progress. It's `None` if the
+    /// timeout is too large to represent.
+    deadline: Option<SystemTime>,
+
+    /// The number of bytes read from the client so far.
+    received: usize,
+
+    /// The time that the connection was accepted.
+    accepted_at: Instant,
+
+    /// How long the TLS handshake took, if it's done.
+    handshake_time: Option<Duration>,
+
+    /// The AEAD algorithm of the keys sent to the client, if the request was served.
+    aead: Option<KnownAeadAlgorithm>,
+
+    /// How long it took from accepting the connection to flushing the response, if the response
+    /// is flushed.
+    exchange_time: Option<Duration>,
+
+    /// How the exchange ended, for the access log. If it's `None` when the connection is closed,
+    /// it's derived from the state of the connection.
+    result: Option<&'static str>,
+
     /// Logger.
     logger: slog::Logger,
 }
 
 impl KeServerConn {
     pub fn new(
-        tcp_stream: TcpStream,
+        stream: KeStream,
+        client_addr: SocketAddr,
         token: mio::Token,
         listener: &KeServerListener,
     ) -> KeServerConn {
         let server_state = listener.state();
 
         // Create a TLS session from a server-wide configuration.
-        let tls_session = rustls::ServerSession::new(&server_state.tls_server_config);
-        // Create a child logger for the connection.
-        let logger = listener.logger().new(slog::o!("client" => listener.addr().to_string()));
+        // We clone the `Arc` so that the lock is not held for the lifetime of the connection.
+        let tls_server_config = server_state.tls_server_config.read().unwrap().clone();
+        let tls_session = rustls::ServerSession::new(&tls_server_config);
+        // Create a child logger for the connection. Behind a load balancer, the client address is
+        // added after the PROXY header is read.
+        let (logger, state) = if listener.proxy_protocol() {
+            (listener.logger().new(slog::o!("proxy" => client_addr.to_string())),
+             KeServerConnState::ProxyHeader)
+        } else {
+            (listener.logger().new(slog::o!("client" => client_addr.to_string())),
+             KeServerConnState::Connected)
+        };
+
+        // The handshake must finish before this deadline.
+        let handshake_timeout = Duration::from_secs(server_state.config.handshake_timeout());
+        let deadline = SystemTime::now().checked_add(handshake_timeout);
 
         KeServerConn {
             // Create an `Arc` reference.
             server_state: server_state.clone(),
-            tcp_stream,
+            stream,
             tls_session,
+            plaintext: listener.plaintext(),
+            exporter_secret: None,
+            plaintext_out: Vec::new(),
+            request: Vec::new(),
             token,
             logger,
-            state: KeServerConnState::Connected,
+            state,
+            client_addr,
+            proxy_header: Vec::new(),
+            deadline,
+            received: 0,
+            accepted_at: Instant::now(),
+            handshake_time: None,
+            aead: None,
+            exchange_time: None,
+            result: None,
         }
     }
 
@@ -133,13 +466,77 @@ impl KeServerConn {
             self.write_ready();
         }
 
+        // After the handshake, any activity postpones the idle timeout.
+        if self.is_handshake_done() {
+            let idle_timeout = Duration::from_secs(self.server_state.config.idle_timeout());
+            self.deadline = SystemTime::now().checked_add(idle_timeout);
+        }
+
         if self.state() != KeServerConnState::Closed {
             // TODO: Fix unwrap later.
             self.reregister(poll).unwrap();
         }
     }
 
+    /// Return true if the TLS handshake is done and the connection is not closed.
+    pub fn is_handshake_done(&self) -> bool {
+        match self.state {
+            KeServerConnState::Opened | KeServerConnState::ResponseSent => true,
+            _ => false,
+        }
+    }
+
+    /// Return the time that the connection will be closed, if there is no progress.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
     fn read_ready(&mut self) {
+        // The TLS data starts only after the PROXY header.
+        if self.state == KeServerConnState::ProxyHeader {
+            let rest = match self.read_proxy_header() {
+                Some(rest) => rest,
+                None => return,
+            };
+            self.state = KeServerConnState::Connected;
+
+            // Without TLS, the request can be read right away.
+            if self.plaintext {
+                self.state = KeServerConnState::Opened;
+                if !rest.is_empty() {
+                    self.process_plaintext(&rest);
+                }
+                return;
+            }
+
+            // The load balancer may have sent some TLS data together with the header. It must be
+            // processed now because it's no longer in the socket to wake us up.
+            if rest.is_empty() {
+                return;
+            }
+            self.state = KeServerConnState::TlsHandshaking;
+            match self.tls_session.read_tls(&mut rest.as_slice()) {
+                Ok(read_count) if read_count == rest.len() => (),
+                Ok(_) => {
+                    error!(self.logger, "too much data after the PROXY header");
+                    self.shutdown();
+                    return;
+                },
+                Err(error) => {
+                    error!(self.logger, "read error: {}", error);
+                    self.shutdown();
+                    return;
+                },
+            }
+            self.process_tls();
+            return;
+        }
+
+        if self.plaintext {
+            self.read_plaintext();
+            return;
+        }
+
         // If this is the first time that `read_ready` is called, it means that we start reading
         // some TLS client hello from the client. So we need to change the state to TlsHandshaking.
         if self.state == KeServerConnState::Connected {
@@ -147,7 +544,7 @@ impl KeServerConn {
         }
 
         // Read some data from the stream and feed it to the TLS stream.
-        let result = self.tls_session.read_tls(&mut self.tcp_stream);
+        let result = self.tls_session.read_tls(&mut self.stream);
 
         let read_count = match result {
             Ok(value) => value,
@@ -171,7 +568,104 @@ impl KeServerConn {
             self.shutdown();
             return;
         }
+        if !self.count_received(read_count) {
+            return;
+        }
+
+        self.process_tls();
+    }
+
+    /// Read the PROXY header from the load balancer. Return the data after the header, if the
+    /// whole header is read.
+    fn read_proxy_header(&mut self) -> Option<Vec<u8>> {
+        let mut buf = [0; 4096];
+        let read_count = match self.stream.read(&mut buf) {
+            Ok(0) => {
+                info!(self.logger, "eof");
+                self.shutdown();
+                return None;
+            },
+            Ok(read_count) => read_count,
+            // If it's a WouldBlock, it's not actually an error.
+            Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => return None,
+            Err(error) => {
+                error!(self.logger, "read error: {}", error);
+                self.shutdown();
+                return None;
+            },
+        };
+        if !self.count_received(read_count) {
+            return None;
+        }
+        self.proxy_header.extend_from_slice(&buf[..read_count]);
+
+        let header = match proxy_protocol::parse_header(&self.proxy_header) {
+            // The header is not complete yet.
+            Ok(None) => return None,
+            Ok(Some(header)) => header,
+            Err(error) => {
+                error!(self.logger, "{}", error);
+                self.shutdown();
+                return None;
+            },
+        };
+
+        // The source is unknown when the connection is the load balancer's own, for example, a
+        // health check. In which case, we treat the load balancer as the client.
+        if let Some(source) = header.source {
+            self.client_addr = source;
+        }
+        self.logger = self.logger.new(slog::o!("client" => self.client_addr.to_string()));
+
+        if !self.server_state.check_access(self.client_addr.ip()) {
+            debug!(self.logger, "denied connection");
+            self.set_result("denied");
+            self.shutdown();
+            return None;
+        }
+        if !self.server_state.check_rate_limit(self.client_addr.ip()) {
+            debug!(self.logger, "rate limited connection");
+            self.set_result("rate_limited");
+            self.shutdown();
+            return None;
+        }
+
+        if self.plaintext {
+            let exporter_secret = proxy_protocol::parse_tlvs(&self.proxy_header, &header)
+                .map_err(|error| error.to_string())
+                .and_then(|tlvs| ExporterSecret::from_tlvs(&tlvs));
+            match exporter_secret {
+                Ok(exporter_secret) => self.exporter_secret = Some(exporter_secret),
+                Err(error) => {
+                    error!(self.logger, "{}", error);
+                    self.shutdown();
+                    return None;
+                },
+            }
+        }
+
+        let rest = self.proxy_header.split_off(header.len);
+        self.proxy_header = Vec::new();
+        Some(rest)
+    }
 
+    /// Count the bytes read from the client. If the client has sent more than the limit, close
+    /// the connection and return false.
+    fn count_received(&mut self, read_count: usize) -> bool {
+        self.received = self.received.saturating_add(read_count);
+        if self.received <= self.server_state.config.conn_buffer_limit {
+            return true;
+        }
+
+        OVERSIZE_REQUEST_COUNTER.inc();
+        info!(self.logger, "closing the connection after reading {} bytes", self.received);
+        self.set_result("oversize_request");
+        self.shutdown();
+        false
+    }
+
+    /// Process the TLS messages that are already read from the client.
+    fn process_tls(&mut self) {
         // Process newly received TLS messages.
         let processed = self.tls_session.process_new_packets();
 
@@ -180,6 +674,13 @@ impl KeServerConn {
             self.shutdown();
         }
 
+        // The handshake is done. It's opened for requests now.
+        if self.state == KeServerConnState::TlsHandshaking && !self.tls_session.is_handshaking() {
+            HANDSHAKE_COUNTER.inc();
+            self.handshake_time = Some(self.accepted_at.elapsed());
+            self.state = KeServerConnState::Opened;
+        }
+
         let mut buf = Vec::new();
         let result = self.tls_session.read_to_end(&mut buf);
 
@@ -195,35 +696,152 @@ impl KeServerConn {
             // The plaintext is not empty. It means that the handshake is also done. We can change
             // the state now.
             if self.state == KeServerConnState::TlsHandshaking {
+                self.handshake_time = Some(self.accepted_at.elapsed());
                 self.state = KeServerConnState::Opened;
             }
 
-            let keys = gen_key(&self.tls_session).unwrap();
-
             // We have to make sure that the response is not sent yet.
-            if self.state == KeServerConnState::Opened {
-                // TODO: Fix unwrap later.
-                self.tls_session
-                    .write_all(&response(keys, &self.server_state.rotator,
-                                         self.server_state.config.next_port)).unwrap();
-                // Mark that the reponse is sent.
-                self.state = KeServerConnState::ResponseSent;
+            if self.state != KeServerConnState::Opened {
+                return;
+            }
+
+            let request = match buffer_request(&mut self.request, &buf) {
+                Some(request) => request,
+                None => return,
+            };
+            let (response, is_error) = self.respond(&request);
+
+            // TODO: Fix unwrap later.
+            self.tls_session.write_all(&response).unwrap();
+            // There is nothing else to say after an error, so close the session.
+            if is_error {
+                self.tls_session.send_close_notify();
+            }
+            // Mark that the reponse is sent.
+            self.state = KeServerConnState::ResponseSent;
+        }
+    }
+
+    /// Read the plaintext request from the load balancer, if TLS is terminated there.
+    fn read_plaintext(&mut self) {
+        let mut buf = [0; 4096];
+        match self.stream.read(&mut buf) {
+            Ok(0) => {
+                info!(self.logger, "eof");
+                self.shutdown();
+            },
+            Ok(read_count) => {
+                if self.count_received(read_count) {
+                    self.process_plaintext(&buf[..read_count]);
+                }
+            },
+            // If it's a WouldBlock, it's not actually an error.
+            Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => (),
+            Err(error) => {
+                error!(self.logger, "read error: {}", error);
+                self.shutdown();
+            },
+        }
+    }
+
+    /// Process the plaintext request, if TLS is terminated by the load balancer.
+    fn process_plaintext(&mut self, data: &[u8]) {
+        debug!(self.logger, "plaintext read {},", data.len());
+
+        // We have to make sure that the response is not sent yet.
+        if self.state != KeServerConnState::Opened {
+            return;
+        }
+
+        let request = match buffer_request(&mut self.request, data) {
+            Some(request) => request,
+            None => return,
+        };
+        let (mut response, _) = self.respond(&request);
+        self.plaintext_out.append(&mut response);
+        self.state = KeServerConnState::ResponseSent;
+    }
+
+    /// Compute the response to the client's request. The second value is true if the response
+    /// is an Error record.
+    fn respond(&mut self, request: &[u8]) -> (Vec<u8>, bool) {
+        let policy = self.server_state.config.unknown_critical_records;
+        let keys = parse_request(request, policy).and_then(|(aeads, ignored)| {
+            if ignored > 0 {
+                warn!(self.logger, "ignored {} unknown critical records", ignored);
             }
+            self.export_keys(&aeads)
+        });
+
+        match keys {
+            Ok(keys) => {
+                let response = response(keys, &self.server_state.rotator,
+                                        self.server_state.next_servers.select());
+                self.aead = Some(keys.aead);
+                self.set_result("ok");
+                (response, false)
+            },
+            Err(code) => {
+                info!(self.logger, "sending an error to the client: {}", code.name());
+                ERROR_RECORD_COUNTER.with_label_values(&[code.name()]).inc();
+                self.set_result(code.name());
+                (error_response(code), true)
+            },
         }
     }
 
+    /// Return the keys for the first of `aeads` that we can export the keys for.
+    fn export_keys(&self, aeads: &[KnownAeadAlgorithm]) -> Result<NTSKeys, KnownErrorCode> {
+        // The PROXY header with the exporter secret is read before any request.
+        if let Some(exporter_secret) = &self.exporter_secret {
+            return Ok(exporter_secret.export_keys(aeads[0]));
+        }
+
+        gen_key(&self.tls_session, aeads[0]).map_err(|error| {
+            error!(self.logger, "cannot export the keys: {}", error);
+            KnownErrorCode::InternalServerError
+        })
+    }
+
     fn write_ready(&mut self) {
-        if let Err(error) = self.tls_session.write_tls(&mut self.tcp_stream) {
+        if self.plaintext {
+            match self.stream.write(&self.plaintext_out) {
+                Ok(write_count) => {
+                    self.plaintext_out.drain(..write_count);
+                    if self.plaintext_out.is_empty() {
+                        self.response_flushed();
+                    }
+                },
+                Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => (),
+                Err(error) => {
+                    error!(self.logger, "write failed: {}", error);
+                    self.shutdown();
+                },
+            }
+            return;
+        }
+
+        if let Err(error) = self.tls_session.write_tls(&mut self.stream) {
             error!(self.logger, "write failed: {}", error);
             self.shutdown();
             return;
         }
+        if !self.tls_session.wants_write() {
+            self.response_flushed();
+        }
+    }
+
+    /// Record the exchange time, if the whole response has just been written to the socket.
+    fn response_flushed(&mut self) {
+        if self.state == KeServerConnState::ResponseSent && self.exchange_time.is_none() {
+            self.exchange_time = Some(self.accepted_at.elapsed());
+        }
     }
 
     /// Register the connection with Poll.
     pub fn register(&self, poll: &mut mio::Poll) -> Result<(), std::io::Error> {
         poll.register(
-            &self.tcp_stream,
+            &self.stream,
             self.token,
             self.interest(),
             mio::PollOpt::level(),
@@ -233,7 +851,7 @@ impl KeServerConn {
     /// Re-register the connection with Poll.
     pub fn reregister(&self, poll: &mut mio::Poll) -> Result<(), std::io::Error> {
         poll.reregister(
-            &self.tcp_stream,
+            &self.stream,
             self.token,
             self.interest(),
             mio::PollOpt::level(),
@@ -243,6 +861,14 @@ impl KeServerConn {
     fn interest(&self) -> mio::Ready {
         let mut ready = mio::Ready::empty();
 
+        if self.plaintext {
+            ready |= mio::Ready::readable();
+            if !self.plaintext_out.is_empty() {
+                ready |= mio::Ready::writable();
+            }
+            return ready;
+        }
+
         if self.tls_session.wants_read() {
             ready |= mio::Ready::readable();
         }
@@ -256,9 +882,217 @@ impl KeServerConn {
         self.state
     }
 
+    /// Record how the exchange ended, unless it's already recorded.
+    pub fn set_result(&mut self, result: &'static str) {
+        if self.result.is_none() {
+            self.result = Some(result);
+        }
+    }
+
     pub fn shutdown(&mut self) {
+        // The connection may be shut down more than once, but it's recorded only the first time.
+        if self.state != KeServerConnState::Closed {
+            self.record_exchange();
+        }
+
         // TODO: Fix unwrap later.
-        self.tcp_stream.shutdown(Shutdown::Both).unwrap();
+        self.stream.shutdown(Shutdown::Both).unwrap();
         self.state = KeServerConnState::Closed;
     }
+
+    /// Write the access log entry and the exchange time metric of the connection.
+    fn record_exchange(&self) {
+        let result = self.result.unwrap_or(match self.state {
+            KeServerConnState::ProxyHeader => "proxy_header_failed",
+            KeServerConnState::Connected | KeServerConnState::TlsHandshaking => {
+                "handshake_failed"
+            },
+            KeServerConnState::Opened => "no_request",
+            KeServerConnState::ResponseSent | KeServerConnState::Closed => "closed",
+        });
+
+        let exchange_time = self.exchange_time.unwrap_or_else(|| self.accepted_at.elapsed());
+        metrics::observe_ke_exchange(result, exchange_time);
+
+        self.server_state.log_access(&AccessRecord {
+            client: self.client_addr.ip(),
+            sni: self.tls_session.get_sni_hostname(),
+            aead: self.aead,
+            cookies: if self.aead.is_some() { COOKIE_COUNT } else { 0 },
+            handshake_time: self.handshake_time,
+            result,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_request() {
+        let mut request = serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]));
+        request.append(&mut serialize(AeadAlgorithmRecord::from(vec![
+            KnownAeadAlgorithm::AeadAesSivCmac256,
+        ])));
+        request.append(&mut serialize(EndOfMessageRecord));
+
+        // The request is only parsed once the End of Message record has arrived, wherever it's
+        // split.
+        for split in 1..request.len() {
+            let mut buffer = Vec::new();
+            assert_eq!(buffer_request(&mut buffer, &request[..split]), None);
+            assert_eq!(buffer_request(&mut buffer, &request[split..]), Some(request.clone()));
+            assert!(buffer.is_empty());
+        }
+
+        let mut buffer = Vec::new();
+        let (aeads, _) = buffer_request(&mut buffer, &request)
+            .and_then(|request| parse_request(&request, UnknownCriticalRecords::Reject).ok())
+            .unwrap();
+        assert_eq!(aeads, vec![KnownAeadAlgorithm::AeadAesSivCmac256]);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let next_protocol = serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]));
+        let aead = serialize(AeadAlgorithmRecord::from(vec![
+            KnownAeadAlgorithm::AeadAes128GcmSiv,
+            KnownAeadAlgorithm::AeadAesSivCmac256,
+        ]));
+        let end = serialize(EndOfMessageRecord);
+        // A critical record of a type that nobody uses.
+        let unknown = vec![0xff, 0xff, 0, 0];
+        let request = |records: &[&Vec<u8>]| -> Vec<u8> {
+            records.iter().flat_map(|record| record.iter().cloned()).collect()
+        };
+        let parse = |records: &[&Vec<u8>]| {
+            parse_request(&request(records), UnknownCriticalRecords::Reject)
+        };
+
+        assert_eq!(parse(&[&next_protocol, &aead, &end]), Ok((vec![
+            KnownAeadAlgorithm::AeadAes128GcmSiv,
+            KnownAeadAlgorithm::AeadAesSivCmac256,
+        ], 0)));
+
+        // The AEAD Algorithm Negotiation record is mandatory.
+        assert_eq!(parse(&[&next_protocol, &end]), Err(KnownErrorCode::BadRequest));
+        // None of the algorithms is supported.
+        let unsupported = vec![0x80, 0x04, 0, 2, 0, 0xff];
+        assert_eq!(parse(&[&next_protocol, &unsupported, &end]), Err(KnownErrorCode::BadRequest));
+        // The Next Protocol Negotiation record is mandatory.
+        assert_eq!(parse(&[&aead, &end]), Err(KnownErrorCode::BadRequest));
+
+        assert_eq!(parse(&[&next_protocol, &unknown, &aead, &end]),
+                   Err(KnownErrorCode::UnrecognizedCriticalRecord));
+        let ignored = parse_request(&request(&[&next_protocol, &unknown, &aead, &end]),
+                                    UnknownCriticalRecords::Ignore);
+        assert_eq!(ignored.map(|(_, ignored)| ignored), Ok(1));
+    }
+
+    /// Captures the exporter secret of a TLS session.
+    struct ExporterSecretLog(std::sync::Mutex<Vec<u8>>);
+
+    impl rustls::KeyLog for ExporterSecretLog {
+        fn log(&self, label: &str, _client_random: &[u8], secret: &[u8]) {
+            if label == "EXPORTER_SECRET" {
+                *self.0.lock().unwrap() = secret.to_vec();
+            }
+        }
+    }
+
+    /// Run a TLS 1.3 handshake with `suite` in memory. Return the server session and its exporter
+    /// secret.
+    fn handshake(suite: rustls::CipherSuite) -> (rustls::ServerSession, Vec<u8>) {
+        use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+
+        let chain = certs(&mut &include_bytes!("../../../tests/chain.pem")[..]).unwrap();
+        let key = pkcs8_private_keys(&mut &include_bytes!("../../../tests/tls-pkcs8.pem")[..])
+            .unwrap()
+            .remove(0);
+        let key_log = Arc::new(ExporterSecretLog(std::sync::Mutex::new(Vec::new())));
+        let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        server_config.set_single_cert(chain, key).unwrap();
+        server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+        server_config.ciphersuites.retain(|found| found.suite == suite);
+        server_config.key_log = key_log.clone();
+
+        let mut client_config = rustls::ClientConfig::new();
+        client_config.dangerous()
+            .set_certificate_verifier(Arc::new(crate::nts_ke::certificate::InsecureVerifier));
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+        let mut server = rustls::ServerSession::new(&Arc::new(server_config));
+        let mut client = rustls::ClientSession::new(&Arc::new(client_config), name);
+        while server.is_handshaking() || client.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets().unwrap();
+
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets().unwrap();
+        }
+
+        let secret = key_log.0.lock().unwrap().clone();
+        (server, secret)
+    }
+
+    #[test]
+    fn test_exporter_secret() {
+        let suites = [
+            (rustls::CipherSuite::TLS13_AES_128_GCM_SHA256, &b"TLS_AES_128_GCM_SHA256"[..]),
+            (rustls::CipherSuite::TLS13_AES_256_GCM_SHA384, &b"TLS_AES_256_GCM_SHA384"[..]),
+        ];
+        for (suite, cipher) in suites.iter() {
+            let (session, secret) = handshake(*suite);
+            let hex: String = secret.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+            // The SSL TLV as HAProxy sends it with `proxy-v2-options ssl,ssl-cipher`.
+            let mut ssl = vec![0x01, 0, 0, 0, 0, proxy_protocol::PP2_SUBTYPE_SSL_CIPHER, 0];
+            ssl.push(cipher.len() as u8);
+            ssl.extend_from_slice(cipher);
+            let tlvs = vec![
+                (proxy_protocol::PP2_TYPE_SSL, &ssl[..]),
+                (PP2_TYPE_EXPORTER_SECRET, hex.as_bytes()),
+            ];
+
+            // The keys are the same as the ones exported from the session itself.
+            let exporter_secret = ExporterSecret::from_tlvs(&tlvs).unwrap();
+            let aeads = [
+                KnownAeadAlgorithm::AeadAesSivCmac256,
+                KnownAeadAlgorithm::AeadAes128GcmSiv,
+            ];
+            for aead in aeads.iter() {
+                let expected = gen_key(&session, *aead).unwrap();
+                let keys = exporter_secret.export_keys(*aead);
+                assert_eq!(keys.c2s[..], expected.c2s[..]);
+                assert_eq!(keys.s2c[..], expected.s2c[..]);
+            }
+
+            // The secret must have the length of the hash of the cipher suite.
+            let short = (PP2_TYPE_EXPORTER_SECRET, &hex.as_bytes()[2..]);
+            assert!(ExporterSecret::from_tlvs(&[tlvs[0], short]).is_err());
+            assert!(ExporterSecret::from_tlvs(&tlvs[..1]).is_err());
+            assert!(ExporterSecret::from_tlvs(&tlvs[1..]).is_err());
+        }
+
+        // Only the TLS 1.3 cipher suites have exporter secrets.
+        let mut ssl = vec![0x01, 0, 0, 0, 0, proxy_protocol::PP2_SUBTYPE_SSL_CIPHER, 0, 27];
+        ssl.extend_from_slice(b"ECDHE-RSA-AES128-GCM-SHA256");
+        let secret = [b'0'; 64];
+        let tlvs = [(proxy_protocol::PP2_TYPE_SSL, &ssl[..]), (PP2_TYPE_EXPORTER_SECRET, &secret)];
+        assert!(ExporterSecret::from_tlvs(&tlvs).is_err());
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex(b"00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex(b""), Some(Vec::new()));
+        assert_eq!(decode_hex(b"0"), None);
+        assert_eq!(decode_hex(b"0g"), None);
+        assert_eq!(decode_hex(b"+1"), None);
+    }
 }
@@ -4,23 +4,32 @@
 
 //! NTS-KE server listener.
 
-use mio::net::TcpListener;
+use lazy_static::lazy_static;
 
-use slog::{error, info};
+use prometheus::{
+    opts, register_counter, register_int_counter, register_int_gauge, IntCounter, IntGauge,
+    __register_gauge,
+};
+
+use slog::{debug, error, info, warn};
 
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
-use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::cfsock;
+use crate::signal;
 
+use super::config::{KeListenAddr, KeSocketAddr};
 use super::connection::KeServerConn;
 use super::connection::KeServerConnState;
+use super::server::AccessRecord;
 use super::server::KeServer;
 use super::server::KeServerState;
+use super::socket::{KeListenSocket, KeStream};
 
 const LISTENER_MIO_TOKEN_ID: usize = 0;
 const CONNECTION_MIO_TOKEN_ID_MIN: usize = LISTENER_MIO_TOKEN_ID + 1;
@@ -30,14 +39,40 @@ const CONNECTION_MIO_TOKEN_ID_MAX: usize = usize::max_value() - 1;
 /// The token used to associate the mio event with the lister event.
 const LISTENER_MIO_TOKEN: mio::Token = mio::Token(LISTENER_MIO_TOKEN_ID);
 
+/// How often a listener that is not accepting connections checks the connection limit again.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a listener stops accepting connections after it runs out of file descriptors or
+/// memory.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How often a listener checks if the server is asked to shut down.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref OPEN_CONNECTIONS_GAUGE: IntGauge = register_int_gauge!(
+        "nts_ke_open_connections",
+        "Number of open NTS-KE connections"
+    ).unwrap();
+    static ref HANDSHAKE_TIMEOUT_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_handshake_timeouts_total",
+        "Number of connections closed before finishing the TLS handshake in time"
+    ).unwrap();
+    static ref IDLE_TIMEOUT_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_idle_timeouts_total",
+        "Number of connections closed after being idle for too long"
+    ).unwrap();
+}
+
 /// NTS-KE server internal listener for a specific listened address.
-/// One listener will correspond to one kernel listening socket.
+/// One listener will correspond to one kernel listening socket, which is a TCP socket or a Unix
+/// domain socket.
 pub struct KeServerListener {
     /// Reference back to the corresponding `KeServer` state.
     state: Arc<KeServerState>,
 
-    /// TCP listener for incoming connections.
-    tcp_listener: TcpListener,
+    /// Listening socket for incoming connections.
+    socket: KeListenSocket,
 
     /// List of connections accepted by this listener.
     connections: HashMap<mio::Token, KeServerConn>,
@@ -49,8 +84,25 @@ pub struct KeServerListener {
     /// The next mio token id for a new connection.
     next_conn_token_id: usize,
 
-    /// Address and port that this listener will listen to.
-    addr: SocketAddr,
+    /// Whether the listening socket is being polled for new connections.
+    accepting: bool,
+
+    /// Whether the server was ready to serve the clients the last time that it was checked.
+    ready: bool,
+
+    /// The time until which the listener doesn't accept connections, because the last accept
+    /// failed for the lack of resources.
+    accept_backoff: Option<Instant>,
+
+    /// The time that the remaining connections will be closed forcibly, if the listener is
+    /// shutting down.
+    drain_deadline: Option<Instant>,
+
+    /// Whether the connections start with a PROXY protocol header.
+    proxy_protocol: bool,
+
+    /// Whether TLS is terminated by the load balancer.
+    plaintext: bool,
 
     /// Polling object from mio.
     poll: mio::Poll,
@@ -65,19 +117,28 @@ impl KeServerListener {
     /// # Errors
     ///
     /// All the errors here are from the kernel which we don't have to know about for now.
-    pub fn bind(addr: SocketAddr, server: &KeServer) -> Result<KeServerListener, std::io::Error> {
+    pub fn bind(listen_addr: KeListenAddr, server: &KeServer)
+        -> Result<KeServerListener, std::io::Error>
+    {
         let state = server.state();
         let poll = mio::Poll::new()?;
 
-        // Create a listening std tcp listener.
-        let std_tcp_listener = cfsock::tcp_listener(&addr)?;
+        let socket = match &listen_addr.addr {
+            KeSocketAddr::Tcp(addr) => {
+                // Create a listening std tcp listener. If there are several workers for each
+                // address, they all bind to the same address with their own sockets.
+                let reuse_port = state.config.accept_workers > 1;
+                let std_tcp_listener = cfsock::tcp_listener(addr, reuse_port, listen_addr.v6only)?;
 
-        // Transform a std tcp listener to a mio tcp listener.
-        let mio_tcp_listener = TcpListener::from_std(std_tcp_listener)?;
+                // Transform a std tcp listener to a mio tcp listener.
+                KeListenSocket::Tcp(mio::net::TcpListener::from_std(std_tcp_listener)?)
+            },
+            KeSocketAddr::Unix(path) => KeListenSocket::bind_unix(path)?,
+        };
 
         // Register for the event that the listener is readable.
         poll.register(
-            &mio_tcp_listener,
+            &socket,
             LISTENER_MIO_TOKEN,
             mio::Ready::readable(),
             mio::PollOpt::level(),
@@ -86,11 +147,16 @@ impl KeServerListener {
         Ok(KeServerListener {
             // Create an `Arc` reference.
             state: state.clone(),
-            tcp_listener: mio_tcp_listener,
+            socket,
             connections: HashMap::new(),
             deadlines: BinaryHeap::new(),
             next_conn_token_id: CONNECTION_MIO_TOKEN_ID_MIN,
-            addr,
+            accepting: true,
+            ready: true,
+            accept_backoff: None,
+            drain_deadline: None,
+            proxy_protocol: listen_addr.proxy_protocol,
+            plaintext: listen_addr.plaintext,
             // In the future, we may want to use the child logger instead the logger itself.
             logger: state.config.logger().clone(),
             poll,
@@ -102,9 +168,52 @@ impl KeServerListener {
         // Holding up to 2048 events.
         let mut events = mio::Events::with_capacity(2048);
 
+        // Don't accept any connection until the server is ready.
+        self.update_accepting()?;
+
         loop {
+            // Stop accepting new connections when the server is asked to shut down, but let the
+            // open connections finish their exchanges.
+            if self.drain_deadline.is_none() && signal::sigterm_received() {
+                self.start_draining()?;
+            }
+
+            if let Some(deadline) = self.drain_deadline {
+                if self.connections.is_empty() {
+                    info!(self.logger, "all connections are drained");
+                    return Ok(());
+                }
+
+                if Instant::now() >= deadline {
+                    warn!(self.logger, "drain timeout; closing {} connections",
+                          self.connections.len());
+                    let tokens: Vec<mio::Token> = self.connections.keys().cloned().collect();
+                    for token in tokens {
+                        if let Some(mut connection) = self.remove_connection(token) {
+                            connection.set_result("drain_timeout");
+                            connection.shutdown();
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+
+            // Wake up periodically to check if the server is asked to shut down. While we are not
+            // accepting connections, we also have to check if the other listeners have closed
+            // some connections, so we wake up more often.
+            let timeout = if self.accepting {
+                SIGNAL_CHECK_INTERVAL
+            } else {
+                ACCEPT_RETRY_INTERVAL
+            };
+
             // The error returned here is from the kernel select.
-            self.poll.poll(&mut events, None)?;
+            match self.poll.poll(&mut events, Some(timeout)) {
+                Ok(_) => (),
+                // The poll can be interrupted by a signal, which is not an actual error.
+                Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
 
             for event in events.iter() {
                 // Close all expired connections.
@@ -113,6 +222,14 @@ impl KeServerListener {
 
                 // If the event is the listener event.
                 if token == LISTENER_MIO_TOKEN {
+                    // Don't accept a new connection, if there are too many connections already.
+                    // The listener will be deregistered right after this loop.
+                    if self.at_connection_limit() || !self.ready || self.accept_backoff.is_some()
+                        || self.drain_deadline.is_some()
+                    {
+                        continue;
+                    }
+
                     // Start accepting a new connection.
                     if let Err(error) = self.accept() {
                         error!(self.logger, "accept failed unrecoverably with error: {}", error);
@@ -125,20 +242,123 @@ impl KeServerListener {
                 // The connection associated with the token may not exist for some reason. In which
                 // case, we just ignore it.
                 if let Some(connection) = self.connections.get_mut(&token) {
+                    let old_deadline = connection.deadline();
                     connection.ready(&mut self.poll, &event);
 
                     if connection.state() == KeServerConnState::Closed {
-                        self.connections.remove(&token);
+                        self.remove_connection(token);
+                    } else if connection.deadline() != old_deadline {
+                        // The old entry in the heap becomes stale and will be skipped.
+                        if let Some(deadline) = connection.deadline() {
+                            self.deadlines.push(Reverse((deadline, token)));
+                        }
                     }
                 }
             }
+
+            // The connections may be closed in this listener or in other listeners, so the
+            // connection count has to be checked after every poll.
+            self.close_expired_connections();
+            if self.drain_deadline.is_none() {
+                self.update_accepting()?;
+            }
         }
     }
 
+    /// Stop polling the listening socket for good and give the open connections some time to
+    /// finish.
+    fn start_draining(&mut self) -> Result<(), std::io::Error> {
+        info!(self.logger, "shutting down; draining {} connections", self.connections.len());
+
+        if self.accepting {
+            self.poll.deregister(&self.socket)?;
+            self.accepting = false;
+        }
+
+        let drain_timeout = Duration::from_secs(self.state.config.drain_timeout);
+        self.drain_deadline = Some(Instant::now() + drain_timeout);
+
+        Ok(())
+    }
+
+    /// Return true if the number of open connections of the server reaches the limit.
+    fn at_connection_limit(&self) -> bool {
+        match self.state.config.max_connections {
+            Some(max_connections) => {
+                self.state.open_connections.load(Ordering::SeqCst) >= max_connections
+            },
+            None => false,
+        }
+    }
+
+    /// Stop polling the listening socket when the server has too many open connections, it's
+    /// not ready to serve the clients, or the last accept failed for the lack of resources, and
+    /// start polling it again when some of the connections are closed, it gets ready, or the
+    /// backoff is over. While we don't poll it, the new connections wait in the kernel backlog.
+    fn update_accepting(&mut self) -> Result<(), std::io::Error> {
+        let at_limit = self.at_connection_limit();
+        if self.accept_backoff.map_or(false, |backoff| Instant::now() >= backoff) {
+            self.accept_backoff = None;
+        }
+        match self.state.readiness() {
+            Err(reason) if self.ready => {
+                warn!(self.logger, "not ready: {}; pausing accepting new connections", reason);
+                self.ready = false;
+            },
+            Ok(()) if !self.ready => {
+                info!(self.logger, "ready to serve the clients");
+                self.ready = true;
+            },
+            _ => (),
+        }
+        let pause = at_limit || !self.ready || self.accept_backoff.is_some();
+
+        if self.accepting && pause {
+            if at_limit {
+                warn!(self.logger, "connection limit reached; pausing accepting new connections");
+            }
+            self.poll.deregister(&self.socket)?;
+            self.accepting = false;
+        } else if !self.accepting && !pause {
+            info!(self.logger, "resuming accepting new connections");
+            self.poll.register(
+                &self.socket,
+                LISTENER_MIO_TOKEN,
+                mio::Ready::readable(),
+                mio::PollOpt::level(),
+            )?;
+            self.accepting = true;
+        }
+
+        Ok(())
+    }
+
+    /// Add a new connection and count it as an open connection of the server.
+    fn add_connection(&mut self, token: mio::Token, connection: KeServerConn) {
+        if let Some(mut old_connection) = self.connections.insert(token, connection) {
+            // It should be rare that the token ids wrap around while the old connection is still
+            // there. But if it happens, the old connection is no longer reachable, so close it.
+            old_connection.shutdown();
+        } else {
+            self.state.open_connections.fetch_add(1, Ordering::SeqCst);
+            OPEN_CONNECTIONS_GAUGE.inc();
+        }
+    }
+
+    /// Remove a connection and stop counting it as an open connection of the server.
+    fn remove_connection(&mut self, token: mio::Token) -> Option<KeServerConn> {
+        let connection = self.connections.remove(&token);
+        if connection.is_some() {
+            self.state.open_connections.fetch_sub(1, Ordering::SeqCst);
+            OPEN_CONNECTIONS_GAUGE.dec();
+        }
+        connection
+    }
+
     /// Accepting a new connection. This will not block the thread, if it's called after receiving
     /// the `LISTENER_MIO_TOKEN` event. But it will block, if it's not.
     fn accept(&mut self) -> Result<(), std::io::Error> {
-        let (tcp_stream, addr) = match self.tcp_listener.accept() {
+        let (stream, addr) = match self.socket.accept() {
             Ok(value) => value,
             Err(error) => {
                 // If it's WouldBlock, just treat it like a success because there isn't an actual
@@ -150,44 +370,82 @@ impl KeServerListener {
                 // If it's not WouldBlock, it's an error.
                 error!(self.logger, "encountered error while accepting connection; err={}", error);
 
-                // TODO: I don't understand why we need another tcp listener and register a new
-                // event here. I will figure it out after I finish refactoring everything.
-                self.tcp_listener = TcpListener::bind(&self.addr)?;
-                // TODO: Ignore error first. I wil figure out what to do later if there is an
-                // error.
-                self.poll.register(
-                    &self.tcp_listener,
-                    LISTENER_MIO_TOKEN,
-                    mio::Ready::readable(),
-                    mio::PollOpt::level(),
-                )?;
-
-                // TODO: I will figure why it returns Ok later.
+                // The listening socket is still fine, and it may have been bound with
+                // SO_REUSEPORT or passed by systemd, so we keep it. But if we are out of file
+                // descriptors or memory, the pending connection keeps the socket readable and
+                // accepting again right away would only spin, so we back off for a while.
+                match error.raw_os_error() {
+                    Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS)
+                    | Some(libc::ENOMEM) => {
+                        warn!(self.logger, "pausing accepting new connections for {:?}",
+                              ACCEPT_BACKOFF);
+                        self.accept_backoff = Some(Instant::now() + ACCEPT_BACKOFF);
+                    },
+                    // The other errors, for example, ECONNABORTED, are about the connection that
+                    // is being accepted, so the next one can be accepted right away.
+                    _ => (),
+                }
+
                 return Ok(());
             },
         };
 
         // Successfully accepting a connection.
 
+        // Drop the connection immediately if the client is not allowed to connect or has made
+        // too many connections recently. Behind a load balancer, the client address is only
+        // known after reading the PROXY header, so the connection will check it by itself.
+        if !self.proxy_protocol {
+            if !self.state.check_access(addr.ip()) {
+                debug!(self.logger, "denied connection from {}", addr);
+                self.state.log_access(&AccessRecord::rejected(addr.ip(), "denied"));
+                return Ok(());
+            }
+            if !self.state.check_rate_limit(addr.ip()) {
+                debug!(self.logger, "rate limited connection from {}", addr);
+                self.state.log_access(&AccessRecord::rejected(addr.ip(), "rate_limited"));
+                return Ok(());
+            }
+        }
+
         info!(self.logger, "accepting new connection from {}", addr);
 
+        // The half-dead connections, for example, from the clients behind NATs that forgot about
+        // them, are dropped by the kernel. It doesn't matter much if the options can't be set
+        // because the connection timeouts still apply.
+        if let KeStream::Tcp(tcp_stream) = &stream {
+            if let Some(keepalive) = self.state.config.tcp_keepalive {
+                if let Err(error) = cfsock::set_tcp_keepalive(
+                    tcp_stream,
+                    keepalive.idle,
+                    keepalive.interval,
+                    keepalive.count,
+                ) {
+                    warn!(self.logger, "cannot set TCP keepalive: {}", error);
+                }
+            }
+            if let Some(user_timeout) = self.state.config.tcp_user_timeout {
+                if let Err(error) = cfsock::set_tcp_user_timeout(tcp_stream, user_timeout) {
+                    warn!(self.logger, "cannot set TCP user timeout: {}", error);
+                }
+            }
+        }
+
         let token = mio::Token(self.next_conn_token_id);
         self.increment_next_conn_token_id();
 
-        let timeout_duration = Duration::new(self.state.config.timeout(), 0);
+        // Create a new connection instance.
+        let connection = KeServerConn::new(stream, addr, token, self);
 
         // If the timeout is so large that we cannot put it in SystemTime, we can assume that
         // it doesn't have a timeout and just don't add it into the map.
-        if let Some(timeout_systime) = SystemTime::now().checked_add(timeout_duration) {
-            self.deadlines.push(Reverse((timeout_systime, token)));
+        if let Some(deadline) = connection.deadline() {
+            self.deadlines.push(Reverse((deadline, token)));
         }
-
-        // Create a new connection instance.
-        let connection = KeServerConn::new(tcp_stream, token, &self);
         // TODO: Fix the unwrap later.
         connection.register(&mut self.poll).unwrap();
 
-        self.connections.insert(token, connection);
+        self.add_connection(token, connection);
 
         Ok(())
     }
@@ -218,13 +476,28 @@ impl KeServerListener {
                 // If the deadline is already elapsed, close the connection and pop the heap.
                 // The connection associated with the token may not exist because, when we close
                 // the connection, it's not possible to find an entry in the heap. In which case,
-                // we can just pop the deadline heap.
-                if let Some(mut connection) = self.connections.remove(&token) {
-                    error!(self.logger, "forcible shutdown after timeout");
-                    connection.shutdown();
-                }
+                // we can just pop the deadline heap. The connection may also have a newer
+                // deadline, which has its own entry in the heap, so this entry is stale.
+                let (deadline, token) = (*deadline, *token);
                 self.deadlines.pop();
 
+                let expired = self.connections.get(&token)
+                    .map_or(false, |connection| connection.deadline() == Some(deadline));
+                if expired {
+                    if let Some(mut connection) = self.remove_connection(token) {
+                        if connection.is_handshake_done() {
+                            IDLE_TIMEOUT_COUNTER.inc();
+                            error!(self.logger, "forcible shutdown after idle timeout");
+                            connection.set_result("idle_timeout");
+                        } else {
+                            HANDSHAKE_TIMEOUT_COUNTER.inc();
+                            error!(self.logger, "forcible shutdown after handshake timeout");
+                            connection.set_result("handshake_timeout");
+                        }
+                        connection.shutdown();
+                    }
+                }
+
                 // In this case, this means that there may be more elapsed deadline. Continue the
                 // loop.
             } else {
@@ -245,8 +518,161 @@ impl KeServerListener {
         &self.logger
     }
 
-    /// Return the address-port of this listener.
-    pub(super) fn addr(&self) -> &SocketAddr {
-        &self.addr
+    /// Return true if the connections start with a PROXY protocol header.
+    pub(super) fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Return true if TLS is terminated by the load balancer.
+    pub(super) fn plaintext(&self) -> bool {
+        self.plaintext
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+
+    use sloggers::Build;
+    use sloggers::null::NullLoggerBuilder;
+
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+
+    use crate::cookie::CookieKey;
+    use crate::key_rotator::RotateError;
+    use crate::key_store::{KeyStore, KeyStoreConfig};
+
+    use super::super::config::KeServerConfig;
+
+    // A key store that has a key for every period.
+    struct MockStore;
+
+    impl KeyStore for MockStore {
+        fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+            Ok(epochs.iter().map(|epoch| Some(epoch.to_be_bytes().to_vec())).collect())
+        }
+
+        fn health(&self) -> Result<(), RotateError> {
+            Ok(())
+        }
+    }
+
+    fn config() -> KeServerConfig {
+        let key_store = KeyStoreConfig::Memcached {
+            urls: vec![String::from("memcache://localhost:11211")],
+            prefix: String::from("/nts/nts-keys"),
+            tls: None,
+            credentials: None,
+        };
+        let cookie_key = CookieKey::from(vec![0; 32]);
+        let mut config = KeServerConfig::new(30, cookie_key, key_store, None, 123);
+        config.set_logger(NullLoggerBuilder.build().unwrap());
+        config.tls_certs = certs(&mut &include_bytes!("../../../tests/chain.pem")[..]).unwrap();
+        config.tls_secret_keys =
+            pkcs8_private_keys(&mut &include_bytes!("../../../tests/tls-pkcs8.pem")[..])
+                .unwrap();
+        config
+    }
+
+    /// Bind a listener of a new server to a Unix domain socket named after `name`.
+    fn listener(config: KeServerConfig, name: &str) -> (KeServerListener, PathBuf) {
+        let server = KeServer::connect(config, Box::new(MockStore)).unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("cfnts-test-{}-{}.sock", std::process::id(), name));
+        let listen_addr = KeListenAddr::new(KeSocketAddr::Unix(path.clone()));
+        (KeServerListener::bind(listen_addr, &server).unwrap(), path)
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let mut config = config();
+        config.max_connections = Some(1);
+        let (mut listener, path) = listener(config, "limit");
+        listener.update_accepting().unwrap();
+        assert!(listener.accepting);
+
+        let _client = UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        assert_eq!(listener.connections.len(), 1);
+        assert_eq!(listener.state.open_connections.load(Ordering::SeqCst), 1);
+
+        // The listener stops accepting at the limit.
+        assert!(listener.at_connection_limit());
+        listener.update_accepting().unwrap();
+        assert!(!listener.accepting);
+
+        // And it resumes when a connection is closed.
+        let token = *listener.connections.keys().next().unwrap();
+        assert!(listener.remove_connection(token).is_some());
+        assert_eq!(listener.state.open_connections.load(Ordering::SeqCst), 0);
+        listener.update_accepting().unwrap();
+        assert!(listener.accepting);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut config = config();
+        config.drain_timeout = 5;
+        let (mut listener, path) = listener(config, "drain");
+
+        // The open connections can finish after the listener stops accepting.
+        let client = UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        listener.start_draining().unwrap();
+        assert!(!listener.accepting);
+        assert!(listener.drain_deadline.is_some());
+
+        let start = Instant::now();
+        drop(client);
+        listener.listen().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(listener.connections.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        let mut config = config();
+        config.drain_timeout = 0;
+        let (mut listener, path) = listener(config, "drain-timeout");
+
+        // The connections that don't finish in time are closed.
+        let _client = UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        listener.start_draining().unwrap();
+        listener.listen().unwrap();
+        assert!(listener.connections.is_empty());
+        assert_eq!(listener.state.open_connections.load(Ordering::SeqCst), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_accept_backoff() {
+        let (mut listener, path) = listener(config(), "backoff");
+        listener.update_accepting().unwrap();
+        assert!(listener.accepting);
+
+        // The listener keeps its socket, but stops accepting for a while.
+        listener.accept_backoff = Some(Instant::now() + ACCEPT_BACKOFF);
+        listener.update_accepting().unwrap();
+        assert!(!listener.accepting);
+
+        std::thread::sleep(ACCEPT_BACKOFF);
+        listener.update_accepting().unwrap();
+        assert!(listener.accepting);
+        assert!(listener.accept_backoff.is_none());
+
+        let _client = UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        assert_eq!(listener.connections.len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
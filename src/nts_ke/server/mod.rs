@@ -4,10 +4,16 @@
 
 //! NTS-KE server implementation.
 
+mod cert_resolver;
+mod client_verifier;
 mod config;
 mod connection;
 mod listener;
+mod next_server;
+mod proxy_protocol;
 mod server;
+mod socket;
+mod ticketer;
 
 // We expose only two structs: KeServer and KeServerConfig. KeServer is used to run an instant of
 // the NTS-KE server and KeServerConfig is used to instantiate KeServer.
@@ -0,0 +1,144 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Selection of the NTP server advertised to each NTS-KE client.
+
+use lazy_static::lazy_static;
+
+use prometheus::{opts, register_int_counter_vec, IntCounterVec, __register_counter_vec};
+
+use rand::Rng;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+lazy_static! {
+    static ref NEXT_SERVER_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_next_server_total",
+        "Number of NTS-KE responses advertising each NTP server",
+        &["endpoint"]
+    ).unwrap();
+}
+
+/// An NTP server that can be advertised to the clients.
+#[derive(Clone, Debug)]
+pub struct NtpEndpoint {
+    /// The hostname or IP address of the server. If it's `None`, the clients use the NTP server
+    /// on the same host as the NTS-KE server.
+    pub server: Option<String>,
+
+    /// The UDP port of the server.
+    pub port: u16,
+
+    /// The relative share of the clients that this server gets.
+    pub weight: u32,
+}
+
+impl NtpEndpoint {
+    /// Return the name of the endpoint used in the metrics.
+    fn label(&self) -> String {
+        format!("{}:{}", self.server.as_ref().map_or("local", String::as_str), self.port)
+    }
+}
+
+/// How an endpoint is chosen for each NTS-KE exchange.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NextServerSelection {
+    /// Go through the endpoints in order. An endpoint is chosen `weight` times in a row.
+    RoundRobin,
+
+    /// Choose an endpoint at random with a probability proportional to its weight.
+    Random,
+}
+
+/// Chooses the NTP server advertised in each NTS-KE response.
+pub struct NextServerSelector {
+    /// The endpoints to choose from. It's never empty.
+    endpoints: Vec<NtpEndpoint>,
+
+    /// The sum of the weights of all the endpoints.
+    total_weight: u64,
+
+    /// The selection policy.
+    selection: NextServerSelection,
+
+    /// The number of endpoints chosen so far, used by the round robin.
+    counter: AtomicUsize,
+}
+
+impl NextServerSelector {
+    /// Create a new selector.
+    ///
+    /// # Panics
+    ///
+    /// If there is no endpoint, or an endpoint has zero weight.
+    ///
+    pub fn new(endpoints: Vec<NtpEndpoint>, selection: NextServerSelection)
+        -> NextServerSelector
+    {
+        assert!(!endpoints.is_empty(), "there must be at least one NTP endpoint");
+        assert!(endpoints.iter().all(|endpoint| endpoint.weight > 0),
+                "the weights of the NTP endpoints must be positive");
+
+        let total_weight = endpoints.iter().map(|endpoint| u64::from(endpoint.weight)).sum();
+        NextServerSelector {
+            endpoints,
+            total_weight,
+            selection,
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    /// Choose the endpoint for a new NTS-KE response.
+    pub fn select(&self) -> &NtpEndpoint {
+        let position = match self.selection {
+            NextServerSelection::RoundRobin => {
+                self.counter.fetch_add(1, Ordering::Relaxed) as u64 % self.total_weight
+            },
+            NextServerSelection::Random => rand::thread_rng().gen_range(0, self.total_weight),
+        };
+
+        let endpoint = self.endpoint_at(position);
+        NEXT_SERVER_COUNTER.with_label_values(&[&endpoint.label()]).inc();
+        endpoint
+    }
+
+    /// Return the endpoint that covers `position` when each endpoint covers `weight` positions.
+    fn endpoint_at(&self, mut position: u64) -> &NtpEndpoint {
+        for endpoint in self.endpoints.iter() {
+            let weight = u64::from(endpoint.weight);
+            if position < weight {
+                return endpoint;
+            }
+            position -= weight;
+        }
+        unreachable!("the position must be less than the total weight")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(server: &str, weight: u32) -> NtpEndpoint {
+        NtpEndpoint {
+            server: Some(String::from(server)),
+            port: 123,
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let selector = NextServerSelector::new(
+            vec![endpoint("a.example.com", 2), endpoint("b.example.com", 1)],
+            NextServerSelection::RoundRobin,
+        );
+
+        let chosen: Vec<&str> = (0..6)
+            .map(|_| selector.select().server.as_ref().map(String::as_str).unwrap())
+            .collect();
+        assert_eq!(chosen, vec!["a.example.com", "a.example.com", "b.example.com",
+                                "a.example.com", "a.example.com", "b.example.com"]);
+    }
+}
@@ -0,0 +1,323 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! PROXY protocol version 1 and 2 header parsing.
+//!
+//! A load balancer sends this header before any other data on the connection to tell us the
+//! address of the actual client. See https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The signature of a version 1 header.
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+
+/// The maximum length of a version 1 header including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// The signature of a version 2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The length of a version 2 header before the addresses.
+const V2_FIXED_LEN: usize = 16;
+
+/// The type of the TLV that describes the TLS session of the client.
+pub const PP2_TYPE_SSL: u8 = 0x20;
+
+/// The type of the TLV in `PP2_TYPE_SSL` with the name of the cipher suite, for example,
+/// `TLS_AES_128_GCM_SHA256`.
+pub const PP2_SUBTYPE_SSL_CIPHER: u8 = 0x23;
+
+/// The length of the client flags and the verify result before the TLVs in `PP2_TYPE_SSL`.
+const SSL_FIXED_LEN: usize = 5;
+
+/// A parsed PROXY protocol header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProxyHeader {
+    /// The address of the client. It's `None` if the load balancer doesn't know it, for example,
+    /// when the connection is its own health check.
+    pub source: Option<SocketAddr>,
+
+    /// The length of the header. The data after it belongs to the proxied connection.
+    pub len: usize,
+}
+
+/// Error returned when the data is not a valid PROXY protocol header.
+#[derive(Clone, Debug)]
+pub struct ProxyHeaderError(String);
+
+impl fmt::Display for ProxyHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid PROXY header: {}", self.0)
+    }
+}
+
+impl std::error::Error for ProxyHeaderError {}
+
+fn error(message: &str) -> ProxyHeaderError {
+    ProxyHeaderError(String::from(message))
+}
+
+/// Return true if `buf` and `signature` agree on their common prefix.
+fn starts_like(buf: &[u8], signature: &[u8]) -> bool {
+    let len = buf.len().min(signature.len());
+    buf[..len] == signature[..len]
+}
+
+/// Parse a PROXY protocol header at the beginning of `buf`. Return `None` if more data is needed
+/// to finish the header.
+///
+/// # Errors
+///
+/// There will be an error if `buf` doesn't start with a valid version 1 or 2 header.
+///
+pub fn parse_header(buf: &[u8]) -> Result<Option<ProxyHeader>, ProxyHeaderError> {
+    if starts_like(buf, V2_SIGNATURE) {
+        if buf.len() < V2_SIGNATURE.len() {
+            return Ok(None);
+        }
+        parse_v2(buf)
+    } else if starts_like(buf, V1_SIGNATURE) {
+        if buf.len() < V1_SIGNATURE.len() {
+            return Ok(None);
+        }
+        parse_v1(buf)
+    } else {
+        Err(error("unknown signature"))
+    }
+}
+
+/// Return the type-length-value fields after the addresses of a version 2 header, as the type and
+/// the value of each field. `buf` must start with a complete header returned by `parse_header`. A
+/// version 1 header has no fields.
+///
+/// # Errors
+///
+/// There will be an error if a field is truncated.
+///
+pub fn parse_tlvs<'a>(buf: &'a [u8], header: &ProxyHeader)
+    -> Result<Vec<(u8, &'a [u8])>, ProxyHeaderError>
+{
+    if !buf.starts_with(V2_SIGNATURE) {
+        return Ok(Vec::new());
+    }
+
+    // The length of the addresses depends on the family.
+    let addrs_len = match buf[13] >> 4 {
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 216,
+        _ => 0,
+    };
+    split_tlvs(buf[..header.len].get(V2_FIXED_LEN + addrs_len..).unwrap_or(&[]))
+}
+
+/// Return the TLVs inside the value of a `PP2_TYPE_SSL` field, as the type and the value of each
+/// field.
+///
+/// # Errors
+///
+/// There will be an error if the value or a field is truncated.
+///
+pub fn parse_ssl_tlvs(value: &[u8]) -> Result<Vec<(u8, &[u8])>, ProxyHeaderError> {
+    match value.get(SSL_FIXED_LEN..) {
+        Some(rest) => split_tlvs(rest),
+        None => Err(error("the SSL TLV is truncated")),
+    }
+}
+
+/// Split `rest` into type-length-value fields.
+fn split_tlvs(mut rest: &[u8]) -> Result<Vec<(u8, &[u8])>, ProxyHeaderError> {
+    let mut tlvs = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            return Err(error("a TLV is truncated"));
+        }
+        let len = usize::from(u16::from_be_bytes([rest[1], rest[2]]));
+        let value = rest.get(3..3 + len).ok_or_else(|| error("a TLV is truncated"))?;
+        tlvs.push((rest[0], value));
+        rest = &rest[3 + len..];
+    }
+
+    Ok(tlvs)
+}
+
+/// Parse a version 1 header, for example, `PROXY TCP4 192.0.2.1 198.51.100.1 56324 4460\r\n`.
+fn parse_v1(buf: &[u8]) -> Result<Option<ProxyHeader>, ProxyHeaderError> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(error("the header is too long")),
+    };
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        return Err(error("the header is too long"));
+    }
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| error("the header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    // The addresses after UNKNOWN must be ignored.
+    if fields.get(1) == Some(&"UNKNOWN") {
+        return Ok(Some(ProxyHeader { source: None, len }));
+    }
+
+    let (protocol, source_ip, source_port) = match fields.as_slice() {
+        [_, protocol, source_ip, _, source_port, _] => (*protocol, *source_ip, *source_port),
+        _ => return Err(error("wrong number of fields")),
+    };
+
+    let source_ip: IpAddr = source_ip.parse().map_err(|_| error("invalid source address"))?;
+    let source_port: u16 = source_port.parse().map_err(|_| error("invalid source port"))?;
+    match (protocol, source_ip) {
+        ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => (),
+        _ => return Err(error("the protocol doesn't match the address")),
+    }
+
+    Ok(Some(ProxyHeader {
+        source: Some(SocketAddr::new(source_ip, source_port)),
+        len,
+    }))
+}
+
+/// Parse a version 2 header. The TLVs after the addresses are skipped.
+fn parse_v2(buf: &[u8]) -> Result<Option<ProxyHeader>, ProxyHeaderError> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(None);
+    }
+
+    let version_command = buf[12];
+    let family_protocol = buf[13];
+    let addrs_len = usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    let len = V2_FIXED_LEN + addrs_len;
+
+    if version_command >> 4 != 2 {
+        return Err(error("unsupported version"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addrs = &buf[V2_FIXED_LEN..len];
+
+    match version_command & 0x0f {
+        // LOCAL: the connection is made by the load balancer itself.
+        0x0 => return Ok(Some(ProxyHeader { source: None, len })),
+        // PROXY: the connection is made on behalf of a client.
+        0x1 => (),
+        _ => return Err(error("unsupported command")),
+    }
+
+    let source = match family_protocol {
+        // TCP over IPv4.
+        0x11 => {
+            if addrs.len() < 12 {
+                return Err(error("the IPv4 addresses are truncated"));
+            }
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addrs[0..4]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+        },
+        // TCP over IPv6.
+        0x21 => {
+            if addrs.len() < 36 {
+                return Err(error("the IPv6 addresses are truncated"));
+            }
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addrs[0..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        },
+        // The other families are not TCP, so we don't know what the source is.
+        _ => None,
+    };
+
+    Ok(Some(ProxyHeader { source, len }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 4460\r\nTLS";
+        let parsed = parse_header(header).unwrap().unwrap();
+        assert_eq!(parsed.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(&header[parsed.len..], b"TLS");
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 443 4460\r\n";
+        let parsed = parse_header(header).unwrap().unwrap();
+        assert_eq!(parsed.source, Some("[2001:db8::1]:443".parse().unwrap()));
+
+        let parsed = parse_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(parsed, ProxyHeader { source: None, len: 15 });
+
+        // Incomplete headers.
+        assert_eq!(parse_header(b"PRO").unwrap(), None);
+        assert_eq!(parse_header(b"PROXY TCP4 192.0.2.1").unwrap(), None);
+
+        // Invalid headers.
+        assert!(parse_header(b"\x16\x03\x01").is_err());
+        assert!(parse_header(b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n").is_err());
+        assert!(parse_header(&[b'P'; 200]).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        header.extend_from_slice(&[0xdc, 0x04, 0x11, 0x6c]);
+
+        // Every prefix of the header is incomplete.
+        for len in 0..header.len() {
+            assert_eq!(parse_header(&header[..len]).unwrap(), None);
+        }
+
+        let parsed = parse_header(&header).unwrap().unwrap();
+        assert_eq!(parsed.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(parsed.len, 28);
+
+        // A LOCAL command has no source address.
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(parse_header(&local).unwrap(), Some(ProxyHeader { source: None, len: 16 }));
+
+        // The TLVs after the addresses.
+        let mut tlvs = header.clone();
+        tlvs[15] += 8;
+        tlvs.extend_from_slice(&[0xe0, 0, 2, 0xaa, 0xbb, 0x04, 0, 0]);
+        let parsed = parse_header(&tlvs).unwrap().unwrap();
+        assert_eq!(parse_tlvs(&tlvs, &parsed).unwrap(),
+                   vec![(0xe0, &[0xaa, 0xbb][..]), (0x04, &[][..])]);
+        assert!(parse_tlvs(&header, &parse_header(&header).unwrap().unwrap()).unwrap().is_empty());
+
+        let mut truncated = header.clone();
+        truncated[15] += 4;
+        truncated.extend_from_slice(&[0xe0, 0, 2, 0xaa]);
+        let parsed = parse_header(&truncated).unwrap().unwrap();
+        assert!(parse_tlvs(&truncated, &parsed).is_err());
+
+        // Unsupported version.
+        let mut version = V2_SIGNATURE.to_vec();
+        version.extend_from_slice(&[0x31, 0x11, 0, 0]);
+        assert!(parse_header(&version).is_err());
+    }
+
+    #[test]
+    fn test_parse_ssl_tlvs() {
+        let mut ssl = vec![0x01, 0, 0, 0, 0];
+        ssl.extend_from_slice(&[0x21, 0, 7]);
+        ssl.extend_from_slice(b"TLSv1.3");
+        ssl.extend_from_slice(&[0x23, 0, 3]);
+        ssl.extend_from_slice(b"AES");
+        assert_eq!(parse_ssl_tlvs(&ssl).unwrap(),
+                   vec![(0x21, &b"TLSv1.3"[..]), (PP2_SUBTYPE_SSL_CIPHER, &b"AES"[..])]);
+
+        assert!(parse_ssl_tlvs(&ssl[..4]).is_err());
+        assert!(parse_ssl_tlvs(&ssl[..ssl.len() - 1]).is_err());
+    }
+}
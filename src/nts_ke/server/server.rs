@@ -4,17 +4,97 @@
 
 //! NTS-KE server instantiation.
 
-use slog::info;
+use lazy_static::lazy_static;
 
-use std::sync::{Arc, RwLock};
+use prometheus::{opts, register_counter, register_int_counter, IntCounter};
 
+use rustls::{Certificate, PrivateKey};
+
+use slog::{error, info};
+
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, Instant};
+
+use crate::admin;
 use crate::key_rotator::KeyRotator;
 use crate::key_rotator::RotateError;
 use crate::key_rotator::periodic_rotate;
+use crate::key_rotator::periodic_reload_master_key;
+use crate::key_rotator::periodic_reload_master_key_aws;
+use crate::key_rotator::periodic_reload_master_key_file;
+use crate::key_store::KeyStore;
 use crate::metrics;
-
-use super::config::KeServerConfig;
+use crate::nts_ke::records::KnownAeadAlgorithm;
+use crate::rate_limiter::RateLimiter;
+use crate::signal;
+
+use super::cert_resolver::KeCertResolver;
+use super::client_verifier::KeClientCertVerifier;
+use super::config::{
+    load_ocsp_response,
+    load_tls_certs,
+    load_tls_secret_keys,
+    ClientAuthConfig,
+    KeServerConfig,
+    KeSocketAddr,
+    SniCert,
+    TlsCertChain,
+};
 use super::listener::KeServerListener;
+use super::next_server::{NextServerSelector, NtpEndpoint};
+use super::ticketer::RotatingTicketer;
+
+/// How often the server checks whether it has received a signal.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref RATE_LIMITED_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_rate_limited_total",
+        "Number of connections dropped by the per-client rate limit"
+    ).unwrap();
+    static ref DENIED_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_denied_total",
+        "Number of connections dropped by the client access lists"
+    ).unwrap();
+}
+
+/// What happened to a connection, which is written to the access log when the connection is
+/// closed.
+pub(super) struct AccessRecord<'a> {
+    /// The address of the client.
+    pub(super) client: IpAddr,
+
+    /// The hostname that the client sent in the SNI extension, if any.
+    pub(super) sni: Option<&'a str>,
+
+    /// The AEAD algorithm of the keys exported for the client, if the request was served.
+    pub(super) aead: Option<KnownAeadAlgorithm>,
+
+    /// The number of cookies sent to the client.
+    pub(super) cookies: usize,
+
+    /// How long the TLS handshake took, if it was done.
+    pub(super) handshake_time: Option<Duration>,
+
+    /// How the exchange ended, for example, `ok` or the name of the Error record sent.
+    pub(super) result: &'a str,
+}
+
+impl<'a> AccessRecord<'a> {
+    /// Create a record for a connection that is dropped before the TLS handshake starts.
+    pub(super) fn rejected(client: IpAddr, result: &'a str) -> AccessRecord<'a> {
+        AccessRecord {
+            client,
+            sni: None,
+            aead: None,
+            cookies: 0,
+            handshake_time: None,
+            result,
+        }
+    }
+}
 
 /// NTS-KE server state that will be shared among listeners.
 pub(super) struct KeServerState {
@@ -31,8 +111,279 @@ pub(super) struct KeServerState {
 
     /// TLS server configuration which will be used among listeners.
     // We use `Arc` here so that every thread can read the config, but the drawback of using `Arc`
-    // is that it uses garbage collection.
-    pub(super) tls_server_config: Arc<rustls::ServerConfig>,
+    // is that it uses garbage collection. The `RwLock` lets us swap in a new config when the
+    // certificates are reloaded. The connections that are already established keep using the old
+    // one.
+    pub(super) tls_server_config: RwLock<Arc<rustls::ServerConfig>>,
+
+    /// Per-client connection rate limiter shared among listeners, if it's enabled.
+    pub(super) rate_limiter: Option<Mutex<RateLimiter>>,
+
+    /// The number of open connections among all listeners.
+    pub(super) open_connections: AtomicUsize,
+
+    /// Chooses the NTP server advertised in each response.
+    pub(super) next_servers: NextServerSelector,
+}
+
+impl KeServerState {
+    /// Check that the server can serve the clients, which is when the key rotator has a key that
+    /// is not older than the `max_key_age`. Return the reason, if it cannot.
+    pub(super) fn readiness(&self) -> Result<(), String> {
+        let max_key_age = Duration::from_secs(match self.config.max_key_age {
+            Some(max_key_age) => max_key_age,
+            None => self.config.rotation.rotate_every.saturating_mul(2),
+        });
+        match self.rotator.read().unwrap().latest_key_age() {
+            None => Err(String::from("no key yet")),
+            Some(age) if age > max_key_age => {
+                Err(format!("the latest key is {} seconds old", age.as_secs()))
+            },
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Re-read the TLS certificates and private keys from their files and swap in a new TLS
+    /// server configuration.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the certificates or private keys cannot be read or they are
+    /// not usable. In which case, the current TLS server configuration is kept.
+    ///
+    pub(super) fn reload_tls_server_config(&self) -> Result<(), std::io::Error> {
+        let identity = TlsIdentity::reload(&self.config)?;
+
+        let tls_server_config = create_tls_server_config(&self.config, identity, &self.rotator)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+        *self.tls_server_config.write().unwrap() = Arc::new(tls_server_config);
+
+        Ok(())
+    }
+
+    /// Write an entry to the access log, if it's enabled.
+    pub(super) fn log_access(&self, record: &AccessRecord) {
+        let logger = match self.config.access_logger() {
+            Some(logger) => logger,
+            None => return,
+        };
+
+        info!(logger, "access";
+              "client" => record.client.to_string(),
+              "sni" => record.sni.unwrap_or("-"),
+              "aead" => record.aead.map_or("-", |aead| aead.name()),
+              "cookies" => record.cookies,
+              "handshake_ms" => record.handshake_time.map(|time| time.as_millis() as u64),
+              "result" => record.result);
+    }
+
+    /// Return true if `addr` is allowed to connect by the client access lists.
+    pub(super) fn check_access(&self, addr: IpAddr) -> bool {
+        let allowlist = &self.config.client_allowlist;
+        let denylist = &self.config.client_denylist;
+
+        let allowed = (allowlist.is_empty() || allowlist.iter().any(|block| block.contains(&addr)))
+            && !denylist.iter().any(|block| block.contains(&addr));
+        if !allowed {
+            DENIED_COUNTER.inc();
+        }
+        allowed
+    }
+
+    /// Return true if a new connection from `addr` is allowed by the per-client rate limit.
+    pub(super) fn check_rate_limit(&self, addr: IpAddr) -> bool {
+        match &self.rate_limiter {
+            Some(rate_limiter) => {
+                let allowed = rate_limiter.lock().unwrap().check(addr, Instant::now());
+                if !allowed {
+                    RATE_LIMITED_COUNTER.inc();
+                }
+                allowed
+            },
+            None => true,
+        }
+    }
+}
+
+/// Certificate chains and private keys that the TLS server presents to the clients, and the
+/// settings that the client certificates are verified with.
+struct TlsIdentity {
+    /// The default certificate chain.
+    certs: Vec<Certificate>,
+
+    /// The private key corresponding to the default certificate chain.
+    secret_key: PrivateKey,
+
+    /// The OCSP response for the default certificate chain, if any.
+    ocsp: Option<Vec<u8>>,
+
+    /// The alternative to the default certificate chain, if any.
+    alt_cert: Option<TlsCertChain>,
+
+    /// The certificate chains selected by SNI.
+    sni_certs: Vec<SniCert>,
+
+    /// The TLS client authentication settings, if it's enabled.
+    client_auth: Option<ClientAuthConfig>,
+}
+
+impl TlsIdentity {
+    /// Take the certificate chains and private keys that were already loaded in the config.
+    fn from_config(config: &KeServerConfig) -> TlsIdentity {
+        TlsIdentity {
+            // rustls::ServerConfig wants to own all of them.
+            certs: config.tls_certs.clone(),
+            secret_key: config.tls_secret_keys[0].clone(),
+            ocsp: config.tls_ocsp.clone(),
+            alt_cert: config.tls_alt_cert.clone(),
+            sni_certs: config.sni_certs.clone(),
+            client_auth: config.client_auth.clone(),
+        }
+    }
+
+    /// Load the certificate chains, private keys, and client authentication settings again from
+    /// the files in the config.
+    fn reload(config: &KeServerConfig) -> Result<TlsIdentity, std::io::Error> {
+        let certs_filename = config.tls_certs_filename().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no TLS certificate file")
+        })?;
+        let secret_keys_filename = config.tls_secret_keys_filename().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no TLS private key file")
+        })?;
+
+        let certs = load_tls_certs(certs_filename)?;
+        let secret_key = match load_tls_secret_keys(secret_keys_filename)?.into_iter().next() {
+            Some(secret_key) => secret_key,
+            None => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("no TLS private key found in {}", secret_keys_filename),
+            )),
+        };
+
+        let ocsp = match config.tls_ocsp_filename() {
+            Some(filename) => load_ocsp_response(filename)?,
+            None => None,
+        };
+
+        let alt_cert = match &config.tls_alt_cert {
+            Some(chain) => Some(chain.reload()?),
+            None => None,
+        };
+
+        let sni_certs = config.sni_certs.iter()
+            .map(SniCert::reload)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let client_auth = config.reload_client_auth()?;
+
+        Ok(TlsIdentity { certs, secret_key, ocsp, alt_cert, sni_certs, client_auth })
+    }
+}
+
+/// Create a TLS server configuration that presents `identity` to the clients.
+fn create_tls_server_config(
+    config: &KeServerConfig,
+    identity: TlsIdentity,
+    rotator: &Arc<RwLock<KeyRotator>>,
+) -> Result<rustls::ServerConfig, rustls::TLSError> {
+    // If the client authentication is configured, the clients must present certificates accepted
+    // by it.
+    let client_auth: Arc<dyn rustls::ClientCertVerifier> = match identity.client_auth {
+        Some(client_auth) => Arc::new(KeClientCertVerifier::new(
+            client_auth.roots,
+            client_auth.allowed_sans,
+        )),
+        // No client auth for TLS server.
+        None => rustls::NoClientAuth::new(),
+    };
+    // TLS server configuration.
+    let mut server_config = rustls::ServerConfig::new(client_auth);
+
+    // We support only TLS1.3
+    server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+
+    // Restrict the cipher suites if the operator pinned them. The configured order is the
+    // server's preference.
+    if !config.tls_cipher_suites.is_empty() {
+        server_config.ciphersuites = config.tls_cipher_suites.clone();
+        server_config.ignore_client_order = true;
+    }
+
+    // Set the certificate chains and their corresponding private keys.
+    let mut cert_resolver = KeCertResolver::new(
+        identity.certs,
+        &identity.secret_key,
+        identity.ocsp,
+    )?;
+    if let Some(chain) = identity.alt_cert {
+        cert_resolver.add_default(chain.tls_certs, &chain.tls_secret_key, chain.tls_ocsp)?;
+    }
+    for sni_cert in identity.sni_certs {
+        cert_resolver.add(
+            &sni_cert.hostname,
+            sni_cert.chain.tls_certs,
+            &sni_cert.chain.tls_secret_key,
+            sni_cert.chain.tls_ocsp,
+        )?;
+    }
+    server_config.cert_resolver = Arc::new(cert_resolver);
+
+    // Issue session tickets encrypted with the rotator keys, so that the clients can resume
+    // their sessions with any server sharing the same keys.
+    if config.tls_session_tickets {
+        server_config.ticketer = Arc::new(
+            RotatingTicketer::new(rotator.clone(), config.tls_ticket_lifetime)
+        );
+    }
+
+    // Log the TLS secrets for debugging. `KeyLogFile` does nothing if SSLKEYLOGFILE is not set.
+    if config.tls_key_log {
+        server_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
+
+    // According to the NTS specification, ALPN protocol must be "ntske/1".
+    server_config
+        .set_protocols(&[Vec::from("ntske/1".as_bytes())]);
+
+    Ok(server_config)
+}
+
+/// Re-read the OCSP responses, together with the certificates, at the configured interval. This
+/// function never returns.
+fn refresh_tls_ocsp(state: Arc<KeServerState>) {
+    let logger = state.config.logger();
+    let interval = Duration::from_secs(state.config.tls_ocsp_refresh_interval);
+
+    loop {
+        std::thread::sleep(interval);
+
+        if let Err(error) = state.reload_tls_server_config() {
+            error!(logger, "failed to refresh OCSP responses: {}", error);
+        }
+    }
+}
+
+/// Reload the TLS certificates every time that the process receives SIGHUP. This function never
+/// returns.
+fn reload_tls_on_sighup(state: Arc<KeServerState>) {
+    let logger = state.config.logger();
+    let mut last_count = signal::sighup_count();
+
+    loop {
+        std::thread::sleep(SIGNAL_CHECK_INTERVAL);
+
+        let count = signal::sighup_count();
+        if count == last_count {
+            continue;
+        }
+        last_count = count;
+
+        info!(logger, "reloading TLS certificates");
+        match state.reload_tls_server_config() {
+            Ok(()) => info!(logger, "reloaded TLS certificates"),
+            Err(error) => error!(logger, "failed to reload TLS certificates: {}", error),
+        }
+    }
 }
 
 /// NTS-KE server instance.
@@ -49,14 +400,18 @@ pub struct KeServer {
 }
 
 impl KeServer {
-    /// Create a new `KeServer` instance, connect to the Memcached server, and rotate initial keys.
+    /// Create a new `KeServer` instance, connect to the key store, and rotate initial keys. The
+    /// store is usually built from the config with `config.key_store().build()`, but it can be
+    /// any `KeyStore`.
     ///
     /// This doesn't start the server yet. It just makes to the state that it's ready to start.
     /// Please run `start` to start the server.
-    pub fn connect(config: KeServerConfig) -> Result<KeServer, RotateError> {
+    pub fn connect(config: KeServerConfig, store: Box<dyn KeyStore>)
+        -> Result<KeServer, RotateError>
+    {
         let rotator = KeyRotator::connect(
-            String::from("/nts/nts-keys"),
-            String::from(config.memcached_url()),
+            store,
+            config.rotation,
 
             // We need to clone all of the following properties because the key rotator also
             // has to own them.
@@ -64,36 +419,32 @@ impl KeServer {
             config.logger().clone(),
         )?;
 
-        // Putting it in a block just to make it easier to read :)
-        let tls_server_config = {
-            // No client auth for TLS server.
-            let client_auth = rustls::NoClientAuth::new();
-            // TLS server configuration.
-            let mut server_config = rustls::ServerConfig::new(client_auth);
-
-            // We support only TLS1.3
-            server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
-
-            // Set the certificate chain and its corresponding private key.
-            server_config
-                .set_single_cert(
-                    // rustls::ServerConfig wants to own both of them.
-                    config.tls_certs.clone(),
-                    config.tls_secret_keys[0].clone()
-                )
-                .expect("invalid key or certificate");
-
-            // According to the NTS specification, ALPN protocol must be "ntske/1".
-            server_config
-                .set_protocols(&[Vec::from("ntske/1".as_bytes())]);
-
-            server_config
-        };
+        let rotator = Arc::new(RwLock::new(rotator));
+
+        let tls_server_config = create_tls_server_config(
+            &config,
+            TlsIdentity::from_config(&config),
+            &rotator,
+        ).expect("invalid key or certificate");
+
+        let rate_limiter = config.conn_rate_limit.as_ref().map(|limit| {
+            Mutex::new(RateLimiter::new(limit.rate, limit.burst, limit.exempt.clone()))
+        });
+
+        // If there is no NTP server in the config, the clients use the one on this host.
+        let mut next_servers = config.next_servers.clone();
+        if next_servers.is_empty() {
+            next_servers.push(NtpEndpoint { server: None, port: config.next_port, weight: 1 });
+        }
+        let next_servers = NextServerSelector::new(next_servers, config.next_server_selection);
 
         let state = Arc::new(KeServerState {
             config,
-            rotator: Arc::new(RwLock::new(rotator)),
-            tls_server_config: Arc::new(tls_server_config),
+            rotator,
+            tls_server_config: RwLock::new(Arc::new(tls_server_config)),
+            rate_limiter,
+            open_connections: AtomicUsize::new(0),
+            next_servers,
         });
 
         Ok(KeServer {
@@ -107,7 +458,7 @@ impl KeServer {
         let logger = self.state.config.logger();
 
         // Side-effect. Logging.
-        info!(logger, "initializing keys with memcached");
+        info!(logger, "initializing keys from the key store");
 
         // Create another reference to the lock so that we can pass it to another thread and
         // periodically rotate the keys.
@@ -116,6 +467,19 @@ impl KeServer {
         // Create a new thread and periodically rotate the keys.
         periodic_rotate(mutable_rotator);
 
+        // Keep the master key in sync with Vault, if it's read from there.
+        if let Some(secret) = self.state.config.cookie_key_vault.clone() {
+            periodic_reload_master_key(self.state.rotator.clone(), secret);
+        }
+        // Or with AWS.
+        if let Some(secret) = self.state.config.cookie_key_aws.clone() {
+            periodic_reload_master_key_aws(self.state.rotator.clone(), secret);
+        }
+        // Or with the file, if it's read from there.
+        if let Some(path) = self.state.config.cookie_key_file.clone() {
+            periodic_reload_master_key_file(self.state.rotator.clone(), path);
+        }
+
         // We need to clone the metrics config here because we need to move it to another thread.
         if let Some(metrics_config) = self.state.config.metrics_config.clone() {
             info!(logger, "spawning metrics");
@@ -123,30 +487,74 @@ impl KeServer {
             // Create a child logger to use inside the metric server.
             let log_metrics = logger.new(slog::o!("component" => "metrics"));
 
+            // The load balancers check /readyz, so that they don't send the clients to a server
+            // which has no fresh keys.
+            let readiness_state = self.state.clone();
+            let readiness: metrics::ReadinessCheck = Arc::new(move || readiness_state.readiness());
+
             // Start a metric server.
             std::thread::spawn(move || {
-                metrics::run_metrics(metrics_config, &log_metrics)
+                metrics::run_metrics(metrics_config, Some(readiness), &log_metrics)
                     .expect("metrics could not be run; starting ntp server failed");
             });
         }
 
+        if let Some(admin_config) = self.state.config.admin_config.clone() {
+            info!(logger, "spawning admin endpoint");
+
+            let log_admin = logger.new(slog::o!("component" => "admin"));
+            let rotator = self.state.rotator.clone();
+
+            std::thread::spawn(move || {
+                admin::run_admin(admin_config, rotator, None, &log_admin)
+                    .expect("admin endpoint could not be run; starting NTS-KE server failed");
+            });
+        }
+
+        // Stop the listeners gracefully on SIGTERM.
+        signal::install_sigterm_handler()?;
+
+        // Reload the TLS certificates on SIGHUP so that renewed certificates can be picked up
+        // without restarting the server. The keys are synced on SIGHUP as well.
+        signal::install_sighup_handler()?;
+        let reload_state = self.state.clone();
+        std::thread::spawn(move || reload_tls_on_sighup(reload_state));
+
+        // OCSP responses expire, so we have to re-read them periodically. Something else, for
+        // example, a cron job, is responsible for fetching the new responses into the files.
+        if self.state.config.has_tls_ocsp() {
+            let refresh_state = self.state.clone();
+            std::thread::spawn(move || refresh_tls_ocsp(refresh_state));
+        }
+
         // For each address in the config, we will create a listener that will listen on that
         // address. After the creation, we will create another thread and start listening inside
         // that thread.
 
-        for addr in self.state.config.addrs() {
+        for listen_addr in self.state.config.addrs() {
+            // Each worker has its own listening socket on the same address, so that the accept
+            // loops don't contend with each other. A Unix domain socket can't be shared like
+            // that, so it has only one.
+            let workers = match listen_addr.addr {
+                KeSocketAddr::Tcp(_) => self.state.config.accept_workers,
+                KeSocketAddr::Unix(_) => 1,
+            };
+
             // Side-effect. Logging.
-            info!(logger, "starting NTS-KE server over TCP/TLS on {}", addr);
+            info!(logger, "starting NTS-KE server over TCP/TLS on {} with {} workers",
+                  listen_addr.addr, workers);
 
-            // Instantiate a listener.
-            // If there is an error here just return an error immediately so that we don't have to
-            // start a thread for other address.
-            let listener = KeServerListener::bind(addr.clone(), &self)?;
+            for _ in 0..workers {
+                // Instantiate a listener.
+                // If there is an error here just return an error immediately so that we don't
+                // have to start a thread for other address.
+                let listener = KeServerListener::bind(listen_addr.clone(), self)?;
 
-            // It needs to be referenced by this thread and the new thread.
-            let atomic_listener = Arc::new(RwLock::new(listener));
+                // It needs to be referenced by this thread and the new thread.
+                let atomic_listener = Arc::new(RwLock::new(listener));
 
-            self.listeners.push(atomic_listener);
+                self.listeners.push(atomic_listener);
+            }
         }
 
         // Join handles for the listeners.
@@ -180,6 +588,11 @@ impl KeServer {
             let _ = handle.join();
         }
 
+        // The listeners only return when the server is shutting down. The metrics won't be
+        // scraped anymore, so put their last values in the log.
+        info!(logger, "NTS-KE server stopped");
+        metrics::log_metrics(logger);
+
         Ok(())
     }
 
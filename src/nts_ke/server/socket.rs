@@ -0,0 +1,187 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! NTS-KE server sockets, which are either TCP or Unix domain sockets.
+
+use mio::net::{TcpListener, TcpStream};
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+/// A listening socket.
+pub enum KeListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A connection accepted by a `KeListenSocket`.
+pub enum KeStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl KeListenSocket {
+    /// Bind a non-blocking Unix domain socket. The socket file left by the previous run is
+    /// replaced.
+    pub fn bind_unix(path: &Path) -> Result<KeListenSocket, std::io::Error> {
+        match std::fs::remove_file(path) {
+            Err(ref error) if error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(std::io::Error::new(error.kind(), format!(
+                    "cannot remove the old socket {}: {}", path.display(), error
+                )));
+            },
+            _ => (),
+        }
+
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(KeListenSocket::Unix(listener))
+    }
+
+    /// Accept a connection and return it with the address of the peer. A Unix domain socket
+    /// peer has no address, so it's the unspecified address until the PROXY header tells the
+    /// client address.
+    pub fn accept(&self) -> Result<(KeStream, SocketAddr), std::io::Error> {
+        match self {
+            KeListenSocket::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((KeStream::Tcp(stream), addr))
+            },
+            KeListenSocket::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok((KeStream::Unix(stream), SocketAddr::from(([0, 0, 0, 0], 0))))
+            },
+        }
+    }
+}
+
+impl AsRawFd for KeListenSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            KeListenSocket::Tcp(listener) => listener.as_raw_fd(),
+            KeListenSocket::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Evented for KeListenSocket {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> Result<(), std::io::Error>
+    {
+        EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> Result<(), std::io::Error>
+    {
+        EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), std::io::Error> {
+        EventedFd(&self.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl KeStream {
+    /// Shut down the reading, writing, or both halves of the connection.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), std::io::Error> {
+        match self {
+            KeStream::Tcp(stream) => stream.shutdown(how),
+            KeStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl AsRawFd for KeStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            KeStream::Tcp(stream) => stream.as_raw_fd(),
+            KeStream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for KeStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self {
+            KeStream::Tcp(stream) => stream.read(buf),
+            KeStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for KeStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        match self {
+            KeStream::Tcp(stream) => stream.write(buf),
+            KeStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            KeStream::Tcp(stream) => stream.flush(),
+            KeStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Evented for KeStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> Result<(), std::io::Error>
+    {
+        EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> Result<(), std::io::Error>
+    {
+        EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), std::io::Error> {
+        EventedFd(&self.as_raw_fd()).deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix() {
+        let path = std::env::temp_dir().join(format!("cfnts-test-{}.sock", std::process::id()));
+        // A stale socket file is replaced.
+        drop(KeListenSocket::bind_unix(&path).unwrap());
+        let listener = KeListenSocket::bind_unix(&path).unwrap();
+
+        // It doesn't block without a connection.
+        match listener.accept() {
+            Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => (),
+            _ => panic!("accepted a connection that doesn't exist"),
+        }
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut stream, addr) = listener.accept().unwrap();
+        assert!(addr.ip().is_unspecified());
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // The stream is non-blocking.
+        match stream.read(&mut buf) {
+            Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => (),
+            _ => panic!("read data that doesn't exist"),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
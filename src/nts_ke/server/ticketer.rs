@@ -0,0 +1,215 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! TLS session ticket encryption for the NTS-KE server.
+
+use lazy_static::lazy_static;
+
+use prometheus::{opts, register_counter, register_int_counter, IntCounter};
+
+use rand::Rng;
+
+use ring::{aead, hmac};
+
+use rustls::ProducesTickets;
+
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock};
+
+use crate::key_rotator::{KeyId, KeyRotator};
+
+/// The label used to derive a ticket key from a rotator key, so that the same key is not used
+/// for both cookies and tickets.
+const TICKET_KEY_LABEL: &[u8] = b"cfnts session ticket key";
+
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+lazy_static! {
+    static ref TICKET_ACCEPTED_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_resumed_handshakes_total",
+        "Number of TLS handshakes resumed using session tickets"
+    ).unwrap();
+    static ref TICKET_REJECTED_COUNTER: IntCounter = register_int_counter!(
+        "nts_ke_session_tickets_rejected_total",
+        "Number of session tickets that could not be decrypted"
+    ).unwrap();
+}
+
+/// Session ticket producer whose keys follow the keys of the `KeyRotator`.
+///
+/// Since every NTS-KE server sharing the same key store has the same keys, a client can
+/// resume its session with any of them. The tickets expire when the rotator drops the key that
+/// was used to encrypt them.
+pub struct RotatingTicketer {
+    /// The rotator that the ticket keys are derived from.
+    rotator: Arc<RwLock<KeyRotator>>,
+
+    /// The lifetime hint in seconds that is sent to the clients.
+    lifetime: u32,
+}
+
+impl RotatingTicketer {
+    /// Create a new ticket producer using the keys from `rotator`.
+    pub fn new(rotator: Arc<RwLock<KeyRotator>>, lifetime: u32) -> RotatingTicketer {
+        RotatingTicketer { rotator, lifetime }
+    }
+
+    /// Derive a ticket key for `key_id`, if the rotator still has it.
+    fn ticket_key(&self, key_id: KeyId) -> Option<aead::LessSafeKey> {
+        let rotator = self.rotator.read().unwrap();
+        let tag = rotator.get(key_id)?;
+        Some(derive_ticket_key(tag.as_ref()))
+    }
+}
+
+/// Derive an AES-256-GCM key from the rotator key material.
+fn derive_ticket_key(material: &[u8]) -> aead::LessSafeKey {
+    let mac_key = hmac::Key::new(hmac::HMAC_SHA256, material);
+    let tag = hmac::sign(&mac_key, TICKET_KEY_LABEL);
+
+    // HMAC-SHA256 tags are 32 bytes, which is exactly the AES-256 key length.
+    let unbound_key = aead::UnboundKey::new(&aead::AES_256_GCM, tag.as_ref())
+        .expect("BUG: HMAC-SHA256 tag should be a valid AES-256-GCM key");
+    aead::LessSafeKey::new(unbound_key)
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    // The ticket format is the key id, followed by the nonce, and then the ciphertext with its
+    // tag. The key id is also authenticated as the associated data.
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let (key_id, key) = {
+            let rotator = self.rotator.read().unwrap();
+            let (key_id, tag) = rotator.latest_key_value();
+            (key_id, derive_ticket_key(tag.as_ref()))
+        };
+
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+
+        let key_id_bytes = key_id.to_be_bytes();
+        let mut in_out = Vec::from(plain);
+        key.seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&key_id_bytes),
+            &mut in_out,
+        ).ok()?;
+
+        let mut ticket = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + in_out.len());
+        ticket.extend_from_slice(&key_id_bytes);
+        ticket.extend_from_slice(&nonce);
+        ticket.append(&mut in_out);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < KEY_ID_LEN + NONCE_LEN {
+            TICKET_REJECTED_COUNTER.inc();
+            return None;
+        }
+
+        let key_id_bytes: [u8; KEY_ID_LEN] = cipher[..KEY_ID_LEN].try_into().unwrap();
+        let nonce: [u8; NONCE_LEN] = cipher[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]
+            .try_into()
+            .unwrap();
+
+        let key = match self.ticket_key(KeyId::from_be_bytes(key_id_bytes)) {
+            Some(key) => key,
+            None => {
+                TICKET_REJECTED_COUNTER.inc();
+                return None;
+            }
+        };
+
+        let mut in_out = Vec::from(&cipher[KEY_ID_LEN + NONCE_LEN..]);
+        match key.open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&key_id_bytes),
+            &mut in_out,
+        ) {
+            Ok(plain) => {
+                TICKET_ACCEPTED_COUNTER.inc();
+                Some(Vec::from(&*plain))
+            },
+            Err(_) => {
+                TICKET_REJECTED_COUNTER.inc();
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sloggers::Build;
+    use sloggers::null::NullLoggerBuilder;
+
+    use crate::cookie::CookieKey;
+    use crate::key_rotator::{RotateError, RotationConfig};
+    use crate::key_store::KeyStore;
+
+    // A key store that has a key for every period.
+    struct MockStore;
+
+    impl KeyStore for MockStore {
+        fn fetch_keys(&self, epochs: &[u64]) -> Result<Vec<Option<Vec<u8>>>, RotateError> {
+            Ok(epochs.iter().map(|epoch| Some(epoch.to_be_bytes().to_vec())).collect())
+        }
+
+        fn health(&self) -> Result<(), RotateError> {
+            Ok(())
+        }
+    }
+
+    fn ticketer(master_key: &[u8]) -> RotatingTicketer {
+        let rotator = KeyRotator::connect(
+            Box::new(MockStore),
+            RotationConfig::default(),
+            CookieKey::from(master_key),
+            NullLoggerBuilder.build().unwrap(),
+        ).unwrap();
+        RotatingTicketer::new(Arc::new(RwLock::new(rotator)), 3600)
+    }
+
+    #[test]
+    fn test_tickets() {
+        let ticketer = ticketer(&[1; 32]);
+        assert!(ticketer.enabled());
+        assert_eq!(ticketer.get_lifetime(), 3600);
+
+        let accepted = TICKET_ACCEPTED_COUNTER.get();
+        let rejected = TICKET_REJECTED_COUNTER.get();
+
+        let ticket = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(ticket.len(), KEY_ID_LEN + NONCE_LEN + b"session".len() + 16);
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session");
+        // Each ticket has its own nonce.
+        assert_ne!(ticketer.encrypt(b"session").unwrap(), ticket);
+        assert_eq!(TICKET_ACCEPTED_COUNTER.get(), accepted + 1);
+
+        // The ticket is authenticated, including the key id.
+        for i in 0..ticket.len() {
+            let mut tampered = ticket.clone();
+            tampered[i] ^= 1;
+            assert_eq!(ticketer.decrypt(&tampered), None);
+        }
+        assert_eq!(ticketer.decrypt(&ticket[..KEY_ID_LEN + NONCE_LEN - 1]), None);
+
+        // The servers with another master key cannot decrypt the ticket.
+        assert_eq!(self::ticketer(&[2; 32]).decrypt(&ticket), None);
+
+        assert_eq!(TICKET_ACCEPTED_COUNTER.get(), accepted + 1);
+        assert_eq!(TICKET_REJECTED_COUNTER.get(), rejected + ticket.len() as i64 + 2);
+    }
+}
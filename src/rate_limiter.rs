@@ -0,0 +1,368 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Per-client token bucket rate limiter, and a single token bucket for the events that are not
+//! limited per client.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::cidr::{client_network, get_cidr_list, Cidr};
+
+/// The most clients that a rate limiter keeps buckets for.
+const MAX_CLIENTS: usize = 65536;
+
+/// Configuration of a per-client token bucket rate limiter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    /// The average number of events per second allowed from each client.
+    pub rate: f64,
+
+    /// The number of events allowed from each client at once.
+    pub burst: u32,
+
+    /// The clients in these address blocks are never limited.
+    pub exempt: Vec<Cidr>,
+}
+
+/// Parse a per-client rate limit from `<prefix>_limit`, `<prefix>_burst`, and `<prefix>_exempt`,
+/// for example, `conn_rate_limit`. The rate limit is enabled only when `<prefix>_limit` is
+/// specified.
+pub fn get_rate_limit_config(settings: &config::Config, prefix: &str)
+    -> Result<Option<RateLimitConfig>, config::ConfigError>
+{
+    let rate = match settings.get_float(&format!("{}_limit", prefix)) {
+        // If it's a not-found error, the rate limit is disabled.
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(rate) if rate > 0.0 && rate.is_finite() => rate,
+        Ok(_) => return Err(config::ConfigError::Message(
+            format!("the {}_limit must be positive", prefix)
+        )),
+    };
+
+    let burst = match settings.get_int(&format!("{}_burst", prefix)) {
+        // If it's a not-found error, allow one second worth of events at once.
+        Err(config::ConfigError::NotFound(_)) => rate.ceil() as u32,
+        Err(error) => return Err(error),
+        Ok(val) => match u32::try_from(val) {
+            Ok(val) if val > 0 => val,
+            _ => return Err(config::ConfigError::Message(
+                format!("the {}_burst is not a positive u32", prefix)
+            )),
+        },
+    };
+
+    let exempt = get_cidr_list(settings, &format!("{}_exempt", prefix))?;
+
+    Ok(Some(RateLimitConfig { rate, burst, exempt }))
+}
+
+/// Token bucket of a single client.
+struct Bucket {
+    /// The number of tokens left. Each allowed event takes one token.
+    tokens: f64,
+
+    /// The last time that the tokens were refilled.
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refill the tokens added at `rate` per second up to `burst` since the last time, and take
+    /// one if there is one. Return true if one was taken.
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated.min(now)).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A single token bucket, which allows `rate` events per second on average and up to `burst`
+/// events at once.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    bucket: Bucket,
+}
+
+impl TokenBucket {
+    /// Create a full token bucket.
+    ///
+    /// # Panics
+    ///
+    /// If `rate` is not positive.
+    ///
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        assert!(rate > 0.0, "the rate must be positive");
+
+        TokenBucket {
+            rate,
+            burst: f64::from(burst),
+            bucket: Bucket { tokens: f64::from(burst), updated: Instant::now() },
+        }
+    }
+
+    /// Take a token. Return true if the event is allowed.
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.bucket.take(self.rate, self.burst, now)
+    }
+}
+
+/// Rate limiter which keeps one token bucket for each client IPv4 address or IPv6 /64 network.
+pub struct RateLimiter {
+    /// The number of tokens added to each bucket per second.
+    rate: f64,
+
+    /// The maximum number of tokens in each bucket.
+    burst: f64,
+
+    /// The clients in these address blocks are never limited.
+    exempt: Vec<Cidr>,
+
+    /// The most clients that have a bucket.
+    max_clients: usize,
+
+    /// The buckets indexed by the client addresses, see `client_network`.
+    buckets: HashMap<IpAddr, Bucket>,
+
+    /// The last time that the full buckets were purged.
+    last_purge: Instant,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter allowing `rate` events per second on average and up to `burst`
+    /// events at once for each client.
+    ///
+    /// # Panics
+    ///
+    /// If `rate` is not positive.
+    ///
+    pub fn new(rate: f64, burst: u32, exempt: Vec<Cidr>) -> RateLimiter {
+        assert!(rate > 0.0, "the rate must be positive");
+
+        RateLimiter {
+            rate,
+            burst: f64::from(burst),
+            exempt,
+            max_clients: MAX_CLIENTS,
+            buckets: HashMap::new(),
+            last_purge: Instant::now(),
+        }
+    }
+
+    /// Take a token from the bucket of `addr`. Return true if the event is allowed.
+    pub fn check(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if self.exempt.iter().any(|block| block.contains(&addr)) {
+            return true;
+        }
+        let addr = client_network(addr);
+
+        self.purge(now, false);
+        if !self.buckets.contains_key(&addr) {
+            self.make_room(now);
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(addr).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        bucket.take(rate, burst, now)
+    }
+
+    /// Return the clients whose buckets are not full at `now`, with the tokens that they have
+    /// left.
+    pub fn buckets(&self, now: Instant) -> Vec<(IpAddr, f64)> {
+        self.buckets.iter()
+            .map(|(&addr, bucket)| {
+                let elapsed = now.duration_since(bucket.updated.min(now)).as_secs_f64();
+                (addr, (bucket.tokens + elapsed * self.rate).min(self.burst))
+            })
+            .filter(|&(_, tokens)| tokens < self.burst)
+            .collect()
+    }
+
+    /// Give `addr` the `tokens` that it had `age` ago, for example, before the server restarted.
+    /// They are refilled since then.
+    pub fn restore(&mut self, addr: IpAddr, tokens: f64, age: Duration, now: Instant) {
+        let addr = client_network(addr);
+        let tokens = (tokens + age.as_secs_f64() * self.rate).min(self.burst);
+        if tokens < self.burst {
+            if !self.buckets.contains_key(&addr) {
+                self.make_room(now);
+            }
+            self.buckets.insert(addr, Bucket { tokens, updated: now });
+        } else {
+            self.buckets.remove(&addr);
+        }
+    }
+
+    /// Remove the buckets that have been refilled completely. Forgetting them doesn't change the
+    /// result of `check` because a new bucket starts full.
+    // Unless it's forced, it only runs once in a while to keep `check` cheap.
+    fn purge(&mut self, now: Instant, force: bool) {
+        let refill_time = Duration::from_secs_f64(self.burst / self.rate);
+        if !force && now.duration_since(self.last_purge.min(now)) < refill_time {
+            return;
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated.min(now)).as_secs_f64();
+            bucket.tokens + elapsed * rate < burst
+        });
+        self.last_purge = now;
+    }
+
+    /// Make room for a new bucket if there are `max_clients` already. The full buckets are
+    /// removed first. If that's not enough, the least recently updated half is forgotten, like
+    /// in the replay cache, so that the cost of finding them is spread over the next clients.
+    /// Those clients get a full bucket again, which is better than limiting all the new ones.
+    fn make_room(&mut self, now: Instant) {
+        if self.buckets.len() < self.max_clients {
+            return;
+        }
+        self.purge(now, true);
+        if self.buckets.len() < self.max_clients {
+            return;
+        }
+
+        let mut updated: Vec<Instant> = self.buckets.values()
+            .map(|bucket| bucket.updated)
+            .collect();
+        let middle = updated.len().saturating_sub(1) / 2;
+        updated.sort_unstable();
+        let median = updated[middle];
+        self.buckets.retain(|_, bucket| bucket.updated > median);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let mut limiter = RateLimiter::new(2.0, 3, vec!["198.51.100.0/24".parse().unwrap()]);
+        let start = Instant::now();
+
+        // The burst is allowed at once, but not more than that.
+        for _ in 0..3 {
+            assert!(limiter.check(client, start));
+        }
+        assert!(!limiter.check(client, start));
+
+        // The other clients have their own buckets.
+        assert!(limiter.check(other, start));
+
+        // Two tokens are refilled in one second.
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check(client, later));
+        assert!(limiter.check(client, later));
+        assert!(!limiter.check(client, later));
+
+        // The exempted clients are never limited.
+        let exempted: IpAddr = "::ffff:198.51.100.9".parse().unwrap();
+        for _ in 0..10 {
+            assert!(limiter.check(exempted, start));
+        }
+
+        // The idle clients are forgotten after their buckets are refilled.
+        limiter.check(client, start + Duration::from_secs(60));
+        assert_eq!(limiter.buckets.len(), 1);
+
+        // The addresses in an IPv6 /64 share a bucket.
+        for i in 0..3 {
+            assert!(limiter.check(format!("2001:db8::{}", i).parse().unwrap(), start));
+        }
+        assert!(!limiter.check("2001:db8::ffff:1".parse().unwrap(), start));
+        assert!(limiter.check("2001:db8:0:1::1".parse().unwrap(), start));
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let mut limiter = RateLimiter::new(1.0, 1, Vec::new());
+        limiter.max_clients = 4;
+        let start = Instant::now();
+        for i in 0..4 {
+            let at = start + Duration::from_millis(i);
+            assert!(limiter.check(format!("192.0.2.{}", i).parse().unwrap(), at));
+        }
+
+        // The least recently updated half is forgotten to make room for a new client.
+        let at = start + Duration::from_millis(4);
+        assert!(limiter.check("192.0.2.4".parse().unwrap(), at));
+        assert_eq!(limiter.buckets.len(), 3);
+        assert!(limiter.check("192.0.2.0".parse().unwrap(), at));
+        assert!(!limiter.check("192.0.2.3".parse().unwrap(), at));
+
+        // The full buckets are removed before anything else.
+        let at = start + Duration::from_secs(10);
+        assert!(limiter.check("192.0.2.5".parse().unwrap(), at));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[test]
+    fn test_restore() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut limiter = RateLimiter::new(1.0, 2, Vec::new());
+        let start = Instant::now();
+        assert!(limiter.check(client, start));
+        assert!(limiter.check(client, start));
+        assert_eq!(limiter.buckets(start), vec![(client, 0.0)]);
+
+        // The bucket is refilled for as long as the limiter was gone.
+        let mut restored = RateLimiter::new(1.0, 2, Vec::new());
+        restored.restore(client, 0.0, Duration::from_millis(500), start);
+        assert!(!restored.check(client, start));
+        restored.restore(client, 0.0, Duration::from_secs(5), start);
+        assert!(restored.buckets(start).is_empty());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(0.5, 2);
+        let start = Instant::now();
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+
+        // One token is refilled in two seconds.
+        assert!(!bucket.take(start + Duration::from_secs(1)));
+        assert!(bucket.take(start + Duration::from_secs(2)));
+        assert!(!bucket.take(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_get_rate_limit_config() {
+        let mut settings = config::Config::new();
+        assert!(get_rate_limit_config(&settings, "query_rate").unwrap().is_none());
+
+        settings.set("query_rate_limit", 2.5).unwrap();
+        let limit = get_rate_limit_config(&settings, "query_rate").unwrap().unwrap();
+        assert_eq!((limit.rate, limit.burst, limit.exempt.len()), (2.5, 3, 0));
+
+        settings.set("query_rate_burst", 0).unwrap();
+        assert!(get_rate_limit_config(&settings, "query_rate").is_err());
+        settings.set("query_rate_burst", 10).unwrap();
+        settings.set("query_rate_exempt", vec!["10.0.0.0/8"]).unwrap();
+        let limit = get_rate_limit_config(&settings, "query_rate").unwrap().unwrap();
+        assert_eq!((limit.burst, limit.exempt.len()), (10, 1));
+
+        settings.set("query_rate_limit", -1).unwrap();
+        assert!(get_rate_limit_config(&settings, "query_rate").is_err());
+    }
+}
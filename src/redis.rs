@@ -0,0 +1,254 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A minimal Redis client which speaks just enough RESP to read and write the keys shared among
+//! the servers. See https://redis.io/topics/protocol.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use url::percent_encoding::percent_decode;
+
+/// How long a connection, a command, or a reply can take.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum length of a bulk string that we accept in a reply. The keys are much shorter.
+const MAX_BULK_LEN: usize = 1 << 20;
+
+/// Error returned from the Redis client.
+#[derive(Debug)]
+pub enum RedisError {
+    /// Error from the connection to the server.
+    Io(std::io::Error),
+    /// The server replied with an error.
+    Server(String),
+    /// The server sent something that we don't understand.
+    Protocol(String),
+    /// The url is not a valid Redis url.
+    Url(String),
+}
+
+impl fmt::Display for RedisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedisError::Io(error) => write!(f, "redis connection error: {}", error),
+            RedisError::Server(message) => write!(f, "redis server error: {}", message),
+            RedisError::Protocol(message) => write!(f, "redis protocol error: {}", message),
+            RedisError::Url(message) => write!(f, "invalid redis url: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RedisError {}
+
+impl From<std::io::Error> for RedisError {
+    fn from(error: std::io::Error) -> RedisError {
+        RedisError::Io(error)
+    }
+}
+
+/// A reply from the server.
+#[derive(Debug, Eq, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// Encode a command as an array of bulk strings.
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+/// Read a line without its CRLF.
+fn read_line(reader: &mut impl BufRead) -> Result<String, RedisError> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(RedisError::Protocol(String::from("the reply ends unexpectedly")));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line)
+        .map_err(|_| RedisError::Protocol(String::from("the reply is not UTF-8")))
+}
+
+/// Parse the length of a bulk string or an array. It's `None` for a null value.
+fn parse_len(value: &str) -> Result<Option<usize>, RedisError> {
+    if value == "-1" {
+        return Ok(None);
+    }
+    match value.parse::<usize>() {
+        Ok(len) if len <= MAX_BULK_LEN => Ok(Some(len)),
+        _ => Err(RedisError::Protocol(format!("invalid length {}", value))),
+    }
+}
+
+/// Read a reply from the server.
+fn read_reply(reader: &mut impl BufRead) -> Result<Reply, RedisError> {
+    let line = read_line(reader)?;
+    if line.is_empty() {
+        return Err(RedisError::Protocol(String::from("empty reply")));
+    }
+    // The types are ASCII, so the value starts at the second byte.
+    let (kind, value) = match line.as_bytes()[0] {
+        kind if kind.is_ascii() => (kind, &line[1..]),
+        kind => return Err(RedisError::Protocol(format!("unknown reply type {:#04x}", kind))),
+    };
+
+    match kind {
+        b'+' => Ok(Reply::Status(String::from(value))),
+        b'-' => Err(RedisError::Server(String::from(value))),
+        b':' => value.parse()
+            .map(Reply::Integer)
+            .map_err(|_| RedisError::Protocol(format!("invalid integer {}", value))),
+        b'$' => match parse_len(value)? {
+            None => Ok(Reply::Bulk(None)),
+            Some(len) => {
+                let mut data = vec![0; len + 2];
+                reader.read_exact(&mut data)?;
+                if !data.ends_with(b"\r\n") {
+                    return Err(RedisError::Protocol(String::from("the bulk string is too long")));
+                }
+                data.truncate(len);
+                Ok(Reply::Bulk(Some(data)))
+            },
+        },
+        b'*' => match parse_len(value)? {
+            None => Ok(Reply::Array(None)),
+            Some(len) => {
+                let mut replies = Vec::new();
+                for _ in 0..len {
+                    replies.push(read_reply(reader)?);
+                }
+                Ok(Reply::Array(Some(replies)))
+            },
+        },
+        _ => Err(RedisError::Protocol(format!("unknown reply type {}", char::from(kind)))),
+    }
+}
+
+/// Return the password in `url`, which is percent-encoded there.
+fn password(url: &url::Url) -> Option<Vec<u8>> {
+    url.password().map(|password| percent_decode(password.as_bytes()).collect())
+}
+
+/// A connection to a Redis server.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    /// Connect to the server at `url`, which looks like `redis://[:password@]host[:port][/db]`.
+    pub fn connect(url: &str) -> Result<Client, RedisError> {
+        let parsed = url::Url::parse(url).map_err(|error| RedisError::Url(error.to_string()))?;
+        if parsed.scheme() != "redis" {
+            return Err(RedisError::Url(format!("unsupported scheme {}", parsed.scheme())));
+        }
+        let host = parsed.host_str()
+            .ok_or_else(|| RedisError::Url(String::from("the host is missing")))?;
+        let port = parsed.port().unwrap_or(6379);
+
+        let addr = (host, port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| RedisError::Url(format!("cannot resolve {}", host)))?;
+        let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut client = Client { reader: BufReader::new(stream) };
+
+        if let Some(password) = password(&parsed) {
+            client.command(&[b"AUTH", &password])?;
+        }
+        let db = parsed.path().trim_start_matches('/');
+        if !db.is_empty() {
+            client.command(&[b"SELECT", db.as_bytes()])?;
+        }
+
+        Ok(client)
+    }
+
+    /// Send a command and read its reply.
+    fn command(&mut self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        self.reader.get_mut().write_all(&encode_command(args))?;
+        read_reply(&mut self.reader)
+    }
+
+    /// Check that the server answers.
+    pub fn ping(&mut self) -> Result<(), RedisError> {
+        match self.command(&[b"PING"])? {
+            Reply::Status(ref status) if status == "PONG" => Ok(()),
+            reply => Err(RedisError::Protocol(format!("unexpected reply to PING: {:?}", reply))),
+        }
+    }
+
+    /// Return the value of `key`, if it exists.
+    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, RedisError> {
+        match self.command(&[b"GET", key.as_bytes()])? {
+            Reply::Bulk(value) => Ok(value),
+            reply => Err(RedisError::Protocol(format!("unexpected reply to GET: {:?}", reply))),
+        }
+    }
+
+    /// Set the value of `key`.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), RedisError> {
+        match self.command(&[b"SET", key.as_bytes(), value])? {
+            Reply::Status(ref status) if status == "OK" => Ok(()),
+            reply => Err(RedisError::Protocol(format!("unexpected reply to SET: {:?}", reply))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(encode_command(&[b"GET", b"/nts/nts-keys/3600"]),
+                   b"*2\r\n$3\r\nGET\r\n$18\r\n/nts/nts-keys/3600\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let read = |mut data: &[u8]| read_reply(&mut data);
+
+        assert_eq!(read(b"+OK\r\n").unwrap(), Reply::Status(String::from("OK")));
+        assert_eq!(read(b":42\r\n").unwrap(), Reply::Integer(42));
+        assert_eq!(read(b"$3\r\nab\n\r\n").unwrap(), Reply::Bulk(Some(b"ab\n".to_vec())));
+        assert_eq!(read(b"$-1\r\n").unwrap(), Reply::Bulk(None));
+        assert_eq!(read(b"*2\r\n$1\r\na\r\n$-1\r\n").unwrap(), Reply::Array(Some(vec![
+            Reply::Bulk(Some(b"a".to_vec())),
+            Reply::Bulk(None),
+        ])));
+
+        match read(b"-ERR unknown command\r\n") {
+            Err(RedisError::Server(message)) => assert_eq!(message, "ERR unknown command"),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+
+        // Invalid replies.
+        assert!(read(b"$3\r\nab").is_err());
+        assert!(read(b"$3\r\nabcd\r\n").is_err());
+        assert!(read(b"+OK").is_err());
+        assert!(read(b"?\r\n").is_err());
+        assert!(read("\u{e9}t\u{e9}\r\n".as_bytes()).is_err());
+        assert!(read(b"$99999999999\r\n").is_err());
+    }
+
+    #[test]
+    fn test_password() {
+        let url = url::Url::parse("redis://:p%40ss%3Aw%25rd@localhost:6379/2").unwrap();
+        assert_eq!(password(&url), Some(b"p@ss:w%rd".to_vec()));
+        assert_eq!(password(&url::Url::parse("redis://localhost").unwrap()), None);
+    }
+}
@@ -0,0 +1,70 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Unix signal handling.
+
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// The number of SIGHUP signals received since the process started.
+// We use a counter instead of a flag because there may be more than one component interested in
+// the signal. Each of them can remember the last count it has seen, so that one component
+// noticing the signal doesn't hide it from the others.
+static SIGHUP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether SIGTERM has been received. Once it's set, it's never cleared.
+static SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+// Only async-signal-safe operations are allowed in the handlers. Atomic operations are some of
+// them.
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    SIGHUP_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+extern "C" fn handle_sigterm(_: libc::c_int) {
+    SIGTERM_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Install `handler` as the handler of `signal`.
+fn install_handler(signal: Signal, handler: extern "C" fn(libc::c_int))
+    -> Result<(), std::io::Error>
+{
+    let action = SigAction::new(
+        SigHandler::Handler(handler),
+        // Restart interrupted system calls so that the blocking calls in other threads are not
+        // affected by the signal.
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+
+    // This is safe because the handlers only touch atomic variables. The only way that
+    // `sigaction` can fail is a system call failure, in which case the reason is in `errno`.
+    unsafe { sigaction(signal, &action) }
+        .map_err(|_| std::io::Error::last_os_error())?;
+
+    Ok(())
+}
+
+/// Install the SIGHUP handler. After calling this, SIGHUP will no longer terminate the process.
+pub fn install_sighup_handler() -> Result<(), std::io::Error> {
+    install_handler(Signal::SIGHUP, handle_sighup)
+}
+
+/// Install the SIGTERM handler. After calling this, SIGTERM will no longer terminate the process.
+/// The process has to check `sigterm_received` and exit by itself.
+pub fn install_sigterm_handler() -> Result<(), std::io::Error> {
+    install_handler(Signal::SIGTERM, handle_sigterm)
+}
+
+/// Return the number of SIGHUP signals received so far.
+pub fn sighup_count() -> usize {
+    SIGHUP_COUNT.load(Ordering::SeqCst)
+}
+
+/// Return true if SIGTERM has been received.
+pub fn sigterm_received() -> bool {
+    SIGTERM_RECEIVED.load(Ordering::SeqCst)
+}
@@ -4,11 +4,14 @@
 
 //! The client subcommand.
 
-use slog::debug;
+use serde_json::{json, Value};
+use slog::{error, info, warn};
 
 use std::fs;
-use std::io::BufReader;
+use std::io::{BufReader, ErrorKind};
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use rustls::{
     internal::pemfile::certs,
@@ -16,15 +19,44 @@ use rustls::{
 };
 
 use crate::error::WrapError;
-use crate::ntp::client::run_nts_ntp_client;
-use crate::nts_ke::client::run_nts_ke_client;
+use crate::ntp::client::{run_nts_ntp_client, NtpClientError, NtpResult};
+use crate::ntp::discipline::{
+    read_drift, write_drift, ClockControl, Correction, DryRun, KernelClock, PollInterval,
+    StepPolicy,
+};
+use crate::ntp::protocol::{LeapState, UNIX_OFFSET};
+use crate::ntp::server::upstream::{self, Filter, Sample};
+use crate::nts_ke::certificate::certificate_info;
+use crate::nts_ke::client::{run_nts_ke_client, NtsKeResult};
+
+/// The offsets that are stepped by default, like ntpd.
+const DEFAULT_STEP_THRESHOLD: f64 = 0.128;
+
+/// The time between the polls of a burst.
+const BURST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the frequency error is written to the drift file.
+const DRIFT_INTERVAL: Duration = Duration::from_secs(3600);
 
-#[derive(Debug)]
+/// How many times a poll that timed out or got an NTS NAK is tried again before the server is
+/// given up on. The delay before each retry doubles up to the maximum.
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+#[derive(Clone, Debug)]
 pub struct ClientConfig {
     pub host: String,
     pub port: Option<String>,
-    pub trusted_cert: Option<Certificate>,
-    pub use_ipv4: Option<bool>
+    /// The trust anchors of the NTS-KE server. The roots of webpki are used if there are none.
+    pub trusted_certs: Vec<Certificate>,
+    /// The SHA-256 hashes of the public keys that the verified chain of the NTS-KE server must
+    /// have one of, if there are any.
+    pub pins: Vec<Vec<u8>>,
+    /// Whether the certificate of the NTS-KE server is not verified at all.
+    pub insecure: bool,
+    pub use_ipv4: Option<bool>,
+    pub key_log: bool,
 }
 
 pub fn load_tls_certs(path: String) -> Result<Vec<Certificate>, config::ConfigError> {
@@ -34,15 +66,44 @@ pub fn load_tls_certs(path: String) -> Result<Vec<Certificate>, config::ConfigEr
         ))
 }
 
+/// Load the CA certificates of the PEM bundle at `path`, or of all the PEM files in it if it's a
+/// directory, like the hashed directories of OpenSSL.
+pub fn load_ca_certs(path: &str) -> Result<Vec<Certificate>, config::ConfigError> {
+    let metadata = fs::metadata(path).wrap_err()?;
+    let mut loaded = Vec::new();
+    if metadata.is_dir() {
+        let mut files: Vec<_> = fs::read_dir(path).wrap_err()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| file.is_file())
+            .collect();
+        files.sort();
+        // The files that are not PEM certificates are skipped.
+        for file in files {
+            if let Ok(mut certs) = load_tls_certs(file.to_string_lossy().into_owned()) {
+                loaded.append(&mut certs);
+            }
+        }
+    } else {
+        loaded = load_tls_certs(String::from(path))?;
+    }
+    if loaded.is_empty() {
+        return Err(config::ConfigError::Message(
+            format!("there are no certificates in {}", path)
+        ));
+    }
+    Ok(loaded)
+}
+
 /// The entry point of `client`.
 pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
     // This should return the clone of `logger` in the main function.
     let logger = slog_scope::logger();
 
+    // The host is empty when only the servers of `--server` are polled.
     let host = matches
         .value_of("host")
         .map(String::from)
-        .unwrap();
+        .unwrap_or_default();
     let port = matches.value_of("port").map(String::from);
     let cert_file = matches.value_of("cert").map(String::from);
 
@@ -62,40 +123,574 @@ pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
         }
     }
 
-    let mut trusted_cert = None;
+    let mut trusted_certs = Vec::new();
     if let Some(file) = cert_file {
         if let Ok(certs) = load_tls_certs(file) {
-            trusted_cert = Some(certs[0].clone());
+            trusted_certs.push(certs[0].clone());
+        }
+    }
+    if let Some(path) = matches.value_of("cacert") {
+        match load_ca_certs(path) {
+            Ok(mut certs) => trusted_certs.append(&mut certs),
+            Err(err) => {
+                eprintln!("cannot load the CA certificates: {}", err);
+                process::exit(1)
+            },
+        }
+    }
+
+    let mut pins = Vec::new();
+    for pin in matches.values_of("pin-sha256").into_iter().flatten() {
+        match base64::decode(pin) {
+            Ok(hash) if hash.len() == 32 => pins.push(hash),
+            _ => {
+                eprintln!("the pin {} is not a base64 SHA-256 hash", pin);
+                process::exit(1)
+            },
         }
     }
 
     let client_config = ClientConfig {
         host,
         port,
-        trusted_cert,
+        trusted_certs,
+        pins,
+        insecure: matches.is_present("insecure"),
         use_ipv4,
+        key_log: matches.is_present("keylog"),
     };
+    let configs = server_configs(matches, client_config.clone());
 
-    let res = run_nts_ke_client(&logger, client_config);
+    if matches.is_present("daemon") {
+        let threshold = match matches.value_of("step-threshold").map(str::parse::<f64>) {
+            None => DEFAULT_STEP_THRESHOLD,
+            Some(Ok(threshold)) if threshold > 0.0 => threshold,
+            Some(_) => {
+                eprintln!("the step threshold is not a positive number of seconds");
+                process::exit(1)
+            },
+        };
+        let limit = match matches.value_of("step-limit").map(str::parse::<u32>) {
+            None => None,
+            Some(Ok(limit)) => Some(limit),
+            Some(Err(_)) => {
+                eprintln!("the step limit is not a number of clock updates");
+                process::exit(1)
+            },
+        };
+        let options = DaemonOptions {
+            set_clock: !matches.is_present("no-set"),
+            step: StepPolicy::new(threshold, limit),
+            drift_file: matches.value_of("drift-file").map(String::from),
+        };
+        run_daemon(&logger, configs, options);
+    }
 
-    match res {
-        Err(err) => {
-            eprintln!("failure of tls stage: {}", err);
+    let count = match matches.value_of("samples").map(str::parse::<usize>) {
+        None => 1,
+        Some(Ok(count)) if count > 0 => count,
+        Some(_) => {
+            eprintln!("the number of samples is not a positive integer");
             process::exit(1)
+        },
+    };
+    let measurement = measure(&logger, configs, count);
+    if matches.value_of("output") == Some("json") {
+        print_json(&measurement);
+    } else {
+        print_text(&measurement, count);
+    }
+    // A single server is reported even if it's not synchronized.
+    let failed = match &measurement.bursts[..] {
+        [burst] => burst.is_err(),
+        _ => measurement.selection.is_none(),
+    };
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// A server that the client polls.
+struct Server {
+    config: ClientConfig,
+
+    /// The keys and the cookies of the last key exchange, if they can still be used.
+    nts_state: Option<NtsKeResult>,
+
+    filter: Filter,
+}
+
+impl Server {
+    fn new(config: ClientConfig) -> Server {
+        Server { config, nts_state: None, filter: Filter::default() }
+    }
+
+    /// Return the host of the server, and its port if it's not the default one.
+    fn name(&self) -> String {
+        match &self.config.port {
+            Some(port) if self.config.host.contains(':') => {
+                format!("[{}]:{}", self.config.host, port)
+            },
+            Some(port) => format!("{}:{}", self.config.host, port),
+            None => self.config.host.clone(),
         }
-        Ok(_) => {}
     }
-    let state = res.unwrap();
-    debug!(logger, "running UDP client with state {:x?}", state);
-    let res = run_nts_ntp_client(&logger, state);
-    match res {
-        Err(err) => {
-            eprintln!("failure of client: {}", err);
-            process::exit(1)
+
+    /// Poll the server, and try again with a backoff when the key exchange or the exchange
+    /// times out, or the server sends an NTS NAK. The other errors are not retried, since they
+    /// would happen again.
+    fn poll(&mut self, logger: &slog::Logger) -> Result<NtpResult, Box<dyn std::error::Error>> {
+        let mut delay = RETRY_DELAY;
+        for _ in 0..RETRIES {
+            match self.exchange(logger) {
+                Err(err) if is_transient(err.as_ref()) => {
+                    info!(logger, "cannot poll the server {}: {}, trying again in {} s",
+                          self.name(), err, delay.as_secs());
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                },
+                result => return result,
+            }
+        }
+        self.exchange(logger)
+    }
+
+    /// Run one exchange with the server. The key exchange is done again when the server runs
+    /// out of cookies or the exchange fails.
+    fn exchange(
+        &mut self,
+        logger: &slog::Logger,
+    ) -> Result<NtpResult, Box<dyn std::error::Error>> {
+        let mut state = match self.nts_state.take() {
+            Some(state) if !state.cookies.is_empty() => state,
+            _ => run_nts_ke_client(logger, self.config.clone())?,
+        };
+        // Each cookie is used once, and the server sends new ones in the response. The state is
+        // dropped if the exchange fails, or the cookies run out.
+        let result = run_nts_ntp_client(logger, &mut state)?;
+        self.nts_state = Some(state);
+        Ok(result)
+    }
+
+    /// Poll the server `count` times over the same association, and return the response with
+    /// the lowest delay, which has the least error. The others are dropped as outliers.
+    fn burst(&mut self, logger: &slog::Logger, count: usize) -> Result<Burst, String> {
+        let mut results = Vec::new();
+        let mut last_error = String::new();
+        for i in 0..count {
+            if i > 0 {
+                thread::sleep(BURST_INTERVAL);
+            }
+            match self.poll(logger) {
+                Ok(result) => results.push(result),
+                // The server is given up on, and the next one is polled.
+                Err(err) => {
+                    warn!(logger, "giving up on the server {}: {}", self.name(), err);
+                    last_error = err.to_string();
+                    break;
+                },
+            }
         }
-        Ok(result) => {
-            println!("stratum: {:}", result.stratum);
-            println!("offset: {:.6}", result.time_diff);
+
+        let best = (0..results.len())
+            .min_by(|&a, &b| upstream::total_cmp(results[a].delay, results[b].delay))
+            .ok_or(last_error)?;
+        let squares: f64 = results.iter()
+            .map(|result| (result.time_diff - results[best].time_diff).powi(2))
+            .sum();
+        let jitter = (squares / results.len() as f64).sqrt();
+        let received = results.len();
+        Ok(Burst { result: results.swap_remove(best), received, jitter })
+    }
+
+    /// Poll the server, and return the sample if it can be used.
+    fn sample(&mut self, logger: &slog::Logger) -> Option<Sample> {
+        match self.poll(logger) {
+            Ok(result) => {
+                let sample = usable_sample(&result);
+                match sample {
+                    Some(_) => info!(logger, "offset {:.6} s, delay {:.6} s", result.time_diff,
+                                     result.delay;
+                                     "server" => self.name(), "stratum" => result.stratum),
+                    None => warn!(logger, "the server {} is not synchronized", self.name()),
+                }
+                sample
+            },
+            Err(err) => {
+                warn!(logger, "cannot poll the server {}: {}", self.name(), err);
+                None
+            },
+        }
+    }
+}
+
+/// Return whether the poll that failed with `err` may succeed if it's tried again: a timeout
+/// of the key exchange or of the exchange, or an NTS NAK, after which the cookies are replaced.
+fn is_transient(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return match err.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => true,
+            _ => false,
+        };
+    }
+    match err.downcast_ref::<NtpClientError>() {
+        Some(NtpClientError::NtsNak) | Some(NtpClientError::OutOfCookies) => true,
+        _ => false,
+    }
+}
+
+/// Return the sample of `result`. A server that is not synchronized, or sends a kiss of death,
+/// has none.
+fn usable_sample(result: &NtpResult) -> Option<Sample> {
+    if result.leap == LeapState::Unknown || result.stratum == 0 {
+        return None;
+    }
+    Some(Sample {
+        offset: result.time_diff,
+        delay: result.delay,
+        stratum: result.stratum,
+        leap: result.leap,
+        root_delay: result.root_delay,
+        root_dispersion: result.root_dispersion,
+        refid: 0,
+    })
+}
+
+/// Split a value of `--server` into the host and the port, if there is one. An IPv6 address
+/// with a port is in brackets, like `[2001:db8::1]:4460`, and one without a port can be bare.
+fn split_host_port(value: &str) -> (&str, Option<&str>) {
+    if value.starts_with('[') {
+        if let Some(end) = value.find(']') {
+            let rest = &value[end + 1..];
+            if rest.is_empty() {
+                return (&value[1..end], None);
+            }
+            if rest.starts_with(':') {
+                return (&value[1..end], Some(&rest[1..]));
+            }
         }
     }
+    match (value.find(':'), value.rfind(':')) {
+        (Some(first), Some(last)) if first == last => (&value[..first], Some(&value[first + 1..])),
+        _ => (value, None),
+    }
+}
+
+/// Return the servers of `--server`, whose values are a host and an optional port, and the one
+/// of the `host` argument with `client_config`.
+fn server_configs(matches: &clap::ArgMatches, client_config: ClientConfig) -> Vec<ClientConfig> {
+    let mut configs = Vec::new();
+    for value in matches.values_of("server").into_iter().flatten() {
+        let (host, port) = split_host_port(value);
+        configs.push(ClientConfig {
+            host: String::from(host),
+            port: port.map(String::from),
+            ..client_config.clone()
+        });
+    }
+    if !client_config.host.is_empty() {
+        configs.insert(0, client_config);
+    }
+    configs
+}
+
+/// The best response of a burst of polls of a server.
+struct Burst {
+    result: NtpResult,
+
+    /// How many polls were answered.
+    received: usize,
+
+    /// The jitter of the offsets of the responses.
+    jitter: f64,
+}
+
+/// The bursts of the servers, and how they were selected.
+struct Measurement {
+    servers: Vec<Server>,
+    bursts: Vec<Result<Burst, String>>,
+
+    /// Whether each server is selected, a falseticker, unsynchronized or unreachable.
+    statuses: Vec<&'static str>,
+
+    /// The sample of the best of the selected servers and their combined offset.
+    selection: Option<(Sample, f64)>,
+}
+
+/// Poll each of `configs` in a burst of `count` polls, and select the servers that agree with
+/// each other.
+fn measure(logger: &slog::Logger, configs: Vec<ClientConfig>, count: usize) -> Measurement {
+    let mut servers: Vec<Server> = configs.into_iter().map(Server::new).collect();
+    let bursts: Vec<Result<Burst, String>> = servers.iter_mut()
+        .map(|server| server.burst(logger, count))
+        .collect();
+    let samples: Vec<Option<Sample>> = bursts.iter()
+        .map(|burst| {
+            let burst = burst.as_ref().ok()?;
+            let mut sample = usable_sample(&burst.result)?;
+            sample.root_dispersion += burst.jitter;
+            Some(sample)
+        })
+        .collect();
+    let candidates: Vec<Sample> = samples.iter().flatten().cloned().collect();
+    let selected = upstream::selected(&candidates);
+
+    let mut index = 0;
+    let statuses = bursts.iter().zip(&samples)
+        .map(|(burst, sample)| match (burst, sample) {
+            (Err(_), _) => "unreachable",
+            (Ok(_), None) => "unsynchronized",
+            (Ok(_), Some(_)) => {
+                index += 1;
+                if selected.contains(&(index - 1)) { "selected" } else { "falseticker" }
+            },
+        })
+        .collect();
+
+    let selection = upstream::select(&candidates);
+    Measurement { servers, bursts, statuses, selection }
+}
+
+/// Print `measurement` as text. With one server, it's its stratum and offset, and otherwise the
+/// result of each server and the combined offset.
+fn print_text(measurement: &Measurement, count: usize) {
+    if let [burst] = &measurement.bursts[..] {
+        let burst = match burst {
+            Ok(burst) => burst,
+            Err(err) => {
+                eprintln!("failure of client: {}", err);
+                process::exit(1)
+            },
+        };
+        println!("stratum: {:}", burst.result.stratum);
+        println!("offset: {:.6}", burst.result.time_diff);
+        if count > 1 {
+            println!("delay: {:.6}", burst.result.delay);
+            println!("jitter: {:.6}", burst.jitter);
+            println!("samples: {}/{}", burst.received, count);
+        }
+        return;
+    }
+
+    let results = measurement.servers.iter().zip(&measurement.bursts).zip(&measurement.statuses);
+    for ((server, burst), status) in results {
+        match burst {
+            Ok(burst) => println!("{}: {}, stratum: {}, offset: {:.6}, delay: {:.6}",
+                                  server.name(), status, burst.result.stratum,
+                                  burst.result.time_diff, burst.result.delay),
+            Err(_) => println!("{}: {}", server.name(), status),
+        }
+    }
+    match measurement.selection {
+        Some((peer, offset)) => {
+            println!("stratum: {:}", peer.stratum);
+            println!("offset: {:.6}", offset);
+        },
+        None => eprintln!("failure of client: no server could be selected"),
+    }
+}
+
+/// Return `bytes` in lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Return the JSON record of the `burst` of polls of `server`, whose `status` is how it was
+/// selected.
+fn json_record(server: &Server, burst: &Result<Burst, String>, status: &str) -> Value {
+    let burst = match burst {
+        Ok(burst) => burst,
+        Err(err) => return json!({ "server": server.name(), "status": status, "error": err }),
+    };
+    let result = &burst.result;
+    let leap = match result.leap {
+        LeapState::NoLeap => "none",
+        LeapState::Positive => "insert",
+        LeapState::Negative => "delete",
+        LeapState::Unknown => "unsynchronized",
+    };
+    let unix = |time: f64| time - UNIX_OFFSET as f64;
+    let [origin, receive, transmit, destination] = result.timestamps;
+    let mut record = json!({
+        "server": server.name(),
+        "address": result.server.to_string(),
+        "status": status,
+        "stratum": result.stratum,
+        "leap": leap,
+        "offset": result.time_diff,
+        "delay": result.delay,
+        "root_delay": result.root_delay,
+        "root_dispersion": result.root_dispersion,
+        "jitter": burst.jitter,
+        "samples": burst.received,
+        "timestamps": {
+            "origin": unix(origin),
+            "receive": unix(receive),
+            "transmit": unix(transmit),
+            "destination": unix(destination),
+        },
+    });
+
+    // The exchange was authenticated with the keys of the last key exchange.
+    if let Some(state) = &server.nts_state {
+        record["nts"] = json!({
+            "authenticated": true,
+            "aead_algorithm": state.aead_scheme,
+            "next_servers": state.next_servers,
+            "next_port": state.next_port,
+            "redirected": state.redirected,
+            "cookies": state.cookies.len(),
+        });
+        if let Some(certificate) = state.certificates.first() {
+            record["certificate"] = match certificate_info(&certificate.0) {
+                Ok(info) => json!({
+                    "subject": info.subject,
+                    "issuer": info.issuer,
+                    "not_before": info.not_before,
+                    "not_after": info.not_after,
+                    "sha256": hex(&info.fingerprint),
+                    "chain_length": state.certificates.len(),
+                }),
+                Err(err) => json!({ "error": err.to_string() }),
+            };
+        }
+    }
+    record
+}
+
+/// Print `measurement` as JSON. With one server, it's its record, and otherwise the records of
+/// all the servers and the combined offset.
+fn print_json(measurement: &Measurement) {
+    let mut records: Vec<Value> = measurement.servers.iter()
+        .zip(&measurement.bursts)
+        .zip(&measurement.statuses)
+        .map(|((server, burst), status)| json_record(server, burst, status))
+        .collect();
+    let output = if records.len() == 1 {
+        records.remove(0)
+    } else {
+        json!({
+            "servers": records,
+            "stratum": measurement.selection.map(|(peer, _)| peer.stratum),
+            "offset": measurement.selection.map(|(_, offset)| offset),
+        })
+    };
+    println!("{}", output);
+}
+
+/// How the client daemon steers the clock.
+struct DaemonOptions {
+    /// Whether the clock is steered, or the daemon only logs what it would do.
+    set_clock: bool,
+
+    /// When the large offsets are stepped.
+    step: StepPolicy,
+
+    /// The file that the frequency error of the clock is kept in across restarts.
+    drift_file: Option<String>,
+}
+
+/// Poll the servers forever, and steer the system clock with the combined offset of the ones
+/// that are selected out of their filtered samples.
+fn run_daemon(logger: &slog::Logger, configs: Vec<ClientConfig>, options: DaemonOptions) -> ! {
+    let DaemonOptions { set_clock, mut step, drift_file } = options;
+    let mut clock: Box<dyn ClockControl> = if set_clock {
+        Box::new(KernelClock)
+    } else {
+        Box::new(DryRun::new(logger.clone()))
+    };
+    let mut servers: Vec<Server> = configs.into_iter().map(Server::new).collect();
+    let mut poll = PollInterval::default();
+    let mut last_used = Vec::new();
+
+    // The frequency error that was learned before is corrected right away, so that the clock
+    // doesn't drift while it's learned again.
+    if let Some(path) = &drift_file {
+        let result = read_drift(path)
+            .and_then(|ppm| ppm.map_or(Ok(()), |ppm| clock.set_frequency(ppm)));
+        if let Err(err) = result {
+            error!(logger, "cannot restore the frequency error from {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    let mut last_drift = Instant::now();
+
+    loop {
+        let mut candidates = Vec::new();
+        let mut jitters = Vec::new();
+        for server in &mut servers {
+            let sample = server.sample(logger);
+            server.filter.add(sample);
+            if let Some((mut best, jitter)) = server.filter.best() {
+                best.root_dispersion += jitter;
+                candidates.push(best);
+                jitters.push(jitter);
+            }
+        }
+
+        // Each sample is used once at most, so that the clock isn't steered twice by it when
+        // the newer ones have a longer delay.
+        let selection = upstream::select(&candidates).filter(|_| candidates != last_used);
+        if let Some((peer, offset)) = selection {
+            let selected = upstream::selected(&candidates);
+            if selected.len() < candidates.len() {
+                warn!(logger, "{} of {} servers are falsetickers",
+                      candidates.len() - selected.len(), candidates.len());
+            }
+            last_used = candidates.clone();
+            let jitter = candidates.iter().position(|sample| *sample == peer)
+                .map_or(0.0, |i| jitters[i]);
+            let result = match step.correction(offset) {
+                Correction::Step => {
+                    info!(logger, "stepping the clock by {:.6} s", offset);
+                    // The samples were taken against the clock before the step.
+                    for server in &mut servers {
+                        server.filter = Filter::default();
+                    }
+                    last_used.clear();
+                    clock.step(offset)
+                },
+                Correction::Slew => {
+                    poll.update(offset, jitter);
+                    clock.slew(offset, poll.exponent(), jitter, peer.root_distance())
+                },
+            };
+            if let Err(err) = result {
+                error!(logger, "cannot steer the clock: {}", err);
+                process::exit(1);
+            }
+        }
+
+        // The drift file is only written when the clock is steered, since nothing is learned
+        // otherwise.
+        if let (true, Some(path)) = (set_clock, &drift_file) {
+            if last_drift.elapsed() >= DRIFT_INTERVAL {
+                last_drift = Instant::now();
+                if let Err(err) = clock.frequency().and_then(|ppm| write_drift(path, ppm)) {
+                    warn!(logger, "cannot write the drift file {}: {}", path, err);
+                }
+            }
+        }
+
+        thread::sleep(poll.interval());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("time.cloudflare.com"), ("time.cloudflare.com", None));
+        assert_eq!(split_host_port("time.cloudflare.com:4460"),
+                   ("time.cloudflare.com", Some("4460")));
+        assert_eq!(split_host_port("192.0.2.1:4460"), ("192.0.2.1", Some("4460")));
+
+        // The IPv6 addresses.
+        assert_eq!(split_host_port("2001:db8::1"), ("2001:db8::1", None));
+        assert_eq!(split_host_port("[2001:db8::1]"), ("2001:db8::1", None));
+        assert_eq!(split_host_port("[2001:db8::1]:4460"), ("2001:db8::1", Some("4460")));
+        assert_eq!(split_host_port("::1"), ("::1", None));
+    }
 }
@@ -42,13 +42,12 @@ pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
     // Let the parsed config use the child logger of the global logger.
     config.set_logger(logger);
 
-    // Try to connect to the Memcached server.
-    let mut server = match KeServer::connect(config) {
+    // Try to connect to the key store.
+    let store = config.key_store().build();
+    let mut server = match KeServer::connect(config, store) {
         Ok(server) => server,
-        Err(_error) => {
-            // Disable the log for now because the Error trait is not implemented for
-            // RotateError yet.
-            // eprintln!("starting NTS-KE server failed: {}", error);
+        Err(error) => {
+            eprintln!("starting NTS-KE server failed: {}", error);
             process::exit(1);
         }
     };
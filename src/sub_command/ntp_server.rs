@@ -5,10 +5,18 @@
 //! The ntp-server subcommand.
 
 use std::process;
+use std::time::Duration;
 
 use crate::ntp::server::NtpServerConfig;
+use crate::ntp::server::PcapConfig;
 use crate::ntp::server::start_ntp_server;
 
+/// The most packets that are captured by default.
+const DEFAULT_PCAP_COUNT: u64 = 10000;
+
+/// How long the packets are captured for by default, in seconds.
+const DEFAULT_PCAP_DURATION: u64 = 60;
+
 /// Get a configuration file path for `ntp-server`.
 ///
 /// If the path is not specified, the system-wide configuration file (/etc/cfnts/ntp-server.config)
@@ -23,6 +31,26 @@ fn resolve_config_filename<'a>(matches: &clap::ArgMatches<'a>) -> String {
     }
 }
 
+/// Get the packet capture from the arguments, if it's asked for.
+fn resolve_pcap_config<'a>(matches: &clap::ArgMatches<'a>) -> Result<Option<PcapConfig>, String> {
+    let path = match matches.value_of("pcap") {
+        Some(path) => String::from(path),
+        None => return Ok(None),
+    };
+    let max_packets = match matches.value_of("pcap-count") {
+        Some(count) => count.parse().map_err(|_| format!("invalid pcap count: {}", count))?,
+        None => DEFAULT_PCAP_COUNT,
+    };
+    let duration = match matches.value_of("pcap-duration") {
+        Some(seconds) => match seconds.parse() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => return Err(format!("invalid pcap duration: {}", seconds)),
+        },
+        None => Duration::from_secs(DEFAULT_PCAP_DURATION),
+    };
+    Ok(Some(PcapConfig { path, max_packets, duration }))
+}
+
 /// The entry point of `ntp-server`.
 pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
     // This should return the clone of `logger` in the main function.
@@ -39,6 +67,14 @@ pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
         },
     };
 
+    config.pcap = match resolve_pcap_config(matches) {
+        Ok(pcap) => pcap,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
+    };
+
     let logger = global_logger.new(slog::o!("component" => "ntp"));
     // Let the parsed config use the child logger of the global logger.
     config.set_logger(logger);
@@ -0,0 +1,167 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! systemd socket activation.
+//!
+//! systemd can bind the sockets on our behalf and pass them to us starting from file descriptor 3,
+//! with their number in `LISTEN_FDS`. This lets us listen on privileged ports without running as
+//! root, and keeps the sockets open across restarts. See sd_listen_fds(3).
+//!
+//! The sockets are matched to the configured addresses by their local addresses, so the addresses
+//! in the socket unit must be the same as the ones in the config.
+
+use lazy_static::lazy_static;
+
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Mutex;
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed by systemd.
+enum InheritedSocket {
+    Tcp(TcpListener, SocketAddr),
+    Udp(UdpSocket, SocketAddr),
+}
+
+lazy_static! {
+    /// The sockets passed by systemd. They are taken by `init` at the start of the process and
+    /// kept open for the lifetime of the process.
+    static ref INHERITED_SOCKETS: Mutex<Vec<InheritedSocket>> = Mutex::new(Vec::new());
+}
+
+/// Take the sockets passed by systemd. It has to be called at the start of `main` before any
+/// thread is spawned, because it modifies the environment, which is not thread-safe.
+pub fn init() {
+    *INHERITED_SOCKETS.lock().unwrap() = take_inherited_sockets();
+}
+
+/// Return the number of sockets passed to the process with the `pid`, given the values of
+/// `LISTEN_PID` and `LISTEN_FDS`. The sockets are meant for another process, if `LISTEN_PID`
+/// doesn't match.
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    let listen_pid = listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok());
+    if listen_pid != Some(pid) {
+        return 0;
+    }
+
+    listen_fds.and_then(|listen_fds| listen_fds.parse().ok()).unwrap_or(0)
+}
+
+/// Return the type of the socket `fd`, for example, `SOCK_STREAM`.
+fn socket_type(fd: RawFd) -> Result<libc::c_int, std::io::Error> {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // This is safe because `kind` and `len` are valid for the size that we pass.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(kind)
+}
+
+/// Take the ownership of the sockets passed by systemd, and remove the variables from the
+/// environment so that they are not passed on to the child processes.
+fn take_inherited_sockets() -> Vec<InheritedSocket> {
+    let count = parse_listen_fds(
+        std::env::var("LISTEN_PID").ok().as_ref().map(String::as_str),
+        std::env::var("LISTEN_FDS").ok().as_ref().map(String::as_str),
+        std::process::id(),
+    );
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let mut sockets = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count as RawFd {
+        // systemd doesn't set close-on-exec on the passed descriptors.
+        // This is safe because it only changes the flags of the descriptor.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+        // Only the IP sockets can be matched to the configured addresses. The other descriptors
+        // are left alone.
+        let socket = match socket_type(fd) {
+            // These are safe because systemd hands the descriptors over to us and nothing else
+            // in the process owns them.
+            Ok(libc::SOCK_STREAM) => {
+                let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                match listener.local_addr() {
+                    Ok(addr) => InheritedSocket::Tcp(listener, addr),
+                    Err(_) => {
+                        std::mem::forget(listener);
+                        continue;
+                    },
+                }
+            },
+            Ok(libc::SOCK_DGRAM) => {
+                let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+                match socket.local_addr() {
+                    Ok(addr) => InheritedSocket::Udp(socket, addr),
+                    Err(_) => {
+                        std::mem::forget(socket);
+                        continue;
+                    },
+                }
+            },
+            _ => continue,
+        };
+        sockets.push(socket);
+    }
+
+    sockets
+}
+
+/// Return the listening TCP socket passed by systemd for `addr`, if any. Every call returns
+/// another handle to the same socket, so several listeners can share it.
+pub fn tcp_listener(addr: &SocketAddr) -> Result<Option<TcpListener>, std::io::Error> {
+    for socket in INHERITED_SOCKETS.lock().unwrap().iter() {
+        if let InheritedSocket::Tcp(listener, local_addr) = socket {
+            if local_addr == addr {
+                return listener.try_clone().map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Return the UDP socket passed by systemd for `addr`, if any. Every call returns another handle
+/// to the same socket.
+pub fn udp_socket(addr: &SocketAddr) -> Result<Option<UdpSocket>, std::io::Error> {
+    for socket in INHERITED_SOCKETS.lock().unwrap().iter() {
+        if let InheritedSocket::Udp(udp_socket, local_addr) = socket {
+            if local_addr == addr {
+                return udp_socket.try_clone().map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(parse_listen_fds(Some("42"), Some("3"), 42), 3);
+
+        // The sockets are for another process.
+        assert_eq!(parse_listen_fds(Some("41"), Some("3"), 42), 0);
+
+        // Missing or invalid variables.
+        assert_eq!(parse_listen_fds(None, Some("3"), 42), 0);
+        assert_eq!(parse_listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(parse_listen_fds(Some("42"), Some("x"), 42), 0);
+        assert_eq!(parse_listen_fds(Some("x"), Some("3"), 42), 0);
+    }
+}
@@ -0,0 +1,340 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A minimal HashiCorp Vault client which reads secrets from the KV secrets engine and decrypts
+//! values with the Transit secrets engine. See https://www.vaultproject.io/api-docs.
+//!
+//! The token is read from a file every time that it's needed, so that it can be renewed by
+//! another process, for example, the Vault agent. Without the file, `VAULT_TOKEN` is used.
+
+use serde_json::{json, Value};
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::http::{self, HttpError};
+use crate::key_rotator::take_refresh_interval;
+
+/// How long a request to Vault can take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The field of the secret that holds the cookie master key, if not configured.
+const DEFAULT_FIELD: &str = "key";
+
+/// How often the cookie master key is read again, if not configured.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Error returned from the Vault client.
+#[derive(Debug)]
+pub enum VaultError {
+    /// Error from the HTTP connection to the server.
+    Http(HttpError),
+    /// The server replied with a status other than 200 or 404.
+    Status(u16, String),
+    /// The server sent something that we don't understand.
+    Protocol(String),
+    /// There is no token or it cannot be read.
+    Token(String),
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VaultError::Http(error) => write!(f, "vault {}", error),
+            VaultError::Status(status, body) => {
+                write!(f, "vault server replied with status {}: {}", status, body)
+            },
+            VaultError::Protocol(message) => write!(f, "vault protocol error: {}", message),
+            VaultError::Token(message) => write!(f, "vault token error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for VaultError {}
+
+impl From<HttpError> for VaultError {
+    fn from(error: HttpError) -> VaultError {
+        VaultError::Http(error)
+    }
+}
+
+/// Return the data of a secret in a read response. The KV version 2 engine wraps the data and
+/// its metadata in another `data` object.
+fn secret_data(response: &Value) -> Result<&Value, VaultError> {
+    let data = response.get("data")
+        .ok_or_else(|| VaultError::Protocol(String::from("the response has no data")))?;
+    match (data.get("data"), data.get("metadata")) {
+        (Some(inner), Some(_)) => Ok(inner),
+        _ => Ok(data),
+    }
+}
+
+/// Return the body that writes `value` to the string `field` of the secret at `path`. The KV
+/// version 2 engine, whose paths have `data` after the mount, wants the fields in a `data` object.
+fn write_body(path: &str, field: &str, value: &str) -> Value {
+    let mut fields = serde_json::Map::new();
+    fields.insert(String::from(field), Value::from(value));
+    if path.trim_start_matches('/').split('/').nth(1) == Some("data") {
+        json!({ "data": fields })
+    } else {
+        Value::Object(fields)
+    }
+}
+
+/// Return the string `field` of `object`.
+fn string_field<'a>(object: &'a Value, field: &str) -> Result<&'a str, VaultError> {
+    object.get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| VaultError::Protocol(format!("the secret has no string field {}", field)))
+}
+
+/// Decode the base64 string `field` of `object`.
+fn decode_field(object: &Value, field: &str) -> Result<Vec<u8>, VaultError> {
+    base64::decode(string_field(object, field)?)
+        .map_err(|_| VaultError::Protocol(format!("{} is not base64", field)))
+}
+
+/// A client of a Vault server.
+#[derive(Clone, Debug)]
+pub struct Client {
+    /// The url of the server without the trailing slash, for example, `https://vault:8200`.
+    url: String,
+
+    /// The file that holds the token.
+    token_file: Option<PathBuf>,
+}
+
+impl Client {
+    /// Create a client of the server at `url`. It doesn't connect to the server until a request
+    /// is made.
+    pub fn new(url: &str, token_file: Option<PathBuf>) -> Client {
+        Client {
+            url: String::from(url.trim_end_matches('/')),
+            token_file,
+        }
+    }
+
+    /// Return the current token.
+    fn token(&self) -> Result<String, VaultError> {
+        let token = match &self.token_file {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|error| VaultError::Token(format!("{}: {}", path.display(), error)))?,
+            None => std::env::var("VAULT_TOKEN")
+                .map_err(|_| VaultError::Token(String::from("no token file and no VAULT_TOKEN")))?,
+        };
+        Ok(String::from(token.trim()))
+    }
+
+    /// Send a request to the API at `path` and return the response. It's `None` if the server
+    /// replies with 404, and `Null` if it replies with 204 and no body.
+    fn request(&self, method: &str, path: &str, body: Option<&Value>)
+        -> Result<Option<Value>, VaultError>
+    {
+        let token = self.token()?;
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let (status, body) = http::send_and_read(
+            method,
+            &format!("{}/v1/{}", self.url, path.trim_start_matches('/')),
+            &[("X-Vault-Token", &token), ("Content-Type", "application/json")],
+            body.as_bytes(),
+            REQUEST_TIMEOUT,
+        )?;
+        match status {
+            200 => serde_json::from_slice(&body)
+                .map(Some)
+                .map_err(|error| VaultError::Protocol(error.to_string())),
+            204 => Ok(Some(Value::Null)),
+            404 => Ok(None),
+            _ => Err(VaultError::Status(status, String::from_utf8_lossy(&body).into_owned())),
+        }
+    }
+
+    /// Check that the server is initialized, unsealed, and active. It doesn't need a token.
+    pub fn health(&self) -> Result<(), VaultError> {
+        let (status, body) = http::send_and_read(
+            "GET",
+            &format!("{}/v1/sys/health", self.url),
+            &[],
+            &[],
+            REQUEST_TIMEOUT,
+        )?;
+        match status {
+            200 => Ok(()),
+            _ => Err(VaultError::Status(status, String::from_utf8_lossy(&body).into_owned())),
+        }
+    }
+
+    /// Read the string `field` of the secret at `path`. For the KV version 2 engine, the path
+    /// includes `data`, for example, `secret/data/cfnts/cookie-key`. It's `None` if the secret
+    /// doesn't exist.
+    pub fn read_string(&self, path: &str, field: &str) -> Result<Option<String>, VaultError> {
+        match self.request("GET", path, None)? {
+            Some(response) => {
+                Ok(Some(String::from(string_field(secret_data(&response)?, field)?)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Read the base64 `field` of the secret at `path` and decode it.
+    pub fn read_field(&self, path: &str, field: &str) -> Result<Option<Vec<u8>>, VaultError> {
+        match self.read_string(path, field)? {
+            Some(value) => base64::decode(&value)
+                .map(Some)
+                .map_err(|_| VaultError::Protocol(format!("{} is not base64", field))),
+            None => Ok(None),
+        }
+    }
+
+    /// Write `value` to the string `field` of the secret at `path`, which replaces the other
+    /// fields of the secret. The path is the same as for `read_string`.
+    pub fn write_string(&self, path: &str, field: &str, value: &str) -> Result<(), VaultError> {
+        self.request("POST", path, Some(&write_body(path, field, value)))?
+            .ok_or_else(|| VaultError::Protocol(format!("cannot write to {}", path)))?;
+        Ok(())
+    }
+
+    /// Encrypt a plaintext with the Transit key `key_name` and return the ciphertext.
+    pub fn transit_encrypt(&self, key_name: &str, plaintext: &[u8])
+        -> Result<String, VaultError>
+    {
+        let request = json!({ "plaintext": base64::encode(plaintext) });
+        let response = self.request("POST", &format!("transit/encrypt/{}", key_name),
+                                    Some(&request))?
+            .ok_or_else(|| VaultError::Protocol(format!("no transit key {}", key_name)))?;
+        let data = response.get("data")
+            .ok_or_else(|| VaultError::Protocol(String::from("the response has no data")))?;
+        Ok(String::from(string_field(data, "ciphertext")?))
+    }
+
+    /// Decrypt a ciphertext, for example, `vault:v1:...`, with the Transit key `key_name`.
+    pub fn transit_decrypt(&self, key_name: &str, ciphertext: &str)
+        -> Result<Vec<u8>, VaultError>
+    {
+        let request = json!({ "ciphertext": ciphertext });
+        let response = self.request("POST", &format!("transit/decrypt/{}", key_name),
+                                    Some(&request))?
+            .ok_or_else(|| VaultError::Protocol(format!("no transit key {}", key_name)))?;
+        let data = response.get("data")
+            .ok_or_else(|| VaultError::Protocol(String::from("the response has no data")))?;
+        decode_field(data, "plaintext")
+    }
+}
+
+/// A secret in Vault which is read periodically, like the cookie master key.
+#[derive(Clone, Debug)]
+pub struct VaultSecret {
+    pub client: Client,
+    /// The API path of the secret.
+    pub path: String,
+    /// The field of the secret that holds the base64 value.
+    pub field: String,
+    /// How often the secret is read again.
+    pub refresh_interval: Duration,
+}
+
+impl VaultSecret {
+    /// Read the secret.
+    pub fn fetch(&self) -> Result<Vec<u8>, VaultError> {
+        self.client.read_field(&self.path, &self.field)?
+            .ok_or_else(|| VaultError::Protocol(format!("no secret at {}", self.path)))
+    }
+}
+
+/// Parse the settings of a secret in the table `name`, which has `url`, `path`, and optionally
+/// `field`, `token_file`, and `refresh_interval` in seconds. It's `None` if the table doesn't
+/// exist.
+pub fn get_vault_secret(settings: &config::Config, name: &str)
+    -> Result<Option<VaultSecret>, config::ConfigError>
+{
+    let mut table = match settings.get_table(name) {
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(table) => table,
+    };
+
+    let mut take_str = |key: &str| match table.remove(key) {
+        Some(value) => value.into_str().map(Some),
+        None => Ok(None),
+    };
+    let url = take_str("url")?.ok_or_else(|| config::ConfigError::Message(
+        format!("{} is missing url", name)
+    ))?;
+    let path = take_str("path")?.ok_or_else(|| config::ConfigError::Message(
+        format!("{} is missing path", name)
+    ))?;
+    let field = take_str("field")?.unwrap_or_else(|| String::from(DEFAULT_FIELD));
+    let token_file = take_str("token_file")?.map(PathBuf::from);
+    let refresh_interval = take_refresh_interval(&mut table, name, DEFAULT_REFRESH_INTERVAL)?;
+
+    Ok(Some(VaultSecret {
+        client: Client::new(&url, token_file),
+        path,
+        field,
+        refresh_interval,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_data() {
+        // KV version 1.
+        let response = json!({ "data": { "key": "c2VjcmV0" } });
+        let data = secret_data(&response).unwrap();
+        assert_eq!(decode_field(data, "key").unwrap(), b"secret".to_vec());
+
+        // KV version 2.
+        let response = json!({
+            "data": { "data": { "key": "c2VjcmV0" }, "metadata": { "version": 3 } },
+        });
+        let data = secret_data(&response).unwrap();
+        assert_eq!(decode_field(data, "key").unwrap(), b"secret".to_vec());
+
+        assert!(secret_data(&json!({ "errors": [] })).is_err());
+    }
+
+    #[test]
+    fn test_decode_field() {
+        let data = json!({ "key": "!!", "number": 1 });
+        assert!(decode_field(&data, "key").is_err());
+        assert!(decode_field(&data, "number").is_err());
+        assert!(decode_field(&data, "missing").is_err());
+    }
+
+    #[test]
+    fn test_write_body() {
+        assert_eq!(write_body("secret/cfnts/nts-keys/0", "key", "c2VjcmV0"),
+                   json!({ "key": "c2VjcmV0" }));
+        assert_eq!(write_body("/secret/data/cfnts/nts-keys/0", "key", "c2VjcmV0"),
+                   json!({ "data": { "key": "c2VjcmV0" } }));
+    }
+
+    #[test]
+    fn test_get_vault_secret() {
+        let mut settings = config::Config::new();
+        assert!(get_vault_secret(&settings, "cookie_key_vault").unwrap().is_none());
+
+        settings.set("cookie_key_vault.url", "https://vault.example.com").unwrap();
+        assert!(get_vault_secret(&settings, "cookie_key_vault").is_err());
+
+        settings.set("cookie_key_vault.path", "secret/data/cfnts").unwrap();
+        let secret = get_vault_secret(&settings, "cookie_key_vault").unwrap().unwrap();
+        assert_eq!(secret.field, DEFAULT_FIELD);
+        assert_eq!(secret.refresh_interval, DEFAULT_REFRESH_INTERVAL);
+
+        settings.set("cookie_key_vault.refresh_interval", 60).unwrap();
+        let secret = get_vault_secret(&settings, "cookie_key_vault").unwrap().unwrap();
+        assert_eq!(secret.refresh_interval, Duration::from_secs(60));
+
+        // A refresh interval of 0 would reload the secret in a loop.
+        for secs in &[0, -1] {
+            settings.set("cookie_key_vault.refresh_interval", *secs).unwrap();
+            assert!(get_vault_secret(&settings, "cookie_key_vault").is_err());
+        }
+    }
+}